@@ -0,0 +1,125 @@
+//! Datapath benchmarks
+//!
+//! Covers the per-packet work of the core: parsing, rule evaluation
+//! against a large rule set, NAT lookups while flows come and go, and the
+//! device's packet queues. Run with `cargo bench --bench datapath`; criterion
+//! compares each run with the previous one, so run it before and after a
+//! change meant to speed things up.
+
+use std::hint::black_box;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use voyage_core::{
+    build_udp_packet, create_tcp_packet, MatchContext, NatKey, NatManager, ParsedPacket,
+    RuleEngine, VirtualTunDevice,
+};
+
+/// Rules in the large rule set
+const RULE_COUNT: usize = 10_000;
+
+/// Packets moved through the device queue per iteration
+const QUEUE_BATCH: usize = 256;
+
+fn parse(c: &mut Criterion) {
+    let tcp = create_tcp_packet([10, 0, 0, 1], [1, 1, 1, 1], 40000, 443, true);
+    let src: SocketAddr = "10.0.0.1:5353".parse().unwrap();
+    let dst: SocketAddr = "8.8.8.8:53".parse().unwrap();
+    let udp = build_udp_packet(src, dst, &[0u8; 512]).unwrap();
+
+    let mut group = c.benchmark_group("parse");
+    group.bench_function("tcp_syn", |b| {
+        b.iter(|| ParsedPacket::parse(black_box(&tcp)).unwrap())
+    });
+    group.bench_function("udp_512", |b| {
+        b.iter(|| ParsedPacket::parse(black_box(&udp)).unwrap())
+    });
+    group.finish();
+}
+
+/// HTTPS flow to `domain` at `ip`
+fn flow(domain: &str, ip: Option<IpAddr>) -> MatchContext<'_> {
+    MatchContext::new(Some(domain), ip, 443).with_source(None, 40000)
+}
+
+fn rules(c: &mut Criterion) {
+    let text: String = (0..RULE_COUNT)
+        .map(|i| match i % 3 {
+            0 => format!("DOMAIN-SUFFIX, site{}.example, PROXY\n", i),
+            1 => format!("DOMAIN, host{}.example.org, DIRECT\n", i),
+            _ => format!("IP-CIDR, 10.{}.{}.0/24, REJECT\n", i / 256 % 256, i % 256),
+        })
+        .collect();
+    let mut engine = RuleEngine::new();
+    engine.add_rules(RuleEngine::parse_config(&text).unwrap());
+    let ip = Some(IpAddr::V4(Ipv4Addr::new(203, 0, 113, 7)));
+
+    let mut group = c.benchmark_group("rules_10k");
+    group.bench_function("first_match", |b| {
+        b.iter(|| engine.evaluate(&flow(black_box("www.site0.example"), ip)))
+    });
+    group.bench_function("middle_match", |b| {
+        let domain = format!("www.site{}.example", RULE_COUNT / 2 - RULE_COUNT / 2 % 3);
+        b.iter(|| engine.evaluate(&flow(black_box(domain.as_str()), ip)))
+    });
+    group.bench_function("no_match", |b| {
+        b.iter(|| engine.evaluate(&flow(black_box("unlisted.test"), ip)))
+    });
+    group.finish();
+}
+
+fn nat(c: &mut Criterion) {
+    let dst: SocketAddr = "1.1.1.1:443".parse().unwrap();
+    let key = |n: u32| {
+        let src = Ipv4Addr::from(0x0a00_0000 | (n >> 16));
+        NatKey::tcp(SocketAddr::from((src, n as u16)), dst)
+    };
+
+    let mut group = c.benchmark_group("nat");
+    group.bench_function("lookup_existing", |b| {
+        let mut nat = NatManager::with_config(10000, 60000, 4096);
+        for n in 0..4096 {
+            nat.get_or_create(key(n)).unwrap();
+        }
+        let mut n = 0u32;
+        b.iter(|| {
+            n = (n + 1) % 4096;
+            black_box(nat.get_or_create(key(n)).unwrap().local_port)
+        })
+    });
+    // A full table where every new flow evicts an old one
+    group.bench_function("create_under_churn", |b| {
+        let mut nat = NatManager::with_config(10000, 60000, 4096);
+        let mut n = 0u32;
+        b.iter(|| {
+            n = n.wrapping_add(1);
+            black_box(nat.get_or_create(key(n)).unwrap().local_port)
+        })
+    });
+    group.finish();
+}
+
+fn device_queue(c: &mut Criterion) {
+    let packet = create_tcp_packet([10, 0, 0, 1], [1, 1, 1, 1], 40000, 443, false);
+    let device = VirtualTunDevice::new();
+    let rx = device.rx_queue();
+
+    let mut group = c.benchmark_group("device_queue");
+    group.throughput(Throughput::Elements(QUEUE_BATCH as u64));
+    group.bench_function("inject_and_drain", |b| {
+        b.iter_batched(
+            || vec![packet.clone(); QUEUE_BATCH],
+            |packets| {
+                for packet in packets {
+                    device.inject_packet(packet);
+                }
+                black_box(rx.drain())
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse, rules, nat, device_queue);
+criterion_main!(benches);
@@ -0,0 +1,46 @@
+//! Inbound packet path benchmark
+//!
+//! Compares the copies made by `process_inbound_packet`, where UniFFI
+//! lifts the packet into a `Vec`, lowers the result back to the host and
+//! the host hands it to the interface, with `inject_inbound`, which copies
+//! the borrowed packet once into a pooled buffer and queues that buffer.
+//!
+//! Run with `cargo bench --bench packet_path`.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use voyage_core::{create_tcp_packet, ProxyConfig, VoyageCore};
+
+fn inbound(c: &mut Criterion) {
+    let packet = create_tcp_packet([10, 0, 0, 1], [1, 1, 1, 1], 40000, 443, false);
+
+    let mut core = VoyageCore::new(ProxyConfig::default());
+    let mut iface = core.new_interface();
+    let rx = iface.packet_queues()[0].clone();
+    let pool = core.buffer_pool().clone();
+
+    let mut group = c.benchmark_group("inbound");
+    group.bench_function("copying", |b| {
+        b.iter(|| {
+            let mut lifted = black_box(&packet).to_vec();
+            let _ = core.process_inbound(&mut lifted);
+            let lowered = lifted.clone();
+            iface.inject_packet(lowered.to_vec());
+            black_box(rx.pop_front())
+        })
+    });
+    group.bench_function("borrowed", |b| {
+        b.iter(|| {
+            let _ = core.inject_inbound(black_box(&packet));
+            // smoltcp hands buffers it has read back to the pool
+            if let Some(buffer) = rx.pop_front() {
+                pool.recycle(buffer);
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, inbound);
+criterion_main!(benches);
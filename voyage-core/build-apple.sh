@@ -135,6 +135,9 @@ generate_bindings() {
     local bindings_dir="generated"
     mkdir -p "$bindings_dir"
     
+    # Hand-written header of the C entry points UniFFI does not cover
+    cp -f include/voyage_packet.h "$bindings_dir/"
+    
     # Generate bindings using uniffi-bindgen
     if cargo run --bin uniffi-bindgen generate \
         --library target/release/libvoyage_core.dylib \
@@ -180,6 +183,7 @@ create_xcframework() {
     cat > "$header_dir/module.modulemap" << 'EOF'
 framework module VoyageCore {
     umbrella header "voyage_coreFFI.h"
+    header "voyage_packet.h"
     export *
     module * { export * }
 }
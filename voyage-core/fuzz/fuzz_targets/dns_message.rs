@@ -0,0 +1,9 @@
+//! DNS message parsing, including compressed names
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use voyage_core::DnsMessage;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = DnsMessage::parse(data);
+});
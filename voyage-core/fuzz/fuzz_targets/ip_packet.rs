@@ -0,0 +1,15 @@
+//! IP header parsing, the full packet parse built on it, and fragmentation
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use voyage_core::packet::split_oversized;
+use voyage_core::{IpPacketInfo, ParsedPacket};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(info) = IpPacketInfo::parse(data) {
+        assert!(info.header_len <= data.len());
+        assert!(info.header_len <= info.total_len);
+    }
+    let _ = ParsedPacket::parse(data);
+    let _ = split_oversized(data, 576);
+});
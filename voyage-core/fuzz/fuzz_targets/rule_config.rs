@@ -0,0 +1,12 @@
+//! Surge-style rule configuration parsing
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use voyage_core::RuleEngine;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(config) = std::str::from_utf8(data) {
+        let mut engine = RuleEngine::new();
+        let _ = engine.load_from_config(config);
+    }
+});
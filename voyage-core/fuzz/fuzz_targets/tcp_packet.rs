@@ -0,0 +1,11 @@
+//! TCP header parsing, and MSS clamping which rewrites its options
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use voyage_core::{clamp_tcp_mss, TcpPacketInfo};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = TcpPacketInfo::parse(data);
+    let mut packet = data.to_vec();
+    let _ = clamp_tcp_mss(&mut packet, 1200);
+});
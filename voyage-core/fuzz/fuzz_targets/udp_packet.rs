@@ -0,0 +1,9 @@
+//! UDP header parsing
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use voyage_core::UdpPacketInfo;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = UdpPacketInfo::parse(data);
+});
@@ -0,0 +1,24 @@
+// Borrowed packet path of Voyage Core, next to the UniFFI-generated header.
+//
+// UniFFI copies every byte buffer it passes; this entry point reads the
+// packet in place instead. Call it with the bytes of a packet from the
+// TUN device, e.g. inside `Data.withUnsafeBytes`.
+
+#ifndef VOYAGE_PACKET_H
+#define VOYAGE_PACKET_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define VOYAGE_INJECT_QUEUED 0
+#define VOYAGE_INJECT_REJECTED 1
+#define VOYAGE_INJECT_QUEUE_FULL 2
+#define VOYAGE_INJECT_UNAVAILABLE (-1)
+
+// Process a packet and queue it on the core's interface. `data` only has
+// to stay valid until the call returns. Returns a VOYAGE_INJECT_ code;
+// after VOYAGE_INJECT_REJECTED, last_error_message() on the same thread
+// says why.
+int32_t voyage_inject_inbound_packet(const uint8_t *data, size_t len);
+
+#endif
@@ -0,0 +1,117 @@
+//! Admission Control
+//!
+//! This module enforces the concurrency caps: simultaneous proxied
+//! connections and simultaneous connections per destination host. A flow
+//! over a cap is rejected or, for TCP, queued: its SYNs are dropped, so the
+//! app keeps retransmitting them, until a slot frees up or the queue
+//! timeout passes. Queued flows are admitted oldest first.
+
+use std::collections::VecDeque;
+use std::time::Instant;
+
+use crate::config::{ConcurrencyLimits, ExcessPolicy};
+use crate::nat::NatKey;
+use crate::proxy::RoutingDecision;
+use crate::rule::RouteAction;
+use crate::shard::ShardedConnections;
+
+/// Rule name recorded on flows rejected by a cap
+pub const CONCURRENCY_LIMIT_RULE: &str = "concurrency limit";
+
+/// What to do with a newly routed flow
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    Admit,
+    Queue,
+    Reject,
+}
+
+/// A flow waiting for a slot
+#[derive(Debug, Clone)]
+pub struct QueuedFlow {
+    pub key: NatKey,
+    /// Decision to apply once the flow is admitted
+    pub decision: RoutingDecision,
+    /// When the flow was queued
+    pub since: Instant,
+}
+
+/// Concurrency caps and the flows waiting under them
+#[derive(Debug, Default)]
+pub struct AdmissionControl {
+    limits: ConcurrencyLimits,
+    queue: VecDeque<QueuedFlow>,
+}
+
+impl AdmissionControl {
+    pub fn new(limits: ConcurrencyLimits) -> Self {
+        Self {
+            limits,
+            queue: VecDeque::new(),
+        }
+    }
+
+    pub fn limits(&self) -> &ConcurrencyLimits {
+        &self.limits
+    }
+
+    /// Change the caps; flows already running are not affected
+    pub fn set_limits(&mut self, limits: ConcurrencyLimits) {
+        self.limits = limits;
+    }
+
+    /// Whether a flow routed by `decision` fits under the caps, given the
+    /// flows `connections` is running
+    pub fn check(
+        &self,
+        key: &NatKey,
+        decision: &RoutingDecision,
+        connections: &ShardedConnections,
+    ) -> Admission {
+        if decision.action == RouteAction::Reject {
+            return Admission::Admit;
+        }
+        let proxied_full = self.limits.max_proxied > 0
+            && decision.action == RouteAction::Proxy
+            && connections.proxied_count() >= self.limits.max_proxied;
+        let destination_full = self.limits.max_per_destination > 0
+            && decision.destination_host().is_some_and(|host| {
+                connections.destination_count(&host) >= self.limits.max_per_destination
+            });
+
+        if !proxied_full && !destination_full {
+            Admission::Admit
+        } else if self.limits.excess == ExcessPolicy::Queue && key.is_tcp() {
+            Admission::Queue
+        } else {
+            Admission::Reject
+        }
+    }
+
+    /// Hold a flow until `take_queue` hands it back
+    pub fn enqueue(&mut self, flow: QueuedFlow) {
+        self.queue.push_back(flow);
+    }
+
+    /// Decision of a queued flow
+    pub fn queued(&self, key: &NatKey) -> Option<&RoutingDecision> {
+        self.queue
+            .iter()
+            .find(|flow| flow.key == *key)
+            .map(|flow| &flow.decision)
+    }
+
+    /// Take every queued flow, oldest first, to admit or requeue
+    pub fn take_queue(&mut self) -> Vec<QueuedFlow> {
+        self.queue.drain(..).collect()
+    }
+
+    /// Number of flows waiting
+    pub fn queue_len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
@@ -0,0 +1,835 @@
+//! Remote Control API
+//!
+//! This module serves a small HTTP API on localhost so the macOS app, CLI
+//! tools or a web dashboard can control a running core. Every request must
+//! carry the API token, as an `Authorization: Bearer` header or, since
+//! browsers cannot set headers on WebSockets, a `token` query parameter.
+//!
+//! | Request                    | Effect                                      |
+//! |----------------------------|---------------------------------------------|
+//! | `GET /connections`         | Live flows                                  |
+//! | `DELETE /connections/:id`  | Close a flow                                |
+//! | `GET /flows?limit=N`       | Recently closed flows as flow log records   |
+//! | `GET /malformed?limit=N`   | Recent packets the parser rejected          |
+//! | `GET /traffic`             | Upload/download rates every second, over a  |
+//! |                            | WebSocket or as chunked JSON lines          |
+//! | `GET /match?host=H&port=P` | Policy the rules pick for a destination     |
+//! | `PUT /rules`               | Replace the rules with the body's rule text |
+//! | `PUT /config`              | Reload the body's configuration file        |
+//! | `GET /profiles`            | Stored profiles                             |
+//! | `PUT /profiles/current`    | Switch profile: `{"name": "..."}`           |
+//! | `GET /proxy`               | Whether the proxy is enabled                |
+//! | `PUT /proxy`               | Enable or disable it: `{"enabled": true}`   |
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpListener as StdTcpListener};
+use std::sync::{Arc, RwLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Notify;
+
+use crate::error::VoyageError;
+use crate::ffi::FfiConnectionFilter;
+use crate::flowlog::FlowRecord;
+use crate::profile::{substitute_variables, VoyageConfig};
+use crate::rule::{MatchContext, RouteAction, RuleEngine};
+use crate::VoyageCore;
+
+/// Port `voyagectl` connects to unless told otherwise
+pub const DEFAULT_API_PORT: u16 = 9090;
+
+/// Closed flows returned by `GET /flows` without a limit
+const DEFAULT_FLOW_LIMIT: usize = 100;
+
+/// Rejected packets returned by `GET /malformed` without a limit
+const DEFAULT_MALFORMED_LIMIT: usize = 32;
+
+/// Interval between traffic stream updates
+pub const TRAFFIC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Longest request head accepted
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// Largest request body accepted
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// How long a client may take to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Appended to the client's key to form the WebSocket accept key
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Handle to the API listener; stopping (or dropping) it closes the socket
+/// and every open stream
+pub struct ApiServer {
+    port: u16,
+    stop: Arc<Notify>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ApiServer {
+    /// Serve the API for `core` on 127.0.0.1:`port` (0 picks a free port),
+    /// accepting requests that carry `token`
+    pub fn start(core: Arc<RwLock<VoyageCore>>, port: u16, token: String) -> std::io::Result<Self> {
+        let listener = StdTcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, port)))?;
+        listener.set_nonblocking(true)?;
+        let port = listener.local_addr()?.port();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .enable_time()
+            .build()?;
+        let stop = Arc::new(Notify::new());
+        let stopped = Arc::clone(&stop);
+        let token: Arc<str> = token.into();
+
+        let thread = std::thread::Builder::new()
+            .name("voyage-api".into())
+            .spawn(move || {
+                runtime.block_on(async move {
+                    let listener = match TcpListener::from_std(listener) {
+                        Ok(listener) => listener,
+                        Err(e) => {
+                            log::error!("API listener failed: {}", e);
+                            return;
+                        }
+                    };
+                    let accept = tokio::spawn(async move {
+                        loop {
+                            match listener.accept().await {
+                                Ok((stream, _)) => {
+                                    let core = Arc::clone(&core);
+                                    tokio::spawn(serve(stream, core, Arc::clone(&token)));
+                                }
+                                Err(e) => log::warn!("API accept failed: {}", e),
+                            }
+                        }
+                    });
+                    stopped.notified().await;
+                    accept.abort();
+                });
+            })?;
+
+        log::info!("API listener on 127.0.0.1:{}", port);
+        Ok(Self {
+            port,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Port the listener is bound to
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Close the listener and every open stream
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.stop.notify_one();
+            let _ = thread.join();
+            log::debug!("API listener stopped");
+        }
+    }
+}
+
+impl Drop for ApiServer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// A parsed HTTP request
+#[derive(Debug, Default)]
+struct Request {
+    method: String,
+    path: String,
+    query: Vec<(String, String)>,
+    /// Header names are lowercased
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn parse_head(head: &str) -> Option<Self> {
+        let mut lines = head.split("\r\n");
+        let mut parts = lines.next()?.split_whitespace();
+        let method = parts.next()?.to_string();
+        let target = parts.next()?;
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let query = query
+            .split('&')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+                (percent_decode(name), percent_decode(value))
+            })
+            .collect();
+        let headers = lines
+            .filter_map(|line| line.split_once(':'))
+            .map(|(name, value)| (name.trim().to_ascii_lowercase(), value.trim().to_string()))
+            .collect();
+        Some(Self {
+            method,
+            path: percent_decode(path),
+            query,
+            headers,
+            body: Vec::new(),
+        })
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn query(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Token from the `Authorization` header or the `token` parameter
+    fn token(&self) -> Option<&str> {
+        self.header("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .or_else(|| self.query("token"))
+    }
+
+    fn is_websocket(&self) -> bool {
+        self.header("upgrade")
+            .is_some_and(|value| value.eq_ignore_ascii_case("websocket"))
+    }
+}
+
+/// Decode `%XX` escapes and `+`
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = |at: usize| bytes.get(at).and_then(|&b| (b as char).to_digit(16));
+        match bytes[i] {
+            b'%' => match (hex(i + 1), hex(i + 2)) {
+                (Some(high), Some(low)) => {
+                    decoded.push((high * 16 + low) as u8);
+                    i += 3;
+                    continue;
+                }
+                _ => decoded.push(b'%'),
+            },
+            b'+' => decoded.push(b' '),
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Compare without leaking the position of the first difference
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// An HTTP response with a complete body
+#[derive(Debug)]
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn json(status: u16, value: Value) -> Self {
+        Self {
+            status,
+            content_type: "application/json",
+            body: value.to_string(),
+        }
+    }
+
+    fn error(status: u16, message: impl Into<String>) -> Self {
+        Self::json(status, json!({ "error": message.into() }))
+    }
+
+    fn no_content() -> Self {
+        Self {
+            status: 204,
+            content_type: "application/json",
+            body: String::new(),
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            self.status,
+            reason(self.status),
+            self.content_type,
+            self.body.len(),
+            self.body
+        )
+        .into_bytes()
+    }
+}
+
+impl From<VoyageError> for Response {
+    fn from(e: VoyageError) -> Self {
+        let status = match e {
+            VoyageError::Rule { .. }
+            | VoyageError::RuleSyntax { .. }
+            | VoyageError::ConfigError { .. }
+            | VoyageError::ConfigSyntax { .. } => 400,
+            _ => 500,
+        };
+        Self::error(status, e.to_string())
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        _ => "Internal Server Error",
+    }
+}
+
+/// Read a request head and its body
+async fn read_request(stream: &mut TcpStream) -> Result<Request, Response> {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 4096];
+    let head_end = loop {
+        if let Some(end) = data.windows(4).position(|window| window == b"\r\n\r\n") {
+            break end;
+        }
+        if data.len() > MAX_HEAD_SIZE {
+            return Err(Response::error(413, "Request head too large"));
+        }
+        let n = stream.read(&mut buf).await.unwrap_or(0);
+        if n == 0 {
+            return Err(Response::error(400, "Incomplete request"));
+        }
+        data.extend_from_slice(&buf[..n]);
+    };
+
+    let head = std::str::from_utf8(&data[..head_end])
+        .ok()
+        .and_then(Request::parse_head);
+    let mut request = head.ok_or_else(|| Response::error(400, "Malformed request"))?;
+    let length = match request.header("content-length") {
+        Some(value) => value
+            .parse::<usize>()
+            .map_err(|_| Response::error(400, "Invalid Content-Length"))?,
+        None => 0,
+    };
+    if length > MAX_BODY_SIZE {
+        return Err(Response::error(413, "Request body too large"));
+    }
+
+    let mut body = data.split_off(head_end + 4);
+    while body.len() < length {
+        let n = stream.read(&mut buf).await.unwrap_or(0);
+        if n == 0 {
+            return Err(Response::error(400, "Incomplete request body"));
+        }
+        body.extend_from_slice(&buf[..n]);
+    }
+    body.truncate(length);
+    request.body = body;
+    Ok(request)
+}
+
+/// Answer one request and close the connection, or stream traffic until
+/// the client goes away
+async fn serve(mut stream: TcpStream, core: Arc<RwLock<VoyageCore>>, token: Arc<str>) {
+    let request = match tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(Ok(request)) => request,
+        Ok(Err(response)) => {
+            let _ = stream.write_all(&response.encode()).await;
+            return;
+        }
+        Err(_) => return,
+    };
+
+    let authorized = request
+        .token()
+        .is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes()));
+    let response = if !authorized {
+        Response::error(401, "Missing or wrong API token")
+    } else if request.method == "GET" && request.path == "/traffic" {
+        stream_traffic(stream, &core, &request).await;
+        return;
+    } else {
+        handle(&core, &request)
+    };
+    let _ = stream.write_all(&response.encode()).await;
+    let _ = stream.shutdown().await;
+}
+
+/// Route a request to its handler
+fn handle(core: &RwLock<VoyageCore>, request: &Request) -> Response {
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    let result = match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["connections"]) => connections(core),
+        ("DELETE", ["connections", id]) => close_connection(core, id),
+        ("GET", ["flows"]) => flows(core, request),
+        ("GET", ["malformed"]) => malformed(core, request),
+        ("GET", ["match"]) => match_route(core, request),
+        ("PUT", ["rules"]) => replace_rules(core, &request.body),
+        ("PUT", ["config"]) => reload_config(core, &request.body),
+        ("GET", ["profiles"]) => profiles(core),
+        ("PUT", ["profiles", "current"]) => switch_profile(core, &request.body),
+        ("GET", ["proxy"]) => proxy_state(core),
+        ("PUT", ["proxy"]) => set_proxy(core, &request.body),
+        (
+            _,
+            ["connections"]
+            | ["connections", _]
+            | ["flows"]
+            | ["malformed"]
+            | ["match"]
+            | ["traffic"]
+            | ["rules"]
+            | ["config"]
+            | ["profiles"]
+            | ["profiles", "current"]
+            | ["proxy"],
+        ) => Ok(Response::error(405, "Method not allowed")),
+        _ => Ok(Response::error(404, "Not found")),
+    };
+    result.unwrap_or_else(Response::from)
+}
+
+fn connections(core: &RwLock<VoyageCore>) -> Result<Response, VoyageError> {
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    let connections: Vec<Value> = core
+        .connections(&FfiConnectionFilter::default())
+        .into_iter()
+        .map(|c| {
+            json!({
+                "id": c.id,
+                "protocol": c.protocol,
+                "src": c.src,
+                "dst": c.dst,
+                "domain": c.domain,
+                "state": format!("{:?}", c.state),
+                "bytes_sent": c.bytes_sent,
+                "bytes_received": c.bytes_received,
+                "age_ms": c.age_ms,
+                "idle_ms": c.idle_ms,
+                "upload_rate": c.upload_rate,
+                "download_rate": c.download_rate,
+                "policy": RouteAction::from(c.policy).name(),
+                "matched_rule": c.matched_rule,
+            })
+        })
+        .collect();
+    Ok(Response::json(200, json!({ "connections": connections })))
+}
+
+fn close_connection(core: &RwLock<VoyageCore>, id: &str) -> Result<Response, VoyageError> {
+    let Ok(id) = id.parse::<u64>() else {
+        return Ok(Response::error(404, "Not found"));
+    };
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    match core.close_connection(id) {
+        Ok(()) => Ok(Response::no_content()),
+        Err(VoyageError::Connection { detail }) => Ok(Response::error(404, detail)),
+        Err(e) => Err(e),
+    }
+}
+
+/// The `limit` query parameter, `default` when absent and `None` when
+/// invalid
+fn query_limit(request: &Request, default: usize) -> Option<usize> {
+    match request.query("limit") {
+        Some(limit) => limit.parse().ok(),
+        None => Some(default),
+    }
+}
+
+fn flows(core: &RwLock<VoyageCore>, request: &Request) -> Result<Response, VoyageError> {
+    let Some(limit) = query_limit(request, DEFAULT_FLOW_LIMIT) else {
+        return Ok(Response::error(400, "Invalid limit"));
+    };
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    let now = Instant::now();
+    let flows: Vec<Value> = core
+        .conn_manager
+        .recent_connections(limit)
+        .iter()
+        .map(|closed| serde_json::to_value(FlowRecord::new(closed, now)).unwrap_or_default())
+        .collect();
+    Ok(Response::json(200, json!({ "flows": flows })))
+}
+
+fn malformed(core: &RwLock<VoyageCore>, request: &Request) -> Result<Response, VoyageError> {
+    let Some(limit) = query_limit(request, DEFAULT_MALFORMED_LIMIT) else {
+        return Ok(Response::error(400, "Invalid limit"));
+    };
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    let packets: Vec<Value> = core
+        .malformed_packets(limit)
+        .into_iter()
+        .map(|packet| {
+            json!({
+                "timestamp_ms": packet.timestamp_ms,
+                "error": packet.error,
+                "offset": packet.offset,
+                "length": packet.length,
+                "data": packet.hex(),
+            })
+        })
+        .collect();
+    Ok(Response::json(200, json!({ "packets": packets })))
+}
+
+fn match_route(core: &RwLock<VoyageCore>, request: &Request) -> Result<Response, VoyageError> {
+    let Some(host) = request.query("host").filter(|host| !host.is_empty()) else {
+        return Ok(Response::error(400, "Missing host"));
+    };
+    let Ok(port) = request.query("port").unwrap_or("443").parse::<u16>() else {
+        return Ok(Response::error(400, "Invalid port"));
+    };
+    let (domain, ip) = match host.trim_matches(['[', ']']).parse::<IpAddr>() {
+        Ok(ip) => (None, Some(ip)),
+        Err(_) => (Some(host), None),
+    };
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    let decision = core.proxy_manager().peek_route(&MatchContext::new(domain, ip, port));
+    Ok(Response::json(
+        200,
+        json!({
+            "policy": decision.action.name(),
+            "matched_rule": decision.matched_rule,
+            "domain": decision.domain,
+        }),
+    ))
+}
+
+fn replace_rules(core: &RwLock<VoyageCore>, body: &[u8]) -> Result<Response, VoyageError> {
+    let text = std::str::from_utf8(body)
+        .map_err(|_| VoyageError::config("Rules must be UTF-8 text".into()))?;
+    // Parse before locking so packet processing isn't held up
+    let rules = RuleEngine::parse_config(text)?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    let count = core.replace_rules(rules);
+    log::info!("Reloaded {} rules over the API", count);
+    Ok(Response::json(200, json!({ "rules": count })))
+}
+
+fn reload_config(core: &RwLock<VoyageCore>, body: &[u8]) -> Result<Response, VoyageError> {
+    let text = std::str::from_utf8(body)
+        .map_err(|_| VoyageError::config("Configuration must be UTF-8 text".into()))?;
+    // Parse before locking so packet processing isn't held up
+    let file = VoyageConfig::parse_auto(&substitute_variables(text, &HashMap::new())?)?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    let diff = core.reload_config(file)?;
+    log::info!("Reloaded configuration over the API");
+    Ok(Response::json(200, json!({ "changed": diff.sections() })))
+}
+
+fn profiles(core: &RwLock<VoyageCore>) -> Result<Response, VoyageError> {
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    let profiles: Vec<Value> = core
+        .profiles()
+        .into_iter()
+        .map(|profile| {
+            json!({
+                "name": profile.name,
+                "active": profile.active,
+                "activations": profile.activations,
+                "direct_connections": profile.direct_connections,
+                "proxied_connections": profile.proxied_connections,
+                "rejected_connections": profile.rejected_connections,
+            })
+        })
+        .collect();
+    Ok(Response::json(200, json!({ "profiles": profiles })))
+}
+
+#[derive(Deserialize)]
+struct ProfileSelection {
+    name: String,
+}
+
+fn switch_profile(core: &RwLock<VoyageCore>, body: &[u8]) -> Result<Response, VoyageError> {
+    let selection: ProfileSelection = parse_body(body)?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    let diff = core.switch_profile(&selection.name)?;
+    Ok(Response::json(200, json!({ "changed": diff.sections() })))
+}
+
+fn proxy_state(core: &RwLock<VoyageCore>) -> Result<Response, VoyageError> {
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    Ok(Response::json(
+        200,
+        json!({ "enabled": core.proxy_manager().is_enabled() }),
+    ))
+}
+
+#[derive(Deserialize)]
+struct ProxySwitch {
+    enabled: bool,
+}
+
+fn set_proxy(core: &RwLock<VoyageCore>, body: &[u8]) -> Result<Response, VoyageError> {
+    let switch: ProxySwitch = parse_body(body)?;
+
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    if switch.enabled {
+        core.proxy_manager().enable();
+    } else {
+        core.proxy_manager().disable();
+    }
+    log::info!(
+        "Proxy {} over the API",
+        if switch.enabled {
+            "enabled"
+        } else {
+            "disabled"
+        }
+    );
+    Ok(Response::json(200, json!({ "enabled": switch.enabled })))
+}
+
+fn parse_body<T: for<'de> Deserialize<'de>>(body: &[u8]) -> Result<T, VoyageError> {
+    serde_json::from_slice(body)
+        .map_err(|e| VoyageError::config(format!("Invalid request body: {}", e)))
+}
+
+/// Current rates and totals as a JSON object
+fn traffic_update(core: &RwLock<VoyageCore>) -> Option<String> {
+    let core = core.read().ok()?;
+    let manager = &core.conn_manager;
+    let update = json!({
+        "up": manager.upload_rate(),
+        "down": manager.download_rate(),
+        "up_total": manager.total_bytes_sent(),
+        "down_total": manager.total_bytes_received(),
+    });
+    Some(update.to_string())
+}
+
+/// Send a traffic update every `TRAFFIC_INTERVAL` until the client goes
+/// away: as WebSocket text frames when the client asked for an upgrade,
+/// otherwise as chunked JSON lines
+async fn stream_traffic(mut stream: TcpStream, core: &RwLock<VoyageCore>, request: &Request) {
+    let websocket = request.is_websocket();
+    let head = match (websocket, request.header("sec-websocket-key")) {
+        (true, Some(key)) => format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {}\r\n\r\n",
+            websocket_accept(key)
+        ),
+        (true, None) => {
+            let response = Response::error(400, "Missing Sec-WebSocket-Key");
+            let _ = stream.write_all(&response.encode()).await;
+            return;
+        }
+        (false, _) => "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                       Transfer-Encoding: chunked\r\n\r\n"
+            .to_string(),
+    };
+    if stream.write_all(head.as_bytes()).await.is_err() {
+        return;
+    }
+
+    let mut ticker = tokio::time::interval(TRAFFIC_INTERVAL);
+    loop {
+        ticker.tick().await;
+        let Some(update) = traffic_update(core) else {
+            break;
+        };
+        let data = if websocket {
+            websocket_text_frame(&update)
+        } else {
+            let line = format!("{}\n", update);
+            format!("{:x}\r\n{}\r\n", line.len(), line).into_bytes()
+        };
+        if stream.write_all(&data).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// `Sec-WebSocket-Accept` value answering `key`
+fn websocket_accept(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.trim());
+    hasher.update(WEBSOCKET_GUID);
+    STANDARD.encode(hasher.finalize())
+}
+
+/// An unmasked, unfragmented text frame
+fn websocket_text_frame(text: &str) -> Vec<u8> {
+    let payload = text.as_bytes();
+    let mut frame = vec![0x81];
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream as StdTcpStream;
+
+    use crate::config::ProxyConfig;
+
+    const TOKEN: &str = "s3cret";
+
+    fn request(port: u16, text: &str) -> String {
+        let mut stream = StdTcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(text.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    fn body(response: &str) -> Value {
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        serde_json::from_str(body).unwrap()
+    }
+
+    #[test]
+    fn test_websocket_accept() {
+        // Example handshake from RFC 6455
+        assert_eq!(
+            websocket_accept("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+        assert_eq!(websocket_text_frame("hi"), vec![0x81, 2, b'h', b'i']);
+    }
+
+    #[test]
+    fn test_parse_request_head() {
+        let request = Request::parse_head(
+            "GET /traffic?token=a%20b&x HTTP/1.1\r\nUpgrade: WebSocket\r\nHost: localhost",
+        )
+        .unwrap();
+        assert_eq!(request.path, "/traffic");
+        assert_eq!(request.token(), Some("a b"));
+        assert!(request.is_websocket());
+        assert_eq!(request.header("host"), Some("localhost"));
+    }
+
+    #[test]
+    fn test_api_requests() {
+        let core = Arc::new(RwLock::new(VoyageCore::new(ProxyConfig::default())));
+        let server = ApiServer::start(Arc::clone(&core), 0, TOKEN.into()).unwrap();
+        let port = server.port();
+        let auth = format!("Authorization: Bearer {}", TOKEN);
+
+        let response = request(port, "GET /connections HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 401"));
+
+        let response = request(
+            port,
+            &format!("GET /connections HTTP/1.1\r\n{}\r\n\r\n", auth),
+        );
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert_eq!(body(&response)["connections"], json!([]));
+
+        let rules = "DOMAIN-SUFFIX,google.com,PROXY\nFINAL,DIRECT";
+        let response = request(
+            port,
+            &format!(
+                "PUT /rules HTTP/1.1\r\n{}\r\nContent-Length: {}\r\n\r\n{}",
+                auth,
+                rules.len(),
+                rules
+            ),
+        );
+        assert_eq!(body(&response)["rules"], 2);
+        assert_eq!(core.read().unwrap().proxy_manager().rule_count(), 2);
+
+        let get = |path: &str| request(port, &format!("GET {} HTTP/1.1\r\n{}\r\n\r\n", path, auth));
+        let response = body(&get("/match?host=www.google.com&port=443"));
+        assert_eq!(response["policy"], "PROXY");
+        assert_eq!(response["matched_rule"], "DOMAIN-SUFFIX,google.com");
+        assert_eq!(body(&get("/match?host=1.1.1.1"))["policy"], "DIRECT");
+        assert_eq!(
+            core.read()
+                .unwrap()
+                .proxy_manager()
+                .get_stats()
+                .proxied_connections,
+            0
+        );
+        assert_eq!(body(&get("/flows?limit=5"))["flows"], json!([]));
+
+        let mut runt = vec![0x45, 0x00];
+        assert!(core.write().unwrap().process_inbound(&mut runt).is_err());
+        let packets = &body(&get("/malformed"))["packets"];
+        assert_eq!(packets[0]["data"], "4500");
+        assert_eq!(packets[0]["length"], 2);
+
+        let switch = r#"{"enabled":false}"#;
+        let response = request(
+            port,
+            &format!(
+                "PUT /proxy?token={} HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+                TOKEN,
+                switch.len(),
+                switch
+            ),
+        );
+        assert_eq!(body(&response)["enabled"], false);
+        assert!(!core.read().unwrap().proxy_manager().is_enabled());
+
+        let response = request(
+            port,
+            &format!("DELETE /connections/7 HTTP/1.1\r\n{}\r\n\r\n", auth),
+        );
+        assert!(response.starts_with("HTTP/1.1 404"));
+        let response = request(port, &format!("POST /proxy HTTP/1.1\r\n{}\r\n\r\n", auth));
+        assert!(response.starts_with("HTTP/1.1 405"));
+        server.stop();
+    }
+}
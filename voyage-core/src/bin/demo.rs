@@ -2,21 +2,38 @@
 //!
 //! This demonstrates the voyage-core functionality on Windows,
 //! simulating packet processing without the iOS Network Extension.
+//!
+//! On Linux, `demo --redirect <port> [rules-file]` instead runs the rules
+//! against real connections diverted by an iptables REDIRECT rule:
+//!
+//! ```text
+//! sudo iptables -t nat -A OUTPUT -p tcp --dport 443 \
+//!     -m owner ! --uid-owner $(id -u) -j REDIRECT --to-ports <port>
+//! ```
 
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, RwLock};
 
 use voyage_core::config::ProxyConfig;
 use voyage_core::connection::ConnectionManager;
 use voyage_core::device::VirtualTunDevice;
+use voyage_core::inbound::InboundServer;
 use voyage_core::nat::{NatKey, NatManager};
 use voyage_core::packet::ParsedPacket;
 use voyage_core::proxy::ProxyManager;
-use voyage_core::rule::RuleEngine;
+use voyage_core::rule::{MatchContext, RuleEngine};
+use voyage_core::VoyageCore;
 
 fn main() {
     // Initialize logging
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("--redirect") {
+        run_redirect(&args[1..]);
+        return;
+    }
+
     println!("=== Voyage Core Demo ===\n");
 
     // Demo 1: Packet Parsing
@@ -105,7 +122,7 @@ FINAL, DIRECT
     ];
 
     for (domain, ip) in test_cases {
-        let action = engine.evaluate(Some(domain), ip, 443, 0);
+        let action = engine.evaluate(&MatchContext::new(Some(domain), ip, 443));
         println!("  {} -> {:?}", domain, action);
     }
     println!();
@@ -119,6 +136,7 @@ fn demo_proxy_manager() {
         server_port: 1080,
         username: Some("user".into()),
         password: Some("secret".into()),
+        ..Default::default()
     });
 
     manager
@@ -136,7 +154,7 @@ FINAL, DIRECT
     // Evaluate some routes
     let domains = ["www.google.com", "example.com", "mail.google.com"];
     for domain in domains {
-        let decision = manager.evaluate_route(Some(domain), None, 443, 0);
+        let decision = manager.evaluate_route(&MatchContext::new(Some(domain), None, 443));
         println!("  {} -> {:?}", domain, decision.action);
     }
 
@@ -159,6 +177,7 @@ fn demo_full_pipeline() {
         server_port: 1080,
         username: None,
         password: None,
+        ..Default::default()
     });
 
     proxy_manager
@@ -175,12 +194,11 @@ fn demo_full_pipeline() {
     for (i, packet) in packets.iter().enumerate() {
         let parsed = ParsedPacket::parse(packet).unwrap();
         let _conn_info = conn_manager.process_packet(&parsed).unwrap();
-        let decision = proxy_manager.evaluate_route(
+        let decision = proxy_manager.evaluate_route(&MatchContext::new(
             None,
             parsed.dst_addr().map(|a| a.ip()),
             parsed.tcp.as_ref().unwrap().dst_port,
-            0,
-        );
+        ));
 
         println!(
             "  Packet {}: {:?}:{} -> {:?}",
@@ -195,6 +213,54 @@ fn demo_full_pipeline() {
     println!();
 }
 
+/// Relay REDIRECTed connections by the given rules until killed
+fn run_redirect(args: &[String]) {
+    let port: u16 = match args.first().and_then(|port| port.parse().ok()) {
+        Some(port) => port,
+        None => {
+            eprintln!("Usage: demo --redirect <port> [rules-file]");
+            std::process::exit(2);
+        }
+    };
+
+    let core = VoyageCore::new(ProxyConfig::default());
+    if let Some(path) = args.get(1) {
+        let rules = std::fs::read_to_string(path).unwrap_or_else(|e| {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(1);
+        });
+        match core.load_rules(&rules) {
+            Ok(count) => println!("Loaded {} rules from {}", count, path),
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let core = Arc::new(RwLock::new(core));
+    let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, port));
+    let _server = match InboundServer::start_redirect(Arc::clone(&core), addr) {
+        Ok(server) => server,
+        Err(e) => {
+            eprintln!("Cannot listen on {}: {}", addr, e);
+            std::process::exit(1);
+        }
+    };
+    println!("Relaying redirected connections on {}; Ctrl-C to stop", addr);
+
+    loop {
+        std::thread::sleep(std::time::Duration::from_secs(10));
+        if let Ok(core) = core.read() {
+            let stats = core.proxy_manager().get_stats().clone();
+            println!(
+                "  direct={} proxied={} rejected={}",
+                stats.direct_connections, stats.proxied_connections, stats.rejected_connections
+            );
+        }
+    }
+}
+
 fn create_tcp_syn_packet(src_port: u16, dst_port: u16, src_ip: [u8; 4], dst_ip: [u8; 4]) -> Vec<u8> {
     let mut packet = vec![0u8; 40];
 
@@ -0,0 +1,293 @@
+//! Command-line control tool
+//!
+//! Talks to a running core through its localhost HTTP API (see
+//! `start_api_server`) to list connections, tail closed flows, test rules
+//! and reload configurations.
+
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::ExitCode;
+use std::thread;
+use std::time::Duration;
+
+use serde_json::Value;
+use voyage_core::api::DEFAULT_API_PORT;
+
+const USAGE: &str = "\
+Usage: voyagectl [--port PORT] [--token TOKEN] <command>
+
+Commands:
+  connections            List live connections
+  close <id>             Close a connection
+  flows [-f] [-n N]      Print the last N closed flows as JSON lines;
+                         -f keeps printing new ones
+  traffic                Print upload/download rates every second
+  match <host[:port]>    Show the policy the rules pick for a destination
+  rules <file>           Replace the rules with the rules in a file
+  reload <file>          Reload a YAML, TOML or JSON configuration file
+  proxy <on|off>         Enable or disable the proxy
+
+The port and token default to $VOYAGE_API_PORT and $VOYAGE_API_TOKEN.";
+
+/// How often `flows -f` polls for new flows
+const FOLLOW_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Closed flows fetched per poll
+const DEFAULT_FLOW_COUNT: usize = 20;
+
+fn main() -> ExitCode {
+    match run(env::args().skip(1).collect()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("voyagectl: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Connection settings for the API
+struct Client {
+    port: u16,
+    token: String,
+}
+
+fn run(args: Vec<String>) -> Result<(), String> {
+    let mut port = env::var("VOYAGE_API_PORT")
+        .ok()
+        .and_then(|port| port.parse().ok())
+        .unwrap_or(DEFAULT_API_PORT);
+    let mut token = env::var("VOYAGE_API_TOKEN").unwrap_or_default();
+
+    let mut args = args.into_iter();
+    let mut command = Vec::new();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--port" => {
+                port = args
+                    .next()
+                    .and_then(|port| port.parse().ok())
+                    .ok_or("--port needs a port number")?;
+            }
+            "--token" => token = args.next().ok_or("--token needs a value")?,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(());
+            }
+            _ => command.push(arg),
+        }
+    }
+    let client = Client { port, token };
+
+    let command: Vec<&str> = command.iter().map(String::as_str).collect();
+    match command.as_slice() {
+        ["connections"] => connections(&client),
+        ["close", id] => {
+            client.request("DELETE", &format!("/connections/{}", id), None)?;
+            Ok(())
+        }
+        ["flows", options @ ..] => flows(&client, options),
+        ["traffic"] => client.stream("/traffic"),
+        ["match", target] => match_route(&client, target),
+        ["rules", file] => {
+            let response = client.request("PUT", "/rules", Some(&read_file(file)?))?;
+            println!("Loaded {} rules", response["rules"]);
+            Ok(())
+        }
+        ["reload", file] => {
+            let response = client.request("PUT", "/config", Some(&read_file(file)?))?;
+            let changed = response["changed"].as_array().cloned().unwrap_or_default();
+            if changed.is_empty() {
+                println!("No changes");
+            } else {
+                let names: Vec<&str> = changed.iter().filter_map(Value::as_str).collect();
+                println!("Reloaded: {}", names.join(", "));
+            }
+            Ok(())
+        }
+        ["proxy", state @ ("on" | "off")] => {
+            let body = format!("{{\"enabled\":{}}}", *state == "on");
+            client.request("PUT", "/proxy", Some(&body))?;
+            Ok(())
+        }
+        _ => Err(format!("unknown command\n\n{}", USAGE)),
+    }
+}
+
+fn read_file(path: &str) -> Result<String, String> {
+    fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn connections(client: &Client) -> Result<(), String> {
+    let response = client.request("GET", "/connections", None)?;
+    let connections = response["connections"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+    println!(
+        "{:>6}  {:<5} {:<22} {:<22} {:<30} {:<7} {:>10} {:>10}",
+        "ID", "PROTO", "SOURCE", "DESTINATION", "DOMAIN", "POLICY", "SENT", "RECEIVED"
+    );
+    for c in &connections {
+        let protocol = match c["protocol"].as_u64() {
+            Some(6) => "tcp",
+            Some(17) => "udp",
+            _ => "icmp",
+        };
+        println!(
+            "{:>6}  {:<5} {:<22} {:<22} {:<30} {:<7} {:>10} {:>10}",
+            c["id"],
+            protocol,
+            c["src"].as_str().unwrap_or("-"),
+            c["dst"].as_str().unwrap_or("-"),
+            c["domain"].as_str().unwrap_or("-"),
+            c["policy"].as_str().unwrap_or("-"),
+            c["bytes_sent"],
+            c["bytes_received"]
+        );
+    }
+    Ok(())
+}
+
+fn flows(client: &Client, options: &[&str]) -> Result<(), String> {
+    let mut follow = false;
+    let mut count = DEFAULT_FLOW_COUNT;
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match *option {
+            "-f" | "--follow" => follow = true,
+            "-n" => {
+                count = options
+                    .next()
+                    .and_then(|n| n.parse().ok())
+                    .ok_or("-n needs a number")?;
+            }
+            other => return Err(format!("unknown flows option: {}", other)),
+        }
+    }
+
+    // Each poll returns the newest flows; anything not in the previous
+    // answer is new
+    let mut seen = HashSet::new();
+    loop {
+        let response = client.request("GET", &format!("/flows?limit={}", count), None)?;
+        let lines: Vec<String> = response["flows"]
+            .as_array()
+            .map(|flows| flows.iter().map(Value::to_string).collect())
+            .unwrap_or_default();
+        for line in lines.iter().rev().filter(|line| !seen.contains(*line)) {
+            println!("{}", line);
+        }
+        if !follow {
+            return Ok(());
+        }
+        seen = lines.into_iter().collect();
+        thread::sleep(FOLLOW_INTERVAL);
+    }
+}
+
+fn match_route(client: &Client, target: &str) -> Result<(), String> {
+    let (host, port) = match target.rsplit_once(':') {
+        // A bare IPv6 address has colons but no port
+        Some((host, port)) if !host.contains(':') || host.ends_with(']') => (host, port),
+        _ => (target, "443"),
+    };
+    let response = client.request("GET", &format!("/match?host={}&port={}", host, port), None)?;
+    println!("{}", response["policy"].as_str().unwrap_or("-"));
+    if let Some(rule) = response["matched_rule"].as_str() {
+        println!("  rule: {}", rule);
+    }
+    if let Some(domain) = response["domain"].as_str().filter(|domain| *domain != host) {
+        println!("  domain: {}", domain);
+    }
+    Ok(())
+}
+
+impl Client {
+    fn connect(&self) -> Result<TcpStream, String> {
+        TcpStream::connect(("127.0.0.1", self.port))
+            .map_err(|e| format!("cannot reach the API on port {}: {}", self.port, e))
+    }
+
+    fn head(&self, method: &str, path: &str, body: Option<&str>) -> String {
+        format!(
+            "{} {} HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {}\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n",
+            method,
+            path,
+            self.token,
+            body.map_or(0, str::len)
+        )
+    }
+
+    /// Send a request and return its JSON answer, or the API's error
+    fn request(&self, method: &str, path: &str, body: Option<&str>) -> Result<Value, String> {
+        let mut stream = self.connect()?;
+        let request = self.head(method, path, body) + body.unwrap_or_default();
+        stream
+            .write_all(request.as_bytes())
+            .map_err(|e| e.to_string())?;
+
+        let mut response = String::new();
+        stream
+            .read_to_string(&mut response)
+            .map_err(|e| e.to_string())?;
+        let (head, body) = response
+            .split_once("\r\n\r\n")
+            .ok_or("malformed response from the API")?;
+        let status: u16 = head
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .ok_or("malformed response from the API")?;
+        let value: Value = if body.is_empty() {
+            Value::Null
+        } else {
+            serde_json::from_str(body).map_err(|e| e.to_string())?
+        };
+        if status >= 400 {
+            return Err(value["error"]
+                .as_str()
+                .map_or_else(|| format!("API answered {}", status), String::from));
+        }
+        Ok(value)
+    }
+
+    /// Print each chunk of a chunked stream until the API closes it
+    fn stream(&self, path: &str) -> Result<(), String> {
+        let mut stream = self.connect()?;
+        stream
+            .write_all(self.head("GET", path, None).as_bytes())
+            .map_err(|e| e.to_string())?;
+        let mut reader = BufReader::new(stream);
+
+        let mut line = String::new();
+        reader.read_line(&mut line).map_err(|e| e.to_string())?;
+        if !line.contains(" 200 ") {
+            return Err(format!("API answered {}", line.trim()));
+        }
+        loop {
+            line.clear();
+            reader.read_line(&mut line).map_err(|e| e.to_string())?;
+            if line == "\r\n" || line.is_empty() {
+                break;
+            }
+        }
+
+        loop {
+            line.clear();
+            if reader.read_line(&mut line).map_err(|e| e.to_string())? == 0 {
+                return Ok(());
+            }
+            let size = usize::from_str_radix(line.trim(), 16).map_err(|e| e.to_string())?;
+            if size == 0 {
+                return Ok(());
+            }
+            let mut chunk = vec![0u8; size + 2];
+            reader.read_exact(&mut chunk).map_err(|e| e.to_string())?;
+            print!("{}", String::from_utf8_lossy(&chunk[..size]));
+        }
+    }
+}
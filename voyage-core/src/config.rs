@@ -1,12 +1,667 @@
 //! Configuration types for Voyage Core
 
-/// Proxy server configuration
-#[derive(Debug, Clone)]
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::device::MTU;
+use crate::fakeip::{Ipv4Range, DEFAULT_FAKE_IP_RANGE, FALLBACK_FAKE_IP_RANGES};
+use crate::hosts::HostEntry;
+use crate::nat::{NatMode, NatTimeouts, PortStrategy};
+use crate::secret::{SecretString, REDACTED};
+
+/// Default smoltcp TCP socket buffer size (also bounds the advertised window)
+pub const DEFAULT_TCP_BUFFER_SIZE: usize = 65536;
+
+/// Default delayed-ACK timeout used by smoltcp
+pub const DEFAULT_ACK_DELAY_MS: u64 = 10;
+
+/// Socket buffer sizes for flows to some destination ports, so bulk
+/// transfers get large windows while the many small flows of an app stay
+/// cheap
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BufferTier {
+    /// Destination ports the sizes apply to
+    pub ports: Vec<u16>,
+    /// Receive buffer size in bytes
+    pub rx_buffer_size: usize,
+    /// Send buffer size in bytes
+    pub tx_buffer_size: usize,
+}
+
+/// Tuning knobs applied to the smoltcp TCP sockets that terminate app flows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TcpConfig {
+    /// Enable Nagle's algorithm (coalesce small writes)
+    pub nagle_enabled: bool,
+    /// Delayed-ACK timeout in milliseconds (`None` acks immediately)
+    pub ack_delay_ms: Option<u64>,
+    /// Receive buffer size in bytes; sets the window advertised to the app
+    pub rx_buffer_size: usize,
+    /// Send buffer size in bytes; bounds in-flight data towards the app
+    pub tx_buffer_size: usize,
+    /// Sizes replacing the two above for flows to the listed ports; the
+    /// first tier listing a port wins
+    pub buffer_tiers: Vec<BufferTier>,
+}
+
+impl TcpConfig {
+    /// Delayed-ACK timeout as a smoltcp duration
+    pub fn ack_delay(&self) -> Option<smoltcp::time::Duration> {
+        self.ack_delay_ms.map(smoltcp::time::Duration::from_millis)
+    }
+
+    /// Receive and send buffer sizes of a flow to `port`, or of a socket
+    /// not yet bound to a flow
+    pub fn buffer_sizes(&self, port: Option<u16>) -> (usize, usize) {
+        port.and_then(|port| {
+            self.buffer_tiers
+                .iter()
+                .find(|tier| tier.ports.contains(&port))
+        })
+        .map_or((self.rx_buffer_size, self.tx_buffer_size), |tier| {
+            (tier.rx_buffer_size, tier.tx_buffer_size)
+        })
+    }
+
+    /// Set both socket buffers to the same size
+    pub fn with_window(mut self, size: usize) -> Self {
+        self.rx_buffer_size = size;
+        self.tx_buffer_size = size;
+        self
+    }
+
+    /// Disable Nagle and delayed ACKs for latency-sensitive traffic
+    pub fn nodelay(mut self) -> Self {
+        self.nagle_enabled = false;
+        self.ack_delay_ms = None;
+        self
+    }
+}
+
+impl Default for TcpConfig {
+    fn default() -> Self {
+        Self {
+            nagle_enabled: true,
+            ack_delay_ms: Some(DEFAULT_ACK_DELAY_MS),
+            rx_buffer_size: DEFAULT_TCP_BUFFER_SIZE,
+            tx_buffer_size: DEFAULT_TCP_BUFFER_SIZE,
+            buffer_tiers: Vec::new(),
+        }
+    }
+}
+
+/// IPv4 + TCP header bytes not counted in the MSS
+const IPV4_TCP_HEADERS: u16 = 40;
+/// IPv6 + TCP header bytes not counted in the MSS
+const IPV6_TCP_HEADERS: u16 = 60;
+
+/// MSS clamping for traffic that is re-encapsulated through the proxy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MssClampConfig {
+    /// MTU of the path the proxied traffic leaves on
+    pub mtu: u16,
+    /// Bytes of per-packet overhead added by the proxy encapsulation
+    pub overhead: u16,
+}
+
+impl MssClampConfig {
+    pub fn new(mtu: u16, overhead: u16) -> Self {
+        Self { mtu, overhead }
+    }
+
+    /// MTU left for the inner packet after encapsulation
+    pub fn effective_mtu(&self) -> u16 {
+        self.mtu.saturating_sub(self.overhead)
+    }
+
+    /// Largest MSS that fits in the effective MTU
+    pub fn max_mss(&self, ipv6: bool) -> u16 {
+        let headers = if ipv6 { IPV6_TCP_HEADERS } else { IPV4_TCP_HEADERS };
+        self.effective_mtu().saturating_sub(headers)
+    }
+}
+
+/// Default depth of each device packet queue
+pub const DEFAULT_QUEUE_DEPTH: usize = 1024;
+
+/// Which packet to drop when a device queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DropPolicy {
+    /// Drop the arriving packet
+    #[default]
+    TailDrop,
+    /// Drop the oldest queued packet to make room
+    HeadDrop,
+}
+
+/// Limits of the device packet queues
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueConfig {
+    /// Packets held per direction before dropping
+    pub max_depth: usize,
+    /// What to drop once `max_depth` is reached
+    pub policy: DropPolicy,
+    /// Depth at which the watermark callback fires
+    pub high_watermark: usize,
+}
+
+impl QueueConfig {
+    /// Queues of `max_depth` packets warning at three quarters full
+    pub fn with_depth(max_depth: usize) -> Self {
+        Self {
+            max_depth,
+            policy: DropPolicy::default(),
+            high_watermark: max_depth - max_depth / 4,
+        }
+    }
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self::with_depth(DEFAULT_QUEUE_DEPTH)
+    }
+}
+
+/// Whether the device checks checksums of packets from the app
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChecksumMode {
+    /// Drop and count packets with a bad IPv4, TCP or UDP checksum
+    #[default]
+    Verify,
+    /// Trust the host's checksums, saving the work at high packet rates
+    Skip,
+}
+
+/// Fake-IP range selection for synthesized DNS answers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FakeIpConfig {
+    /// Preferred range
+    pub range: Ipv4Range,
+    /// Alternates tried in order if `range` overlaps a local network
+    pub fallback_ranges: Vec<Ipv4Range>,
+}
+
+impl FakeIpConfig {
+    pub fn new(range: Ipv4Range) -> Self {
+        Self {
+            range,
+            ..Default::default()
+        }
+    }
+}
+
+impl Default for FakeIpConfig {
+    fn default() -> Self {
+        Self {
+            range: DEFAULT_FAKE_IP_RANGE.parse().unwrap(),
+            fallback_ranges: FALLBACK_FAKE_IP_RANGES
+                .iter()
+                .map(|r| r.parse().unwrap())
+                .collect(),
+        }
+    }
+}
+
+/// Most addresses the virtual interface can hold
+pub const MAX_INTERFACE_ADDRESSES: usize = 2;
+
+/// Smallest MTU IPv6 allows on a link
+pub const MIN_IPV6_MTU: usize = 1280;
+
+/// Smallest MTU every IPv4 host must accept
+pub const MIN_IPV4_MTU: usize = 576;
+
+/// An address of the virtual interface with its prefix length
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterfaceAddress {
+    pub addr: IpAddr,
+    pub prefix_len: u8,
+}
+
+impl InterfaceAddress {
+    pub fn new(addr: IpAddr, prefix_len: u8) -> Self {
+        Self { addr, prefix_len }
+    }
+}
+
+impl FromStr for InterfaceAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ip, prefix) = s
+            .trim()
+            .split_once('/')
+            .ok_or_else(|| format!("Invalid CIDR format: {}", s))?;
+        let addr = IpAddr::from_str(ip.trim()).map_err(|_| format!("Invalid IP: {}", ip))?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len: u8 = prefix
+            .trim()
+            .parse()
+            .ok()
+            .filter(|prefix| *prefix <= max_prefix)
+            .ok_or_else(|| format!("Invalid prefix: {}", prefix))?;
+        Ok(Self::new(addr, prefix_len))
+    }
+}
+
+impl fmt::Display for InterfaceAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Addressing of the virtual interface, as negotiated by the tunnel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceConfig {
+    /// Interface addresses (IPv4 and/or IPv6)
+    pub addresses: Vec<InterfaceAddress>,
+    /// Default IPv4 gateway
+    pub gateway_v4: Option<Ipv4Addr>,
+    /// Default IPv6 gateway
+    pub gateway_v6: Option<Ipv6Addr>,
+    /// Link MTU
+    pub mtu: usize,
+    /// Accept packets to any destination rather than only the interface's
+    /// own networks, as terminating intercepted flows requires
+    pub any_ip: bool,
+}
+
+impl InterfaceConfig {
+    /// Check the settings can be applied to the interface
+    pub fn validate(&self) -> Result<(), String> {
+        if self.addresses.is_empty() {
+            return Err("Interface needs at least one address".into());
+        }
+        if self.addresses.len() > MAX_INTERFACE_ADDRESSES {
+            return Err(format!(
+                "Interface supports at most {} addresses",
+                MAX_INTERFACE_ADDRESSES
+            ));
+        }
+        for address in &self.addresses {
+            let unicast = match address.addr {
+                IpAddr::V4(ip) => !(ip.is_unspecified() || ip.is_broadcast() || ip.is_multicast()),
+                IpAddr::V6(ip) => !(ip.is_unspecified() || ip.is_multicast()),
+            };
+            if !unicast {
+                return Err(format!("Not a unicast address: {}", address.addr));
+            }
+        }
+        let has_v4 = self.addresses.iter().any(|a| a.addr.is_ipv4());
+        let has_v6 = self.addresses.iter().any(|a| a.addr.is_ipv6());
+        if self.gateway_v4.is_some() && !has_v4 {
+            return Err("IPv4 gateway set without an IPv4 address".into());
+        }
+        if self.gateway_v6.is_some() && !has_v6 {
+            return Err("IPv6 gateway set without an IPv6 address".into());
+        }
+        let min_mtu = if has_v6 { MIN_IPV6_MTU } else { MIN_IPV4_MTU };
+        if self.mtu < min_mtu || self.mtu > u16::MAX as usize {
+            return Err(format!("Invalid MTU: {}", self.mtu));
+        }
+        Ok(())
+    }
+}
+
+impl Default for InterfaceConfig {
+    fn default() -> Self {
+        Self {
+            addresses: vec![
+                InterfaceAddress::new(Ipv4Addr::new(10, 0, 0, 1).into(), 24),
+                InterfaceAddress::new(Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1).into(), 64),
+            ],
+            gateway_v4: None,
+            gateway_v6: None,
+            mtu: MTU,
+            any_ip: true,
+        }
+    }
+}
+
+/// Default NAT table capacity
+pub const DEFAULT_NAT_MAX_ENTRIES: usize = 65535;
+
+/// Default interval between background cleanup passes
+pub const DEFAULT_CLEANUP_INTERVAL_MS: u64 = 5000;
+
+/// Default number of closed flows kept for the recent activity view
+pub const DEFAULT_HISTORY_SIZE: usize = 256;
+
+/// NAT table behaviour
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NatConfig {
+    /// UDP port mapping mode
+    pub udp_mode: NatMode,
+    /// How local ports are picked for new mappings
+    pub port_strategy: PortStrategy,
+    /// Table capacity; the least recently active entry is evicted when full
+    pub max_entries: usize,
+    /// Maximum entries per source IP (unlimited when `None`)
+    pub max_per_source: Option<usize>,
+    /// Idle timeouts per flow state
+    pub timeouts: NatTimeouts,
+    /// Interval of the background cleanup task in milliseconds
+    pub cleanup_interval_ms: u64,
+    /// Closed flows kept for the recent activity view
+    pub history_size: usize,
+}
+
+impl NatConfig {
+    /// Interval of the background cleanup task
+    pub fn cleanup_interval(&self) -> Duration {
+        Duration::from_millis(self.cleanup_interval_ms)
+    }
+}
+
+impl Default for NatConfig {
+    fn default() -> Self {
+        Self {
+            udp_mode: NatMode::default(),
+            port_strategy: PortStrategy::default(),
+            max_entries: DEFAULT_NAT_MAX_ENTRIES,
+            max_per_source: None,
+            timeouts: NatTimeouts::default(),
+            cleanup_interval_ms: DEFAULT_CLEANUP_INTERVAL_MS,
+            history_size: DEFAULT_HISTORY_SIZE,
+        }
+    }
+}
+
+/// Default cap on concurrently tracked connections
+pub const DEFAULT_MAX_CONNECTIONS: usize = 4096;
+
+/// Estimated bookkeeping per flow (NAT entry, lookup maps, stats) on top
+/// of its socket buffers
+pub const FLOW_OVERHEAD_BYTES: usize = 1024;
+
+/// Caps keeping the core inside the host's memory limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResourceLimits {
+    /// Maximum concurrently tracked connections
+    pub max_connections: usize,
+    /// Estimated bytes all flows together may use (unlimited when 0)
+    pub memory_budget: usize,
+}
+
+impl Default for ResourceLimits {
+    fn default() -> Self {
+        Self {
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            memory_budget: 0,
+        }
+    }
+}
+
+/// Default time a connection waits for a concurrency slot
+pub const DEFAULT_QUEUE_TIMEOUT_MS: u64 = 10_000;
+
+/// What happens to a connection that would exceed a concurrency cap
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ExcessPolicy {
+    /// Hold TCP connections until a slot frees up; the app keeps
+    /// retransmitting its SYN meanwhile. UDP flows cannot wait and are
+    /// rejected.
+    #[default]
+    Queue,
+    /// Reject the connection at once
+    Reject,
+}
+
+/// Caps on simultaneous connections, protecting the upstream proxy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConcurrencyLimits {
+    /// Maximum simultaneous proxied connections (unlimited when 0)
+    pub max_proxied: usize,
+    /// Maximum simultaneous connections to one destination host, by name
+    /// when known and by address otherwise (unlimited when 0)
+    pub max_per_destination: usize,
+    /// What happens to connections over a cap
+    pub excess: ExcessPolicy,
+    /// Longest a queued connection waits before it is rejected
+    pub queue_timeout_ms: u64,
+}
+
+impl ConcurrencyLimits {
+    /// Longest a queued connection waits
+    pub fn queue_timeout(&self) -> Duration {
+        Duration::from_millis(self.queue_timeout_ms)
+    }
+}
+
+impl Default for ConcurrencyLimits {
+    fn default() -> Self {
+        Self {
+            max_proxied: 0,
+            max_per_destination: 0,
+            excess: ExcessPolicy::default(),
+            queue_timeout_ms: DEFAULT_QUEUE_TIMEOUT_MS,
+        }
+    }
+}
+
+/// Default time a source over its connection rate stays blocked
+pub const DEFAULT_RATE_BLOCK_SECS: u64 = 10;
+
+/// Caps on how fast new connections may be opened, protecting the NAT
+/// table and the socket set from an app opening connections in a loop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionRateLimits {
+    /// New connections per second from one source IP (unlimited when 0)
+    pub per_source: u32,
+    /// New connections per second from all sources (unlimited when 0)
+    pub total: u32,
+    /// How long a source over its rate has all new connections dropped
+    /// (not blocked when 0)
+    pub block_secs: u64,
+}
+
+impl ConnectionRateLimits {
+    /// How long a source over its rate is blocked
+    pub fn block_duration(&self) -> Duration {
+        Duration::from_secs(self.block_secs)
+    }
+}
+
+impl Default for ConnectionRateLimits {
+    fn default() -> Self {
+        Self {
+            per_source: 0,
+            total: 0,
+            block_secs: DEFAULT_RATE_BLOCK_SECS,
+        }
+    }
+}
+
+/// Default idle time before the first keep-alive probe
+pub const DEFAULT_KEEPALIVE_IDLE_SECS: u64 = 60;
+
+/// Default time between unanswered keep-alive probes
+pub const DEFAULT_KEEPALIVE_INTERVAL_SECS: u64 = 15;
+
+/// Default number of unanswered keep-alive probes before giving up
+pub const DEFAULT_KEEPALIVE_PROBES: u32 = 4;
+
+/// Socket options of the real TCP connections the core opens, direct to a
+/// destination or to the proxy server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OutboundConfig {
+    /// Disable Nagle's algorithm
+    pub nodelay: bool,
+    /// Idle seconds before the first keep-alive probe (0 disables keep-alive)
+    pub keepalive_idle_secs: u64,
+    /// Seconds between unanswered keep-alive probes
+    pub keepalive_interval_secs: u64,
+    /// Unanswered probes before the connection is dropped
+    pub keepalive_probes: u32,
+    /// Carry the first data in the SYN (Linux only; ignored elsewhere)
+    pub fast_open: bool,
+    /// Seconds without traffic after which a relayed TCP flow is closed on
+    /// both legs (0 disables). Independent of the NAT timeouts.
+    pub idle_timeout_secs: u64,
+}
+
+impl OutboundConfig {
+    /// Relay idle timeout, `None` when disabled
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        (self.idle_timeout_secs > 0).then(|| Duration::from_secs(self.idle_timeout_secs))
+    }
+}
+
+impl Default for OutboundConfig {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive_idle_secs: DEFAULT_KEEPALIVE_IDLE_SECS,
+            keepalive_interval_secs: DEFAULT_KEEPALIVE_INTERVAL_SECS,
+            keepalive_probes: DEFAULT_KEEPALIVE_PROBES,
+            fast_open: false,
+            idle_timeout_secs: 0,
+        }
+    }
+}
+
+/// Default upstream for names routed DIRECT
+pub const DEFAULT_DNS_UPSTREAM: &str = "1.1.1.1:53";
+
+/// Default resolver reached through the proxy for names routed PROXY
+pub const DEFAULT_PROXY_DNS_UPSTREAM: &str = "8.8.8.8:53";
+
+/// Default per-upstream DNS timeout
+pub const DEFAULT_DNS_TIMEOUT_MS: u64 = 2000;
+
+/// Default number of cached DNS answers
+pub const DEFAULT_DNS_CACHE_SIZE: usize = 1024;
+
+/// Default number of resolved addresses remembered for IP rule matching
+pub const DEFAULT_DOMAIN_MAP_SIZE: usize = 4096;
+
+/// Default number of answered DNS queries kept in the query log
+pub const DEFAULT_DNS_QUERY_LOG_SIZE: usize = 256;
+
+/// Built-in DNS forwarder settings
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsConfig {
+    /// Upstreams for DIRECT names, tried in order
+    pub upstreams: Vec<SocketAddr>,
+    /// Resolver queried over TCP through the proxy for PROXY names
+    pub proxy_upstream: SocketAddr,
+    /// Answer PROXY names with fake IPs instead of resolving them
+    pub fake_ip: bool,
+    /// Per-upstream timeout in milliseconds
+    pub timeout_ms: u64,
+    /// Maximum cached answers (0 disables the cache)
+    pub cache_size: usize,
+    /// Resolved addresses remembered so connections by IP match DOMAIN rules
+    pub domain_map_size: usize,
+    /// Static name to address mappings answered without an upstream
+    pub hosts: Vec<HostEntry>,
+    /// Answered queries kept in the query log (0 disables the log)
+    pub query_log_size: usize,
+    /// Domains of the local network. Names under them, like single-label
+    /// and `.local` names, go DIRECT unless a rule other than FINAL says
+    /// otherwise.
+    pub search_domains: Vec<String>,
+    /// Resolver for local names, in place of the DIRECT upstreams
+    pub local_upstream: Option<SocketAddr>,
+}
+
+impl DnsConfig {
+    /// Per-upstream timeout
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    /// Whether `name` belongs to the local network
+    pub fn is_local_name(&self, name: &str) -> bool {
+        crate::dns::is_local_name(name, &self.search_domains)
+    }
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        Self {
+            upstreams: vec![DEFAULT_DNS_UPSTREAM.parse().unwrap()],
+            proxy_upstream: DEFAULT_PROXY_DNS_UPSTREAM.parse().unwrap(),
+            fake_ip: true,
+            timeout_ms: DEFAULT_DNS_TIMEOUT_MS,
+            cache_size: DEFAULT_DNS_CACHE_SIZE,
+            domain_map_size: DEFAULT_DOMAIN_MAP_SIZE,
+            hosts: Vec::new(),
+            query_log_size: DEFAULT_DNS_QUERY_LOG_SIZE,
+            search_domains: Vec::new(),
+            local_upstream: None,
+        }
+    }
+}
+
+/// Protocol spoken to the upstream proxy server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ProxyProtocol {
+    #[default]
+    Socks5,
+}
+
+/// What happens to new flows while the engine drains before shutdown
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DrainPolicy {
+    /// Reset new TCP connections and drop new UDP flows
+    #[default]
+    Reset,
+    /// Let new flows out directly, bypassing the proxy
+    Direct,
+}
+
+/// Page sent to plain HTTP requests rejected by a rule. `{host}` and
+/// `{rule}` stand for the blocked host and the rule that matched.
+pub const DEFAULT_REJECT_PAGE: &str = "<!DOCTYPE html>
+<html><head><title>Blocked</title></head>
+<body><h1>Blocked</h1><p>The request to {host} was blocked by the rule {rule}.</p></body></html>
+";
+
+/// Proxy server configuration. `Debug` and `Display` leave out the
+/// credentials.
+#[derive(Clone)]
 pub struct ProxyConfig {
     pub server_host: String,
     pub server_port: u16,
     pub username: Option<String>,
-    pub password: Option<String>,
+    pub password: Option<SecretString>,
+    /// Protocol spoken to the server
+    pub protocol: ProxyProtocol,
+    /// smoltcp socket tuning
+    pub tcp: TcpConfig,
+    /// Device packet queue limits
+    pub queues: QueueConfig,
+    /// Checksum verification of inbound packets
+    pub checksum: ChecksumMode,
+    /// Rewrite MSS on forwarded SYN/SYN-ACK packets (disabled when `None`)
+    pub mss_clamp: Option<MssClampConfig>,
+    /// Fake-IP range for DNS answers
+    pub fake_ip: FakeIpConfig,
+    /// Built-in DNS forwarder
+    pub dns: DnsConfig,
+    /// NAT table behaviour
+    pub nat: NatConfig,
+    /// Connection and memory caps
+    pub limits: ResourceLimits,
+    /// Caps on simultaneous proxied and per-destination connections
+    pub concurrency: ConcurrencyLimits,
+    /// Caps on the rate of new connections
+    pub connection_rate: ConnectionRateLimits,
+    /// Virtual interface addressing
+    pub interface: InterfaceConfig,
+    /// Socket options of direct and proxy connections
+    pub outbound: OutboundConfig,
+    /// HTML template answered to rejected plain HTTP requests, see
+    /// `DEFAULT_REJECT_PAGE`
+    pub reject_page: String,
+    /// Treatment of new flows during `VoyageCore::begin_drain`
+    pub drain_policy: DrainPolicy,
+    /// Reject QUIC (UDP 443) flows routed to the proxy, so apps fall back
+    /// to TCP where sniffing and the proxy work
+    pub block_quic: bool,
 }
 
 impl ProxyConfig {
@@ -16,16 +671,124 @@ impl ProxyConfig {
             server_port: port,
             username: None,
             password: None,
+            protocol: ProxyProtocol::default(),
+            tcp: TcpConfig::default(),
+            queues: QueueConfig::default(),
+            checksum: ChecksumMode::default(),
+            mss_clamp: None,
+            fake_ip: FakeIpConfig::default(),
+            dns: DnsConfig::default(),
+            nat: NatConfig::default(),
+            limits: ResourceLimits::default(),
+            concurrency: ConcurrencyLimits::default(),
+            connection_rate: ConnectionRateLimits::default(),
+            interface: InterfaceConfig::default(),
+            outbound: OutboundConfig::default(),
+            reject_page: DEFAULT_REJECT_PAGE.into(),
+            drain_policy: DrainPolicy::default(),
+            block_quic: false,
+        }
+    }
+
+    /// Estimated memory of one flow: its socket buffers plus bookkeeping
+    pub fn flow_memory(&self) -> usize {
+        self.flow_memory_to(None)
+    }
+
+    /// Estimated memory of one flow to `port`, whose buffers may be sized
+    /// by a tier
+    pub fn flow_memory_to(&self, port: Option<u16>) -> usize {
+        let (rx_size, tx_size) = self.tcp.buffer_sizes(port);
+        rx_size + tx_size + FLOW_OVERHEAD_BYTES
+    }
+
+    /// Number of flows allowed by both the connection cap and the memory budget
+    pub fn connection_cap(&self) -> usize {
+        match self.limits.memory_budget {
+            0 => self.limits.max_connections,
+            budget => self.limits.max_connections.min(budget / self.flow_memory()),
         }
     }
 
     pub fn with_auth(mut self, username: impl Into<String>, password: impl Into<String>) -> Self {
         self.username = Some(username.into());
-        self.password = Some(password.into());
+        self.password = Some(SecretString::new(password));
+        self
+    }
+
+    pub fn with_tcp(mut self, tcp: TcpConfig) -> Self {
+        self.tcp = tcp;
+        self
+    }
+
+    pub fn with_mss_clamp(mut self, mtu: u16, overhead: u16) -> Self {
+        self.mss_clamp = Some(MssClampConfig::new(mtu, overhead));
+        self
+    }
+
+    pub fn with_fake_ip_range(mut self, range: Ipv4Range) -> Self {
+        self.fake_ip.range = range;
+        self
+    }
+
+    pub fn with_dns(mut self, dns: DnsConfig) -> Self {
+        self.dns = dns;
+        self
+    }
+
+    pub fn with_udp_nat_mode(mut self, mode: NatMode) -> Self {
+        self.nat.udp_mode = mode;
+        self
+    }
+
+    pub fn with_nat_port_strategy(mut self, strategy: PortStrategy) -> Self {
+        self.nat.port_strategy = strategy;
+        self
+    }
+
+    pub fn with_outbound(mut self, outbound: OutboundConfig) -> Self {
+        self.outbound = outbound;
         self
     }
 }
 
+impl fmt::Debug for ProxyConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProxyConfig")
+            .field("server_host", &self.server_host)
+            .field("server_port", &self.server_port)
+            .field("username", &self.username.as_ref().map(|_| REDACTED))
+            .field("password", &self.password)
+            .field("protocol", &self.protocol)
+            .field("tcp", &self.tcp)
+            .field("queues", &self.queues)
+            .field("checksum", &self.checksum)
+            .field("mss_clamp", &self.mss_clamp)
+            .field("fake_ip", &self.fake_ip)
+            .field("dns", &self.dns)
+            .field("nat", &self.nat)
+            .field("limits", &self.limits)
+            .field("concurrency", &self.concurrency)
+            .field("connection_rate", &self.connection_rate)
+            .field("interface", &self.interface)
+            .field("outbound", &self.outbound)
+            .field("reject_page", &self.reject_page)
+            .field("drain_policy", &self.drain_policy)
+            .field("block_quic", &self.block_quic)
+            .finish()
+    }
+}
+
+/// `host:port`, prefixed with `***@` when credentials are set
+impl fmt::Display for ProxyConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.username.is_some() || self.password.is_some() {
+            write!(f, "{}@", REDACTED)?;
+        }
+        write!(f, "{}:{}", self.server_host, self.server_port)
+    }
+}
+
 impl Default for ProxyConfig {
     fn default() -> Self {
         Self::new("127.0.0.1", 1080)
@@ -49,6 +812,93 @@ mod tests {
         let config = ProxyConfig::new("proxy.example.com", 8080)
             .with_auth("user", "pass");
         assert_eq!(config.username, Some("user".to_string()));
-        assert_eq!(config.password, Some("pass".to_string()));
+        assert_eq!(config.password, Some("pass".into()));
+    }
+
+    #[test]
+    fn test_proxy_config_redacts_credentials() {
+        let config = ProxyConfig::new("proxy.example.com", 8080).with_auth("alice", "hunter2");
+        let debug = format!("{:?}", config);
+        assert!(!debug.contains("alice") && !debug.contains("hunter2"), "{}", debug);
+        assert_eq!(config.to_string(), "***@proxy.example.com:8080");
+        assert_eq!(ProxyConfig::default().to_string(), "127.0.0.1:1080");
+    }
+
+    #[test]
+    fn test_tcp_config_defaults() {
+        let tcp = ProxyConfig::default().tcp;
+        assert!(tcp.nagle_enabled);
+        assert_eq!(tcp.ack_delay_ms, Some(DEFAULT_ACK_DELAY_MS));
+        assert_eq!(tcp.rx_buffer_size, DEFAULT_TCP_BUFFER_SIZE);
+    }
+
+    #[test]
+    fn test_tcp_buffer_tiers() {
+        let mut tcp = TcpConfig::default().with_window(4096);
+        tcp.buffer_tiers.push(BufferTier {
+            ports: vec![443, 8443],
+            rx_buffer_size: 1 << 18,
+            tx_buffer_size: 1 << 16,
+        });
+        assert_eq!(tcp.buffer_sizes(Some(443)), (1 << 18, 1 << 16));
+        assert_eq!(tcp.buffer_sizes(Some(5222)), (4096, 4096));
+        assert_eq!(tcp.buffer_sizes(None), (4096, 4096));
+    }
+
+    #[test]
+    fn test_tcp_config_nodelay_and_window() {
+        let tcp = TcpConfig::default().with_window(1 << 20).nodelay();
+        assert!(!tcp.nagle_enabled);
+        assert!(tcp.ack_delay().is_none());
+        assert_eq!(tcp.rx_buffer_size, 1 << 20);
+        assert_eq!(tcp.tx_buffer_size, 1 << 20);
+    }
+
+    #[test]
+    fn test_interface_config() {
+        let config = InterfaceConfig::default();
+        assert!(config.validate().is_ok());
+        assert_eq!(config.addresses[0].to_string(), "10.0.0.1/24");
+        assert_eq!(config.addresses[1].to_string(), "fd00::1/64");
+
+        let v6: InterfaceAddress = "fd00::2/64".parse().unwrap();
+        assert_eq!(v6.prefix_len, 64);
+        assert!("10.0.0.1/33".parse::<InterfaceAddress>().is_err());
+        assert!("fd00::2".parse::<InterfaceAddress>().is_err());
+
+        let mut config = InterfaceConfig {
+            addresses: vec![v6],
+            gateway_v4: Some(Ipv4Addr::new(10, 0, 0, 254)),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        config.gateway_v4 = None;
+        config.mtu = 1000;
+        assert!(config.validate().is_err());
+        config.mtu = 1280;
+        assert!(config.validate().is_ok());
+        config.addresses.push("0.0.0.0/0".parse().unwrap());
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_mss_clamp_config() {
+        let config = ProxyConfig::default().with_mss_clamp(1500, 100);
+        let clamp = config.mss_clamp.unwrap();
+        assert_eq!(clamp.effective_mtu(), 1400);
+        assert_eq!(clamp.max_mss(false), 1360);
+        assert_eq!(clamp.max_mss(true), 1340);
+        assert!(ProxyConfig::default().mss_clamp.is_none());
+    }
+
+    #[test]
+    fn test_fake_ip_config() {
+        let config = ProxyConfig::default();
+        assert_eq!(config.fake_ip.range.to_string(), DEFAULT_FAKE_IP_RANGE);
+        assert_eq!(config.fake_ip.fallback_ranges.len(), FALLBACK_FAKE_IP_RANGES.len());
+
+        let range: Ipv4Range = "10.233.0.0/16".parse().unwrap();
+        let config = config.with_fake_ip_range(range);
+        assert_eq!(config.fake_ip.range, range);
     }
 }
@@ -3,17 +3,44 @@
 //! This module provides the connection management layer that integrates
 //! the NAT manager with smoltcp interface to handle TCP/UDP connections.
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use smoltcp::iface::{SocketHandle, SocketSet};
-use smoltcp::socket::tcp::{Socket as TcpSocket, State as TcpState};
-use tokio::sync::Mutex;
+use serde::Serialize;
+use smoltcp::socket::tcp::State as TcpState;
+use smoltcp::socket::Socket;
+use tokio::sync::{broadcast, Mutex};
+use tokio::task::JoinHandle;
 
+use crate::config::{NatConfig, DEFAULT_HISTORY_SIZE};
 use crate::error::VoyageError;
-use crate::nat::{NatKey, NatManager, NatState};
-use crate::packet::ParsedPacket;
+use crate::event::{ConnectionEvent, ConnectionEventKind, EventBus};
+use crate::flowlog::FlowLogger;
+use crate::history::{CloseReason, ClosedConnection, ConnectionHistory};
+use crate::iface::{InterfaceManager, SocketStateChange};
+use crate::nat::{
+    FlowDirection, NatEntry, NatKey, NatManager, NatMode, NatState, NatTimeouts, PortStrategy,
+    DEFAULT_MAX_PORT, DEFAULT_MIN_PORT,
+};
+use crate::packet::{ParseErrorKind, ParsedPacket, TcpFlags};
+use crate::proxy::RoutingDecision;
+use crate::rate::RateMeter;
+use crate::rule::RouteAction;
+use crate::traffic::TrafficRecorder;
+use crate::usage::{UsageTable, DEFAULT_USAGE_ENTRIES};
+
+/// Most annotations a single connection carries
+pub const MAX_ANNOTATIONS: usize = 16;
+
+/// Longest annotation, name and value together, in bytes
+pub const MAX_ANNOTATION_SIZE: usize = 1024;
+
+/// Most new TCP flows waiting for a listening socket, and most accepted
+/// flows waiting for the relay layer; the oldest are dropped beyond it
+pub const MAX_PENDING_FLOWS: usize = 4096;
 
 /// Connection state combining NAT and socket state
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -39,6 +66,56 @@ impl From<NatState> for ConnectionState {
     }
 }
 
+/// Status of the outbound relay task serving a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RelayStatus {
+    /// No relay task started yet
+    Pending,
+    /// Relay task is copying data
+    Running,
+    /// Relay task finished normally
+    Finished,
+    /// Relay task failed or was aborted
+    Failed,
+}
+
+/// Full internal state of a single flow, for bug reports
+#[derive(Debug, Clone, Serialize)]
+pub struct FlowDump {
+    /// "tcp", "udp" or "icmp"
+    pub protocol: &'static str,
+    /// Original source address
+    pub src: String,
+    /// Original destination address
+    pub dst: String,
+    /// Hostname sniffed from the flow, if any
+    pub domain: Option<String>,
+    /// Local port allocated by NAT
+    pub local_port: u16,
+    /// NAT entry state
+    pub nat_state: String,
+    /// smoltcp socket state, if a socket is registered and visible
+    pub socket_state: Option<String>,
+    /// Outbound relay task status
+    pub relay_status: RelayStatus,
+    /// Bytes sent
+    pub bytes_sent: u64,
+    /// Bytes received
+    pub bytes_received: u64,
+    /// Milliseconds since the NAT entry was created
+    pub age_ms: u64,
+    /// Milliseconds since the last activity on the NAT entry
+    pub idle_ms: u64,
+    /// Bytes waiting in the smoltcp receive buffer
+    pub rx_buffered: Option<usize>,
+    /// Bytes waiting in the smoltcp send buffer
+    pub tx_buffered: Option<usize>,
+    /// Key/value metadata attached by the host app
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+}
+
 /// Information about an active connection
 #[derive(Debug, Clone)]
 pub struct ConnectionInfo {
@@ -54,8 +131,49 @@ pub struct ConnectionInfo {
     pub bytes_sent: u64,
     /// Bytes received
     pub bytes_received: u64,
-    /// Time connection was created
+    /// Time the NAT entry was created
     pub created_at: Instant,
+    /// Time of the last activity on the NAT entry
+    pub last_seen: Instant,
+    /// Current upload speed in bytes per second
+    pub upload_rate: u64,
+    /// Current download speed in bytes per second
+    pub download_rate: u64,
+    /// Routing decision, once the flow has been classified
+    pub route: Option<RoutingDecision>,
+    /// Key/value metadata attached by the host app
+    pub annotations: BTreeMap<String, String>,
+}
+
+impl ConnectionInfo {
+    /// Snapshot a NAT entry
+    pub fn from_entry(key: NatKey, entry: &NatEntry, socket_handle: Option<SocketHandle>) -> Self {
+        let now = Instant::now();
+        Self {
+            key,
+            local_port: entry.local_port,
+            socket_handle,
+            state: entry.state.into(),
+            bytes_sent: entry.bytes_sent,
+            bytes_received: entry.bytes_received,
+            created_at: entry.created_at,
+            last_seen: entry.last_seen,
+            upload_rate: entry.upload.rate(now),
+            download_rate: entry.download.rate(now),
+            route: entry.route.clone(),
+            annotations: entry.annotations.clone(),
+        }
+    }
+
+    /// Time since the connection was created
+    pub fn duration(&self) -> Duration {
+        self.created_at.elapsed()
+    }
+
+    /// Time since the last activity on the connection
+    pub fn idle_time(&self) -> Duration {
+        self.last_seen.elapsed()
+    }
 }
 
 /// Manages the mapping between app connections and proxy connections
@@ -66,24 +184,114 @@ pub struct ConnectionManager {
     socket_handles: HashMap<NatKey, SocketHandle>,
     /// Map from socket handle to NAT key (reverse lookup)
     handle_to_key: HashMap<SocketHandle, NatKey>,
+    /// Relay task status per connection
+    relay_status: HashMap<NatKey, RelayStatus>,
+    /// Outbound relay task per connection, used for liveness tracking
+    relay_tasks: HashMap<NatKey, JoinHandle<()>>,
+    /// Flows reaped because their relay task died
+    reaped_flows: u64,
+    /// Sockets of flows evicted from the NAT table, waiting to be closed
+    orphaned_handles: Vec<SocketHandle>,
+    /// Hostname sniffed from each flow's first data segment (`None` if
+    /// sniffing was attempted but found nothing)
+    sniffed_domains: HashMap<NatKey, Option<String>>,
+    /// Lifecycle events for subscribers
+    events: EventBus,
+    /// Recently closed flows
+    history: ConnectionHistory,
+    /// Where completed flows are logged, if anywhere
+    flow_log: Option<Arc<FlowLogger>>,
+    /// App identifier the host reported for each flow
+    app_ids: HashMap<NatKey, String>,
+    /// Traffic totals per source IP
+    usage_by_source: UsageTable<IpAddr>,
+    /// Traffic totals per app identifier
+    usage_by_app: UsageTable<String>,
+    /// Traffic totals per domain
+    usage_by_domain: UsageTable<String>,
+    /// Traffic totals per routing policy
+    usage_by_policy: UsageTable<RouteAction>,
     /// Total bytes sent
     total_bytes_sent: u64,
     /// Total bytes received
     total_bytes_received: u64,
+    /// Bucketed upload and download totals for charts
+    traffic: TrafficRecorder,
+    /// Upload speed across all flows
+    upload: RateMeter,
+    /// Download speed across all flows
+    download: RateMeter,
     /// Total connections created
     total_connections: u64,
+    /// Maximum flows tracked at once
+    connection_limit: usize,
+    /// New flows refused because `connection_limit` was reached
+    connection_limit_hits: u64,
+    /// New TCP flows still waiting for a listening socket
+    pending_listeners: Vec<NatKey>,
+    /// Flows whose socket completed the handshake, not yet picked up by
+    /// the relay layer
+    accepted: Vec<(NatKey, SocketHandle)>,
+    /// Time without traffic after which a relayed flow is closed
+    relay_idle_timeout: Option<Duration>,
 }
 
 impl ConnectionManager {
     /// Create a new connection manager
     pub fn new() -> Self {
+        Self::with_nat(NatManager::new(), DEFAULT_HISTORY_SIZE)
+    }
+
+    /// Create a connection manager with NAT settings from the config
+    pub fn with_nat_config(config: &NatConfig) -> Self {
+        let nat = NatManager::with_config(DEFAULT_MIN_PORT, DEFAULT_MAX_PORT, config.max_entries)
+            .with_udp_mode(config.udp_mode)
+            .with_port_strategy(config.port_strategy)
+            .with_max_per_source(config.max_per_source)
+            .with_timeouts(config.timeouts);
+        Self::with_nat(nat, config.history_size)
+    }
+
+    fn with_nat(nat: NatManager, history_size: usize) -> Self {
+        Self::with_parts(nat, history_size, EventBus::default(), TrafficRecorder::default())
+    }
+
+    /// Create one shard of a `ShardedConnections`, publishing to the
+    /// shared `events` and bucketing traffic from the origin of `traffic`
+    pub(crate) fn with_parts(
+        nat: NatManager,
+        history_size: usize,
+        events: EventBus,
+        traffic: TrafficRecorder,
+    ) -> Self {
         Self {
-            nat: NatManager::new(),
+            nat,
             socket_handles: HashMap::new(),
             handle_to_key: HashMap::new(),
+            relay_status: HashMap::new(),
+            relay_tasks: HashMap::new(),
+            reaped_flows: 0,
+            orphaned_handles: Vec::new(),
+            sniffed_domains: HashMap::new(),
+            events,
+            history: ConnectionHistory::new(history_size),
+            flow_log: None,
+            app_ids: HashMap::new(),
+            usage_by_source: UsageTable::new(DEFAULT_USAGE_ENTRIES),
+            usage_by_app: UsageTable::new(DEFAULT_USAGE_ENTRIES),
+            usage_by_domain: UsageTable::new(DEFAULT_USAGE_ENTRIES),
+            usage_by_policy: UsageTable::new(DEFAULT_USAGE_ENTRIES),
             total_bytes_sent: 0,
             total_bytes_received: 0,
+            traffic,
+            upload: RateMeter::default(),
+            download: RateMeter::default(),
             total_connections: 0,
+            connection_limit: usize::MAX,
+            connection_limit_hits: 0,
+            pending_listeners: Vec::new(),
+            accepted: Vec::new(),
+            relay_idle_timeout: None,
         }
     }
 
@@ -91,11 +299,35 @@ impl ConnectionManager {
     pub fn process_packet(&mut self, packet: &ParsedPacket) -> Result<ConnectionInfo, VoyageError> {
         let key = packet
             .to_nat_key()
-            .ok_or_else(|| VoyageError::InvalidPacket("Cannot create NAT key".into()))?;
+            .ok_or_else(|| VoyageError::packet(ParseErrorKind::NoFlow, 0))?;
 
         // Get or create NAT entry
-        let entry = self.nat.get_or_create(key)?;
-        let local_port = entry.local_port;
+        let is_new = self.nat.get(&key).is_none();
+        if is_new && self.nat.len() >= self.connection_limit {
+            self.connection_limit_hits += 1;
+            return Err(VoyageError::connection(format!(
+                "Connection limit of {} reached",
+                self.connection_limit
+            )));
+        }
+        self.nat.get_or_create(key)?;
+        self.forget_evicted();
+        if let Some(tcp) = &packet.tcp {
+            self.nat.on_flags(&key, tcp.flags, FlowDirection::Outbound);
+        }
+        let entry = self.nat.get_mut(&key).expect("entry was just created");
+        if packet.is_tcp_rst() {
+            entry.set_close_reason(CloseReason::Reset);
+        } else if packet.is_tcp_fin() {
+            entry.set_close_reason(CloseReason::AppClosed);
+        }
+        let entry = entry.clone();
+        if is_new {
+            self.usage_by_source.add_connection(&key.src_ip, Instant::now());
+            if key.is_tcp() && packet.is_tcp_syn() {
+                push_capped(&mut self.pending_listeners, key);
+            }
+        }
 
         // Track new connections
         if entry.state == NatState::SynSent && packet.is_tcp_syn() {
@@ -105,15 +337,80 @@ impl ConnectionManager {
         // Get socket handle if exists
         let socket_handle = self.socket_handles.get(&key).copied();
 
-        Ok(ConnectionInfo {
-            key,
-            local_port,
-            socket_handle,
-            state: entry.state.into(),
-            bytes_sent: entry.bytes_sent,
-            bytes_received: entry.bytes_received,
-            created_at: Instant::now(), // Approximate
-        })
+        Ok(ConnectionInfo::from_entry(key, &entry, socket_handle))
+    }
+
+    /// Account a UDP datagram from a remote on its way to the app.
+    ///
+    /// The sender is matched through the NAT mapping: a full-cone source
+    /// takes datagrams from any remote, opening a flow for a new one, while
+    /// a symmetric flow only takes them from its destination.
+    pub fn process_reply(&mut self, packet: &ParsedPacket) -> Result<ConnectionInfo, VoyageError> {
+        let reply = packet
+            .to_nat_key()
+            .filter(NatKey::is_udp)
+            .ok_or_else(|| VoyageError::packet(ParseErrorKind::NoFlow, 0))?;
+        let (remote, app) = (reply.src_addr(), reply.dst_addr());
+        let key = self
+            .nat
+            .reply_port(app, remote)
+            .and_then(|port| self.nat.inbound_key(port, remote))
+            .ok_or_else(|| VoyageError::nat(format!("No mapping admits {} to {}", remote, app)))?;
+        if !self.contains(&key) {
+            self.nat.get_or_create(key)?;
+            self.forget_evicted();
+        }
+        self.add_bytes_received(&key, packet.ip.total_len as u64);
+
+        let entry = self.nat.get(&key).expect("entry was just looked up");
+        Ok(ConnectionInfo::from_entry(key, entry, self.get_socket_handle(&key)))
+    }
+
+    /// Change the NAT idle timeouts
+    pub fn set_nat_timeouts(&mut self, timeouts: NatTimeouts) {
+        self.nat.set_timeouts(timeouts);
+    }
+
+    /// Get the NAT idle timeouts
+    pub fn nat_timeouts(&self) -> NatTimeouts {
+        self.nat.timeouts()
+    }
+
+    /// Close relayed flows after `timeout` without traffic (`None` disables)
+    pub fn set_relay_idle_timeout(&mut self, timeout: Option<Duration>) {
+        self.relay_idle_timeout = timeout;
+    }
+
+    /// Change the UDP mapping mode for new flows
+    pub fn set_udp_nat_mode(&mut self, mode: NatMode) {
+        self.nat.set_udp_mode(mode);
+    }
+
+    /// Change how local ports are picked for new flows
+    pub fn set_nat_port_strategy(&mut self, strategy: PortStrategy) {
+        self.nat.set_port_strategy(strategy);
+    }
+
+    /// Change the NAT table capacity; flows evicted to fit are closed
+    pub fn set_nat_max_entries(&mut self, max_entries: usize) {
+        self.nat.set_max_entries(max_entries);
+        self.forget_evicted();
+    }
+
+    /// Cap the number of tracked flows; new flows beyond it are refused
+    /// while existing ones keep running
+    pub fn set_connection_limit(&mut self, limit: usize) {
+        self.connection_limit = limit;
+    }
+
+    /// Maximum flows tracked at once
+    pub fn connection_limit(&self) -> usize {
+        self.connection_limit
+    }
+
+    /// New flows refused because the connection limit was reached
+    pub fn connection_limit_hits(&self) -> u64 {
+        self.connection_limit_hits
     }
 
     /// Register a socket handle for a connection
@@ -132,37 +429,349 @@ impl ConnectionManager {
         self.handle_to_key.get(&handle)
     }
 
+    /// Open a listening socket on the NAT-allocated port of every new TCP
+    /// flow, so the interface can accept the SYN when it is polled. Flows
+    /// whose rule asks for `nodelay` get a latency-tuned socket.
+    ///
+    /// Returns the number of listeners created.
+    pub fn open_listeners(&mut self, iface: &mut InterfaceManager) -> usize {
+        let mut opened = 0;
+        for key in std::mem::take(&mut self.pending_listeners) {
+            if self.socket_handles.contains_key(&key) {
+                continue;
+            }
+            let Some(port) = self.nat.get(&key).map(|entry| entry.local_port) else {
+                continue;
+            };
+            match iface.listen_flow(port, key.dst_port) {
+                Ok(handle) => {
+                    if self.route(&key).is_some_and(|route| route.nodelay) {
+                        iface.set_nodelay(handle, true);
+                    }
+                    iface.bind_key(handle, key);
+                    self.register_socket(key, handle);
+                    opened += 1;
+                }
+                Err(e) => log::warn!("No listener for {}: {}", key.dst_addr(), e),
+            }
+        }
+        opened
+    }
+
+    /// Poll `iface` and fold its socket state changes into the table,
+    /// opening listeners for new flows first so their SYNs are accepted
+    pub fn poll_interface(&mut self, iface: &mut InterfaceManager) -> bool {
+        self.open_listeners(iface);
+        let changed = iface.poll();
+        self.apply_socket_changes(&iface.take_state_changes());
+        changed
+    }
+
+    /// Keep a new TCP flow from getting a listening socket, so its SYNs go
+    /// unanswered while it waits for admission
+    pub fn hold_listener(&mut self, key: &NatKey) {
+        self.pending_listeners.retain(|pending| pending != key);
+    }
+
+    /// Give a held flow its listening socket on the next poll
+    pub fn release_listener(&mut self, key: NatKey) {
+        push_capped(&mut self.pending_listeners, key);
+    }
+
+    /// Check whether a flow is still tracked
+    pub fn contains(&self, key: &NatKey) -> bool {
+        self.nat.get(key).is_some()
+    }
+
+    /// Live flows routed through the proxy
+    pub fn proxied_count(&self) -> usize {
+        self.nat.proxied_count()
+    }
+
+    /// Live flows routed to `host` and not rejected
+    pub fn destination_count(&self, host: &str) -> usize {
+        self.nat.destination_count(host)
+    }
+
+    /// Take the flows accepted since the last call, for the relay layer to
+    /// start relaying
+    pub fn take_accepted(&mut self) -> Vec<(NatKey, SocketHandle)> {
+        std::mem::take(&mut self.accepted)
+    }
+
+    /// Record the status of a connection's relay task
+    pub fn set_relay_status(&mut self, key: NatKey, status: RelayStatus) {
+        self.relay_status.insert(key, status);
+    }
+
+    /// Get the status of a connection's relay task
+    pub fn relay_status(&self, key: &NatKey) -> RelayStatus {
+        let status = self
+            .relay_status
+            .get(key)
+            .copied()
+            .unwrap_or(RelayStatus::Pending);
+
+        // A task that ended without reporting back panicked or was aborted
+        match self.relay_tasks.get(key) {
+            Some(task) if task.is_finished() && status == RelayStatus::Running => {
+                RelayStatus::Failed
+            }
+            _ => status,
+        }
+    }
+
+    /// Check whether a flow's first data segment still needs sniffing
+    pub fn needs_sniff(&self, key: &NatKey) -> bool {
+        !self.sniffed_domains.contains_key(key)
+    }
+
+    /// Record the result of sniffing a flow
+    pub fn set_sniffed_domain(&mut self, key: NatKey, domain: Option<String>) {
+        self.sniffed_domains.insert(key, domain);
+    }
+
+    /// Store the routing decision for a connection, reused for every
+    /// later packet of the flow.
+    ///
+    /// Emits `Opened` for the first decision and `Rerouted` for later ones.
+    pub fn set_route(&mut self, key: &NatKey, decision: RoutingDecision) -> bool {
+        let previous = match self.nat.get(key) {
+            Some(entry) => entry.route.clone(),
+            None => return false,
+        };
+        let kind = match previous {
+            Some(_) => ConnectionEventKind::Rerouted,
+            None => ConnectionEventKind::Opened,
+        };
+
+        // Count the flow once under each policy and domain it ends up with
+        let now = Instant::now();
+        if previous.as_ref().map(|p| &p.action) != Some(&decision.action) {
+            self.usage_by_policy.add_connection(&decision.action, now);
+        }
+        if let Some(domain) = &decision.domain {
+            if previous.as_ref().and_then(|p| p.domain.as_ref()) != Some(domain) {
+                self.usage_by_domain.add_connection(domain, now);
+            }
+        }
+
+        if decision.action == RouteAction::Reject {
+            self.set_close_reason(key, CloseReason::Rejected);
+        }
+        self.nat.set_route(key, decision);
+        self.emit(kind, key);
+        true
+    }
+
+    /// Subscribe to connection lifecycle events
+    pub fn subscribe_events(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.events.subscribe()
+    }
+
+    /// Publish an event for a live flow
+    fn emit(&self, kind: ConnectionEventKind, key: &NatKey) {
+        if let Some(entry) = self.nat.get(key) {
+            self.emit_for(kind, key, entry);
+        }
+    }
+
+    fn emit_for(&self, kind: ConnectionEventKind, key: &NatKey, entry: &NatEntry) {
+        if self.events.has_subscribers() {
+            let domain = self.domain(key).map(String::from);
+            self.events.emit(ConnectionEvent::new(kind, *key, entry, domain));
+        }
+    }
+
+    /// Get the stored routing decision for a connection
+    pub fn route(&self, key: &NatKey) -> Option<&RoutingDecision> {
+        self.nat.get(key)?.route.as_ref()
+    }
+
+    /// Get the hostname sniffed for a connection
+    pub fn domain(&self, key: &NatKey) -> Option<&str> {
+        self.sniffed_domains.get(key)?.as_deref()
+    }
+
+    /// Record why a flow is ending, e.g. a failed proxy handshake reported by
+    /// its relay task. The first reason recorded wins.
+    pub fn set_close_reason(&mut self, key: &NatKey, reason: CloseReason) -> bool {
+        match self.nat.get_mut(key) {
+            Some(entry) => {
+                entry.set_close_reason(reason);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Register the relay task serving a connection so its liveness can be tracked
+    pub fn register_relay_task(&mut self, key: NatKey, task: JoinHandle<()>) {
+        if let Some(old) = self.relay_tasks.insert(key, task) {
+            old.abort();
+        }
+        self.relay_status.insert(key, RelayStatus::Running);
+    }
+
+    /// Drop the per-flow state of entries the NAT table evicted
+    fn forget_evicted(&mut self) {
+        for (key, mut entry) in self.nat.take_evicted() {
+            if let Some(handle) = self.forget_closed(&key, &mut entry, CloseReason::Evicted) {
+                self.orphaned_handles.push(handle);
+            }
+        }
+    }
+
+    /// Announce that a removed entry's flow closed for `reason` and record it
+    /// in the history, then forget it
+    fn forget_closed(
+        &mut self,
+        key: &NatKey,
+        entry: &mut NatEntry,
+        reason: CloseReason,
+    ) -> Option<SocketHandle> {
+        entry.close_reason = Some(reason);
+        self.emit_for(ConnectionEventKind::Closed, key, entry);
+        let domain = self
+            .domain(key)
+            .map(String::from)
+            .or_else(|| entry.route.as_ref().and_then(|r| r.domain.clone()));
+        let handle = self.forget(key);
+        let closed = ClosedConnection {
+            info: ConnectionInfo::from_entry(*key, entry, handle),
+            domain,
+            reason,
+            closed_at: Instant::now(),
+        };
+        if let Some(flow_log) = &self.flow_log {
+            flow_log.log(&closed);
+        }
+        self.history.push(closed);
+        handle
+    }
+
+    /// Log every flow that completes from now on to `flow_log`, or stop
+    /// logging with `None`
+    pub fn set_flow_logger(&mut self, flow_log: Option<Arc<FlowLogger>>) {
+        self.flow_log = flow_log;
+    }
+
+    /// Flow log lines dropped because the writer fell behind
+    pub fn flow_log_dropped(&self) -> u64 {
+        self.flow_log.as_ref().map_or(0, |flow_log| flow_log.dropped())
+    }
+
+    /// Up to `limit` recently closed flows, newest first
+    pub fn recent_connections(&self, limit: usize) -> impl Iterator<Item = &ClosedConnection> {
+        self.history.recent(limit)
+    }
+
+    /// Drop everything tracked for a flow besides its NAT entry, returning
+    /// its socket handle
+    fn forget(&mut self, key: &NatKey) -> Option<SocketHandle> {
+        let handle = self.socket_handles.remove(key);
+        if let Some(handle) = handle {
+            self.handle_to_key.remove(&handle);
+        }
+        self.relay_status.remove(key);
+        self.sniffed_domains.remove(key);
+        self.app_ids.remove(key);
+        self.pending_listeners.retain(|pending| pending != key);
+        self.accepted.retain(|(accepted, _)| accepted != key);
+        if let Some(task) = self.relay_tasks.remove(key) {
+            task.abort();
+        }
+        handle
+    }
+
+    /// Remove connections whose relay task is no longer running, plus flows
+    /// evicted from the NAT table.
+    ///
+    /// Returns the socket handles of those flows; the caller owns the
+    /// socket set and must abort and remove them.
+    pub fn reap_orphaned(&mut self) -> Vec<SocketHandle> {
+        let dead: Vec<NatKey> = self
+            .relay_tasks
+            .iter()
+            .filter(|(_, task)| task.is_finished())
+            .map(|(key, _)| *key)
+            .collect();
+
+        let mut handles = std::mem::take(&mut self.orphaned_handles);
+        for key in dead {
+            if let Some(handle) = self.socket_handles.get(&key) {
+                handles.push(*handle);
+            }
+            if self.remove_with_reason(&key, CloseReason::RelayFailed).is_some() {
+                log::debug!("Reaped orphaned flow {} -> {}", key.src_addr(), key.dst_addr());
+                self.reaped_flows += 1;
+            }
+            self.relay_tasks.remove(&key);
+        }
+
+        handles
+    }
+
+    /// Number of flows reaped because their relay task died
+    pub fn reaped_flows(&self) -> u64 {
+        self.reaped_flows
+    }
+
+    /// Number of flows evicted from the full NAT table
+    pub fn nat_evictions(&self) -> u64 {
+        self.nat.evictions()
+    }
+
+    /// Number of flows refused by the per-source NAT cap
+    pub fn nat_source_limit_hits(&self) -> u64 {
+        self.nat.source_limit_hits()
+    }
+
+    /// Number of local ports found taken while allocating
+    pub fn nat_port_collisions(&self) -> u64 {
+        self.nat.port_collisions()
+    }
+
+    /// Number of TCP segments whose flags did not fit their flow's state
+    pub fn nat_invalid_transitions(&self) -> u64 {
+        self.nat.invalid_transitions()
+    }
+
     /// Get connection info by local port
     pub fn get_by_port(&self, port: u16) -> Option<ConnectionInfo> {
         let key = self.nat.get_key_by_port(port)?;
         let entry = self.nat.get(key)?;
 
-        Some(ConnectionInfo {
-            key: *key,
-            local_port: entry.local_port,
-            socket_handle: self.socket_handles.get(key).copied(),
-            state: entry.state.into(),
-            bytes_sent: entry.bytes_sent,
-            bytes_received: entry.bytes_received,
-            created_at: Instant::now(),
-        })
+        Some(ConnectionInfo::from_entry(*key, entry, self.socket_handles.get(key).copied()))
     }
 
     /// Mark a connection as established
     pub fn establish(&mut self, key: &NatKey) {
-        self.nat.establish(key);
+        let was_established = self
+            .nat
+            .get(key)
+            .is_some_and(|entry| entry.state == NatState::Established);
+        if self.nat.establish(key) && !was_established {
+            self.emit(ConnectionEventKind::Established, key);
+        }
     }
 
     /// Add bytes sent to a connection
     pub fn add_bytes_sent(&mut self, key: &NatKey, bytes: u64) {
         self.nat.add_bytes_sent(key, bytes);
         self.total_bytes_sent += bytes;
+        let now = Instant::now();
+        self.upload.record(bytes, now);
+        self.record_usage(key, bytes, 0, now);
     }
 
     /// Add bytes received to a connection
     pub fn add_bytes_received(&mut self, key: &NatKey, bytes: u64) {
         self.nat.add_bytes_received(key, bytes);
         self.total_bytes_received += bytes;
+        let now = Instant::now();
+        self.download.record(bytes, now);
+        self.record_usage(key, 0, bytes, now);
     }
 
     /// Close a connection
@@ -174,40 +783,126 @@ impl ConnectionManager {
 
     /// Remove a connection completely
     pub fn remove_connection(&mut self, key: &NatKey) -> Option<ConnectionInfo> {
-        let entry = self.nat.remove(key)?;
+        self.remove_with_reason(key, CloseReason::Closed)
+    }
 
-        if let Some(handle) = self.socket_handles.remove(key) {
-            self.handle_to_key.remove(&handle);
-        }
+    /// Remove a connection, closing it for `reason` unless an earlier
+    /// reason was recorded on the entry
+    fn remove_with_reason(&mut self, key: &NatKey, reason: CloseReason) -> Option<ConnectionInfo> {
+        let mut entry = self.nat.remove(key)?;
+        let reason = entry.close_reason.unwrap_or(reason);
+        self.forget_closed(key, &mut entry, reason);
 
-        Some(ConnectionInfo {
-            key: *key,
-            local_port: entry.local_port,
-            socket_handle: None,
-            state: entry.state.into(),
-            bytes_sent: entry.bytes_sent,
-            bytes_received: entry.bytes_received,
-            created_at: Instant::now(),
-        })
+        Some(ConnectionInfo::from_entry(*key, &entry, None))
     }
 
-    /// Clean up expired and closed connections
-    pub fn cleanup(&mut self) {
-        // First, collect keys to remove
-        let keys_to_remove: Vec<NatKey> = self
+    /// Kill a connection on demand.
+    ///
+    /// Aborts the relay task, which drops the outbound proxy stream, and
+    /// removes the NAT entry. The returned info keeps the socket handle so
+    /// the socket set owner can reset it with `InterfaceManager::close_orphaned`.
+    pub fn abort(&mut self, key: &NatKey) -> Option<ConnectionInfo> {
+        let mut entry = self.nat.remove(key)?;
+        let socket_handle = self.forget_closed(key, &mut entry, CloseReason::Aborted);
+        log::debug!("Aborted flow {} -> {}", key.src_addr(), key.dst_addr());
+
+        Some(ConnectionInfo::from_entry(*key, &entry, socket_handle))
+    }
+
+    /// Abort every flow, e.g. when the tunnel stops.
+    ///
+    /// Returns the socket handles of all flows, including ones still waiting
+    /// for `reap_orphaned`.
+    pub fn abort_all(&mut self) -> Vec<SocketHandle> {
+        let keys: Vec<NatKey> = self
             .nat
-            .get_all_connections()
-            .iter()
-            .filter(|(_, entry)| entry.state == NatState::Closed)
+            .iter_filtered(None, None, None)
             .map(|(key, _)| *key)
             .collect();
+        let mut handles = std::mem::take(&mut self.orphaned_handles);
+        for key in keys {
+            if let Some(info) = self.abort(&key) {
+                handles.extend(info.socket_handle);
+            }
+        }
+        for (_, task) in self.relay_tasks.drain() {
+            task.abort();
+        }
+        handles
+    }
 
-        for key in keys_to_remove {
-            self.remove_connection(&key);
+    /// Get the NAT key of the connection with this identifier
+    pub fn key_by_id(&self, id: u64) -> Option<NatKey> {
+        self.nat.get_key_by_id(id)
+    }
+
+    /// Clean up expired and closed connections.
+    ///
+    /// Returns how many were removed; their sockets are handed out by the
+    /// next `reap_orphaned` call.
+    pub fn cleanup(&mut self) -> usize {
+        let idle = self.close_idle_relays();
+        let expired = self.nat.expired_keys();
+        for key in &expired {
+            if let Some(mut entry) = self.nat.remove(key) {
+                let reason = match entry.close_reason {
+                    Some(reason) => reason,
+                    None if entry.state == NatState::Closed => CloseReason::Closed,
+                    None => CloseReason::Expired,
+                };
+                if let Some(handle) = self.forget_closed(key, &mut entry, reason) {
+                    self.orphaned_handles.push(handle);
+                }
+            }
         }
+        idle + expired.len()
+    }
 
-        // Then cleanup NAT table
-        self.nat.cleanup_expired();
+    /// Close relayed flows idle for longer than the relay idle timeout.
+    ///
+    /// Aborting the relay task drops the proxy leg; the socket is handed out
+    /// by `reap_orphaned` so the app's leg gets reset too.
+    fn close_idle_relays(&mut self) -> usize {
+        let Some(timeout) = self.relay_idle_timeout else {
+            return 0;
+        };
+        let idle: Vec<NatKey> = self
+            .relay_tasks
+            .keys()
+            .filter(|key| {
+                self.nat
+                    .get(key)
+                    .is_some_and(|entry| entry.last_seen.elapsed() >= timeout)
+            })
+            .copied()
+            .collect();
+        for key in &idle {
+            if let Some(mut entry) = self.nat.remove(key) {
+                log::debug!("Closing idle flow {} -> {}", key.src_addr(), key.dst_addr());
+                if let Some(handle) = self.forget_closed(key, &mut entry, CloseReason::Idle) {
+                    self.orphaned_handles.push(handle);
+                }
+            }
+        }
+        idle.len()
+    }
+
+    /// Evict the least recently active flow, as a full NAT table does;
+    /// its socket is handed out by the next `reap_orphaned` call
+    pub fn evict_lru(&mut self) -> bool {
+        if self.nat.evict_lru().is_err() {
+            return false;
+        }
+        self.forget_evicted();
+        true
+    }
+
+    /// Last activity of the least recently active flow
+    pub fn least_recent_activity(&self) -> Option<Instant> {
+        self.nat
+            .iter_filtered(None, None, None)
+            .map(|(_, entry)| entry.last_seen)
+            .min()
     }
 
     /// Get the number of active connections
@@ -215,6 +910,16 @@ impl ConnectionManager {
         self.nat.len()
     }
 
+    /// Number of flows with a registered socket
+    pub fn socket_count(&self) -> usize {
+        self.socket_handles.len()
+    }
+
+    /// Estimated heap bytes held by the NAT table
+    pub fn nat_bytes(&self) -> usize {
+        self.nat.estimated_bytes()
+    }
+
     /// Get total bytes sent
     pub fn total_bytes_sent(&self) -> u64 {
         self.total_bytes_sent
@@ -225,50 +930,221 @@ impl ConnectionManager {
         self.total_bytes_received
     }
 
+    fn record_usage(&mut self, key: &NatKey, sent: u64, received: u64, now: Instant) {
+        self.usage_by_source.add_bytes(&key.src_ip, sent, received, now);
+        if let Some(app) = self.app_ids.get(key) {
+            self.usage_by_app.add_bytes(app, sent, received, now);
+        }
+        let route = self.nat.get(key).and_then(|entry| entry.route.as_ref());
+        self.traffic.record(route.map(|route| route.action.clone()), sent, received, now);
+        if let Some(route) = route {
+            self.usage_by_policy.add_bytes(&route.action, sent, received, now);
+            let domain = self
+                .sniffed_domains
+                .get(key)
+                .and_then(|d| d.as_ref())
+                .or(route.domain.as_ref());
+            if let Some(domain) = domain {
+                self.usage_by_domain.add_bytes(domain, sent, received, now);
+            }
+        }
+    }
+
+    /// Attribute a flow to an app, so its later traffic counts towards the
+    /// app's usage
+    pub fn set_app_id(&mut self, key: &NatKey, app_id: String) -> bool {
+        if self.nat.get(key).is_none() {
+            return false;
+        }
+        self.usage_by_app.add_connection(&app_id, Instant::now());
+        self.app_ids.insert(*key, app_id);
+        true
+    }
+
+    /// Attach `value` under `name` to a flow, or remove `name` when `value`
+    /// is `None`. Annotations travel with the flow's events, its history
+    /// record and its flow log line.
+    pub fn annotate(
+        &mut self,
+        key: &NatKey,
+        name: &str,
+        value: Option<String>,
+    ) -> Result<(), VoyageError> {
+        let entry = self
+            .nat
+            .get_mut(key)
+            .ok_or_else(|| VoyageError::connection("Connection not found".into()))?;
+        let Some(value) = value else {
+            entry.annotations.remove(name);
+            return Ok(());
+        };
+        if name.is_empty() || name.len() + value.len() > MAX_ANNOTATION_SIZE {
+            return Err(VoyageError::connection(format!(
+                "Annotation {} must be named and at most {} bytes",
+                name, MAX_ANNOTATION_SIZE
+            )));
+        }
+        if entry.annotations.len() >= MAX_ANNOTATIONS && !entry.annotations.contains_key(name) {
+            return Err(VoyageError::connection(format!(
+                "A connection carries at most {} annotations",
+                MAX_ANNOTATIONS
+            )));
+        }
+        entry.annotations.insert(name.to_string(), value);
+        Ok(())
+    }
+
+    /// Get the app a flow was attributed to
+    pub fn app_id(&self, key: &NatKey) -> Option<&str> {
+        self.app_ids.get(key).map(String::as_str)
+    }
+
+    /// Traffic totals per source IP
+    pub fn usage_by_source(&self) -> &UsageTable<IpAddr> {
+        &self.usage_by_source
+    }
+
+    /// Traffic totals per app identifier
+    pub fn usage_by_app(&self) -> &UsageTable<String> {
+        &self.usage_by_app
+    }
+
+    /// Traffic totals per sniffed or resolved domain
+    pub fn usage_by_domain(&self) -> &UsageTable<String> {
+        &self.usage_by_domain
+    }
+
+    /// Traffic totals per routing policy
+    pub fn usage_by_policy(&self) -> &UsageTable<RouteAction> {
+        &self.usage_by_policy
+    }
+
+    /// Bucketed traffic totals, overall and per policy
+    pub fn traffic(&self) -> &TrafficRecorder {
+        &self.traffic
+    }
+
+    /// Current upload speed across all flows, in bytes per second
+    pub fn upload_rate(&self) -> u64 {
+        self.upload.rate(Instant::now())
+    }
+
+    /// Current download speed across all flows, in bytes per second
+    pub fn download_rate(&self) -> u64 {
+        self.download.rate(Instant::now())
+    }
+
     /// Get total connections created
     pub fn total_connections(&self) -> u64 {
         self.total_connections
     }
 
+    /// Iterate NAT entries by protocol, state and last activity (see
+    /// `NatManager::iter_filtered`)
+    pub fn iter_filtered(
+        &self,
+        protocol: Option<u8>,
+        state: Option<NatState>,
+        since: Option<Instant>,
+    ) -> impl Iterator<Item = (&NatKey, &NatEntry)> {
+        self.nat.iter_filtered(protocol, state, since)
+    }
+
     /// Get all active connections
     pub fn get_all_connections(&self) -> Vec<ConnectionInfo> {
         self.nat
             .get_all_connections()
             .iter()
-            .map(|(key, entry)| ConnectionInfo {
-                key: *key,
-                local_port: entry.local_port,
-                socket_handle: self.socket_handles.get(key).copied(),
-                state: entry.state.into(),
-                bytes_sent: entry.bytes_sent,
-                bytes_received: entry.bytes_received,
-                created_at: Instant::now(),
+            .map(|(key, entry)| {
+                ConnectionInfo::from_entry(*key, entry, self.socket_handles.get(key).copied())
             })
             .collect()
     }
 
-    /// Synchronize connection states with smoltcp socket states
-    pub fn sync_socket_states(&mut self, sockets: &SocketSet<'_>) {
-        for (key, handle) in &self.socket_handles {
-            let socket = sockets.get::<TcpSocket>(*handle);
-            let new_state = match socket.state() {
-                TcpState::Established => NatState::Established,
-                TcpState::FinWait1 | TcpState::FinWait2 | TcpState::Closing | TcpState::TimeWait => {
-                    NatState::FinWait
+    /// Snapshot the full flow table, including smoltcp socket state when
+    /// the socket set is available
+    pub fn dump_flows(&self, sockets: Option<&SocketSet<'_>>) -> Vec<FlowDump> {
+        let mut flows: Vec<FlowDump> = self
+            .nat
+            .get_all_connections()
+            .into_iter()
+            .map(|(key, entry)| {
+                // Handles missing from `sockets` are skipped, as smoltcp
+                // panics on them
+                let socket = match (sockets, self.socket_handles.get(&key)) {
+                    (Some(sockets), Some(handle)) => {
+                        sockets.iter().find_map(|(live, socket)| match socket {
+                            Socket::Tcp(socket) if live == *handle => Some(socket),
+                            _ => None,
+                        })
+                    }
+                    _ => None,
+                };
+
+                FlowDump {
+                    protocol: key.protocol_name(),
+                    src: key.src_addr().to_string(),
+                    dst: key.dst_addr().to_string(),
+                    domain: self.domain(&key).map(String::from),
+                    local_port: entry.local_port,
+                    nat_state: format!("{:?}", entry.state),
+                    socket_state: socket.map(|s| s.state().to_string()),
+                    relay_status: self.relay_status(&key),
+                    bytes_sent: entry.bytes_sent,
+                    bytes_received: entry.bytes_received,
+                    age_ms: entry.created_at.elapsed().as_millis() as u64,
+                    idle_ms: entry.last_seen.elapsed().as_millis() as u64,
+                    rx_buffered: socket.map(|s| s.recv_queue()),
+                    tx_buffered: socket.map(|s| s.send_queue()),
+                    annotations: entry.annotations.clone(),
                 }
-                TcpState::Closed | TcpState::CloseWait | TcpState::LastAck => NatState::Closed,
+            })
+            .collect();
+
+        flows.sort_by_key(|f| f.local_port);
+        flows
+    }
+
+    /// Dump the full flow table as pretty-printed JSON
+    pub fn dump_flows_json(&self, sockets: Option<&SocketSet<'_>>) -> String {
+        serde_json::to_string_pretty(&self.dump_flows(sockets)).unwrap_or_else(|_| "[]".into())
+    }
+
+    /// Update connection states from the socket state changes reported by
+    /// `InterfaceManager::take_state_changes`
+    pub fn apply_socket_changes(&mut self, changes: &[SocketStateChange]) {
+        for change in changes {
+            let Some(key) = change
+                .key
+                .or_else(|| self.handle_to_key.get(&change.handle).copied())
+            else {
+                continue;
+            };
+            // The local stack is the app's peer: each of its state changes
+            // stands for a segment it sent, or one from the app it accepted
+            let (flags, direction) = match change.state {
+                TcpState::SynReceived => (0x12, FlowDirection::Inbound), // SYN-ACK
+                TcpState::Established => (0x10, FlowDirection::Outbound), // ACK
+                TcpState::CloseWait => (0x11, FlowDirection::Outbound),  // FIN-ACK
+                TcpState::FinWait1 | TcpState::LastAck => (0x11, FlowDirection::Inbound),
+                TcpState::TimeWait => (0x10, FlowDirection::Inbound),
+                TcpState::Closed => (0x04, FlowDirection::Inbound), // RST
                 _ => continue,
             };
+            if self
+                .nat
+                .on_flags(&key, TcpFlags::from_byte(flags), direction)
+                .is_none()
+            {
+                continue;
+            }
 
-            if let Some(entry) = self.nat.get_mut(key) {
-                if entry.state != new_state {
-                    match new_state {
-                        NatState::Established => entry.establish(),
-                        NatState::FinWait => entry.start_close(),
-                        NatState::Closed => entry.close(),
-                        _ => {}
-                    }
-                }
+            // Hand the flow to the relay layer once, the first time its
+            // socket completes the handshake
+            if change.state == TcpState::Established && !self.relay_status.contains_key(&key) {
+                self.relay_status.insert(key, RelayStatus::Pending);
+                push_capped(&mut self.accepted, (key, change.handle));
+                self.emit(ConnectionEventKind::Established, &key);
             }
         }
     }
@@ -280,6 +1156,15 @@ impl Default for ConnectionManager {
     }
 }
 
+/// Append to a queue of flows, dropping the oldest beyond `MAX_PENDING_FLOWS`
+fn push_capped<T>(queue: &mut Vec<T>, item: T) {
+    if queue.len() >= MAX_PENDING_FLOWS {
+        queue.remove(0);
+        log::debug!("Pending flow queue full, dropped the oldest");
+    }
+    queue.push(item);
+}
+
 /// Thread-safe wrapper for ConnectionManager
 pub type SharedConnectionManager = Arc<Mutex<ConnectionManager>>;
 
@@ -329,6 +1214,91 @@ mod tests {
         assert_eq!(manager.get_key_for_handle(handle), Some(&key));
     }
 
+    #[test]
+    fn test_open_listeners() {
+        let mut iface = InterfaceManager::new();
+        let mut manager = ConnectionManager::new();
+        let syn = crate::create_tcp_packet([10, 0, 0, 2], [8, 8, 8, 8], 40000, 443, true);
+        let info = manager.process_packet(&ParsedPacket::parse(&syn).unwrap()).unwrap();
+        let ack = crate::create_tcp_packet([10, 0, 0, 2], [8, 8, 8, 8], 40001, 443, false);
+        manager.process_packet(&ParsedPacket::parse(&ack).unwrap()).unwrap();
+
+        // Only the flow that started with a SYN gets a listener
+        assert_eq!(manager.open_listeners(&mut iface), 1);
+        assert_eq!(manager.open_listeners(&mut iface), 0);
+        let handle = manager.get_socket_handle(&info.key).unwrap();
+        let socket = iface.get_tcp_socket(handle);
+        assert_eq!(socket.state(), TcpState::Listen);
+        assert!(socket.nagle_enabled());
+        assert!(manager.take_accepted().is_empty());
+
+        // A flow routed by a `nodelay` rule gets a latency-tuned socket
+        let syn = crate::create_tcp_packet([10, 0, 0, 2], [8, 8, 8, 8], 40002, 443, true);
+        let info = manager.process_packet(&ParsedPacket::parse(&syn).unwrap()).unwrap();
+        let mut route = RoutingDecision::direct(443);
+        route.nodelay = true;
+        manager.set_route(&info.key, route);
+        assert_eq!(manager.open_listeners(&mut iface), 1);
+        let handle = manager.get_socket_handle(&info.key).unwrap();
+        let socket = iface.get_tcp_socket(handle);
+        assert!(!socket.nagle_enabled());
+        assert_eq!(socket.ack_delay(), None);
+    }
+
+    #[test]
+    fn test_pending_listeners_pruned_and_capped() {
+        let mut iface = InterfaceManager::new();
+        let mut manager = ConnectionManager::new();
+        let syn = crate::create_tcp_packet([10, 0, 0, 2], [8, 8, 8, 8], 40000, 443, true);
+        let info = manager.process_packet(&ParsedPacket::parse(&syn).unwrap()).unwrap();
+
+        // A flow gone before the next poll gets no listener
+        manager.abort(&info.key);
+        assert_eq!(manager.open_listeners(&mut iface), 0);
+        assert_eq!(iface.socket_count(), 0);
+
+        let mut queue = Vec::new();
+        for n in 0..=MAX_PENDING_FLOWS {
+            push_capped(&mut queue, n);
+        }
+        assert_eq!(queue.len(), MAX_PENDING_FLOWS);
+        assert_eq!(queue[0], 1);
+    }
+
+    #[test]
+    fn test_apply_socket_changes() {
+        let mut manager = ConnectionManager::new();
+        let key = make_tcp_key(12345, 443);
+        manager.nat.get_or_create(key).unwrap();
+        let handle = mock_socket_handle(1);
+        manager.register_socket(key, handle);
+        let mut events = manager.subscribe_events();
+
+        // Unbound changes are matched through the registered handle
+        let change = |state| SocketStateChange {
+            handle,
+            key: None,
+            state,
+        };
+        manager.apply_socket_changes(&[change(TcpState::SynReceived), change(TcpState::Established)]);
+        assert_eq!(manager.nat.get(&key).unwrap().state, NatState::Established);
+        assert_eq!(manager.take_accepted(), [(key, handle)]);
+        assert_eq!(events.try_recv().unwrap().kind, ConnectionEventKind::Established);
+
+        manager.apply_socket_changes(&[change(TcpState::Established)]);
+        assert!(manager.take_accepted().is_empty());
+
+        // The app closing first half-closes the flow until the stack's FIN
+        // is acknowledged
+        manager.apply_socket_changes(&[change(TcpState::CloseWait)]);
+        assert_eq!(manager.nat.get(&key).unwrap().state, NatState::FinWait);
+        manager.apply_socket_changes(&[change(TcpState::LastAck)]);
+        assert_eq!(manager.nat.get(&key).unwrap().state, NatState::Closing);
+        manager.apply_socket_changes(&[change(TcpState::Closed)]);
+        assert_eq!(manager.nat.get(&key).unwrap().state, NatState::Closed);
+        assert_eq!(manager.nat_invalid_transitions(), 0);
+    }
+
     #[test]
     fn test_connection_state_transition() {
         let mut manager = ConnectionManager::new();
@@ -392,6 +1362,23 @@ mod tests {
         assert_eq!(conn.unwrap().key, key);
     }
 
+    #[test]
+    fn test_connection_duration() {
+        let mut manager = ConnectionManager::new();
+        let key = make_tcp_key(12345, 443);
+        let local_port = manager.nat.get_or_create(key).unwrap().local_port;
+        let first = manager.get_by_port(local_port).unwrap();
+
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        manager.add_bytes_sent(&key, 10);
+
+        // The creation time comes from the NAT entry, not the query
+        let conn = manager.get_by_port(local_port).unwrap();
+        assert_eq!(conn.created_at, first.created_at);
+        assert!(conn.duration() >= std::time::Duration::from_millis(10));
+        assert!(conn.idle_time() < conn.duration());
+    }
+
     #[test]
     fn test_cleanup() {
         let mut manager = ConnectionManager::new();
@@ -410,6 +1397,9 @@ mod tests {
 
         // Closed connections should be removed
         assert_eq!(manager.active_connections(), 5);
+        let recent: Vec<&ClosedConnection> = manager.recent_connections(10).collect();
+        assert_eq!(recent.len(), 5);
+        assert!(recent.iter().all(|c| c.reason == CloseReason::Closed));
     }
 
     #[test]
@@ -449,6 +1439,389 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_sniffed_domain() {
+        let mut manager = ConnectionManager::new();
+        let key = make_tcp_key(12345, 443);
+        manager.nat.get_or_create(key).unwrap();
+
+        assert!(manager.needs_sniff(&key));
+        manager.set_sniffed_domain(key, Some("example.com".into()));
+        assert!(!manager.needs_sniff(&key));
+        assert_eq!(manager.domain(&key), Some("example.com"));
+
+        manager.remove_connection(&key);
+        assert!(manager.needs_sniff(&key));
+        assert_eq!(manager.domain(&key), None);
+    }
+
+    #[test]
+    fn test_relay_status() {
+        let mut manager = ConnectionManager::new();
+        let key = make_tcp_key(12345, 443);
+        manager.nat.get_or_create(key).unwrap();
+
+        assert_eq!(manager.relay_status(&key), RelayStatus::Pending);
+        manager.set_relay_status(key, RelayStatus::Running);
+        assert_eq!(manager.relay_status(&key), RelayStatus::Running);
+
+        manager.remove_connection(&key);
+        assert_eq!(manager.relay_status(&key), RelayStatus::Pending);
+    }
+
+    /// Spawn a relay task that is aborted before it can finish
+    async fn dead_relay_task() -> JoinHandle<()> {
+        let task = tokio::spawn(std::future::pending::<()>());
+        task.abort();
+        while !task.is_finished() {
+            tokio::task::yield_now().await;
+        }
+        task
+    }
+
+    #[test]
+    fn test_reap_orphaned() {
+        let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        rt.block_on(async {
+            let mut iface = crate::iface::InterfaceManager::new();
+            let mut manager = ConnectionManager::new();
+
+            let live = make_tcp_key(10001, 443);
+            let dead = make_tcp_key(10002, 443);
+            let dead_handle = iface.create_tcp_socket();
+            manager.nat.get_or_create(live).unwrap();
+            manager.nat.get_or_create(dead).unwrap();
+            manager.register_socket(dead, dead_handle);
+
+            manager.register_relay_task(live, tokio::spawn(std::future::pending::<()>()));
+            manager.register_relay_task(dead, dead_relay_task().await);
+
+            assert_eq!(manager.relay_status(&live), RelayStatus::Running);
+            assert_eq!(manager.relay_status(&dead), RelayStatus::Failed);
+
+            let handles = manager.reap_orphaned();
+            assert_eq!(handles, vec![dead_handle]);
+            iface.close_orphaned(&handles);
+
+            assert_eq!(manager.reaped_flows(), 1);
+            assert_eq!(manager.active_connections(), 1);
+            assert_eq!(iface.socket_count(), 0);
+            assert!(manager.reap_orphaned().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_idle_relays_closed() {
+        let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        rt.block_on(async {
+            let mut iface = crate::iface::InterfaceManager::new();
+            let mut manager = ConnectionManager::new();
+
+            let relayed = make_tcp_key(10001, 443);
+            let pending = make_tcp_key(10002, 443);
+            let handle = iface.create_tcp_socket();
+            manager.nat.get_or_create(relayed).unwrap();
+            manager.nat.get_or_create(pending).unwrap();
+            manager.register_socket(relayed, handle);
+            manager.register_relay_task(relayed, tokio::spawn(std::future::pending::<()>()));
+
+            manager.set_relay_idle_timeout(Some(Duration::from_secs(60)));
+            assert_eq!(manager.cleanup(), 0);
+
+            manager.set_relay_idle_timeout(Some(Duration::ZERO));
+            assert_eq!(manager.cleanup(), 1);
+            assert!(!manager.contains(&relayed));
+            assert!(manager.contains(&pending));
+            assert_eq!(manager.reap_orphaned(), vec![handle]);
+
+            let closed = manager.recent_connections(1).next().unwrap();
+            assert_eq!(closed.reason, CloseReason::Idle);
+        });
+    }
+
+    #[test]
+    fn test_usage_by_source_and_app() {
+        let mut manager = ConnectionManager::new();
+        let packet = crate::create_tcp_packet([10, 0, 0, 1], [8, 8, 8, 8], 10001, 443, true);
+        let key = manager
+            .process_packet(&ParsedPacket::parse(&packet).unwrap())
+            .unwrap()
+            .key;
+        manager.add_bytes_sent(&key, 100);
+        assert!(manager.set_app_id(&key, "com.example.app".into()));
+        manager.add_bytes_received(&key, 400);
+        manager.remove_connection(&key);
+
+        // Totals outlive the flow
+        let source = manager.usage_by_source().get(&key.src_ip).unwrap();
+        assert_eq!((source.bytes_sent, source.bytes_received, source.connections), (100, 400, 1));
+        let app = manager.usage_by_app().get(&"com.example.app".to_string()).unwrap();
+        assert_eq!((app.bytes_sent, app.bytes_received, app.connections), (0, 400, 1));
+        assert!(!manager.set_app_id(&key, "other".into()));
+    }
+
+    #[test]
+    fn test_annotations_follow_the_flow() {
+        let mut manager = ConnectionManager::new();
+        let key = make_tcp_key(12346, 443);
+        manager.nat.get_or_create(key).unwrap();
+        let mut events = manager.subscribe_events();
+
+        manager.annotate(&key, "app", Some("Mail".into())).unwrap();
+        manager.annotate(&key, "tab", Some("1".into())).unwrap();
+        manager.annotate(&key, "tab", None).unwrap();
+        assert!(manager.annotate(&key, "", Some("x".into())).is_err());
+        let huge = "x".repeat(MAX_ANNOTATION_SIZE);
+        assert!(manager.annotate(&key, "big", Some(huge)).is_err());
+        for i in 1..MAX_ANNOTATIONS {
+            manager.annotate(&key, &i.to_string(), Some(String::new())).unwrap();
+        }
+        assert!(manager.annotate(&key, "one-too-many", Some(String::new())).is_err());
+        manager.annotate(&key, "app", Some("Mail".into())).unwrap();
+
+        manager.remove_connection(&key);
+        let closed = manager.recent_connections(1).next().unwrap();
+        assert_eq!(closed.info.annotations["app"], "Mail");
+        assert!(!closed.info.annotations.contains_key("tab"));
+        let event = events.try_recv().unwrap();
+        assert_eq!(event.kind, ConnectionEventKind::Closed);
+        assert_eq!(event.annotations.len(), MAX_ANNOTATIONS);
+        assert!(manager.annotate(&key, "app", None).is_err());
+    }
+
+    #[test]
+    fn test_usage_by_domain_and_policy() {
+        let mut manager = ConnectionManager::new();
+        let key = make_tcp_key(12345, 443);
+        manager.nat.get_or_create(key).unwrap();
+
+        manager.set_route(&key, RoutingDecision::direct(443));
+        manager.add_bytes_sent(&key, 100);
+        let mut decision = RoutingDecision::proxy(443);
+        decision.domain = Some("example.com".into());
+        manager.set_route(&key, decision);
+        manager.add_bytes_received(&key, 400);
+
+        let direct = manager.usage_by_policy().get(&RouteAction::Direct).unwrap();
+        assert_eq!((direct.bytes_sent, direct.connections), (100, 1));
+        let proxy = manager.usage_by_policy().get(&RouteAction::Proxy).unwrap();
+        assert_eq!((proxy.bytes_received, proxy.connections), (400, 1));
+        let domain = manager.usage_by_domain().get(&"example.com".to_string()).unwrap();
+        assert_eq!((domain.bytes_sent, domain.bytes_received, domain.connections), (0, 400, 1));
+    }
+
+    #[test]
+    fn test_close_reasons() {
+        let mut manager = ConnectionManager::new();
+        let mut packet = crate::create_tcp_packet([10, 0, 0, 1], [8, 8, 8, 8], 10001, 443, false);
+        packet[33] = 0x11; // FIN + ACK
+        let key = manager
+            .process_packet(&ParsedPacket::parse(&packet).unwrap())
+            .unwrap()
+            .key;
+        packet[33] = 0x04; // RST
+        manager.process_packet(&ParsedPacket::parse(&packet).unwrap()).unwrap();
+        assert_eq!(manager.nat.get(&key).unwrap().state, NatState::Closed);
+        manager.remove_connection(&key);
+
+        let rejected = make_tcp_key(12345, 443);
+        manager.nat.get_or_create(rejected).unwrap();
+        manager.set_route(&rejected, RoutingDecision::reject(443));
+        manager.remove_connection(&rejected);
+
+        let proxied = make_tcp_key(12346, 443);
+        manager.nat.get_or_create(proxied).unwrap();
+        assert!(manager.set_close_reason(&proxied, CloseReason::ProxyFailed));
+        manager.abort(&proxied);
+
+        let reasons: Vec<CloseReason> = manager.recent_connections(10).map(|c| c.reason).collect();
+        assert_eq!(
+            reasons,
+            [CloseReason::Aborted, CloseReason::Rejected, CloseReason::AppClosed]
+        );
+    }
+
+    #[test]
+    fn test_connection_events() {
+        let mut manager = ConnectionManager::new();
+        let mut events = manager.subscribe_events();
+        let key = make_tcp_key(12345, 443);
+        manager.nat.get_or_create(key).unwrap();
+
+        manager.set_route(&key, RoutingDecision::direct(443));
+        manager.establish(&key);
+        manager.establish(&key);
+        manager.set_sniffed_domain(key, Some("example.com".into()));
+        manager.set_route(&key, RoutingDecision::proxy(443));
+        manager.add_bytes_sent(&key, 64);
+        manager.remove_connection(&key);
+
+        let kinds: Vec<ConnectionEventKind> = std::iter::from_fn(|| events.try_recv().ok())
+            .map(|event| {
+                if event.kind == ConnectionEventKind::Closed {
+                    assert_eq!(event.reason, Some(CloseReason::Closed));
+                    assert_eq!(event.domain.as_deref(), Some("example.com"));
+                    assert_eq!(event.bytes_sent, 64);
+                    assert!(event.decision.is_some());
+                }
+                event.kind
+            })
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                ConnectionEventKind::Opened,
+                ConnectionEventKind::Established,
+                ConnectionEventKind::Rerouted,
+                ConnectionEventKind::Closed,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_abort_connection() {
+        let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        rt.block_on(async {
+            let mut iface = crate::iface::InterfaceManager::new();
+            let mut manager = ConnectionManager::new();
+            let key = make_tcp_key(10001, 443);
+            let handle = iface.create_tcp_socket();
+            let id = manager.nat.get_or_create(key).unwrap().id;
+            manager.register_socket(key, handle);
+            let relay = tokio::spawn(std::future::pending::<()>());
+            let relay_abort = relay.abort_handle();
+            manager.register_relay_task(key, relay);
+
+            assert_eq!(manager.key_by_id(id), Some(key));
+            let info = manager.abort(&key).unwrap();
+            assert_eq!(info.socket_handle, Some(handle));
+            iface.close_orphaned(&[handle]);
+
+            tokio::task::yield_now().await;
+            assert!(relay_abort.is_finished());
+            assert_eq!(manager.active_connections(), 0);
+            assert_eq!(manager.key_by_id(id), None);
+            assert_eq!(iface.socket_count(), 0);
+            assert!(manager.abort(&key).is_none());
+        });
+    }
+
+    #[test]
+    fn test_evicted_flow_socket_is_reaped() {
+        let mut iface = crate::iface::InterfaceManager::new();
+        let config = NatConfig {
+            max_entries: 1,
+            ..Default::default()
+        };
+        let mut manager = ConnectionManager::with_nat_config(&config);
+
+        let first = crate::create_tcp_packet([10, 0, 0, 1], [8, 8, 8, 8], 10001, 443, true);
+        let first = ParsedPacket::parse(&first).unwrap();
+        let info = manager.process_packet(&first).unwrap();
+        let handle = iface.create_tcp_socket();
+        manager.register_socket(info.key, handle);
+        manager.set_sniffed_domain(info.key, Some("example.com".into()));
+
+        let second = crate::create_tcp_packet([10, 0, 0, 1], [8, 8, 8, 8], 10002, 443, true);
+        manager.process_packet(&ParsedPacket::parse(&second).unwrap()).unwrap();
+
+        assert_eq!(manager.active_connections(), 1);
+        assert_eq!(manager.nat_evictions(), 1);
+        assert!(manager.get_socket_handle(&info.key).is_none());
+        assert_eq!(manager.domain(&info.key), None);
+        assert_eq!(manager.reap_orphaned(), vec![handle]);
+        assert!(manager.reap_orphaned().is_empty());
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        let mut manager = ConnectionManager::new();
+        manager.set_connection_limit(1);
+
+        let first = crate::create_tcp_packet([10, 0, 0, 1], [8, 8, 8, 8], 10001, 443, true);
+        let first = ParsedPacket::parse(&first).unwrap();
+        manager.process_packet(&first).unwrap();
+        let second = crate::create_tcp_packet([10, 0, 0, 1], [8, 8, 8, 8], 10002, 443, true);
+        let second = ParsedPacket::parse(&second).unwrap();
+        assert!(matches!(manager.process_packet(&second), Err(VoyageError::Connection { .. })));
+
+        // Existing flows keep working at the limit
+        assert!(manager.process_packet(&first).is_ok());
+        assert_eq!((manager.active_connections(), manager.connection_limit_hits()), (1, 1));
+
+        manager.set_nat_max_entries(0);
+        assert_eq!(manager.active_connections(), 0);
+        assert_eq!(manager.nat_evictions(), 1);
+    }
+
+    #[test]
+    fn test_udp_replies_follow_nat_mode() {
+        let app: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let dns: SocketAddr = "8.8.8.8:53".parse().unwrap();
+        let other: SocketAddr = "1.1.1.1:53".parse().unwrap();
+        let packet = |src, dst| crate::packet::build_udp_packet(src, dst, b"x").unwrap();
+
+        for mode in [NatMode::Symmetric, NatMode::FullCone] {
+            let mut manager = ConnectionManager::new();
+            manager.set_udp_nat_mode(mode);
+            let query = packet(app, dns);
+            let sent = manager.process_packet(&ParsedPacket::parse(&query).unwrap()).unwrap();
+
+            let reply = packet(dns, app);
+            let info = manager.process_reply(&ParsedPacket::parse(&reply).unwrap()).unwrap();
+            assert_eq!((info.key, info.bytes_received), (sent.key, reply.len() as u64));
+
+            let stranger = packet(other, app);
+            let result = manager.process_reply(&ParsedPacket::parse(&stranger).unwrap());
+            if mode == NatMode::FullCone {
+                let info = result.unwrap();
+                assert_eq!(info.key, NatKey::udp(app, other));
+                assert_eq!(info.local_port, sent.local_port);
+                assert_eq!(manager.active_connections(), 2);
+            } else {
+                assert!(matches!(result, Err(VoyageError::Nat { .. })));
+                assert_eq!(manager.active_connections(), 1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_dump_flows_json() {
+        let mut manager = ConnectionManager::new();
+        let key = make_tcp_key(12345, 443);
+        manager.nat.get_or_create(key).unwrap();
+        manager.add_bytes_sent(&key, 42);
+        manager.set_relay_status(key, RelayStatus::Failed);
+
+        let flows = manager.dump_flows(None);
+        assert_eq!(flows.len(), 1);
+        assert_eq!(flows[0].dst, "8.8.8.8:443");
+        assert!(flows[0].socket_state.is_none());
+
+        let json: serde_json::Value =
+            serde_json::from_str(&manager.dump_flows_json(None)).unwrap();
+        assert_eq!(json[0]["protocol"], "tcp");
+        assert_eq!(json[0]["relay_status"], "failed");
+        assert_eq!(json[0]["bytes_sent"], 42);
+        assert_eq!(json[0]["nat_state"], "SynSent");
+    }
+
+    #[test]
+    fn test_dump_flows_with_sockets() {
+        let mut iface = crate::iface::InterfaceManager::new();
+        let handle = iface.create_tcp_socket();
+
+        let mut manager = ConnectionManager::new();
+        let key = make_tcp_key(12345, 443);
+        manager.nat.get_or_create(key).unwrap();
+        manager.register_socket(key, handle);
+
+        let flows = manager.dump_flows(Some(iface.sockets()));
+        assert_eq!(flows[0].socket_state.as_deref(), Some("CLOSED"));
+        assert_eq!(flows[0].rx_buffered, Some(0));
+        assert_eq!(flows[0].tx_buffered, Some(0));
+    }
+
     #[test]
     fn test_shared_connection_manager() {
         let shared = new_shared_connection_manager();
@@ -1,29 +1,557 @@
 //! Virtual TUN device for smoltcp
 
-use smoltcp::phy::{Device, DeviceCapabilities, Medium, RxToken, TxToken};
+use crate::config::{ChecksumMode, DropPolicy, QueueConfig};
+use crate::packet::{split_oversized, verify_checksums};
+use crossbeam_queue::{ArrayQueue, SegQueue};
+use smoltcp::phy::{Checksum, Device, DeviceCapabilities, Medium, RxToken, TxToken};
 use smoltcp::time::Instant;
 use std::collections::VecDeque;
-use std::sync::{Arc, Mutex};
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use tokio::sync::Notify;
 
 /// Maximum Transmission Unit
 pub const MTU: usize = 1500;
 
-/// Thread-safe packet queue
-pub type PacketQueue = Arc<Mutex<VecDeque<Vec<u8>>>>;
+/// Idle buffers a device's pool keeps for reuse
+pub const DEFAULT_POOL_CAPACITY: usize = 256;
+
+#[derive(Debug)]
+struct PoolCore {
+    idle: ArrayQueue<Vec<u8>>,
+    buffer_size: usize,
+    allocated: AtomicU64,
+    reused: AtomicU64,
+}
+
+/// Allocation counters of a `BufferPool`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// Buffers allocated because none was idle
+    pub allocated: u64,
+    /// Buffers handed out again instead of allocated
+    pub reused: u64,
+    /// Buffers waiting for reuse
+    pub idle: usize,
+    /// Bytes idle buffers hold, assuming they are of the pool's size
+    pub idle_bytes: usize,
+}
+
+/// Pool of reusable packet buffers; clones share the same pool.
+///
+/// Packets read by smoltcp return their buffer here, and packets smoltcp
+/// sends are written into recycled buffers, so a steady flow of traffic
+/// stops hitting the allocator.
+#[derive(Debug, Clone)]
+pub struct BufferPool(Arc<PoolCore>);
+
+impl BufferPool {
+    /// Pool of buffers of at least `buffer_size` bytes, keeping up to
+    /// `capacity` of them idle
+    pub fn new(buffer_size: usize, capacity: usize) -> Self {
+        Self(Arc::new(PoolCore {
+            idle: ArrayQueue::new(capacity.max(1)),
+            buffer_size,
+            allocated: AtomicU64::new(0),
+            reused: AtomicU64::new(0),
+        }))
+    }
+
+    /// A zeroed buffer of `len` bytes, reused when one is idle
+    pub fn get(&self, len: usize) -> PooledBuffer {
+        let mut buffer = match self.0.idle.pop() {
+            Some(buffer) => {
+                self.0.reused.fetch_add(1, Ordering::Relaxed);
+                buffer
+            }
+            None => {
+                self.0.allocated.fetch_add(1, Ordering::Relaxed);
+                Vec::with_capacity(len.max(self.0.buffer_size))
+            }
+        };
+        buffer.clear();
+        buffer.resize(len, 0);
+        self.wrap(buffer)
+    }
+
+    /// Hand `buffer` back to the pool when dropped
+    pub fn wrap(&self, buffer: Vec<u8>) -> PooledBuffer {
+        PooledBuffer {
+            buffer,
+            pool: self.clone(),
+        }
+    }
+
+    /// Keep `buffer` for reuse; empty buffers, or buffers beyond the
+    /// pool's capacity, are freed. Packets from the host arrive in buffers
+    /// of their exact size, which grow on reuse if a larger packet needs them.
+    pub fn recycle(&self, buffer: Vec<u8>) {
+        if buffer.capacity() > 0 {
+            let _ = self.0.idle.push(buffer);
+        }
+    }
+
+    /// Size buffers are allocated with
+    pub fn buffer_size(&self) -> usize {
+        self.0.buffer_size
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let idle = self.0.idle.len();
+        PoolStats {
+            allocated: self.0.allocated.load(Ordering::Relaxed),
+            reused: self.0.reused.load(Ordering::Relaxed),
+            idle,
+            idle_bytes: idle * self.0.buffer_size,
+        }
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new(MTU, DEFAULT_POOL_CAPACITY)
+    }
+}
+
+/// Packet buffer that goes back to its pool when dropped
+#[derive(Debug)]
+pub struct PooledBuffer {
+    buffer: Vec<u8>,
+    pool: BufferPool,
+}
+
+impl PooledBuffer {
+    /// Take the buffer out of the pool's care, e.g. to queue it
+    pub fn into_vec(mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buffer)
+    }
+}
+
+impl Deref for PooledBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buffer
+    }
+}
+
+impl DerefMut for PooledBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer
+    }
+}
+
+impl Drop for PooledBuffer {
+    fn drop(&mut self) {
+        self.pool.recycle(std::mem::take(&mut self.buffer));
+    }
+}
+
+#[derive(Debug, Default)]
+struct QueueCore {
+    packets: SegQueue<Vec<u8>>,
+    bytes: AtomicUsize,
+    notify: Arc<Notify>,
+}
+
+/// Lock-free packet queue; clones share the same queue.
+///
+/// Pushing never blocks on the poll loop, and wakes whoever waits on
+/// `notifier` so a reader thread can hand packets over without contention.
+#[derive(Debug, Clone, Default)]
+pub struct PacketQueue(Arc<QueueCore>);
+
+impl PacketQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push_back(&self, packet: Vec<u8>) {
+        self.0.bytes.fetch_add(packet.len(), Ordering::Relaxed);
+        self.0.packets.push(packet);
+        self.0.notify.notify_one();
+    }
+
+    pub fn pop_front(&self) -> Option<Vec<u8>> {
+        let packet = self.0.packets.pop()?;
+        self.0.bytes.fetch_sub(packet.len(), Ordering::Relaxed);
+        Some(packet)
+    }
+
+    /// Take the packets queued so far; packets pushed meanwhile may be left
+    pub fn drain(&self) -> Vec<Vec<u8>> {
+        (0..self.len()).map_while(|_| self.pop_front()).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.packets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.packets.is_empty()
+    }
+
+    /// Bytes of all queued packets
+    pub fn bytes(&self) -> usize {
+        self.0.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Notified after every push
+    pub fn notifier(&self) -> Arc<Notify> {
+        Arc::clone(&self.0.notify)
+    }
+
+    /// Handle that does not keep the queue alive
+    pub fn downgrade(&self) -> WeakPacketQueue {
+        WeakPacketQueue(Arc::downgrade(&self.0))
+    }
+}
+
+/// Non-owning handle to a `PacketQueue`
+#[derive(Debug, Clone)]
+pub struct WeakPacketQueue(Weak<QueueCore>);
+
+impl WeakPacketQueue {
+    pub fn upgrade(&self) -> Option<PacketQueue> {
+        self.0.upgrade().map(PacketQueue)
+    }
+
+    /// Whether the queue has been dropped
+    pub fn is_dropped(&self) -> bool {
+        self.0.strong_count() == 0
+    }
+}
+
+/// Receives batches of packets sent by the device, in place of polling
+/// `take_packets`
+pub type PacketSink = Arc<dyn Fn(Vec<Vec<u8>>) + Send + Sync>;
+
+/// Direction of a device queue
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueDirection {
+    /// Packets from the app waiting for smoltcp
+    Rx,
+    /// Packets from smoltcp waiting for the host
+    Tx,
+}
+
+/// Told when a queue fills up to its high watermark, with its depth
+pub type WatermarkCallback = Arc<dyn Fn(QueueDirection, usize) + Send + Sync>;
+
+/// Traffic and queue counters of a device
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceStats {
+    /// Packets from the app queued for smoltcp
+    pub rx_packets: u64,
+    /// Bytes of those packets
+    pub rx_bytes: u64,
+    /// Packets from the app dropped because the receive queue was full
+    pub rx_dropped: u64,
+    /// Deepest the receive queue has been
+    pub rx_peak_depth: u64,
+    /// Packets from smoltcp queued for the host
+    pub tx_packets: u64,
+    /// Bytes of those packets
+    pub tx_bytes: u64,
+    /// Packets from smoltcp dropped because the send queue was full
+    pub tx_dropped: u64,
+    /// Deepest the send queue has been
+    pub tx_peak_depth: u64,
+    /// Packets from the app that are not IPv4 or IPv6
+    pub malformed: u64,
+    /// Packets from the app dropped for a bad checksum
+    pub bad_checksum: u64,
+    /// Packets from the app larger than the MTU, split to fit
+    pub segmented: u64,
+    /// Packets from the app larger than the MTU that could not be split
+    pub oversized: u64,
+}
+
+impl DeviceStats {
+    /// Add the counters of another device; peak depths keep the deeper one
+    pub fn merge(&mut self, other: &DeviceStats) {
+        self.rx_packets += other.rx_packets;
+        self.rx_bytes += other.rx_bytes;
+        self.rx_dropped += other.rx_dropped;
+        self.rx_peak_depth = self.rx_peak_depth.max(other.rx_peak_depth);
+        self.tx_packets += other.tx_packets;
+        self.tx_bytes += other.tx_bytes;
+        self.tx_dropped += other.tx_dropped;
+        self.tx_peak_depth = self.tx_peak_depth.max(other.tx_peak_depth);
+        self.malformed += other.malformed;
+        self.bad_checksum += other.bad_checksum;
+        self.segmented += other.segmented;
+        self.oversized += other.oversized;
+    }
+}
+
+/// Whether `packet` starts like an IPv4 or IPv6 header
+fn is_ip_packet(packet: &[u8]) -> bool {
+    matches!(packet.first().map(|b| b >> 4), Some(4 | 6))
+}
+
+/// A packet queue with its limits and counters.
+///
+/// Limits apply to packets added through the device; code holding the raw
+/// `PacketQueue` bypasses them. Concurrent producers may overshoot the depth
+/// by a packet each, as the check and the push are not atomic together.
+struct BoundedQueue {
+    direction: QueueDirection,
+    packets: PacketQueue,
+    config: Mutex<QueueConfig>,
+    packets_in: AtomicU64,
+    bytes_in: AtomicU64,
+    dropped: AtomicU64,
+    /// Packets refused before queueing; only the receive queue checks
+    malformed: AtomicU64,
+    /// Packets dropped by checksum verification; receive queue only
+    bad_checksum: AtomicU64,
+    /// Packets split or dropped for exceeding the MTU; receive queue only
+    segmented: AtomicU64,
+    oversized: AtomicU64,
+    peak_depth: AtomicUsize,
+    /// Set while the depth is at or above the watermark, so the callback
+    /// fires once per crossing
+    above_watermark: AtomicBool,
+    on_watermark: Mutex<Option<WatermarkCallback>>,
+}
+
+impl BoundedQueue {
+    fn new(direction: QueueDirection, config: QueueConfig) -> Self {
+        Self {
+            direction,
+            packets: PacketQueue::new(),
+            config: Mutex::new(config),
+            packets_in: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            malformed: AtomicU64::new(0),
+            bad_checksum: AtomicU64::new(0),
+            segmented: AtomicU64::new(0),
+            oversized: AtomicU64::new(0),
+            peak_depth: AtomicUsize::new(0),
+            above_watermark: AtomicBool::new(false),
+            on_watermark: Mutex::new(None),
+        }
+    }
+
+    /// Queue `packet`, dropping per the policy if full. Returns whether
+    /// `packet` was queued.
+    fn push(&self, packet: Vec<u8>) -> bool {
+        if self.direction == QueueDirection::Rx && !is_ip_packet(&packet) {
+            self.malformed.fetch_add(1, Ordering::Relaxed);
+        }
+        let config = self.config.lock().map(|c| *c).unwrap_or_default();
+        let queue = &self.packets;
+        let mut queued = true;
+        if queue.len() >= config.max_depth {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            match config.policy {
+                DropPolicy::TailDrop => queued = false,
+                DropPolicy::HeadDrop => {
+                    queue.pop_front();
+                }
+            }
+        }
+        if queued && config.max_depth > 0 {
+            self.packets_in.fetch_add(1, Ordering::Relaxed);
+            self.bytes_in.fetch_add(packet.len() as u64, Ordering::Relaxed);
+            queue.push_back(packet);
+        }
+        let depth = queue.len();
+
+        self.peak_depth.fetch_max(depth, Ordering::Relaxed);
+        if depth < config.high_watermark {
+            self.above_watermark.store(false, Ordering::Relaxed);
+        } else if !self.above_watermark.swap(true, Ordering::Relaxed) {
+            log::warn!("{:?} queue reached {} packets", self.direction, depth);
+            let callback = self.on_watermark.lock().ok().and_then(|cb| cb.clone());
+            if let Some(callback) = callback {
+                callback(self.direction, depth);
+            }
+        }
+        queued && config.max_depth > 0
+    }
+
+    fn pop(&self) -> Option<Vec<u8>> {
+        self.packets.pop_front()
+    }
+
+    fn drain(&self) -> Vec<Vec<u8>> {
+        let packets = self.packets.drain();
+        self.above_watermark.store(false, Ordering::Relaxed);
+        packets
+    }
+
+    fn len(&self) -> usize {
+        self.packets.len()
+    }
+}
+
+fn read_stats(rx: &BoundedQueue, tx: &BoundedQueue) -> DeviceStats {
+    DeviceStats {
+        rx_packets: rx.packets_in.load(Ordering::Relaxed),
+        rx_bytes: rx.bytes_in.load(Ordering::Relaxed),
+        rx_dropped: rx.dropped.load(Ordering::Relaxed),
+        rx_peak_depth: rx.peak_depth.load(Ordering::Relaxed) as u64,
+        tx_packets: tx.packets_in.load(Ordering::Relaxed),
+        tx_bytes: tx.bytes_in.load(Ordering::Relaxed),
+        tx_dropped: tx.dropped.load(Ordering::Relaxed),
+        tx_peak_depth: tx.peak_depth.load(Ordering::Relaxed) as u64,
+        malformed: rx.malformed.load(Ordering::Relaxed),
+        bad_checksum: rx.bad_checksum.load(Ordering::Relaxed),
+        segmented: rx.segmented.load(Ordering::Relaxed),
+        oversized: rx.oversized.load(Ordering::Relaxed),
+    }
+}
+
+/// Reads the counters of a device without keeping it alive
+#[derive(Clone)]
+pub struct DeviceStatsReader {
+    rx: Weak<BoundedQueue>,
+    tx: Weak<BoundedQueue>,
+}
+
+impl DeviceStatsReader {
+    /// Current counters, or `None` once the device is dropped
+    pub fn read(&self) -> Option<DeviceStats> {
+        let (rx, tx) = (self.rx.upgrade()?, self.tx.upgrade()?);
+        Some(read_stats(&rx, &tx))
+    }
+}
+
+/// Queues packets from the app into a device from another thread, under
+/// the same limits as `VirtualTunDevice::inject_packet`
+#[derive(Clone)]
+pub struct PacketInjector(Arc<BoundedQueue>);
+
+impl PacketInjector {
+    /// Queue a packet; returns false if it was dropped
+    pub fn inject(&self, packet: Vec<u8>) -> bool {
+        self.0.push(packet)
+    }
+}
 
 /// Virtual TUN device that interfaces with smoltcp
 pub struct VirtualTunDevice {
-    rx_queue: PacketQueue,
-    tx_queue: PacketQueue,
+    rx: Arc<BoundedQueue>,
+    tx: Arc<BoundedQueue>,
     mtu: usize,
+    sink: Option<PacketSink>,
+    pool: BufferPool,
+    checksum: ChecksumMode,
+    /// Pieces of a split packet not yet handed to smoltcp
+    split_backlog: VecDeque<Vec<u8>>,
 }
 
 impl VirtualTunDevice {
     pub fn new() -> Self {
+        Self::with_queue_config(QueueConfig::default())
+    }
+
+    /// Create a device whose queues follow `config`
+    pub fn with_queue_config(config: QueueConfig) -> Self {
         Self {
-            rx_queue: Arc::new(Mutex::new(VecDeque::new())),
-            tx_queue: Arc::new(Mutex::new(VecDeque::new())),
+            rx: Arc::new(BoundedQueue::new(QueueDirection::Rx, config)),
+            tx: Arc::new(BoundedQueue::new(QueueDirection::Tx, config)),
             mtu: MTU,
+            sink: None,
+            pool: BufferPool::default(),
+            checksum: ChecksumMode::default(),
+            split_backlog: VecDeque::new(),
+        }
+    }
+
+    /// Choose whether received packets have their checksums verified
+    pub fn set_checksum_mode(&mut self, mode: ChecksumMode) {
+        self.checksum = mode;
+    }
+
+    pub fn checksum_mode(&self) -> ChecksumMode {
+        self.checksum
+    }
+
+    /// Next received packet, dropping those that fail verification and
+    /// splitting those larger than the MTU
+    fn next_rx_packet(&mut self) -> Option<Vec<u8>> {
+        if let Some(piece) = self.split_backlog.pop_front() {
+            return Some(piece);
+        }
+        loop {
+            let packet = self.rx.pop()?;
+            if self.checksum == ChecksumMode::Verify && !verify_checksums(&packet) {
+                self.rx.bad_checksum.fetch_add(1, Ordering::Relaxed);
+                log::debug!("Dropped {} byte packet with a bad checksum", packet.len());
+                self.pool.recycle(packet);
+                continue;
+            }
+            if packet.len() <= self.mtu {
+                return Some(packet);
+            }
+
+            let pieces = split_oversized(&packet, self.mtu);
+            self.pool.recycle(packet);
+            match pieces {
+                Some(pieces) => {
+                    self.rx.segmented.fetch_add(1, Ordering::Relaxed);
+                    self.split_backlog.extend(pieces);
+                    return self.split_backlog.pop_front();
+                }
+                None => {
+                    self.rx.oversized.fetch_add(1, Ordering::Relaxed);
+                    log::debug!("Dropped packet larger than the {} byte MTU", self.mtu);
+                }
+            }
+        }
+    }
+
+    /// Recycle packet buffers through `pool`, e.g. one shared by several
+    /// devices
+    pub fn set_buffer_pool(&mut self, pool: BufferPool) {
+        self.pool = pool;
+    }
+
+    pub fn buffer_pool(&self) -> &BufferPool {
+        &self.pool
+    }
+
+    /// Return buffers of packets taken from the device, once written out,
+    /// so the device can send through them again
+    pub fn recycle(&self, packets: Vec<Vec<u8>>) {
+        for packet in packets {
+            self.pool.recycle(packet);
+        }
+    }
+
+    /// Change the queue limits; packets already queued are kept
+    pub fn set_queue_config(&mut self, config: QueueConfig) {
+        for queue in [&self.rx, &self.tx] {
+            if let Ok(mut current) = queue.config.lock() {
+                *current = config;
+            }
+        }
+    }
+
+    /// Call `callback` whenever a queue fills up to its high watermark
+    pub fn set_watermark_callback(&mut self, callback: Option<WatermarkCallback>) {
+        for queue in [&self.rx, &self.tx] {
+            if let Ok(mut slot) = queue.on_watermark.lock() {
+                *slot = callback.clone();
+            }
+        }
+    }
+
+    /// Traffic, drop and queue depth counters
+    pub fn stats(&self) -> DeviceStats {
+        read_stats(&self.rx, &self.tx)
+    }
+
+    /// Handle for reading `stats` from elsewhere
+    pub fn stats_reader(&self) -> DeviceStatsReader {
+        DeviceStatsReader {
+            rx: Arc::downgrade(&self.rx),
+            tx: Arc::downgrade(&self.tx),
         }
     }
 
@@ -32,34 +560,62 @@ impl VirtualTunDevice {
         self
     }
 
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    pub fn set_mtu(&mut self, mtu: usize) {
+        self.mtu = mtu;
+    }
+
     pub fn rx_queue(&self) -> PacketQueue {
-        Arc::clone(&self.rx_queue)
+        self.rx.packets.clone()
     }
 
     pub fn tx_queue(&self) -> PacketQueue {
-        Arc::clone(&self.tx_queue)
+        self.tx.packets.clone()
     }
 
-    pub fn inject_packet(&self, packet: Vec<u8>) {
-        if let Ok(mut queue) = self.rx_queue.lock() {
-            queue.push_back(packet);
-        }
+    /// Queue a packet from the app; returns false if it was dropped
+    pub fn inject_packet(&self, packet: Vec<u8>) -> bool {
+        self.rx.push(packet)
     }
 
     pub fn take_packets(&self) -> Vec<Vec<u8>> {
-        if let Ok(mut queue) = self.tx_queue.lock() {
-            queue.drain(..).collect()
-        } else {
-            Vec::new()
+        self.tx.drain()
+    }
+
+    /// Handle for queueing packets from the app without the device
+    pub fn injector(&self) -> PacketInjector {
+        PacketInjector(Arc::clone(&self.rx))
+    }
+
+    /// Deliver sent packets to `sink` on every `flush` (`None` goes back to
+    /// queueing them for `take_packets`)
+    pub fn set_sink(&mut self, sink: Option<PacketSink>) {
+        self.sink = sink;
+    }
+
+    /// Hand queued packets to the sink as one batch, returning how many
+    /// were delivered
+    pub fn flush(&self) -> usize {
+        let Some(sink) = &self.sink else {
+            return 0;
+        };
+        let packets = self.take_packets();
+        let count = packets.len();
+        if count > 0 {
+            sink(packets);
         }
+        count
     }
 
     pub fn has_rx_packets(&self) -> bool {
-        self.rx_queue.lock().map(|q| !q.is_empty()).unwrap_or(false)
+        !self.split_backlog.is_empty() || self.rx.len() > 0
     }
 
     pub fn pending_tx_count(&self) -> usize {
-        self.tx_queue.lock().map(|q| q.len()).unwrap_or(0)
+        self.tx.len()
     }
 }
 
@@ -77,25 +633,37 @@ impl Device for VirtualTunDevice {
         let mut caps = DeviceCapabilities::default();
         caps.medium = Medium::Ip;
         caps.max_transmission_unit = self.mtu;
+        // Received packets were verified by `receive`, or are trusted
+        caps.checksum.ipv4 = Checksum::Tx;
+        caps.checksum.tcp = Checksum::Tx;
+        caps.checksum.udp = Checksum::Tx;
         caps
     }
 
     fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
-        let packet = self.rx_queue.lock().ok()?.pop_front()?;
+        let packet = self.next_rx_packet()?;
         
         Some((
-            VirtualRxToken { packet },
-            VirtualTxToken { queue: Arc::clone(&self.tx_queue) },
+            VirtualRxToken {
+                packet: self.pool.wrap(packet),
+            },
+            VirtualTxToken {
+                queue: Arc::clone(&self.tx),
+                pool: self.pool.clone(),
+            },
         ))
     }
 
     fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
-        Some(VirtualTxToken { queue: Arc::clone(&self.tx_queue) })
+        Some(VirtualTxToken {
+            queue: Arc::clone(&self.tx),
+            pool: self.pool.clone(),
+        })
     }
 }
 
 pub struct VirtualRxToken {
-    packet: Vec<u8>,
+    packet: PooledBuffer,
 }
 
 impl RxToken for VirtualRxToken {
@@ -109,7 +677,8 @@ impl RxToken for VirtualRxToken {
 }
 
 pub struct VirtualTxToken {
-    queue: PacketQueue,
+    queue: Arc<BoundedQueue>,
+    pool: BufferPool,
 }
 
 impl TxToken for VirtualTxToken {
@@ -117,12 +686,10 @@ impl TxToken for VirtualTxToken {
     where
         F: FnOnce(&mut [u8]) -> R,
     {
-        let mut buffer = vec![0u8; len];
+        let mut buffer = self.pool.get(len);
         let result = f(&mut buffer);
         
-        if let Ok(mut queue) = self.queue.lock() {
-            queue.push_back(buffer);
-        }
+        self.queue.push(buffer.into_vec());
         
         result
     }
@@ -154,6 +721,223 @@ mod tests {
         assert_eq!(caps.max_transmission_unit, MTU);
     }
 
+    #[test]
+    fn test_flush_to_sink() {
+        let mut device = VirtualTunDevice::new();
+        let queue = device.tx_queue();
+        queue.push_back(vec![1]);
+        assert_eq!(device.flush(), 0);
+        assert_eq!(device.pending_tx_count(), 1);
+
+        let batches = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&batches);
+        device.set_sink(Some(Arc::new(move |packets| sink.lock().unwrap().push(packets))));
+        queue.push_back(vec![2]);
+        assert_eq!(device.flush(), 2);
+        assert_eq!(device.flush(), 0);
+        assert_eq!(*batches.lock().unwrap(), [vec![vec![1], vec![2]]]);
+    }
+
+    #[test]
+    fn test_packet_queue() {
+        let queue = PacketQueue::new();
+        let weak = queue.downgrade();
+        let producers: Vec<_> = (0..4)
+            .map(|_| {
+                let queue = queue.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..100 {
+                        queue.push_back(vec![0; 10]);
+                    }
+                })
+            })
+            .collect();
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        assert_eq!(queue.len(), 400);
+        assert_eq!(queue.bytes(), 4000);
+
+        assert!(queue.pop_front().is_some());
+        assert_eq!(queue.drain().len(), 399);
+        assert_eq!(queue.bytes(), 0);
+        assert!(queue.is_empty());
+
+        drop(queue);
+        assert!(weak.is_dropped());
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn test_buffer_pool() {
+        let pool = BufferPool::new(64, 2);
+        let mut buffer = pool.get(10);
+        buffer[0] = 7;
+        assert_eq!(buffer.len(), 10);
+        drop(buffer);
+
+        // Reused, and zeroed again
+        let buffer = pool.get(20);
+        assert_eq!(&buffer[..], &[0; 20][..]);
+        let kept = buffer.into_vec();
+        assert_eq!(pool.stats().idle, 0);
+
+        pool.recycle(kept);
+        pool.recycle(Vec::new());
+        pool.recycle(vec![0; 8]);
+        pool.recycle(Vec::with_capacity(64));
+        assert_eq!(
+            pool.stats(),
+            PoolStats {
+                allocated: 1,
+                reused: 1,
+                idle: 2,
+                idle_bytes: 128,
+            }
+        );
+    }
+
+    #[test]
+    fn test_tokens_recycle_buffers() {
+        let mut device = VirtualTunDevice::new();
+        device.set_checksum_mode(ChecksumMode::Skip);
+        device.inject_packet(Vec::with_capacity(MTU));
+        let (rx, tx) = device.receive(Instant::from_millis(0)).unwrap();
+        rx.consume(|_| ());
+        assert_eq!(device.buffer_pool().stats().idle, 1);
+
+        tx.consume(40, |buffer| buffer[0] = 0x45);
+        assert_eq!(device.buffer_pool().stats().reused, 1);
+        let sent = device.take_packets();
+        assert_eq!(sent[0].len(), 40);
+
+        device.recycle(sent);
+        assert_eq!(device.buffer_pool().stats().idle, 1);
+    }
+
+    #[test]
+    fn test_tail_drop() {
+        let device = VirtualTunDevice::with_queue_config(QueueConfig::with_depth(2));
+        assert!(device.inject_packet(vec![1]));
+        assert!(device.inject_packet(vec![2]));
+        assert!(!device.inject_packet(vec![3]));
+
+        assert_eq!(device.rx_queue().drain(), [vec![1], vec![2]]);
+        assert_eq!(
+            device.stats(),
+            DeviceStats {
+                rx_packets: 2,
+                rx_bytes: 2,
+                malformed: 3,
+                rx_dropped: 1,
+                rx_peak_depth: 2,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_head_drop_and_watermark() {
+        let mut device = VirtualTunDevice::with_queue_config(QueueConfig {
+            policy: DropPolicy::HeadDrop,
+            ..QueueConfig::with_depth(4)
+        });
+        let warnings = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&warnings);
+        device.set_watermark_callback(Some(Arc::new(move |direction, depth| {
+            seen.lock().unwrap().push((direction, depth))
+        })));
+
+        for i in 0..6 {
+            assert!(device.inject_packet(vec![i]));
+        }
+        assert_eq!(device.rx_queue().drain(), [vec![2], vec![3], vec![4], vec![5]]);
+        assert_eq!(device.stats().rx_dropped, 2);
+        // Fired once when the queue reached 3 of 4 packets
+        assert_eq!(*warnings.lock().unwrap(), [(QueueDirection::Rx, 3)]);
+    }
+
+    #[test]
+    fn test_traffic_counters() {
+        let mut device = VirtualTunDevice::new();
+        device.set_checksum_mode(ChecksumMode::Skip);
+        let reader = device.stats_reader();
+        assert!(device.inject_packet(vec![0x45; 20]));
+        assert!(device.inject_packet(Vec::new()));
+        assert!(device.inject_packet(vec![0x12, 0]));
+
+        let (rx, tx) = device.receive(Instant::from_millis(0)).unwrap();
+        rx.consume(|_| ());
+        tx.consume(60, |_| ());
+        let stats = reader.read().unwrap();
+        assert_eq!((stats.rx_packets, stats.rx_bytes), (3, 22));
+        assert_eq!((stats.tx_packets, stats.tx_bytes), (1, 60));
+        assert_eq!(stats.malformed, 2);
+
+        let mut total = stats.clone();
+        total.merge(&stats);
+        assert_eq!((total.rx_packets, total.rx_peak_depth), (6, 3));
+
+        drop(device);
+        assert!(reader.read().is_none());
+    }
+
+    #[test]
+    fn test_checksum_verification() {
+        let src = "10.0.0.2:5353".parse().unwrap();
+        let dst = "10.0.0.1:53".parse().unwrap();
+        let good = crate::packet::build_udp_packet(src, dst, b"query").unwrap();
+        let mut bad = good.clone();
+        bad[30] ^= 0xFF;
+
+        let mut device = VirtualTunDevice::new();
+        device.inject_packet(bad.clone());
+        device.inject_packet(good.clone());
+        let (rx, _) = device.receive(Instant::from_millis(0)).unwrap();
+        assert_eq!(rx.consume(|packet| packet.to_vec()), good);
+        assert_eq!(device.stats().bad_checksum, 1);
+
+        // Skipped: the corrupt packet reaches smoltcp, which trusts it too
+        device.set_checksum_mode(ChecksumMode::Skip);
+        device.inject_packet(bad.clone());
+        let (rx, _) = device.receive(Instant::from_millis(0)).unwrap();
+        assert_eq!(rx.consume(|packet| packet.to_vec()), bad);
+        assert!(matches!(device.capabilities().checksum.udp, Checksum::Tx));
+        assert_eq!(device.stats().bad_checksum, 1);
+    }
+
+    #[test]
+    fn test_split_oversized() {
+        use smoltcp::wire::Ipv4Packet;
+
+        let mut device = VirtualTunDevice::new().with_mtu(576);
+        device.set_checksum_mode(ChecksumMode::Skip);
+        let mut tcp = crate::create_tcp_packet([10, 0, 0, 2], [10, 0, 0, 1], 40000, 80, false);
+        tcp.extend_from_slice(&[7; 1000]);
+        let total_len = tcp.len() as u16;
+        tcp[2..4].copy_from_slice(&total_len.to_be_bytes());
+        let mut udp = crate::packet::build_udp_packet(
+            "10.0.0.2:5353".parse().unwrap(),
+            "10.0.0.1:53".parse().unwrap(),
+            &[0; 1000],
+        )
+        .unwrap();
+        device.inject_packet(tcp);
+        // Don't Fragment, as smoltcp sets it, then cleared
+        device.inject_packet(udp.clone());
+        udp[6] &= !0x40;
+        Ipv4Packet::new_unchecked(&mut udp[..]).fill_checksum();
+        device.inject_packet(udp);
+
+        let mut sizes = Vec::new();
+        while let Some((rx, _)) = device.receive(Instant::from_millis(0)) {
+            sizes.push(rx.consume(|packet| packet.len()));
+        }
+        assert_eq!(sizes, [576, 504, 572, 476]);
+        let stats = device.stats();
+        assert_eq!((stats.segmented, stats.oversized), (2, 1));
+    }
+
     #[test]
     fn test_custom_mtu() {
         let device = VirtualTunDevice::new().with_mtu(9000);
@@ -0,0 +1,186 @@
+//! Happy Eyeballs Dialing
+//!
+//! This module dials domains on the DIRECT path the way RFC 8305
+//! describes: the resolved addresses are interleaved by family, starting
+//! with the family the resolver preferred, and each attempt gets a head
+//! start of `CONNECTION_ATTEMPT_DELAY` before the next one begins. The
+//! first connection to complete wins and the others are cancelled, so a
+//! broken IPv6 route costs a quarter second instead of a full timeout.
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use tokio::net::{lookup_host, TcpStream};
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+
+use crate::config::OutboundConfig;
+use crate::outbound;
+
+/// Head start each attempt gets before the next begins (RFC 8305 §5)
+pub const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Resolve `host` and race connections to its addresses
+pub async fn connect_host(
+    host: &str,
+    port: u16,
+    options: &OutboundConfig,
+) -> io::Result<TcpStream> {
+    let addrs = interleave(lookup_host((host, port)).await?.collect());
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} has no addresses", host),
+        ));
+    }
+    race(addrs, options, CONNECTION_ATTEMPT_DELAY).await
+}
+
+/// Order addresses so the two families alternate, starting with the
+/// family of the first address (RFC 8305 §4)
+pub fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let Some(first) = addrs.first() else {
+        return addrs;
+    };
+    let preferred_v6 = first.is_ipv6();
+    let (preferred, other): (Vec<_>, Vec<_>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == preferred_v6);
+
+    let mut ordered = Vec::with_capacity(preferred.len() + other.len());
+    let mut preferred = preferred.into_iter();
+    let mut other = other.into_iter();
+    loop {
+        match (preferred.next(), other.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Connect to the first of `addrs` to answer
+///
+/// A new attempt starts every `delay`, or as soon as the previous one
+/// fails. Attempts still in flight when one succeeds are aborted.
+pub async fn race(
+    addrs: Vec<SocketAddr>,
+    options: &OutboundConfig,
+    delay: Duration,
+) -> io::Result<TcpStream> {
+    let mut attempts = JoinSet::new();
+    let mut pending = addrs.into_iter();
+    let mut last_error = None;
+
+    loop {
+        if let Some(addr) = pending.next() {
+            let options = *options;
+            attempts.spawn(async move { outbound::connect(addr, &options).await });
+        } else if attempts.is_empty() {
+            break;
+        }
+
+        // Wait for an attempt to finish, giving up after `delay` while
+        // there are still addresses left to try
+        let finished = if pending.len() > 0 {
+            match timeout(delay, attempts.join_next()).await {
+                Ok(finished) => finished,
+                Err(_) => continue,
+            }
+        } else {
+            attempts.join_next().await
+        };
+        match finished {
+            Some(Ok(Ok(stream))) => return Ok(stream),
+            Some(Ok(Err(e))) => last_error = Some(e),
+            Some(Err(e)) => last_error = Some(io::Error::other(e)),
+            None => {}
+        }
+    }
+
+    Err(last_error
+        .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no addresses to connect to")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener as StdTcpListener;
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    /// An address nothing listens on
+    fn closed_addr() -> SocketAddr {
+        let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap()
+    }
+
+    #[test]
+    fn test_interleave_alternates_families() {
+        let v6a: SocketAddr = "[2001:db8::1]:443".parse().unwrap();
+        let v6b: SocketAddr = "[2001:db8::2]:443".parse().unwrap();
+        let v6c: SocketAddr = "[2001:db8::3]:443".parse().unwrap();
+        let v4a: SocketAddr = "192.0.2.1:443".parse().unwrap();
+        let v4b: SocketAddr = "192.0.2.2:443".parse().unwrap();
+
+        assert_eq!(
+            interleave(vec![v6a, v6b, v6c, v4a, v4b]),
+            vec![v6a, v4a, v6b, v4b, v6c]
+        );
+        // The resolver's preferred family goes first
+        assert_eq!(interleave(vec![v4a, v4b, v6a]), vec![v4a, v6a, v4b]);
+        assert!(interleave(Vec::new()).is_empty());
+    }
+
+    #[test]
+    fn test_race_skips_failed_attempts() {
+        let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let options = OutboundConfig::default();
+
+        let stream = runtime()
+            .block_on(race(
+                vec![closed_addr(), addr],
+                &options,
+                Duration::from_secs(10),
+            ))
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), addr);
+    }
+
+    #[test]
+    fn test_race_reports_last_error() {
+        let options = OutboundConfig::default();
+        let error = runtime()
+            .block_on(race(
+                vec![closed_addr()],
+                &options,
+                CONNECTION_ATTEMPT_DELAY,
+            ))
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+
+        let error = runtime()
+            .block_on(race(Vec::new(), &options, CONNECTION_ATTEMPT_DELAY))
+            .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn test_connect_host() {
+        let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let options = OutboundConfig::default();
+
+        let stream = runtime()
+            .block_on(connect_host("localhost", port, &options))
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap().port(), port);
+        assert!(stream.nodelay().unwrap());
+    }
+}
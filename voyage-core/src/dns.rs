@@ -0,0 +1,1674 @@
+//! DNS Forwarder
+//!
+//! This module parses DNS messages intercepted on port 53, decides how each
+//! query is answered (locally, through a direct upstream, or through the
+//! proxy's resolver) and exchanges queries with the chosen upstreams.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UdpSocket;
+
+use crate::config::DnsConfig;
+use crate::dnsrule::{DnsAction, DnsRuleSet};
+use crate::error::VoyageError;
+use crate::fakeip::FakeIpPool;
+use crate::hosts::{HostEntry, HostTable, HOST_TTL};
+use crate::packet::ParseErrorKind;
+use crate::querylog::{DnsQueryLog, DnsQueryRecord};
+use crate::rule::RouteAction;
+use crate::socks5::{Socks5Client, TargetAddr};
+
+/// Well-known DNS port
+pub const DNS_PORT: u16 = 53;
+
+/// IPv4 address record
+pub const TYPE_A: u16 = 1;
+/// Canonical name record
+pub const TYPE_CNAME: u16 = 5;
+/// Start of authority record (carries the negative-caching TTL)
+pub const TYPE_SOA: u16 = 6;
+/// IPv6 address record
+pub const TYPE_AAAA: u16 = 28;
+/// Internet class
+pub const CLASS_IN: u16 = 1;
+
+/// No error
+pub const RCODE_NOERROR: u8 = 0;
+/// The query could not be parsed
+pub const RCODE_FORMERR: u8 = 1;
+/// The upstream failed
+pub const RCODE_SERVFAIL: u8 = 2;
+/// The name does not exist
+pub const RCODE_NXDOMAIN: u8 = 3;
+
+/// TTL of synthesized fake-IP answers, kept short so stale mappings expire
+pub const FAKE_IP_TTL: u32 = 1;
+
+/// Negative-caching TTL when an NXDOMAIN/NODATA answer carries no SOA
+pub const DEFAULT_NEGATIVE_TTL: u32 = 60;
+
+/// Upper bound on how long any answer is cached
+pub const MAX_CACHE_TTL: u32 = 3600;
+
+/// Minimum time a resolved address keeps its name; apps often connect after
+/// a short-TTL answer has expired
+pub const DOMAIN_MAP_MIN_TTL: u32 = 300;
+
+/// Top-level domain resolved by multicast DNS (RFC 6762)
+const MDNS_DOMAIN: &str = "local";
+
+/// Reverse lookup zones of the link-local ranges, 169.254.0.0/16 and
+/// fe80::/10
+const LINK_LOCAL_REVERSE_ZONES: [&str; 5] = [
+    "254.169.in-addr.arpa",
+    "8.e.f.ip6.arpa",
+    "9.e.f.ip6.arpa",
+    "a.e.f.ip6.arpa",
+    "b.e.f.ip6.arpa",
+];
+
+const HEADER_LEN: usize = 12;
+const FLAG_QR: u16 = 0x8000;
+const FLAG_RD: u16 = 0x0100;
+const FLAG_RA: u16 = 0x0080;
+const MAX_NAME_LEN: usize = 255;
+const MAX_POINTER_HOPS: usize = 32;
+const MAX_UDP_RESPONSE: usize = 4096;
+
+/// A question section entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsQuestion {
+    pub name: String,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+/// Record payload; types without special handling are kept verbatim
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Cname(String),
+    Other(Vec<u8>),
+}
+
+/// A resource record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsRecord {
+    pub name: String,
+    pub rtype: u16,
+    pub rclass: u16,
+    pub ttl: u32,
+    pub data: RecordData,
+}
+
+impl DnsRecord {
+    /// Create an IN A record
+    pub fn a(name: impl Into<String>, addr: Ipv4Addr, ttl: u32) -> Self {
+        Self {
+            name: name.into(),
+            rtype: TYPE_A,
+            rclass: CLASS_IN,
+            ttl,
+            data: RecordData::A(addr),
+        }
+    }
+
+    /// Create an IN CNAME record
+    pub fn cname(name: impl Into<String>, target: impl Into<String>, ttl: u32) -> Self {
+        Self {
+            name: name.into(),
+            rtype: TYPE_CNAME,
+            rclass: CLASS_IN,
+            ttl,
+            data: RecordData::Cname(target.into()),
+        }
+    }
+
+    /// Create an IN AAAA record
+    pub fn aaaa(name: impl Into<String>, addr: Ipv6Addr, ttl: u32) -> Self {
+        Self {
+            name: name.into(),
+            rtype: TYPE_AAAA,
+            rclass: CLASS_IN,
+            ttl,
+            data: RecordData::Aaaa(addr),
+        }
+    }
+}
+
+/// A parsed DNS message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsMessage {
+    pub id: u16,
+    pub flags: u16,
+    pub questions: Vec<DnsQuestion>,
+    pub answers: Vec<DnsRecord>,
+    pub authorities: Vec<DnsRecord>,
+    pub additionals: Vec<DnsRecord>,
+}
+
+/// Forward-only reader that follows compression pointers in names
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self.pos.checked_add(len).ok_or("Length overflow")?;
+        let slice = self.data.get(self.pos..end).ok_or("Truncated DNS message")?;
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let mut labels: Vec<String> = Vec::new();
+        let mut len = 0;
+        let mut pos = self.pos;
+        let mut resume = None;
+        let mut hops = 0;
+
+        loop {
+            let b = *self.data.get(pos).ok_or("Truncated name")?;
+            match b & 0xC0 {
+                0x00 if b == 0 => {
+                    pos += 1;
+                    break;
+                }
+                0x00 => {
+                    let label = self
+                        .data
+                        .get(pos + 1..pos + 1 + b as usize)
+                        .ok_or("Truncated label")?;
+                    len += label.len() + 1;
+                    if len > MAX_NAME_LEN {
+                        return Err("Name too long".into());
+                    }
+                    labels.push(String::from_utf8_lossy(label).into_owned());
+                    pos += 1 + b as usize;
+                }
+                0xC0 => {
+                    let lo = *self.data.get(pos + 1).ok_or("Truncated pointer")?;
+                    hops += 1;
+                    if hops > MAX_POINTER_HOPS {
+                        return Err("Compression loop".into());
+                    }
+                    resume.get_or_insert(pos + 2);
+                    pos = (((b & 0x3F) as usize) << 8) | lo as usize;
+                }
+                _ => return Err("Unsupported label type".into()),
+            }
+        }
+
+        self.pos = resume.unwrap_or(pos);
+        Ok(labels.join("."))
+    }
+
+    fn question(&mut self) -> Result<DnsQuestion, String> {
+        Ok(DnsQuestion {
+            name: self.name()?,
+            qtype: self.u16()?,
+            qclass: self.u16()?,
+        })
+    }
+
+    fn record(&mut self) -> Result<DnsRecord, String> {
+        let name = self.name()?;
+        let rtype = self.u16()?;
+        let rclass = self.u16()?;
+        let ttl = self.u32()?;
+        let rdlen = self.u16()? as usize;
+        let start = self.pos;
+        let rdata = self.bytes(rdlen)?;
+
+        let data = match (rtype, rdlen) {
+            (TYPE_A, 4) => RecordData::A(Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3])),
+            (TYPE_AAAA, 16) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(rdata);
+                RecordData::Aaaa(Ipv6Addr::from(octets))
+            }
+            (TYPE_CNAME, _) => {
+                let end = self.pos;
+                self.pos = start;
+                let target = self.name()?;
+                self.pos = end;
+                RecordData::Cname(target)
+            }
+            _ => RecordData::Other(rdata.to_vec()),
+        };
+
+        Ok(DnsRecord {
+            name,
+            rtype,
+            rclass,
+            ttl,
+            data,
+        })
+    }
+}
+
+fn write_name(out: &mut Vec<u8>, name: &str) {
+    for label in name.trim_end_matches('.').split('.').filter(|l| !l.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        out.push(label.len() as u8);
+        out.extend_from_slice(label);
+    }
+    out.push(0);
+}
+
+fn write_record(out: &mut Vec<u8>, record: &DnsRecord) {
+    write_name(out, &record.name);
+    out.extend_from_slice(&record.rtype.to_be_bytes());
+    out.extend_from_slice(&record.rclass.to_be_bytes());
+    out.extend_from_slice(&record.ttl.to_be_bytes());
+
+    let rdata = match &record.data {
+        RecordData::A(addr) => addr.octets().to_vec(),
+        RecordData::Aaaa(addr) => addr.octets().to_vec(),
+        RecordData::Cname(target) => {
+            let mut buf = Vec::new();
+            write_name(&mut buf, target);
+            buf
+        }
+        RecordData::Other(raw) => raw.clone(),
+    };
+    out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    out.extend_from_slice(&rdata);
+}
+
+impl DnsMessage {
+    /// Parse a DNS message from wire format
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        if data.len() < HEADER_LEN {
+            return Err("DNS message too short".into());
+        }
+
+        let mut reader = Reader::new(data);
+        let id = reader.u16()?;
+        let flags = reader.u16()?;
+        let qdcount = reader.u16()?;
+        let ancount = reader.u16()?;
+        let nscount = reader.u16()?;
+        let arcount = reader.u16()?;
+
+        let questions = (0..qdcount)
+            .map(|_| reader.question())
+            .collect::<Result<Vec<_>, _>>()?;
+        let answers = (0..ancount)
+            .map(|_| reader.record())
+            .collect::<Result<Vec<_>, _>>()?;
+        let authorities = (0..nscount)
+            .map(|_| reader.record())
+            .collect::<Result<Vec<_>, _>>()?;
+        let additionals = (0..arcount)
+            .map(|_| reader.record())
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Self {
+            id,
+            flags,
+            questions,
+            answers,
+            authorities,
+            additionals,
+        })
+    }
+
+    /// Encode to wire format (without name compression)
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(512);
+        out.extend_from_slice(&self.id.to_be_bytes());
+        out.extend_from_slice(&self.flags.to_be_bytes());
+        for count in [
+            self.questions.len(),
+            self.answers.len(),
+            self.authorities.len(),
+            self.additionals.len(),
+        ] {
+            out.extend_from_slice(&(count as u16).to_be_bytes());
+        }
+
+        for q in &self.questions {
+            write_name(&mut out, &q.name);
+            out.extend_from_slice(&q.qtype.to_be_bytes());
+            out.extend_from_slice(&q.qclass.to_be_bytes());
+        }
+        for record in self
+            .answers
+            .iter()
+            .chain(&self.authorities)
+            .chain(&self.additionals)
+        {
+            write_record(&mut out, record);
+        }
+        out
+    }
+
+    /// Build an empty response to `query` with the given rcode
+    pub fn reply(query: &DnsMessage, rcode: u8) -> Self {
+        Self {
+            id: query.id,
+            flags: FLAG_QR | (query.flags & FLAG_RD) | FLAG_RA | (rcode & 0x0F) as u16,
+            questions: query.questions.clone(),
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+        }
+    }
+
+    /// Add an answer record
+    pub fn with_answer(mut self, record: DnsRecord) -> Self {
+        self.answers.push(record);
+        self
+    }
+
+    /// Check if this is a response
+    pub fn is_response(&self) -> bool {
+        self.flags & FLAG_QR != 0
+    }
+
+    /// Response code
+    pub fn rcode(&self) -> u8 {
+        (self.flags & 0x0F) as u8
+    }
+
+    /// The first question, which is the only one resolvers act on
+    pub fn question(&self) -> Option<&DnsQuestion> {
+        self.questions.first()
+    }
+
+    /// Copy of this query asking for `name` instead
+    pub fn renamed(&self, name: &str) -> DnsMessage {
+        let mut query = self.clone();
+        if let Some(question) = query.questions.first_mut() {
+            question.name = name.to_string();
+        }
+        query
+    }
+
+    /// Answer `query` from the upstream `response` to its rewrite to
+    /// `target`: a CNAME to the target followed by the target's records
+    pub fn rewritten_reply(query: &DnsMessage, target: &str, response: &DnsMessage) -> Self {
+        let mut reply = Self::reply(query, response.rcode());
+        let Some(question) = query.question() else {
+            return reply;
+        };
+        // Only fully decoded records; raw RDATA may hold compression pointers
+        let records: Vec<DnsRecord> = response
+            .answers
+            .iter()
+            .filter(|r| !matches!(r.data, RecordData::Other(_)))
+            .cloned()
+            .collect();
+        let ttl = records
+            .iter()
+            .map(|r| r.ttl)
+            .min()
+            .unwrap_or(DEFAULT_NEGATIVE_TTL);
+        reply.answers.push(DnsRecord::cname(question.name.clone(), target, ttl));
+        reply.answers.extend(records);
+        reply
+    }
+}
+
+/// How a query will be answered
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnsPlan {
+    /// Answer locally with this response
+    Answer(DnsMessage),
+    /// Forward to the upstreams in order, optionally through the proxy,
+    /// asking for `rewrite` instead of the queried name when set
+    Forward {
+        upstreams: Vec<SocketAddr>,
+        via_proxy: bool,
+        rewrite: Option<String>,
+    },
+}
+
+/// DNS forwarder counters
+#[derive(Debug, Clone, Default)]
+pub struct DnsStats {
+    /// Queries seen
+    pub queries: u64,
+    /// Queries answered without contacting an upstream
+    pub local_answers: u64,
+    /// Queries sent to a direct upstream
+    pub forwarded_direct: u64,
+    /// Queries sent to the proxy's resolver
+    pub forwarded_proxy: u64,
+    /// Forwarded queries that failed and were answered with SERVFAIL
+    pub failures: u64,
+    /// Queries blocked by a DNS rule
+    pub blocked: u64,
+    /// Queries answered from the cache (including negative answers)
+    pub cache_hits: u64,
+    /// Cacheable queries that had to go upstream
+    pub cache_misses: u64,
+    /// Cache hits that returned NXDOMAIN/NODATA
+    pub cache_negative_hits: u64,
+    /// Entries dropped to make room for new ones
+    pub cache_evictions: u64,
+    /// Entries currently cached
+    pub cache_entries: u64,
+}
+
+/// Cache key: lowercased name, type and class
+type CacheKey = (String, u16, u16);
+
+#[derive(Debug)]
+struct CacheEntry {
+    response: DnsMessage,
+    stored_at: Instant,
+    expires_at: Instant,
+    negative: bool,
+    /// LRU clock value of the last access
+    last_used: u64,
+    /// Estimated heap bytes, fixed when stored
+    bytes: usize,
+}
+
+/// LRU + TTL cache of upstream answers, including NXDOMAIN/NODATA
+#[derive(Debug)]
+pub struct DnsCache {
+    entries: HashMap<CacheKey, CacheEntry>,
+    capacity: usize,
+    clock: u64,
+    /// Estimated heap bytes of all entries
+    bytes: usize,
+    hits: u64,
+    misses: u64,
+    negative_hits: u64,
+    evictions: u64,
+}
+
+fn cache_key(question: &DnsQuestion) -> CacheKey {
+    (
+        question.name.trim_end_matches('.').to_ascii_lowercase(),
+        question.qtype,
+        question.qclass,
+    )
+}
+
+/// Negative-caching TTL from the SOA in the authority section (RFC 2308)
+fn negative_ttl(response: &DnsMessage) -> u32 {
+    response
+        .authorities
+        .iter()
+        .find(|r| r.rtype == TYPE_SOA)
+        .and_then(|soa| match &soa.data {
+            // MINIMUM is the last field of the SOA RDATA
+            RecordData::Other(raw) if raw.len() >= 4 => {
+                let min = &raw[raw.len() - 4..];
+                let minimum = u32::from_be_bytes([min[0], min[1], min[2], min[3]]);
+                Some(soa.ttl.min(minimum))
+            }
+            _ => None,
+        })
+        .unwrap_or(DEFAULT_NEGATIVE_TTL)
+}
+
+impl DnsCache {
+    /// Create a cache holding at most `capacity` answers (0 disables caching)
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            capacity,
+            clock: 0,
+            bytes: 0,
+            hits: 0,
+            misses: 0,
+            negative_hits: 0,
+            evictions: 0,
+        }
+    }
+
+    /// Number of cached answers (including expired ones not yet evicted)
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check if the cache is empty
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Estimated heap bytes of the cached answers, sizing each response
+    /// by its wire length when it was stored
+    pub fn estimated_bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Drop every cached answer
+    pub fn flush(&mut self) {
+        self.entries.clear();
+        self.bytes = 0;
+    }
+
+    /// Drop the answer stored under `key`
+    fn remove(&mut self, key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.bytes -= entry.bytes;
+        }
+    }
+
+    /// Look up an answer for `query`, with its ID and remaining TTLs rewritten
+    pub fn get(&mut self, query: &DnsMessage, now: Instant) -> Option<DnsMessage> {
+        if self.capacity == 0 {
+            return None;
+        }
+        let key = cache_key(query.question()?);
+
+        let fresh = self
+            .entries
+            .get(&key)
+            .map(|entry| entry.expires_at > now)
+            .unwrap_or(false);
+        if !fresh {
+            self.remove(&key);
+            self.misses += 1;
+            return None;
+        }
+
+        self.clock += 1;
+        let entry = self.entries.get_mut(&key)?;
+        entry.last_used = self.clock;
+        self.hits += 1;
+        if entry.negative {
+            self.negative_hits += 1;
+        }
+
+        let elapsed = now.duration_since(entry.stored_at).as_secs() as u32;
+        let mut response = entry.response.clone();
+        response.id = query.id;
+        for record in response
+            .answers
+            .iter_mut()
+            .chain(response.authorities.iter_mut())
+        {
+            record.ttl = record.ttl.saturating_sub(elapsed);
+        }
+        Some(response)
+    }
+
+    /// Store an upstream response; returns whether it was cacheable
+    pub fn insert(&mut self, response: &DnsMessage, now: Instant) -> bool {
+        if self.capacity == 0 || !response.is_response() {
+            return false;
+        }
+        let Some(question) = response.question() else {
+            return false;
+        };
+
+        let negative = match response.rcode() {
+            RCODE_NXDOMAIN => true,
+            RCODE_NOERROR => response.answers.is_empty(),
+            _ => return false,
+        };
+        let ttl = if negative {
+            negative_ttl(response)
+        } else {
+            response.answers.iter().map(|r| r.ttl).min().unwrap_or(0)
+        }
+        .min(MAX_CACHE_TTL);
+        if ttl == 0 {
+            return false;
+        }
+
+        let key = cache_key(question);
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            self.evict(now);
+        }
+
+        self.clock += 1;
+        let bytes =
+            std::mem::size_of::<(CacheKey, CacheEntry)>() + key.0.len() + response.encode().len();
+        self.remove(&key);
+        self.bytes += bytes;
+        self.entries.insert(
+            key,
+            CacheEntry {
+                response: response.clone(),
+                stored_at: now,
+                expires_at: now + Duration::from_secs(ttl as u64),
+                negative,
+                last_used: self.clock,
+                bytes,
+            },
+        );
+        true
+    }
+
+    /// Drop expired entries, or the least recently used one if none expired
+    fn evict(&mut self, now: Instant) {
+        let before = self.entries.len();
+        let mut freed = 0;
+        self.entries.retain(|_, entry| {
+            let live = entry.expires_at > now;
+            if !live {
+                freed += entry.bytes;
+            }
+            live
+        });
+        self.bytes -= freed;
+        if self.entries.len() == before {
+            let lru = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(key) = lru {
+                self.remove(&key);
+            }
+        }
+        self.evictions += (before - self.entries.len()) as u64;
+    }
+}
+
+#[derive(Debug)]
+struct MappedName {
+    domain: String,
+    expires_at: Instant,
+    /// Clock value of the last answer that produced this mapping
+    recorded: u64,
+}
+
+/// Reverse map from addresses in DNS answers to the name that was queried,
+/// so connections made by IP can still match DOMAIN rules
+#[derive(Debug)]
+pub struct DomainMap {
+    entries: HashMap<IpAddr, MappedName>,
+    capacity: usize,
+    clock: u64,
+}
+
+impl DomainMap {
+    /// Create a map remembering at most `capacity` addresses (0 disables it)
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            capacity,
+            clock: 0,
+        }
+    }
+
+    /// Number of mapped addresses
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check if the map is empty
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Forget every mapping
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Map every A/AAAA address in `response` to its question name.
+    ///
+    /// Addresses reached through a CNAME chain are mapped to the name the
+    /// app asked for, since that is what rules are written against.
+    pub fn record(&mut self, response: &DnsMessage, now: Instant) -> usize {
+        if self.capacity == 0 || response.rcode() != RCODE_NOERROR {
+            return 0;
+        }
+        let Some(question) = response.question() else {
+            return 0;
+        };
+        let domain = question.name.trim_end_matches('.').to_ascii_lowercase();
+
+        let mut mapped = 0;
+        for record in &response.answers {
+            let addr = match record.data {
+                RecordData::A(addr) => IpAddr::V4(addr),
+                RecordData::Aaaa(addr) => IpAddr::V6(addr),
+                _ => continue,
+            };
+            if !self.entries.contains_key(&addr) && self.entries.len() >= self.capacity {
+                self.evict(now);
+            }
+            self.clock += 1;
+            let ttl = record.ttl.max(DOMAIN_MAP_MIN_TTL);
+            self.entries.insert(
+                addr,
+                MappedName {
+                    domain: domain.clone(),
+                    expires_at: now + Duration::from_secs(ttl as u64),
+                    recorded: self.clock,
+                },
+            );
+            mapped += 1;
+        }
+        mapped
+    }
+
+    /// Name that most recently resolved to `addr`, if still fresh
+    pub fn lookup(&self, addr: IpAddr, now: Instant) -> Option<&str> {
+        self.entries
+            .get(&addr)
+            .filter(|entry| entry.expires_at > now)
+            .map(|entry| entry.domain.as_str())
+    }
+
+    /// Drop expired mappings, or the oldest one if none expired
+    fn evict(&mut self, now: Instant) {
+        let before = self.entries.len();
+        self.entries.retain(|_, entry| entry.expires_at > now);
+        if self.entries.len() == before {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.recorded)
+                .map(|(addr, _)| *addr);
+            if let Some(addr) = oldest {
+                self.entries.remove(&addr);
+            }
+        }
+    }
+}
+
+/// Decides how intercepted queries are answered
+#[derive(Debug)]
+pub struct DnsResolver {
+    config: DnsConfig,
+    stats: DnsStats,
+    cache: DnsCache,
+    hosts: HostTable,
+    rules: DnsRuleSet,
+    query_log: DnsQueryLog,
+}
+
+impl DnsResolver {
+    pub fn new(config: DnsConfig) -> Self {
+        let cache = DnsCache::new(config.cache_size);
+        let hosts = HostTable::new(config.hosts.clone());
+        let query_log = DnsQueryLog::new(config.query_log_size);
+        Self {
+            config,
+            stats: DnsStats::default(),
+            cache,
+            hosts,
+            rules: DnsRuleSet::new(),
+            query_log,
+        }
+    }
+
+    /// Get the configuration
+    pub fn config(&self) -> &DnsConfig {
+        &self.config
+    }
+
+    /// Replace the configuration (the cache is rebuilt)
+    pub fn set_config(&mut self, config: DnsConfig) {
+        self.cache = DnsCache::new(config.cache_size);
+        self.hosts = HostTable::new(config.hosts.clone());
+        if config.query_log_size != self.config.query_log_size {
+            self.query_log = DnsQueryLog::new(config.query_log_size);
+        }
+        self.config = config;
+    }
+
+    /// Get the static host table
+    pub fn hosts(&self) -> &HostTable {
+        &self.hosts
+    }
+
+    /// Get the DNS rules
+    pub fn rules(&self) -> &DnsRuleSet {
+        &self.rules
+    }
+
+    /// Replace the DNS rules
+    pub fn set_rules(&mut self, rules: DnsRuleSet) {
+        self.rules = rules;
+    }
+
+    /// Replace the static host entries
+    pub fn set_hosts(&mut self, entries: Vec<HostEntry>) {
+        self.hosts = HostTable::new(entries.clone());
+        self.config.hosts = entries;
+    }
+
+    /// Get statistics, including cache metrics
+    pub fn stats(&self) -> DnsStats {
+        DnsStats {
+            cache_hits: self.cache.hits,
+            cache_misses: self.cache.misses,
+            cache_negative_hits: self.cache.negative_hits,
+            cache_evictions: self.cache.evictions,
+            cache_entries: self.cache.len() as u64,
+            ..self.stats.clone()
+        }
+    }
+
+    /// Estimated heap bytes of the answer cache
+    pub fn cache_bytes(&self) -> usize {
+        self.cache.estimated_bytes()
+    }
+
+    /// Drop every cached answer
+    pub fn flush_cache(&mut self) {
+        self.cache.flush();
+    }
+
+    /// Recently answered queries
+    pub fn query_log(&self) -> &DnsQueryLog {
+        &self.query_log
+    }
+
+    /// Add an answered query to the query log
+    pub fn log_query(&mut self, record: DnsQueryRecord) {
+        self.query_log.push(record);
+    }
+
+    /// Forget every logged query
+    pub fn clear_query_log(&mut self) {
+        self.query_log.clear();
+    }
+
+    /// Cache a response received from an upstream
+    pub fn record_response(&mut self, response: &DnsMessage) {
+        self.cache.insert(response, Instant::now());
+    }
+
+    /// Plan the answer to a query given the rule action for its name.
+    ///
+    /// Static hosts are answered first, then DNS rules are applied. PROXY
+    /// names get a fake IP when fake-IP mode is on, otherwise they are
+    /// resolved through the proxy so the answer matches the proxy's view.
+    pub fn plan(
+        &mut self,
+        query: &DnsMessage,
+        action: &RouteAction,
+        fake_ip: &mut FakeIpPool,
+    ) -> DnsPlan {
+        self.stats.queries += 1;
+
+        let Some(question) = query.question() else {
+            return self.answer(DnsMessage::reply(query, RCODE_FORMERR));
+        };
+
+        if question.qclass == CLASS_IN {
+            let addrs = self.hosts.lookup(&question.name);
+            if !addrs.is_empty() {
+                return self.answer(host_reply(query, question, &addrs));
+            }
+        }
+
+        match self.rules.find(&question.name).cloned() {
+            Some(DnsAction::Block) => {
+                self.stats.blocked += 1;
+                return self.answer(DnsMessage::reply(query, RCODE_NXDOMAIN));
+            }
+            Some(DnsAction::BlockZero) => {
+                self.stats.blocked += 1;
+                return self.answer(zero_reply(query, question));
+            }
+            Some(DnsAction::Cname(target)) => {
+                return self.forward_uncached(query, |resolver| {
+                    let mut plan = resolver.forward(matches!(action, RouteAction::Proxy));
+                    if let DnsPlan::Forward { rewrite, .. } = &mut plan {
+                        *rewrite = Some(target);
+                    }
+                    plan
+                });
+            }
+            Some(DnsAction::Upstream(upstreams)) => {
+                return self.forward_uncached(query, |resolver| {
+                    resolver.stats.forwarded_direct += 1;
+                    DnsPlan::Forward {
+                        upstreams,
+                        via_proxy: false,
+                        rewrite: None,
+                    }
+                });
+            }
+            None => {}
+        }
+
+        if let (RouteAction::Direct, Some(upstream)) = (action, self.config.local_upstream) {
+            if self.config.is_local_name(&question.name) {
+                return self.forward_uncached(query, |resolver| {
+                    resolver.stats.forwarded_direct += 1;
+                    DnsPlan::Forward {
+                        upstreams: vec![upstream],
+                        via_proxy: false,
+                        rewrite: None,
+                    }
+                });
+            }
+        }
+
+        match action {
+            RouteAction::Reject => self.answer(DnsMessage::reply(query, RCODE_NXDOMAIN)),
+            RouteAction::Proxy if self.config.fake_ip && question.qclass == CLASS_IN => {
+                match question.qtype {
+                    TYPE_A => {
+                        let addr = fake_ip.allocate(&question.name);
+                        let record = DnsRecord::a(question.name.clone(), addr, FAKE_IP_TTL);
+                        self.answer(DnsMessage::reply(query, RCODE_NOERROR).with_answer(record))
+                    }
+                    // No fake IPv6 range: an empty answer makes apps use IPv4
+                    TYPE_AAAA => self.answer(DnsMessage::reply(query, RCODE_NOERROR)),
+                    _ => self.forward(true),
+                }
+            }
+            _ => self.forward_uncached(query, |resolver| {
+                resolver.forward(matches!(action, RouteAction::Proxy))
+            }),
+        }
+    }
+
+    /// Answer from the cache, or plan forwarding with `forward`
+    fn forward_uncached(
+        &mut self,
+        query: &DnsMessage,
+        forward: impl FnOnce(&mut Self) -> DnsPlan,
+    ) -> DnsPlan {
+        match self.cache.get(query, Instant::now()) {
+            Some(cached) => DnsPlan::Answer(cached),
+            None => forward(self),
+        }
+    }
+
+    /// Record that a forwarded query failed
+    pub fn record_failure(&mut self) {
+        self.stats.failures += 1;
+    }
+
+    fn answer(&mut self, response: DnsMessage) -> DnsPlan {
+        self.stats.local_answers += 1;
+        DnsPlan::Answer(response)
+    }
+
+    fn forward(&mut self, via_proxy: bool) -> DnsPlan {
+        let upstreams = if via_proxy {
+            self.stats.forwarded_proxy += 1;
+            vec![self.config.proxy_upstream]
+        } else {
+            self.stats.forwarded_direct += 1;
+            self.config.upstreams.clone()
+        };
+        DnsPlan::Forward {
+            upstreams,
+            via_proxy,
+            rewrite: None,
+        }
+    }
+}
+
+/// Answer a blocked query with the unspecified address of its type
+fn zero_reply(query: &DnsMessage, question: &DnsQuestion) -> DnsMessage {
+    let reply = DnsMessage::reply(query, RCODE_NOERROR);
+    match question.qtype {
+        TYPE_A => reply.with_answer(DnsRecord::a(
+            question.name.clone(),
+            Ipv4Addr::UNSPECIFIED,
+            DEFAULT_NEGATIVE_TTL,
+        )),
+        TYPE_AAAA => reply.with_answer(DnsRecord::aaaa(
+            question.name.clone(),
+            Ipv6Addr::UNSPECIFIED,
+            DEFAULT_NEGATIVE_TTL,
+        )),
+        _ => reply,
+    }
+}
+
+/// Answer a query from static host addresses; addresses of the other family
+/// are left out, giving an empty (NODATA) answer
+fn host_reply(query: &DnsMessage, question: &DnsQuestion, addrs: &[IpAddr]) -> DnsMessage {
+    let mut reply = DnsMessage::reply(query, RCODE_NOERROR);
+    for addr in addrs {
+        match (question.qtype, addr) {
+            (TYPE_A, IpAddr::V4(v4)) => {
+                reply.answers.push(DnsRecord::a(question.name.clone(), *v4, HOST_TTL))
+            }
+            (TYPE_AAAA, IpAddr::V6(v6)) => {
+                reply.answers.push(DnsRecord::aaaa(question.name.clone(), *v6, HOST_TTL))
+            }
+            _ => {}
+        }
+    }
+    reply
+}
+
+impl Default for DnsResolver {
+    fn default() -> Self {
+        Self::new(DnsConfig::default())
+    }
+}
+
+/// Whether `name` only means something on the local network: a single
+/// label (`printer`), an mDNS `.local` name, a link-local reverse lookup, or
+/// a name under one of the `search_domains`
+pub fn is_local_name(name: &str, search_domains: &[String]) -> bool {
+    let name = name.trim_end_matches('.');
+    if name.is_empty() {
+        return false;
+    }
+    !name.contains('.')
+        || std::iter::once(MDNS_DOMAIN)
+            .chain(LINK_LOCAL_REVERSE_ZONES)
+            .chain(search_domains.iter().map(|domain| domain.trim_matches('.')))
+            .any(|zone| in_zone(name, zone))
+}
+
+/// Whether `name` is `zone` or a name under it, ignoring case
+fn in_zone(name: &str, zone: &str) -> bool {
+    if zone.is_empty() || name.len() < zone.len() {
+        return false;
+    }
+    let (head, tail) = name.split_at(name.len() - zone.len());
+    tail.eq_ignore_ascii_case(zone) && (head.is_empty() || head.ends_with('.'))
+}
+
+fn io_error(e: std::io::Error) -> VoyageError {
+    VoyageError::io(e.to_string())
+}
+
+fn timed_out(upstream: SocketAddr) -> VoyageError {
+    VoyageError::io(format!("DNS upstream {} timed out", upstream))
+}
+
+/// Exchange a query with an upstream over UDP
+pub async fn exchange_udp(
+    upstream: SocketAddr,
+    query: &[u8],
+    timeout: Duration,
+) -> Result<Vec<u8>, VoyageError> {
+    let bind: SocketAddr = if upstream.is_ipv4() {
+        (Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = UdpSocket::bind(bind).await.map_err(io_error)?;
+    socket.connect(upstream).await.map_err(io_error)?;
+    socket.send(query).await.map_err(io_error)?;
+
+    let id = query.get(..2);
+    let mut buf = vec![0u8; MAX_UDP_RESPONSE];
+    tokio::time::timeout(timeout, async {
+        loop {
+            let n = socket.recv(&mut buf).await.map_err(io_error)?;
+            // Ignore stray datagrams that don't answer this query
+            if n >= HEADER_LEN && buf.get(..2) == id {
+                return Ok(buf[..n].to_vec());
+            }
+        }
+    })
+    .await
+    .map_err(|_| timed_out(upstream))?
+}
+
+/// Read one length-prefixed DNS message from a TCP stream (`None` on EOF)
+pub async fn read_tcp_message<S>(stream: &mut S) -> Result<Option<Vec<u8>>, VoyageError>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut len = [0u8; 2];
+    match stream.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(io_error(e)),
+    }
+    let mut message = vec![0u8; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut message).await.map_err(io_error)?;
+    Ok(Some(message))
+}
+
+/// Write one length-prefixed DNS message to a TCP stream
+pub async fn write_tcp_message<S>(stream: &mut S, message: &[u8]) -> Result<(), VoyageError>
+where
+    S: AsyncWrite + Unpin,
+{
+    let len = u16::try_from(message.len())
+        .map_err(|_| VoyageError::packet(ParseErrorKind::DnsTooLong, 0))?;
+    let mut framed = Vec::with_capacity(message.len() + 2);
+    framed.extend_from_slice(&len.to_be_bytes());
+    framed.extend_from_slice(message);
+    stream.write_all(&framed).await.map_err(io_error)?;
+    stream.flush().await.map_err(io_error)
+}
+
+/// Exchange a query with an upstream through the SOCKS5 proxy (DNS over TCP)
+pub async fn exchange_via_proxy(
+    proxy: &Socks5Client,
+    upstream: SocketAddr,
+    query: &[u8],
+    timeout: Duration,
+) -> Result<Vec<u8>, VoyageError> {
+    tokio::time::timeout(timeout, async {
+        let mut stream = proxy.connect(TargetAddr::from_socket_addr(upstream)).await?;
+        write_tcp_message(&mut stream, query).await?;
+        read_tcp_message(&mut stream)
+            .await?
+            .ok_or_else(|| VoyageError::connection("DNS upstream closed the connection".into()))
+    })
+    .await
+    .map_err(|_| timed_out(upstream))?
+}
+
+/// Forward a query to each upstream in turn until one answers; returns
+/// the upstream that answered and its response
+pub async fn forward(
+    upstreams: &[SocketAddr],
+    proxy: Option<&Socks5Client>,
+    query: &[u8],
+    timeout: Duration,
+) -> Result<(SocketAddr, Vec<u8>), VoyageError> {
+    let mut last_error = VoyageError::config("No DNS upstream configured".into());
+    for upstream in upstreams {
+        let result = match proxy {
+            Some(proxy) => exchange_via_proxy(proxy, *upstream, query, timeout).await,
+            None => exchange_udp(*upstream, query, timeout).await,
+        };
+        match result {
+            Ok(response) => return Ok((*upstream, response)),
+            Err(e) => {
+                log::debug!("DNS upstream {} failed: {}", upstream, e);
+                last_error = e;
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// Serve DNS over a TCP stream (port 53 flows terminated by the stack),
+/// answering each framed query with `resolve`
+pub async fn serve_tcp<S, F, Fut>(mut stream: S, mut resolve: F) -> Result<(), VoyageError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: FnMut(Vec<u8>) -> Fut,
+    Fut: Future<Output = Vec<u8>>,
+{
+    while let Some(query) = read_tcp_message(&mut stream).await? {
+        let response = resolve(query).await;
+        write_tcp_message(&mut stream, &response).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(name: &str, qtype: u16) -> DnsMessage {
+        DnsMessage {
+            id: 0x1234,
+            flags: FLAG_RD,
+            questions: vec![DnsQuestion {
+                name: name.into(),
+                qtype,
+                qclass: CLASS_IN,
+            }],
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_encode_parse_round_trip() {
+        let msg = DnsMessage::reply(&query("example.com", TYPE_A), RCODE_NOERROR)
+            .with_answer(DnsRecord::a("example.com", Ipv4Addr::new(93, 184, 216, 34), 300));
+        let parsed = DnsMessage::parse(&msg.encode()).unwrap();
+        assert_eq!(parsed, msg);
+        assert!(parsed.is_response());
+        assert_eq!(parsed.rcode(), RCODE_NOERROR);
+        assert_eq!(parsed.flags & FLAG_RD, FLAG_RD);
+    }
+
+    #[test]
+    fn test_parse_compressed_response() {
+        // www.example.com CNAME example.com, example.com A 1.2.3.4
+        let mut data = vec![0x12, 0x34, 0x81, 0x80, 0, 1, 0, 2, 0, 0, 0, 0];
+        data.extend_from_slice(b"\x03www\x07example\x03com\x00\x00\x01\x00\x01");
+        data.extend_from_slice(&[0xC0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 2, 0xC0, 16]);
+        data.extend_from_slice(&[0xC0, 16, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 1, 2, 3, 4]);
+
+        let msg = DnsMessage::parse(&data).unwrap();
+        assert_eq!(msg.question().unwrap().name, "www.example.com");
+        assert_eq!(msg.answers[0].data, RecordData::Cname("example.com".into()));
+        assert_eq!(msg.answers[1].name, "example.com");
+        assert_eq!(msg.answers[1].data, RecordData::A(Ipv4Addr::new(1, 2, 3, 4)));
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_messages() {
+        assert!(DnsMessage::parse(&[0; 4]).is_err());
+
+        // Question name pointing at itself
+        let mut data = vec![0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+        data.extend_from_slice(&[0xC0, 12, 0, 1, 0, 1]);
+        assert!(DnsMessage::parse(&data).is_err());
+    }
+
+    #[test]
+    fn test_plan_by_action() {
+        let mut resolver = DnsResolver::default();
+        let mut pool = FakeIpPool::default();
+
+        let plan = resolver.plan(&query("ads.example.com", TYPE_A), &RouteAction::Reject, &mut pool);
+        match plan {
+            DnsPlan::Answer(reply) => assert_eq!(reply.rcode(), RCODE_NXDOMAIN),
+            other => panic!("unexpected plan {:?}", other),
+        }
+
+        let plan = resolver.plan(&query("google.com", TYPE_A), &RouteAction::Proxy, &mut pool);
+        match plan {
+            DnsPlan::Answer(reply) => {
+                let RecordData::A(addr) = reply.answers[0].data else {
+                    panic!("expected an A record");
+                };
+                assert!(pool.contains(addr));
+                assert_eq!(pool.lookup(addr), Some("google.com"));
+            }
+            other => panic!("unexpected plan {:?}", other),
+        }
+
+        let plan = resolver.plan(&query("baidu.com", TYPE_A), &RouteAction::Direct, &mut pool);
+        assert_eq!(
+            plan,
+            DnsPlan::Forward {
+                upstreams: resolver.config().upstreams.clone(),
+                via_proxy: false,
+                rewrite: None,
+            }
+        );
+
+        let stats = resolver.stats();
+        assert_eq!(stats.queries, 3);
+        assert_eq!(stats.local_answers, 2);
+        assert_eq!(stats.forwarded_direct, 1);
+    }
+
+    #[test]
+    fn test_plan_without_fake_ip_uses_proxy_resolver() {
+        let config = DnsConfig {
+            fake_ip: false,
+            ..Default::default()
+        };
+        let proxy_upstream = config.proxy_upstream;
+        let mut resolver = DnsResolver::new(config);
+        let plan = resolver.plan(
+            &query("google.com", TYPE_A),
+            &RouteAction::Proxy,
+            &mut FakeIpPool::default(),
+        );
+        assert_eq!(
+            plan,
+            DnsPlan::Forward {
+                upstreams: vec![proxy_upstream],
+                via_proxy: true,
+                rewrite: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_is_local_name() {
+        let search = vec!["corp.example".to_string()];
+        for name in [
+            "printer",
+            "nas.local",
+            "NAS.Local.",
+            "1.0.254.169.in-addr.arpa",
+            "wiki.corp.example",
+            "corp.example",
+        ] {
+            assert!(is_local_name(name, &search), "{}", name);
+        }
+        for name in ["example.com", "notcorp.example", "local.example.com", "."] {
+            assert!(!is_local_name(name, &search), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_plan_sends_local_names_to_local_upstream() {
+        let local: SocketAddr = "192.168.1.1:53".parse().unwrap();
+        let mut resolver = DnsResolver::new(DnsConfig {
+            local_upstream: Some(local),
+            ..Default::default()
+        });
+        let mut pool = FakeIpPool::default();
+
+        let plan = resolver.plan(&query("printer", TYPE_A), &RouteAction::Direct, &mut pool);
+        assert_eq!(
+            plan,
+            DnsPlan::Forward {
+                upstreams: vec![local],
+                via_proxy: false,
+                rewrite: None,
+            }
+        );
+        let plan = resolver.plan(&query("example.com", TYPE_A), &RouteAction::Direct, &mut pool);
+        assert!(matches!(plan, DnsPlan::Forward { upstreams, .. } if upstreams != vec![local]));
+    }
+
+    fn answer(name: &str, ttl: u32) -> DnsMessage {
+        DnsMessage::reply(&query(name, TYPE_A), RCODE_NOERROR)
+            .with_answer(DnsRecord::a(name, Ipv4Addr::new(1, 2, 3, 4), ttl))
+    }
+
+    #[test]
+    fn test_cache_hit_rewrites_id_and_ttl() {
+        let mut cache = DnsCache::new(8);
+        let now = Instant::now();
+        assert!(cache.insert(&answer("example.com", 300), now));
+
+        let mut q = query("EXAMPLE.com", TYPE_A);
+        q.id = 0x9999;
+        let hit = cache.get(&q, now + Duration::from_secs(100)).unwrap();
+        assert_eq!(hit.id, 0x9999);
+        assert_eq!(hit.answers[0].ttl, 200);
+
+        assert!(cache.get(&q, now + Duration::from_secs(301)).is_none());
+        assert!(cache.is_empty());
+        assert_eq!((cache.hits, cache.misses), (1, 1));
+    }
+
+    #[test]
+    fn test_cache_negative_answers() {
+        let mut cache = DnsCache::new(8);
+        let now = Instant::now();
+        let q = query("missing.example", TYPE_A);
+
+        // SOA with TTL 900 and MINIMUM 30: negative TTL is 30
+        let mut soa = vec![0u8; 20];
+        soa[16..].copy_from_slice(&30u32.to_be_bytes());
+        let mut nx = DnsMessage::reply(&q, RCODE_NXDOMAIN);
+        nx.authorities.push(DnsRecord {
+            name: "example".into(),
+            rtype: TYPE_SOA,
+            rclass: CLASS_IN,
+            ttl: 900,
+            data: RecordData::Other(soa),
+        });
+        assert!(cache.insert(&nx, now));
+        let hit = cache.get(&q, now + Duration::from_secs(10)).unwrap();
+        assert_eq!(hit.rcode(), RCODE_NXDOMAIN);
+        assert_eq!(cache.negative_hits, 1);
+        assert!(cache.get(&q, now + Duration::from_secs(31)).is_none());
+
+        // SERVFAIL and zero TTLs are never cached
+        assert!(!cache.insert(&DnsMessage::reply(&q, RCODE_SERVFAIL), now));
+        assert!(!cache.insert(&answer("zero.example", 0), now));
+    }
+
+    #[test]
+    fn test_cache_evicts_least_recently_used() {
+        let mut cache = DnsCache::new(2);
+        let now = Instant::now();
+        cache.insert(&answer("a.com", 300), now);
+        let entry_bytes = cache.estimated_bytes();
+        cache.insert(&answer("b.com", 300), now);
+        assert!(cache.get(&query("a.com", TYPE_A), now).is_some());
+
+        cache.insert(&answer("c.com", 300), now);
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.evictions, 1);
+        assert!(cache.get(&query("b.com", TYPE_A), now).is_none());
+        assert!(cache.get(&query("a.com", TYPE_A), now).is_some());
+
+        // Replacing and evicting keep the size in step with the entries
+        cache.insert(&answer("a.com", 300), now);
+        assert_eq!(cache.estimated_bytes(), 2 * entry_bytes);
+        cache.flush();
+        assert_eq!(cache.estimated_bytes(), 0);
+    }
+
+    #[test]
+    fn test_resolver_serves_from_cache() {
+        let mut resolver = DnsResolver::default();
+        let mut pool = FakeIpPool::default();
+        let q = query("baidu.com", TYPE_A);
+
+        assert!(matches!(
+            resolver.plan(&q, &RouteAction::Direct, &mut pool),
+            DnsPlan::Forward { .. }
+        ));
+        resolver.record_response(&answer("baidu.com", 60));
+        assert!(matches!(
+            resolver.plan(&q, &RouteAction::Direct, &mut pool),
+            DnsPlan::Answer(_)
+        ));
+        assert_eq!(resolver.stats().cache_hits, 1);
+        assert_eq!(resolver.stats().cache_entries, 1);
+
+        resolver.flush_cache();
+        assert_eq!(resolver.stats().cache_entries, 0);
+    }
+
+    #[test]
+    fn test_plan_answers_static_hosts() {
+        let mut resolver = DnsResolver::default();
+        let mut pool = FakeIpPool::default();
+        resolver.set_hosts(
+            HostTable::parse_config("HOST, router.local, 192.168.1.1\nHOST, *.corp.local, fd00::7")
+                .unwrap(),
+        );
+
+        // Hosts win even over REJECT and fake-IP
+        let plan = resolver.plan(&query("router.local", TYPE_A), &RouteAction::Reject, &mut pool);
+        match plan {
+            DnsPlan::Answer(reply) => {
+                assert_eq!(reply.rcode(), RCODE_NOERROR);
+                assert_eq!(reply.answers[0].data, RecordData::A(Ipv4Addr::new(192, 168, 1, 1)));
+                assert_eq!(reply.answers[0].ttl, HOST_TTL);
+            }
+            other => panic!("unexpected plan {:?}", other),
+        }
+
+        let plan = resolver.plan(&query("git.corp.local", TYPE_AAAA), &RouteAction::Proxy, &mut pool);
+        match plan {
+            DnsPlan::Answer(reply) => {
+                assert_eq!(reply.answers[0].data, RecordData::Aaaa("fd00::7".parse().unwrap()))
+            }
+            other => panic!("unexpected plan {:?}", other),
+        }
+
+        // An A query for an IPv6-only host gets an empty answer
+        let plan = resolver.plan(&query("git.corp.local", TYPE_A), &RouteAction::Direct, &mut pool);
+        match plan {
+            DnsPlan::Answer(reply) => assert!(reply.answers.is_empty()),
+            other => panic!("unexpected plan {:?}", other),
+        }
+        assert!(pool.is_empty());
+        assert_eq!(resolver.config().hosts.len(), 2);
+    }
+
+    #[test]
+    fn test_plan_applies_dns_rules() {
+        let mut resolver = DnsResolver::default();
+        let mut pool = FakeIpPool::default();
+        resolver.set_rules(
+            DnsRuleSet::from_config(
+                "DOMAIN-SUFFIX, ads.example, BLOCK\n\
+                 DOMAIN-SUFFIX, tracker.example, BLOCK, ZERO\n\
+                 DOMAIN, old.example, CNAME, new.example\n\
+                 DOMAIN-SUFFIX, corp.example, UPSTREAM, 10.0.0.53",
+            )
+            .unwrap(),
+        );
+
+        // Rules run before the routing action, even for fake-IP names
+        match resolver.plan(&query("x.ads.example", TYPE_A), &RouteAction::Proxy, &mut pool) {
+            DnsPlan::Answer(reply) => assert_eq!(reply.rcode(), RCODE_NXDOMAIN),
+            other => panic!("unexpected plan {:?}", other),
+        }
+        match resolver.plan(&query("tracker.example", TYPE_AAAA), &RouteAction::Direct, &mut pool) {
+            DnsPlan::Answer(reply) => {
+                assert_eq!(reply.answers[0].data, RecordData::Aaaa(Ipv6Addr::UNSPECIFIED))
+            }
+            other => panic!("unexpected plan {:?}", other),
+        }
+        assert_eq!(resolver.stats().blocked, 2);
+        assert!(pool.is_empty());
+
+        assert_eq!(
+            resolver.plan(&query("old.example", TYPE_A), &RouteAction::Proxy, &mut pool),
+            DnsPlan::Forward {
+                upstreams: vec![resolver.config().proxy_upstream],
+                via_proxy: true,
+                rewrite: Some("new.example".into()),
+            }
+        );
+        assert_eq!(
+            resolver.plan(&query("git.corp.example", TYPE_A), &RouteAction::Proxy, &mut pool),
+            DnsPlan::Forward {
+                upstreams: vec!["10.0.0.53:53".parse().unwrap()],
+                via_proxy: false,
+                rewrite: None,
+            }
+        );
+    }
+
+    #[test]
+    fn test_rewritten_reply() {
+        let original = query("old.example", TYPE_A);
+        let rewritten = original.renamed("new.example");
+        assert_eq!(rewritten.question().unwrap().name, "new.example");
+        assert_eq!(rewritten.id, original.id);
+
+        let upstream = DnsMessage::reply(&rewritten, RCODE_NOERROR)
+            .with_answer(DnsRecord::a("new.example", Ipv4Addr::new(5, 6, 7, 8), 120));
+        let reply = DnsMessage::rewritten_reply(&original, "new.example", &upstream);
+
+        assert_eq!(reply.question().unwrap().name, "old.example");
+        assert_eq!(
+            reply.answers[0],
+            DnsRecord::cname("old.example", "new.example", 120)
+        );
+        assert_eq!(reply.answers[1].data, RecordData::A(Ipv4Addr::new(5, 6, 7, 8)));
+        // Survives an encode round trip
+        assert_eq!(DnsMessage::parse(&reply.encode()).unwrap(), reply);
+    }
+
+    #[test]
+    fn test_domain_map_records_answers() {
+        let mut map = DomainMap::new(2);
+        let now = Instant::now();
+        let addr = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4));
+
+        // CNAME chains map back to the queried name
+        let mut response = answer("WWW.Example.com", 10);
+        response.answers.insert(
+            0,
+            DnsRecord {
+                name: "www.example.com".into(),
+                rtype: TYPE_CNAME,
+                rclass: CLASS_IN,
+                ttl: 10,
+                data: RecordData::Cname("cdn.example.net".into()),
+            },
+        );
+        assert_eq!(map.record(&response, now), 1);
+        assert_eq!(map.lookup(addr, now), Some("www.example.com"));
+
+        // Short TTLs are extended to the minimum
+        let later = now + Duration::from_secs(DOMAIN_MAP_MIN_TTL as u64 - 1);
+        assert!(map.lookup(addr, later).is_some());
+        assert!(map.lookup(addr, now + Duration::from_secs(DOMAIN_MAP_MIN_TTL as u64)).is_none());
+
+        // Failed answers are ignored
+        let nx = DnsMessage::reply(&query("gone.example", TYPE_A), RCODE_NXDOMAIN);
+        assert_eq!(map.record(&nx, now), 0);
+    }
+
+    #[test]
+    fn test_domain_map_evicts_oldest() {
+        let mut map = DomainMap::new(2);
+        let now = Instant::now();
+        let record = |name: &str, last: u8| {
+            DnsMessage::reply(&query(name, TYPE_A), RCODE_NOERROR).with_answer(DnsRecord::a(
+                name,
+                Ipv4Addr::new(10, 0, 0, last),
+                600,
+            ))
+        };
+        map.record(&record("a.com", 1), now);
+        map.record(&record("b.com", 2), now);
+        map.record(&record("c.com", 3), now);
+
+        assert_eq!(map.len(), 2);
+        assert!(map.lookup(Ipv4Addr::new(10, 0, 0, 1).into(), now).is_none());
+        assert_eq!(map.lookup(Ipv4Addr::new(10, 0, 0, 3).into(), now), Some("c.com"));
+    }
+
+    #[test]
+    fn test_exchange_udp() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let upstream = server.local_addr().unwrap();
+            tokio::spawn(async move {
+                let mut buf = [0u8; 512];
+                let (n, peer) = server.recv_from(&mut buf).await.unwrap();
+                let request = DnsMessage::parse(&buf[..n]).unwrap();
+                let reply = DnsMessage::reply(&request, RCODE_NOERROR)
+                    .with_answer(DnsRecord::a("example.com", Ipv4Addr::new(1, 2, 3, 4), 60));
+                server.send_to(&reply.encode(), peer).await.unwrap();
+            });
+
+            let request = query("example.com", TYPE_A).encode();
+            let (answered_by, response) =
+                forward(&[upstream], None, &request, Duration::from_secs(2))
+                    .await
+                    .unwrap();
+            assert_eq!(answered_by, upstream);
+            let response = DnsMessage::parse(&response).unwrap();
+            assert_eq!(response.id, 0x1234);
+            assert_eq!(response.answers.len(), 1);
+        });
+    }
+
+    #[test]
+    fn test_tcp_framing() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            let (mut client, server) = tokio::io::duplex(1024);
+            let server = tokio::spawn(serve_tcp(server, |q: Vec<u8>| async move {
+                let q = DnsMessage::parse(&q).unwrap();
+                DnsMessage::reply(&q, RCODE_NXDOMAIN).encode()
+            }));
+
+            write_tcp_message(&mut client, &query("nope.example", TYPE_A).encode())
+                .await
+                .unwrap();
+            let response = read_tcp_message(&mut client).await.unwrap().unwrap();
+            assert_eq!(DnsMessage::parse(&response).unwrap().rcode(), RCODE_NXDOMAIN);
+
+            drop(client);
+            server.await.unwrap().unwrap();
+        });
+    }
+}
@@ -0,0 +1,188 @@
+//! DNS Rules
+//!
+//! This module provides DNS-layer actions evaluated before a query is
+//! forwarded: blocking names, rewriting them to another target through a
+//! CNAME, or sending them to a specific upstream. Rules use the Surge-like
+//! routing syntax with a DNS action in place of the routing policy:
+//!
+//! ```text
+//! DOMAIN-SUFFIX, doubleclick.net, BLOCK
+//! DOMAIN-KEYWORD, tracker, BLOCK, ZERO
+//! DOMAIN, old.example.com, CNAME, new.example.net
+//! DOMAIN-SUFFIX, corp.example, UPSTREAM, 10.0.0.53, 10.0.1.53:5353
+//! ```
+
+use std::net::{IpAddr, SocketAddr};
+
+use crate::dns::DNS_PORT;
+use crate::rule::{MatchContext, RuleType};
+
+/// What to do with a query matched by a DNS rule
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnsAction {
+    /// Answer NXDOMAIN
+    Block,
+    /// Answer with the unspecified address (`0.0.0.0` / `::`)
+    BlockZero,
+    /// Resolve this name instead and answer with a CNAME to it
+    Cname(String),
+    /// Forward directly to these upstreams
+    Upstream(Vec<SocketAddr>),
+}
+
+/// A single DNS rule
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsRule {
+    /// Name matcher (domain rule types only)
+    pub rule_type: RuleType,
+    /// Action to take when matched
+    pub action: DnsAction,
+}
+
+impl DnsRule {
+    /// Parse a `TYPE, value, ACTION[, args...]` line
+    pub fn parse_line(line: &str) -> Result<Self, String> {
+        let parts: Vec<&str> = line.split(',').map(|s| s.trim()).collect();
+        if parts.len() < 3 {
+            return Err(format!("Invalid DNS rule format: {}", line));
+        }
+
+        let value = parts[1].to_string();
+        let rule_type = match parts[0].to_uppercase().as_str() {
+            "DOMAIN" => RuleType::Domain(value),
+            "DOMAIN-SUFFIX" => RuleType::DomainSuffix(value),
+            "DOMAIN-KEYWORD" => RuleType::DomainKeyword(value),
+            other => return Err(format!("Unsupported DNS rule type: {}", other)),
+        };
+
+        let args = &parts[3..];
+        let action = match parts[2].to_uppercase().as_str() {
+            "BLOCK" => match args {
+                [] => DnsAction::Block,
+                [mode] if mode.eq_ignore_ascii_case("NXDOMAIN") => DnsAction::Block,
+                [mode] if mode.eq_ignore_ascii_case("ZERO") => DnsAction::BlockZero,
+                _ => return Err(format!("Invalid BLOCK mode: {}", args.join(", "))),
+            },
+            "CNAME" => match args {
+                [target] if !target.is_empty() => {
+                    DnsAction::Cname(target.trim_end_matches('.').to_ascii_lowercase())
+                }
+                _ => return Err("CNAME rule requires one target name".into()),
+            },
+            "UPSTREAM" => {
+                if args.is_empty() {
+                    return Err("UPSTREAM rule requires an upstream".into());
+                }
+                DnsAction::Upstream(
+                    args.iter()
+                        .map(|a| parse_upstream(a))
+                        .collect::<Result<_, _>>()?,
+                )
+            }
+            other => return Err(format!("Unknown DNS action: {}", other)),
+        };
+
+        Ok(Self { rule_type, action })
+    }
+
+    /// Check if the rule matches a queried name
+    pub fn matches(&self, name: &str) -> bool {
+        self.rule_type
+            .matches(&MatchContext::new(Some(name.trim_end_matches('.')), None, DNS_PORT))
+    }
+}
+
+/// Parse `ip` or `ip:port` (port 53 by default)
+fn parse_upstream(s: &str) -> Result<SocketAddr, String> {
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    s.parse::<IpAddr>()
+        .map(|ip| SocketAddr::new(ip, DNS_PORT))
+        .map_err(|_| format!("Invalid upstream: {}", s))
+}
+
+/// Ordered DNS rules; the first match wins
+#[derive(Debug, Clone, Default)]
+pub struct DnsRuleSet {
+    rules: Vec<DnsRule>,
+}
+
+impl DnsRuleSet {
+    /// Create an empty rule set
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse rules from configuration, skipping blank lines and comments
+    pub fn from_config(config: &str) -> Result<Self, String> {
+        let rules = config
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#') && !line.starts_with("//"))
+            .map(DnsRule::parse_line)
+            .collect::<Result<_, _>>()?;
+        Ok(Self { rules })
+    }
+
+    /// Number of rules
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// Check if there are no rules
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Action of the first rule matching `name`
+    pub fn find(&self, name: &str) -> Option<&DnsAction> {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(name))
+            .map(|rule| &rule.action)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_actions() {
+        let rules = DnsRuleSet::from_config(
+            "# ads\n\
+             DOMAIN-SUFFIX, doubleclick.net, BLOCK\n\
+             DOMAIN-KEYWORD, tracker, block, zero\n\
+             DOMAIN, old.example.com, CNAME, New.Example.net.\n\
+             DOMAIN-SUFFIX, corp.example, UPSTREAM, 10.0.0.53, [fd00::53]:5353",
+        )
+        .unwrap();
+        assert_eq!(rules.len(), 4);
+
+        assert_eq!(rules.find("ad.doubleclick.net"), Some(&DnsAction::Block));
+        assert_eq!(rules.find("eu.tracker.io."), Some(&DnsAction::BlockZero));
+        assert_eq!(
+            rules.find("OLD.example.com"),
+            Some(&DnsAction::Cname("new.example.net".into()))
+        );
+        assert_eq!(
+            rules.find("git.corp.example"),
+            Some(&DnsAction::Upstream(vec![
+                "10.0.0.53:53".parse().unwrap(),
+                "[fd00::53]:5353".parse().unwrap(),
+            ]))
+        );
+        assert_eq!(rules.find("example.org"), None);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(DnsRule::parse_line("DOMAIN, a.com").is_err());
+        assert!(DnsRule::parse_line("IP-CIDR, 10.0.0.0/8, BLOCK").is_err());
+        assert!(DnsRule::parse_line("DOMAIN, a.com, BLOCK, SOMETIMES").is_err());
+        assert!(DnsRule::parse_line("DOMAIN, a.com, CNAME").is_err());
+        assert!(DnsRule::parse_line("DOMAIN, a.com, UPSTREAM, nowhere").is_err());
+        assert!(DnsRule::parse_line("DOMAIN, a.com, PROXY").is_err());
+    }
+}
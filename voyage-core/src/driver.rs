@@ -0,0 +1,233 @@
+//! Interface Driver
+//!
+//! This module drives an `InterfaceManager` from a tokio task instead of
+//! having the host poll it in a loop. The task polls the interface, then
+//! sleeps until smoltcp's next timer (`poll_delay`) or until it is woken
+//! because a packet was injected or a relay wrote to a socket.
+//!
+//! The engine's own interface is driven through the core, which closes the
+//! sockets of finished flows on every poll.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::Duration;
+
+use tokio::runtime::Handle;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+use crate::device::PacketInjector;
+use crate::iface::InterfaceManager;
+use crate::VoyageCore;
+
+/// Longest sleep when smoltcp has no pending timer
+pub const MAX_IDLE_DELAY: Duration = Duration::from_secs(1);
+
+/// Interface shared between the driver and the code feeding it
+pub type SharedInterface = Arc<Mutex<InterfaceManager>>;
+
+/// Runs after every poll with the interface locked, so the relay layer can
+/// move data between smoltcp sockets and their outbound streams
+pub type PollHook = Box<dyn FnMut(&mut InterfaceManager) + Send>;
+
+/// One poll of the driven interface, returning smoltcp's next timer, or
+/// `Err` to stop the driver
+type PollStep = Box<dyn FnMut() -> Result<Option<Duration>, ()> + Send>;
+
+/// Wakes the driver for an immediate poll; cheap to clone into relays
+#[derive(Debug, Clone, Default)]
+pub struct PollWaker(Arc<Notify>);
+
+impl PollWaker {
+    /// Request a poll. Wakes issued while the driver is polling are kept,
+    /// so the next sleep returns at once.
+    pub fn wake(&self) {
+        self.0.notify_one();
+    }
+
+    async fn wait(&self, timeout: Duration) {
+        let _ = tokio::time::timeout(timeout, self.0.notified()).await;
+    }
+}
+
+/// Handle to the task driving an interface; stopping (or dropping) it
+/// ends the task
+pub struct InterfaceDriver {
+    waker: PollWaker,
+    injector: PacketInjector,
+    polls: Arc<AtomicU64>,
+    task: JoinHandle<()>,
+}
+
+impl InterfaceDriver {
+    /// Start driving `iface` on `runtime`, calling `hook` after each poll
+    pub fn spawn(iface: SharedInterface, runtime: &Handle, mut hook: Option<PollHook>) -> Self {
+        let driven = Arc::clone(&iface);
+        let step = Box::new(move || {
+            let Ok(mut iface) = driven.lock() else {
+                log::error!("Interface lock poisoned, stopping driver");
+                return Err(());
+            };
+            iface.poll();
+            if let Some(hook) = hook.as_mut() {
+                hook(&mut iface);
+            }
+            Ok(iface.poll_delay())
+        });
+        Self::start(&iface, runtime, step)
+    }
+
+    /// Start driving the engine's interface `iface` on `runtime` through
+    /// `core`, which is read-locked before the interface on every poll, so
+    /// packets are processed meanwhile. The driver stops once the core is
+    /// dropped.
+    pub fn spawn_for_core(
+        core: Weak<RwLock<VoyageCore>>,
+        iface: SharedInterface,
+        runtime: &Handle,
+    ) -> Self {
+        let driven = Arc::clone(&iface);
+        let step = Box::new(move || {
+            let core = core.upgrade().ok_or(())?;
+            let (Ok(core), Ok(mut iface)) = (core.read(), driven.lock()) else {
+                log::error!("Core lock poisoned, stopping driver");
+                return Err(());
+            };
+            Ok(core.poll_interface(&mut iface))
+        });
+        Self::start(&iface, runtime, step)
+    }
+
+    fn start(iface: &SharedInterface, runtime: &Handle, mut step: PollStep) -> Self {
+        let polls = Arc::new(AtomicU64::new(0));
+        let (injector, rx_queue) = match iface.lock() {
+            Ok(iface) => (iface.packet_injector(), iface.packet_queues()[0].clone()),
+            Err(poisoned) => {
+                let iface = poisoned.into_inner();
+                (iface.packet_injector(), iface.packet_queues()[0].clone())
+            }
+        };
+        // Packets queued from any thread wake the driver without touching
+        // the interface lock
+        let waker = PollWaker(rx_queue.notifier());
+
+        let woken = waker.clone();
+        let counter = Arc::clone(&polls);
+        let task = runtime.spawn(async move {
+            while let Ok(delay) = step() {
+                counter.fetch_add(1, Ordering::Relaxed);
+
+                match delay.map(|delay| delay.min(MAX_IDLE_DELAY)) {
+                    Some(delay) if delay.is_zero() => tokio::task::yield_now().await,
+                    Some(delay) => woken.wait(delay).await,
+                    None => woken.wait(MAX_IDLE_DELAY).await,
+                }
+            }
+        });
+
+        log::debug!("Interface driver started");
+        Self {
+            waker,
+            injector,
+            polls,
+            task,
+        }
+    }
+
+    /// Handle for waking the driver, e.g. after a relay wrote to a socket
+    pub fn waker(&self) -> PollWaker {
+        self.waker.clone()
+    }
+
+    /// Queue a packet from the app and poll right away; returns false if
+    /// the queue was full and the packet dropped
+    pub fn inject_packet(&self, packet: Vec<u8>) -> bool {
+        self.injector.inject(packet)
+    }
+
+    /// Number of polls run so far
+    pub fn polls(&self) -> u64 {
+        self.polls.load(Ordering::Relaxed)
+    }
+
+    /// Stop driving the interface
+    pub fn stop(self) {}
+}
+
+impl Drop for InterfaceDriver {
+    fn drop(&mut self) {
+        self.task.abort();
+        log::debug!("Interface driver stopped");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .enable_time()
+            .build()
+            .unwrap()
+    }
+
+    fn wait_for(mut done: impl FnMut() -> bool) -> bool {
+        for _ in 0..200 {
+            if done() {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        false
+    }
+
+    #[test]
+    fn test_idle_driver_sleeps() {
+        let runtime = runtime();
+        let iface = Arc::new(Mutex::new(InterfaceManager::new()));
+        let driver = InterfaceDriver::spawn(iface, runtime.handle(), None);
+
+        assert!(wait_for(|| driver.polls() >= 1));
+        std::thread::sleep(Duration::from_millis(50));
+        // Nothing to do: one poll, then asleep until woken
+        assert_eq!(driver.polls(), 1);
+
+        driver.waker().wake();
+        assert!(wait_for(|| driver.polls() >= 2));
+    }
+
+    #[test]
+    fn test_injected_packet_is_polled() {
+        let runtime = runtime();
+        let iface = Arc::new(Mutex::new(InterfaceManager::new()));
+        let hook_runs = Arc::new(AtomicU64::new(0));
+        let runs = Arc::clone(&hook_runs);
+        let driver = InterfaceDriver::spawn(
+            Arc::clone(&iface),
+            runtime.handle(),
+            Some(Box::new(move |_: &mut InterfaceManager| {
+                runs.fetch_add(1, Ordering::Relaxed);
+            })),
+        );
+        assert!(wait_for(|| driver.polls() >= 1));
+
+        driver.inject_packet(crate::create_tcp_packet([10, 0, 0, 2], [10, 0, 0, 1], 40000, 80, true));
+        let queue = iface.lock().unwrap().packet_queues()[0].clone();
+        assert!(wait_for(|| queue.is_empty()));
+        assert!(wait_for(|| hook_runs.load(Ordering::Relaxed) >= 2));
+
+        // Packets queued behind the driver's back wake it as well
+        let polls = driver.polls();
+        queue.push_back(vec![0; 20]);
+        assert!(wait_for(|| driver.polls() > polls));
+
+        driver.stop();
+        std::thread::sleep(Duration::from_millis(20));
+        let polls = hook_runs.load(Ordering::Relaxed);
+        iface.lock().unwrap().inject_packet(vec![0; 20]);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(hook_runs.load(Ordering::Relaxed), polls);
+    }
+}
@@ -0,0 +1,151 @@
+//! Engine Lifecycle
+//!
+//! This module tracks whether the engine's background work (maintenance,
+//! relays, the async runtime) is running, and tells a listener about every
+//! transition so the app can reflect it in its UI.
+
+use std::sync::{Arc, Mutex};
+
+/// Rule name recorded on flows opened while the engine drains
+pub const DRAIN_RULE: &str = "drain";
+
+/// Lifecycle state of the engine
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EngineState {
+    /// Nothing running
+    Stopped,
+    /// Background work is being started
+    Starting,
+    /// Processing traffic
+    Running,
+    /// Letting running flows finish before stopping; new flows are turned
+    /// away or sent direct
+    Draining,
+    /// Background work is being torn down
+    Stopping,
+    /// Starting failed
+    Error { reason: String },
+}
+
+impl EngineState {
+    /// Check if the engine is up or on its way up
+    pub fn is_active(&self) -> bool {
+        matches!(self, EngineState::Starting | EngineState::Running)
+    }
+
+    /// Check if packets from the device are processed
+    pub fn accepts_packets(&self) -> bool {
+        matches!(self, EngineState::Running | EngineState::Draining)
+    }
+}
+
+/// Callback told about every state change
+pub type StateListener = Arc<dyn Fn(&EngineState) + Send + Sync>;
+
+/// Current engine state plus the listener notified on changes
+pub struct EngineStatus {
+    state: Mutex<EngineState>,
+    listener: Mutex<Option<StateListener>>,
+}
+
+impl EngineStatus {
+    /// Create a stopped engine status without a listener
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(EngineState::Stopped),
+            listener: Mutex::new(None),
+        }
+    }
+
+    /// Current state
+    pub fn state(&self) -> EngineState {
+        self.state
+            .lock()
+            .map(|state| state.clone())
+            .unwrap_or(EngineState::Stopped)
+    }
+
+    /// Move to `state` and notify the listener.
+    ///
+    /// The listener runs without any lock held, so it may query the state.
+    pub fn set(&self, state: EngineState) {
+        let _ = self.transition(|_| true, state);
+    }
+
+    /// Move to `state` only if the current state satisfies `from`, checked
+    /// and changed under one lock. Returns the current state otherwise.
+    pub fn transition(
+        &self,
+        from: impl FnOnce(&EngineState) -> bool,
+        state: EngineState,
+    ) -> Result<(), EngineState> {
+        {
+            let mut current = self.state.lock().map_err(|_| EngineState::Stopped)?;
+            if !from(&current) {
+                return Err(current.clone());
+            }
+            if *current == state {
+                return Ok(());
+            }
+            *current = state.clone();
+        }
+        log::info!("Engine state: {:?}", state);
+        let listener = self.listener.lock().ok().and_then(|slot| slot.clone());
+        if let Some(listener) = listener {
+            listener(&state);
+        }
+        Ok(())
+    }
+
+    /// Replace the listener (`None` removes it)
+    pub fn set_listener(&self, listener: Option<StateListener>) {
+        if let Ok(mut slot) = self.listener.lock() {
+            *slot = listener;
+        }
+    }
+}
+
+impl Default for EngineStatus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_listener_sees_transitions() {
+        let status = EngineStatus::new();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&seen);
+        status.set_listener(Some(Arc::new(move |state: &EngineState| {
+            sink.lock().unwrap().push(state.clone())
+        })));
+
+        status.set(EngineState::Starting);
+        status.set(EngineState::Running);
+        status.set(EngineState::Running);
+        assert!(status.state().is_active());
+
+        status.set_listener(None);
+        status.set(EngineState::Stopped);
+        assert_eq!(status.state(), EngineState::Stopped);
+        assert_eq!(*seen.lock().unwrap(), [EngineState::Starting, EngineState::Running]);
+    }
+
+    #[test]
+    fn test_transition_checks_current_state() {
+        let status = EngineStatus::new();
+        let stopped = |state: &EngineState| *state == EngineState::Stopped;
+
+        assert_eq!(status.transition(stopped, EngineState::Starting), Ok(()));
+        // A second starter loses the race
+        assert_eq!(
+            status.transition(stopped, EngineState::Starting),
+            Err(EngineState::Starting)
+        );
+        assert_eq!(status.state(), EngineState::Starting);
+    }
+}
@@ -1,45 +1,247 @@
 //! Error types for Voyage Core
 
+use std::fmt;
+
 use thiserror::Error;
 
+use crate::message::LocalizedMessage;
+use crate::packet::ParseErrorKind;
+use crate::socks5::{HandshakeStage, Socks5ErrorKind};
+
 #[derive(Error, Debug)]
 pub enum VoyageError {
-    #[error("Core not initialized")]
     NotInitialized,
 
-    #[error("Core already initialized")]
     AlreadyInitialized,
 
-    #[error("Lock error")]
     LockError,
 
-    #[error("Invalid packet: {0}")]
-    InvalidPacket(String),
+    /// A packet or message failed to parse at this byte offset
+    Packet {
+        kind: ParseErrorKind,
+        offset: u64,
+    },
 
-    #[error("Socket error: {0}")]
-    SocketError(String),
+    SocketError {
+        detail: String,
+    },
 
-    #[error("NAT table full")]
     NatTableFull,
 
-    #[error("Connection error: {0}")]
-    Connection(String),
+    Connection {
+        detail: String,
+    },
 
-    #[error("NAT error: {0}")]
-    Nat(String),
+    Nat {
+        detail: String,
+    },
 
-    #[error("Rule error: {0}")]
-    Rule(String),
+    Rule {
+        detail: String,
+    },
+    /// A rule failed to parse at this 1-based line
+    RuleSyntax {
+        line: u32,
+        detail: String,
+    },
 
-    #[error("SOCKS5 error: {0}")]
-    Socks5Error(String),
+    /// A SOCKS5 exchange failed at this stage
+    Socks5 {
+        stage: HandshakeStage,
+        kind: Socks5ErrorKind,
+    },
 
-    #[error("IO error: {0}")]
-    IoError(String),
+    IoError {
+        detail: String,
+    },
 
-    #[error("Configuration error: {0}")]
-    ConfigError(String),
+    ConfigError {
+        detail: String,
+    },
+    /// A configuration file failed to parse or validate at this 1-based
+    /// line
+    ConfigSyntax {
+        line: u32,
+        detail: String,
+    },
+}
+
+impl VoyageError {
+    /// Parse failure of `kind` at byte `offset`
+    pub fn packet(kind: ParseErrorKind, offset: usize) -> Self {
+        VoyageError::Packet {
+            kind,
+            offset: offset as u64,
+        }
+    }
+
+    /// SOCKS5 failure of `kind` during `stage`
+    pub fn socks5(stage: HandshakeStage, kind: Socks5ErrorKind) -> Self {
+        VoyageError::Socks5 { stage, kind }
+    }
+
+    /// Socket failure described by `detail`
+    pub fn socket(detail: String) -> Self {
+        VoyageError::SocketError { detail }
+    }
+
+    /// Connection failure described by `detail`
+    pub fn connection(detail: String) -> Self {
+        VoyageError::Connection { detail }
+    }
+
+    /// NAT failure described by `detail`
+    pub fn nat(detail: String) -> Self {
+        VoyageError::Nat { detail }
+    }
+
+    /// Rule failure described by `detail`
+    pub fn rule(detail: String) -> Self {
+        VoyageError::Rule { detail }
+    }
+
+    /// Rule parse failure on 1-based `line`
+    pub fn rule_syntax(line: u32, detail: String) -> Self {
+        VoyageError::RuleSyntax { line, detail }
+    }
+
+    /// IO failure described by `detail`
+    pub fn io(detail: String) -> Self {
+        VoyageError::IoError { detail }
+    }
+
+    /// Invalid configuration described by `detail`
+    pub fn config(detail: String) -> Self {
+        VoyageError::ConfigError { detail }
+    }
+
+    /// Configuration parse or validation failure on 1-based `line`
+    pub fn config_syntax(line: u32, detail: String) -> Self {
+        VoyageError::ConfigSyntax { line, detail }
+    }
+
+    /// Move the offset of a packet error by `by` bytes, for errors of an
+    /// inner header parsed on its own
+    pub(crate) fn shifted(self, by: usize) -> Self {
+        match self {
+            VoyageError::Packet { kind, offset } => VoyageError::Packet {
+                kind,
+                offset: offset + by as u64,
+            },
+            other => other,
+        }
+    }
+
+    /// Stable message key and parameters for localization
+    pub fn message(&self) -> LocalizedMessage {
+        match self {
+            VoyageError::NotInitialized => LocalizedMessage::plain("error.not_initialized"),
+            VoyageError::AlreadyInitialized => LocalizedMessage::plain("error.already_initialized"),
+            VoyageError::LockError => LocalizedMessage::plain("error.lock"),
+            VoyageError::Packet { kind, .. } => {
+                LocalizedMessage::new("error.invalid_packet", vec![kind.to_string()])
+            }
+            VoyageError::SocketError { detail } => {
+                LocalizedMessage::new("error.socket", vec![detail.clone()])
+            }
+            VoyageError::NatTableFull => LocalizedMessage::plain("error.nat_table_full"),
+            VoyageError::Connection { detail } => {
+                LocalizedMessage::new("error.connection", vec![detail.clone()])
+            }
+            VoyageError::Nat { detail } => {
+                LocalizedMessage::new("error.nat", vec![detail.clone()])
+            }
+            VoyageError::Rule { detail } => {
+                LocalizedMessage::new("error.rule", vec![detail.clone()])
+            }
+            VoyageError::RuleSyntax { line, detail } => {
+                LocalizedMessage::new("error.rule_syntax", vec![line.to_string(), detail.clone()])
+            }
+            VoyageError::Socks5 {
+                kind: Socks5ErrorKind::Reply { code },
+                ..
+            } => LocalizedMessage::plain(code.message_key()),
+            VoyageError::Socks5 { kind, .. } => {
+                LocalizedMessage::new("error.socks5", vec![kind.to_string()])
+            }
+            VoyageError::IoError { detail } => {
+                LocalizedMessage::new("error.io", vec![detail.clone()])
+            }
+            VoyageError::ConfigError { detail } => {
+                LocalizedMessage::new("error.config", vec![detail.clone()])
+            }
+            VoyageError::ConfigSyntax { line, detail } => {
+                LocalizedMessage::new("error.config_syntax", vec![line.to_string(), detail.clone()])
+            }
+        }
+    }
+}
+
+impl VoyageError {
+    /// Stable numeric code, grouped by area: 1xx lifecycle, 2xx packets and
+    /// sockets, 3xx NAT and connections, 4xx rules and configuration, 5xx
+    /// SOCKS5, 6xx IO
+    pub fn code(&self) -> u32 {
+        match self {
+            VoyageError::NotInitialized => 100,
+            VoyageError::AlreadyInitialized => 101,
+            VoyageError::LockError => 102,
+            VoyageError::Packet { .. } => 200,
+            VoyageError::SocketError { .. } => 201,
+            VoyageError::NatTableFull => 300,
+            VoyageError::Nat { .. } => 301,
+            VoyageError::Connection { .. } => 302,
+            VoyageError::Rule { .. } => 400,
+            VoyageError::RuleSyntax { .. } => 401,
+            VoyageError::ConfigError { .. } => 402,
+            VoyageError::ConfigSyntax { .. } => 403,
+            VoyageError::Socks5 {
+                kind: Socks5ErrorKind::Reply { .. },
+                ..
+            } => 501,
+            VoyageError::Socks5 { .. } => 500,
+            VoyageError::IoError { .. } => 600,
+        }
+    }
+}
+
+impl fmt::Display for VoyageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message().render())
+    }
 }
 
 pub type Result<T> = std::result::Result<T, VoyageError>;
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::socks5::ReplyCode;
+
+    #[test]
+    fn test_error_message_keys() {
+        let err = VoyageError::packet(ParseErrorKind::Empty, 0);
+        assert_eq!(err.message().key, "error.invalid_packet");
+        assert_eq!(err.message().args, vec!["Empty packet".to_string()]);
+        assert_eq!(err.to_string(), "Invalid packet: Empty packet");
+
+        assert_eq!(VoyageError::NatTableFull.to_string(), "NAT table full");
+
+        let err = VoyageError::rule_syntax(3, "Unknown rule type: FOO".into());
+        assert_eq!(err.code(), 401);
+        assert_eq!(err.to_string(), "Rule error on line 3: Unknown rule type: FOO");
+    }
+
+    #[test]
+    fn test_socks5_reply_message() {
+        let refused = Socks5ErrorKind::Reply {
+            code: ReplyCode::ConnectionRefused,
+        };
+        let err = VoyageError::socks5(HandshakeStage::Connect, refused);
+        assert_eq!(err.message().key, "socks5.reply.connection_refused");
+        assert_eq!(err.to_string(), "Connection refused");
+
+        let err = VoyageError::socks5(HandshakeStage::Authentication, Socks5ErrorKind::AuthFailed);
+        assert_eq!((err.code(), err.to_string().as_str()), (500, "SOCKS5 error: Authentication failed"));
+    }
+}
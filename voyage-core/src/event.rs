@@ -0,0 +1,229 @@
+//! Connection Events
+//!
+//! This module provides the event bus the connection manager publishes flow
+//! lifecycle events on, so the app can show a live activity log without
+//! polling. Rust code subscribes to the bus directly; the FFI layer bridges
+//! it to a host callback through an `EventForwarder` thread.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::history::CloseReason;
+use crate::nat::{NatEntry, NatKey};
+use crate::proxy::RoutingDecision;
+
+/// Events buffered per subscriber before the slowest one starts losing them
+pub const EVENT_BUFFER: usize = 256;
+
+/// How often a forwarder waiting for events checks whether it was stopped
+const FORWARDER_POLL: Duration = Duration::from_millis(100);
+
+/// What happened to a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionEventKind {
+    /// A new flow was classified
+    Opened,
+    /// The TCP handshake completed
+    Established,
+    /// The flow was removed from the NAT table
+    Closed,
+    /// A sniffed hostname replaced the flow's routing decision
+    Rerouted,
+}
+
+/// A connection lifecycle event
+#[derive(Debug, Clone)]
+pub struct ConnectionEvent {
+    /// What happened
+    pub kind: ConnectionEventKind,
+    /// NAT key of the flow
+    pub key: NatKey,
+    /// Sniffed or resolved hostname, if known
+    pub domain: Option<String>,
+    /// Routing decision, once the flow has been classified
+    pub decision: Option<RoutingDecision>,
+    /// Bytes sent so far
+    pub bytes_sent: u64,
+    /// Bytes received so far
+    pub bytes_received: u64,
+    /// Why the flow ended, for `Closed` events
+    pub reason: Option<CloseReason>,
+    /// Key/value metadata attached by the host app
+    pub annotations: BTreeMap<String, String>,
+}
+
+impl ConnectionEvent {
+    /// Build an event from the flow's NAT entry
+    pub fn new(
+        kind: ConnectionEventKind,
+        key: NatKey,
+        entry: &NatEntry,
+        domain: Option<String>,
+    ) -> Self {
+        let decision = entry.route.clone();
+        Self {
+            kind,
+            key,
+            domain: domain.or_else(|| decision.as_ref().and_then(|d| d.domain.clone())),
+            decision,
+            bytes_sent: entry.bytes_sent,
+            bytes_received: entry.bytes_received,
+            reason: entry.close_reason.filter(|_| kind == ConnectionEventKind::Closed),
+            annotations: entry.annotations.clone(),
+        }
+    }
+}
+
+/// Broadcasts connection events to any number of subscribers
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<ConnectionEvent>,
+}
+
+impl EventBus {
+    /// Create a bus buffering `capacity` events per subscriber
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self { sender }
+    }
+
+    /// Subscribe to events published from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Check if anyone is listening, so events can be skipped otherwise
+    pub fn has_subscribers(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+
+    /// Publish an event; it is dropped if nobody is subscribed
+    pub fn emit(&self, event: ConnectionEvent) {
+        let _ = self.sender.send(event);
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new(EVENT_BUFFER)
+    }
+}
+
+/// Handle to a thread delivering a subscription to a callback; stopping (or
+/// dropping) it ends the thread
+pub struct EventForwarder {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl EventForwarder {
+    /// Start calling `deliver` for every event received on `events`.
+    ///
+    /// Delivery happens off the core lock, so the callback may call back
+    /// into the core.
+    pub fn start<F>(
+        mut events: broadcast::Receiver<ConnectionEvent>,
+        mut deliver: F,
+    ) -> std::io::Result<Self>
+    where
+        F: FnMut(ConnectionEvent) + Send + 'static,
+    {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = Arc::clone(&stop);
+
+        let thread = std::thread::Builder::new()
+            .name("voyage-events".into())
+            .spawn(move || {
+                runtime.block_on(async move {
+                    while !stopped.load(Ordering::Relaxed) {
+                        match tokio::time::timeout(FORWARDER_POLL, events.recv()).await {
+                            Ok(Ok(event)) => deliver(event),
+                            Ok(Err(RecvError::Lagged(missed))) => {
+                                log::warn!("Event listener fell behind, {} events dropped", missed)
+                            }
+                            Ok(Err(RecvError::Closed)) => break,
+                            Err(_) => {}
+                        }
+                    }
+                });
+            })?;
+
+        Ok(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Stop delivering events and wait for the thread to exit
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.stop.store(true, Ordering::Relaxed);
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for EventForwarder {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    fn event(kind: ConnectionEventKind) -> ConnectionEvent {
+        let key = NatKey::tcp("10.0.0.1:1000".parse().unwrap(), "1.1.1.1:443".parse().unwrap());
+        let entry = NatEntry::new(key.src_addr(), key.dst_addr(), 10000);
+        ConnectionEvent::new(kind, key, &entry, Some("one.one".into()))
+    }
+
+    #[test]
+    fn test_bus_fans_out() {
+        let bus = EventBus::default();
+        assert!(!bus.has_subscribers());
+        bus.emit(event(ConnectionEventKind::Opened));
+
+        let mut a = bus.subscribe();
+        let mut b = bus.subscribe();
+        assert!(bus.has_subscribers());
+        bus.emit(event(ConnectionEventKind::Closed));
+
+        assert_eq!(a.try_recv().unwrap().kind, ConnectionEventKind::Closed);
+        assert_eq!(b.try_recv().unwrap().domain.as_deref(), Some("one.one"));
+        assert!(a.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_forwarder_delivers_until_stopped() {
+        let bus = EventBus::default();
+        let (tx, rx) = mpsc::channel();
+        let forwarder = EventForwarder::start(bus.subscribe(), move |event| {
+            let _ = tx.send(event.kind);
+        })
+        .unwrap();
+
+        bus.emit(event(ConnectionEventKind::Established));
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+            ConnectionEventKind::Established
+        );
+
+        forwarder.stop();
+        assert!(!bus.has_subscribers());
+    }
+}
@@ -0,0 +1,299 @@
+//! Fake-IP Pool
+//!
+//! This module hands out addresses from a reserved IPv4 range in place of
+//! real DNS answers, so flows to those addresses can be mapped back to the
+//! hostname. The range must not overlap any network the device is actually
+//! attached to, otherwise local traffic would be captured.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+use crate::message::LocalizedMessage;
+
+/// Default fake-IP range (RFC 2544 benchmarking block)
+pub const DEFAULT_FAKE_IP_RANGE: &str = "198.18.0.0/15";
+
+/// Alternate ranges tried in order when the configured one collides
+pub const FALLBACK_FAKE_IP_RANGES: &[&str] = &["100.64.0.0/10", "172.29.0.0/16"];
+
+/// An IPv4 network in CIDR notation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Ipv4Range {
+    pub network: Ipv4Addr,
+    pub prefix_len: u8,
+}
+
+impl Ipv4Range {
+    /// Create a range; host bits of `addr` are cleared
+    pub fn new(addr: Ipv4Addr, prefix_len: u8) -> Self {
+        let prefix_len = prefix_len.min(32);
+        Self {
+            network: Ipv4Addr::from(u32::from(addr) & Self::mask_for(prefix_len)),
+            prefix_len,
+        }
+    }
+
+    fn mask_for(prefix_len: u8) -> u32 {
+        if prefix_len == 0 {
+            0
+        } else {
+            u32::MAX << (32 - prefix_len)
+        }
+    }
+
+    /// Network mask as an integer
+    pub fn mask(&self) -> u32 {
+        Self::mask_for(self.prefix_len)
+    }
+
+    /// Number of addresses in the range
+    pub fn size(&self) -> u64 {
+        1u64 << (32 - self.prefix_len)
+    }
+
+    /// Check if an address is inside the range
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        u32::from(addr) & self.mask() == u32::from(self.network)
+    }
+
+    /// Check if two ranges share any address
+    pub fn overlaps(&self, other: &Ipv4Range) -> bool {
+        let mask = self.mask() & other.mask();
+        u32::from(self.network) & mask == u32::from(other.network) & mask
+    }
+
+    /// Address at `offset` from the network address
+    fn nth(&self, offset: u32) -> Ipv4Addr {
+        Ipv4Addr::from(u32::from(self.network).wrapping_add(offset))
+    }
+}
+
+impl FromStr for Ipv4Range {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ip, prefix) = s
+            .trim()
+            .split_once('/')
+            .ok_or_else(|| format!("Invalid CIDR format: {}", s))?;
+        let ip = Ipv4Addr::from_str(ip.trim()).map_err(|_| format!("Invalid IP: {}", ip))?;
+        let prefix: u8 = prefix
+            .trim()
+            .parse()
+            .map_err(|_| format!("Invalid prefix: {}", prefix))?;
+        if prefix > 32 {
+            return Err(format!("Invalid prefix: {}", prefix));
+        }
+        Ok(Self::new(ip, prefix))
+    }
+}
+
+impl fmt::Display for Ipv4Range {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+/// Pick a fake-IP range that does not overlap any local network.
+///
+/// Returns the chosen range and a warning when the preferred range had to
+/// be abandoned (or no alternate was free).
+pub fn select_range(
+    preferred: Ipv4Range,
+    fallbacks: &[Ipv4Range],
+    local_networks: &[Ipv4Range],
+) -> (Ipv4Range, Option<LocalizedMessage>) {
+    let conflict = |range: &Ipv4Range| local_networks.iter().find(|net| range.overlaps(net));
+
+    let Some(blocker) = conflict(&preferred) else {
+        return (preferred, None);
+    };
+
+    match fallbacks.iter().find(|range| conflict(range).is_none()) {
+        Some(alternate) => {
+            log::warn!(
+                "Fake-IP range {} overlaps local network {}, using {}",
+                preferred,
+                blocker,
+                alternate
+            );
+            let warning = LocalizedMessage::new(
+                "warning.fake_ip_conflict",
+                vec![preferred.to_string(), blocker.to_string(), alternate.to_string()],
+            );
+            (*alternate, Some(warning))
+        }
+        None => {
+            log::warn!(
+                "Fake-IP range {} overlaps local network {} and no alternate is free",
+                preferred,
+                blocker
+            );
+            let warning = LocalizedMessage::new(
+                "warning.fake_ip_no_free_range",
+                vec![preferred.to_string(), blocker.to_string()],
+            );
+            (preferred, Some(warning))
+        }
+    }
+}
+
+/// Domain <-> fake address mapping backed by an IPv4 range
+#[derive(Debug)]
+pub struct FakeIpPool {
+    range: Ipv4Range,
+    /// Next offset to hand out, relative to the first usable address
+    cursor: u32,
+    by_domain: HashMap<String, Ipv4Addr>,
+    by_ip: HashMap<Ipv4Addr, String>,
+}
+
+impl FakeIpPool {
+    pub fn new(range: Ipv4Range) -> Self {
+        Self {
+            range,
+            cursor: 0,
+            by_domain: HashMap::new(),
+            by_ip: HashMap::new(),
+        }
+    }
+
+    /// The range addresses are allocated from
+    pub fn range(&self) -> Ipv4Range {
+        self.range
+    }
+
+    /// Check if an address belongs to the pool
+    pub fn contains(&self, addr: Ipv4Addr) -> bool {
+        self.range.contains(addr)
+    }
+
+    /// Number of live mappings
+    pub fn len(&self) -> usize {
+        self.by_domain.len()
+    }
+
+    /// Check if no addresses are allocated
+    pub fn is_empty(&self) -> bool {
+        self.by_domain.is_empty()
+    }
+
+    /// Get (or allocate) the fake address for a domain.
+    ///
+    /// When the range is exhausted the oldest address is recycled.
+    pub fn allocate(&mut self, domain: &str) -> Ipv4Addr {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        if let Some(ip) = self.by_domain.get(&domain) {
+            return *ip;
+        }
+
+        // Skip the network and broadcast addresses unless the range is tiny
+        let size = self.range.size();
+        let (first, usable) = if size > 2 { (1, size - 2) } else { (0, size) };
+        let ip = self.range.nth(first + self.cursor);
+        self.cursor = ((self.cursor as u64 + 1) % usable) as u32;
+
+        if let Some(old) = self.by_ip.remove(&ip) {
+            self.by_domain.remove(&old);
+        }
+        self.by_domain.insert(domain.clone(), ip);
+        self.by_ip.insert(ip, domain);
+        ip
+    }
+
+    /// Look up the domain a fake address was handed out for
+    pub fn lookup(&self, addr: Ipv4Addr) -> Option<&str> {
+        self.by_ip.get(&addr).map(|d| d.as_str())
+    }
+
+    /// Switch to a new range, dropping every existing mapping
+    pub fn reset(&mut self, range: Ipv4Range) {
+        *self = Self::new(range);
+    }
+}
+
+impl Default for FakeIpPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_FAKE_IP_RANGE.parse().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(s: &str) -> Ipv4Range {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_range() {
+        let r = range("198.18.3.4/15");
+        assert_eq!(r.network, Ipv4Addr::new(198, 18, 0, 0));
+        assert_eq!(r.to_string(), "198.18.0.0/15");
+        assert!(r.contains(Ipv4Addr::new(198, 19, 255, 1)));
+        assert!(!r.contains(Ipv4Addr::new(198, 20, 0, 1)));
+        assert!("198.18.0.0".parse::<Ipv4Range>().is_err());
+        assert!("198.18.0.0/33".parse::<Ipv4Range>().is_err());
+    }
+
+    #[test]
+    fn test_overlaps() {
+        assert!(range("10.0.0.0/8").overlaps(&range("10.1.2.0/24")));
+        assert!(range("10.1.2.0/24").overlaps(&range("10.0.0.0/8")));
+        assert!(!range("198.18.0.0/15").overlaps(&range("192.168.1.0/24")));
+    }
+
+    #[test]
+    fn test_select_range_no_conflict() {
+        let (chosen, warning) =
+            select_range(range("198.18.0.0/15"), &[], &[range("192.168.1.0/24")]);
+        assert_eq!(chosen, range("198.18.0.0/15"));
+        assert!(warning.is_none());
+    }
+
+    #[test]
+    fn test_select_range_falls_back() {
+        let fallbacks = [range("100.64.0.0/10"), range("172.29.0.0/16")];
+        let local = [range("198.18.5.0/24"), range("100.100.0.0/16")];
+        let (chosen, warning) = select_range(range("198.18.0.0/15"), &fallbacks, &local);
+        assert_eq!(chosen, range("172.29.0.0/16"));
+        let warning = warning.unwrap();
+        assert_eq!(warning.key, "warning.fake_ip_conflict");
+        assert_eq!(warning.args[1], "198.18.5.0/24");
+
+        let (chosen, warning) = select_range(range("198.18.0.0/15"), &[], &local);
+        assert_eq!(chosen, range("198.18.0.0/15"));
+        assert_eq!(warning.unwrap().key, "warning.fake_ip_no_free_range");
+    }
+
+    #[test]
+    fn test_pool_allocate_and_lookup() {
+        let mut pool = FakeIpPool::new(range("198.18.0.0/15"));
+        let a = pool.allocate("Example.com.");
+        assert_eq!(a, Ipv4Addr::new(198, 18, 0, 1));
+        assert_eq!(pool.allocate("example.com"), a);
+        assert_eq!(pool.lookup(a), Some("example.com"));
+
+        let b = pool.allocate("other.com");
+        assert_ne!(a, b);
+        assert!(pool.contains(b));
+        assert_eq!(pool.len(), 2);
+    }
+
+    #[test]
+    fn test_pool_recycles_when_exhausted() {
+        // /30 has two usable addresses
+        let mut pool = FakeIpPool::new(range("10.0.0.0/30"));
+        let a = pool.allocate("a.com");
+        let b = pool.allocate("b.com");
+        let c = pool.allocate("c.com");
+        assert_eq!(a, Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(b, Ipv4Addr::new(10, 0, 0, 2));
+        assert_eq!(c, a);
+        assert_eq!(pool.lookup(a), Some("c.com"));
+        assert_eq!(pool.len(), 2);
+    }
+}
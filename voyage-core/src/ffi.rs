@@ -3,243 +3,2417 @@
 //! This module provides the FFI functions that are exposed to Swift
 //! through UniFFI bindings.
 
-use std::net::IpAddr;
-use std::sync::{Arc, Mutex, OnceLock};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
-use crate::config::ProxyConfig;
+use tokio::runtime::Handle;
+
+use crate::api::ApiServer;
+use crate::config::{
+    BufferTier, ConcurrencyLimits, ConnectionRateLimits, DnsConfig, DrainPolicy, ExcessPolicy,
+    InterfaceConfig, ProxyConfig, ProxyProtocol, ResourceLimits,
+};
+use crate::device::DeviceStats;
+use crate::dns::{self, DnsMessage, DnsPlan, DnsStats, DNS_PORT, RCODE_SERVFAIL};
+use crate::dnsrule::DnsRuleSet;
 use crate::error::VoyageError;
-use crate::packet::ParsedPacket;
-use crate::rule::FfiRouteAction;
+use crate::engine::{EngineState, EngineStatus};
+use crate::event::{ConnectionEvent, ConnectionEventKind, EventForwarder};
+use crate::fakeip::Ipv4Range;
+use crate::flowlog::FlowLogger;
+use crate::geoip::GeoIpDb;
+use crate::geosite::GeoSiteDb;
+use crate::history::CloseReason;
+use crate::hosts::HostTable;
+use crate::import::{self, ImportDiagnostic, ImportFormat};
+use crate::inbound::InboundServer;
+use crate::lint::{self, ConfigDiagnostic};
+use crate::logging::{self, LogLevel, LogRecord};
+use crate::maintenance::MaintenanceTask;
+use crate::memory::MemoryStats;
+use crate::metrics::{self, MetricsServer};
+use crate::message::{self, LocalizedMessage, MessageTemplate};
+use crate::nat::{NatMode, NatState, NatTimeouts, PortStrategy};
+use crate::network::{self, NetworkPath};
+use crate::packet::{build_udp_packet, ParseErrorKind, ParsedPacket};
+use crate::profile::{substitute_variables, ConfigDiff, VoyageConfig};
+use crate::profile_store::ProfileInfo;
+use crate::quarantine::MalformedPacket;
+use crate::querylog::DnsQueryRecord;
+use crate::proxy::{FlowFacts, RouteComparison, RouteDivergence, RoutingDecision};
+use crate::rewrite::RewriteEngine;
+use crate::rule::{FfiRouteAction, MatchContext, RouteAction, RuleEngine};
+use crate::secret::SecretString;
+use crate::selftest::{self, SelfTestResult};
+use crate::shaping::ShapingStats;
+use crate::socks5::TargetAddr;
+use crate::traffic::{TrafficHistory, TrafficResolution};
+use crate::stats::SharedStats;
+use crate::tap::{TapChunk, TrafficTap};
+use crate::usage::Usage;
 use crate::VoyageCore;
 
-/// Global core instance
-static CORE_INSTANCE: OnceLock<Arc<Mutex<VoyageCore>>> = OnceLock::new();
+/// Global core instance, present between `init_core` and `shutdown_core`
+static CORE_INSTANCE: RwLock<Option<Arc<RwLock<VoyageCore>>>> = RwLock::new(None);
+
+/// Statistics of the core instance, polled without its lock
+static CORE_STATS: RwLock<Option<Arc<SharedStats>>> = RwLock::new(None);
+
+/// How often a drain checks whether the running flows have finished
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Engine lifecycle state and its listener
+static ENGINE: EngineStatus = EngineStatus::new();
+
+/// Background cleanup task, running while the engine is up
+static MAINTENANCE: Mutex<Option<MaintenanceTask>> = Mutex::new(None);
+
+/// Localhost listener serving OpenMetrics text, if started
+static METRICS_SERVER: Mutex<Option<MetricsServer>> = Mutex::new(None);
+
+/// Localhost remote control API, if started
+static API_SERVER: Mutex<Option<ApiServer>> = Mutex::new(None);
+
+/// Local SOCKS5/HTTP proxy listener, if started
+static INBOUND_SERVER: Mutex<Option<InboundServer>> = Mutex::new(None);
+
+/// Thread delivering connection events to the host's listener
+static EVENT_FORWARDER: Mutex<Option<EventForwarder>> = Mutex::new(None);
+
+thread_local! {
+    /// Why this thread's last `voyage_inject_inbound_packet` call was
+    /// rejected; UniFFI calls return their errors instead
+    static INJECT_ERROR: RefCell<Option<LocalizedMessage>> = const { RefCell::new(None) };
+}
+
+/// The running core instance
+fn current_core() -> Result<Arc<RwLock<VoyageCore>>, VoyageError> {
+    CORE_INSTANCE
+        .read()
+        .map_err(|_| VoyageError::LockError)?
+        .clone()
+        .ok_or(VoyageError::NotInitialized)
+}
+
+/// Core statistics for FFI
+#[derive(Debug, Clone, Default)]
+pub struct CoreStats {
+    /// Bytes sent through the proxy
+    pub bytes_sent: u64,
+    /// Bytes received through the proxy
+    pub bytes_received: u64,
+    /// Number of active connections
+    pub active_connections: u64,
+    /// Total connections since start
+    pub total_connections: u64,
+    /// Flows reaped because their relay task died
+    pub reaped_flows: u64,
+    /// Flows evicted because the NAT table was full
+    pub nat_evictions: u64,
+    /// Flows refused because their source hit the per-source cap
+    pub nat_source_limit_hits: u64,
+    /// Local ports found taken while allocating NAT mappings
+    pub nat_port_collisions: u64,
+    /// TCP segments whose flags did not fit their flow's state
+    pub nat_invalid_transitions: u64,
+    /// Flows removed by background cleanup
+    pub expired_flows: u64,
+    /// Background cleanup passes run
+    pub maintenance_runs: u64,
+    /// Current upload speed in bytes per second
+    pub upload_rate: u64,
+    /// Current download speed in bytes per second
+    pub download_rate: u64,
+    /// Estimated memory used by the tracked flows in bytes
+    pub memory_usage: u64,
+    /// Memory budget in bytes (0 when unlimited)
+    pub memory_budget: u64,
+    /// Buffer bytes of the TCP sockets of interfaces created by the core
+    pub socket_buffer_bytes: u64,
+    /// New flows refused by the connection or memory cap
+    pub connection_limit_hits: u64,
+    /// Flows waiting for a concurrency slot
+    pub waiting_connections: u64,
+    /// Flows rejected by a concurrency cap
+    pub limit_rejected_connections: u64,
+    /// Routes answered from the rule decision cache
+    pub route_cache_hits: u64,
+    /// Routes that had to run through the rules
+    pub route_cache_misses: u64,
+    /// Proxied QUIC flows rejected to force a TCP fallback
+    pub quic_blocked: u64,
+    /// New connections dropped by the connection rate limits
+    pub rate_limited_connections: u64,
+    /// Times a source was blocked for going over its connection rate
+    pub rate_limit_blocks: u64,
+    /// Inbound packets the parser rejected
+    pub malformed_packets: u64,
+}
+
+/// Concurrency caps for FFI; 0 means unlimited
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FfiConcurrencyLimits {
+    /// Maximum simultaneous proxied connections
+    pub max_proxied: u32,
+    /// Maximum simultaneous connections per destination host
+    pub max_per_destination: u32,
+    /// What happens to connections over a cap
+    pub excess: ExcessPolicy,
+    /// Longest a queued connection waits before it is rejected
+    pub queue_timeout_ms: u64,
+}
+
+impl From<FfiConcurrencyLimits> for ConcurrencyLimits {
+    fn from(limits: FfiConcurrencyLimits) -> Self {
+        Self {
+            max_proxied: limits.max_proxied as usize,
+            max_per_destination: limits.max_per_destination as usize,
+            excess: limits.excess,
+            queue_timeout_ms: limits.queue_timeout_ms,
+        }
+    }
+}
+
+/// Filter for `get_connections`; unset fields match every flow
+#[derive(Debug, Clone, Default)]
+pub struct FfiConnectionFilter {
+    /// IP protocol number (6 = TCP, 17 = UDP)
+    pub protocol: Option<u8>,
+    /// NAT state
+    pub state: Option<NatState>,
+    /// Only flows with activity in the last N milliseconds
+    pub active_within_ms: Option<u64>,
+}
+
+/// A live flow for the app's connection viewer
+#[derive(Debug, Clone)]
+pub struct FfiConnection {
+    /// Connection identifier, for `close_connection`
+    pub id: u64,
+    /// IP protocol number
+    pub protocol: u8,
+    /// Original source address
+    pub src: String,
+    /// Original destination address
+    pub dst: String,
+    /// Sniffed or resolved hostname, if known
+    pub domain: Option<String>,
+    /// NAT state
+    pub state: NatState,
+    /// Bytes sent
+    pub bytes_sent: u64,
+    /// Bytes received
+    pub bytes_received: u64,
+    /// Milliseconds since the flow was created
+    pub age_ms: u64,
+    /// Milliseconds since the last activity
+    pub idle_ms: u64,
+    /// Current upload speed in bytes per second
+    pub upload_rate: u64,
+    /// Current download speed in bytes per second
+    pub download_rate: u64,
+    /// Policy chosen for the flow
+    pub policy: FfiRouteAction,
+    /// Rule that picked the policy, if the flow matched one
+    pub matched_rule: Option<String>,
+    /// Key/value metadata attached with `set_connection_annotation`
+    pub annotations: HashMap<String, String>,
+}
+
+/// Traffic totals of one source IP or app, for FFI
+#[derive(Debug, Clone)]
+pub struct FfiUsageStats {
+    /// Source IP or app identifier
+    pub group: String,
+    /// Bytes sent
+    pub bytes_sent: u64,
+    /// Bytes received
+    pub bytes_received: u64,
+    /// Connections opened
+    pub connections: u64,
+    /// Current upload speed in bytes per second
+    pub upload_rate: u64,
+    /// Current download speed in bytes per second
+    pub download_rate: u64,
+}
+
+impl FfiUsageStats {
+    fn new(group: String, usage: &Usage, now: Instant) -> Self {
+        Self {
+            group,
+            bytes_sent: usage.bytes_sent,
+            bytes_received: usage.bytes_received,
+            connections: usage.connections,
+            upload_rate: usage.upload.rate(now),
+            download_rate: usage.download.rate(now),
+        }
+    }
+}
+
+/// A recently closed flow, for FFI
+#[derive(Debug, Clone)]
+pub struct FfiClosedConnection {
+    /// IP protocol number
+    pub protocol: u8,
+    /// Original source address
+    pub src: String,
+    /// Original destination address
+    pub dst: String,
+    /// Sniffed or resolved hostname, if known
+    pub domain: Option<String>,
+    /// Policy chosen for the flow, if it was classified
+    pub policy: Option<FfiRouteAction>,
+    /// Final bytes sent
+    pub bytes_sent: u64,
+    /// Final bytes received
+    pub bytes_received: u64,
+    /// How long the flow lived in milliseconds
+    pub duration_ms: u64,
+    /// Milliseconds since the flow ended
+    pub closed_ms_ago: u64,
+    /// Why the flow ended
+    pub reason: CloseReason,
+    /// Key/value metadata attached with `set_connection_annotation`
+    pub annotations: HashMap<String, String>,
+}
+
+/// A connection lifecycle event, for FFI
+#[derive(Debug, Clone)]
+pub struct FfiConnectionEvent {
+    /// What happened
+    pub kind: ConnectionEventKind,
+    /// IP protocol number
+    pub protocol: u8,
+    /// Original source address
+    pub src: String,
+    /// Original destination address
+    pub dst: String,
+    /// Sniffed or resolved hostname, if known
+    pub domain: Option<String>,
+    /// Policy chosen for the flow, once classified
+    pub policy: Option<FfiRouteAction>,
+    /// Rule that picked the policy, if any
+    pub matched_rule: Option<String>,
+    /// Bytes sent so far
+    pub bytes_sent: u64,
+    /// Bytes received so far
+    pub bytes_received: u64,
+    /// Why the flow ended, for `Closed` events
+    pub reason: Option<CloseReason>,
+    /// Key/value metadata attached with `set_connection_annotation`
+    pub annotations: HashMap<String, String>,
+}
+
+impl From<ConnectionEvent> for FfiConnectionEvent {
+    fn from(event: ConnectionEvent) -> Self {
+        let (policy, matched_rule) = match event.decision {
+            Some(decision) => (Some(decision.action.into()), decision.matched_rule),
+            None => (None, None),
+        };
+        Self {
+            kind: event.kind,
+            protocol: event.key.protocol,
+            src: event.key.src_addr().to_string(),
+            dst: event.key.dst_addr().to_string(),
+            domain: event.domain,
+            policy,
+            matched_rule,
+            bytes_sent: event.bytes_sent,
+            bytes_received: event.bytes_received,
+            reason: event.reason,
+            annotations: event.annotations.into_iter().collect(),
+        }
+    }
+}
+
+/// Host callback receiving connection events.
+///
+/// Called on a background thread without the core lock held.
+pub trait ConnectionEventListener: Send + Sync {
+    fn on_connection_event(&self, event: FfiConnectionEvent);
+}
+
+/// A flow the candidate ruleset would have routed differently, for FFI
+#[derive(Debug, Clone)]
+pub struct FfiRouteDivergence {
+    /// Domain name if known
+    pub domain: Option<String>,
+    /// Destination IP
+    pub dst_ip: Option<String>,
+    /// Destination port
+    pub dst_port: u16,
+    /// Action taken by the active ruleset
+    pub active: FfiRouteAction,
+    /// Action the candidate ruleset would have taken
+    pub candidate: FfiRouteAction,
+}
+
+impl From<RouteDivergence> for FfiRouteDivergence {
+    fn from(d: RouteDivergence) -> Self {
+        Self {
+            domain: d.domain,
+            dst_ip: d.dst_ip.map(|ip| ip.to_string()),
+            dst_port: d.dst_port,
+            active: d.active.into(),
+            candidate: d.candidate.into(),
+        }
+    }
+}
+
+/// Virtual interface addressing for FFI
+#[derive(Debug, Clone)]
+pub struct FfiInterfaceConfig {
+    /// Addresses in CIDR notation (at most one IPv4 and one IPv6)
+    pub addresses: Vec<String>,
+    /// Default IPv4 gateway
+    pub gateway_v4: Option<String>,
+    /// Default IPv6 gateway
+    pub gateway_v6: Option<String>,
+    /// Link MTU
+    pub mtu: u32,
+    /// Accept packets to any destination, see `InterfaceConfig::any_ip`
+    pub any_ip: bool,
+}
+
+/// Socket buffer sizes for flows to some destination ports, for FFI
+#[derive(Debug, Clone)]
+pub struct FfiBufferTier {
+    /// Destination ports the sizes apply to; flows to ports in no tier
+    /// keep the sizes of `set_tcp_buffer_sizes`
+    pub ports: Vec<u16>,
+    /// Receive buffer size in bytes
+    pub rx_bytes: u32,
+    /// Send buffer size in bytes
+    pub tx_bytes: u32,
+}
+
+impl From<FfiBufferTier> for BufferTier {
+    fn from(tier: FfiBufferTier) -> Self {
+        Self {
+            ports: tier.ports,
+            rx_buffer_size: tier.rx_bytes as usize,
+            tx_buffer_size: tier.tx_bytes as usize,
+        }
+    }
+}
+
+impl TryFrom<FfiInterfaceConfig> for InterfaceConfig {
+    type Error = VoyageError;
+
+    fn try_from(config: FfiInterfaceConfig) -> Result<Self, Self::Error> {
+        let addresses = config
+            .addresses
+            .iter()
+            .map(|a| a.parse())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(VoyageError::config)?;
+        let gateway_v4 = config
+            .gateway_v4
+            .map(|ip| ip.parse().map_err(|_| format!("Invalid IPv4 gateway: {}", ip)))
+            .transpose()
+            .map_err(VoyageError::config)?;
+        let gateway_v6 = config
+            .gateway_v6
+            .map(|ip| ip.parse().map_err(|_| format!("Invalid IPv6 gateway: {}", ip)))
+            .transpose()
+            .map_err(VoyageError::config)?;
+        let config = InterfaceConfig {
+            addresses,
+            gateway_v4,
+            gateway_v6,
+            mtu: config.mtu as usize,
+            any_ip: config.any_ip,
+        };
+        config.validate().map_err(VoyageError::config)?;
+        Ok(config)
+    }
+}
+
+impl From<&InterfaceConfig> for FfiInterfaceConfig {
+    fn from(config: &InterfaceConfig) -> Self {
+        Self {
+            addresses: config.addresses.iter().map(ToString::to_string).collect(),
+            gateway_v4: config.gateway_v4.map(|ip| ip.to_string()),
+            gateway_v6: config.gateway_v6.map(|ip| ip.to_string()),
+            mtu: config.mtu as u32,
+            any_ip: config.any_ip,
+        }
+    }
+}
+
+/// A/B ruleset comparison summary for FFI
+#[derive(Debug, Clone, Default)]
+pub struct FfiRouteComparison {
+    /// Flows evaluated against both rulesets
+    pub evaluated: u64,
+    /// Flows where the rulesets disagreed
+    pub diverged: u64,
+    /// Most recent divergences, oldest first
+    pub samples: Vec<FfiRouteDivergence>,
+}
+
+impl From<RouteComparison> for FfiRouteComparison {
+    fn from(c: RouteComparison) -> Self {
+        Self {
+            evaluated: c.evaluated,
+            diverged: c.diverged,
+            samples: c.samples.into_iter().map(Into::into).collect(),
+        }
+    }
+}
+
+/// Initialize the voyage core with a proxy configuration
+pub fn init_core(
+    server_host: String,
+    server_port: u16,
+    username: Option<String>,
+    password: Option<String>,
+) -> Result<(), VoyageError> {
+    let config = ProxyConfig {
+        server_host,
+        server_port,
+        username,
+        password: password.map(SecretString::from),
+        ..Default::default()
+    };
+
+    let mut slot = CORE_INSTANCE.write().map_err(|_| VoyageError::LockError)?;
+    if slot.is_some() {
+        return Err(VoyageError::AlreadyInitialized);
+    }
+
+    let core = VoyageCore::new(config);
+    let stats = core.shared_stats();
+    *slot = Some(Arc::new(RwLock::new(core)));
+    drop(slot);
+    if let Ok(mut slot) = CORE_STATS.write() {
+        *slot = Some(stats);
+    }
+    log::info!("Voyage core initialized");
+
+    if let Err(e) = start_engine() {
+        log::error!("Failed to start engine: {}", e);
+    }
+    Ok(())
+}
+
+/// Shutdown the core: stop the engine and event delivery, abort every flow
+/// and drop the instance, so `init_core` can start a new one
+pub fn shutdown_core() {
+    log::info!("Voyage core shutdown requested");
+    if is_initialized() {
+        let _ = stop_engine();
+    }
+    clear_connection_event_listener();
+    stop_metrics_server();
+    stop_api_server();
+    stop_inbound_server();
+
+    if let Ok(mut slot) = CORE_STATS.write() {
+        slot.take();
+    }
+    let core = CORE_INSTANCE.write().ok().and_then(|mut slot| slot.take());
+    if let Some(core) = core {
+        if let Ok(mut core) = core.write() {
+            let sockets = core.shutdown();
+            log::debug!("Released {} sockets", sockets.len());
+        }
+    }
+}
+
+/// Start the engine's background work: the core's runtime, the interface
+/// and the periodic NAT maintenance. Called by `init_core`; does nothing if already running
+/// and cancels an ongoing drain.
+pub fn start_engine() -> Result<(), VoyageError> {
+    let core = current_core()?;
+    if ENGINE
+        .transition(|state| *state == EngineState::Draining, EngineState::Running)
+        .is_ok()
+    {
+        core.write().map_err(|_| VoyageError::LockError)?.end_drain();
+        return Ok(());
+    }
+    let startable = |state: &EngineState| {
+        matches!(state, EngineState::Stopped | EngineState::Error { .. })
+    };
+    match ENGINE.transition(startable, EngineState::Starting) {
+        Ok(()) => {}
+        Err(state) if state.is_active() => return Ok(()),
+        Err(state) => {
+            return Err(VoyageError::connection(format!(
+                "Cannot start while the engine is {:?}",
+                state
+            )))
+        }
+    }
+
+    let started = core_runtime(&core).and_then(|runtime| {
+        VoyageCore::start_interface(&core)?;
+        let interval = core
+            .read()
+            .map_err(|_| VoyageError::LockError)?
+            .config
+            .nat
+            .cleanup_interval();
+        Ok(MaintenanceTask::start(core, &runtime, interval))
+    });
+    match started {
+        Ok(task) => {
+            if let Ok(mut slot) = MAINTENANCE.lock() {
+                *slot = Some(task);
+            }
+            ENGINE.set(EngineState::Running);
+            Ok(())
+        }
+        Err(e) => {
+            ENGINE.set(EngineState::Error {
+                reason: e.to_string(),
+            });
+            Err(e)
+        }
+    }
+}
+
+/// Stop the engine's background work and abort every flow, keeping the
+/// core and its configuration for a later `start_engine`
+pub fn stop_engine() -> Result<(), VoyageError> {
+    stop_engine_from(|state| !matches!(state, EngineState::Stopped | EngineState::Stopping))
+}
+
+/// Stop the engine if its state satisfies `from`
+fn stop_engine_from(from: impl FnOnce(&EngineState) -> bool) -> Result<(), VoyageError> {
+    let core = current_core()?;
+    if ENGINE.transition(from, EngineState::Stopping).is_err() {
+        return Ok(());
+    }
+
+    let task = MAINTENANCE.lock().ok().and_then(|mut slot| slot.take());
+    if let Some(task) = task {
+        task.stop();
+    }
+    let sockets = {
+        let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+        core.stop_runtime();
+        core.shutdown()
+    };
+    log::debug!("Engine stopped, {} sockets released", sockets.len());
+
+    ENGINE.set(EngineState::Stopped);
+    Ok(())
+}
+
+/// Stop taking new flows and stop the engine once the running ones finish,
+/// or after `timeout_ms` at the latest.
+///
+/// Lets in-flight downloads complete when the VPN is switched off. New flows
+/// are reset or sent direct as set with `set_drain_policy`; `start_engine`
+/// cancels the drain. Returns how many flows are still running.
+pub fn begin_drain(timeout_ms: u64) -> Result<u32, VoyageError> {
+    let core = current_core()?;
+    let runtime = core_runtime(&core)?;
+    if let Err(state) =
+        ENGINE.transition(|state| *state == EngineState::Running, EngineState::Draining)
+    {
+        return Err(VoyageError::connection(format!(
+            "Cannot drain while the engine is {:?}",
+            state
+        )));
+    }
+
+    let running = core
+        .write()
+        .map_err(|_| VoyageError::LockError)?
+        .begin_drain(Duration::from_millis(timeout_ms));
+    runtime.spawn(async move {
+        loop {
+            let done = match core.read() {
+                Ok(core) => core.drain_done(),
+                Err(_) => Some(true),
+            };
+            match done {
+                Some(true) => break,
+                Some(false) => tokio::time::sleep(DRAIN_POLL_INTERVAL).await,
+                // Cancelled, or the engine was stopped meanwhile
+                None => return,
+            }
+        }
+        // Unless start_engine cancelled the drain meanwhile
+        log::info!("Drain finished, stopping the engine");
+        let _ = stop_engine_from(|state| *state == EngineState::Draining);
+    });
+    Ok(running as u32)
+}
+
+/// Choose what happens to new flows during `begin_drain`
+pub fn set_drain_policy(policy: DrainPolicy) -> Result<(), VoyageError> {
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    core.config.drain_policy = policy;
+    Ok(())
+}
+
+/// Host callback writing packets to the TUN device.
+///
+/// Called with every batch of packets the core's interfaces send, on the
+/// thread that polled them.
+pub trait PacketWriter: Send + Sync {
+    fn write_packets(&self, packets: Vec<Vec<u8>>);
+}
+
+/// Write outbound packets through `writer` instead of queueing them
+pub fn set_packet_writer(writer: Box<dyn PacketWriter>) -> Result<(), VoyageError> {
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    core.set_packet_sink(Some(Arc::new(move |packets| writer.write_packets(packets))));
+    Ok(())
+}
+
+/// Go back to queueing outbound packets
+pub fn clear_packet_writer() -> Result<(), VoyageError> {
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    core.set_packet_sink(None);
+    Ok(())
+}
+
+/// Current engine lifecycle state
+pub fn get_engine_state() -> EngineState {
+    ENGINE.state()
+}
+
+/// Host callback told about engine state changes.
+///
+/// Called on the thread that changed the state, without any lock held.
+pub trait EngineStateListener: Send + Sync {
+    fn on_engine_state_changed(&self, state: EngineState);
+}
+
+/// Report engine state changes to `listener`, replacing any previous one
+pub fn set_engine_state_listener(listener: Box<dyn EngineStateListener>) {
+    ENGINE.set_listener(Some(Arc::new(move |state: &EngineState| {
+        listener.on_engine_state_changed(state.clone())
+    })));
+}
+
+/// Stop reporting engine state changes
+pub fn clear_engine_state_listener() {
+    ENGINE.set_listener(None);
+}
+
+/// Process an inbound packet from the TUN device.
+///
+/// With a packet writer registered, DNS queries (UDP to port 53) are
+/// answered through the writer and an empty packet is returned.
+pub fn process_inbound_packet(mut packet: Vec<u8>) -> Result<Vec<u8>, VoyageError> {
+    let core = current_core()?;
+    ensure_accepting()?;
+    if intercept_dns(&core, &packet)? {
+        return Ok(Vec::new());
+    }
+
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    core.process_inbound(&mut packet)?;
+
+    // For now, just return the packet as-is
+    // In a full implementation, this would involve routing through smoltcp
+    Ok(packet)
+}
+
+/// `voyage_inject_inbound_packet`: the packet is queued on the interface
+pub const INJECT_QUEUED: i32 = 0;
+/// `voyage_inject_inbound_packet`: the core rejected the packet;
+/// `last_error_message` on the same thread says why
+pub const INJECT_REJECTED: i32 = 1;
+/// `voyage_inject_inbound_packet`: the interface's rx queue was full
+pub const INJECT_QUEUE_FULL: i32 = 2;
+/// `voyage_inject_inbound_packet`: no core, the engine is not running, or
+/// the core's lock is poisoned
+pub const INJECT_UNAVAILABLE: i32 = -1;
+
+/// Process an inbound packet straight from the host's buffer and queue it
+/// on the core's interface, for packet loops where the copies of
+/// `process_inbound_packet` show up in profiles.
+///
+/// UniFFI cannot borrow host memory, so this is a plain C function,
+/// declared in `include/voyage_packet.h`. Swift calls it from
+/// `withUnsafeBytes`; the buffer may be reused as soon as it returns.
+/// Returns one of the `INJECT_*` codes.
+///
+/// # Safety
+///
+/// `data` must point to `len` readable bytes for the duration of the call.
+#[no_mangle]
+pub unsafe extern "C" fn voyage_inject_inbound_packet(data: *const u8, len: usize) -> i32 {
+    if data.is_null() {
+        return INJECT_REJECTED;
+    }
+    if !ENGINE.state().accepts_packets() {
+        return INJECT_UNAVAILABLE;
+    }
+    // SAFETY: the caller guarantees `len` readable bytes at `data`
+    let packet = unsafe { std::slice::from_raw_parts(data, len) };
+    match inject_inbound(packet) {
+        Ok(true) => INJECT_QUEUED,
+        Ok(false) => INJECT_QUEUE_FULL,
+        Err(VoyageError::NotInitialized | VoyageError::LockError) => INJECT_UNAVAILABLE,
+        Err(e) => {
+            INJECT_ERROR.with(|last| *last.borrow_mut() = Some(e.message()));
+            INJECT_REJECTED
+        }
+    }
+}
+
+/// Queue `packet` on the core's interface, answering DNS queries directly
+fn inject_inbound(packet: &[u8]) -> Result<bool, VoyageError> {
+    let core = current_core()?;
+    if intercept_dns(&core, packet)? {
+        return Ok(true);
+    }
+
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    core.inject_inbound(packet)
+}
+
+/// Fail unless the engine takes packets from the device
+fn ensure_accepting() -> Result<(), VoyageError> {
+    let state = ENGINE.state();
+    if state.accepts_packets() {
+        Ok(())
+    } else {
+        Err(VoyageError::connection(format!(
+            "Packet dropped while the engine is {:?}",
+            state
+        )))
+    }
+}
+
+/// Process an outbound packet to send to the TUN device.
+///
+/// Fails for a UDP datagram whose sender no NAT mapping admits.
+pub fn process_outbound_packet(mut packet: Vec<u8>) -> Result<Vec<u8>, VoyageError> {
+    let core = current_core()?;
+
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    core.process_outbound(&mut packet)?;
+    Ok(packet)
+}
+
+/// Process a batch of inbound packets under a single lock.
+///
+/// Packets that fail to process are dropped from the returned batch, which
+/// keeps the order of the rest.
+pub fn process_inbound_packets(mut packets: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>, VoyageError> {
+    let core = current_core()?;
+    ensure_accepting()?;
+    packets.retain(|packet| !intercept_dns(&core, packet).unwrap_or(false));
+
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    let pool = core.buffer_pool().clone();
+    Ok(packets
+        .into_iter()
+        .filter_map(|mut packet| match core.process_inbound(&mut packet) {
+            Ok(()) => Some(packet),
+            Err(e) => {
+                log::debug!("Dropped inbound packet: {}", e);
+                // Its buffer can carry a packet the core sends
+                pool.recycle(packet);
+                None
+            }
+        })
+        .collect())
+}
+
+/// Process a batch of outbound packets under a single lock.
+///
+/// Packets that fail to process are dropped from the returned batch, which
+/// keeps the order of the rest.
+pub fn process_outbound_packets(packets: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>, VoyageError> {
+    let core = current_core()?;
+
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    let pool = core.buffer_pool().clone();
+    Ok(packets
+        .into_iter()
+        .filter_map(|mut packet| match core.process_outbound(&mut packet) {
+            Ok(()) => Some(packet),
+            Err(e) => {
+                log::debug!("Dropped outbound packet: {}", e);
+                pool.recycle(packet);
+                None
+            }
+        })
+        .collect())
+}
+
+/// Answer a DNS query packet (UDP to port 53) from the TUN device.
+///
+/// Returns the response packet to write back to the device, or `None` if
+/// the packet is not a DNS query. Blocks while an upstream is consulted, so
+/// call it off the main packet loop.
+pub fn process_dns_packet(packet: Vec<u8>) -> Result<Option<Vec<u8>>, VoyageError> {
+    let (src, dst, query) = match dns_query(&packet)? {
+        Some(query) => query,
+        None => return Ok(None),
+    };
+
+    let response = resolve_query(query)?;
+
+    // Answer from the address the app queried
+    Ok(build_udp_packet(dst, src, &response))
+}
+
+/// Answer a raw DNS query (e.g. one read from a TCP port 53 flow)
+pub fn resolve_dns_query(query: Vec<u8>) -> Result<Vec<u8>, VoyageError> {
+    resolve_query(&query)
+}
+
+/// Source, destination and query of a UDP packet to port 53
+type DnsQueryPacket<'a> = (SocketAddr, SocketAddr, &'a [u8]);
+
+fn dns_query(packet: &[u8]) -> Result<Option<DnsQueryPacket<'_>>, VoyageError> {
+    let parsed = ParsedPacket::parse(packet)?;
+    Ok(match (parsed.src_addr(), parsed.dst_addr(), &parsed.udp) {
+        (Some(src), Some(dst), Some(_)) if dst.port() == DNS_PORT => {
+            Some((src, dst, parsed.udp_payload(packet).unwrap_or_default()))
+        }
+        _ => None,
+    })
+}
+
+/// Answer a DNS query from the packet path on the core's runtime, writing
+/// the response through the packet writer. Returns false, leaving the
+/// packet to the flow pipeline, if it is not a DNS query or no writer is
+/// registered.
+fn intercept_dns(core: &Arc<RwLock<VoyageCore>>, packet: &[u8]) -> Result<bool, VoyageError> {
+    // Malformed packets are quarantined by the flow pipeline
+    let Ok(Some((src, dst, query))) = dns_query(packet) else {
+        return Ok(false);
+    };
+    let sink = core.read().map_err(|_| VoyageError::LockError)?.packet_sink();
+    let Some(sink) = sink else {
+        return Ok(false);
+    };
+
+    let query = query.to_vec();
+    let resolving = Arc::clone(core);
+    core_runtime(core)?.spawn(async move {
+        match resolve(resolving, &query).await {
+            // Answer from the address the app queried
+            Ok(response) => match build_udp_packet(dst, src, &response) {
+                Some(packet) => sink(vec![packet]),
+                None => log::debug!("DNS answer to {} does not fit a packet", src),
+            },
+            Err(e) => log::debug!("Dropped DNS query from {}: {}", src, e),
+        }
+    });
+    Ok(true)
+}
+
+/// The core's runtime, started on first use
+fn core_runtime(core: &RwLock<VoyageCore>) -> Result<Handle, VoyageError> {
+    let running = core
+        .read()
+        .map_err(|_| VoyageError::LockError)?
+        .runtime_handle();
+    match running {
+        Some(handle) => Ok(handle),
+        None => core.write().map_err(|_| VoyageError::LockError)?.runtime(),
+    }
+}
+
+/// Run a blocking FFI call on the core runtime's blocking pool, so the
+/// caller's thread never waits on the core lock
+async fn run_blocking<T, F>(f: F) -> Result<T, VoyageError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, VoyageError> + Send + 'static,
+{
+    let core = current_core()?;
+    core_runtime(&core)?
+        .spawn_blocking(f)
+        .await
+        .map_err(|e| VoyageError::io(e.to_string()))?
+}
+
+fn malformed_dns(detail: String) -> VoyageError {
+    log::debug!("Malformed DNS message: {}", detail);
+    VoyageError::packet(ParseErrorKind::MalformedDns, 0)
+}
+
+/// Answer a DNS query from a synchronous FFI call, on the core's runtime
+fn resolve_query(query: &[u8]) -> Result<Vec<u8>, VoyageError> {
+    let core = current_core()?;
+    let runtime = core_runtime(&core)?;
+    let resolving = resolve(core, query);
+    if Handle::try_current().is_err() {
+        return runtime.block_on(resolving);
+    }
+    // Handle::block_on panics on a thread already running a runtime
+    std::thread::scope(|scope| scope.spawn(|| runtime.block_on(resolving)).join())
+        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+}
+
+/// Plan and answer a DNS query; upstream failures become SERVFAIL answers
+async fn resolve(core: Arc<RwLock<VoyageCore>>, query: &[u8]) -> Result<Vec<u8>, VoyageError> {
+    let message = DnsMessage::parse(query).map_err(malformed_dns)?;
+
+    let started = Instant::now();
+
+    // Don't hold the lock while waiting on an upstream
+    let (plan, proxy, timeout) = {
+        let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+        let plan = core.plan_dns(&message);
+        if let DnsPlan::Answer(response) = &plan {
+            core.log_dns_query(&message, response, None, false, started.elapsed());
+        }
+        let proxy = match &plan {
+            DnsPlan::Forward { via_proxy: true, .. } => Some(core.socks5_client()),
+            _ => None,
+        };
+        (plan, proxy, core.dns.config().timeout())
+    };
+
+    let (upstreams, rewrite, via_proxy) = match plan {
+        DnsPlan::Answer(response) => return Ok(response.encode()),
+        DnsPlan::Forward {
+            upstreams,
+            rewrite,
+            via_proxy,
+        } => (upstreams, rewrite, via_proxy),
+    };
+    let rewritten = rewrite.as_ref().map(|target| message.renamed(target).encode());
+    let query = rewritten.as_deref().unwrap_or(query);
+
+    let result = match proxy {
+        Some(Err(e)) => Err(e),
+        Some(Ok(client)) => dns::forward(&upstreams, Some(&client), query, timeout).await,
+        None => dns::forward(&upstreams, None, query, timeout).await,
+    };
+
+    let result = result.and_then(|(upstream, response)| match &rewrite {
+        Some(target) => {
+            let response = DnsMessage::parse(&response).map_err(malformed_dns)?;
+            let response = DnsMessage::rewritten_reply(&message, target, &response).encode();
+            Ok((upstream, response))
+        }
+        None => Ok((upstream, response)),
+    });
+
+    match result {
+        Ok((upstream, response)) => {
+            if let Ok(mut core) = core.write() {
+                core.record_dns_response(&response);
+                if let Ok(parsed) = DnsMessage::parse(&response) {
+                    let latency = started.elapsed();
+                    core.log_dns_query(&message, &parsed, Some(upstream), via_proxy, latency);
+                }
+            }
+            Ok(response)
+        }
+        Err(e) => {
+            log::warn!("DNS query for {:?} failed: {}", message.question(), e);
+            let response = DnsMessage::reply(&message, RCODE_SERVFAIL);
+            if let Ok(mut core) = core.write() {
+                core.dns.record_failure();
+                core.log_dns_query(&message, &response, None, via_proxy, started.elapsed());
+            }
+            Ok(response.encode())
+        }
+    }
+}
+
+/// Up to `limit` recent inbound packets the parser rejected, newest first,
+/// for attaching to bug reports
+pub fn get_malformed_packets(limit: u32) -> Result<Vec<MalformedPacket>, VoyageError> {
+    let core = current_core()?;
+
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    Ok(core.malformed_packets(limit as usize))
+}
+
+/// Forget the quarantined malformed packets
+pub fn clear_malformed_packets() -> Result<(), VoyageError> {
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    core.clear_malformed_packets();
+    Ok(())
+}
+
+/// Up to `limit` recently answered DNS queries, newest first
+pub fn get_dns_query_log(limit: u32) -> Result<Vec<DnsQueryRecord>, VoyageError> {
+    let core = current_core()?;
+
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    Ok(core.dns_query_log(limit as usize))
+}
+
+/// Forget every logged DNS query
+pub fn clear_dns_query_log() -> Result<(), VoyageError> {
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    core.dns.clear_query_log();
+    Ok(())
+}
+
+/// Drop every cached DNS answer
+pub fn flush_dns_cache() -> Result<(), VoyageError> {
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    core.dns.flush_cache();
+    log::info!("DNS cache flushed");
+    Ok(())
+}
+
+/// Get DNS forwarder and cache statistics
+pub fn get_dns_stats() -> Result<DnsStats, VoyageError> {
+    let core = current_core()?;
+
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    Ok(core.dns.stats())
+}
+
+/// Replace the static DNS hosts with `HOST, name, address` lines
+pub fn load_hosts(config: String) -> Result<u32, VoyageError> {
+    let entries = HostTable::parse_config(&config).map_err(VoyageError::config)?;
+
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    let count = entries.len() as u32;
+    core.dns.set_hosts(entries);
+    log::info!("Loaded {} static hosts", count);
+    Ok(count)
+}
+
+/// Replace the DNS block/rewrite/upstream rules
+pub fn load_dns_rules(config: String) -> Result<u32, VoyageError> {
+    let rules = DnsRuleSet::from_config(&config).map_err(VoyageError::config)?;
+
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    let count = rules.len() as u32;
+    core.dns.set_rules(rules);
+    log::info!("Loaded {} DNS rules", count);
+    Ok(count)
+}
+
+/// Remove every DNS rule
+pub fn clear_dns_rules() -> Result<(), VoyageError> {
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    core.dns.set_rules(DnsRuleSet::new());
+    Ok(())
+}
+
+/// Replace the HEADER-REWRITE and URL-REWRITE rules
+pub fn load_rewrite_rules(config: String) -> Result<u32, VoyageError> {
+    let rules = RewriteEngine::from_config(&config).map_err(VoyageError::config)?;
+
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    let count = rules.len() as u32;
+    core.rewrite = rules;
+    log::info!("Loaded {} rewrite rules", count);
+    Ok(count)
+}
+
+/// Remove every rewrite rule
+pub fn clear_rewrite_rules() -> Result<(), VoyageError> {
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    core.rewrite = RewriteEngine::new();
+    Ok(())
+}
+
+/// Remove every static DNS host
+pub fn clear_hosts() -> Result<(), VoyageError> {
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    core.dns.set_hosts(Vec::new());
+    Ok(())
+}
+
+/// Switch to a different proxy server without restarting the core.
+///
+/// With `drain_proxied` set, flows relayed through the old server are
+/// aborted; returns how many were.
+pub fn update_proxy_config(
+    server_host: String,
+    server_port: u16,
+    username: Option<String>,
+    password: Option<String>,
+    protocol: ProxyProtocol,
+    drain_proxied: bool,
+) -> Result<u32, VoyageError> {
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    let drained = core.update_proxy_server(
+        server_host,
+        server_port,
+        username,
+        password.map(SecretString::from),
+        protocol,
+        drain_proxied,
+    )?;
+    Ok(drained as u32)
+}
+
+/// Select `member` in a proxy group of the configuration file. Flows through
+/// the previous server are aborted if the group interrupts existing
+/// connections; returns how many were.
+pub fn select_proxy(group: String, member: String) -> Result<u32, VoyageError> {
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    let drained = core.select_proxy(&group, &member)?;
+    Ok(drained as u32)
+}
+
+/// Set how UDP flows are mapped to local ports (applies to new flows)
+pub fn set_udp_nat_mode(mode: NatMode) -> Result<(), VoyageError> {
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    core.config.nat.udp_mode = mode;
+    core.conn_manager.set_udp_nat_mode(mode);
+    log::info!("UDP NAT mode set to {:?}", mode);
+    Ok(())
+}
+
+/// Set how local ports are picked for new NAT mappings
+pub fn set_nat_port_strategy(strategy: PortStrategy) -> Result<(), VoyageError> {
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    core.config.nat.port_strategy = strategy;
+    core.conn_manager.set_nat_port_strategy(strategy);
+    log::info!("NAT port strategy set to {:?}", strategy);
+    Ok(())
+}
+
+/// Reject QUIC to proxied destinations so apps fall back to TCP (applies to
+/// new flows)
+pub fn set_block_quic(enabled: bool) -> Result<(), VoyageError> {
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    core.config.block_quic = enabled;
+    log::info!("QUIC blocking {}", if enabled { "enabled" } else { "disabled" });
+    Ok(())
+}
+
+/// Set the NAT idle timeouts per flow state
+pub fn set_nat_timeouts(timeouts: NatTimeouts) -> Result<(), VoyageError> {
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    core.config.nat.timeouts = timeouts;
+    core.conn_manager.set_nat_timeouts(timeouts);
+    Ok(())
+}
+
+/// Get the NAT idle timeouts per flow state
+pub fn get_nat_timeouts() -> Result<NatTimeouts, VoyageError> {
+    let core = current_core()?;
+
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    Ok(core.conn_manager.nat_timeouts())
+}
+
+/// Set the NAT table capacity; the least recently active flows are
+/// closed if more are tracked
+pub fn set_nat_table_size(size: u32) -> Result<(), VoyageError> {
+    if size == 0 {
+        return Err(VoyageError::config("NAT table size must be positive".into()));
+    }
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    core.set_nat_table_size(size as usize);
+    core.publish_stats();
+    Ok(())
+}
+
+/// Set the receive and send buffer sizes of each TCP connection
+/// (applies to connections opened afterwards)
+pub fn set_tcp_buffer_sizes(rx_bytes: u32, tx_bytes: u32) -> Result<(), VoyageError> {
+    if rx_bytes == 0 || tx_bytes == 0 {
+        return Err(VoyageError::config("TCP buffer sizes must be positive".into()));
+    }
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    core.set_tcp_buffer_sizes(rx_bytes as usize, tx_bytes as usize);
+    core.publish_stats();
+    Ok(())
+}
+
+/// Size the buffers of flows to the listed ports by tier instead of
+/// `set_tcp_buffer_sizes`, e.g. large windows for bulk transfers and small
+/// ones for chatty ports (applies to connections opened afterwards)
+pub fn set_tcp_buffer_tiers(tiers: Vec<FfiBufferTier>) -> Result<(), VoyageError> {
+    if tiers.iter().any(|tier| tier.rx_bytes == 0 || tier.tx_bytes == 0) {
+        return Err(VoyageError::config("TCP buffer sizes must be positive".into()));
+    }
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    core.set_tcp_buffer_tiers(tiers.into_iter().map(BufferTier::from).collect());
+    Ok(())
+}
+
+/// Set the maximum number of tracked connections; new flows beyond it
+/// are refused
+pub fn set_max_connections(limit: u32) -> Result<(), VoyageError> {
+    if limit == 0 {
+        return Err(VoyageError::config("Connection limit must be positive".into()));
+    }
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    let limits = ResourceLimits {
+        max_connections: limit as usize,
+        ..core.config.limits
+    };
+    core.set_resource_limits(limits);
+    core.publish_stats();
+    Ok(())
+}
+
+/// Cap simultaneous proxied and per-destination connections; connections
+/// over a cap are queued or rejected as `limits.excess` says
+pub fn set_concurrency_limits(limits: FfiConcurrencyLimits) -> Result<(), VoyageError> {
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    core.set_concurrency_limits(limits.into());
+    core.publish_stats();
+    Ok(())
+}
+
+/// Cap how fast new connections may be opened, per source IP and in total;
+/// a source over its rate has its new connections dropped for a while
+pub fn set_connection_rate_limits(limits: ConnectionRateLimits) -> Result<(), VoyageError> {
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    core.set_connection_rate_limits(limits);
+    log::info!("Connection rate limits set to {:?}", limits);
+    Ok(())
+}
+
+/// Set the memory all flows together may use in bytes (0 = unlimited);
+/// new flows are refused once their estimated usage would exceed it
+pub fn set_memory_budget(bytes: u64) -> Result<(), VoyageError> {
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    let limits = ResourceLimits {
+        memory_budget: usize::try_from(bytes).unwrap_or(usize::MAX),
+        ..core.config.limits
+    };
+    core.set_resource_limits(limits);
+    core.publish_stats();
+    Ok(())
+}
+
+/// Load routing rules from a configuration string
+pub fn load_rules(config: String) -> Result<u32, VoyageError> {
+    let core = current_core()?;
+
+    // Parse before locking so packet processing isn't held up
+    let rules = RuleEngine::parse_config(&config)?;
+
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    let count = core.proxy_manager().add_rules(rules);
+    log::info!("Loaded {} rules", count);
+
+    Ok(count as u32)
+}
+
+/// Check a YAML, TOML or JSON configuration file without loading it;
+/// needs no running core. `${NAME}` placeholders are filled from
+/// `variables`, as in `reload_config`.
+pub fn validate_config(
+    config: String,
+    variables: HashMap<String, String>,
+) -> Vec<ConfigDiagnostic> {
+    lint::validate_config(&config, &variables)
+}
+
+/// Switch the running core to a YAML, TOML or JSON configuration file,
+/// applying only what changed. `${NAME}` placeholders are filled from
+/// `variables`, so secrets such as proxy passwords can stay in the
+/// Keychain rather than in the file.
+pub fn reload_config(
+    config: String,
+    variables: HashMap<String, String>,
+) -> Result<ConfigDiff, VoyageError> {
+    // Parse before locking so packet processing isn't held up
+    let file = VoyageConfig::parse_auto(&substitute_variables(&config, &variables)?)?;
+
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    core.reload_config(file)
+}
+
+/// Store a YAML, TOML or JSON configuration file as a named profile,
+/// with `${NAME}` placeholders filled from `variables`
+pub fn add_profile(
+    name: String,
+    config: String,
+    variables: HashMap<String, String>,
+) -> Result<(), VoyageError> {
+    let file = VoyageConfig::parse_auto(&substitute_variables(&config, &variables)?)?;
+
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    core.add_profile(&name, file)
+}
+
+/// Forget a named profile other than the active one
+pub fn remove_profile(name: String) -> Result<(), VoyageError> {
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    core.remove_profile(&name)
+}
+
+/// Make a stored profile the running configuration
+pub fn switch_profile(name: String) -> Result<ConfigDiff, VoyageError> {
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    core.switch_profile(&name)
+}
+
+/// Stored profiles with the traffic routed while each was active
+pub fn list_profiles() -> Result<Vec<ProfileInfo>, VoyageError> {
+    let core = current_core()?;
+
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    Ok(core.profiles())
+}
+
+/// A configuration converted from another client, as Voyage YAML
+#[derive(Debug, Clone)]
+pub struct FfiImportResult {
+    /// The converted configuration file
+    pub config: String,
+    /// Directives that were skipped or changed, with their lines
+    pub diagnostics: Vec<ImportDiagnostic>,
+}
+
+/// Convert a Surge or Clash configuration; needs no running core
+pub fn import_config(text: String, format: ImportFormat) -> Result<FfiImportResult, VoyageError> {
+    let result = import::convert(&text, format)?;
+    log::info!(
+        "Imported configuration with {} diagnostics",
+        result.diagnostics.len()
+    );
+    Ok(FfiImportResult {
+        config: result.config.to_yaml()?,
+        diagnostics: result.diagnostics,
+    })
+}
+
+/// Evaluate routing decision for a connection
+pub fn evaluate_route(
+    domain: Option<String>,
+    dst_ip: Option<String>,
+    dst_port: u16,
+    src_port: u16,
+) -> Result<FfiRouteAction, VoyageError> {
+    let core = current_core()?;
+
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    let ip: Option<IpAddr> = dst_ip
+        .as_ref()
+        .and_then(|s| s.parse().ok());
+
+    let flow = MatchContext::new(domain.as_deref(), ip, dst_port).with_source(None, src_port);
+    let action = core.proxy_manager().evaluate_route_ffi(&flow);
+
+    Ok(action)
+}
+
+/// What a routing script sees of a flow
+#[derive(Debug, Clone)]
+pub struct ScriptContext {
+    pub domain: Option<String>,
+    pub dst_ip: Option<String>,
+    pub dst_port: u16,
+    /// App that opened the flow, if `set_connection_app` attributed it
+    /// before the flow was routed
+    pub process: Option<String>,
+    /// Country code of the destination address, if `load_geoip` loaded a
+    /// database listing it
+    pub country: Option<String>,
+    /// Policy the rules picked
+    pub action: FfiRouteAction,
+    /// Rule that picked it, if any
+    pub matched_rule: Option<String>,
+}
+
+impl ScriptContext {
+    fn new(decision: &RoutingDecision, facts: &FlowFacts) -> Self {
+        Self {
+            domain: decision.domain.clone(),
+            dst_ip: decision.dst_ip.map(|ip| ip.to_string()),
+            dst_port: decision.dst_port,
+            process: facts.process.map(String::from),
+            country: facts.country.map(String::from),
+            action: decision.action.clone().into(),
+            matched_rule: decision.matched_rule.clone(),
+        }
+    }
+}
+
+/// Host callback deciding routes the rule syntax cannot express.
+///
+/// Called for every flow the rules route, on the routing thread with the
+/// core locked: it must return quickly and must not call into the core.
+/// Returning `None` keeps the rules' policy.
+pub trait ScriptHandler: Send + Sync {
+    fn route(&self, context: ScriptContext) -> Option<FfiRouteAction>;
+}
+
+/// Let `handler` override routing decisions, replacing any previous one
+pub fn set_script_handler(handler: Box<dyn ScriptHandler>) -> Result<(), VoyageError> {
+    let core = current_core()?;
+
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    core.proxy_manager().set_script(Some(Arc::new(
+        move |decision: &RoutingDecision, facts: &FlowFacts| {
+            handler
+                .route(ScriptContext::new(decision, facts))
+                .map(RouteAction::from)
+        },
+    )));
+    Ok(())
+}
+
+/// Route by the rules alone again
+pub fn clear_script_handler() -> Result<(), VoyageError> {
+    let core = current_core()?;
+
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    core.proxy_manager().set_script(None);
+    Ok(())
+}
+
+/// Async variant of `evaluate_route`
+pub async fn evaluate_route_async(
+    domain: Option<String>,
+    dst_ip: Option<String>,
+    dst_port: u16,
+    src_port: u16,
+) -> Result<FfiRouteAction, VoyageError> {
+    run_blocking(move || evaluate_route(domain, dst_ip, dst_port, src_port)).await
+}
+
+/// Async variant of `load_rules`
+pub async fn load_rules_async(config: String) -> Result<u32, VoyageError> {
+    run_blocking(move || load_rules(config)).await
+}
+
+/// Open a tunnel to `target_host:target_port` through the proxy and return
+/// how long it took, in milliseconds
+pub async fn test_proxy_latency_async(
+    target_host: String,
+    target_port: u16,
+    timeout_ms: u64,
+) -> Result<u64, VoyageError> {
+    measure_proxy_latency(target_host, target_port, timeout_ms).await
+}
+
+async fn measure_proxy_latency(
+    target_host: String,
+    target_port: u16,
+    timeout_ms: u64,
+) -> Result<u64, VoyageError> {
+    let client = run_blocking(|| {
+        let core = current_core()?;
+        let core = core.read().map_err(|_| VoyageError::LockError)?;
+        core.socks5_client()
+    })
+    .await?;
+
+    let target = TargetAddr::from_domain(target_host, target_port);
+    let timeout = Duration::from_millis(timeout_ms);
+    let core = current_core()?;
+    core_runtime(&core)?
+        .spawn(async move {
+            let started = Instant::now();
+            tokio::time::timeout(timeout, client.connect(target))
+                .await
+                .map_err(|_| {
+                    VoyageError::io(format!("Proxy did not answer within {} ms", timeout_ms))
+                })??;
+            Ok(started.elapsed().as_millis() as u64)
+        })
+        .await
+        .map_err(|e| VoyageError::io(e.to_string()))?
+}
+
+/// Get current core statistics
+pub fn get_stats() -> Result<CoreStats, VoyageError> {
+    // Read the published counters so polling never waits on the core lock
+    let stats = CORE_STATS
+        .read()
+        .map_err(|_| VoyageError::LockError)?
+        .clone()
+        .ok_or(VoyageError::NotInitialized)?;
+
+    Ok(stats.snapshot())
+}
+
+/// Render the engine's counters as OpenMetrics text
+pub fn get_metrics_text() -> Result<String, VoyageError> {
+    let core = current_core()?;
+
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    Ok(metrics::render(&core))
+}
+
+/// Serve `get_metrics_text` at `http://127.0.0.1:<port>/metrics` (0 picks
+/// a free port) and return the port. Not available on iOS.
+pub fn start_metrics_server(port: u16) -> Result<u16, VoyageError> {
+    if cfg!(target_os = "ios") {
+        return Err(VoyageError::config(
+            "Metrics listener is not available on iOS".into(),
+        ));
+    }
+    current_core()?;
+
+    let mut slot = METRICS_SERVER.lock().map_err(|_| VoyageError::LockError)?;
+    // Release the old port first so it can be bound again
+    if let Some(server) = slot.take() {
+        server.stop();
+    }
+    let server = MetricsServer::start(port, || get_metrics_text().unwrap_or_default())
+        .map_err(|e| VoyageError::io(e.to_string()))?;
+    let port = server.port();
+    *slot = Some(server);
+    Ok(port)
+}
+
+/// Close the metrics listener, if running
+pub fn stop_metrics_server() {
+    let server = METRICS_SERVER.lock().ok().and_then(|mut slot| slot.take());
+    if let Some(server) = server {
+        server.stop();
+    }
+}
+
+/// Serve the remote control API at `http://127.0.0.1:<port>/` (0 picks a
+/// free port) and return the port. Requests must carry `token`, which
+/// must not be empty. Not available on iOS.
+pub fn start_api_server(port: u16, token: String) -> Result<u16, VoyageError> {
+    if cfg!(target_os = "ios") {
+        return Err(VoyageError::config(
+            "API listener is not available on iOS".into(),
+        ));
+    }
+    if token.is_empty() {
+        return Err(VoyageError::config("API token must not be empty".into()));
+    }
+    let core = current_core()?;
+
+    let mut slot = API_SERVER.lock().map_err(|_| VoyageError::LockError)?;
+    // Release the old port first so it can be bound again
+    if let Some(server) = slot.take() {
+        server.stop();
+    }
+    let server = ApiServer::start(core, port, token)
+        .map_err(|e| VoyageError::io(e.to_string()))?;
+    let port = server.port();
+    *slot = Some(server);
+    Ok(port)
+}
+
+/// Close the remote control API and its open streams, if running
+pub fn stop_api_server() {
+    let server = API_SERVER.lock().ok().and_then(|mut slot| slot.take());
+    if let Some(server) = server {
+        server.stop();
+    }
+}
+
+/// Serve a SOCKS5 and HTTP proxy at 127.0.0.1:<port> (0 picks a free
+/// port) that routes requests by the active rules, so the core can run as
+/// a system proxy without a TUN device. Returns the port. Not available on
+/// iOS.
+pub fn start_inbound_server(port: u16) -> Result<u16, VoyageError> {
+    if cfg!(target_os = "ios") {
+        return Err(VoyageError::config(
+            "Inbound proxy listener is not available on iOS".into(),
+        ));
+    }
+    let core = current_core()?;
+
+    let mut slot = INBOUND_SERVER.lock().map_err(|_| VoyageError::LockError)?;
+    // Release the old port first so it can be bound again
+    if let Some(server) = slot.take() {
+        server.stop();
+    }
+    let server =
+        InboundServer::start(core, port).map_err(|e| VoyageError::io(e.to_string()))?;
+    let port = server.port();
+    *slot = Some(server);
+    Ok(port)
+}
+
+/// Close the inbound proxy listener and its relayed connections, if running
+pub fn stop_inbound_server() {
+    let server = INBOUND_SERVER.lock().ok().and_then(|mut slot| slot.take());
+    if let Some(server) = server {
+        server.stop();
+    }
+}
+
+/// Get the estimated memory held by sockets, NAT, packet queues and DNS cache
+pub fn get_memory_stats() -> Result<MemoryStats, VoyageError> {
+    let core = current_core()?;
+
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    Ok(core.memory_stats())
+}
+
+/// Get packet, byte and drop counters of the virtual device, to tell
+/// device-level losses from relay-level ones
+pub fn get_device_stats() -> Result<DeviceStats, VoyageError> {
+    let core = current_core()?;
+
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    Ok(core.device_stats())
+}
+
+/// List live flows matching `filter`, ordered by local port
+pub fn get_connections(filter: FfiConnectionFilter) -> Result<Vec<FfiConnection>, VoyageError> {
+    let core = current_core()?;
+
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    Ok(core.connections(&filter))
+}
+
+/// List every live flow, ordered by local port, for the connections screen
+pub fn get_active_connections() -> Result<Vec<FfiConnection>, VoyageError> {
+    get_connections(FfiConnectionFilter::default())
+}
+
+/// Deliver connection events to `listener`, replacing any previous one
+pub fn set_connection_event_listener(
+    listener: Box<dyn ConnectionEventListener>,
+) -> Result<(), VoyageError> {
+    let core = current_core()?;
+
+    let events = core
+        .read()
+        .map_err(|_| VoyageError::LockError)?
+        .conn_manager
+        .subscribe_events();
+
+    let forwarder = EventForwarder::start(events, move |event| {
+        listener.on_connection_event(event.into())
+    })
+    .map_err(|e| VoyageError::io(e.to_string()))?;
+
+    let previous = EVENT_FORWARDER
+        .lock()
+        .map_err(|_| VoyageError::LockError)?
+        .replace(forwarder);
+    if let Some(previous) = previous {
+        previous.stop();
+    }
+    Ok(())
+}
+
+/// Stop delivering connection events
+pub fn clear_connection_event_listener() {
+    let forwarder = EVENT_FORWARDER.lock().ok().and_then(|mut slot| slot.take());
+    if let Some(forwarder) = forwarder {
+        forwarder.stop();
+    }
+}
+
+/// Host callback receiving Rust log records.
+///
+/// Called on a background thread; records arrive in order.
+pub trait LogSink: Send + Sync {
+    fn on_log(&self, record: LogRecord);
+}
+
+/// Deliver log records at `level` and above to `sink`, replacing any
+/// previous sink. Works before `init_core`.
+pub fn set_log_callback(sink: Box<dyn LogSink>, level: LogLevel) -> Result<(), VoyageError> {
+    logging::set_sink(level, move |record| sink.on_log(record))
+}
+
+/// Stop delivering log records
+pub fn clear_log_callback() {
+    logging::clear_sink();
+}
 
-/// Core statistics for FFI
-#[derive(Debug, Clone, Default)]
-pub struct CoreStats {
-    /// Bytes sent through the proxy
-    pub bytes_sent: u64,
-    /// Bytes received through the proxy
-    pub bytes_received: u64,
-    /// Number of active connections
-    pub active_connections: u64,
-    /// Total connections since start
-    pub total_connections: u64,
+/// Host callback receiving one JSON line per completed connection.
+///
+/// Called on a background thread; lines arrive in order.
+pub trait FlowLogSink: Send + Sync {
+    fn on_flow(&self, line: String);
 }
 
-/// Initialize the voyage core with a proxy configuration
-pub fn init_core(
-    server_host: String,
-    server_port: u16,
-    username: Option<String>,
-    password: Option<String>,
+/// Log every completed connection as a JSON line to `path`, rotating the
+/// file once it exceeds `max_bytes` (0 = never) and keeping `max_files`
+/// rotated files. Replaces any previous flow log.
+pub fn set_flow_log_file(path: String, max_bytes: u64, max_files: u32) -> Result<(), VoyageError> {
+    let logger = FlowLogger::to_file(&path, max_bytes, max_files)?;
+    let core = current_core()?;
+
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    core.conn_manager.set_flow_logger(Some(logger));
+    log::info!("Logging flows to {}", path);
+    Ok(())
+}
+
+/// Hand every completed connection as a JSON line to `sink`, replacing any
+/// previous flow log
+pub fn set_flow_log_callback(sink: Box<dyn FlowLogSink>) -> Result<(), VoyageError> {
+    let logger = FlowLogger::to_callback(move |line| sink.on_flow(line))?;
+    let core = current_core()?;
+
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    core.conn_manager.set_flow_logger(Some(logger));
+    Ok(())
+}
+
+/// Stop logging flows; lines already queued are still written
+pub fn clear_flow_log() -> Result<(), VoyageError> {
+    let core = current_core()?;
+
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    core.conn_manager.set_flow_logger(None);
+    Ok(())
+}
+
+/// Host callback receiving copies of the bytes relayed for tapped hosts.
+///
+/// Called on a background thread; chunks arrive in order.
+pub trait TrafficTapSink: Send + Sync {
+    fn on_chunk(&self, chunk: TapChunk);
+}
+
+/// Mirror the streams the inbound proxy relays for `hosts` (names,
+/// `*.suffix` patterns or addresses) to `path` as JSON lines, rotating the
+/// file like the flow log. Replaces any previous tap.
+pub fn set_traffic_tap_file(
+    path: String,
+    max_bytes: u64,
+    max_files: u32,
+    hosts: Vec<String>,
 ) -> Result<(), VoyageError> {
-    let config = ProxyConfig {
-        server_host,
-        server_port,
-        username,
-        password,
-    };
+    let tap = TrafficTap::to_file(&path, max_bytes, max_files, hosts)?;
+    let core = current_core()?;
 
-    let core = VoyageCore::new(config);
-    
-    CORE_INSTANCE
-        .set(Arc::new(Mutex::new(core)))
-        .map_err(|_| VoyageError::AlreadyInitialized)?;
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
 
-    log::info!("Voyage core initialized");
+    core.tap = Some(Arc::new(tap));
+    log::info!("Mirroring tapped traffic to {}", path);
     Ok(())
 }
 
-/// Shutdown the core (note: OnceLock cannot be reset, so this just logs)
-pub fn shutdown_core() {
-    log::info!("Voyage core shutdown requested");
-    // OnceLock cannot be reset, so we just log the shutdown request
-    // In a real app, you might set a shutdown flag instead
+/// Hand copies of the streams relayed for `hosts` to `sink`, replacing any
+/// previous tap
+pub fn set_traffic_tap_callback(
+    sink: Box<dyn TrafficTapSink>,
+    hosts: Vec<String>,
+) -> Result<(), VoyageError> {
+    let tap = TrafficTap::to_callback(hosts, move |chunk| sink.on_chunk(chunk))?;
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    core.tap = Some(Arc::new(tap));
+    Ok(())
 }
 
-/// Process an inbound packet from the TUN device
-pub fn process_inbound_packet(packet: Vec<u8>) -> Result<Vec<u8>, VoyageError> {
-    let core = CORE_INSTANCE
-        .get()
-        .ok_or(VoyageError::NotInitialized)?;
+/// Stop mirroring; connections already tapped keep mirroring until they
+/// close
+pub fn clear_traffic_tap() -> Result<(), VoyageError> {
+    let core = current_core()?;
 
-    let mut core = core.lock().map_err(|_| VoyageError::LockError)?;
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
 
-    // Parse the packet
-    let parsed = ParsedPacket::parse(&packet)?;
+    core.tap = None;
+    Ok(())
+}
 
-    // Process through connection manager
-    let _conn_info = core.conn_manager.process_packet(&parsed)?;
+/// Kill the connection with this identifier (from `get_connections`)
+pub fn close_connection(connection_id: u64) -> Result<(), VoyageError> {
+    let core = current_core()?;
 
-    // For now, just return the packet as-is
-    // In a full implementation, this would involve routing through smoltcp
-    Ok(packet)
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    core.close_connection(connection_id)
 }
 
-/// Process an outbound packet to send to the TUN device
-pub fn process_outbound_packet(packet: Vec<u8>) -> Result<Vec<u8>, VoyageError> {
-    let core = CORE_INSTANCE
-        .get()
-        .ok_or(VoyageError::NotInitialized)?;
+/// Attribute a flow (from `get_connections`) to an app, for per-app usage
+pub fn set_connection_app(connection_id: u64, app_id: String) -> Result<(), VoyageError> {
+    let core = current_core()?;
 
-    let _core = core.lock().map_err(|_| VoyageError::LockError)?;
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
 
-    // For now, just return the packet as-is
-    Ok(packet)
+    core.set_connection_app(connection_id, app_id)
 }
 
-/// Load routing rules from a configuration string
-pub fn load_rules(config: String) -> Result<u32, VoyageError> {
-    let core = CORE_INSTANCE
-        .get()
-        .ok_or(VoyageError::NotInitialized)?;
+/// Attach `value` under `key` to a flow (from `get_connections`), or remove
+/// `key` when `value` is null. Annotations show up in the flow's later
+/// events, in `get_recent_connections` and in the flow log.
+pub fn set_connection_annotation(
+    connection_id: u64,
+    key: String,
+    value: Option<String>,
+) -> Result<(), VoyageError> {
+    let core = current_core()?;
 
-    let mut core = core.lock().map_err(|_| VoyageError::LockError)?;
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
 
-    let count = core.proxy_manager.load_rules(&config)?;
-    log::info!("Loaded {} rules", count);
+    core.annotate_connection(connection_id, &key, value)
+}
 
-    Ok(count as u32)
+/// Source IPs that transferred the most bytes, largest first
+pub fn get_stats_by_source(limit: u32) -> Result<Vec<FfiUsageStats>, VoyageError> {
+    let core = current_core()?;
+
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    let now = Instant::now();
+    Ok(core
+        .conn_manager
+        .usage_by_source()
+        .top(limit as usize)
+        .into_iter()
+        .map(|(ip, usage)| FfiUsageStats::new(ip.to_string(), usage, now))
+        .collect())
 }
 
-/// Evaluate routing decision for a connection
-pub fn evaluate_route(
-    domain: Option<String>,
-    dst_ip: Option<String>,
-    dst_port: u16,
-    src_port: u16,
-) -> Result<FfiRouteAction, VoyageError> {
-    let core = CORE_INSTANCE
-        .get()
-        .ok_or(VoyageError::NotInitialized)?;
+/// Apps that transferred the most bytes, largest first
+pub fn get_stats_by_app(limit: u32) -> Result<Vec<FfiUsageStats>, VoyageError> {
+    let core = current_core()?;
 
-    let mut core = core.lock().map_err(|_| VoyageError::LockError)?;
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
 
-    let ip: Option<IpAddr> = dst_ip
-        .as_ref()
-        .and_then(|s| s.parse().ok());
+    let now = Instant::now();
+    Ok(core
+        .conn_manager
+        .usage_by_app()
+        .top(limit as usize)
+        .into_iter()
+        .map(|(app, usage)| FfiUsageStats::new(app.clone(), usage, now))
+        .collect())
+}
 
-    let action = core
-        .proxy_manager
-        .evaluate_route_ffi(domain.as_deref(), ip, dst_port, src_port);
+/// Domains that transferred the most bytes, largest first
+pub fn get_stats_by_domain(limit: u32) -> Result<Vec<FfiUsageStats>, VoyageError> {
+    let core = current_core()?;
 
-    Ok(action)
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    let now = Instant::now();
+    Ok(core
+        .conn_manager
+        .usage_by_domain()
+        .top(limit as usize)
+        .into_iter()
+        .map(|(domain, usage)| FfiUsageStats::new(domain.clone(), usage, now))
+        .collect())
 }
 
-/// Get current core statistics
-pub fn get_stats() -> Result<CoreStats, VoyageError> {
-    let core = CORE_INSTANCE
-        .get()
-        .ok_or(VoyageError::NotInitialized)?;
+/// Traffic per routing policy (DIRECT, PROXY, REJECT), largest first
+pub fn get_stats_by_policy() -> Result<Vec<FfiUsageStats>, VoyageError> {
+    let core = current_core()?;
 
-    let core = core.lock().map_err(|_| VoyageError::LockError)?;
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
 
-    let conn_stats = core.conn_manager.get_all_connections();
-    let active = conn_stats.len() as u64;
+    let now = Instant::now();
+    let policies = core.conn_manager.usage_by_policy();
+    Ok(policies
+        .top(policies.len())
+        .into_iter()
+        .map(|(action, usage)| FfiUsageStats::new(action.name().to_string(), usage, now))
+        .collect())
+}
 
-    Ok(CoreStats {
-        bytes_sent: core.conn_manager.total_bytes_sent(),
-        bytes_received: core.conn_manager.total_bytes_received(),
-        active_connections: active,
-        total_connections: core.conn_manager.total_connections(),
-    })
+/// Bytes moved per bucket over the last `window` seconds or minutes,
+/// overall and per policy, for speed graphs
+pub fn get_traffic_history(
+    resolution: TrafficResolution,
+    window: u32,
+) -> Result<TrafficHistory, VoyageError> {
+    let core = current_core()?;
+
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    Ok(core
+        .conn_manager
+        .traffic()
+        .history(resolution, window as usize, Instant::now()))
+}
+
+/// Cap all relayed traffic at `bytes_per_second`; `None` or 0 lifts the cap
+pub fn set_global_rate_limit(bytes_per_second: Option<u64>) -> Result<(), VoyageError> {
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    core.shaper.set_global_limit(bytes_per_second);
+    Ok(())
+}
+
+/// Cap the traffic of flows routed by `policy` at `bytes_per_second`;
+/// `None` or 0 lifts the cap
+pub fn set_policy_rate_limit(
+    policy: FfiRouteAction,
+    bytes_per_second: Option<u64>,
+) -> Result<(), VoyageError> {
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    core.shaper.set_policy_limit(policy.into(), bytes_per_second);
+    Ok(())
+}
+
+/// Current state of every bandwidth limit
+pub fn get_shaping_stats() -> Result<Vec<ShapingStats>, VoyageError> {
+    let core = current_core()?;
+
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    Ok(core.shaper.stats())
+}
+
+/// For relays run by the host: account for `bytes` about to be forwarded
+/// on a connection (from `get_connections`) and return how many
+/// milliseconds to wait before forwarding them
+pub fn shaping_delay(connection_id: u64, bytes: u64) -> Result<u64, VoyageError> {
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    Ok(core.shaping_delay(connection_id, bytes)?.as_millis() as u64)
+}
+
+/// Up to `limit` recently closed flows, newest first
+pub fn get_recent_connections(limit: u32) -> Result<Vec<FfiClosedConnection>, VoyageError> {
+    let core = current_core()?;
+
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    Ok(core.recent_connections(limit as usize))
+}
+
+/// Dump the full flow table with internal state as JSON (for bug reports)
+pub fn dump_flows_json() -> Result<String, VoyageError> {
+    let core = current_core()?;
+
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    Ok(core.dump_flows_json())
 }
 
 /// Check if the core is initialized
 pub fn is_initialized() -> bool {
-    CORE_INSTANCE.get().is_some()
+    current_core().is_ok()
 }
 
 /// Add bytes sent (for tracking from Swift side)
 pub fn add_bytes_sent(bytes: u64) -> Result<(), VoyageError> {
-    let core = CORE_INSTANCE
-        .get()
-        .ok_or(VoyageError::NotInitialized)?;
+    let core = current_core()?;
 
-    let mut core = core.lock().map_err(|_| VoyageError::LockError)?;
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
 
-    core.proxy_manager.add_proxy_bytes_sent(bytes);
+    core.proxy_manager().add_proxy_bytes_sent(bytes);
     Ok(())
 }
 
 /// Add bytes received (for tracking from Swift side)
 pub fn add_bytes_received(bytes: u64) -> Result<(), VoyageError> {
-    let core = CORE_INSTANCE
-        .get()
-        .ok_or(VoyageError::NotInitialized)?;
+    let core = current_core()?;
+
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    core.proxy_manager().add_proxy_bytes_received(bytes);
+    Ok(())
+}
+
+/// Load a v2ray-style `geosite.dat` for GEOSITE rules, replacing any
+/// loaded before; returns how many categories it lists
+pub fn load_geosite(path: String) -> Result<u32, VoyageError> {
+    let core = current_core()?;
+
+    // Decode before locking; category files run to megabytes
+    let db = Arc::new(GeoSiteDb::load(&path)?);
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    let count = db.len() as u32;
+    core.set_geosite(Some(db));
+    log::info!("Loaded {} geosite categories", count);
+    Ok(count)
+}
 
-    let mut core = core.lock().map_err(|_| VoyageError::LockError)?;
+/// Load a v2ray-style `geoip.dat` telling routing scripts the country of
+/// each destination, replacing any loaded before; returns how many
+/// countries it lists
+pub fn load_geoip(path: String) -> Result<u32, VoyageError> {
+    let core = current_core()?;
 
-    core.proxy_manager.add_proxy_bytes_received(bytes);
+    // Decode before locking, like geosite files
+    let db = Arc::new(GeoIpDb::load(&path)?);
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    let count = db.len() as u32;
+    core.set_geoip(Some(db));
+    log::info!("Loaded {} geoip countries", count);
+    Ok(count)
+}
+
+/// Unload the geoip database; scripts then see no countries
+pub fn clear_geoip() -> Result<(), VoyageError> {
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    core.set_geoip(None);
+    Ok(())
+}
+
+/// Unload the geosite database; GEOSITE rules then match nothing
+pub fn clear_geosite() -> Result<(), VoyageError> {
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    core.set_geosite(None);
     Ok(())
 }
 
 /// Clear all routing rules
 pub fn clear_rules() -> Result<(), VoyageError> {
-    let core = CORE_INSTANCE
-        .get()
-        .ok_or(VoyageError::NotInitialized)?;
+    let core = current_core()?;
 
-    let mut core = core.lock().map_err(|_| VoyageError::LockError)?;
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
 
-    core.proxy_manager.clear_rules();
+    core.proxy_manager().clear_rules();
+    core.shaper.clear_rule_limits();
     log::info!("Cleared all rules");
     Ok(())
 }
 
+/// Load a candidate ruleset to compare against the active one without
+/// changing routing behavior
+pub fn load_candidate_rules(config: String) -> Result<u32, VoyageError> {
+    let core = current_core()?;
+
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    let count = core.proxy_manager().load_candidate_rules(&config)?;
+    log::info!("Loaded {} candidate rules for comparison", count);
+
+    Ok(count as u32)
+}
+
+/// Stop comparing against the candidate ruleset
+pub fn clear_candidate_rules() -> Result<(), VoyageError> {
+    let core = current_core()?;
+
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    core.proxy_manager().clear_candidate_rules();
+    Ok(())
+}
+
+/// Get the A/B comparison summary between the active and candidate rulesets
+pub fn get_route_comparison() -> Result<FfiRouteComparison, VoyageError> {
+    let core = current_core()?;
+
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    let comparison = core.proxy_manager().route_comparison().clone();
+    Ok(comparison.into())
+}
+
 /// Get the number of loaded rules
 pub fn rule_count() -> Result<u32, VoyageError> {
-    let core = CORE_INSTANCE
-        .get()
-        .ok_or(VoyageError::NotInitialized)?;
+    let core = current_core()?;
 
-    let core = core.lock().map_err(|_| VoyageError::LockError)?;
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
 
-    Ok(core.proxy_manager.rule_count() as u32)
+    let count = core.proxy_manager().rule_count();
+    Ok(count as u32)
 }
 
 /// Enable the proxy
 pub fn enable_proxy() -> Result<(), VoyageError> {
-    let core = CORE_INSTANCE
-        .get()
-        .ok_or(VoyageError::NotInitialized)?;
+    let core = current_core()?;
 
-    let mut core = core.lock().map_err(|_| VoyageError::LockError)?;
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
 
-    core.proxy_manager.enable();
+    core.proxy_manager().enable();
     log::info!("Proxy enabled");
     Ok(())
 }
 
 /// Disable the proxy
 pub fn disable_proxy() -> Result<(), VoyageError> {
-    let core = CORE_INSTANCE
-        .get()
-        .ok_or(VoyageError::NotInitialized)?;
+    let core = current_core()?;
 
-    let mut core = core.lock().map_err(|_| VoyageError::LockError)?;
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
 
-    core.proxy_manager.disable();
+    core.proxy_manager().disable();
     log::info!("Proxy disabled");
     Ok(())
 }
 
+/// Set the preferred fake-IP range (CIDR), returning the range in use
+pub fn set_fake_ip_range(cidr: String) -> Result<String, VoyageError> {
+    let range: Ipv4Range = cidr.parse().map_err(VoyageError::config)?;
+
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    Ok(core.set_fake_ip_range(range).to_string())
+}
+
+/// Get the fake-IP range currently in use
+pub fn get_fake_ip_range() -> Result<String, VoyageError> {
+    let core = current_core()?;
+
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    Ok(core.fake_ip_pool.range().to_string())
+}
+
+/// Report the device's local IPv4 networks (CIDRs), returning the fake-IP
+/// range in use after collision avoidance
+pub fn set_local_networks(cidrs: Vec<String>) -> Result<String, VoyageError> {
+    let networks = cidrs
+        .iter()
+        .map(|c| c.parse::<Ipv4Range>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(VoyageError::config)?;
+
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    Ok(core.set_local_networks(networks).to_string())
+}
+
+/// Report the current network path, e.g. on every `NWPathMonitor` update.
+///
+/// Also report the initial path at startup, which resolves the proxy server
+/// ahead of the first flow. After a move to another network, flows bound to
+/// the old one are reset, the DNS cache is flushed and the proxy server is
+/// resolved and health-checked again in the background. Returns how many
+/// flows were reset.
+pub fn on_network_changed(path: NetworkPath) -> Result<u32, VoyageError> {
+    let core = current_core()?;
+
+    let available = path.available;
+    let reset = core
+        .write()
+        .map_err(|_| VoyageError::LockError)?
+        .network_changed(path)?;
+    match reset {
+        Some(reset) => {
+            if available {
+                core_runtime(&core)?.spawn(network::refresh(core));
+            }
+            Ok(reset as u32)
+        }
+        None => Ok(0),
+    }
+}
+
+/// Report that the device is going to sleep
+pub fn on_sleep() -> Result<(), VoyageError> {
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    core.sleep();
+    Ok(())
+}
+
+/// Report that the device woke up.
+///
+/// Flushes the DNS cache, resets flows that went half-dead during a long
+/// sleep and re-runs the proxy resolution and health checks in the
+/// background. Returns how many flows were reset.
+pub fn on_wake() -> Result<u32, VoyageError> {
+    let core = current_core()?;
+
+    let reset = core.write().map_err(|_| VoyageError::LockError)?.wake();
+    if path_usable(&core)? {
+        core_runtime(&core)?.spawn(network::refresh(core));
+    }
+    Ok(reset as u32)
+}
+
+/// Whether the last reported network path, if any, has a route
+fn path_usable(core: &RwLock<VoyageCore>) -> Result<bool, VoyageError> {
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+    Ok(core.network_path().is_none_or(|path| path.available))
+}
+
+/// Apply the addresses, gateways and MTU negotiated for the tunnel; may be
+/// called again whenever the tunnel settings change
+pub fn set_interface_config(config: FfiInterfaceConfig) -> Result<(), VoyageError> {
+    let config = InterfaceConfig::try_from(config)?;
+
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    core.set_interface_config(config)
+}
+
+/// Get the virtual interface addressing
+pub fn get_interface_config() -> Result<FfiInterfaceConfig, VoyageError> {
+    let core = current_core()?;
+
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    Ok(FfiInterfaceConfig::from(&core.config.interface))
+}
+
+/// Take the warning events queued since the last call
+pub fn drain_events() -> Result<Vec<LocalizedMessage>, VoyageError> {
+    let core = current_core()?;
+
+    let mut core = core.write().map_err(|_| VoyageError::LockError)?;
+
+    Ok(core.drain_events())
+}
+
+/// Run the startup self-test suite.
+///
+/// Works before `init_core`; the resolver probe then uses the default
+/// upstreams instead of the configured ones.
+pub fn run_self_test() -> Vec<SelfTestResult> {
+    let upstreams = current_core()
+        .ok()
+        .and_then(|core| core.read().ok().map(|c| c.dns.config().upstreams.clone()))
+        .unwrap_or_else(|| DnsConfig::default().upstreams);
+
+    selftest::run_all(&upstreams)
+}
+
+/// Get why this thread's last `voyage_inject_inbound_packet` call returned
+/// `INJECT_REJECTED`
+pub fn last_error_message() -> Option<LocalizedMessage> {
+    INJECT_ERROR.with(|last| last.borrow().clone())
+}
+
+/// Get the stable code of an error (see `VoyageError::code`)
+pub fn error_code(error: VoyageError) -> u32 {
+    error.code()
+}
+
+/// Get the message key and parameters of an error, for localization
+pub fn error_message(error: VoyageError) -> LocalizedMessage {
+    error.message()
+}
+
+/// Get every message key with its English default text
+pub fn get_message_catalog() -> Vec<MessageTemplate> {
+    message::catalog()
+}
+
 /// Check if proxy is enabled
 pub fn is_proxy_enabled() -> Result<bool, VoyageError> {
-    let core = CORE_INSTANCE
-        .get()
-        .ok_or(VoyageError::NotInitialized)?;
+    let core = current_core()?;
 
-    let core = core.lock().map_err(|_| VoyageError::LockError)?;
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
 
-    Ok(core.proxy_manager.is_enabled())
+    Ok(core.is_enabled())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::socks5::{HandshakeStage, ReplyCode, Socks5ErrorKind};
+    use serial_test::serial;
 
     // Note: These tests use serial_test because they share global state
     // In a real test environment, you would want to reset the global state
@@ -251,6 +2425,217 @@ mod tests {
         assert_eq!(stats.bytes_received, 0);
         assert_eq!(stats.active_connections, 0);
         assert_eq!(stats.total_connections, 0);
+        assert_eq!(stats.reaped_flows, 0);
+    }
+
+    #[test]
+    #[serial]
+    fn test_reinit_core() {
+        init_core("127.0.0.1".into(), 1080, None, None).unwrap();
+        assert!(matches!(
+            init_core("127.0.0.1".into(), 1080, None, None),
+            Err(VoyageError::AlreadyInitialized)
+        ));
+        let packet = crate::create_tcp_packet([10, 0, 0, 1], [8, 8, 8, 8], 40000, 443, true);
+        process_inbound_packet(packet.clone()).unwrap();
+        assert_eq!(get_stats().unwrap().active_connections, 1);
+        let flows = get_active_connections().unwrap();
+        assert_eq!(flows.len(), 1);
+        assert_eq!(flows[0].dst, "8.8.8.8:443");
+
+        // Stopping the engine drops the flows but keeps the core
+        assert_eq!(get_engine_state(), EngineState::Running);
+        stop_engine().unwrap();
+        assert_eq!(get_engine_state(), EngineState::Stopped);
+        assert!(get_active_connections().unwrap().is_empty());
+        // ... along with its runtime, and packets are refused until restarted
+        assert!(current_core().unwrap().read().unwrap().runtime_handle().is_none());
+        assert!(process_inbound_packet(packet.clone()).is_err());
+        assert!(get_active_connections().unwrap().is_empty());
+        start_engine().unwrap();
+        assert_eq!(get_engine_state(), EngineState::Running);
+
+        shutdown_core();
+        assert!(!is_initialized());
+        assert_eq!(get_engine_state(), EngineState::Stopped);
+        assert!(matches!(get_stats(), Err(VoyageError::NotInitialized)));
+
+        // A new instance starts from scratch
+        init_core("127.0.0.1".into(), 1081, None, None).unwrap();
+        assert_eq!(get_stats().unwrap().active_connections, 0);
+        shutdown_core();
+    }
+
+    fn dns_query_packet(name: &str) -> Vec<u8> {
+        let query = DnsMessage {
+            id: 0x4242,
+            flags: 0x0100,
+            questions: vec![crate::dns::DnsQuestion {
+                name: name.into(),
+                qtype: crate::dns::TYPE_A,
+                qclass: crate::dns::CLASS_IN,
+            }],
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+        };
+        let src = "10.0.0.1:53000".parse().unwrap();
+        let dst = "198.18.0.2:53".parse().unwrap();
+        build_udp_packet(src, dst, &query.encode()).unwrap()
+    }
+
+    fn answered_address(packet: &[u8]) -> crate::dns::RecordData {
+        let parsed = ParsedPacket::parse(packet).unwrap();
+        assert_eq!(parsed.src_addr(), Some("198.18.0.2:53".parse().unwrap()));
+        let reply = DnsMessage::parse(parsed.udp_payload(packet).unwrap()).unwrap();
+        assert_eq!(reply.id, 0x4242);
+        reply.answers[0].data.clone()
+    }
+
+    #[test]
+    #[serial]
+    fn test_dns_query_inside_runtime() {
+        init_core("127.0.0.1".into(), 1080, None, None).unwrap();
+        load_hosts("HOST, router.local, 192.168.1.1".into()).unwrap();
+        let router = crate::dns::RecordData::A(std::net::Ipv4Addr::new(192, 168, 1, 1));
+
+        let answer = process_dns_packet(dns_query_packet("router.local")).unwrap();
+        assert_eq!(answered_address(&answer.unwrap()), router);
+
+        // A host calling from its own async code must not panic the runtime
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let answer = runtime
+            .block_on(async { process_dns_packet(dns_query_packet("router.local")) })
+            .unwrap();
+        assert_eq!(answered_address(&answer.unwrap()), router);
+        shutdown_core();
+    }
+
+    struct ChannelWriter(Mutex<std::sync::mpsc::Sender<Vec<u8>>>);
+
+    impl PacketWriter for ChannelWriter {
+        fn write_packets(&self, packets: Vec<Vec<u8>>) {
+            let sender = self.0.lock().unwrap();
+            for packet in packets {
+                let _ = sender.send(packet);
+            }
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_dns_intercepted_on_packet_path() {
+        init_core("127.0.0.1".into(), 1080, None, None).unwrap();
+        load_hosts("HOST, router.local, 192.168.1.1".into()).unwrap();
+
+        // Without a writer the query is an ordinary UDP flow
+        let passed = process_inbound_packets(vec![dns_query_packet("router.local")]).unwrap();
+        assert_eq!(passed.len(), 1);
+
+        let (sender, answers) = std::sync::mpsc::channel();
+        set_packet_writer(Box::new(ChannelWriter(Mutex::new(sender)))).unwrap();
+        let tcp = crate::create_tcp_packet([10, 0, 0, 1], [8, 8, 8, 8], 40000, 443, true);
+        let passed = process_inbound_packets(vec![dns_query_packet("router.local"), tcp]).unwrap();
+        assert_eq!(passed.len(), 1);
+        assert!(process_inbound_packet(dns_query_packet("router.local")).unwrap().is_empty());
+
+        for _ in 0..2 {
+            let answer = answers.recv_timeout(Duration::from_secs(2)).unwrap();
+            assert_eq!(
+                answered_address(&answer),
+                crate::dns::RecordData::A(std::net::Ipv4Addr::new(192, 168, 1, 1))
+            );
+        }
+        shutdown_core();
+    }
+
+    #[test]
+    #[serial]
+    fn test_run_blocking() {
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        // Blocking calls run on the core's runtime
+        let err = runtime.block_on(run_blocking(|| Ok(7)));
+        assert!(matches!(err, Err(VoyageError::NotInitialized)));
+
+        init_core("127.0.0.1".into(), 1080, None, None).unwrap();
+        let value = runtime.block_on(run_blocking(|| Ok(7))).unwrap();
+        assert_eq!(value, 7);
+        let err = runtime.block_on(run_blocking(|| Err::<(), _>(VoyageError::NotInitialized)));
+        assert!(matches!(err, Err(VoyageError::NotInitialized)));
+        shutdown_core();
+    }
+
+    #[test]
+    fn test_route_comparison_conversion() {
+        let comparison = RouteComparison {
+            evaluated: 2,
+            diverged: 1,
+            samples: vec![RouteDivergence {
+                domain: None,
+                dst_ip: Some("1.2.3.4".parse().unwrap()),
+                dst_port: 443,
+                active: crate::rule::RouteAction::Direct,
+                candidate: crate::rule::RouteAction::Proxy,
+            }],
+        };
+
+        let ffi = FfiRouteComparison::from(comparison);
+        assert_eq!(ffi.diverged, 1);
+        assert_eq!(ffi.samples[0].dst_ip.as_deref(), Some("1.2.3.4"));
+        assert_eq!(ffi.samples[0].candidate, FfiRouteAction::Proxy);
+    }
+
+    #[test]
+    fn test_interface_config_conversion() {
+        let ffi = FfiInterfaceConfig {
+            addresses: vec!["198.18.0.1/16".into(), "fd00::1/64".into()],
+            gateway_v4: Some("198.18.0.254".into()),
+            gateway_v6: None,
+            mtu: 1400,
+            any_ip: true,
+        };
+        let config = InterfaceConfig::try_from(ffi.clone()).unwrap();
+        assert_eq!(config.gateway_v4, Some("198.18.0.254".parse().unwrap()));
+        assert_eq!(FfiInterfaceConfig::from(&config).addresses, ffi.addresses);
+
+        let bad_gateway = FfiInterfaceConfig {
+            gateway_v6: Some("198.18.0.254".into()),
+            ..ffi.clone()
+        };
+        assert!(matches!(
+            InterfaceConfig::try_from(bad_gateway),
+            Err(VoyageError::ConfigError { .. })
+        ));
+        let bad_mtu = FfiInterfaceConfig { mtu: 100, ..ffi };
+        assert!(InterfaceConfig::try_from(bad_mtu).is_err());
+    }
+
+    #[test]
+    fn test_error_details_cross_ffi() {
+        use uniffi::{Lift, Lower};
+
+        let roundtrip = |e: VoyageError| {
+            let buf = <VoyageError as Lower<crate::UniFfiTag>>::lower(e);
+            <VoyageError as Lift<crate::UniFfiTag>>::try_lift(buf).unwrap()
+        };
+
+        let refused = Socks5ErrorKind::Reply {
+            code: ReplyCode::ConnectionRefused,
+        };
+        let err = roundtrip(VoyageError::socks5(HandshakeStage::Connect, refused));
+        assert!(matches!(
+            err,
+            VoyageError::Socks5 { stage: HandshakeStage::Connect, kind } if kind == refused
+        ));
+        assert_eq!(error_code(err), 501);
+
+        let version = ParseErrorKind::UnknownIpVersion { version: 7 };
+        let err = roundtrip(VoyageError::packet(version, 20));
+        assert!(matches!(err, VoyageError::Packet { kind, offset: 20 } if kind == version));
+
+        let err = roundtrip(VoyageError::rule_syntax(2, "bad".into()));
+        assert!(matches!(&err, VoyageError::RuleSyntax { line: 2, detail } if detail == "bad"));
+        assert_eq!(error_message(err).key, "error.rule_syntax");
     }
 
     #[test]
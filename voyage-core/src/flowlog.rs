@@ -0,0 +1,295 @@
+//! Flow Log
+//!
+//! This module writes one JSON line per completed connection: when it
+//! started and ended, its 5-tuple, domain, policy and matched rule, bytes
+//! moved, why it closed and any annotations the host app attached. Lines
+//! go to a size-rotated file or to a host callback, for auditing and for
+//! reproducing routing bugs.
+//!
+//! As with log records, lines are queued on a bounded channel and written
+//! from a dedicated thread, so a slow disk never blocks the datapath; when
+//! the queue is full new lines are dropped and counted.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::error::VoyageError;
+use crate::history::ClosedConnection;
+
+/// Lines queued for writing before new ones are dropped
+pub const FLOW_LOG_QUEUE_SIZE: usize = 1024;
+
+/// One completed connection
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FlowRecord {
+    /// When the flow started, in milliseconds since the Unix epoch
+    pub start_ms: u64,
+    /// When the flow ended, in milliseconds since the Unix epoch
+    pub end_ms: u64,
+    /// "tcp", "udp" or "icmp"
+    pub protocol: &'static str,
+    pub src: String,
+    pub dst: String,
+    /// Sniffed or resolved hostname, if known
+    pub domain: Option<String>,
+    /// "DIRECT", "PROXY" or "REJECT", if the flow was classified
+    pub policy: Option<&'static str>,
+    pub matched_rule: Option<String>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub duration_ms: u64,
+    pub close_reason: String,
+    /// Key/value metadata attached by the host app
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+}
+
+impl FlowRecord {
+    /// Record for a closed flow, with timestamps taken relative to `now`
+    pub fn new(closed: &ClosedConnection, now: Instant) -> Self {
+        let info = &closed.info;
+        let route = info.route.as_ref();
+        Self {
+            start_ms: epoch_ms(info.created_at, now),
+            end_ms: epoch_ms(closed.closed_at, now),
+            protocol: info.key.protocol_name(),
+            src: info.key.src_addr().to_string(),
+            dst: info.key.dst_addr().to_string(),
+            domain: closed.domain.clone(),
+            policy: route.map(|route| route.action.name()),
+            matched_rule: route.and_then(|route| route.matched_rule.clone()),
+            bytes_sent: info.bytes_sent,
+            bytes_received: info.bytes_received,
+            duration_ms: closed.duration().as_millis() as u64,
+            close_reason: format!("{:?}", closed.reason),
+            annotations: info.annotations.clone(),
+        }
+    }
+
+    /// The record as a single JSON line, without the newline
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// Milliseconds since the Unix epoch of `instant`, which lies before `now`
+fn epoch_ms(instant: Instant, now: Instant) -> u64 {
+    let epoch_now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    epoch_now
+        .saturating_sub(now.saturating_duration_since(instant))
+        .as_millis() as u64
+}
+
+/// A file that is rotated once it grows past `max_bytes`: `flows.jsonl`
+/// becomes `flows.jsonl.1`, `.1` becomes `.2` and so on, keeping
+/// `max_files` old files
+#[derive(Debug)]
+pub struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_bytes: u64,
+    max_files: u32,
+}
+
+impl RotatingFile {
+    /// Append to `path`, creating it if needed
+    pub fn open(
+        path: impl AsRef<Path>,
+        max_bytes: u64,
+        max_files: u32,
+    ) -> Result<Self, VoyageError> {
+        let path = path.as_ref().to_path_buf();
+        let file = open_append(&path)?;
+        let size = file.metadata().map(|meta| meta.len()).unwrap_or(0);
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_bytes,
+            max_files,
+        })
+    }
+
+    /// Append `line` and a newline, rotating first if the line would not fit
+    pub fn write_line(&mut self, line: &str) -> Result<(), VoyageError> {
+        let len = line.len() as u64 + 1;
+        if self.max_bytes > 0 && self.size > 0 && self.size + len > self.max_bytes {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line).map_err(|e| VoyageError::io(e.to_string()))?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<(), VoyageError> {
+        let numbered = |n: u32| PathBuf::from(format!("{}.{}", self.path.display(), n));
+        if self.max_files == 0 {
+            let _ = fs::remove_file(&self.path);
+        } else {
+            let _ = fs::remove_file(numbered(self.max_files));
+            for n in (1..self.max_files).rev() {
+                let _ = fs::rename(numbered(n), numbered(n + 1));
+            }
+            fs::rename(&self.path, numbered(1)).map_err(|e| VoyageError::io(e.to_string()))?;
+        }
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> Result<File, VoyageError> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| VoyageError::io(format!("{}: {}", path.display(), e)))
+}
+
+/// Queue feeding the writer thread; dropping it ends the thread once the
+/// queued lines are written
+#[derive(Debug)]
+pub struct FlowLogger {
+    sender: SyncSender<String>,
+    dropped: AtomicU64,
+}
+
+impl FlowLogger {
+    /// Write lines to a rotating file
+    pub fn to_file(
+        path: impl AsRef<Path>,
+        max_bytes: u64,
+        max_files: u32,
+    ) -> Result<Self, VoyageError> {
+        let mut file = RotatingFile::open(path, max_bytes, max_files)?;
+        Self::spawn(move |line| {
+            if let Err(e) = file.write_line(&line) {
+                log::warn!("Flow log write failed: {}", e);
+            }
+        })
+    }
+
+    /// Hand lines to `deliver`
+    pub fn to_callback<F>(deliver: F) -> Result<Self, VoyageError>
+    where
+        F: FnMut(String) + Send + 'static,
+    {
+        Self::spawn(deliver)
+    }
+
+    fn spawn<F>(mut deliver: F) -> Result<Self, VoyageError>
+    where
+        F: FnMut(String) + Send + 'static,
+    {
+        let (sender, lines) = mpsc::sync_channel(FLOW_LOG_QUEUE_SIZE);
+        std::thread::Builder::new()
+            .name("voyage-flowlog".into())
+            .spawn(move || {
+                for line in lines {
+                    deliver(line);
+                }
+            })
+            .map_err(|e| VoyageError::io(e.to_string()))?;
+        Ok(Self {
+            sender,
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Queue a line for `closed`
+    pub fn log(&self, closed: &ClosedConnection) {
+        let line = FlowRecord::new(closed, Instant::now()).to_json();
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(line) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Lines dropped because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use crate::connection::ConnectionInfo;
+    use crate::history::CloseReason;
+    use crate::nat::{NatEntry, NatKey};
+    use crate::proxy::RoutingDecision;
+
+    fn closed() -> ClosedConnection {
+        let key = NatKey::tcp(
+            "10.0.0.1:40000".parse().unwrap(),
+            "1.1.1.1:443".parse().unwrap(),
+        );
+        let mut entry = NatEntry::new(key.src_addr(), key.dst_addr(), 10000);
+        entry.route = Some(RoutingDecision::proxy(443).with_rule("google"));
+        entry.bytes_sent = 120;
+        ClosedConnection {
+            info: ConnectionInfo::from_entry(key, &entry, None),
+            domain: Some("www.google.com".into()),
+            reason: CloseReason::Reset,
+            closed_at: entry.created_at + Duration::from_millis(1500),
+        }
+    }
+
+    #[test]
+    fn test_flow_record_json() {
+        let closed = closed();
+        let record = FlowRecord::new(&closed, closed.closed_at);
+        assert_eq!(record.end_ms - record.start_ms, 1500);
+
+        let json: serde_json::Value = serde_json::from_str(&record.to_json()).unwrap();
+        assert_eq!(json["protocol"], "tcp");
+        assert_eq!(json["dst"], "1.1.1.1:443");
+        assert_eq!(json["domain"], "www.google.com");
+        assert_eq!(json["policy"], "PROXY");
+        assert_eq!(json["matched_rule"], "google");
+        assert_eq!(json["bytes_sent"], 120);
+        assert_eq!(json["duration_ms"], 1500);
+        assert_eq!(json["close_reason"], "Reset");
+    }
+
+    #[test]
+    fn test_rotating_file() {
+        let dir = std::env::temp_dir().join(format!("voyage-flowlog-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("flows.jsonl");
+
+        let mut file = RotatingFile::open(&path, 10, 2).unwrap();
+        for line in ["aaaa", "bbbb", "cccc", "dddd", "eeee"] {
+            file.write_line(line).unwrap();
+        }
+        let read = |p: PathBuf| fs::read_to_string(p).unwrap();
+        assert_eq!(read(path.clone()), "eeee\n");
+        assert_eq!(read(dir.join("flows.jsonl.1")), "cccc\ndddd\n");
+        assert_eq!(read(dir.join("flows.jsonl.2")), "aaaa\nbbbb\n");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_logger_delivers_lines() {
+        let (tx, rx) = mpsc::channel();
+        let logger = FlowLogger::to_callback(move |line| {
+            let _ = tx.send(line);
+        })
+        .unwrap();
+        logger.log(&closed());
+        let line = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(line.contains("\"close_reason\":\"Reset\""));
+        assert_eq!(logger.dropped(), 0);
+    }
+}
@@ -0,0 +1,196 @@
+//! GeoIP Database
+//!
+//! This module loads v2ray-style `geoip.dat` files so the core can tell
+//! which country a destination address belongs to, e.g. for routing
+//! scripts.
+//!
+//! The file is a protobuf `GeoIPList` of country codes and CIDR ranges.
+//! Only two-letter country codes are kept: the files also carry lists
+//! such as `private` or `telegram` that overlap real countries. Ranges
+//! are flattened into one sorted table, so a lookup is a binary search.
+//! IPv4 ranges are stored as IPv4-mapped IPv6 ranges.
+
+use std::net::{IpAddr, Ipv6Addr};
+use std::path::Path;
+
+use crate::error::VoyageError;
+use crate::geosite::{utf8, ProtoReader, ProtoValue};
+
+/// First and last address of a range, in the IPv6 space
+type Range = (u128, u128);
+
+/// A loaded geoip database
+#[derive(Debug, Default)]
+pub struct GeoIpDb {
+    /// Uppercase country codes
+    countries: Vec<String>,
+    /// First and last address of each range and its country, by first address
+    ranges: Vec<(u128, u128, usize)>,
+}
+
+impl GeoIpDb {
+    /// Read a `geoip.dat` file
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, VoyageError> {
+        let data = std::fs::read(path.as_ref())
+            .map_err(|e| VoyageError::io(format!("{}: {}", path.as_ref().display(), e)))?;
+        Self::parse(&data)
+    }
+
+    /// Decode a serialized `GeoIPList`
+    pub fn parse(data: &[u8]) -> Result<Self, VoyageError> {
+        let invalid =
+            |detail: &str| VoyageError::config(format!("Invalid geoip data: {}", detail));
+        let mut db = Self::default();
+        let mut list = ProtoReader::new(data);
+        while let Some((field, value)) = list.next_field().map_err(invalid)? {
+            if let (1, ProtoValue::Bytes(entry)) = (field, value) {
+                let (code, ranges) = parse_entry(entry).map_err(invalid)?;
+                if code.len() != 2 || !code.bytes().all(|b| b.is_ascii_alphabetic()) {
+                    continue;
+                }
+                let index = db.countries.len();
+                db.countries.push(code.to_ascii_uppercase());
+                db.ranges
+                    .extend(ranges.into_iter().map(|(first, last)| (first, last, index)));
+            }
+        }
+        db.ranges.sort_unstable();
+        Ok(db)
+    }
+
+    /// Number of countries
+    pub fn len(&self) -> usize {
+        self.countries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.countries.is_empty()
+    }
+
+    /// Uppercase code of the country `ip` is in, if listed
+    pub fn country(&self, ip: IpAddr) -> Option<&str> {
+        let ip = mapped(ip);
+        let after = self.ranges.partition_point(|(first, _, _)| *first <= ip);
+        let (_, last, index) = self.ranges[..after].last()?;
+        (ip <= *last).then(|| self.countries[*index].as_str())
+    }
+}
+
+/// `ip` as a number in the IPv6 space, IPv4 addresses mapped
+fn mapped(ip: IpAddr) -> u128 {
+    let ip = match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    };
+    u128::from(ip)
+}
+
+/// Decode a `GeoIP` message: its country code and address ranges
+fn parse_entry(data: &[u8]) -> Result<(String, Vec<Range>), &'static str> {
+    let mut code = String::new();
+    let mut ranges = Vec::new();
+    let mut reverse = false;
+    let mut entry = ProtoReader::new(data);
+    while let Some((field, value)) = entry.next_field()? {
+        match (field, value) {
+            (1, ProtoValue::Bytes(bytes)) => code = utf8(bytes)?,
+            (2, ProtoValue::Bytes(cidr)) => ranges.push(parse_cidr(cidr)?),
+            (3, ProtoValue::Varint(flag)) => reverse = flag != 0,
+            _ => {}
+        }
+    }
+    // An inverted list names every address but its ranges; not a country
+    if reverse {
+        code.clear();
+    }
+    Ok((code, ranges))
+}
+
+/// Decode a `CIDR` message into its first and last address
+fn parse_cidr(data: &[u8]) -> Result<Range, &'static str> {
+    let mut ip = None;
+    let mut prefix = 0;
+    let mut cidr = ProtoReader::new(data);
+    while let Some((field, value)) = cidr.next_field()? {
+        match (field, value) {
+            (1, ProtoValue::Bytes(bytes)) => {
+                ip = Some(match bytes.len() {
+                    4 => mapped(IpAddr::from(<[u8; 4]>::try_from(bytes).unwrap())),
+                    16 => u128::from(Ipv6Addr::from(<[u8; 16]>::try_from(bytes).unwrap())),
+                    _ => return Err("address is neither IPv4 nor IPv6"),
+                });
+                if bytes.len() == 4 {
+                    prefix += 96;
+                }
+            }
+            (2, ProtoValue::Varint(bits)) => prefix += bits,
+            _ => {}
+        }
+    }
+    let ip = ip.ok_or("range without an address")?;
+    if prefix > 128 {
+        return Err("prefix longer than the address");
+    }
+    let host_bits = u128::MAX.checked_shr(prefix as u32).unwrap_or(0);
+    Ok((ip & !host_bits, ip | host_bits))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(mut value: u64, out: &mut Vec<u8>) {
+        while value >= 0x80 {
+            out.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    fn bytes_field(field: u64, bytes: &[u8], out: &mut Vec<u8>) {
+        varint(field << 3 | 2, out);
+        varint(bytes.len() as u64, out);
+        out.extend_from_slice(bytes);
+    }
+
+    fn entry(code: &str, cidrs: &[(&str, u64)]) -> Vec<u8> {
+        let mut out = Vec::new();
+        bytes_field(1, code.as_bytes(), &mut out);
+        for (ip, prefix) in cidrs {
+            let ip: IpAddr = ip.parse().unwrap();
+            let bytes = match ip {
+                IpAddr::V4(ip) => ip.octets().to_vec(),
+                IpAddr::V6(ip) => ip.octets().to_vec(),
+            };
+            let mut cidr = Vec::new();
+            bytes_field(1, &bytes, &mut cidr);
+            varint(2 << 3, &mut cidr);
+            varint(*prefix, &mut cidr);
+            bytes_field(2, &cidr, &mut out);
+        }
+        out
+    }
+
+    #[test]
+    fn test_parse_and_lookup() {
+        let mut data = Vec::new();
+        bytes_field(1, &entry("private", &[("10.0.0.0", 8)]), &mut data);
+        bytes_field(1, &entry("jp", &[("1.0.16.0", 20), ("2001:200::", 23)]), &mut data);
+        bytes_field(1, &entry("US", &[("3.0.0.0", 8), ("10.1.0.0", 16)]), &mut data);
+        let db = GeoIpDb::parse(&data).unwrap();
+        assert_eq!(db.len(), 2);
+
+        let country = |ip: &str| db.country(ip.parse().unwrap());
+        assert_eq!(country("1.0.16.1"), Some("JP"));
+        assert_eq!(country("1.0.31.255"), Some("JP"));
+        assert_eq!(country("1.0.32.0"), None);
+        assert_eq!(country("2001:201::1"), Some("JP"));
+        assert_eq!(country("3.255.0.1"), Some("US"));
+        // Lists that are not countries are left out
+        assert_eq!(country("10.1.2.3"), Some("US"));
+        assert_eq!(country("10.2.0.1"), None);
+        assert_eq!(country("0.0.0.0"), None);
+
+        assert!(GeoIpDb::parse(&[0x0a, 0x05, 0x0a]).is_err());
+    }
+}
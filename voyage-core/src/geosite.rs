@@ -0,0 +1,514 @@
+//! GeoSite Database
+//!
+//! This module loads v2ray-style `geosite.dat` files (the domain-list
+//! community format) so rules can name a whole category of domains:
+//! `GEOSITE, category-ads-all, REJECT`. A category may be narrowed to the
+//! domains carrying attributes, as in `GEOSITE, google@cn, DIRECT`.
+//!
+//! The file is a protobuf `GeoSiteList`; only the fields needed for
+//! matching are decoded. A category is compiled into hash sets of full
+//! names and domain suffixes plus a keyword list, so matching costs one
+//! lookup per label of the host. Regex entries are skipped, since the
+//! core carries no regex engine.
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::error::VoyageError;
+
+/// How a listed domain matches a host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GeoSiteKind {
+    /// The value appears anywhere in the host
+    Keyword,
+    /// A regular expression (not supported, never matches)
+    Regex,
+    /// The host is the value or one of its subdomains
+    Domain,
+    /// The host is exactly the value
+    Full,
+}
+
+impl GeoSiteKind {
+    fn from_proto(value: u64) -> Option<Self> {
+        match value {
+            0 => Some(GeoSiteKind::Keyword),
+            1 => Some(GeoSiteKind::Regex),
+            2 => Some(GeoSiteKind::Domain),
+            3 => Some(GeoSiteKind::Full),
+            _ => None,
+        }
+    }
+}
+
+/// A domain entry of a category
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GeoSiteDomain {
+    pub kind: GeoSiteKind,
+    /// Lowercase domain, keyword or pattern
+    pub value: String,
+    /// Lowercase attribute names, e.g. `cn` or `ads`
+    pub attributes: Vec<String>,
+}
+
+/// Compiled domains of one category, possibly narrowed by attributes
+#[derive(Debug, Default)]
+pub struct GeoSiteMatcher {
+    full: HashSet<String>,
+    suffixes: HashSet<String>,
+    keywords: Vec<String>,
+    /// Regex entries left out
+    skipped: usize,
+}
+
+impl GeoSiteMatcher {
+    fn compile<'a>(domains: impl IntoIterator<Item = &'a GeoSiteDomain>) -> Self {
+        let mut matcher = Self::default();
+        for domain in domains {
+            let value = domain.value.clone();
+            match domain.kind {
+                GeoSiteKind::Full => {
+                    matcher.full.insert(value);
+                }
+                GeoSiteKind::Domain => {
+                    matcher.suffixes.insert(value);
+                }
+                GeoSiteKind::Keyword => matcher.keywords.push(value),
+                GeoSiteKind::Regex => matcher.skipped += 1,
+            }
+        }
+        matcher
+    }
+
+    /// Whether `host` is in the category
+    pub fn matches(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        if self.full.contains(&host) {
+            return true;
+        }
+        let mut rest = host.as_str();
+        loop {
+            if self.suffixes.contains(rest) {
+                return true;
+            }
+            match rest.split_once('.') {
+                Some((_, parent)) => rest = parent,
+                None => break,
+            }
+        }
+        self.keywords
+            .iter()
+            .any(|keyword| host.contains(keyword.as_str()))
+    }
+
+    /// Domains the matcher checks
+    pub fn len(&self) -> usize {
+        self.full.len() + self.suffixes.len() + self.keywords.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Regex entries that were left out
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+}
+
+/// A loaded geosite database: categories by lowercase name
+#[derive(Debug, Default)]
+pub struct GeoSiteDb {
+    categories: HashMap<String, Vec<GeoSiteDomain>>,
+}
+
+impl GeoSiteDb {
+    /// Read a `geosite.dat` file
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, VoyageError> {
+        let data = std::fs::read(path.as_ref())
+            .map_err(|e| VoyageError::io(format!("{}: {}", path.as_ref().display(), e)))?;
+        Self::parse(&data)
+    }
+
+    /// Decode a serialized `GeoSiteList`
+    pub fn parse(data: &[u8]) -> Result<Self, VoyageError> {
+        let invalid =
+            |detail: &str| VoyageError::config(format!("Invalid geosite data: {}", detail));
+        let mut categories = HashMap::new();
+        let mut list = ProtoReader::new(data);
+        while let Some((field, value)) = list.next_field().map_err(invalid)? {
+            if let (1, ProtoValue::Bytes(site)) = (field, value) {
+                let (name, domains) = parse_site(site).map_err(invalid)?;
+                categories
+                    .entry(name.to_ascii_lowercase())
+                    .or_insert_with(Vec::new)
+                    .extend(domains);
+            }
+        }
+        Ok(Self { categories })
+    }
+
+    /// Number of categories
+    pub fn len(&self) -> usize {
+        self.categories.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.categories.is_empty()
+    }
+
+    /// Whether the database lists `category`
+    pub fn contains(&self, category: &str) -> bool {
+        self.categories.contains_key(&category.to_ascii_lowercase())
+    }
+
+    /// Compile the domains named by `spec`: a category, optionally
+    /// followed by `@attribute` filters that a domain must all carry.
+    /// `None` if the category is not listed.
+    pub fn matcher(&self, spec: &str) -> Option<GeoSiteMatcher> {
+        let spec = spec.to_ascii_lowercase();
+        let mut parts = spec.split('@');
+        let domains = self.categories.get(parts.next()?.trim())?;
+        let required: Vec<&str> = parts.map(str::trim).filter(|a| !a.is_empty()).collect();
+        let matcher = GeoSiteMatcher::compile(domains.iter().filter(|domain| {
+            required
+                .iter()
+                .all(|attribute| domain.attributes.iter().any(|a| a == attribute))
+        }));
+        if matcher.skipped() > 0 {
+            log::debug!(
+                "GEOSITE {}: skipped {} regex entries",
+                spec,
+                matcher.skipped()
+            );
+        }
+        Some(matcher)
+    }
+}
+
+/// A GEOSITE rule's category and, once resolved against a database, its
+/// compiled domains
+#[derive(Debug, Clone)]
+pub struct GeoSite {
+    /// Category as written, with any `@attribute` filters
+    pub spec: String,
+    matcher: Option<Arc<GeoSiteMatcher>>,
+}
+
+impl GeoSite {
+    /// An unresolved category; it matches nothing until resolved
+    pub fn new(spec: impl Into<String>) -> Self {
+        Self {
+            spec: spec.into(),
+            matcher: None,
+        }
+    }
+
+    /// Compile the category from `db`, or clear it without one. Returns
+    /// `false` if `db` does not list the category.
+    pub fn resolve(&mut self, db: Option<&GeoSiteDb>) -> bool {
+        self.matcher = db.and_then(|db| db.matcher(&self.spec)).map(Arc::new);
+        db.is_none() || self.matcher.is_some()
+    }
+
+    /// Whether the category was found in a database
+    pub fn is_resolved(&self) -> bool {
+        self.matcher.is_some()
+    }
+
+    /// Whether `host` is in the category
+    pub fn matches(&self, host: &str) -> bool {
+        self.matcher
+            .as_ref()
+            .is_some_and(|matcher| matcher.matches(host))
+    }
+}
+
+impl PartialEq for GeoSite {
+    fn eq(&self, other: &Self) -> bool {
+        self.spec.eq_ignore_ascii_case(&other.spec)
+    }
+}
+
+impl Eq for GeoSite {}
+
+/// Decode a `GeoSite` message: its country code and domains
+fn parse_site(data: &[u8]) -> Result<(String, Vec<GeoSiteDomain>), &'static str> {
+    let mut name = String::new();
+    let mut domains = Vec::new();
+    let mut site = ProtoReader::new(data);
+    while let Some((field, value)) = site.next_field()? {
+        match (field, value) {
+            (1, ProtoValue::Bytes(code)) => name = utf8(code)?,
+            (2, ProtoValue::Bytes(domain)) => {
+                if let Some(domain) = parse_domain(domain)? {
+                    domains.push(domain);
+                }
+            }
+            _ => {}
+        }
+    }
+    if name.is_empty() {
+        return Err("category without a name");
+    }
+    Ok((name, domains))
+}
+
+/// Decode a `Domain` message; `None` for an unknown type
+fn parse_domain(data: &[u8]) -> Result<Option<GeoSiteDomain>, &'static str> {
+    // Type defaults to Plain (keyword) when omitted
+    let mut kind = Some(GeoSiteKind::Keyword);
+    let mut value = String::new();
+    let mut attributes = Vec::new();
+    let mut domain = ProtoReader::new(data);
+    while let Some((field, field_value)) = domain.next_field()? {
+        match (field, field_value) {
+            (1, ProtoValue::Varint(number)) => kind = GeoSiteKind::from_proto(number),
+            (2, ProtoValue::Bytes(bytes)) => value = utf8(bytes)?.to_ascii_lowercase(),
+            (3, ProtoValue::Bytes(attribute)) => {
+                let mut attribute = ProtoReader::new(attribute);
+                while let Some((field, field_value)) = attribute.next_field()? {
+                    if let (1, ProtoValue::Bytes(key)) = (field, field_value) {
+                        attributes.push(utf8(key)?.to_ascii_lowercase());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(kind.map(|kind| GeoSiteDomain {
+        kind,
+        value,
+        attributes,
+    }))
+}
+
+pub(crate) fn utf8(bytes: &[u8]) -> Result<String, &'static str> {
+    String::from_utf8(bytes.to_vec()).map_err(|_| "string is not UTF-8")
+}
+
+/// A decoded protobuf field value
+pub(crate) enum ProtoValue<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+    /// A fixed-width value, ignored by every message read here
+    Fixed,
+}
+
+/// Reads the fields of one protobuf message
+pub(crate) struct ProtoReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ProtoReader<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn varint(&mut self) -> Result<u64, &'static str> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *self.data.get(self.pos).ok_or("truncated varint")?;
+            self.pos += 1;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("varint too long")
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], &'static str> {
+        let end = self.pos.checked_add(len).ok_or("field too long")?;
+        let bytes = self.data.get(self.pos..end).ok_or("truncated field")?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    /// The next field number and value, or `None` at the end
+    pub(crate) fn next_field(&mut self) -> Result<Option<(u64, ProtoValue<'a>)>, &'static str> {
+        if self.pos >= self.data.len() {
+            return Ok(None);
+        }
+        let key = self.varint()?;
+        let value = match key & 0x7 {
+            0 => ProtoValue::Varint(self.varint()?),
+            1 => {
+                self.take(8)?;
+                ProtoValue::Fixed
+            }
+            2 => {
+                let len = usize::try_from(self.varint()?).map_err(|_| "field too long")?;
+                ProtoValue::Bytes(self.take(len)?)
+            }
+            5 => {
+                self.take(4)?;
+                ProtoValue::Fixed
+            }
+            _ => return Err("unsupported wire type"),
+        };
+        Ok(Some((key >> 3, value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rule::{MatchContext, RouteAction, Rule, RuleEngine, RuleType};
+
+    fn varint(mut value: u64, out: &mut Vec<u8>) {
+        while value >= 0x80 {
+            out.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        out.push(value as u8);
+    }
+
+    fn bytes_field(field: u64, bytes: &[u8], out: &mut Vec<u8>) {
+        varint(field << 3 | 2, out);
+        varint(bytes.len() as u64, out);
+        out.extend_from_slice(bytes);
+    }
+
+    fn domain(kind: u64, value: &str, attributes: &[&str]) -> Vec<u8> {
+        let mut out = Vec::new();
+        varint(1 << 3, &mut out);
+        varint(kind, &mut out);
+        bytes_field(2, value.as_bytes(), &mut out);
+        for attribute in attributes {
+            let mut encoded = Vec::new();
+            bytes_field(1, attribute.as_bytes(), &mut encoded);
+            // bool_value = true
+            encoded.extend_from_slice(&[2 << 3, 1]);
+            bytes_field(3, &encoded, &mut out);
+        }
+        out
+    }
+
+    fn site(name: &str, domains: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = Vec::new();
+        bytes_field(1, name.as_bytes(), &mut out);
+        for domain in domains {
+            bytes_field(2, domain, &mut out);
+        }
+        out
+    }
+
+    fn sample() -> Vec<u8> {
+        let mut out = Vec::new();
+        let ads = site(
+            "CATEGORY-ADS-ALL",
+            &[
+                domain(2, "doubleclick.net", &[]),
+                domain(3, "ads.example.com", &[]),
+                domain(0, "adservice", &[]),
+                domain(1, "^ad[0-9]+\\.", &[]),
+            ],
+        );
+        let google = site(
+            "GOOGLE",
+            &[
+                domain(2, "google.com", &[]),
+                domain(2, "google.cn", &["cn"]),
+            ],
+        );
+        bytes_field(1, &ads, &mut out);
+        bytes_field(1, &google, &mut out);
+        out
+    }
+
+    #[test]
+    fn test_parse_and_match() {
+        let db = GeoSiteDb::parse(&sample()).unwrap();
+        assert_eq!(db.len(), 2);
+        assert!(db.contains("category-ads-all"));
+
+        let ads = db.matcher("category-ads-all").unwrap();
+        assert_eq!(ads.len(), 3);
+        assert_eq!(ads.skipped(), 1);
+        assert!(ads.matches("doubleclick.net"));
+        assert!(ads.matches("stats.g.DoubleClick.net."));
+        assert!(!ads.matches("notdoubleclick.net"));
+        assert!(ads.matches("ads.example.com"));
+        assert!(!ads.matches("www.ads.example.com"));
+        assert!(ads.matches("pagead.adservice.io"));
+        assert!(!ads.matches("example.com"));
+    }
+
+    #[test]
+    fn test_attribute_filter() {
+        let db = GeoSiteDb::parse(&sample()).unwrap();
+        let all = db.matcher("google").unwrap();
+        assert!(all.matches("www.google.com") && all.matches("google.cn"));
+
+        let cn = db.matcher("Google@CN").unwrap();
+        assert!(cn.matches("www.google.cn"));
+        assert!(!cn.matches("www.google.com"));
+
+        assert!(db.matcher("google@ads").unwrap().is_empty());
+        assert!(db.matcher("netflix").is_none());
+    }
+
+    #[test]
+    fn test_resolve() {
+        let db = GeoSiteDb::parse(&sample()).unwrap();
+        let mut site = GeoSite::new("google");
+        assert!(!site.matches("google.com"));
+        assert!(site.resolve(Some(&db)));
+        assert!(site.matches("google.com"));
+
+        let mut missing = GeoSite::new("netflix");
+        assert!(!missing.resolve(Some(&db)));
+        assert!(!missing.is_resolved());
+        assert!(site.resolve(None));
+        assert!(!site.matches("google.com"));
+    }
+
+    #[test]
+    fn test_rule_engine_geosite() {
+        let mut engine = RuleEngine::new();
+        engine
+            .load_from_config("GEOSITE, category-ads-all, REJECT\nGEOSITE, netflix, PROXY")
+            .unwrap();
+        // Nothing matches before a database is loaded
+        assert_eq!(
+            engine.evaluate(&MatchContext::new(Some("doubleclick.net"), None, 443)),
+            RouteAction::Direct
+        );
+
+        let db = Arc::new(GeoSiteDb::parse(&sample()).unwrap());
+        assert_eq!(engine.set_geosite(Some(db)), vec!["netflix"]);
+        assert_eq!(
+            engine.evaluate(&MatchContext::new(Some("doubleclick.net"), None, 443)),
+            RouteAction::Reject
+        );
+        assert_eq!(
+            engine.evaluate(&MatchContext::new(Some("example.com"), None, 443)),
+            RouteAction::Direct
+        );
+
+        // Rules added later resolve against the loaded database
+        engine.add_rule(Rule::new(
+            RuleType::GeoSite(GeoSite::new("google@cn")),
+            RouteAction::Proxy,
+        ));
+        assert_eq!(
+            engine.evaluate(&MatchContext::new(Some("www.google.cn"), None, 443)),
+            RouteAction::Proxy
+        );
+        assert_eq!(
+            engine.evaluate(&MatchContext::new(Some("www.google.com"), None, 443)),
+            RouteAction::Direct
+        );
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        let data = sample();
+        assert!(GeoSiteDb::parse(&data[..data.len() - 3]).is_err());
+        assert!(GeoSiteDb::parse(&[0x0a, 0xff]).is_err());
+        assert!(GeoSiteDb::parse(&[]).unwrap().is_empty());
+    }
+}
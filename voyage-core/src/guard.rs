@@ -0,0 +1,188 @@
+//! Connection Rate Guard
+//!
+//! This module protects the NAT table and the smoltcp socket set from an
+//! app that opens connections in a tight loop, such as a SYN flood from a
+//! runaway process. New flows are counted per source IP and in total
+//! against per-second budgets; flows over a budget are dropped before they
+//! get a NAT entry, and a source over its budget is blocked for a while.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::config::ConnectionRateLimits;
+use crate::shaping::TokenBucket;
+
+/// Time after which a source's bucket is full again and can be forgotten
+const SOURCE_IDLE: Duration = Duration::from_secs(1);
+
+/// Per-second budgets for new connections and the sources blocked by them
+#[derive(Debug, Default)]
+pub struct ConnectionGuard {
+    limits: ConnectionRateLimits,
+    /// Budget shared by all sources, created on first use
+    total: Option<TokenBucket>,
+    /// Budget of each source and when it was last drawn from
+    sources: HashMap<IpAddr, (TokenBucket, Instant)>,
+    /// Blocked sources and when their block ends
+    blocked: HashMap<IpAddr, Instant>,
+    /// New connections dropped by a budget or a block
+    dropped: u64,
+    /// Sources blocked for going over their budget
+    blocks: u64,
+}
+
+impl ConnectionGuard {
+    /// Create a guard enforcing `limits`
+    pub fn new(limits: ConnectionRateLimits) -> Self {
+        let mut guard = Self::default();
+        guard.set_limits(limits);
+        guard
+    }
+
+    /// The enforced limits
+    pub fn limits(&self) -> &ConnectionRateLimits {
+        &self.limits
+    }
+
+    /// Change the limits; budgets start full and current blocks are lifted
+    pub fn set_limits(&mut self, limits: ConnectionRateLimits) {
+        self.limits = limits;
+        self.total = None;
+        self.sources.clear();
+        self.blocked.clear();
+    }
+
+    /// Check whether a new connection from `src` may be created, drawing
+    /// from the budgets if so
+    pub fn allow(&mut self, src: IpAddr, now: Instant) -> bool {
+        if let Some(&until) = self.blocked.get(&src) {
+            if now < until {
+                self.dropped += 1;
+                return false;
+            }
+            self.blocked.remove(&src);
+        }
+
+        if self.limits.per_source > 0 {
+            let rate = self.limits.per_source.into();
+            let (bucket, used) = self
+                .sources
+                .entry(src)
+                .or_insert_with(|| (TokenBucket::new(rate, now), now));
+            *used = now;
+            if !bucket.try_take(1, now) {
+                self.dropped += 1;
+                if self.limits.block_secs > 0 {
+                    log::warn!(
+                        "Blocking new connections from {} for {}s: over {} per second",
+                        src,
+                        self.limits.block_secs,
+                        self.limits.per_source
+                    );
+                    self.sources.remove(&src);
+                    self.blocked.insert(src, now + self.limits.block_duration());
+                    self.blocks += 1;
+                }
+                return false;
+            }
+        }
+
+        if self.limits.total > 0 {
+            let rate = self.limits.total.into();
+            let total = self
+                .total
+                .get_or_insert_with(|| TokenBucket::new(rate, now));
+            if !total.try_take(1, now) {
+                self.dropped += 1;
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Forget sources that stopped connecting and blocks that ended
+    pub fn prune(&mut self, now: Instant) {
+        self.sources
+            .retain(|_, (_, used)| now.saturating_duration_since(*used) < SOURCE_IDLE);
+        self.blocked.retain(|_, until| now < *until);
+    }
+
+    /// Sources currently blocked
+    pub fn blocked_sources(&self, now: Instant) -> Vec<IpAddr> {
+        let mut sources: Vec<IpAddr> = self
+            .blocked
+            .iter()
+            .filter(|(_, until)| now < **until)
+            .map(|(src, _)| *src)
+            .collect();
+        sources.sort();
+        sources
+    }
+
+    /// Total new connections dropped
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// Total times a source was blocked
+    pub fn blocks(&self) -> u64 {
+        self.blocks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_per_source_rate() {
+        let now = Instant::now();
+        let mut guard = ConnectionGuard::new(ConnectionRateLimits {
+            per_source: 2,
+            total: 0,
+            block_secs: 5,
+        });
+        let flood: IpAddr = "10.0.0.2".parse().unwrap();
+        let other: IpAddr = "10.0.0.3".parse().unwrap();
+
+        assert!(guard.allow(flood, now));
+        assert!(guard.allow(flood, now));
+        assert!(!guard.allow(flood, now));
+        assert_eq!(guard.blocked_sources(now), [flood]);
+
+        // Blocked sources stay dropped after their budget refilled, others
+        // are not affected
+        let later = now + Duration::from_secs(2);
+        assert!(!guard.allow(flood, later));
+        assert!(guard.allow(other, later));
+        assert_eq!((guard.dropped(), guard.blocks()), (2, 1));
+
+        let unblocked = now + Duration::from_secs(6);
+        guard.prune(unblocked);
+        assert!(guard.blocked_sources(unblocked).is_empty());
+        assert!(guard.allow(flood, unblocked));
+    }
+
+    #[test]
+    fn test_total_rate() {
+        let now = Instant::now();
+        let mut guard = ConnectionGuard::new(ConnectionRateLimits {
+            per_source: 0,
+            total: 1,
+            block_secs: 5,
+        });
+        let a: IpAddr = "10.0.0.2".parse().unwrap();
+        let b: IpAddr = "10.0.0.3".parse().unwrap();
+
+        assert!(guard.allow(a, now));
+        assert!(!guard.allow(b, now));
+        // The shared budget drops without blocking anyone
+        assert!(guard.blocked_sources(now).is_empty());
+        assert!(guard.allow(b, now + Duration::from_secs(1)));
+
+        // No limits, nothing dropped
+        guard.set_limits(ConnectionRateLimits::default());
+        assert!((0..100).all(|_| guard.allow(a, now)));
+    }
+}
@@ -0,0 +1,143 @@
+//! Connection History
+//!
+//! This module keeps a bounded ring buffer of recently closed flows, so the
+//! app can show recent activity after the NAT entries are gone.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::connection::ConnectionInfo;
+
+/// Why a flow ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The connection finished normally
+    Closed,
+    /// The app sent a FIN
+    AppClosed,
+    /// The connection was reset by the app or the remote end
+    Reset,
+    /// A REJECT rule matched the flow
+    Rejected,
+    /// The proxy handshake or CONNECT request failed
+    ProxyFailed,
+    /// No traffic within the idle timeout
+    Expired,
+    /// Dropped to make room in a full NAT table
+    Evicted,
+    /// The relay task serving the flow died
+    RelayFailed,
+    /// Killed on request from the host app
+    Aborted,
+    /// No traffic within the relay idle timeout; both legs were closed
+    Idle,
+}
+
+/// A flow that has ended
+#[derive(Debug, Clone)]
+pub struct ClosedConnection {
+    /// Final snapshot of the flow
+    pub info: ConnectionInfo,
+    /// Sniffed or resolved hostname, if known
+    pub domain: Option<String>,
+    /// Why the flow ended
+    pub reason: CloseReason,
+    /// When the flow ended
+    pub closed_at: Instant,
+}
+
+impl ClosedConnection {
+    /// How long the flow lived
+    pub fn duration(&self) -> Duration {
+        self.closed_at.saturating_duration_since(self.info.created_at)
+    }
+}
+
+/// Most recently closed flows, oldest dropped first
+#[derive(Debug, Clone)]
+pub struct ConnectionHistory {
+    records: VecDeque<ClosedConnection>,
+    capacity: usize,
+}
+
+impl ConnectionHistory {
+    /// Create a history keeping at most `capacity` records
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            records: VecDeque::with_capacity(capacity.min(1024)),
+            capacity,
+        }
+    }
+
+    /// Number of records kept
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Check if no flow has been recorded
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Forget every record
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+
+    /// Record a closed flow, dropping the oldest record if full
+    pub fn push(&mut self, record: ClosedConnection) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.records.len() >= self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    /// Up to `limit` records, newest first
+    pub fn recent(&self, limit: usize) -> impl Iterator<Item = &ClosedConnection> {
+        self.records.iter().rev().take(limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nat::{NatEntry, NatKey};
+
+    fn record(port: u16) -> ClosedConnection {
+        let key = NatKey::tcp(
+            format!("10.0.0.1:{}", port).parse().unwrap(),
+            "1.1.1.1:443".parse().unwrap(),
+        );
+        let entry = NatEntry::new(key.src_addr(), key.dst_addr(), port);
+        ClosedConnection {
+            info: ConnectionInfo::from_entry(key, &entry, None),
+            domain: None,
+            reason: CloseReason::Closed,
+            closed_at: entry.created_at + Duration::from_secs(3),
+        }
+    }
+
+    #[test]
+    fn test_history_keeps_newest() {
+        let mut history = ConnectionHistory::new(3);
+        for port in 1000..1005 {
+            history.push(record(port));
+        }
+        assert_eq!(history.len(), 3);
+
+        let ports: Vec<u16> = history.recent(2).map(|r| r.info.local_port).collect();
+        assert_eq!(ports, [1004, 1003]);
+        assert_eq!(history.recent(10).count(), 3);
+        assert_eq!(history.recent(1).next().unwrap().duration(), Duration::from_secs(3));
+    }
+
+    #[test]
+    fn test_zero_capacity_keeps_nothing() {
+        let mut history = ConnectionHistory::new(0);
+        history.push(record(1000));
+        assert!(history.is_empty());
+    }
+}
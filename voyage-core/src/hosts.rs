@@ -0,0 +1,174 @@
+//! Static Hosts
+//!
+//! This module holds hosts-file style name to address mappings that the DNS
+//! forwarder answers directly, so internal names resolve without an external
+//! server. Entries use the rule syntax (`HOST, router.local, 192.168.1.1`);
+//! a leading `*.` matches any subdomain of the name.
+
+use std::net::IpAddr;
+
+/// TTL of answers synthesized from host entries
+pub const HOST_TTL: u32 = 60;
+
+/// A static name to address mapping
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HostEntry {
+    /// Lowercased name, or `*.suffix` for a wildcard
+    pub pattern: String,
+    /// Address answered for the name
+    pub addr: IpAddr,
+}
+
+impl HostEntry {
+    /// Create an entry for `pattern` (case-insensitive)
+    pub fn new(pattern: &str, addr: IpAddr) -> Self {
+        Self {
+            pattern: normalize(pattern),
+            addr,
+        }
+    }
+
+    /// Whether the entry is a `*.suffix` wildcard
+    pub fn is_wildcard(&self) -> bool {
+        self.pattern.starts_with("*.")
+    }
+
+    /// Parse a `HOST, name, address` line
+    pub fn parse_line(line: &str) -> Result<Self, String> {
+        let parts: Vec<&str> = line.split(',').map(|s| s.trim()).collect();
+        if parts.len() != 3 || !parts[0].eq_ignore_ascii_case("HOST") {
+            return Err(format!("Invalid host format: {}", line));
+        }
+        if parts[1].is_empty() {
+            return Err("HOST entry requires a name".into());
+        }
+        let addr: IpAddr = parts[2]
+            .parse()
+            .map_err(|e| format!("Invalid IP: {}", e))?;
+        Ok(Self::new(parts[1], addr))
+    }
+
+    /// Length of the matched name part when `name` matches, used to prefer
+    /// exact entries and then the most specific wildcard
+    fn match_len(&self, name: &str) -> Option<usize> {
+        match self.pattern.strip_prefix("*.") {
+            Some(suffix) => {
+                let matched = name.len() > suffix.len()
+                    && name.ends_with(suffix)
+                    && name.as_bytes()[name.len() - suffix.len() - 1] == b'.';
+                matched.then_some(suffix.len())
+            }
+            None => (self.pattern == name).then_some(usize::MAX),
+        }
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Static host table consulted before any upstream
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HostTable {
+    entries: Vec<HostEntry>,
+}
+
+impl HostTable {
+    /// Create a table from entries
+    pub fn new(entries: Vec<HostEntry>) -> Self {
+        Self { entries }
+    }
+
+    /// Number of entries
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Check if there are no entries
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Get all entries
+    pub fn entries(&self) -> &[HostEntry] {
+        &self.entries
+    }
+
+    /// Parse `HOST` lines, skipping blank lines and comments
+    pub fn parse_config(config: &str) -> Result<Vec<HostEntry>, String> {
+        config
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#') && !line.starts_with("//"))
+            .map(HostEntry::parse_line)
+            .collect()
+    }
+
+    /// Addresses for `name`: every entry of the best match, exact names
+    /// first, then the longest wildcard suffix
+    pub fn lookup(&self, name: &str) -> Vec<IpAddr> {
+        let name = normalize(name);
+        let best = self
+            .entries
+            .iter()
+            .filter_map(|entry| entry.match_len(&name))
+            .max();
+        match best {
+            Some(len) => self
+                .entries
+                .iter()
+                .filter(|entry| entry.match_len(&name) == Some(len))
+                .map(|entry| entry.addr)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let entries = HostTable::parse_config(
+            "# internal names\n\
+             HOST, Router.Local, 192.168.1.1\n\
+             \n\
+             host, *.corp.example, fd00::1",
+        )
+        .unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].pattern, "router.local");
+        assert!(!entries[0].is_wildcard());
+        assert!(entries[1].is_wildcard());
+        assert_eq!(entries[1].addr, "fd00::1".parse::<IpAddr>().unwrap());
+
+        assert!(HostTable::parse_config("HOST, nas.local").is_err());
+        assert!(HostTable::parse_config("HOST, nas.local, not-an-ip").is_err());
+        assert!(HostTable::parse_config("DOMAIN, nas.local, 10.0.0.1").is_err());
+    }
+
+    #[test]
+    fn test_lookup_prefers_exact_then_longest_wildcard() {
+        let table = HostTable::new(
+            HostTable::parse_config(
+                "HOST, *.example.com, 10.0.0.1\n\
+                 HOST, *.api.example.com, 10.0.0.2\n\
+                 HOST, api.example.com, 10.0.0.3\n\
+                 HOST, api.example.com, 10.0.0.4",
+            )
+            .unwrap(),
+        );
+        let ips = |name: &str| -> Vec<String> {
+            table.lookup(name).iter().map(|ip| ip.to_string()).collect()
+        };
+
+        assert_eq!(ips("API.example.com."), ["10.0.0.3", "10.0.0.4"]);
+        assert_eq!(ips("v1.api.example.com"), ["10.0.0.2"]);
+        assert_eq!(ips("www.example.com"), ["10.0.0.1"]);
+        // A wildcard does not match the bare name or look-alike suffixes
+        assert!(ips("example.com").is_empty());
+        assert!(ips("badexample.com").is_empty());
+    }
+}
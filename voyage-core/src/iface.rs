@@ -1,17 +1,27 @@
 //! Network interface manager for smoltcp
 
-use crate::device::VirtualTunDevice;
+use crate::config::{
+    BufferTier, ChecksumMode, InterfaceAddress, InterfaceConfig, ProxyConfig, QueueConfig,
+    TcpConfig,
+};
+use crate::device::{
+    BufferPool, DeviceStats, DeviceStatsReader, PacketInjector, PacketQueue, PacketSink, VirtualTunDevice,
+    WatermarkCallback,
+};
+use crate::error::VoyageError;
+use crate::memory::SocketBufferGauge;
+use crate::nat::NatKey;
+use crate::sim::SimClock;
 use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
 use smoltcp::socket::tcp::{Socket as TcpSocket, SocketBuffer as TcpSocketBuffer, State as TcpState};
 use smoltcp::time::Instant;
-use smoltcp::wire::{HardwareAddress, IpAddress, IpCidr};
+use smoltcp::wire::{HardwareAddress, IpAddress, IpCidr, Ipv4Address, Ipv6Address};
 use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
 use std::time::SystemTime;
 
-/// Buffer size for TCP sockets
-const TCP_RX_BUFFER_SIZE: usize = 65536;
-const TCP_TX_BUFFER_SIZE: usize = 65536;
-
 /// Get current time as smoltcp Instant
 fn smoltcp_now() -> Instant {
     let duration = SystemTime::now()
@@ -20,11 +30,73 @@ fn smoltcp_now() -> Instant {
     Instant::from_millis(duration.as_millis() as i64)
 }
 
-/// Connection info for debugging
+fn to_cidr(address: &InterfaceAddress) -> IpCidr {
+    let addr = match address.addr {
+        IpAddr::V4(ip) => IpAddress::Ipv4(Ipv4Address::from_bytes(&ip.octets())),
+        IpAddr::V6(ip) => IpAddress::Ipv6(Ipv6Address::from_bytes(&ip.octets())),
+    };
+    IpCidr::new(addr, address.prefix_len)
+}
+
+fn to_ipv4(ip: Ipv4Addr) -> Ipv4Address {
+    Ipv4Address::from_bytes(&ip.octets())
+}
+
+fn to_ipv6(ip: Ipv6Addr) -> Ipv6Address {
+    Ipv6Address::from_bytes(&ip.octets())
+}
+
+/// Interface addressing shared by the core with the interfaces it creates,
+/// so a change reaches each of them on its next poll
+#[derive(Debug, Default)]
+pub struct SharedInterfaceConfig {
+    config: RwLock<InterfaceConfig>,
+    version: AtomicU64,
+}
+
+impl SharedInterfaceConfig {
+    pub fn new(config: InterfaceConfig) -> Self {
+        Self {
+            config: RwLock::new(config),
+            version: AtomicU64::new(0),
+        }
+    }
+
+    /// Current settings
+    pub fn get(&self) -> InterfaceConfig {
+        self.config.read().map(|config| config.clone()).unwrap_or_default()
+    }
+
+    /// Replace the settings; interfaces pick them up on their next poll
+    pub fn set(&self, config: InterfaceConfig) {
+        if let Ok(mut current) = self.config.write() {
+            *current = config;
+            self.version.fetch_add(1, Ordering::Release);
+        }
+    }
+
+    /// Incremented on every `set`
+    pub fn version(&self) -> u64 {
+        self.version.load(Ordering::Acquire)
+    }
+}
+
+/// A tracked socket with the flow it serves and its last seen state
 #[derive(Debug, Clone)]
 pub struct IfaceConnectionInfo {
     pub handle: SocketHandle,
-    pub state: String,
+    /// Flow the socket terminates, once bound
+    pub key: Option<NatKey>,
+    pub state: TcpState,
+}
+
+/// A tracked socket changed state during `poll`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketStateChange {
+    pub handle: SocketHandle,
+    /// Flow the socket terminates, if bound
+    pub key: Option<NatKey>,
+    pub state: TcpState,
 }
 
 /// Manages the smoltcp network interface
@@ -34,18 +106,50 @@ pub struct InterfaceManager {
     sockets: SocketSet<'static>,
     socket_map: HashMap<SocketHandle, IfaceConnectionInfo>,
     next_local_port: u16,
+    tcp_config: TcpConfig,
+    /// Buffer bytes of the sockets in `sockets`
+    buffers: SocketBufferGauge,
+    /// Upper bound on the MTU from the MSS clamp settings
+    max_mtu: Option<usize>,
+    /// State changes of tracked sockets since the last `take_state_changes`
+    state_changes: Vec<SocketStateChange>,
+    /// Followed settings and the version last applied
+    shared_config: Option<(Arc<SharedInterfaceConfig>, u64)>,
+    /// Virtual time driving smoltcp's timers instead of the system clock
+    clock: Option<SimClock>,
 }
 
 impl InterfaceManager {
     pub fn new() -> Self {
+        Self::with_tcp_config(TcpConfig::default())
+    }
+
+    /// Create an interface with the addressing, socket tuning and MSS clamp
+    /// of a proxy config
+    pub fn from_proxy_config(config: &ProxyConfig) -> Self {
+        let mut manager = Self::with_tcp_config(config.tcp.clone());
+        manager.max_mtu = config.mss_clamp.map(|clamp| clamp.effective_mtu() as usize);
+        manager.set_queue_config(config.queues);
+        manager.set_checksum_mode(config.checksum);
+        if let Err(e) = manager.apply_interface_config(&config.interface) {
+            log::warn!("Keeping default interface addressing: {}", e);
+            manager.set_mtu(manager.max_mtu.unwrap_or(config.interface.mtu));
+        }
+        manager
+    }
+
+    pub fn with_tcp_config(tcp_config: TcpConfig) -> Self {
         let mut device = VirtualTunDevice::new();
 
         let config = Config::new(HardwareAddress::Ip);
         let mut iface = Interface::new(config, &mut device, smoltcp_now());
 
-        // Configure interface with a private IP range
+        // Start on a private IP range until the tunnel settings are known
+        let defaults = InterfaceConfig::default();
         iface.update_ip_addrs(|addrs| {
-            let _ = addrs.push(IpCidr::new(IpAddress::v4(10, 0, 0, 1), 24));
+            for address in &defaults.addresses {
+                let _ = addrs.push(to_cidr(address));
+            }
         });
 
         let sockets = SocketSet::new(vec![]);
@@ -56,35 +160,389 @@ impl InterfaceManager {
             sockets,
             socket_map: HashMap::new(),
             next_local_port: 49152,
+            tcp_config,
+            buffers: SocketBufferGauge::new(),
+            max_mtu: None,
+            state_changes: Vec::new(),
+            shared_config: None,
+            clock: None,
+        }
+    }
+
+    /// Replace the interface addresses, default routes and MTU.
+    ///
+    /// The medium is plain IP, so there is no ARP or NDP and a next hop is
+    /// never resolved: whatever the route, packets leave through the TUN
+    /// device to the virtual peer. Without a configured gateway the
+    /// default routes go through the interface's own addresses, which is
+    /// also what lets any-IP mode accept packets to arbitrary remote
+    /// addresses (smoltcp accepts a foreign destination only if its route
+    /// points at one of the interface's addresses).
+    ///
+    /// Established sockets keep their endpoints; packets for addresses
+    /// that were removed are no longer accepted.
+    pub fn apply_interface_config(&mut self, config: &InterfaceConfig) -> Result<(), VoyageError> {
+        config.validate().map_err(VoyageError::config)?;
+
+        self.iface.update_ip_addrs(|addrs| {
+            addrs.clear();
+            for address in &config.addresses {
+                let _ = addrs.push(to_cidr(address));
+            }
+        });
+        self.iface.set_any_ip(config.any_ip);
+
+        let own_v4 = config.addresses.iter().find_map(|a| match a.addr {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(_) => None,
+        });
+        let own_v6 = config.addresses.iter().find_map(|a| match a.addr {
+            IpAddr::V4(_) => None,
+            IpAddr::V6(ip) => Some(ip),
+        });
+        let foreign_gateway = config.gateway_v4.is_some_and(|ip| Some(ip) != own_v4);
+        if config.any_ip && foreign_gateway {
+            log::warn!("IPv4 gateway is not an interface address; any-IP capture needs one");
         }
+
+        let routes = self.iface.routes_mut();
+        routes.remove_default_ipv4_route();
+        routes.remove_default_ipv6_route();
+        if let Some(gateway) = config.gateway_v4.or(own_v4) {
+            routes
+                .add_default_ipv4_route(to_ipv4(gateway))
+                .map_err(|_| VoyageError::config("Route table full".into()))?;
+        }
+        if let Some(gateway) = config.gateway_v6.or(own_v6) {
+            routes
+                .add_default_ipv6_route(to_ipv6(gateway))
+                .map_err(|_| VoyageError::config("Route table full".into()))?;
+        }
+
+        self.set_mtu(self.max_mtu.map_or(config.mtu, |max| max.min(config.mtu)));
+        log::info!(
+            "Interface configured: {:?}, MTU {}",
+            config.addresses.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            self.device.mtu()
+        );
+        Ok(())
     }
 
-    pub fn inject_packet(&mut self, packet: Vec<u8>) {
-        self.device.inject_packet(packet);
+    /// Point the default route of `gateway`'s address family at it,
+    /// replacing the one set by the interface settings
+    pub fn set_default_gateway(&mut self, gateway: IpAddr) -> Result<(), VoyageError> {
+        let routes = self.iface.routes_mut();
+        let added = match gateway {
+            IpAddr::V4(ip) => routes.add_default_ipv4_route(to_ipv4(ip)),
+            IpAddr::V6(ip) => routes.add_default_ipv6_route(to_ipv6(ip)),
+        };
+        added.map_err(|_| VoyageError::config("Route table full".into()))?;
+        Ok(())
+    }
+
+    /// Gateway of the IPv6 or IPv4 default route
+    pub fn default_gateway(&mut self, ipv6: bool) -> Option<IpAddr> {
+        let mut gateway = None;
+        let default = if ipv6 {
+            IpCidr::new(IpAddress::Ipv6(Ipv6Address::UNSPECIFIED), 0)
+        } else {
+            IpCidr::new(IpAddress::Ipv4(Ipv4Address::UNSPECIFIED), 0)
+        };
+        self.iface.routes_mut().update(|routes| {
+            gateway = routes
+                .iter()
+                .find(|route| route.cidr == default)
+                .map(|route| route.via_router);
+        });
+        gateway.map(|via| match via {
+            IpAddress::Ipv4(ip) => IpAddr::V4(Ipv4Addr::from(ip.0)),
+            IpAddress::Ipv6(ip) => IpAddr::V6(Ipv6Addr::from(ip.0)),
+        })
+    }
+
+    /// Whether packets to any destination are accepted
+    pub fn any_ip(&self) -> bool {
+        self.iface.any_ip()
+    }
+
+    /// Follow `shared` from now on, applying its settings on every poll
+    /// after they change
+    pub fn follow_config(&mut self, shared: Arc<SharedInterfaceConfig>) {
+        self.shared_config = Some((shared, u64::MAX));
+        self.refresh_config();
+    }
+
+    /// Addresses currently assigned to the interface
+    pub fn ip_addrs(&self) -> Vec<IpCidr> {
+        self.iface.ip_addrs().to_vec()
+    }
+
+    fn refresh_config(&mut self) {
+        let Some((shared, applied)) = &self.shared_config else {
+            return;
+        };
+        let version = shared.version();
+        if version == *applied {
+            return;
+        }
+        let config = shared.get();
+        if let Some((_, applied)) = self.shared_config.as_mut() {
+            *applied = version;
+        }
+        if let Err(e) = self.apply_interface_config(&config) {
+            log::error!("Cannot apply interface settings: {}", e);
+        }
+    }
+
+    /// Set the device MTU; smoltcp derives the MSS it advertises from it
+    pub fn set_mtu(&mut self, mtu: usize) {
+        self.device.set_mtu(mtu);
+    }
+
+    pub fn tcp_config(&self) -> &TcpConfig {
+        &self.tcp_config
+    }
+
+    /// Update the socket tuning; only sockets created afterwards are affected
+    pub fn set_tcp_config(&mut self, tcp_config: TcpConfig) {
+        self.tcp_config = tcp_config;
+    }
+
+    /// Size the buffers of flows to the listed ports by tier instead of
+    /// the default sizes; only sockets created afterwards are affected
+    pub fn set_buffer_tiers(&mut self, tiers: Vec<BufferTier>) {
+        self.tcp_config.buffer_tiers = tiers;
+    }
+
+    /// Buffer bytes allocated by the sockets of this interface
+    pub fn buffer_memory(&self) -> usize {
+        self.buffers.bytes()
+    }
+
+    /// Gauge of `buffer_memory` that can be read without the interface
+    pub fn buffer_gauge(&self) -> SocketBufferGauge {
+        self.buffers.clone()
+    }
+
+    /// Queue a packet from the app; returns false if the queue was full
+    pub fn inject_packet(&mut self, packet: Vec<u8>) -> bool {
+        self.device.inject_packet(packet)
+    }
+
+    /// Return buffers of packets from `take_packets` once written out
+    pub fn recycle_packets(&self, packets: Vec<Vec<u8>>) {
+        self.device.recycle(packets);
+    }
+
+    /// Recycle packet buffers through `pool` instead of the device's own
+    pub fn set_buffer_pool(&mut self, pool: BufferPool) {
+        self.device.set_buffer_pool(pool);
+    }
+
+    pub fn buffer_pool(&self) -> &BufferPool {
+        self.device.buffer_pool()
+    }
+
+    /// Handle for queueing packets from the app from another thread
+    pub fn packet_injector(&self) -> PacketInjector {
+        self.device.injector()
     }
 
     pub fn take_packets(&mut self) -> Vec<Vec<u8>> {
         self.device.take_packets()
     }
 
+    /// Inbound and outbound packet queues of the device
+    pub fn packet_queues(&self) -> [PacketQueue; 2] {
+        [self.device.rx_queue(), self.device.tx_queue()]
+    }
+
+    /// Change the depth and drop policy of the packet queues
+    pub fn set_queue_config(&mut self, config: QueueConfig) {
+        self.device.set_queue_config(config);
+    }
+
+    /// Choose whether packets from the app have their checksums verified
+    pub fn set_checksum_mode(&mut self, mode: ChecksumMode) {
+        self.device.set_checksum_mode(mode);
+    }
+
+    /// Call `callback` whenever a packet queue fills up to its high watermark
+    pub fn set_watermark_callback(&mut self, callback: Option<WatermarkCallback>) {
+        self.device.set_watermark_callback(callback);
+    }
+
+    /// Traffic, drop and queue depth counters of the device
+    pub fn device_stats(&self) -> DeviceStats {
+        self.device.stats()
+    }
+
+    /// Handle for reading `device_stats` without the interface
+    pub fn device_stats_reader(&self) -> DeviceStatsReader {
+        self.device.stats_reader()
+    }
+
+    /// Deliver outbound packets to `sink` after every poll instead of
+    /// queueing them for `take_packets`
+    pub fn set_packet_sink(&mut self, sink: Option<PacketSink>) {
+        self.device.set_sink(sink);
+    }
+
+    /// Take the time for polls from `clock` instead of the system clock,
+    /// so retransmits and timeouts fire only when a test advances it
+    pub fn set_clock(&mut self, clock: Option<SimClock>) {
+        self.clock = clock;
+    }
+
+    fn now(&self) -> Instant {
+        match &self.clock {
+            Some(clock) => Instant::from_millis(clock.now_ms() as i64),
+            None => smoltcp_now(),
+        }
+    }
+
     pub fn poll(&mut self) -> bool {
-        self.iface.poll(smoltcp_now(), &mut self.device, &mut self.sockets)
+        self.refresh_config();
+        let now = self.now();
+        let changed = self.iface.poll(now, &mut self.device, &mut self.sockets);
+        self.device.flush();
+        self.record_state_changes();
+        changed
+    }
+
+    /// Time until smoltcp next needs a poll for its timers (retransmits,
+    /// delayed ACKs, keep-alives); `None` when only new packets matter
+    pub fn poll_delay(&mut self) -> Option<std::time::Duration> {
+        self.iface
+            .poll_delay(self.now(), &self.sockets)
+            .map(|delay| std::time::Duration::from_micros(delay.total_micros()))
     }
 
     pub fn create_tcp_socket(&mut self) -> SocketHandle {
-        let rx_buffer = TcpSocketBuffer::new(vec![0u8; TCP_RX_BUFFER_SIZE]);
-        let tx_buffer = TcpSocketBuffer::new(vec![0u8; TCP_TX_BUFFER_SIZE]);
-        let socket = TcpSocket::new(rx_buffer, tx_buffer);
+        self.create_sized_socket(None)
+    }
+
+    /// Create a socket with the buffer sizes of flows to `port`
+    fn create_sized_socket(&mut self, port: Option<u16>) -> SocketHandle {
+        let (rx_size, tx_size) = self.tcp_config.buffer_sizes(port);
+        let rx_buffer = TcpSocketBuffer::new(vec![0u8; rx_size]);
+        let tx_buffer = TcpSocketBuffer::new(vec![0u8; tx_size]);
+        let mut socket = TcpSocket::new(rx_buffer, tx_buffer);
+        socket.set_nagle_enabled(self.tcp_config.nagle_enabled);
+        socket.set_ack_delay(self.tcp_config.ack_delay());
+        self.buffers.add(rx_size + tx_size);
         self.sockets.add(socket)
     }
 
+    /// Open a socket that accepts the next connection to `port`, so an
+    /// intercepted flow can be terminated locally. Its buffers are sized
+    /// by the tier listing `port`, if any.
+    pub fn listen_tcp(&mut self, port: u16) -> Result<SocketHandle, VoyageError> {
+        self.listen_flow(port, port)
+    }
+
+    /// Open a socket on `port` that accepts a flow to `dst_port`, with its
+    /// buffers sized by the tier listing `dst_port`, if any
+    pub fn listen_flow(&mut self, port: u16, dst_port: u16) -> Result<SocketHandle, VoyageError> {
+        let handle = self.create_sized_socket(Some(dst_port));
+        if let Err(e) = self.get_tcp_socket(handle).listen(port) {
+            self.remove_socket(handle);
+            return Err(VoyageError::socket(format!(
+                "Cannot listen on port {}: {}",
+                port, e
+            )));
+        }
+        self.socket_map.insert(
+            handle,
+            IfaceConnectionInfo {
+                handle,
+                key: None,
+                state: TcpState::Listen,
+            },
+        );
+        Ok(handle)
+    }
+
+    /// Track `handle` as the socket of flow `key`; its state changes are
+    /// reported by `take_state_changes` from now on
+    pub fn bind_key(&mut self, handle: SocketHandle, key: NatKey) {
+        let state = self.sockets.get::<TcpSocket>(handle).state();
+        self.socket_map
+            .entry(handle)
+            .and_modify(|info| info.key = Some(key))
+            .or_insert(IfaceConnectionInfo {
+                handle,
+                key: Some(key),
+                state,
+            });
+    }
+
+    /// Flow a tracked socket is bound to
+    pub fn key_for_handle(&self, handle: SocketHandle) -> Option<NatKey> {
+        self.socket_map.get(&handle).and_then(|info| info.key)
+    }
+
+    /// Take the socket state changes seen since the last call, oldest first
+    pub fn take_state_changes(&mut self) -> Vec<SocketStateChange> {
+        std::mem::take(&mut self.state_changes)
+    }
+
+    fn record_state_changes(&mut self) {
+        for info in self.socket_map.values_mut() {
+            let state = self.sockets.get::<TcpSocket>(info.handle).state();
+            if state != info.state {
+                info.state = state;
+                self.state_changes.push(SocketStateChange {
+                    handle: info.handle,
+                    key: info.key,
+                    state,
+                });
+            }
+        }
+    }
+
+    /// Toggle latency mode on a socket (e.g. from a rule's `nodelay` option)
+    pub fn set_nodelay(&mut self, handle: SocketHandle, nodelay: bool) {
+        let tcp_config = if nodelay {
+            self.tcp_config.clone().nodelay()
+        } else {
+            self.tcp_config.clone()
+        };
+        let socket = self.sockets.get_mut::<TcpSocket>(handle);
+        socket.set_nagle_enabled(tcp_config.nagle_enabled);
+        socket.set_ack_delay(tcp_config.ack_delay());
+    }
+
     pub fn get_tcp_socket(&mut self, handle: SocketHandle) -> &mut TcpSocket<'static> {
         self.sockets.get_mut::<TcpSocket>(handle)
     }
 
+    pub fn sockets(&self) -> &SocketSet<'static> {
+        &self.sockets
+    }
+
     pub fn remove_socket(&mut self, handle: SocketHandle) {
         self.socket_map.remove(&handle);
-        self.sockets.remove(handle);
+        if let smoltcp::socket::Socket::Tcp(socket) = self.sockets.remove(handle) {
+            self.buffers.remove(socket.recv_capacity() + socket.send_capacity());
+        }
+    }
+
+    /// Reset and drop sockets whose flow was reaped, flushing the RSTs to the app
+    pub fn close_orphaned(&mut self, handles: &[SocketHandle]) {
+        // Skip handles already removed, which smoltcp would panic on
+        let handles: Vec<SocketHandle> = handles
+            .iter()
+            .copied()
+            .filter(|handle| self.sockets.iter().any(|(live, _)| live == *handle))
+            .collect();
+        for handle in &handles {
+            self.get_tcp_socket(*handle).abort();
+        }
+        self.poll();
+        for handle in handles {
+            self.remove_socket(handle);
+        }
     }
 
     pub fn allocate_local_port(&mut self) -> u16 {
@@ -101,6 +559,8 @@ impl InterfaceManager {
     }
 
     pub fn cleanup_closed_sockets(&mut self) {
+        // Report closures before the sockets disappear
+        self.record_state_changes();
         let mut to_remove = Vec::new();
 
         for (handle, _) in self.socket_map.iter() {
@@ -141,6 +601,181 @@ mod tests {
         assert_eq!(manager.socket_count(), 0);
     }
 
+    #[test]
+    fn test_listen_tcp() {
+        let mut manager = InterfaceManager::new();
+        let handle = manager.listen_tcp(10000).unwrap();
+        assert_eq!(manager.get_tcp_socket(handle).state(), TcpState::Listen);
+        assert!(manager.listen_tcp(0).is_err());
+        assert_eq!(manager.socket_count(), 1);
+
+        manager.get_tcp_socket(handle).abort();
+        manager.cleanup_closed_sockets();
+        assert_eq!(manager.socket_count(), 0);
+    }
+
+    #[test]
+    fn test_state_changes() {
+        let mut manager = InterfaceManager::new();
+        let key = NatKey::tcp("10.0.0.2:40000".parse().unwrap(), "8.8.8.8:443".parse().unwrap());
+        let handle = manager.listen_tcp(10000).unwrap();
+        manager.bind_key(handle, key);
+        assert_eq!(manager.key_for_handle(handle), Some(key));
+
+        manager.poll();
+        assert!(manager.take_state_changes().is_empty());
+
+        manager.get_tcp_socket(handle).abort();
+        manager.poll();
+        let changes = manager.take_state_changes();
+        assert_eq!(
+            changes,
+            [SocketStateChange {
+                handle,
+                key: Some(key),
+                state: TcpState::Closed
+            }]
+        );
+        assert!(manager.take_state_changes().is_empty());
+    }
+
+    #[test]
+    fn test_tcp_config_applied() {
+        let tcp_config = TcpConfig {
+            nagle_enabled: false,
+            ack_delay_ms: Some(40),
+            ..TcpConfig::default().with_window(4096)
+        };
+        let mut manager = InterfaceManager::with_tcp_config(tcp_config);
+        let handle = manager.create_tcp_socket();
+
+        let socket = manager.get_tcp_socket(handle);
+        assert!(!socket.nagle_enabled());
+        assert_eq!(socket.ack_delay(), Some(smoltcp::time::Duration::from_millis(40)));
+        assert_eq!(socket.recv_capacity(), 4096);
+        assert_eq!(socket.send_capacity(), 4096);
+    }
+
+    #[test]
+    fn test_buffer_tiers() {
+        let tcp_config = TcpConfig::default().with_window(4096);
+        let mut manager = InterfaceManager::with_tcp_config(tcp_config);
+        manager.set_buffer_tiers(vec![BufferTier {
+            ports: vec![443],
+            rx_buffer_size: 1 << 16,
+            tx_buffer_size: 1 << 15,
+        }]);
+        let bulk = manager.listen_tcp(443).unwrap();
+        let chat = manager.listen_tcp(5222).unwrap();
+        assert_eq!(manager.get_tcp_socket(bulk).recv_capacity(), 1 << 16);
+        assert_eq!(manager.get_tcp_socket(chat).recv_capacity(), 4096);
+        assert_eq!(manager.buffer_memory(), (1 << 16) + (1 << 15) + 8192);
+
+        let gauge = manager.buffer_gauge();
+        manager.remove_socket(bulk);
+        assert_eq!(gauge.bytes(), 8192);
+    }
+
+    #[test]
+    fn test_set_nodelay() {
+        let mut manager = InterfaceManager::new();
+        let handle = manager.create_tcp_socket();
+        assert!(manager.get_tcp_socket(handle).nagle_enabled());
+
+        manager.set_nodelay(handle, true);
+        assert!(!manager.get_tcp_socket(handle).nagle_enabled());
+        assert!(manager.get_tcp_socket(handle).ack_delay().is_none());
+
+        manager.set_nodelay(handle, false);
+        assert!(manager.get_tcp_socket(handle).nagle_enabled());
+        assert!(manager.get_tcp_socket(handle).ack_delay().is_some());
+    }
+
+    #[test]
+    fn test_set_mtu() {
+        let mut manager = InterfaceManager::new();
+        manager.set_mtu(1400);
+        assert_eq!(manager.device.mtu(), 1400);
+
+        let config = ProxyConfig::default().with_mss_clamp(1500, 80);
+        let manager = InterfaceManager::from_proxy_config(&config);
+        assert_eq!(manager.device.mtu(), 1420);
+    }
+
+    #[test]
+    fn test_apply_interface_config() {
+        let mut manager = InterfaceManager::new();
+        assert_eq!(manager.ip_addrs()[0].to_string(), "10.0.0.1/24");
+
+        let config = InterfaceConfig {
+            addresses: vec!["198.18.0.1/16".parse().unwrap(), "fd00::1/64".parse().unwrap()],
+            gateway_v4: Some("198.18.0.254".parse().unwrap()),
+            gateway_v6: None,
+            mtu: 1400,
+            any_ip: true,
+        };
+        manager.apply_interface_config(&config).unwrap();
+        let addrs: Vec<String> = manager.ip_addrs().iter().map(ToString::to_string).collect();
+        assert_eq!(addrs, ["198.18.0.1/16", "fd00::1/64"]);
+        assert_eq!(manager.device.mtu(), 1400);
+
+        // IPv6 has no gateway, so its default route goes through the interface
+        let gateway = |ip: &str| Some(ip.parse::<IpAddr>().unwrap());
+        assert_eq!(manager.default_gateway(false), gateway("198.18.0.254"));
+        assert_eq!(manager.default_gateway(true), gateway("fd00::1"));
+        let peer = "fd00::fe".parse().unwrap();
+        manager.set_default_gateway(peer).unwrap();
+        assert_eq!(manager.default_gateway(true), gateway("fd00::fe"));
+
+        let invalid = InterfaceConfig {
+            addresses: Vec::new(),
+            ..config
+        };
+        assert!(manager.apply_interface_config(&invalid).is_err());
+        assert_eq!(manager.ip_addrs().len(), 2);
+    }
+
+    #[test]
+    fn test_follow_shared_config() {
+        let shared = Arc::new(SharedInterfaceConfig::new(InterfaceConfig::default()));
+        let config = ProxyConfig::default().with_mss_clamp(1500, 100);
+        let mut manager = InterfaceManager::from_proxy_config(&config);
+        manager.follow_config(Arc::clone(&shared));
+        assert_eq!(manager.device.mtu(), 1400);
+
+        shared.set(InterfaceConfig {
+            addresses: vec!["172.19.0.1/30".parse().unwrap()],
+            mtu: 1280,
+            ..Default::default()
+        });
+        manager.poll();
+        assert_eq!(manager.ip_addrs()[0].to_string(), "172.19.0.1/30");
+        // The MSS clamp still caps the MTU from above only
+        assert_eq!(manager.device.mtu(), 1280);
+    }
+
+    #[test]
+    fn test_any_ip_capture() {
+        let syn = crate::create_tcp_packet([10, 0, 0, 2], [8, 8, 8, 8], 40000, 443, true);
+        let accepts = |any_ip: bool| {
+            let mut manager = InterfaceManager::new();
+            let config = InterfaceConfig {
+                any_ip,
+                ..Default::default()
+            };
+            manager.apply_interface_config(&config).unwrap();
+            assert_eq!(manager.any_ip(), any_ip);
+            let handle = manager.listen_tcp(443).unwrap();
+            manager.inject_packet(syn.clone());
+            manager.poll();
+            manager.get_tcp_socket(handle).state() == TcpState::SynReceived
+        };
+
+        // A SYN to a remote address only reaches the listener in any-IP mode
+        assert!(accepts(true));
+        assert!(!accepts(false));
+    }
+
     #[test]
     fn test_port_allocation() {
         let mut manager = InterfaceManager::new();
@@ -0,0 +1,851 @@
+//! Configuration Import
+//!
+//! This module converts configurations written for Surge (`.conf`) and
+//! Clash (YAML) into a `VoyageConfig`. Everything Voyage can express is
+//! carried over; anything else (proxy protocols other than SOCKS5, rule
+//! types such as GEOIP or RULE-SET, unknown settings) is left out and
+//! reported as a diagnostic with the line it came from, so the result
+//! always loads.
+//!
+//! Voyage never resolves names to match IP rules, so the `no-resolve` rule
+//! option is dropped without a diagnostic.
+
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr};
+
+use serde_yaml::{Mapping, Value};
+
+use crate::error::VoyageError;
+use crate::fakeip::Ipv4Range;
+use crate::message::LocalizedMessage;
+use crate::profile::{
+    is_builtin, line_of, parse_dns_server, parse_log_level, GroupKind, ProxyEntry, ProxyGroupEntry,
+    ProxyKind, VoyageConfig,
+};
+use crate::rule::RuleEngine;
+use crate::secret::SecretString;
+
+/// Rule types Voyage understands, after renaming Clash's MATCH to FINAL
+const RULE_TYPES: [&str; 10] = [
+    "DOMAIN",
+    "DOMAIN-SUFFIX",
+    "DOMAIN-KEYWORD",
+    "GEOSITE",
+    "IP-CIDR",
+    "IP-CIDR6",
+    "DST-PORT",
+    "SRC-PORT",
+    "SRC-IP-CIDR",
+    "FINAL",
+];
+
+/// Surge sections the importer reads
+const SURGE_SECTIONS: [&str; 5] = ["General", "Proxy", "Proxy Group", "Rule", "Host"];
+
+/// Client a configuration was written for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImportFormat {
+    /// Surge `.conf` profile
+    Surge,
+    /// Clash YAML profile
+    Clash,
+}
+
+/// A directive that was skipped or changed during import
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImportDiagnostic {
+    /// 1-based line of the directive in the source, if known
+    pub line: Option<u32>,
+    /// What happened to it, as a catalog message
+    pub message: LocalizedMessage,
+}
+
+/// A converted configuration with the diagnostics collected on the way
+#[derive(Debug, Clone)]
+pub struct ImportResult {
+    /// The converted configuration, valid as is
+    pub config: VoyageConfig,
+    /// Directives that were skipped or changed, in source order
+    pub diagnostics: Vec<ImportDiagnostic>,
+}
+
+/// Convert `text` written for another client into a Voyage configuration.
+/// Fails only when the source cannot be read at all.
+pub fn convert(text: &str, format: ImportFormat) -> Result<ImportResult, VoyageError> {
+    let mut importer = Importer::new(text);
+    match format {
+        ImportFormat::Surge => importer.read_surge(),
+        ImportFormat::Clash => importer.read_clash()?,
+    }
+    importer.finish()
+}
+
+/// Conversion state shared by both formats
+struct Importer<'a> {
+    text: &'a str,
+    config: VoyageConfig,
+    /// Groups with their lines, kept apart until members are checked
+    groups: Vec<(Option<u32>, ProxyGroupEntry)>,
+    /// Translated rules with their lines, checked once groups are settled
+    rules: Vec<(Option<u32>, String)>,
+    /// Names of proxies and groups that had to be renamed, by source name
+    renames: HashMap<String, String>,
+    diagnostics: Vec<ImportDiagnostic>,
+}
+
+impl<'a> Importer<'a> {
+    fn new(text: &'a str) -> Self {
+        Self {
+            text,
+            config: VoyageConfig::default(),
+            groups: Vec::new(),
+            rules: Vec::new(),
+            renames: HashMap::new(),
+            diagnostics: Vec::new(),
+        }
+    }
+
+    fn note(&mut self, line: Option<u32>, key: &str, args: &[&str]) {
+        self.diagnostics.push(ImportDiagnostic {
+            line,
+            message: LocalizedMessage::new(key, args.iter().map(|arg| arg.to_string()).collect()),
+        });
+    }
+
+    /// Line of the first mention of `needle`, for sources read as a tree
+    fn line_of(&self, needle: &str) -> Option<u32> {
+        line_of(self.text, needle).map(|line| line as u32)
+    }
+
+    // ---- Surge ----
+
+    fn read_surge(&mut self) {
+        let text = self.text;
+        let mut section: Option<&str> = None;
+        for (index, raw) in text.lines().enumerate() {
+            let line = Some(index as u32 + 1);
+            let trimmed = raw.trim();
+            if trimmed.is_empty()
+                || trimmed.starts_with('#')
+                || trimmed.starts_with(';')
+                || trimmed.starts_with("//")
+            {
+                continue;
+            }
+            if let Some(name) = trimmed
+                .strip_prefix('[')
+                .and_then(|rest| rest.strip_suffix(']'))
+            {
+                if !SURGE_SECTIONS.contains(&name) {
+                    self.note(line, "import.unsupported_section", &[name]);
+                }
+                section = Some(name);
+                continue;
+            }
+
+            match section {
+                Some("General") => self.surge_general(line, trimmed),
+                Some("Proxy") => self.surge_proxy(line, trimmed),
+                Some("Proxy Group") => self.surge_group(line, trimmed),
+                Some("Rule") => self.add_rule(line, trimmed),
+                Some("Host") => self.surge_host(line, trimmed),
+                // Contents of skipped sections were reported with the header
+                Some(_) => {}
+                None => self.note(line, "import.invalid_line", &[trimmed]),
+            }
+        }
+    }
+
+    fn surge_general(&mut self, line: Option<u32>, entry: &str) {
+        let Some((key, value)) = split_assignment(entry) else {
+            self.note(line, "import.invalid_line", &[entry]);
+            return;
+        };
+        match key {
+            "dns-server" => {
+                for server in split_list(value) {
+                    self.add_dns_server(line, "dns-server", server);
+                }
+            }
+            "skip-proxy" => {
+                for network in split_list(value) {
+                    self.add_skip_proxy(line, "skip-proxy", network);
+                }
+            }
+            "loglevel" => {
+                let level = match value {
+                    "verbose" => "trace",
+                    "notify" => "info",
+                    other => other,
+                };
+                self.set_log_level(line, key, level);
+            }
+            _ => self.note(line, "import.unsupported_setting", &[key]),
+        }
+    }
+
+    fn surge_proxy(&mut self, line: Option<u32>, entry: &str) {
+        let Some((name, value)) = split_assignment(entry) else {
+            self.note(line, "import.invalid_line", &[entry]);
+            return;
+        };
+        let fields: Vec<&str> = split_list(value).collect();
+        let kind = fields.first().copied().unwrap_or_default();
+        if !kind.eq_ignore_ascii_case("socks5") {
+            self.note(line, "import.unsupported_proxy", &[name, kind]);
+            return;
+        }
+
+        let mut credentials = Vec::new();
+        let mut username = None;
+        let mut password = None;
+        for field in fields.iter().skip(3) {
+            match split_assignment(field) {
+                Some(("username", value)) => username = Some(value.to_string()),
+                Some(("password", value)) => password = Some(value.to_string()),
+                Some((option, _)) => self.note(line, "import.unsupported_option", &[option, name]),
+                None => credentials.push(field.to_string()),
+            }
+        }
+        // Older profiles list the credentials positionally
+        let mut credentials = credentials.into_iter();
+        let username = username.or_else(|| credentials.next());
+        let password = password.or_else(|| credentials.next());
+
+        let server = fields.get(1).copied().unwrap_or_default();
+        let port = fields.get(2).copied().unwrap_or_default();
+        self.add_proxy(line, name, server, port, username, password);
+    }
+
+    fn surge_group(&mut self, line: Option<u32>, entry: &str) {
+        let Some((name, value)) = split_assignment(entry) else {
+            self.note(line, "import.invalid_line", &[entry]);
+            return;
+        };
+        let mut fields = split_list(value);
+        let kind = fields.next().unwrap_or_default();
+        let mut members = Vec::new();
+        for field in fields {
+            match split_assignment(field) {
+                Some((option, _)) => self.note(line, "import.unsupported_option", &[option, name]),
+                None => members.push(field.to_string()),
+            }
+        }
+        self.add_group(line, name, kind, members);
+    }
+
+    fn surge_host(&mut self, line: Option<u32>, entry: &str) {
+        match split_assignment(entry) {
+            Some((name, addr)) => self.add_host(line, name, addr),
+            None => self.note(line, "import.invalid_line", &[entry]),
+        }
+    }
+
+    // ---- Clash ----
+
+    fn read_clash(&mut self) -> Result<(), VoyageError> {
+        let root: Value = serde_yaml::from_str(self.text).map_err(|e| match e.location() {
+            Some(location) => VoyageError::config_syntax(location.line() as u32, e.to_string()),
+            None => VoyageError::config(e.to_string()),
+        })?;
+        let Value::Mapping(root) = root else {
+            return Err(VoyageError::config(
+                "Clash configuration must be a mapping".into(),
+            ));
+        };
+
+        for (key, value) in &root {
+            let key = scalar(key).unwrap_or_default();
+            match key.as_str() {
+                "proxies" => {
+                    for proxy in sequence(value) {
+                        self.clash_proxy(proxy);
+                    }
+                }
+                "proxy-groups" => {
+                    for group in sequence(value) {
+                        self.clash_group(group);
+                    }
+                }
+                "rules" => {
+                    for rule in sequence(value) {
+                        if let Some(rule) = scalar(rule) {
+                            self.add_rule(self.line_of(&rule), &rule);
+                        }
+                    }
+                }
+                "dns" => {
+                    if let Value::Mapping(dns) = value {
+                        self.clash_dns(dns);
+                    }
+                }
+                "hosts" => {
+                    if let Value::Mapping(hosts) = value {
+                        for (name, addr) in hosts {
+                            let name = scalar(name).unwrap_or_default();
+                            let addr = scalar(addr).unwrap_or_default();
+                            self.add_host(self.line_of(&name), &name, &addr);
+                        }
+                    }
+                }
+                "log-level" => {
+                    let level = scalar(value).unwrap_or_default();
+                    self.set_log_level(self.line_of("log-level"), "log-level", &level);
+                }
+                _ => {
+                    let line = self.line_of(&format!("{}:", key));
+                    self.note(line, "import.unsupported_setting", &[&key]);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn clash_proxy(&mut self, proxy: &Value) {
+        let field = |key: &str| proxy.get(key).and_then(scalar).unwrap_or_default();
+        let name = field("name");
+        let line = self.line_of(&name);
+        let kind = field("type");
+        if !kind.eq_ignore_ascii_case("socks5") {
+            self.note(line, "import.unsupported_proxy", &[&name, &kind]);
+            return;
+        }
+        if let Value::Mapping(options) = proxy {
+            for key in options.keys().filter_map(scalar) {
+                if !matches!(
+                    key.as_str(),
+                    "name" | "type" | "server" | "port" | "username" | "password"
+                ) {
+                    self.note(line, "import.unsupported_option", &[&key, &name]);
+                }
+            }
+        }
+        let username = proxy.get("username").and_then(scalar);
+        let password = proxy.get("password").and_then(scalar);
+        self.add_proxy(
+            line,
+            &name,
+            &field("server"),
+            &field("port"),
+            username,
+            password,
+        );
+    }
+
+    fn clash_group(&mut self, group: &Value) {
+        let name = group.get("name").and_then(scalar).unwrap_or_default();
+        let line = self.line_of(&name);
+        if let Value::Mapping(options) = group {
+            for key in options.keys().filter_map(scalar) {
+                if !matches!(key.as_str(), "name" | "type" | "proxies") {
+                    self.note(line, "import.unsupported_option", &[&key, &name]);
+                }
+            }
+        }
+        let kind = group.get("type").and_then(scalar).unwrap_or_default();
+        let members = group
+            .get("proxies")
+            .map(sequence)
+            .unwrap_or_default()
+            .iter()
+            .filter_map(scalar)
+            .collect();
+        self.add_group(line, &name, &kind, members);
+    }
+
+    fn clash_dns(&mut self, dns: &Mapping) {
+        for (key, value) in dns {
+            let key = scalar(key).unwrap_or_default();
+            let setting = format!("dns.{}", key);
+            let line = self.line_of(&format!("{}:", key));
+            match key.as_str() {
+                "nameserver" => {
+                    for server in sequence(value).iter().filter_map(scalar) {
+                        self.add_dns_server(self.line_of(&server), &setting, &server);
+                    }
+                }
+                "enhanced-mode" => match scalar(value).unwrap_or_default().as_str() {
+                    "fake-ip" => self.config.dns.fake_ip = true,
+                    "redir-host" | "normal" => self.config.dns.fake_ip = false,
+                    other => self.note(line, "import.invalid_value", &[other, &setting]),
+                },
+                "fake-ip-range" => {
+                    let range = scalar(value).unwrap_or_default();
+                    match range.parse::<Ipv4Range>() {
+                        Ok(_) => self.config.dns.fake_ip_range = Some(range),
+                        Err(_) => self.note(line, "import.invalid_value", &[&range, &setting]),
+                    }
+                }
+                // Voyage's forwarder is always on
+                "enable" => {}
+                _ => self.note(line, "import.unsupported_setting", &[&setting]),
+            }
+        }
+    }
+
+    // ---- Shared ----
+
+    fn add_proxy(
+        &mut self,
+        line: Option<u32>,
+        name: &str,
+        server: &str,
+        port: &str,
+        username: Option<String>,
+        password: Option<String>,
+    ) {
+        if name.is_empty() || server.is_empty() {
+            self.note(
+                line,
+                "import.invalid_proxy",
+                &[name, "missing name or server"],
+            );
+            return;
+        }
+        let port = match port.parse::<u16>() {
+            Ok(port) if port != 0 => port,
+            _ => {
+                self.note(line, "import.invalid_proxy", &[name, "invalid port"]);
+                return;
+            }
+        };
+        let Some(name) = self.claim_name(line, name) else {
+            return;
+        };
+        self.config.proxies.push(ProxyEntry {
+            name,
+            kind: ProxyKind::Socks5,
+            server: server.to_string(),
+            port,
+            username,
+            password: password.map(SecretString::from),
+        });
+    }
+
+    fn add_group(&mut self, line: Option<u32>, name: &str, kind: &str, members: Vec<String>) {
+        let kind = match kind {
+            "select" => GroupKind::Select,
+            "url-test" => GroupKind::UrlTest,
+            "fallback" => GroupKind::Fallback,
+            other => {
+                self.note(line, "import.unsupported_group", &[name, other]);
+                GroupKind::Select
+            }
+        };
+        let Some(name) = self.claim_name(line, name) else {
+            return;
+        };
+        self.groups.push((
+            line,
+            ProxyGroupEntry {
+                name,
+                kind,
+                proxies: members,
+                interrupt_existing_connections: false,
+                selected: None,
+            },
+        ));
+    }
+
+    /// Queue a rule, renaming MATCH to FINAL and dropping options
+    fn add_rule(&mut self, line: Option<u32>, rule: &str) {
+        let mut parts: Vec<&str> = rule.split(',').map(str::trim).collect();
+        let kind = parts[0].to_ascii_uppercase();
+        let kind = if kind == "MATCH" {
+            "FINAL".to_string()
+        } else {
+            kind
+        };
+        let Some(kind) = RULE_TYPES
+            .iter()
+            .copied()
+            .find(|supported| *supported == kind)
+        else {
+            self.note(line, "import.unsupported_rule", &[rule, &kind]);
+            return;
+        };
+        parts[0] = kind;
+
+        let policy_index = if kind == "FINAL" { 1 } else { 2 };
+        if parts.len() <= policy_index {
+            self.note(line, "import.invalid_rule", &[rule, "missing policy"]);
+            return;
+        }
+        let policy = parts[policy_index];
+        if policy.to_ascii_uppercase().starts_with("REJECT-") {
+            self.note(line, "import.rejected_as_reject", &[policy]);
+            parts[policy_index] = "REJECT";
+        }
+        for option in parts.drain(policy_index + 1..) {
+            if !option.eq_ignore_ascii_case("no-resolve") && !option.is_empty() {
+                self.note(line, "import.unsupported_option", &[option, rule]);
+            }
+        }
+        self.rules.push((line, parts.join(", ")));
+    }
+
+    fn add_dns_server(&mut self, line: Option<u32>, setting: &str, server: &str) {
+        match parse_dns_server(server) {
+            Ok(_) => self.config.dns.servers.push(server.to_string()),
+            Err(_) => self.note(line, "import.invalid_value", &[server, setting]),
+        }
+    }
+
+    fn add_skip_proxy(&mut self, line: Option<u32>, setting: &str, network: &str) {
+        // A bare address skips just that host
+        let network = match network.parse::<Ipv4Addr>() {
+            Ok(ip) => format!("{}/32", ip),
+            Err(_) => network.to_string(),
+        };
+        match network.parse::<Ipv4Range>() {
+            Ok(_) => self.config.general.skip_proxy.push(network),
+            Err(_) => self.note(line, "import.invalid_value", &[&network, setting]),
+        }
+    }
+
+    fn add_host(&mut self, line: Option<u32>, name: &str, addr: &str) {
+        match addr.parse::<IpAddr>() {
+            Ok(_) => {
+                self.config
+                    .dns
+                    .hosts
+                    .insert(name.to_string(), addr.to_string());
+            }
+            Err(_) => self.note(line, "import.invalid_value", &[addr, name]),
+        }
+    }
+
+    fn set_log_level(&mut self, line: Option<u32>, setting: &str, level: &str) {
+        match parse_log_level(level) {
+            Ok(_) => self.config.logging.level = level.to_ascii_lowercase(),
+            Err(_) => self.note(line, "import.invalid_value", &[level, setting]),
+        }
+    }
+
+    /// Name a proxy or group is imported under. Names clashing with a
+    /// built-in policy (Clash profiles often have a group called Proxy) are
+    /// renamed, duplicates are skipped.
+    fn claim_name(&mut self, line: Option<u32>, name: &str) -> Option<String> {
+        let claimed = if is_builtin(name) {
+            format!("{} (imported)", name)
+        } else {
+            name.to_string()
+        };
+        if name.is_empty() || self.name_taken(&claimed) {
+            self.note(line, "import.duplicate_name", &[name]);
+            return None;
+        }
+        if claimed != name {
+            self.note(line, "import.renamed", &[name, &claimed]);
+            self.renames.insert(name.to_string(), claimed.clone());
+        }
+        Some(claimed)
+    }
+
+    fn name_taken(&self, name: &str) -> bool {
+        self.config.proxies.iter().any(|proxy| proxy.name == name)
+            || self.groups.iter().any(|(_, group)| group.name == name)
+    }
+
+    /// Drop references to skipped proxies, then check every rule against
+    /// the final set of policies
+    fn finish(mut self) -> Result<ImportResult, VoyageError> {
+        let mut names: HashSet<String> = self
+            .config
+            .proxies
+            .iter()
+            .map(|proxy| proxy.name.clone())
+            .collect();
+        names.extend(self.groups.iter().map(|(_, group)| group.name.clone()));
+        let renames = std::mem::take(&mut self.renames);
+        for (_, group) in &mut self.groups {
+            for member in &mut group.proxies {
+                if let Some(renamed) = renames.get(member.as_str()) {
+                    *member = renamed.clone();
+                }
+            }
+        }
+
+        // Removing an empty group can empty the groups that contain it
+        loop {
+            let mut changed = false;
+            let mut notes = Vec::new();
+            for (line, group) in &mut self.groups {
+                group.proxies.retain(|member| {
+                    let known = names.contains(member) || member == "DIRECT";
+                    if !known {
+                        notes.push((
+                            *line,
+                            "import.dropped_member",
+                            vec![member.clone(), group.name.clone()],
+                        ));
+                    }
+                    known
+                });
+                if group.proxies.is_empty() && names.remove(&group.name) {
+                    notes.push((*line, "import.empty_group", vec![group.name.clone()]));
+                    changed = true;
+                }
+            }
+            self.groups.retain(|(_, group)| !group.proxies.is_empty());
+            for (line, key, args) in notes {
+                let args: Vec<&str> = args.iter().map(String::as_str).collect();
+                self.note(line, key, &args);
+            }
+            if !changed {
+                break;
+            }
+        }
+        self.config.proxy_groups = self.groups.drain(..).map(|(_, group)| group).collect();
+
+        for (line, rule) in std::mem::take(&mut self.rules) {
+            let mut parts: Vec<&str> = rule.split(", ").collect();
+            let policy_index = if parts[0] == "FINAL" { 1 } else { 2 };
+            if let Some(renamed) = renames.get(parts[policy_index]) {
+                parts[policy_index] = renamed;
+            }
+            let rule = parts.join(", ");
+            let policy = parts[policy_index];
+            if !is_builtin(policy) && !names.contains(policy) {
+                self.note(line, "import.unknown_policy", &[&rule, policy]);
+                continue;
+            }
+            if let Err(e) = RuleEngine::parse_config(&self.config.translate_rule(&rule)) {
+                let detail = match e {
+                    VoyageError::RuleSyntax { detail, .. } => detail,
+                    other => other.to_string(),
+                };
+                self.note(line, "import.invalid_rule", &[&rule, &detail]);
+                continue;
+            }
+            self.config.rules.push(rule);
+        }
+
+        self.config.validate("")?;
+        Ok(ImportResult {
+            config: self.config,
+            diagnostics: self.diagnostics,
+        })
+    }
+}
+
+/// Split `key = value`, trimming both sides
+fn split_assignment(entry: &str) -> Option<(&str, &str)> {
+    entry
+        .split_once('=')
+        .map(|(key, value)| (key.trim(), value.trim()))
+}
+
+/// Comma-separated fields, trimmed, without empty ones
+fn split_list(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|field| !field.is_empty())
+}
+
+/// String form of a YAML scalar
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+fn sequence(value: &Value) -> &[Value] {
+    match value {
+        Value::Sequence(items) => items,
+        _ => &[],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SURGE: &str = "\
+[General]
+loglevel = notify
+dns-server = system, 223.5.5.5, 1.1.1.1
+skip-proxy = 127.0.0.1, 192.168.0.0/16, localhost
+tun-excluded-routes = 10.0.0.0/8
+
+[Proxy]
+Tokyo = socks5, tokyo.example.com, 1080, alice, secret
+HK = ss, hk.example.com, 8388, encrypt-method=aes-128-gcm, password=x
+Osaka = socks5, osaka.example.com, 1081, tfo=true
+
+[Proxy Group]
+Auto = url-test, Tokyo, HK, url=http://www.gstatic.com/generate_204
+Asia = select, HK
+Main = load-balance, Auto, Asia, DIRECT
+
+[Rule]
+DOMAIN-SUFFIX,google.com,Auto
+GEOIP,CN,DIRECT
+IP-CIDR,10.0.0.0/8,DIRECT,no-resolve
+DOMAIN,ads.example.com,REJECT-TINYGIF
+DOMAIN-KEYWORD,video,Asia
+FINAL,Main,dns-failed
+
+[Host]
+router.local = 192.168.1.1
+nas.local = server:1.1.1.1
+
+[MITM]
+hostname = *.example.com
+";
+
+    fn keys(result: &ImportResult) -> Vec<(Option<u32>, &str)> {
+        result
+            .diagnostics
+            .iter()
+            .map(|d| (d.line, d.message.key.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn test_import_surge() {
+        let result = convert(SURGE, ImportFormat::Surge).unwrap();
+        let config = &result.config;
+
+        assert_eq!(config.logging.level, "info");
+        assert_eq!(config.dns.servers, vec!["223.5.5.5", "1.1.1.1"]);
+        assert_eq!(
+            config.general.skip_proxy,
+            vec!["127.0.0.1/32", "192.168.0.0/16"]
+        );
+        assert_eq!(config.proxies.len(), 2);
+        assert_eq!(config.proxies[0].username.as_deref(), Some("alice"));
+        assert_eq!(config.proxies[0].password, Some("secret".into()));
+
+        let groups: Vec<_> = config
+            .proxy_groups
+            .iter()
+            .map(|g| g.name.as_str())
+            .collect();
+        assert_eq!(groups, vec!["Auto", "Main"]);
+        assert_eq!(config.proxy_groups[0].proxies, vec!["Tokyo"]);
+        assert_eq!(config.proxy_groups[1].kind, GroupKind::Select);
+        assert_eq!(config.proxy_groups[1].proxies, vec!["Auto", "DIRECT"]);
+
+        assert_eq!(
+            config.rules,
+            vec![
+                "DOMAIN-SUFFIX, google.com, Auto",
+                "IP-CIDR, 10.0.0.0/8, DIRECT",
+                "DOMAIN, ads.example.com, REJECT",
+                "FINAL, Main",
+            ]
+        );
+        assert_eq!(config.dns.hosts.len(), 1);
+
+        let diagnostics = keys(&result);
+        for expected in [
+            (Some(3), "import.invalid_value"),
+            (Some(4), "import.invalid_value"),
+            (Some(5), "import.unsupported_setting"),
+            (Some(9), "import.unsupported_proxy"),
+            (Some(10), "import.unsupported_option"),
+            (Some(13), "import.unsupported_option"),
+            (Some(13), "import.dropped_member"),
+            (Some(14), "import.dropped_member"),
+            (Some(14), "import.empty_group"),
+            (Some(15), "import.unsupported_group"),
+            (Some(15), "import.dropped_member"),
+            (Some(19), "import.unsupported_rule"),
+            (Some(21), "import.rejected_as_reject"),
+            (Some(22), "import.unknown_policy"),
+            (Some(23), "import.unsupported_option"),
+            (Some(27), "import.invalid_value"),
+            (Some(29), "import.unsupported_section"),
+        ] {
+            assert!(
+                diagnostics.contains(&expected),
+                "missing {:?} in {:?}",
+                expected,
+                diagnostics
+            );
+        }
+        assert_eq!(diagnostics.len(), 17, "{:?}", diagnostics);
+
+        // The result loads as a regular configuration
+        let yaml = config.to_yaml().unwrap();
+        let reloaded = VoyageConfig::parse(&yaml, crate::profile::ConfigFormat::Yaml).unwrap();
+        assert_eq!(&reloaded, config);
+    }
+
+    #[test]
+    fn test_import_clash() {
+        let clash = "\
+port: 7890
+log-level: warning
+dns:
+  enable: true
+  enhanced-mode: redir-host
+  nameserver:
+    - 114.114.114.114
+    - https://doh.example.com/dns-query
+proxies:
+  - name: Home
+    type: socks5
+    server: 10.0.0.2
+    port: 1080
+    udp: true
+  - name: VMess
+    type: vmess
+    server: v.example.com
+    port: 443
+proxy-groups:
+  - name: Proxy
+    type: select
+    proxies: [Home, VMess]
+rules:
+  - DOMAIN-SUFFIX,example.org,Proxy
+  - RULE-SET,ads,REJECT
+  - MATCH,DIRECT
+";
+        let result = convert(clash, ImportFormat::Clash).unwrap();
+        let config = &result.config;
+        assert_eq!(config.logging.level, "warning");
+        assert!(!config.dns.fake_ip);
+        assert_eq!(config.dns.servers, vec!["114.114.114.114"]);
+        assert_eq!(config.proxies.len(), 1);
+        assert_eq!(config.proxy_groups[0].name, "Proxy (imported)");
+        assert_eq!(config.proxy_groups[0].proxies, vec!["Home"]);
+        assert_eq!(
+            config.rules,
+            vec![
+                "DOMAIN-SUFFIX, example.org, Proxy (imported)",
+                "FINAL, DIRECT"
+            ]
+        );
+        assert_eq!(
+            config.rules_text(),
+            "DOMAIN-SUFFIX, example.org, PROXY\nFINAL, DIRECT"
+        );
+
+        let diagnostics = keys(&result);
+        assert_eq!(
+            diagnostics,
+            vec![
+                (Some(1), "import.unsupported_setting"),
+                (Some(8), "import.invalid_value"),
+                (Some(10), "import.unsupported_option"),
+                (Some(15), "import.unsupported_proxy"),
+                (Some(20), "import.renamed"),
+                (Some(25), "import.unsupported_rule"),
+                (Some(20), "import.dropped_member"),
+            ]
+        );
+        assert_eq!(
+            result.diagnostics[3].message.render(),
+            "Proxy VMess of type vmess is not supported and was skipped"
+        );
+
+        let err = convert("proxies: [", ImportFormat::Clash).unwrap_err();
+        assert!(matches!(err, VoyageError::ConfigSyntax { .. }), "{:?}", err);
+    }
+}
@@ -0,0 +1,1069 @@
+//! Local Proxy Inbound
+//!
+//! This module listens on localhost as a mixed SOCKS5 and HTTP proxy, so
+//! the core can run as a regular system proxy on macOS without a TUN
+//! device. Each request is routed by the same rules as tunnelled flows:
+//! DIRECT requests are dialed from this machine, PROXY requests go through
+//! the configured SOCKS5 server and REJECT requests are refused.
+//!
+//! The first byte tells the protocols apart: SOCKS5 greetings start with
+//! version 5, anything else is read as an HTTP request. HTTP clients may
+//! tunnel with `CONNECT host:port` or send plain requests with an absolute
+//! URI, which are forwarded in origin form.
+//!
+//! For development on Linux the same pipeline can also take connections
+//! diverted by an iptables `REDIRECT` rule (`start_redirect`). Their
+//! original destination is read back from the kernel and the hostname
+//! sniffed from the first TLS or HTTP bytes, so real apps and CI tests
+//! exercise routing without the iOS extension:
+//!
+//! ```text
+//! iptables -t nat -A OUTPUT -p tcp -m owner ! --uid-owner voyage \
+//!     -j REDIRECT --to-ports 7892
+//! ```
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener as StdTcpListener};
+use std::sync::{Arc, RwLock};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Notify;
+
+use crate::dial;
+use crate::error::VoyageError;
+use crate::history::CloseReason;
+use crate::nat::NatKey;
+use crate::outbound;
+use crate::proxy::RoutingDecision;
+use crate::rewrite::{self, HttpHead, UrlMode};
+use crate::rule::{MatchContext, RouteAction};
+use crate::sniff::{self, HTTP_PORT, TLS_PORT};
+use crate::socks5::{
+    AddressType, AuthMethod, Command, HandshakeStage, ReplyCode, Socks5ErrorKind, TargetAddr,
+};
+use crate::tap::{TapSession, TapStream};
+use crate::VoyageCore;
+
+/// SOCKS5 version byte
+const SOCKS5_VERSION: u8 = 0x05;
+
+/// Longest HTTP request head accepted
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// How long a client may take to say where it wants to go
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long dialing the destination or upstream proxy may take
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a redirected connection may take to send its first bytes
+/// before it is routed by IP alone
+const SNIFF_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// Bytes peeked from a redirected connection for sniffing
+const SNIFF_BUFFER: usize = 2048;
+
+/// Handle to the inbound listener; stopping (or dropping) it closes the
+/// socket and every relayed connection
+pub struct InboundServer {
+    port: u16,
+    stop: Arc<Notify>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl InboundServer {
+    /// Accept SOCKS5 and HTTP proxy requests for `core` on
+    /// 127.0.0.1:`port` (0 picks a free port)
+    pub fn start(core: Arc<RwLock<VoyageCore>>, port: u16) -> io::Result<Self> {
+        let listener = StdTcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, port)))?;
+        Self::spawn(core, listener, false)
+    }
+
+    /// Accept connections diverted to `addr` by an iptables `REDIRECT`
+    /// rule and relay them to their original destination. Connections
+    /// that were not redirected are closed. Linux only.
+    pub fn start_redirect(core: Arc<RwLock<VoyageCore>>, addr: SocketAddr) -> io::Result<Self> {
+        if !cfg!(target_os = "linux") {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "Redirect inbound needs Linux netfilter",
+            ));
+        }
+        Self::spawn(core, StdTcpListener::bind(addr)?, true)
+    }
+
+    fn spawn(
+        core: Arc<RwLock<VoyageCore>>,
+        listener: StdTcpListener,
+        transparent: bool,
+    ) -> io::Result<Self> {
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let port = addr.port();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .enable_time()
+            .build()?;
+        let stop = Arc::new(Notify::new());
+        let stopped = Arc::clone(&stop);
+
+        let thread = std::thread::Builder::new()
+            .name("voyage-inbound".into())
+            .spawn(move || {
+                runtime.block_on(async move {
+                    let listener = match TcpListener::from_std(listener) {
+                        Ok(listener) => listener,
+                        Err(e) => {
+                            log::error!("Inbound listener failed: {}", e);
+                            return;
+                        }
+                    };
+                    let accept = tokio::spawn(async move {
+                        loop {
+                            match listener.accept().await {
+                                Ok((stream, peer)) => {
+                                    let core = Arc::clone(&core);
+                                    tokio::spawn(async move {
+                                        let served = serve(stream, peer, core, transparent);
+                                        if let Err(e) = served.await {
+                                            log::debug!("Inbound from {}: {}", peer, e);
+                                        }
+                                    });
+                                }
+                                Err(e) => log::warn!("Inbound accept failed: {}", e),
+                            }
+                        }
+                    });
+                    stopped.notified().await;
+                    accept.abort();
+                });
+            })?;
+
+        if transparent {
+            log::info!("Redirect inbound on {}", addr);
+        } else {
+            log::info!("SOCKS5/HTTP inbound on {}", addr);
+        }
+        Ok(Self {
+            port,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Port the listener is bound to
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Close the listener and every relayed connection
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.stop.notify_one();
+            let _ = thread.join();
+            log::debug!("Inbound listener stopped");
+        }
+    }
+}
+
+impl Drop for InboundServer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// How the client asked for its destination
+enum Handshake {
+    Socks5,
+    /// `CONNECT`: answer with 200 once connected
+    HttpConnect,
+    /// Plain request: send the rewritten head upstream first
+    HttpForward(Vec<u8>),
+    /// Redirected by the kernel: the client already thinks it is connected
+    Transparent,
+}
+
+fn io_error(e: io::Error) -> VoyageError {
+    VoyageError::io(e.to_string())
+}
+
+async fn serve(
+    mut client: TcpStream,
+    peer: SocketAddr,
+    core: Arc<RwLock<VoyageCore>>,
+    transparent: bool,
+) -> Result<(), VoyageError> {
+    let (mut target, mut handshake, sniffed) = if transparent {
+        let target = redirected_target(&client)?;
+        let sniffed = sniff(&client, target.port()).await;
+        (target, Handshake::Transparent, sniffed)
+    } else {
+        let (target, handshake) = tokio::time::timeout(HANDSHAKE_TIMEOUT, read_target(&mut client))
+            .await
+            .map_err(|_| VoyageError::io("Timed out waiting for the request".into()))??;
+        (target, handshake, None)
+    };
+    if let Handshake::HttpForward(head) = &mut handshake {
+        if let Some(response) = rewrite_request(&core, &mut target, head)? {
+            return client.write_all(&response).await.map_err(io_error);
+        }
+    }
+
+    // The TUN flow this connection carries, if the app dialed an address
+    let flow = match &target {
+        TargetAddr::Ip(addr) => Some(NatKey::tcp(peer, *addr)),
+        TargetAddr::Domain(..) => None,
+    };
+    let decision = route(&core, &target, sniffed.as_deref(), peer.port())?;
+    // The proxy server resolves sniffed names itself; DIRECT keeps the
+    // address the app already picked
+    let target = match sniffed {
+        Some(domain) if decision.action == RouteAction::Proxy => {
+            TargetAddr::Domain(domain, target.port())
+        }
+        _ => target,
+    };
+    log::debug!(
+        "Inbound {} -> {}:{} via {:?}",
+        peer,
+        decision.destination_host().unwrap_or_default(),
+        target.port(),
+        decision.action
+    );
+
+    let mut upstream = match tokio::time::timeout(CONNECT_TIMEOUT, dial(&core, &decision, &target))
+        .await
+        .unwrap_or_else(|_| Err(VoyageError::io("Timed out connecting".into())))
+    {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            if decision.action == RouteAction::Proxy {
+                if let (Some(flow), Ok(core)) = (flow, core.read()) {
+                    core.conn_manager.set_close_reason(&flow, CloseReason::ProxyFailed);
+                }
+            }
+            refuse(&mut client, &handshake, &decision, &core).await;
+            return Err(e);
+        }
+    };
+    if decision.nodelay {
+        let _ = upstream.set_nodelay(true);
+    }
+    let _own_socket = OwnSocket::register(&core, &upstream);
+
+    match &handshake {
+        Handshake::Socks5 => reply_socks5(&mut client, ReplyCode::Succeeded).await?,
+        Handshake::HttpConnect => client
+            .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+            .await
+            .map_err(io_error)?,
+        Handshake::HttpForward(head) => upstream.write_all(head).await.map_err(io_error)?,
+        Handshake::Transparent => {}
+    }
+
+    let (sent, received) = relay(&mut client, &mut upstream, &core, &decision).await;
+    if decision.action == RouteAction::Proxy {
+        let core = core.read().map_err(|_| VoyageError::LockError)?;
+        core.proxy_manager().add_proxy_bytes_sent(sent);
+        core.proxy_manager().add_proxy_bytes_received(received);
+    }
+    Ok(())
+}
+
+/// Copy bytes both ways until either side closes, through the TLS
+/// interceptor when the host is on its list and mirroring to the traffic
+/// tap when the host is on that one
+async fn relay(
+    client: &mut TcpStream,
+    upstream: &mut TcpStream,
+    core: &RwLock<VoyageCore>,
+    decision: &RoutingDecision,
+) -> (u64, u64) {
+    let tap = tap_for(core, decision);
+    #[cfg(feature = "mitm")]
+    if let Some((interceptor, host)) = interceptor_for(core, decision) {
+        let rules = core
+            .read()
+            .map(|core| core.rewrite.clone())
+            .unwrap_or_default();
+        return interceptor
+            .intercept(client, upstream, &host, &rules, tap)
+            .await
+            .unwrap_or_else(|e| {
+                log::debug!("Interception of {} failed: {}", host, e);
+                (0, 0)
+            });
+    }
+    let copied = match tap {
+        Some(tap) => {
+            let mut upstream = TapStream::new(upstream, Some(tap));
+            tokio::io::copy_bidirectional(client, &mut upstream).await
+        }
+        None => tokio::io::copy_bidirectional(client, upstream).await,
+    };
+    copied.unwrap_or_default()
+}
+
+/// A tap session for the flow, when a traffic tap is set and the host is
+/// on its list
+fn tap_for(core: &RwLock<VoyageCore>, decision: &RoutingDecision) -> Option<TapSession> {
+    let tap = core.read().ok()?.tap.clone()?;
+    let host = decision.destination_host()?;
+    tap.should_tap(&host)
+        .then(|| tap.session(&host, decision.dst_port))
+}
+
+/// The interceptor and host name, when the flow is TLS to a host the
+/// interceptor is configured for
+#[cfg(feature = "mitm")]
+fn interceptor_for(
+    core: &RwLock<VoyageCore>,
+    decision: &RoutingDecision,
+) -> Option<(Arc<crate::mitm::Interceptor>, String)> {
+    if decision.dst_port != TLS_PORT {
+        return None;
+    }
+    let host = decision.domain.clone()?;
+    let interceptor = core.read().ok()?.mitm.clone()?;
+    interceptor
+        .should_intercept(&host)
+        .then_some((interceptor, host))
+}
+
+/// Keeps an upstream socket's local port known to the routing loop guard
+/// while the relay runs
+struct OwnSocket<'a> {
+    core: &'a RwLock<VoyageCore>,
+    port: u16,
+}
+
+impl<'a> OwnSocket<'a> {
+    fn register(core: &'a RwLock<VoyageCore>, upstream: &TcpStream) -> Option<Self> {
+        let port = upstream.local_addr().ok()?.port();
+        core.read().ok()?.proxy_manager().register_own_socket(port);
+        Some(Self { core, port })
+    }
+}
+
+impl Drop for OwnSocket<'_> {
+    fn drop(&mut self) {
+        if let Ok(core) = self.core.read() {
+            core.proxy_manager().release_own_socket(self.port);
+        }
+    }
+}
+
+/// Route a request through the active rules, counting it in the stats
+fn route(
+    core: &RwLock<VoyageCore>,
+    target: &TargetAddr,
+    sniffed: Option<&str>,
+    src_port: u16,
+) -> Result<RoutingDecision, VoyageError> {
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+
+    let flow = match target {
+        TargetAddr::Ip(addr) => MatchContext::new(sniffed, Some(addr.ip()), addr.port()),
+        TargetAddr::Domain(domain, port) => MatchContext::new(Some(domain), None, *port),
+    };
+    let decision = core.proxy_manager().evaluate_route(&flow.with_source(None, src_port));
+    Ok(decision)
+}
+
+/// Open the upstream connection for a routed request
+async fn dial(
+    core: &RwLock<VoyageCore>,
+    decision: &RoutingDecision,
+    target: &TargetAddr,
+) -> Result<TcpStream, VoyageError> {
+    match decision.action {
+        RouteAction::Direct => {
+            let options = core
+                .read()
+                .map_err(|_| VoyageError::LockError)?
+                .config
+                .outbound;
+            match target {
+                TargetAddr::Ip(addr) => outbound::connect(*addr, &options).await,
+                TargetAddr::Domain(domain, port) => {
+                    dial::connect_host(domain, *port, &options).await
+                }
+            }
+            .map_err(io_error)
+        }
+        RouteAction::Proxy => {
+            let client = core
+                .read()
+                .map_err(|_| VoyageError::LockError)?
+                .socks5_client()?;
+            client.connect(target.clone()).await
+        }
+        RouteAction::Reject => Err(VoyageError::connection(format!(
+            "Rejected by rule {}",
+            decision.matched_rule.as_deref().unwrap_or("FINAL")
+        ))),
+    }
+}
+
+/// Tell the client its destination could not be reached. Rejected plain
+/// HTTP requests get the reject page so the user sees why.
+async fn refuse(
+    client: &mut TcpStream,
+    handshake: &Handshake,
+    decision: &RoutingDecision,
+    core: &RwLock<VoyageCore>,
+) {
+    let rejected = decision.action == RouteAction::Reject;
+    let page = || {
+        let template = core.read().map(|core| core.config.reject_page.clone());
+        reject_response(&template.unwrap_or_default(), decision)
+    };
+    let _ = match handshake {
+        Handshake::HttpForward(_) if rejected => client.write_all(&page()).await.map_err(io_error),
+        Handshake::Transparent if rejected && decision.dst_port == HTTP_PORT => {
+            discard_request_head(client).await;
+            client.write_all(&page()).await.map_err(io_error)
+        }
+        Handshake::Socks5 => {
+            let code = if rejected {
+                ReplyCode::ConnectionNotAllowed
+            } else {
+                ReplyCode::HostUnreachable
+            };
+            reply_socks5(client, code).await
+        }
+        // Closing is all a redirected client can be told
+        Handshake::Transparent => Ok(()),
+        _ => {
+            let status: &[u8] = if rejected {
+                b"HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            } else {
+                b"HTTP/1.1 502 Bad Gateway\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"
+            };
+            client.write_all(status).await.map_err(io_error)
+        }
+    };
+}
+
+/// 403 response carrying the reject page, with `{host}` and `{rule}` filled
+/// in from the decision
+fn reject_response(template: &str, decision: &RoutingDecision) -> Vec<u8> {
+    let host = escape_html(&decision.destination_host().unwrap_or_default());
+    let rule = escape_html(decision.matched_rule.as_deref().unwrap_or("FINAL"));
+    // Substitute in one pass so a placeholder inside the host stays literal
+    let body = template
+        .split("{host}")
+        .map(|part| part.replace("{rule}", &rule))
+        .collect::<Vec<_>>()
+        .join(&host);
+    format!(
+        "HTTP/1.1 403 Forbidden\r\nContent-Type: text/html; charset=utf-8\r\n\
+         Content-Length: {}\r\nCache-Control: no-store\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Read the rest of a redirected request head, which sniffing only peeked
+/// at; closing with unread data would reset the connection before the
+/// client sees the reply
+async fn discard_request_head(client: &mut TcpStream) {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") && head.len() < MAX_HEAD_SIZE {
+        match tokio::time::timeout(SNIFF_TIMEOUT, client.read(&mut buf)).await {
+            Ok(Ok(n)) if n > 0 => head.extend_from_slice(&buf[..n]),
+            _ => return,
+        }
+    }
+}
+
+/// Where a redirected connection was headed before netfilter diverted it
+fn redirected_target(client: &TcpStream) -> Result<TargetAddr, VoyageError> {
+    let local = client.local_addr().map_err(io_error)?;
+    let original = original_destination(client).map_err(io_error)?;
+    // Without a REDIRECT rule the kernel reports the listener itself;
+    // relaying there would loop
+    if original == local {
+        return Err(VoyageError::connection(
+            "Connection was not redirected".into(),
+        ));
+    }
+    Ok(TargetAddr::Ip(original))
+}
+
+#[cfg(target_os = "linux")]
+fn original_destination(stream: &TcpStream) -> io::Result<SocketAddr> {
+    use std::os::unix::io::AsRawFd;
+
+    /// `SO_ORIGINAL_DST` and `IP6T_SO_ORIGINAL_DST` from the netfilter
+    /// headers, which libc does not export
+    const SO_ORIGINAL_DST: libc::c_int = 80;
+
+    let fd = stream.as_raw_fd();
+    if stream.local_addr()?.is_ipv4() {
+        // SAFETY: all-zero is a valid sockaddr_in
+        let mut addr: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+        // SAFETY: addr and len describe a writable sockaddr_in owned by
+        // this frame, and fd stays open while `stream` is borrowed
+        let rc = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_IP,
+                SO_ORIGINAL_DST,
+                &mut addr as *mut libc::sockaddr_in as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if rc != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+        Ok(SocketAddr::new(ip.into(), u16::from_be(addr.sin_port)))
+    } else {
+        // SAFETY: all-zero is a valid sockaddr_in6
+        let mut addr: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+        // SAFETY: as above, for a sockaddr_in6
+        let rc = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_IPV6,
+                SO_ORIGINAL_DST,
+                &mut addr as *mut libc::sockaddr_in6 as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if rc != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+        Ok(SocketAddr::new(ip.into(), u16::from_be(addr.sin6_port)))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn original_destination(_stream: &TcpStream) -> io::Result<SocketAddr> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Redirect inbound needs Linux netfilter",
+    ))
+}
+
+/// Hostname from the first bytes of a TLS or HTTP connection, if they
+/// arrive in time; the bytes stay queued for the relay
+async fn sniff(client: &TcpStream, port: u16) -> Option<String> {
+    if port != TLS_PORT && port != HTTP_PORT {
+        return None;
+    }
+    let mut buf = [0u8; SNIFF_BUFFER];
+    let n = tokio::time::timeout(SNIFF_TIMEOUT, client.peek(&mut buf))
+        .await
+        .ok()?
+        .ok()?;
+    sniff::sniff_domain(port, &buf[..n])
+}
+
+/// Read the client's handshake up to the point where it names its
+/// destination
+async fn read_target(client: &mut TcpStream) -> Result<(TargetAddr, Handshake), VoyageError> {
+    let mut first = [0u8; 1];
+    client.read_exact(&mut first).await.map_err(io_error)?;
+    if first[0] == SOCKS5_VERSION {
+        let target = read_socks5_target(client).await?;
+        return Ok((target, Handshake::Socks5));
+    }
+
+    let mut head = first.to_vec();
+    let mut buf = [0u8; 4096];
+    let end = loop {
+        if let Some(end) = head.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+        if head.len() > MAX_HEAD_SIZE {
+            return Err(VoyageError::connection("HTTP request head too long".into()));
+        }
+        let n = client.read(&mut buf).await.map_err(io_error)?;
+        if n == 0 {
+            return Err(VoyageError::connection(
+                "Connection closed before request".into(),
+            ));
+        }
+        head.extend_from_slice(&buf[..n]);
+    };
+    let text = String::from_utf8_lossy(&head[..end]).into_owned();
+    let (target, rewritten) = parse_http_request(&text)?;
+    match rewritten {
+        None => Ok((target, Handshake::HttpConnect)),
+        Some(rewritten) => {
+            // Body bytes that arrived with the head go out after it
+            let mut forward = rewritten.into_bytes();
+            forward.extend_from_slice(&head[end..]);
+            Ok((target, Handshake::HttpForward(forward)))
+        }
+    }
+}
+
+/// Destination of an HTTP proxy request, and for plain requests the head
+/// to send upstream: origin-form target, proxy headers dropped and the
+/// connection closed after one response, since the next request on a kept
+/// alive connection may be for another host
+fn parse_http_request(head: &str) -> Result<(TargetAddr, Option<String>), VoyageError> {
+    let malformed = || VoyageError::connection("Malformed HTTP proxy request".into());
+    let mut lines = head.split("\r\n");
+    let mut parts = lines.next().ok_or_else(malformed)?.split_whitespace();
+    let (method, uri, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(uri), Some(version)) => (method, uri, version),
+        _ => return Err(malformed()),
+    };
+
+    if method.eq_ignore_ascii_case("CONNECT") {
+        let target = parse_authority(uri, 443).ok_or_else(malformed)?;
+        return Ok((target, None));
+    }
+
+    let rest = uri.strip_prefix("http://").ok_or_else(|| {
+        VoyageError::connection(format!("Unsupported proxy request URI: {}", uri))
+    })?;
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+    let target = parse_authority(authority, 80).ok_or_else(malformed)?;
+
+    let mut rewritten = format!("{} {} {}\r\n", method, path, version);
+    for line in lines.filter(|line| !line.is_empty()) {
+        let name = line.split(':').next().unwrap_or_default().trim();
+        let dropped = [
+            "proxy-connection",
+            "proxy-authorization",
+            "connection",
+            "keep-alive",
+        ];
+        if !dropped
+            .iter()
+            .any(|header| name.eq_ignore_ascii_case(header))
+        {
+            rewritten.push_str(line);
+            rewritten.push_str("\r\n");
+        }
+    }
+    rewritten.push_str("Connection: close\r\n\r\n");
+    Ok((target, Some(rewritten)))
+}
+
+/// Apply the rewrite rules to a plain HTTP request about to be forwarded.
+/// A redirect comes back as the response to give the app; a transparent
+/// rewrite changes the destination and the head in place.
+fn rewrite_request(
+    core: &RwLock<VoyageCore>,
+    target: &mut TargetAddr,
+    forward: &mut Vec<u8>,
+) -> Result<Option<Vec<u8>>, VoyageError> {
+    let core = core.read().map_err(|_| VoyageError::LockError)?;
+    if core.rewrite.is_empty() {
+        return Ok(None);
+    }
+    let Some(end) = forward.windows(4).position(|w| w == b"\r\n\r\n") else {
+        return Ok(None);
+    };
+    let Some(mut head) = HttpHead::parse(&String::from_utf8_lossy(&forward[..end])) else {
+        return Ok(None);
+    };
+
+    let url = format!("http://{}{}", url_authority(target), head.path());
+    match core.rewrite.apply(&url, &mut head) {
+        Some((UrlMode::Redirect, location)) => {
+            return Ok(Some(rewrite::redirect_response(&location)))
+        }
+        Some((UrlMode::Transparent, to)) => {
+            let destination = rewrite::split_url(&to)
+                .filter(|(scheme, _, _)| scheme.eq_ignore_ascii_case("http"))
+                .and_then(|(_, authority, path)| {
+                    Some((parse_authority(authority, HTTP_PORT)?, authority, path))
+                });
+            match destination {
+                Some((new_target, authority, path)) => {
+                    *target = new_target;
+                    head.set_path(path);
+                    head.set_header("Host", authority);
+                }
+                None => log::warn!("Cannot send a plain HTTP request to {}", to),
+            }
+        }
+        None => {}
+    }
+    let mut rewritten = head.to_bytes();
+    rewritten.extend_from_slice(&forward[end + 4..]);
+    *forward = rewritten;
+    Ok(None)
+}
+
+/// Host and, unless it is 80, port of `target` as written in a URL
+fn url_authority(target: &TargetAddr) -> String {
+    let (host, port) = match target {
+        TargetAddr::Domain(domain, port) => (domain.clone(), *port),
+        TargetAddr::Ip(SocketAddr::V4(addr)) => (addr.ip().to_string(), addr.port()),
+        TargetAddr::Ip(SocketAddr::V6(addr)) => (format!("[{}]", addr.ip()), addr.port()),
+    };
+    if port == HTTP_PORT {
+        host
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+/// Parse `host`, `host:port`, `1.2.3.4:port` or `[v6]:port`
+fn parse_authority(authority: &str, default_port: u16) -> Option<TargetAddr> {
+    if let Ok(addr) = authority.parse::<SocketAddr>() {
+        return Some(TargetAddr::Ip(addr));
+    }
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') || host.ends_with(']') => {
+            (host, port.parse().ok()?)
+        }
+        _ => (authority, default_port),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return None;
+    }
+    Some(match host.parse::<IpAddr>() {
+        Ok(ip) => TargetAddr::Ip(SocketAddr::new(ip, port)),
+        Err(_) => TargetAddr::Domain(host.to_ascii_lowercase(), port),
+    })
+}
+
+/// Finish a SOCKS5 greeting (version byte already read) and read the
+/// CONNECT request
+async fn read_socks5_target(client: &mut TcpStream) -> Result<TargetAddr, VoyageError> {
+    let mut count = [0u8; 1];
+    client.read_exact(&mut count).await.map_err(io_error)?;
+    let mut methods = vec![0u8; count[0] as usize];
+    client.read_exact(&mut methods).await.map_err(io_error)?;
+    if !methods.contains(&(AuthMethod::NoAuth as u8)) {
+        let _ = client
+            .write_all(&[SOCKS5_VERSION, AuthMethod::NoAcceptable as u8])
+            .await;
+        return Err(VoyageError::socks5(
+            HandshakeStage::Greeting,
+            Socks5ErrorKind::NoAcceptableMethod,
+        ));
+    }
+    client
+        .write_all(&[SOCKS5_VERSION, AuthMethod::NoAuth as u8])
+        .await
+        .map_err(io_error)?;
+
+    let mut header = [0u8; 4];
+    client.read_exact(&mut header).await.map_err(io_error)?;
+    if header[0] != SOCKS5_VERSION {
+        return Err(VoyageError::socks5(HandshakeStage::Connect, Socks5ErrorKind::BadVersion));
+    }
+    if header[1] != Command::Connect as u8 {
+        reply_socks5(client, ReplyCode::CommandNotSupported).await?;
+        return Err(VoyageError::socks5(
+            HandshakeStage::Connect,
+            Socks5ErrorKind::UnsupportedCommand { command: header[1] },
+        ));
+    }
+
+    let target = match header[3] {
+        t if t == AddressType::IPv4 as u8 => {
+            let mut addr = [0u8; 6];
+            client.read_exact(&mut addr).await.map_err(io_error)?;
+            let ip = Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]);
+            TargetAddr::Ip(SocketAddr::new(
+                ip.into(),
+                u16::from_be_bytes([addr[4], addr[5]]),
+            ))
+        }
+        t if t == AddressType::IPv6 as u8 => {
+            let mut addr = [0u8; 18];
+            client.read_exact(&mut addr).await.map_err(io_error)?;
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addr[..16]);
+            let port = u16::from_be_bytes([addr[16], addr[17]]);
+            TargetAddr::Ip(SocketAddr::new(Ipv6Addr::from(octets).into(), port))
+        }
+        t if t == AddressType::DomainName as u8 => {
+            let mut len = [0u8; 1];
+            client.read_exact(&mut len).await.map_err(io_error)?;
+            let mut domain = vec![0u8; len[0] as usize + 2];
+            client.read_exact(&mut domain).await.map_err(io_error)?;
+            let port = u16::from_be_bytes([domain[len[0] as usize], domain[len[0] as usize + 1]]);
+            domain.truncate(len[0] as usize);
+            let domain = String::from_utf8_lossy(&domain).to_ascii_lowercase();
+            match domain.parse::<IpAddr>() {
+                Ok(ip) => TargetAddr::Ip(SocketAddr::new(ip, port)),
+                Err(_) => TargetAddr::Domain(domain, port),
+            }
+        }
+        other => {
+            reply_socks5(client, ReplyCode::AddressTypeNotSupported).await?;
+            return Err(VoyageError::socks5(
+                HandshakeStage::Connect,
+                Socks5ErrorKind::UnsupportedAddress { atyp: other },
+            ));
+        }
+    };
+    Ok(target)
+}
+
+/// Send a SOCKS5 reply with an unspecified bound address
+async fn reply_socks5(client: &mut TcpStream, code: ReplyCode) -> Result<(), VoyageError> {
+    client
+        .write_all(&[
+            SOCKS5_VERSION,
+            code as u8,
+            0x00,
+            AddressType::IPv4 as u8,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ])
+        .await
+        .map_err(io_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener as StdTcpListener, TcpStream as StdTcpStream};
+
+    use crate::config::ProxyConfig;
+    use crate::rewrite::RewriteEngine;
+
+    /// Listener that answers each connection with what it read, prefixed
+    fn echo_server() -> u16 {
+        let listener = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut buf = [0u8; 1024];
+                let n = stream.read(&mut buf).unwrap_or(0);
+                let _ = stream.write_all(b"echo:");
+                let _ = stream.write_all(&buf[..n]);
+            }
+        });
+        port
+    }
+
+    fn read_all(stream: &mut StdTcpStream) -> String {
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_parse_http_request() {
+        let (target, rewritten) = parse_http_request(
+            "GET http://Example.com:8080/a?b HTTP/1.1\r\nHost: example.com\r\n\
+             Proxy-Connection: keep-alive\r\n\r\n",
+        )
+        .unwrap();
+        assert!(matches!(target, TargetAddr::Domain(ref d, 8080) if d == "example.com"));
+        assert_eq!(
+            rewritten.unwrap(),
+            "GET /a?b HTTP/1.1\r\nHost: example.com\r\nConnection: close\r\n\r\n"
+        );
+
+        let (target, rewritten) = parse_http_request("CONNECT [::1]:443 HTTP/1.1\r\n\r\n").unwrap();
+        assert!(matches!(target, TargetAddr::Ip(addr) if addr.port() == 443));
+        assert!(rewritten.is_none());
+        assert!(parse_http_request("GET /relative HTTP/1.1\r\n\r\n").is_err());
+        assert!(parse_authority("example.com", 80).is_some_and(|t| t.port() == 80));
+    }
+
+    #[test]
+    fn test_inbound_routes_requests() {
+        let echo = echo_server();
+        let core = Arc::new(RwLock::new(VoyageCore::new(ProxyConfig::default())));
+        core.write()
+            .unwrap()
+            .load_rules("DOMAIN,blocked.test,REJECT\nFINAL,DIRECT")
+            .unwrap();
+        let server = InboundServer::start(Arc::clone(&core), 0).unwrap();
+
+        // SOCKS5 CONNECT to the echo server, routed DIRECT
+        let mut stream = StdTcpStream::connect(("127.0.0.1", server.port())).unwrap();
+        stream.write_all(&[5, 1, 0]).unwrap();
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(reply, [5, 0]);
+        let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+        request.extend_from_slice(&echo.to_be_bytes());
+        stream.write_all(&request).unwrap();
+        let mut reply = [0u8; 10];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(reply[1], ReplyCode::Succeeded as u8);
+        stream.write_all(b"hello").unwrap();
+        assert_eq!(read_all(&mut stream), "echo:hello");
+
+        // HTTP CONNECT
+        let mut stream = StdTcpStream::connect(("127.0.0.1", server.port())).unwrap();
+        write!(stream, "CONNECT 127.0.0.1:{} HTTP/1.1\r\n\r\n", echo).unwrap();
+        let mut reply = [0u8; 39];
+        stream.read_exact(&mut reply).unwrap();
+        assert!(reply.starts_with(b"HTTP/1.1 200"));
+        stream.write_all(b"tunnel").unwrap();
+        assert_eq!(read_all(&mut stream), "echo:tunnel");
+
+        // Plain HTTP is forwarded in origin form
+        let mut stream = StdTcpStream::connect(("127.0.0.1", server.port())).unwrap();
+        write!(stream, "GET http://127.0.0.1:{}/x HTTP/1.1\r\n\r\n", echo).unwrap();
+        assert_eq!(
+            read_all(&mut stream),
+            "echo:GET /x HTTP/1.1\r\nConnection: close\r\n\r\n"
+        );
+
+        // Rejected by rule
+        let mut stream = StdTcpStream::connect(("127.0.0.1", server.port())).unwrap();
+        stream
+            .write_all(b"CONNECT blocked.test:443 HTTP/1.1\r\n\r\n")
+            .unwrap();
+        assert!(read_all(&mut stream).starts_with("HTTP/1.1 403"));
+
+        let stats = core.read().unwrap().proxy_manager().get_stats().clone();
+        assert_eq!(stats.direct_connections, 3);
+        assert_eq!(stats.rejected_connections, 1);
+        server.stop();
+    }
+
+    #[test]
+    fn test_proxy_failure_recorded_on_flow() {
+        // Nothing listens on the proxy port
+        let unused = StdTcpListener::bind("127.0.0.1:0").unwrap();
+        let config = ProxyConfig {
+            server_host: "127.0.0.1".into(),
+            server_port: unused.local_addr().unwrap().port(),
+            ..ProxyConfig::default()
+        };
+        drop(unused);
+        let core = Arc::new(RwLock::new(VoyageCore::new(config)));
+        core.write().unwrap().load_rules("FINAL,PROXY").unwrap();
+        let server = InboundServer::start(Arc::clone(&core), 0).unwrap();
+
+        let mut stream = StdTcpStream::connect(("127.0.0.1", server.port())).unwrap();
+        let app = stream.local_addr().unwrap();
+        let target: SocketAddr = "127.0.0.2:443".parse().unwrap();
+        let flow = NatKey::tcp(app, target);
+        let syn = crate::create_tcp_packet([127, 0, 0, 1], [127, 0, 0, 2], app.port(), 443, true);
+        let syn = crate::packet::ParsedPacket::parse(&syn).unwrap();
+        core.write().unwrap().conn_manager.process_packet(&syn).unwrap();
+
+        write!(stream, "CONNECT {} HTTP/1.1\r\n\r\n", target).unwrap();
+        assert!(read_all(&mut stream).starts_with("HTTP/1.1 502"));
+        let core = core.read().unwrap();
+        core.conn_manager.remove_connection(&flow).unwrap();
+        let closed = core.conn_manager.recent_connections(1).remove(0);
+        assert_eq!(closed.reason, CloseReason::ProxyFailed);
+        drop(core);
+        server.stop();
+    }
+
+    #[test]
+    fn test_reject_page() {
+        let decision = RoutingDecision::reject(80)
+            .with_domain("<ads>.test")
+            .with_rule("ads");
+        let response = reject_response("{host} by {rule}", &decision);
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        assert!(response.contains("Content-Length: 23\r\n"));
+        assert!(response.ends_with("\r\n\r\n&lt;ads&gt;.test by ads"));
+
+        let core = Arc::new(RwLock::new(VoyageCore::new(ProxyConfig::default())));
+        core.write().unwrap().config.reject_page = "Blocked {host}".into();
+        core.write()
+            .unwrap()
+            .load_rules("DOMAIN,blocked.test,REJECT")
+            .unwrap();
+        let server = InboundServer::start(Arc::clone(&core), 0).unwrap();
+        let mut stream = StdTcpStream::connect(("127.0.0.1", server.port())).unwrap();
+        stream
+            .write_all(b"GET http://blocked.test/ HTTP/1.1\r\n\r\n")
+            .unwrap();
+        let response = read_all(&mut stream);
+        assert!(response.starts_with("HTTP/1.1 403"));
+        assert!(response.ends_with("Blocked blocked.test"));
+        server.stop();
+    }
+
+    #[test]
+    fn test_rewrite_rules() {
+        let echo = echo_server();
+        let core = Arc::new(RwLock::new(VoyageCore::new(ProxyConfig::default())));
+        core.write().unwrap().load_rules("FINAL,DIRECT").unwrap();
+        core.write().unwrap().rewrite = RewriteEngine::from_config(&format!(
+            "URL-REWRITE, http://old.test/*, https://new.test/$1, 302\n\
+             URL-REWRITE, http://moved.test/*, http://127.0.0.1:{}/v2/$1, TRANSPARENT\n\
+             HEADER-REWRITE, *, DEL, Cookie",
+            echo
+        ))
+        .unwrap();
+        let server = InboundServer::start(Arc::clone(&core), 0).unwrap();
+
+        let mut stream = StdTcpStream::connect(("127.0.0.1", server.port())).unwrap();
+        stream
+            .write_all(b"GET http://old.test/a?b HTTP/1.1\r\n\r\n")
+            .unwrap();
+        let response = read_all(&mut stream);
+        assert!(response.starts_with("HTTP/1.1 302 Found\r\n"));
+        assert!(response.contains("Location: https://new.test/a?b\r\n"));
+
+        let mut stream = StdTcpStream::connect(("127.0.0.1", server.port())).unwrap();
+        stream
+            .write_all(b"GET http://moved.test/x HTTP/1.1\r\nCookie: id=1\r\n\r\n")
+            .unwrap();
+        assert_eq!(
+            read_all(&mut stream),
+            format!(
+                "echo:GET /v2/x HTTP/1.1\r\nConnection: close\r\nHost: 127.0.0.1:{}\r\n\r\n",
+                echo
+            )
+        );
+        assert_eq!(
+            url_authority(&TargetAddr::Domain("a.test".into(), 80)),
+            "a.test"
+        );
+        server.stop();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_redirect_closes_direct_connections() {
+        let core = Arc::new(RwLock::new(VoyageCore::new(ProxyConfig::default())));
+        let addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        let server = InboundServer::start_redirect(Arc::clone(&core), addr).unwrap();
+
+        // Connecting straight to the listener has no original destination
+        // to relay to
+        let mut stream = StdTcpStream::connect(("127.0.0.1", server.port())).unwrap();
+        assert_eq!(read_all(&mut stream), "");
+        let stats = core.read().unwrap().proxy_manager().get_stats().clone();
+        assert_eq!(stats.direct_connections, 0);
+        server.stop();
+    }
+}
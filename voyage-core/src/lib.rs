@@ -4,106 +4,1635 @@
 //! for userspace TCP/IP stack processing.
 
 // Public modules
+pub mod admission;
+pub mod api;
 pub mod config;
 pub mod connection;
 pub mod device;
+pub mod dial;
+pub mod dns;
+pub mod dnsrule;
+pub mod driver;
+pub mod engine;
 pub mod error;
+pub mod event;
+pub mod fakeip;
+pub mod flowlog;
 pub mod ffi;
+pub mod geoip;
+pub mod geosite;
+pub mod guard;
+pub mod history;
+pub mod hosts;
 pub mod iface;
+pub mod import;
+pub mod inbound;
+pub mod lint;
+pub mod logging;
+pub mod maintenance;
+pub mod memory;
+pub mod message;
+pub mod metrics;
+#[cfg(feature = "mitm")]
+pub mod mitm;
 pub mod nat;
+pub mod network;
+pub mod outbound;
 pub mod packet;
 pub mod proxy;
+pub mod quarantine;
+pub mod querylog;
+pub mod profile;
+pub mod profile_store;
+pub mod rate;
+pub mod rewrite;
 pub mod rule;
+pub mod secret;
+pub mod selftest;
+pub mod shaping;
+pub mod shard;
+pub mod sim;
+pub mod sniff;
 pub mod socks5;
+pub mod stats;
+pub mod tap;
+pub mod traffic;
+pub mod usage;
 
 // Re-exports for convenience
-pub use config::ProxyConfig;
-pub use connection::{ConnectionInfo, ConnectionManager, ConnectionState};
-pub use device::{PacketQueue, VirtualTunDevice, MTU};
+pub use admission::{Admission, AdmissionControl, QueuedFlow};
+pub use api::ApiServer;
+pub use config::{
+    BufferTier, ChecksumMode, ConcurrencyLimits, ConnectionRateLimits, DnsConfig, DrainPolicy,
+    DropPolicy, ExcessPolicy, FakeIpConfig, InterfaceAddress, InterfaceConfig, MssClampConfig,
+    NatConfig, OutboundConfig, ProxyConfig, ProxyProtocol, QueueConfig, ResourceLimits, TcpConfig,
+};
+pub use connection::{ConnectionInfo, ConnectionManager, ConnectionState, FlowDump, RelayStatus};
+pub use device::{
+    BufferPool, DeviceStats, DeviceStatsReader, PacketInjector, PacketQueue, PacketSink,
+    PoolStats, PooledBuffer, QueueDirection, VirtualTunDevice, WatermarkCallback, MTU,
+};
+pub use dns::{DnsCache, DnsMessage, DnsPlan, DnsResolver, DnsStats, DomainMap};
+pub use dnsrule::{DnsAction, DnsRule, DnsRuleSet};
+pub use driver::{InterfaceDriver, PollWaker, SharedInterface};
+pub use engine::{EngineState, EngineStatus};
 pub use error::VoyageError;
-pub use iface::InterfaceManager;
-pub use nat::{NatEntry, NatKey, NatManager, NatState};
-pub use packet::{IpPacketInfo, ParsedPacket, TcpFlags, TcpPacketInfo, UdpPacketInfo};
-pub use proxy::{ProxyManager, ProxyStats, RoutingDecision};
-pub use rule::{FfiRouteAction, RouteAction, Rule, RuleEngine, RuleType};
-pub use socks5::{Socks5Client, TargetAddr};
+pub use memory::{GaugeRegistry, MemoryStats, QueueRegistry, SocketBufferGauge};
+pub use event::{ConnectionEvent, ConnectionEventKind, EventBus, EventForwarder};
+pub use fakeip::{FakeIpPool, Ipv4Range};
+pub use flowlog::{FlowLogger, FlowRecord, RotatingFile};
+pub use geoip::GeoIpDb;
+pub use geosite::{GeoSite, GeoSiteDb, GeoSiteMatcher};
+pub use guard::ConnectionGuard;
+pub use history::{CloseReason, ClosedConnection, ConnectionHistory};
+pub use hosts::{HostEntry, HostTable};
+pub use iface::{InterfaceManager, SharedInterfaceConfig};
+pub use import::{ImportDiagnostic, ImportFormat, ImportResult};
+pub use inbound::InboundServer;
+pub use lint::{ConfigDiagnostic, Severity};
+pub use logging::{LogLevel, LogRecord};
+pub use maintenance::{MaintenanceReport, MaintenanceStats, MaintenanceTask};
+pub use profile::{
+    substitute_variables, ConfigDiff, ConfigFormat, OutboundSettings, VoyageConfig,
+};
+pub use profile_store::{ProfileInfo, ProfileManager};
+pub use message::{LocalizedMessage, MessageTemplate};
+pub use nat::{
+    FlowDirection, NatEntry, NatKey, NatManager, NatMode, NatShared, NatState, NatTimeouts,
+    PortStrategy,
+};
+pub use network::{NetworkInterface, NetworkPath};
+pub use packet::{
+    build_udp_packet, clamp_tcp_mss, IcmpPacketInfo, IpPacketInfo, ParseErrorKind, ParsedPacket,
+    TcpFlags, TcpPacketInfo, UdpPacketInfo,
+};
+pub use proxy::{
+    FlowFacts, ProxyManager, ProxyStats, RouteComparison, RouteDivergence, RouteScript,
+    RoutingDecision,
+};
+pub use quarantine::{MalformedPacket, PacketQuarantine};
+pub use querylog::{DnsQueryLog, DnsQueryRecord};
+pub use rate::RateMeter;
+pub use rewrite::{HeaderAction, HttpHead, RewriteAction, RewriteEngine, RewriteRule, UrlMode};
+pub use rule::{FfiRouteAction, MatchContext, RouteAction, Rule, RuleEngine, RuleType};
+pub use secret::SecretString;
+pub use selftest::SelfTestResult;
+pub use shard::{ShardedConnections, CONNECTION_SHARDS};
+pub use shaping::{FlowLimiter, ShapingScope, ShapingStats, TokenBucket, TrafficShaper};
+pub use sim::{Segment, SimClock, SimPeer, Simulation};
+pub use socks5::{HandshakeStage, ReplyCode, Socks5Client, Socks5ErrorKind, TargetAddr};
+pub use stats::SharedStats;
+pub use tap::{TapChunk, TapDirection, TapSession, TapStream, TrafficTap};
+pub use traffic::{TrafficHistory, TrafficRecorder, TrafficResolution, TrafficSample};
+pub use usage::{Usage, UsageTable};
 
 // FFI exports
 pub use ffi::{
-    add_bytes_received, add_bytes_sent, clear_rules, disable_proxy, enable_proxy,
-    evaluate_route, get_stats, init_core, is_initialized, is_proxy_enabled, load_rules,
-    process_inbound_packet, process_outbound_packet, rule_count, shutdown_core, CoreStats,
+    add_bytes_received, add_bytes_sent, add_profile, begin_drain, clear_candidate_rules,
+    clear_connection_event_listener, clear_dns_query_log, clear_dns_rules,
+    clear_engine_state_listener, clear_flow_log, clear_geoip, clear_geosite, clear_hosts,
+    clear_log_callback, clear_malformed_packets, clear_packet_writer, clear_rewrite_rules,
+    clear_rules, clear_script_handler, clear_traffic_tap, close_connection, disable_proxy,
+    drain_events, dump_flows_json, enable_proxy, error_code, error_message, evaluate_route,
+    evaluate_route_async, flush_dns_cache, get_active_connections, get_connections,
+    get_device_stats, get_dns_query_log, get_dns_stats, get_engine_state, get_fake_ip_range,
+    get_interface_config, get_malformed_packets, get_memory_stats, get_message_catalog,
+    get_metrics_text, get_nat_timeouts, get_recent_connections, get_route_comparison,
+    get_shaping_stats, get_stats, get_stats_by_app, get_stats_by_domain, get_stats_by_policy,
+    get_stats_by_source, get_traffic_history, import_config, init_core, is_initialized,
+    is_proxy_enabled, last_error_message, list_profiles, load_candidate_rules, load_dns_rules,
+    load_geoip, load_geosite, load_hosts, load_rewrite_rules, load_rules, load_rules_async,
+    on_network_changed, on_sleep, on_wake, process_dns_packet, process_inbound_packet,
+    process_inbound_packets, process_outbound_packet, process_outbound_packets, reload_config,
+    remove_profile, resolve_dns_query, rule_count, run_self_test, select_proxy, set_block_quic,
+    set_concurrency_limits, set_connection_annotation, set_connection_app,
+    set_connection_event_listener, set_connection_rate_limits, set_drain_policy,
+    set_engine_state_listener, set_fake_ip_range, set_flow_log_callback, set_flow_log_file,
+    set_global_rate_limit, set_interface_config, set_local_networks, set_log_callback,
+    set_max_connections, set_memory_budget, set_nat_port_strategy, set_nat_table_size,
+    set_nat_timeouts, set_packet_writer, set_policy_rate_limit, set_script_handler,
+    set_tcp_buffer_sizes, set_tcp_buffer_tiers, set_traffic_tap_callback, set_traffic_tap_file,
+    set_udp_nat_mode, shaping_delay, shutdown_core, start_api_server, start_engine,
+    start_inbound_server, start_metrics_server, stop_api_server, stop_engine, stop_inbound_server,
+    stop_metrics_server, switch_profile, test_proxy_latency_async, update_proxy_config,
+    validate_config, voyage_inject_inbound_packet, ConnectionEventListener, CoreStats,
+    EngineStateListener, FfiBufferTier, FfiClosedConnection, FfiConcurrencyLimits, FfiConnection,
+    FfiConnectionEvent, FfiConnectionFilter, FfiImportResult, FfiInterfaceConfig,
+    FfiRouteComparison, FfiRouteDivergence, FfiUsageStats, FlowLogSink, INJECT_QUEUED,
+    INJECT_QUEUE_FULL, INJECT_REJECTED, INJECT_UNAVAILABLE, LogSink, PacketWriter, ScriptContext,
+    ScriptHandler, TrafficTapSink,
 };
 
+use std::collections::VecDeque;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+
+use smoltcp::iface::SocketHandle;
+use tokio::runtime::{Handle, Runtime};
+
+/// Maximum number of warning events kept until the host drains them
+pub const MAX_PENDING_EVENTS: usize = 64;
 
-/// The main core engine
+/// Worker threads of the core's runtime
+pub const RUNTIME_WORKERS: usize = 2;
+
+/// The main core engine.
+///
+/// Packet processing, rule loading and the relays' accounting take `&self`,
+/// so they run under a read lock of the core; the parts they change are
+/// locked on their own. Locks are taken in the order admission, guard,
+/// proxy manager, connection shard.
 pub struct VoyageCore {
     /// Proxy configuration
     pub config: ProxyConfig,
-    /// Connection manager
-    pub conn_manager: ConnectionManager,
-    /// Proxy manager
-    pub proxy_manager: ProxyManager,
+    /// Connection table, sharded so flows are processed in parallel
+    pub conn_manager: ShardedConnections,
+    /// Routing rules and their statistics
+    proxy_manager: Mutex<ProxyManager>,
+    /// Fake-IP allocations for DNS answers
+    pub fake_ip_pool: FakeIpPool,
+    /// Built-in DNS forwarder
+    pub dns: DnsResolver,
+    /// Networks the device is attached to, as reported by the host
+    local_networks: Vec<Ipv4Range>,
+    /// Warnings waiting to be picked up by the host
+    events: VecDeque<LocalizedMessage>,
+    /// Background cleanup totals
+    maintenance: MaintenanceStats,
+    /// Sockets of cleaned-up flows, closed on the next poll of the
+    /// engine's interface
+    orphaned_sockets: Mutex<Vec<SocketHandle>>,
+    /// Interface the engine runs, created by `start_interface`
+    interface: Option<SharedInterface>,
+    /// Task polling `interface`
+    driver: Option<InterfaceDriver>,
+    /// Counters readable without the core lock
+    stats: Arc<SharedStats>,
+    /// Where interfaces created by the core write outbound packets
+    packet_sink: Option<PacketSink>,
+    /// Packet queues of interfaces created by the core
+    packet_queues: QueueRegistry,
+    /// Socket buffers of interfaces created by the core
+    socket_buffers: GaugeRegistry,
+    /// Rx queue of the interface last created by `new_interface`, fed by
+    /// `inject_inbound`
+    injector: Option<PacketInjector>,
+    /// Packet buffers shared by interfaces created by the core
+    buffer_pool: BufferPool,
+    /// Counters of the devices of interfaces created by the core
+    devices: Mutex<Vec<DeviceStatsReader>>,
+    /// Addressing followed by interfaces created by the core
+    interface_config: Arc<SharedInterfaceConfig>,
+    /// Configuration file the core runs, for diffing on reload
+    profile: Option<VoyageConfig>,
+    /// Named configurations the core can switch between
+    profiles: ProfileManager,
+    /// Bandwidth limits enforced by the relays
+    pub shaper: TrafficShaper,
+    /// Concurrency caps and the flows queued under them
+    admission: Mutex<AdmissionControl>,
+    /// Rate limits on new connections and the sources blocked by them
+    guard: Mutex<ConnectionGuard>,
+    /// Recent inbound packets the parser rejected
+    quarantine: Mutex<PacketQuarantine>,
+    /// HEADER-REWRITE and URL-REWRITE rules of the inbound proxy
+    pub rewrite: RewriteEngine,
+    /// TLS interception of whitelisted hosts by the inbound proxy
+    #[cfg(feature = "mitm")]
+    pub mitm: Option<Arc<mitm::Interceptor>>,
+    /// Mirrors relayed streams of allow-listed hosts to a consumer
+    pub tap: Option<Arc<TrafficTap>>,
+    /// Last resolved address of a proxy server given by hostname
+    proxy_addr: Option<SocketAddr>,
+    /// Network path last reported by the host
+    network_path: Option<NetworkPath>,
+    /// When the host reported the device going to sleep
+    asleep_since: Option<Instant>,
+    /// When an ongoing drain gives up on the flows still running
+    drain_deadline: Option<Instant>,
+    /// Runtime of the background work and the async FFI calls, started on
+    /// first use
+    runtime: Option<Runtime>,
 }
 
 impl VoyageCore {
     /// Create a new VoyageCore with the given configuration
     pub fn new(config: ProxyConfig) -> Self {
-        log::info!(
-            "Creating VoyageCore with proxy: {}:{}",
-            config.server_host,
-            config.server_port
-        );
+        log::info!("Creating VoyageCore with proxy: {}", config);
 
-        let proxy_manager = ProxyManager::with_config(config.clone());
+        let proxy_manager = Mutex::new(ProxyManager::with_config(config.clone()));
+        let fake_ip_pool = FakeIpPool::new(config.fake_ip.range);
+        let dns = DnsResolver::new(config.dns.clone());
+        let interface_config = Arc::new(SharedInterfaceConfig::new(config.interface.clone()));
+        let admission = Mutex::new(AdmissionControl::new(config.concurrency));
+        let guard = Mutex::new(ConnectionGuard::new(config.connection_rate));
+        let mut conn_manager = ShardedConnections::with_nat_config(&config.nat);
+        conn_manager.set_connection_limit(config.connection_cap());
+        conn_manager.set_relay_idle_timeout(config.outbound.idle_timeout());
 
         Self {
             config,
-            conn_manager: ConnectionManager::new(),
+            conn_manager,
             proxy_manager,
+            fake_ip_pool,
+            dns,
+            local_networks: Vec::new(),
+            events: VecDeque::new(),
+            maintenance: MaintenanceStats::default(),
+            orphaned_sockets: Mutex::new(Vec::new()),
+            interface: None,
+            driver: None,
+            stats: Arc::new(SharedStats::new()),
+            packet_sink: None,
+            packet_queues: QueueRegistry::new(),
+            socket_buffers: GaugeRegistry::new(),
+            injector: None,
+            buffer_pool: BufferPool::default(),
+            devices: Mutex::new(Vec::new()),
+            interface_config,
+            profile: None,
+            profiles: ProfileManager::new(),
+            shaper: TrafficShaper::new(),
+            admission,
+            guard,
+            quarantine: Mutex::default(),
+            rewrite: RewriteEngine::new(),
+            #[cfg(feature = "mitm")]
+            mitm: None,
+            tap: None,
+            proxy_addr: None,
+            network_path: None,
+            asleep_since: None,
+            drain_deadline: None,
+            runtime: None,
+        }
+    }
+
+    /// Create a core from a YAML, TOML or JSON configuration file, loading
+    /// its rules and skip-proxy networks and applying its log level
+    pub fn from_config_str(text: &str) -> Result<Self, VoyageError> {
+        let file = VoyageConfig::parse_auto(text)?;
+        let mut core = Self::new(file.to_proxy_config()?);
+        core.load_rules(&file.rules_text())?;
+        core.rewrite = file.rewrite_engine()?;
+        let networks = file.skip_proxy_networks()?;
+        if !networks.is_empty() {
+            core.set_local_networks(networks);
+        }
+        log::set_max_level(file.log_level()?.into());
+        core.profile = Some(file);
+        Ok(core)
+    }
+
+    /// Switch to a new configuration file, applying only what changed.
+    ///
+    /// Everything is checked before anything is applied. Rules are swapped
+    /// in one step; connections keep running unless the server PROXY
+    /// traffic goes through changed, in which case proxied flows are
+    /// drained. A core not created from a configuration file has all rules
+    /// replaced. A status event lists the changed settings.
+    pub fn reload_config(&mut self, file: VoyageConfig) -> Result<ConfigDiff, VoyageError> {
+        file.validate("")?;
+        let proxy = file.to_proxy_config()?;
+        let rules = RuleEngine::parse_config(&file.rules_text())?;
+        let rewrite = file.rewrite_engine()?;
+        let networks = file.skip_proxy_networks()?;
+        let level = file.log_level()?;
+        let mut interface = self.config.interface.clone();
+        interface.mtu = proxy.interface.mtu;
+        interface.validate().map_err(VoyageError::config)?;
+
+        let diff = match &self.profile {
+            Some(current) => current.diff(&file),
+            None => ConfigDiff {
+                rules: true,
+                ..VoyageConfig::default().diff(&file)
+            },
+        };
+
+        if diff.rules {
+            let count = self.replace_rules(rules);
+            log::info!("Reloaded {} rules", count);
+        }
+        if diff.upstream {
+            let protocol = self.config.protocol;
+            let drained = self.update_proxy_server(
+                proxy.server_host,
+                proxy.server_port,
+                proxy.username,
+                proxy.password,
+                protocol,
+                true,
+            )?;
+            log::info!("Drained {} flows of the previous proxy server", drained);
+        }
+        if diff.dns {
+            let mut dns = self.dns.config().clone();
+            dns.upstreams = proxy.dns.upstreams;
+            dns.proxy_upstream = proxy.dns.proxy_upstream;
+            dns.fake_ip = proxy.dns.fake_ip;
+            dns.hosts = proxy.dns.hosts;
+            dns.search_domains = proxy.dns.search_domains;
+            dns.local_upstream = proxy.dns.local_upstream;
+            self.config.dns = dns.clone();
+            self.dns.set_config(dns);
+            // Routing consults the search domains
+            self.proxy_manager().set_config(self.config.clone());
+            if proxy.fake_ip.range != self.config.fake_ip.range {
+                self.set_fake_ip_range(proxy.fake_ip.range);
+            }
+        }
+        if diff.skip_proxy && !networks.is_empty() {
+            self.set_local_networks(networks);
+        }
+        if diff.mtu {
+            self.set_interface_config(interface)?;
+        }
+        if diff.outbound {
+            // Applies to connections opened from now on
+            self.config.outbound = proxy.outbound;
+            self.conn_manager.set_relay_idle_timeout(proxy.outbound.idle_timeout());
+        }
+        if diff.reject_page {
+            self.config.reject_page = proxy.reject_page;
+        }
+        if diff.block_quic {
+            // Applies to flows opened from now on
+            self.config.block_quic = proxy.block_quic;
+        }
+        if diff.rewrite {
+            log::info!("Reloaded {} rewrite rules", rewrite.len());
+            self.rewrite = rewrite;
+        }
+        if diff.log_level {
+            log::set_max_level(level.into());
         }
+
+        if !diff.is_empty() {
+            let sections = diff.sections().join(", ");
+            log::info!("Configuration reloaded, changed: {}", sections);
+            self.push_event(LocalizedMessage::new("status.config_reloaded", vec![sections]));
+        }
+        self.profile = Some(file);
+        Ok(diff)
+    }
+
+    /// Select `member` in the proxy group `group` of the configuration
+    /// file, manually or on failover.
+    ///
+    /// If that changes the server PROXY traffic goes through, new flows use
+    /// the new server right away; flows relayed through the old one are
+    /// drained when the group sets `interrupt-existing-connections` and keep
+    /// running otherwise. Returns how many flows were drained.
+    pub fn select_proxy(&mut self, group: &str, member: &str) -> Result<usize, VoyageError> {
+        let current = self
+            .profile
+            .as_ref()
+            .ok_or_else(|| VoyageError::config("No configuration file loaded".into()))?;
+        let mut file = current.clone();
+        let interrupt = file.select_proxy(group, member)?;
+        file.validate("")?;
+        let diff = current.diff(&file);
+        let proxy = file.to_proxy_config()?;
+        self.profile = Some(file);
+        log::info!("Proxy group {} selected {}", group, member);
+
+        if !diff.upstream {
+            return Ok(0);
+        }
+        let protocol = self.config.protocol;
+        let drained = self.update_proxy_server(
+            proxy.server_host,
+            proxy.server_port,
+            proxy.username,
+            proxy.password,
+            protocol,
+            interrupt,
+        )?;
+        if drained > 0 {
+            log::info!("Interrupted {} flows of the previous proxy server", drained);
+        }
+        Ok(drained)
+    }
+
+    /// Store a named configuration to switch to later
+    pub fn add_profile(&mut self, name: &str, file: VoyageConfig) -> Result<(), VoyageError> {
+        self.profiles.insert(name, file)
+    }
+
+    /// Forget a named configuration other than the active one
+    pub fn remove_profile(&mut self, name: &str) -> Result<(), VoyageError> {
+        self.profiles.remove(name)
+    }
+
+    /// Run the named configuration, applying what differs from the current
+    /// one as `reload_config` does. Nothing changes if it fails.
+    pub fn switch_profile(&mut self, name: &str) -> Result<ConfigDiff, VoyageError> {
+        let file = self.profiles.get(name)?.clone();
+        let diff = self.reload_config(file)?;
+        let current = self.proxy_manager().get_stats().clone();
+        self.profiles.activate(name, &current)?;
+        log::info!("Switched to profile {}", name);
+        Ok(diff)
+    }
+
+    /// Stored profiles with the traffic routed while each was active
+    pub fn profiles(&self) -> Vec<ProfileInfo> {
+        self.profiles.list(self.proxy_manager().get_stats())
+    }
+
+    /// Routing rules and their statistics. Hold the guard only briefly:
+    /// packet processing waits on it to route new flows.
+    pub fn proxy_manager(&self) -> MutexGuard<'_, ProxyManager> {
+        lock(&self.proxy_manager)
     }
 
     /// Load routing rules from a configuration string
-    pub fn load_rules(&mut self, rules_text: &str) -> Result<usize, VoyageError> {
-        self.proxy_manager.load_rules(rules_text)
+    pub fn load_rules(&self, rules_text: &str) -> Result<usize, VoyageError> {
+        self.proxy_manager().load_rules(rules_text)
+    }
+
+    /// Match GEOSITE rules against `db` (`None` disables them), returning
+    /// the categories they name that `db` lacks
+    pub fn set_geosite(&mut self, db: Option<Arc<GeoSiteDb>>) -> Vec<String> {
+        self.proxy_manager().set_geosite(db)
+    }
+
+    /// Tell routing scripts destination countries from `db` (`None` stops)
+    pub fn set_geoip(&mut self, db: Option<Arc<GeoIpDb>>) {
+        self.proxy_manager().set_geoip(db);
+    }
+
+    /// Replace the routing rules and the bandwidth limits they set,
+    /// returning how many rules are active
+    pub fn replace_rules(&mut self, rules: Vec<Rule>) -> usize {
+        self.shaper.clear_rule_limits();
+        self.proxy_manager().replace_rules(rules)
     }
 
     /// Evaluate routing for a domain
     pub fn should_proxy_domain(&mut self, domain: &str) -> bool {
-        let decision = self.proxy_manager.evaluate_route(Some(domain), None, 443, 0);
+        let decision = self
+            .proxy_manager()
+            .evaluate_route(&MatchContext::new(Some(domain), None, 443));
         matches!(decision.action, RouteAction::Proxy)
     }
 
     /// Get current statistics
     pub fn get_stats(&self) -> CoreStats {
+        let proxy = self.proxy_manager().get_stats().clone();
+        let guard = lock(&self.guard);
         CoreStats {
             bytes_sent: self.conn_manager.total_bytes_sent(),
             bytes_received: self.conn_manager.total_bytes_received(),
             active_connections: self.conn_manager.active_connections() as u64,
             total_connections: self.conn_manager.total_connections(),
+            reaped_flows: self.conn_manager.reaped_flows(),
+            nat_evictions: self.conn_manager.nat_evictions(),
+            nat_source_limit_hits: self.conn_manager.nat_source_limit_hits(),
+            nat_port_collisions: self.conn_manager.nat_port_collisions(),
+            nat_invalid_transitions: self.conn_manager.nat_invalid_transitions(),
+            expired_flows: self.maintenance.expired_flows,
+            maintenance_runs: self.maintenance.runs,
+            upload_rate: self.conn_manager.upload_rate(),
+            download_rate: self.conn_manager.download_rate(),
+            memory_usage: self.memory_usage() as u64,
+            memory_budget: self.config.limits.memory_budget as u64,
+            socket_buffer_bytes: self.socket_buffers.total_bytes() as u64,
+            connection_limit_hits: self.conn_manager.connection_limit_hits(),
+            waiting_connections: lock(&self.admission).queue_len() as u64,
+            limit_rejected_connections: proxy.limit_rejected_connections,
+            route_cache_hits: proxy.route_cache_hits,
+            route_cache_misses: proxy.route_cache_misses,
+            quic_blocked: proxy.quic_blocked,
+            rate_limited_connections: guard.dropped(),
+            rate_limit_blocks: guard.blocks(),
+            malformed_packets: lock(&self.quarantine).total(),
+        }
+    }
+
+    /// Estimated memory used by the tracked flows, each sized by the
+    /// buffer tier of its destination port
+    pub fn memory_usage(&self) -> usize {
+        self.conn_manager
+            .sum_flows(|key| self.config.flow_memory_to(Some(key.dst_port)))
+    }
+
+    /// Estimated heap usage broken down by owner
+    pub fn memory_stats(&self) -> MemoryStats {
+        MemoryStats {
+            socket_buffers: self.socket_buffers.total_bytes() as u64,
+            nat_entries: self.conn_manager.nat_bytes() as u64,
+            queued_packets: self.packet_queues.queued_bytes() as u64,
+            dns_cache: self.dns.cache_bytes() as u64,
+            buffer_pool: self.buffer_pool.stats().idle_bytes as u64,
+            total: 0,
+            budget: self.config.limits.memory_budget as u64,
+        }
+        .with_total()
+    }
+
+    /// Change the connection and memory caps; flows beyond them keep
+    /// running, but new ones are refused until usage drops
+    pub fn set_resource_limits(&mut self, limits: ResourceLimits) {
+        self.config.limits = limits;
+        self.conn_manager.set_connection_limit(self.config.connection_cap());
+    }
+
+    /// Change the socket buffer sizes of flows opened afterwards, on the
+    /// engine's interface and on interfaces created later; the memory
+    /// budget then admits a different number of flows
+    pub fn set_tcp_buffer_sizes(&mut self, rx_buffer_size: usize, tx_buffer_size: usize) {
+        self.config.tcp.rx_buffer_size = rx_buffer_size;
+        self.config.tcp.tx_buffer_size = tx_buffer_size;
+        self.conn_manager.set_connection_limit(self.config.connection_cap());
+        self.push_tcp_config();
+    }
+
+    /// Hand the socket tuning to the engine's interface, if running
+    fn push_tcp_config(&self) {
+        if let Some(iface) = &self.interface {
+            if let Ok(mut iface) = iface.lock() {
+                iface.set_tcp_config(self.config.tcp.clone());
+            }
+        }
+    }
+
+    /// Size the socket buffers of flows to the listed ports by tier, for
+    /// flows opened afterwards on the engine's interface and on interfaces
+    /// created later; other flows keep the default sizes
+    pub fn set_tcp_buffer_tiers(&mut self, tiers: Vec<BufferTier>) {
+        self.config.tcp.buffer_tiers = tiers;
+        self.push_tcp_config();
+    }
+
+    /// Change the NAT table capacity, closing the least recently active
+    /// flows if the table is larger
+    pub fn set_nat_table_size(&mut self, max_entries: usize) {
+        self.config.nat.max_entries = max_entries;
+        self.conn_manager.set_nat_max_entries(max_entries);
+        let evicted = self.conn_manager.reap_orphaned();
+        self.release_sockets(evicted);
+    }
+
+    /// Deliver outbound packets of the engine's interface, and of
+    /// interfaces created afterwards by `new_interface`, to `sink`
+    pub fn set_packet_sink(&mut self, sink: Option<PacketSink>) {
+        if let Some(iface) = &self.interface {
+            if let Ok(mut iface) = iface.lock() {
+                iface.set_packet_sink(sink.clone());
+            }
+        }
+        self.packet_sink = sink;
+    }
+
+    /// The registered packet sink, if any
+    pub fn packet_sink(&self) -> Option<PacketSink> {
+        self.packet_sink.clone()
+    }
+
+    /// Create a smoltcp interface tuned by the core's configuration and
+    /// writing through the registered packet sink. Packets passed to
+    /// `inject_inbound` go to the interface created last.
+    pub fn new_interface(&mut self) -> InterfaceManager {
+        let mut iface = InterfaceManager::from_proxy_config(&self.config);
+        iface.set_packet_sink(self.packet_sink.clone());
+        iface.set_buffer_pool(self.buffer_pool.clone());
+        iface.follow_config(Arc::clone(&self.interface_config));
+        for queue in iface.packet_queues() {
+            self.packet_queues.register(&queue);
+        }
+        self.socket_buffers.register(&iface.buffer_gauge());
+        self.injector = Some(iface.packet_injector());
+        if let Ok(mut devices) = self.devices.lock() {
+            devices.push(iface.device_stats_reader());
+        }
+        iface
+    }
+
+    /// Create the engine's interface and drive it on the core's runtime.
+    ///
+    /// Packets passed to `inject_inbound` go to it, and the sockets of
+    /// flows the core closes are aborted on its next poll. Does nothing if
+    /// the interface is already running.
+    pub fn start_interface(core: &Arc<RwLock<VoyageCore>>) -> Result<(), VoyageError> {
+        let mut this = core.write().map_err(|_| VoyageError::LockError)?;
+        if this.driver.is_some() {
+            return Ok(());
+        }
+        let runtime = this.runtime()?;
+        let iface = Arc::new(Mutex::new(this.new_interface()));
+        let driver =
+            InterfaceDriver::spawn_for_core(Arc::downgrade(core), Arc::clone(&iface), &runtime);
+        this.interface = Some(iface);
+        this.driver = Some(driver);
+        log::info!("Engine interface started");
+        Ok(())
+    }
+
+    /// The interface the engine runs, if started
+    pub fn interface(&self) -> Option<SharedInterface> {
+        self.interface.clone()
+    }
+
+    /// One poll of the engine's interface by its driver: close the sockets
+    /// of finished flows, open listeners for new ones and let smoltcp run.
+    /// Returns smoltcp's next timer.
+    pub fn poll_interface(&self, iface: &mut InterfaceManager) -> Option<Duration> {
+        let orphaned = std::mem::take(&mut *lock(&self.orphaned_sockets));
+        if !orphaned.is_empty() {
+            iface.close_orphaned(&orphaned);
+            log::debug!("Closed {} sockets of finished flows", orphaned.len());
+        }
+        self.conn_manager.poll_interface(iface);
+        iface.poll_delay()
+    }
+
+    /// Queue sockets of removed flows for closing and wake the driver
+    fn release_sockets(&self, sockets: impl IntoIterator<Item = SocketHandle>) {
+        let mut orphaned = lock(&self.orphaned_sockets);
+        orphaned.extend(sockets);
+        if let Some(driver) = &self.driver {
+            if !orphaned.is_empty() {
+                driver.waker().wake();
+            }
+        }
+    }
+
+    /// Device counters summed over the live interfaces created by the core;
+    /// interfaces that were dropped no longer count
+    pub fn device_stats(&self) -> DeviceStats {
+        let mut total = DeviceStats::default();
+        if let Ok(mut devices) = self.devices.lock() {
+            devices.retain(|device| match device.read() {
+                Some(stats) => {
+                    total.merge(&stats);
+                    true
+                }
+                None => false,
+            });
+        }
+        total
+    }
+
+    /// Buffers shared by interfaces created by the core; packets the host
+    /// is done with can be recycled into it
+    pub fn buffer_pool(&self) -> &BufferPool {
+        &self.buffer_pool
+    }
+
+    /// Change the addressing of the virtual interface; interfaces created
+    /// by the core apply it on their next poll
+    pub fn set_interface_config(&mut self, config: InterfaceConfig) -> Result<(), VoyageError> {
+        config.validate().map_err(VoyageError::config)?;
+        self.interface_config.set(config.clone());
+        self.config.interface = config;
+        Ok(())
+    }
+
+    /// Counters published by `publish_stats`, for polling without the core lock
+    pub fn shared_stats(&self) -> Arc<SharedStats> {
+        Arc::clone(&self.stats)
+    }
+
+    /// Refresh the lock-free copy of the statistics
+    pub fn publish_stats(&self) {
+        self.stats.publish(&self.get_stats());
+    }
+
+    /// Refresh the lock-free copy of the statistics unless that was done
+    /// within `stats::PUBLISH_INTERVAL`
+    fn publish_stats_throttled(&self) {
+        if self.stats.publish_due() {
+            self.publish_stats();
+        }
+    }
+
+    /// Handle of the core's runtime, starting it on first use
+    pub fn runtime(&mut self) -> Result<Handle, VoyageError> {
+        if let Some(runtime) = &self.runtime {
+            return Ok(runtime.handle().clone());
+        }
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(RUNTIME_WORKERS)
+            .thread_name("voyage-core")
+            .enable_all()
+            .build()
+            .map_err(|e| VoyageError::io(format!("Failed to start runtime: {}", e)))?;
+        let handle = runtime.handle().clone();
+        self.runtime = Some(runtime);
+        Ok(handle)
+    }
+
+    /// Handle of the core's runtime, if it is running
+    pub fn runtime_handle(&self) -> Option<Handle> {
+        self.runtime.as_ref().map(|runtime| runtime.handle().clone())
+    }
+
+    /// Stop the runtime, cancelling everything spawned on it
+    pub fn stop_runtime(&mut self) {
+        if let Some(runtime) = self.runtime.take() {
+            // This may run on one of the runtime's own threads (a drain
+            // stopping the engine), where waiting for them would deadlock
+            runtime.shutdown_background();
+            log::debug!("Core runtime stopped");
+        }
+    }
+
+    /// Expire idle NAT entries and reap dead flows.
+    ///
+    /// Called periodically by the background `MaintenanceTask`; the sockets
+    /// of removed flows are closed by the engine's interface.
+    pub fn run_maintenance(&mut self) -> &MaintenanceStats {
+        let started = Instant::now();
+        let report = self
+            .conn_manager
+            .each_shard(|shard| maintenance::run_once(shard, None))
+            .into_iter()
+            .fold(MaintenanceReport::default(), |mut total, report| {
+                total.expired_flows += report.expired_flows;
+                total.orphaned_sockets.extend(report.orphaned_sockets);
+                total
+            });
+        self.admit_queued();
+        lock(&self.guard).prune(Instant::now());
+        self.maintenance.record(&report, started.elapsed());
+        if report.expired_flows > 0 || !report.orphaned_sockets.is_empty() {
+            log::debug!(
+                "Maintenance removed {} flows, {} sockets to close",
+                report.expired_flows,
+                report.orphaned_sockets.len()
+            );
+        }
+        self.release_sockets(report.orphaned_sockets);
+        self.publish_stats();
+        &self.maintenance
+    }
+
+    /// Background cleanup totals
+    pub fn maintenance_stats(&self) -> &MaintenanceStats {
+        &self.maintenance
+    }
+
+    /// Take the sockets of removed flows, for an interface not started by
+    /// `start_interface` to close with `InterfaceManager::close_orphaned`
+    pub fn take_orphaned_sockets(&mut self) -> Vec<SocketHandle> {
+        std::mem::take(&mut *lock(&self.orphaned_sockets))
+    }
+
+    /// Routing decision for a flow, classifying it on its first packet.
+    ///
+    /// The decision is stored on the NAT entry and reused for every later
+    /// packet, so rules and stats see each flow once.
+    pub fn route_flow(&self, info: &ConnectionInfo) -> RoutingDecision {
+        if let Some(decision) = &info.route {
+            return decision.clone();
+        }
+        if let Some(decision) = lock(&self.admission).queued(&info.key) {
+            return decision.clone();
+        }
+
+        let key = info.key;
+        if self.drain_deadline.is_some() {
+            let decision = match self.config.drain_policy {
+                DrainPolicy::Reset => RoutingDecision::reject(key.dst_port),
+                DrainPolicy::Direct => RoutingDecision::direct(key.dst_port),
+            };
+            let decision = RoutingDecision {
+                matched_rule: Some(engine::DRAIN_RULE.into()),
+                ..decision
+            };
+            self.conn_manager.set_route(&key, decision.clone());
+            return decision;
+        }
+        let domain = self.conn_manager.domain(&key);
+        let app = self.conn_manager.app_id(&key);
+        let flow = flow_context(&key, domain.as_deref(), app.as_deref());
+        let decision = self.proxy_manager().evaluate_route(&flow);
+        let decision = self.block_quic(&key, decision);
+        self.admit(key, decision)
+    }
+
+    /// Reject a proxied QUIC flow if `block_quic` is set, so the app falls
+    /// back to TCP
+    fn block_quic(&self, key: &NatKey, decision: RoutingDecision) -> RoutingDecision {
+        let quic = key.is_udp() && key.dst_port == 443;
+        if !self.config.block_quic || !quic || decision.action != RouteAction::Proxy {
+            return decision;
+        }
+        log::debug!("Rejected QUIC to {} to force TCP", key.dst_addr());
+        self.proxy_manager().record_quic_blocked();
+        RoutingDecision {
+            action: RouteAction::Reject,
+            matched_rule: Some(proxy::BLOCK_QUIC_RULE.into()),
+            ..decision
+        }
+    }
+
+    /// Store a new flow's decision if it fits under the concurrency caps,
+    /// otherwise queue or reject it
+    fn admit(&self, key: NatKey, decision: RoutingDecision) -> RoutingDecision {
+        // Held until the decision is stored, so flows routed at the same
+        // time cannot all take the last slot
+        let mut admission = lock(&self.admission);
+        match admission.check(&key, &decision, &self.conn_manager) {
+            Admission::Admit => {
+                self.conn_manager.set_route(&key, decision.clone());
+                decision
+            }
+            Admission::Queue => {
+                log::debug!("Queued {} until a connection slot frees up", key.dst_addr());
+                self.conn_manager.hold_listener(&key);
+                self.proxy_manager().record_queued();
+                admission.enqueue(QueuedFlow {
+                    key,
+                    decision: decision.clone(),
+                    since: Instant::now(),
+                });
+                decision
+            }
+            Admission::Reject => self.reject_over_limit(key, decision),
+        }
+    }
+
+    fn reject_over_limit(&self, key: NatKey, decision: RoutingDecision) -> RoutingDecision {
+        log::debug!("Rejected {}: concurrency limit reached", key.dst_addr());
+        self.proxy_manager().record_limit_rejected(&decision.action);
+        let decision = RoutingDecision {
+            action: RouteAction::Reject,
+            matched_rule: Some(admission::CONCURRENCY_LIMIT_RULE.into()),
+            ..decision
+        };
+        self.conn_manager.set_route(&key, decision.clone());
+        decision
+    }
+
+    /// Admit queued flows that now fit under the concurrency caps, oldest
+    /// first, and reject those that waited longer than the queue timeout
+    pub fn admit_queued(&self) {
+        let mut admission = lock(&self.admission);
+        if admission.is_empty() {
+            return;
+        }
+        let now = Instant::now();
+        let timeout = self.config.concurrency.queue_timeout();
+        for flow in admission.take_queue() {
+            // Flows that ended while waiting are dropped
+            if !self.conn_manager.contains(&flow.key) {
+                continue;
+            }
+            match admission.check(&flow.key, &flow.decision, &self.conn_manager) {
+                Admission::Admit => {
+                    self.conn_manager.set_route(&flow.key, flow.decision);
+                    self.conn_manager.release_listener(flow.key);
+                }
+                Admission::Queue if now.duration_since(flow.since) < timeout => {
+                    admission.enqueue(flow)
+                }
+                _ => {
+                    self.reject_over_limit(flow.key, flow.decision);
+                }
+            }
+        }
+    }
+
+    /// Change the concurrency caps. Running flows are not affected; queued
+    /// ones are admitted if the new caps allow it.
+    pub fn set_concurrency_limits(&mut self, limits: ConcurrencyLimits) {
+        self.config.concurrency = limits;
+        lock(&self.admission).set_limits(limits);
+        self.admit_queued();
+    }
+
+    /// Change the rate limits on new connections; blocked sources are
+    /// unblocked
+    pub fn set_connection_rate_limits(&mut self, limits: ConnectionRateLimits) {
+        self.config.connection_rate = limits;
+        lock(&self.guard).set_limits(limits);
+    }
+
+    /// Sources whose new connections are dropped for going over their rate
+    pub fn blocked_sources(&self) -> Vec<IpAddr> {
+        lock(&self.guard).blocked_sources(Instant::now())
+    }
+
+    /// Flows waiting for a concurrency slot
+    pub fn queued_connections(&self) -> usize {
+        lock(&self.admission).queue_len()
+    }
+
+    /// Sniff the hostname from the first data segment of a TCP flow and
+    /// re-run routing on it.
+    ///
+    /// Returns the corrected decision, which replaces the flow's stored one,
+    /// when a hostname was found.
+    pub fn sniff_route(&self, parsed: &ParsedPacket, data: &[u8]) -> Option<RoutingDecision> {
+        let key = parsed.to_nat_key()?;
+        let payload = parsed.tcp_payload(data).filter(|p| !p.is_empty())?;
+        if !self.conn_manager.needs_sniff(&key) {
+            return None;
+        }
+
+        let domain = sniff::sniff_domain(key.dst_port, payload);
+        self.conn_manager.set_sniffed_domain(key, domain.clone());
+        let domain = domain?;
+
+        // The flow was counted when it was first routed
+        let app = self.conn_manager.app_id(&key);
+        let decision = self
+            .proxy_manager()
+            .reroute(&flow_context(&key, Some(&domain), app.as_deref()));
+        log::debug!(
+            "Sniffed {} for {} -> {:?}",
+            domain,
+            key.dst_addr(),
+            decision.action
+        );
+        self.conn_manager.set_route(&key, decision.clone());
+        Some(decision)
+    }
+
+    /// Run an inbound packet from the TUN device through the parse/route
+    /// pipeline, rewriting it in place
+    pub fn process_inbound(&self, packet: &mut [u8]) -> Result<(), VoyageError> {
+        let parsed = match ParsedPacket::parse(packet) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                lock(&self.quarantine).record(packet, &e);
+                return Err(e);
+            }
+        };
+
+        // Drop new flows over the connection rate before they take a NAT
+        // entry or a socket
+        if let Some(key) = parsed.to_nat_key() {
+            if !self.conn_manager.contains(&key)
+                && !lock(&self.guard).allow(key.src_ip, Instant::now())
+            {
+                return Err(VoyageError::connection(format!(
+                    "New connection from {} dropped by the rate limit",
+                    key.src_addr()
+                )));
+            }
+        }
+
+        // Let queued flows in first, so their next SYN finds its decision
+        self.admit_queued();
+
+        // Process through connection manager
+        let conn_info = self.conn_manager.process_packet(&parsed)?;
+
+        // Classify new flows once; later packets reuse the stored decision.
+        // Packets of flows waiting for a concurrency slot are dropped.
+        self.route_flow(&conn_info);
+        if conn_info.route.is_none() && lock(&self.admission).queued(&conn_info.key).is_some() {
+            return Err(VoyageError::connection(format!(
+                "Connection to {} queued by the concurrency limit",
+                conn_info.key.dst_addr()
+            )));
+        }
+
+        // Recover the hostname from the first data segment so DOMAIN rules apply
+        self.sniff_route(&parsed, packet);
+
+        self.clamp_mss(packet);
+        self.publish_stats_throttled();
+        Ok(())
+    }
+
+    /// Process a packet from the app that sits in a borrowed buffer and
+    /// queue it on the interface created last by `new_interface`.
+    ///
+    /// The packet is copied once, into a buffer from the pool; it is parsed
+    /// and rewritten in that buffer, which then goes to the device's rx
+    /// queue as is. Returns false if the queue was full and the packet
+    /// dropped.
+    pub fn inject_inbound(&self, packet: &[u8]) -> Result<bool, VoyageError> {
+        let injector = self.injector.clone().ok_or_else(|| {
+            VoyageError::socket("No interface to deliver packets to".into())
+        })?;
+        let mut buffer = self.buffer_pool.get(packet.len());
+        buffer.copy_from_slice(packet);
+        // On failure the buffer goes back to the pool
+        self.process_inbound(&mut buffer)?;
+        Ok(injector.inject(buffer.into_vec()))
+    }
+
+    /// Run a packet bound for the TUN device through the NAT, rewriting it
+    /// in place.
+    ///
+    /// UDP datagrams are only let through from a remote their flow's NAT
+    /// mapping admits; other packets pass unchanged apart from MSS clamping.
+    pub fn process_outbound(&self, packet: &mut [u8]) -> Result<(), VoyageError> {
+        if let Ok(parsed) = ParsedPacket::parse(packet) {
+            if parsed.udp.is_some() {
+                self.conn_manager.process_reply(&parsed)?;
+            }
+        }
+
+        self.clamp_mss(packet);
+        self.publish_stats_throttled();
+        Ok(())
+    }
+
+    /// Clamp the MSS of a forwarded SYN/SYN-ACK if clamping is configured
+    pub fn clamp_mss(&self, packet: &mut [u8]) -> Option<u16> {
+        let clamp = self.config.mss_clamp?;
+        let ipv6 = packet.first().map(|b| b >> 4 == 6).unwrap_or(false);
+        clamp_tcp_mss(packet, clamp.max_mss(ipv6))
+    }
+
+    /// Decide how to answer an intercepted DNS query using the routing rules
+    pub fn plan_dns(&mut self, query: &DnsMessage) -> DnsPlan {
+        let action = query
+            .question()
+            .map(|q| self.proxy_manager().dns_action(&q.name))
+            .unwrap_or(RouteAction::Direct);
+        let plan = self.dns.plan(query, &action, &mut self.fake_ip_pool);
+        if let DnsPlan::Answer(response) = &plan {
+            self.proxy_manager().record_dns_answer(response);
+        }
+        plan
+    }
+
+    /// Cache an upstream DNS answer and remember the addresses it resolved
+    pub fn record_dns_response(&mut self, response: &[u8]) {
+        if let Ok(message) = DnsMessage::parse(response) {
+            self.dns.record_response(&message);
+            self.proxy_manager().record_dns_answer(&message);
+        }
+    }
+
+    /// Add an answered query to the DNS query log. `upstream` is the
+    /// upstream that answered, if the query was forwarded.
+    pub fn log_dns_query(
+        &mut self,
+        query: &DnsMessage,
+        response: &DnsMessage,
+        upstream: Option<SocketAddr>,
+        via_proxy: bool,
+        latency: Duration,
+    ) {
+        let mut record = DnsQueryRecord::new(query, response, upstream, via_proxy, latency);
+        record.fake_ip = upstream.is_none()
+            && response.answers.iter().any(|answer| match answer.data {
+                dns::RecordData::A(addr) => self.fake_ip_pool.contains(addr),
+                _ => false,
+            });
+        self.dns.log_query(record);
+    }
+
+    /// Up to `limit` inbound packets the parser rejected, newest first
+    pub fn malformed_packets(&self, limit: usize) -> Vec<MalformedPacket> {
+        lock(&self.quarantine).recent(limit).cloned().collect()
+    }
+
+    /// Forget the quarantined packets
+    pub fn clear_malformed_packets(&mut self) {
+        lock(&self.quarantine).clear();
+    }
+
+    /// Up to `limit` answered DNS queries, newest first
+    pub fn dns_query_log(&self, limit: usize) -> Vec<DnsQueryRecord> {
+        self.dns.query_log().recent(limit).cloned().collect()
+    }
+
+    /// SOCKS5 client for the configured proxy server
+    pub fn socks5_client(&self) -> Result<Socks5Client, VoyageError> {
+        let resolved = self.proxy_addr.map(|addr| addr.ip().to_string());
+        socks5::create_socks5_client(
+            resolved.as_deref().unwrap_or(&self.config.server_host),
+            self.config.server_port,
+            self.config.username.as_deref(),
+            self.config.password.as_ref().map(SecretString::expose),
+        )
+        .map(|client| client.with_options(self.config.outbound))
+    }
+
+    /// Point the core at a different proxy server.
+    ///
+    /// New flows use the new server right away. Flows already relayed through
+    /// the old one keep running unless `drain` is set, in which case every
+    /// proxied flow is aborted so the app reconnects through the new server.
+    /// Returns how many flows were drained.
+    pub fn update_proxy_server(
+        &mut self,
+        host: String,
+        port: u16,
+        username: Option<String>,
+        password: Option<SecretString>,
+        protocol: ProxyProtocol,
+        drain: bool,
+    ) -> Result<usize, VoyageError> {
+        if host.is_empty() || port == 0 {
+            return Err(VoyageError::config(format!(
+                "Invalid proxy server {}:{}",
+                host, port
+            )));
+        }
+        if host != self.config.server_host {
+            self.proxy_addr = None;
+        }
+        self.config.server_host = host;
+        self.config.server_port = port;
+        self.config.username = username;
+        self.config.password = password;
+        log::info!("Switching proxy server to {}", self.config);
+        self.config.protocol = protocol;
+        self.proxy_manager().set_config(self.config.clone());
+
+        if !drain {
+            return Ok(0);
+        }
+        let proxied: Vec<NatKey> = self
+            .conn_manager
+            .iter_filtered(None, None, None)
+            .filter(|(_, entry)| {
+                entry
+                    .route
+                    .as_ref()
+                    .is_some_and(|route| route.action == RouteAction::Proxy)
+            })
+            .map(|(key, _)| key)
+            .collect();
+        for key in &proxied {
+            if let Some(info) = self.conn_manager.abort(key) {
+                self.release_sockets(info.socket_handle);
+            }
+        }
+        self.publish_stats();
+        Ok(proxied.len())
+    }
+
+    /// Remember where the proxy server `host` resolved to, for clients
+    /// built by `socks5_client`. Ignored if the server changed meanwhile.
+    pub fn set_proxy_address(&mut self, host: &str, addr: SocketAddr) {
+        if host == self.config.server_host {
+            self.proxy_addr = Some(addr);
+        }
+    }
+
+    /// Take note of a new network path.
+    ///
+    /// The path's local networks replace the ones set with
+    /// `set_local_networks`. When the device moved to another network, TCP
+    /// flows bound to the old one are reset, the DNS cache is flushed and the
+    /// proxy server must be resolved again. The first report and a lost
+    /// route only record the path. Returns how many flows were reset, or
+    /// `None` if the path did not change.
+    pub fn network_changed(&mut self, path: NetworkPath) -> Result<Option<usize>, VoyageError> {
+        let networks = path.networks()?;
+        if self.network_path.as_ref() == Some(&path) {
+            return Ok(None);
+        }
+        log::info!(
+            "Network path changed: {:?}, available: {}, expensive: {}",
+            path.interface,
+            path.available,
+            path.expensive
+        );
+        self.set_local_networks(networks);
+        let available = path.available;
+        let previous = self.network_path.replace(path);
+        if !available || previous.is_none() {
+            return Ok(Some(0));
+        }
+
+        self.dns.flush_cache();
+        self.proxy_addr = None;
+        Ok(Some(self.reset_tcp_flows(None)))
+    }
+
+    /// Network path last reported by the host
+    pub fn network_path(&self) -> Option<&NetworkPath> {
+        self.network_path.as_ref()
+    }
+
+    /// Take note of the device going to sleep
+    pub fn sleep(&mut self) {
+        self.asleep_since.get_or_insert_with(Instant::now);
+    }
+
+    /// Take note of the device waking up.
+    ///
+    /// Flushes the DNS cache and, after a sleep of at least
+    /// `network::HALF_DEAD_AFTER`, resets the TCP flows that have been idle
+    /// since the device went to sleep, as their remote ends have likely given
+    /// up on them. Returns how many flows were reset.
+    pub fn wake(&mut self) -> usize {
+        let Some(since) = self.asleep_since.take() else {
+            return 0;
+        };
+        self.dns.flush_cache();
+        if since.elapsed() < network::HALF_DEAD_AFTER {
+            return 0;
+        }
+        self.reset_tcp_flows(Some(since))
+    }
+
+    /// Abort TCP flows, or only the ones without activity since `idle_since`
+    fn reset_tcp_flows(&mut self, idle_since: Option<Instant>) -> usize {
+        let stale: Vec<NatKey> = self
+            .conn_manager
+            .iter_filtered(Some(packet::PROTO_TCP), None, None)
+            .filter(|(_, entry)| idle_since.is_none_or(|t| entry.last_seen < t))
+            .map(|(key, _)| key)
+            .collect();
+        for key in &stale {
+            if let Some(info) = self.conn_manager.abort(key) {
+                self.release_sockets(info.socket_handle);
+            }
+        }
+        if !stale.is_empty() {
+            log::info!("Reset {} stale TCP flows", stale.len());
+            self.publish_stats();
+        }
+        stale.len()
+    }
+
+    /// Set the preferred fake-IP range, returning the range actually used
+    pub fn set_fake_ip_range(&mut self, range: Ipv4Range) -> Ipv4Range {
+        self.config.fake_ip.range = range;
+        self.reselect_fake_ip_range()
+    }
+
+    /// Record the device's local networks, returning the fake-IP range in use.
+    ///
+    /// If the configured range collides with one of them an alternate range
+    /// is chosen and a warning event is queued.
+    pub fn set_local_networks(&mut self, networks: Vec<Ipv4Range>) -> Ipv4Range {
+        self.local_networks = networks;
+        self.reselect_fake_ip_range()
+    }
+
+    fn reselect_fake_ip_range(&mut self) -> Ipv4Range {
+        let (range, warning) = fakeip::select_range(
+            self.config.fake_ip.range,
+            &self.config.fake_ip.fallback_ranges,
+            &self.local_networks,
+        );
+        if let Some(warning) = warning {
+            self.push_event(warning);
+        }
+        if range != self.fake_ip_pool.range() {
+            log::info!("Fake-IP range set to {}", range);
+            self.fake_ip_pool.reset(range);
+        }
+        range
+    }
+
+    /// Queue a warning for the host, dropping the oldest if full
+    pub fn push_event(&mut self, event: LocalizedMessage) {
+        if self.events.len() >= MAX_PENDING_EVENTS {
+            self.events.pop_front();
         }
+        self.events.push_back(event);
+    }
+
+    /// Take all queued warnings
+    pub fn drain_events(&mut self) -> Vec<LocalizedMessage> {
+        self.events.drain(..).collect()
+    }
+
+    /// Kill a connection by identifier; its socket is aborted on the
+    /// engine's interface right away, so the app sees a reset
+    pub fn close_connection(&mut self, id: u64) -> Result<(), VoyageError> {
+        let info = self
+            .conn_manager
+            .key_by_id(id)
+            .and_then(|key| self.conn_manager.abort(&key))
+            .ok_or_else(|| VoyageError::connection(format!("No connection with id {}", id)))?;
+        self.release_sockets(info.socket_handle);
+        self.close_orphaned_now();
+        self.publish_stats();
+        Ok(())
+    }
+
+    /// Close the queued sockets on the engine's interface now instead of
+    /// on its next poll
+    fn close_orphaned_now(&mut self) {
+        let Some(iface) = &self.interface else {
+            return;
+        };
+        if let Ok(mut iface) = iface.lock() {
+            iface.close_orphaned(&std::mem::take(&mut *lock(&self.orphaned_sockets)));
+        }
+    }
+
+    /// Stop taking new flows and give the running TCP flows until `timeout`
+    /// to finish.
+    ///
+    /// New flows are reset or sent direct as `ProxyConfig::drain_policy`
+    /// says. The caller polls `drain_done` and shuts down once it returns
+    /// `Some(true)`. Returns how many flows are still running.
+    pub fn begin_drain(&mut self, timeout: Duration) -> usize {
+        self.drain_deadline = Some(Instant::now() + timeout);
+        let running = self.running_tcp_flows();
+        log::info!("Draining {} flows for up to {:?}", running, timeout);
+        running
+    }
+
+    /// Abandon an ongoing drain and take new flows again
+    pub fn end_drain(&mut self) {
+        if self.drain_deadline.take().is_some() {
+            log::info!("Drain cancelled");
+        }
+    }
+
+    /// Whether the drain is over because every TCP flow finished or the
+    /// timeout passed; `None` if no drain is ongoing
+    pub fn drain_done(&self) -> Option<bool> {
+        let deadline = self.drain_deadline?;
+        Some(Instant::now() >= deadline || self.running_tcp_flows() == 0)
+    }
+
+    fn running_tcp_flows(&self) -> usize {
+        self.conn_manager
+            .iter_filtered(Some(packet::PROTO_TCP), None, None)
+            .filter(|(_, entry)| entry.state != NatState::Closed && entry.close_reason.is_none())
+            .count()
+    }
+
+    /// Abort every flow before the core is dropped, closing their sockets
+    /// and stopping the engine's interface.
+    ///
+    /// Returns the sockets of all flows; they are already closed if the
+    /// interface was started with `start_interface`.
+    pub fn shutdown(&mut self) -> Vec<SocketHandle> {
+        self.drain_deadline = None;
+        let mut sockets = std::mem::take(&mut *lock(&self.orphaned_sockets));
+        sockets.extend(self.conn_manager.abort_all());
+        self.driver = None;
+        if let Some(iface) = self.interface.take() {
+            if let Ok(mut iface) = iface.lock() {
+                iface.close_orphaned(&sockets);
+            }
+            self.injector = None;
+        }
+        self.publish_stats();
+        sockets
+    }
+
+    /// Attribute a connection to an app, for per-app usage
+    pub fn set_connection_app(&mut self, id: u64, app_id: String) -> Result<(), VoyageError> {
+        let key = self
+            .conn_manager
+            .key_by_id(id)
+            .ok_or_else(|| VoyageError::connection(format!("No connection with id {}", id)))?;
+        self.conn_manager.set_app_id(&key, app_id);
+        Ok(())
+    }
+
+    /// Attach `value` under `name` to a connection, or remove `name` when
+    /// `value` is `None`
+    pub fn annotate_connection(
+        &mut self,
+        id: u64,
+        name: &str,
+        value: Option<String>,
+    ) -> Result<(), VoyageError> {
+        let key = self
+            .conn_manager
+            .key_by_id(id)
+            .ok_or_else(|| VoyageError::connection(format!("No connection with id {}", id)))?;
+        self.conn_manager.annotate(&key, name, value)
+    }
+
+    /// Bandwidth limits a flow's relay must enforce. Flows not yet
+    /// classified are treated as direct.
+    pub fn flow_limiter(&mut self, key: &NatKey) -> FlowLimiter {
+        match self.conn_manager.route(key) {
+            Some(decision) => self.shaper.flow_limiter(&decision),
+            None => self.shaper.flow_limiter(&RoutingDecision::direct(key.dst_port)),
+        }
+    }
+
+    /// Account for `bytes` a relay is about to forward on a connection,
+    /// returning how long it should wait first
+    pub fn shaping_delay(&mut self, id: u64, bytes: u64) -> Result<Duration, VoyageError> {
+        let key = self
+            .conn_manager
+            .key_by_id(id)
+            .ok_or_else(|| VoyageError::connection(format!("No connection with id {}", id)))?;
+        Ok(self.flow_limiter(&key).delay(bytes, Instant::now()))
+    }
+
+    /// Up to `limit` recently closed flows, newest first
+    pub fn recent_connections(&self, limit: usize) -> Vec<FfiClosedConnection> {
+        let now = Instant::now();
+        self.conn_manager
+            .recent_connections(limit)
+            .into_iter()
+            .map(|closed| FfiClosedConnection {
+                protocol: closed.info.key.protocol,
+                src: closed.info.key.src_addr().to_string(),
+                dst: closed.info.key.dst_addr().to_string(),
+                domain: closed.domain.clone(),
+                policy: closed.info.route.as_ref().map(|r| r.action.clone().into()),
+                bytes_sent: closed.info.bytes_sent,
+                bytes_received: closed.info.bytes_received,
+                duration_ms: closed.duration().as_millis() as u64,
+                closed_ms_ago: now.saturating_duration_since(closed.closed_at).as_millis() as u64,
+                reason: closed.reason,
+                annotations: closed.info.annotations.clone().into_iter().collect(),
+            })
+            .collect()
+    }
+
+    /// Live flows matching `filter`, ordered by local port
+    pub fn connections(&self, filter: &FfiConnectionFilter) -> Vec<FfiConnection> {
+        let now = Instant::now();
+        let since = filter
+            .active_within_ms
+            .and_then(|ms| now.checked_sub(Duration::from_millis(ms)));
+
+        let proxy = self.proxy_manager();
+        let mut flows: Vec<(u16, FfiConnection)> = self
+            .conn_manager
+            .iter_filtered(filter.protocol, filter.state, since)
+            .map(|(key, entry)| {
+                let route = entry.route.as_ref();
+                let domain = self
+                    .conn_manager
+                    .domain(&key)
+                    .or_else(|| route.and_then(|r| r.domain.clone()))
+                    .or_else(|| proxy.domain_for_ip(key.dst_ip).map(String::from));
+                // Flows not classified yet show what the rules would pick
+                let policy = match route {
+                    Some(route) => route.action.clone(),
+                    None => {
+                        let app = self.conn_manager.app_id(&key);
+                        proxy.peek_action(&flow_context(&key, domain.as_deref(), app.as_deref()))
+                    }
+                };
+                let record = FfiConnection {
+                    id: entry.id,
+                    protocol: key.protocol,
+                    src: key.src_addr().to_string(),
+                    dst: key.dst_addr().to_string(),
+                    domain,
+                    state: entry.state,
+                    bytes_sent: entry.bytes_sent,
+                    bytes_received: entry.bytes_received,
+                    age_ms: now.duration_since(entry.created_at).as_millis() as u64,
+                    idle_ms: now.duration_since(entry.last_seen).as_millis() as u64,
+                    upload_rate: entry.upload.rate(now),
+                    download_rate: entry.download.rate(now),
+                    policy: policy.into(),
+                    matched_rule: route.and_then(|r| r.matched_rule.clone()),
+                    annotations: entry.annotations.clone().into_iter().collect(),
+                };
+                (entry.local_port, record)
+            })
+            .collect();
+
+        flows.sort_by_key(|(port, _)| *port);
+        flows.into_iter().map(|(_, record)| record).collect()
+    }
+
+    /// Dump the flow table as JSON for bug reports, with the socket state
+    /// of flows on the engine's interface
+    pub fn dump_flows_json(&self) -> String {
+        let iface = self.interface.as_ref().and_then(|iface| iface.lock().ok());
+        self.conn_manager.dump_flows_json(iface.as_ref().map(|iface| iface.sockets()))
     }
 
     /// Enable the proxy
-    pub fn enable(&mut self) {
-        self.proxy_manager.enable();
+    pub fn enable(&self) {
+        self.proxy_manager().enable();
     }
 
     /// Disable the proxy
-    pub fn disable(&mut self) {
-        self.proxy_manager.disable();
+    pub fn disable(&self) {
+        self.proxy_manager().disable();
     }
 
     /// Check if proxy is enabled
     pub fn is_enabled(&self) -> bool {
-        self.proxy_manager.is_enabled()
+        self.proxy_manager().is_enabled()
+    }
+}
+
+impl Drop for VoyageCore {
+    fn drop(&mut self) {
+        self.stop_runtime();
     }
 }
 
-// UniFFI scaffolding
-uniffi::include_scaffolding!("voyage_core");
-
+/// What the rules see of the flow `key` with hostname `domain`, opened by
+/// the app `app`
+fn flow_context<'a>(
+    key: &NatKey,
+    domain: Option<&'a str>,
+    app: Option<&'a str>,
+) -> MatchContext<'a> {
+    MatchContext::new(domain, Some(key.dst_ip), key.dst_port)
+        .with_source(Some(key.src_ip), key.src_port)
+        .with_process(app)
+}
+
+/// Lock a part of the core. Each part is consistent between calls, so a
+/// panic while one was held does not leave it broken.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// UniFFI scaffolding. The generated code leaves blank lines after doc
+// comments, so the lint is allowed for it alone.
+#[allow(clippy::empty_line_after_doc_comments)]
+mod scaffolding {
+    use super::*;
+
+    uniffi::include_scaffolding!("voyage_core");
+}
+pub use scaffolding::UniFfiTag;
+
+/// Helper function to create a TCP packet for testing
+pub fn create_tcp_packet(
+    src_ip: [u8; 4],
+    dst_ip: [u8; 4],
+    src_port: u16,
+    dst_port: u16,
+    syn: bool,
+) -> Vec<u8> {
+    let mut packet = vec![0u8; 40];
+    
+    // IPv4 header
+    packet[0] = 0x45; // Version 4, IHL 5
+    packet[1] = 0x00; // DSCP/ECN
+    packet[2] = 0x00; // Total length (high)
+    packet[3] = 0x28; // Total length (low) = 40
+    packet[4..6].copy_from_slice(&[0x00, 0x00]); // ID
+    packet[6..8].copy_from_slice(&[0x40, 0x00]); // Flags + Fragment
+    packet[8] = 64; // TTL
+    packet[9] = 6; // Protocol: TCP
+    packet[10..12].copy_from_slice(&[0x00, 0x00]); // Checksum (placeholder)
+    packet[12..16].copy_from_slice(&src_ip);
+    packet[16..20].copy_from_slice(&dst_ip);
+    
+    // TCP header
+    packet[20] = (src_port >> 8) as u8;
+    packet[21] = src_port as u8;
+    packet[22] = (dst_port >> 8) as u8;
+    packet[23] = dst_port as u8;
+    packet[24..28].copy_from_slice(&[0x00, 0x00, 0x00, 0x01]); // Seq
+    packet[28..32].copy_from_slice(&[0x00, 0x00, 0x00, 0x00]); // Ack
+    packet[32] = 0x50; // Data offset (5 words)
+    packet[33] = if syn { 0x02 } else { 0x10 }; // Flags: SYN or ACK
+    packet[34..36].copy_from_slice(&[0xFF, 0xFF]); // Window
+    packet[36..38].copy_from_slice(&[0x00, 0x00]); // Checksum
+    packet[38..40].copy_from_slice(&[0x00, 0x00]); // Urgent ptr
+    
+    // Valid checksums, so the device does not drop the packet
+    let src = smoltcp::wire::IpAddress::Ipv4(smoltcp::wire::Ipv4Address(src_ip));
+    let dst = smoltcp::wire::IpAddress::Ipv4(smoltcp::wire::Ipv4Address(dst_ip));
+    smoltcp::wire::Ipv4Packet::new_unchecked(&mut packet[..]).fill_checksum();
+    smoltcp::wire::TcpPacket::new_unchecked(&mut packet[20..]).fill_checksum(&src, &dst);
+    
+    packet
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -115,6 +1644,7 @@ mod tests {
             server_port: 1080,
             username: None,
             password: None,
+            ..Default::default()
         };
 
         let core = VoyageCore::new(config);
@@ -128,9 +1658,10 @@ mod tests {
             server_port: 1080,
             username: None,
             password: None,
+            ..Default::default()
         };
 
-        let mut core = VoyageCore::new(config);
+        let core = VoyageCore::new(config);
         let count = core.load_rules("FINAL, DIRECT").unwrap();
         assert_eq!(count, 1);
     }
@@ -142,6 +1673,7 @@ mod tests {
             server_port: 1080,
             username: None,
             password: None,
+            ..Default::default()
         };
 
         let mut core = VoyageCore::new(config);
@@ -164,6 +1696,7 @@ FINAL, DIRECT
             server_port: 1080,
             username: None,
             password: None,
+            ..Default::default()
         };
 
         let core = VoyageCore::new(config);
@@ -172,6 +1705,885 @@ FINAL, DIRECT
         assert_eq!(stats.bytes_sent, 0);
         assert_eq!(stats.bytes_received, 0);
         assert_eq!(stats.active_connections, 0);
+
+        // The shared copy follows packet processing
+        let shared = core.shared_stats();
+        let mut packet = create_tcp_packet([10, 0, 0, 1], [8, 8, 8, 8], 40000, 443, true);
+        core.process_inbound(&mut packet).unwrap();
+        assert_eq!(shared.snapshot().active_connections, 1);
+        assert_eq!(shared.snapshot().total_connections, 1);
+    }
+
+    #[test]
+    fn test_sniff_route() {
+        // ClientHello for "www.google.com" carrying only the SNI extension
+        let host = b"www.google.com";
+        let mut hello = vec![0x16, 0x03, 0x01, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x03, 0x03];
+        hello.extend_from_slice(&[0u8; 32]);
+        hello.extend_from_slice(&[0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+        let ext_len = 9 + host.len() as u16;
+        hello.extend_from_slice(&ext_len.to_be_bytes());
+        hello.extend_from_slice(&[0x00, 0x00]);
+        hello.extend_from_slice(&(ext_len - 4).to_be_bytes());
+        hello.extend_from_slice(&(ext_len - 6).to_be_bytes());
+        hello.push(0x00);
+        hello.extend_from_slice(&(host.len() as u16).to_be_bytes());
+        hello.extend_from_slice(host);
+        let hs_len = hello.len() - 9;
+        hello[6..9].copy_from_slice(&(hs_len as u32).to_be_bytes()[1..]);
+        let rec_len = (hello.len() - 5) as u16;
+        hello[3..5].copy_from_slice(&rec_len.to_be_bytes());
+
+        let mut packet = create_tcp_packet([10, 0, 0, 1], [142, 250, 0, 1], 40000, 443, false);
+        packet.extend_from_slice(&hello);
+        let total = packet.len() as u16;
+        packet[2..4].copy_from_slice(&total.to_be_bytes());
+
+        let core = VoyageCore::new(ProxyConfig::default());
+        core.load_rules(
+            r#"
+DOMAIN-SUFFIX, .google.com, PROXY
+FINAL, DIRECT
+"#,
+        )
+        .unwrap();
+
+        let parsed = ParsedPacket::parse(&packet).unwrap();
+        core.conn_manager.process_packet(&parsed).unwrap();
+        let decision = core.sniff_route(&parsed, &packet).unwrap();
+        assert_eq!(decision.action, RouteAction::Proxy);
+        assert_eq!(decision.domain.as_deref(), Some("www.google.com"));
+        assert_eq!(decision.matched_rule.as_deref(), Some("DOMAIN-SUFFIX,.google.com"));
+        // Sniffing refines the route of a flow that was already counted
+        let stats = core.proxy_manager().get_stats().clone();
+        assert_eq!((stats.proxied_connections, stats.direct_connections), (0, 0));
+
+        let key = parsed.to_nat_key().unwrap();
+        assert_eq!(core.conn_manager.domain(&key).as_deref(), Some("www.google.com"));
+
+        // Only the first data segment is sniffed
+        assert!(core.sniff_route(&parsed, &packet).is_none());
+    }
+
+    #[test]
+    fn test_connections_filtered() {
+        let core = VoyageCore::new(ProxyConfig::default());
+        core.load_rules("IP-CIDR, 1.1.1.0/24, PROXY\nFINAL, DIRECT")
+            .unwrap();
+
+        for (dst, port) in [([1, 1, 1, 1], 40001), ([8, 8, 8, 8], 40000)] {
+            let packet = create_tcp_packet([10, 0, 0, 1], dst, port, 443, true);
+            let parsed = ParsedPacket::parse(&packet).unwrap();
+            core.conn_manager.process_packet(&parsed).unwrap();
+        }
+        let key = NatKey::tcp("10.0.0.1:40001".parse().unwrap(), "1.1.1.1:443".parse().unwrap());
+        core.conn_manager.establish(&key);
+        core.conn_manager.add_bytes_sent(&key, 100);
+
+        let all = core.connections(&FfiConnectionFilter::default());
+        assert_eq!(all.len(), 2);
+        // Ordered by local port, i.e. creation order
+        assert_eq!(all[0].src, "10.0.0.1:40001");
+
+        let established = core.connections(&FfiConnectionFilter {
+            state: Some(NatState::Established),
+            ..Default::default()
+        });
+        assert_eq!(established.len(), 1);
+        assert_eq!(established[0].dst, "1.1.1.1:443");
+        assert_eq!(established[0].protocol, 6);
+        assert_eq!(established[0].bytes_sent, 100);
+        assert_eq!(established[0].policy, FfiRouteAction::Proxy);
+
+        let udp = core.connections(&FfiConnectionFilter {
+            protocol: Some(17),
+            ..Default::default()
+        });
+        assert!(udp.is_empty());
+        // Listing flows does not count as routing them
+        assert_eq!(core.proxy_manager().get_stats().proxied_connections, 0);
+    }
+
+    #[test]
+    fn test_route_flow_classified_once() {
+        let core = VoyageCore::new(ProxyConfig::default());
+        core.load_rules("IP-CIDR, 1.1.1.0/24, PROXY\nFINAL, DIRECT")
+            .unwrap();
+
+        let packet = create_tcp_packet([10, 0, 0, 1], [1, 1, 1, 1], 40000, 443, true);
+        let parsed = ParsedPacket::parse(&packet).unwrap();
+        let info = core.conn_manager.process_packet(&parsed).unwrap();
+        assert!(info.route.is_none());
+        assert_eq!(core.route_flow(&info).action, RouteAction::Proxy);
+
+        // Later packets reuse the stored decision even if the rules change
+        core.load_rules("FINAL, REJECT").unwrap();
+        let info = core.conn_manager.process_packet(&parsed).unwrap();
+        assert_eq!(info.route.as_ref().unwrap().action, RouteAction::Proxy);
+        assert_eq!(core.route_flow(&info).action, RouteAction::Proxy);
+        assert_eq!(core.proxy_manager().get_stats().proxied_connections, 1);
+
+        let flows = core.connections(&FfiConnectionFilter::default());
+        assert_eq!(flows[0].policy, FfiRouteAction::Proxy);
+        assert!(flows[0].matched_rule.is_some());
+    }
+
+    #[test]
+    fn test_process_inbound() {
+        let core = VoyageCore::new(ProxyConfig::default());
+        let mut packet = create_tcp_packet([10, 0, 0, 1], [8, 8, 8, 8], 40000, 443, true);
+        core.process_inbound(&mut packet).unwrap();
+        assert_eq!(core.conn_manager.active_connections(), 1);
+        let key = ParsedPacket::parse(&packet).unwrap().to_nat_key().unwrap();
+        assert!(core.conn_manager.route(&key).is_some());
+
+        assert!(core.process_inbound(&mut [0u8; 4]).is_err());
+        assert_eq!(core.conn_manager.active_connections(), 1);
+    }
+
+    #[test]
+    fn test_packets_and_rule_reloads_share_the_core() {
+        let core = Arc::new(RwLock::new(VoyageCore::new(ProxyConfig::default())));
+        core.read().unwrap().load_rules("FINAL,PROXY").unwrap();
+
+        // Held by this thread throughout; none of the work below waits on
+        // the write lock
+        let shared = core.read().unwrap();
+        std::thread::scope(|scope| {
+            for worker in 0..4u16 {
+                let core = &shared;
+                scope.spawn(move || {
+                    for port in 0..50 {
+                        let src_port = 40000 + worker * 50 + port;
+                        let mut packet =
+                            create_tcp_packet([10, 0, 0, 1], [8, 8, 8, 8], src_port, 443, true);
+                        core.process_inbound(&mut packet).unwrap();
+                    }
+                });
+            }
+            scope.spawn(|| {
+                for _ in 0..20 {
+                    shared.load_rules("DOMAIN,example.com,DIRECT").unwrap();
+                }
+            });
+            scope.spawn(|| {
+                for _ in 0..20 {
+                    shared.publish_stats();
+                }
+            });
+        });
+
+        assert_eq!(shared.conn_manager.active_connections(), 200);
+        assert_eq!(shared.get_stats().total_connections, 200);
+        assert_eq!(shared.proxy_manager().rule_count(), 21);
+        let mut flows = shared.conn_manager.iter_filtered(None, None, None);
+        assert!(flows.all(|(_, entry)| entry.route.is_some()));
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let mut core = VoyageCore::new(ProxyConfig::default());
+        core.set_tcp_buffer_sizes(4096, 4096);
+        let flow = core.config.flow_memory();
+        core.set_resource_limits(ResourceLimits {
+            memory_budget: flow * 2,
+            ..Default::default()
+        });
+
+        for port in 40000..40003 {
+            let mut packet = create_tcp_packet([10, 0, 0, 1], [8, 8, 8, 8], port, 443, true);
+            let _ = core.process_inbound(&mut packet);
+        }
+        let stats = core.get_stats();
+        assert_eq!(stats.active_connections, 2);
+        assert_eq!(stats.memory_usage, flow as u64 * 2);
+        assert_eq!(stats.connection_limit_hits, 1);
+
+        core.set_nat_table_size(1);
+        assert_eq!(core.memory_usage(), flow);
+    }
+
+    #[test]
+    fn test_connection_rate_limits() {
+        let mut core = VoyageCore::new(ProxyConfig::default());
+        core.set_connection_rate_limits(ConnectionRateLimits {
+            per_source: 2,
+            total: 0,
+            block_secs: 60,
+        });
+        let syn = |core: &mut VoyageCore, src: [u8; 4], port: u16| {
+            let mut packet = create_tcp_packet(src, [8, 8, 8, 8], port, 443, true);
+            core.process_inbound(&mut packet)
+        };
+
+        // A flooding source loses its new flows but keeps its existing ones
+        syn(&mut core, [10, 0, 0, 1], 40000).unwrap();
+        syn(&mut core, [10, 0, 0, 1], 40001).unwrap();
+        assert!(syn(&mut core, [10, 0, 0, 1], 40002).is_err());
+        syn(&mut core, [10, 0, 0, 1], 40000).unwrap();
+        assert_eq!(core.conn_manager.active_connections(), 2);
+        assert_eq!(core.blocked_sources(), ["10.0.0.1".parse::<IpAddr>().unwrap()]);
+
+        // Other sources are not affected
+        syn(&mut core, [10, 0, 0, 2], 40000).unwrap();
+        let stats = core.get_stats();
+        assert_eq!((stats.rate_limited_connections, stats.rate_limit_blocks), (1, 1));
+
+        core.set_connection_rate_limits(ConnectionRateLimits::default());
+        syn(&mut core, [10, 0, 0, 1], 40002).unwrap();
+    }
+
+    #[test]
+    fn test_concurrency_limits() {
+        let mut core = VoyageCore::new(ProxyConfig::default());
+        core.load_rules("FINAL, PROXY").unwrap();
+        core.set_concurrency_limits(ConcurrencyLimits {
+            max_proxied: 1,
+            ..Default::default()
+        });
+        let key = |port: u16| {
+            NatKey::tcp(
+                format!("10.0.0.1:{}", port).parse().unwrap(),
+                "8.8.8.8:443".parse().unwrap(),
+            )
+        };
+        let syn = |core: &mut VoyageCore, port: u16| {
+            let mut packet = create_tcp_packet([10, 0, 0, 1], [8, 8, 8, 8], port, 443, true);
+            core.process_inbound(&mut packet)
+        };
+
+        // The second proxied flow waits, even when its SYN is retransmitted
+        syn(&mut core, 40000).unwrap();
+        assert!(syn(&mut core, 40001).is_err());
+        assert!(syn(&mut core, 40001).is_err());
+        assert_eq!(core.get_stats().waiting_connections, 1);
+        assert!(core.conn_manager.route(&key(40001)).is_none());
+
+        // and gets in once the first one is gone
+        core.conn_manager.remove_connection(&key(40000));
+        syn(&mut core, 40001).unwrap();
+        assert_eq!(core.conn_manager.route(&key(40001)).unwrap().action, RouteAction::Proxy);
+        assert_eq!(core.queued_connections(), 0);
+
+        // Queued flows are rejected once they waited too long
+        core.set_concurrency_limits(ConcurrencyLimits {
+            max_proxied: 1,
+            queue_timeout_ms: 0,
+            ..Default::default()
+        });
+        assert!(syn(&mut core, 40002).is_err());
+        core.admit_queued();
+        let rejected = core.conn_manager.route(&key(40002)).unwrap();
+        assert_eq!(rejected.action, RouteAction::Reject);
+        assert_eq!(rejected.matched_rule.as_deref(), Some(admission::CONCURRENCY_LIMIT_RULE));
+
+        // Per-destination caps reject at once when told to
+        core.set_concurrency_limits(ConcurrencyLimits {
+            max_per_destination: 1,
+            excess: ExcessPolicy::Reject,
+            ..Default::default()
+        });
+        syn(&mut core, 40003).unwrap();
+        assert_eq!(core.conn_manager.route(&key(40003)).unwrap().action, RouteAction::Reject);
+
+        let stats = core.proxy_manager().get_stats().clone();
+        assert_eq!((stats.queued_connections, stats.limit_rejected_connections), (2, 2));
+        assert_eq!((stats.proxied_connections, stats.rejected_connections), (2, 2));
+        assert_eq!(core.get_stats().limit_rejected_connections, 2);
+    }
+
+    #[test]
+    fn test_from_config_str() {
+        let text = "\
+proxies:
+  - {name: Home, type: socks5, server: 10.0.0.2, port: 1080}
+rules:
+  - DOMAIN-SUFFIX, example.com, Home
+  - FINAL, DIRECT
+";
+        let mut core = VoyageCore::from_config_str(text).unwrap();
+        assert_eq!(core.config.server_host, "10.0.0.2");
+        assert!(core.should_proxy_domain("www.example.com"));
+        assert!(!core.should_proxy_domain("example.org"));
+
+        let result = VoyageCore::from_config_str("rules:\n  - FINAL, Nowhere\n");
+        assert!(matches!(result, Err(VoyageError::ConfigSyntax { line: 2, .. })));
+    }
+
+    #[test]
+    fn test_reload_config() {
+        let text = "\
+proxies:
+  - {name: Home, type: socks5, server: 10.0.0.2, port: 1080}
+rules:
+  - IP-CIDR, 1.1.1.0/24, Home
+  - FINAL, DIRECT
+";
+        let mut core = VoyageCore::from_config_str(text).unwrap();
+        for dst in [[1, 1, 1, 1], [8, 8, 8, 8]] {
+            let mut packet = create_tcp_packet([10, 0, 0, 1], dst, 40000, 443, true);
+            core.process_inbound(&mut packet).unwrap();
+        }
+        assert_eq!(core.conn_manager.active_connections(), 2);
+
+        // New rules apply to new flows; existing ones keep running
+        let rules_only = text.replace("FINAL, DIRECT", "FINAL, Home");
+        let diff = core
+            .reload_config(VoyageConfig::parse_auto(&rules_only).unwrap())
+            .unwrap();
+        assert_eq!(diff.sections(), vec!["rules"]);
+        assert_eq!(core.conn_manager.active_connections(), 2);
+        assert!(core.should_proxy_domain("example.org"));
+
+        // A new server drains the proxied flow only
+        let new_server = rules_only.replace("10.0.0.2", "10.0.0.3");
+        let diff = core
+            .reload_config(VoyageConfig::parse_auto(&new_server).unwrap())
+            .unwrap();
+        assert!(diff.upstream && !diff.rules);
+        assert_eq!(diff.proxies_changed, vec!["Home"]);
+        assert_eq!(core.config.server_host, "10.0.0.3");
+        assert_eq!(core.conn_manager.active_connections(), 1);
+
+        let events = core.drain_events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(
+            events[1].render(),
+            "Configuration reloaded; changed: proxies, general.proxy"
+        );
+
+        let unchanged = core
+            .reload_config(VoyageConfig::parse_auto(&new_server).unwrap())
+            .unwrap();
+        assert!(unchanged.is_empty());
+        assert!(core.drain_events().is_empty());
+    }
+
+    #[test]
+    fn test_select_proxy() {
+        let text = "\
+general:
+  proxy: Auto
+proxies:
+  - {name: Home, type: socks5, server: 10.0.0.2, port: 1080}
+  - {name: Work, type: socks5, server: 10.0.0.3, port: 1080}
+proxy-groups:
+  - {name: Auto, type: fallback, proxies: [Home, Work], interrupt-existing-connections: true}
+  - {name: Manual, type: select, proxies: [Home, Work]}
+rules:
+  - FINAL, Auto
+";
+        let mut core = VoyageCore::from_config_str(text).unwrap();
+        let mut packet = create_tcp_packet([10, 0, 0, 1], [1, 1, 1, 1], 40000, 443, true);
+        core.process_inbound(&mut packet).unwrap();
+        assert_eq!(core.config.server_host, "10.0.0.2");
+
+        assert!(core.select_proxy("Auto", "Other").is_err());
+        assert!(core.select_proxy("Other", "Home").is_err());
+        // Groups off the active path change nothing
+        assert_eq!(core.select_proxy("Manual", "Work").unwrap(), 0);
+        assert_eq!(core.config.server_host, "10.0.0.2");
+
+        // Failing over moves new flows and interrupts the running one
+        assert_eq!(core.select_proxy("Auto", "Work").unwrap(), 1);
+        assert_eq!(core.config.server_host, "10.0.0.3");
+        assert_eq!(core.conn_manager.active_connections(), 0);
+
+        // Without the setting, running flows stay on the old server
+        let text = text.replace(", interrupt-existing-connections: true", "");
+        let mut core = VoyageCore::from_config_str(&text).unwrap();
+        core.process_inbound(&mut packet).unwrap();
+        assert_eq!(core.select_proxy("Auto", "Work").unwrap(), 0);
+        assert_eq!(core.config.server_host, "10.0.0.3");
+        assert_eq!(core.conn_manager.active_connections(), 1);
+    }
+
+    #[test]
+    fn test_switch_profile() {
+        let home = "\
+proxies:
+  - {name: Home, type: socks5, server: 10.0.0.2, port: 1080}
+rules:
+  - FINAL, Home
+";
+        let mut core = VoyageCore::new(ProxyConfig::default());
+        let travel = VoyageConfig::parse_auto("rules: ['FINAL, DIRECT']").unwrap();
+        core.add_profile("Home", VoyageConfig::parse_auto(home).unwrap()).unwrap();
+        core.add_profile("Travel", travel).unwrap();
+        assert!(core.switch_profile("Work").is_err());
+
+        core.switch_profile("Home").unwrap();
+        assert_eq!(core.config.server_host, "10.0.0.2");
+        assert!(core.should_proxy_domain("example.com"));
+
+        let diff = core.switch_profile("Travel").unwrap();
+        assert!(diff.rules);
+        assert!(!core.should_proxy_domain("example.com"));
+
+        let profiles = core.profiles();
+        assert_eq!(profiles[0].proxied_connections, 1);
+        assert!(profiles[1].active);
+        assert_eq!(profiles[1].direct_connections, 1);
+    }
+
+    #[test]
+    fn test_inject_inbound() {
+        let mut core = VoyageCore::new(ProxyConfig::default());
+        let packet = create_tcp_packet([10, 0, 0, 1], [8, 8, 8, 8], 40000, 443, true);
+        assert!(core.inject_inbound(&packet).is_err());
+
+        let iface = core.new_interface();
+        assert!(core.inject_inbound(&packet).unwrap());
+        assert!(core.inject_inbound(&packet[..10]).is_err());
+        assert_eq!(core.conn_manager.active_connections(), 1);
+
+        let queued = iface.packet_queues()[0].drain();
+        assert_eq!(queued, vec![packet]);
+        // The rejected packet's buffer went back to the pool
+        assert_eq!(core.buffer_pool().stats().idle, 1);
+    }
+
+    fn wait_for(mut done: impl FnMut() -> bool) -> bool {
+        for _ in 0..400 {
+            if done() {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        false
+    }
+
+    #[test]
+    fn test_engine_interface_closes_orphaned_sockets() {
+        let core = Arc::new(RwLock::new(VoyageCore::new(ProxyConfig::default())));
+        VoyageCore::start_interface(&core).unwrap();
+        let iface = core.read().unwrap().interface().unwrap();
+
+        // Two flows with sockets on the engine's interface
+        let mut keys = Vec::new();
+        for src_port in [40000, 40001] {
+            let mut packet = create_tcp_packet([10, 0, 0, 1], [8, 8, 8, 8], src_port, 443, true);
+            let core = core.read().unwrap();
+            core.process_inbound(&mut packet).unwrap();
+            let key = ParsedPacket::parse(&packet).unwrap().to_nat_key().unwrap();
+            let handle = iface.lock().unwrap().create_tcp_socket();
+            core.conn_manager.register_socket(key, handle);
+            keys.push(key);
+        }
+        assert_eq!(iface.lock().unwrap().socket_count(), 2);
+
+        // A flow removed by maintenance has its socket closed by the driver
+        {
+            let mut core = core.write().unwrap();
+            core.conn_manager.close_connection(&keys[0]);
+            core.run_maintenance();
+        }
+        assert!(wait_for(|| iface.lock().unwrap().socket_count() == 1));
+        assert!(core.write().unwrap().take_orphaned_sockets().is_empty());
+
+        // Shutting down closes the rest and stops the interface
+        let sockets = core.write().unwrap().shutdown();
+        assert_eq!(sockets.len(), 1);
+        assert_eq!(iface.lock().unwrap().socket_count(), 0);
+        assert!(core.read().unwrap().interface().is_none());
+    }
+
+    #[test]
+    fn test_memory_stats() {
+        let mut core = VoyageCore::new(ProxyConfig::default());
+        let empty = core.memory_stats();
+        assert_eq!((empty.socket_buffers, empty.queued_packets, empty.dns_cache), (0, 0, 0));
+
+        let mut iface = core.new_interface();
+        let mut packet = create_tcp_packet([10, 0, 0, 1], [8, 8, 8, 8], 40000, 443, true);
+        core.process_inbound(&mut packet).unwrap();
+        let key = ParsedPacket::parse(&packet).unwrap().to_nat_key().unwrap();
+        core.conn_manager.register_socket(key, iface.create_tcp_socket());
+        iface.inject_packet(packet.clone());
+
+        let stats = core.memory_stats();
+        let buffers = core.config.tcp.rx_buffer_size + core.config.tcp.tx_buffer_size;
+        assert_eq!(stats.socket_buffers, buffers as u64);
+        assert!(stats.nat_entries > 0);
+        assert_eq!(stats.queued_packets, packet.len() as u64);
+        assert_eq!(
+            stats.total,
+            stats.socket_buffers + stats.nat_entries + stats.queued_packets
+        );
+
+        // Once read by smoltcp the packet's buffer waits for reuse
+        iface.poll();
+        let stats = core.memory_stats();
+        assert_eq!(stats.buffer_pool, MTU as u64);
+        assert_eq!(core.buffer_pool().stats().idle, 1);
+
+        let devices = core.device_stats();
+        assert_eq!(devices.rx_packets, 1);
+        assert_eq!(devices.rx_bytes, packet.len() as u64);
+
+        drop(iface);
+        assert_eq!(core.memory_stats().queued_packets, 0);
+        assert_eq!(core.device_stats(), DeviceStats::default());
+    }
+
+    #[test]
+    fn test_update_proxy_server() {
+        let mut core = VoyageCore::new(ProxyConfig::default());
+        core.load_rules("IP-CIDR, 1.1.1.0/24, PROXY\nFINAL, DIRECT")
+            .unwrap();
+        for (dst, port) in [([1, 1, 1, 1], 40000), ([8, 8, 8, 8], 40001)] {
+            let mut packet = create_tcp_packet([10, 0, 0, 1], dst, port, 443, true);
+            core.process_inbound(&mut packet).unwrap();
+        }
+
+        assert!(core
+            .update_proxy_server(String::new(), 1080, None, None, ProxyProtocol::Socks5, true)
+            .is_err());
+        assert_eq!(core.config.server_host, "127.0.0.1");
+
+        let drained = core
+            .update_proxy_server("10.1.1.1".into(), 1081, None, None, ProxyProtocol::Socks5, true)
+            .unwrap();
+        assert_eq!(drained, 1);
+        assert_eq!(core.conn_manager.active_connections(), 1);
+        assert_eq!(core.proxy_manager().get_config().unwrap().server_port, 1081);
+    }
+
+    #[test]
+    fn test_close_connection() {
+        let mut core = VoyageCore::new(ProxyConfig::default());
+        let packet = create_tcp_packet([10, 0, 0, 1], [8, 8, 8, 8], 40000, 443, true);
+        core.conn_manager
+            .process_packet(&ParsedPacket::parse(&packet).unwrap())
+            .unwrap();
+
+        let id = core.connections(&FfiConnectionFilter::default())[0].id;
+        core.close_connection(id).unwrap();
+        assert!(core.connections(&FfiConnectionFilter::default()).is_empty());
+
+        let recent = core.recent_connections(10);
+        assert_eq!(recent.len(), 1);
+        assert_eq!(recent[0].dst, "8.8.8.8:443");
+        assert_eq!(recent[0].reason, CloseReason::Aborted);
+        assert!(matches!(
+            core.close_connection(id),
+            Err(VoyageError::Connection { .. })
+        ));
+    }
+
+    #[test]
+    fn test_engine_interface_opens_listeners() {
+        let core = Arc::new(RwLock::new(VoyageCore::new(ProxyConfig::default())));
+        VoyageCore::start_interface(&core).unwrap();
+        let iface = core.read().unwrap().interface().unwrap();
+
+        let syn = create_tcp_packet([10, 0, 0, 1], [8, 8, 8, 8], 40000, 443, true);
+        let key = ParsedPacket::parse(&syn).unwrap().to_nat_key().unwrap();
+        assert!(core.write().unwrap().inject_inbound(&syn).unwrap());
+
+        // The driver's next poll gives the new flow its listening socket
+        assert!(wait_for(|| core.read().unwrap().conn_manager.get_socket_handle(&key).is_some()));
+        assert_eq!(iface.lock().unwrap().socket_count(), 1);
+        let dump = core.read().unwrap().dump_flows_json();
+        assert!(dump.contains("\"socket_state\": \"LISTEN\""), "{}", dump);
+        core.write().unwrap().shutdown();
+        assert_eq!(iface.lock().unwrap().socket_count(), 0);
+    }
+
+    #[test]
+    fn test_buffer_sizes_reach_engine_interface() {
+        let core = Arc::new(RwLock::new(VoyageCore::new(ProxyConfig::default())));
+        VoyageCore::start_interface(&core).unwrap();
+        let iface = core.read().unwrap().interface().unwrap();
+        core.write().unwrap().set_tcp_buffer_sizes(4096, 2048);
+
+        // A flow opened afterwards gets the new sizes
+        let syn = create_tcp_packet([10, 0, 0, 1], [8, 8, 8, 8], 40000, 443, true);
+        let key = ParsedPacket::parse(&syn).unwrap().to_nat_key().unwrap();
+        assert!(core.write().unwrap().inject_inbound(&syn).unwrap());
+        assert!(wait_for(|| core.read().unwrap().conn_manager.get_socket_handle(&key).is_some()));
+        let handle = core.read().unwrap().conn_manager.get_socket_handle(&key).unwrap();
+        {
+            let mut iface = iface.lock().unwrap();
+            let socket = iface.get_tcp_socket(handle);
+            assert_eq!((socket.recv_capacity(), socket.send_capacity()), (4096, 2048));
+        }
+        core.write().unwrap().shutdown();
+    }
+
+    #[test]
+    fn test_buffer_tiers_reach_engine_interface() {
+        let core = Arc::new(RwLock::new(VoyageCore::new(ProxyConfig::default())));
+        VoyageCore::start_interface(&core).unwrap();
+        let iface = core.read().unwrap().interface().unwrap();
+        core.write().unwrap().set_tcp_buffer_tiers(vec![BufferTier {
+            ports: vec![443],
+            rx_buffer_size: 1 << 16,
+            tx_buffer_size: 1 << 15,
+        }]);
+
+        // A flow to a tiered port gets the tier's sizes and is counted by them
+        let syn = create_tcp_packet([10, 0, 0, 1], [8, 8, 8, 8], 40000, 443, true);
+        let key = ParsedPacket::parse(&syn).unwrap().to_nat_key().unwrap();
+        assert!(core.write().unwrap().inject_inbound(&syn).unwrap());
+        assert!(wait_for(|| core.read().unwrap().conn_manager.get_socket_handle(&key).is_some()));
+        let handle = core.read().unwrap().conn_manager.get_socket_handle(&key).unwrap();
+        {
+            let mut iface = iface.lock().unwrap();
+            let socket = iface.get_tcp_socket(handle);
+            assert_eq!((socket.recv_capacity(), socket.send_capacity()), (1 << 16, 1 << 15));
+        }
+        let core_ref = core.read().unwrap();
+        assert_eq!(core_ref.memory_usage(), core_ref.config.flow_memory_to(Some(443)));
+        assert_ne!(core_ref.memory_usage(), core_ref.config.flow_memory());
+        drop(core_ref);
+        core.write().unwrap().shutdown();
+    }
+
+    #[test]
+    fn test_packet_sink_follows_engine_interface() {
+        let core = Arc::new(RwLock::new(VoyageCore::new(ProxyConfig::default())));
+        VoyageCore::start_interface(&core).unwrap();
+        let iface = core.read().unwrap().interface().unwrap();
+        iface.lock().unwrap().listen_tcp(443).unwrap();
+
+        // A sink registered once the interface runs gets its packets
+        let (sender, written) = std::sync::mpsc::channel();
+        let sender = Mutex::new(sender);
+        core.write().unwrap().set_packet_sink(Some(Arc::new(move |packets| {
+            for packet in packets {
+                let _ = sender.lock().unwrap().send(packet);
+            }
+        })));
+        let syn = create_tcp_packet([10, 0, 0, 1], [8, 8, 8, 8], 40000, 443, true);
+        assert!(core.write().unwrap().inject_inbound(&syn).unwrap());
+        let syn_ack = written.recv_timeout(Duration::from_secs(2)).unwrap();
+        assert!(sim::Segment::parse(&syn_ack).unwrap().flags.is_syn_ack());
+        core.write().unwrap().shutdown();
+    }
+
+    #[test]
+    fn test_close_connection_resets_app() {
+        let core = Arc::new(RwLock::new(VoyageCore::new(ProxyConfig::default())));
+        let (sender, written) = std::sync::mpsc::channel();
+        let sender = Mutex::new(sender);
+        core.write().unwrap().set_packet_sink(Some(Arc::new(move |packets| {
+            for packet in packets {
+                let _ = sender.lock().unwrap().send(packet);
+            }
+        })));
+        VoyageCore::start_interface(&core).unwrap();
+        let iface = core.read().unwrap().interface().unwrap();
+
+        // The app's SYN reaches a socket on the engine's interface
+        let listener = iface.lock().unwrap().listen_tcp(443).unwrap();
+        let syn = create_tcp_packet([10, 0, 0, 1], [8, 8, 8, 8], 40000, 443, true);
+        let key = ParsedPacket::parse(&syn).unwrap().to_nat_key().unwrap();
+        {
+            let core = core.read().unwrap();
+            assert!(core.inject_inbound(&syn).unwrap());
+            core.conn_manager.register_socket(key, listener);
+        }
+        let syn_ack = written.recv_timeout(Duration::from_secs(2)).unwrap();
+        assert!(sim::Segment::parse(&syn_ack).unwrap().flags.is_syn_ack());
+
+        let id = core.read().unwrap().connections(&FfiConnectionFilter::default())[0].id;
+        core.write().unwrap().close_connection(id).unwrap();
+
+        // The reset went out before close_connection returned
+        let rst = written.try_recv().unwrap();
+        let rst = sim::Segment::parse(&rst).unwrap();
+        assert!(rst.flags.rst);
+        assert_eq!(rst.dst, key.src_addr());
+        assert_eq!(iface.lock().unwrap().socket_count(), 0);
+        core.write().unwrap().shutdown();
+    }
+
+    #[test]
+    fn test_block_quic() {
+        let mut core = VoyageCore::new(ProxyConfig::default());
+        core.load_rules("DST-PORT, 443, PROXY\nFINAL, DIRECT").unwrap();
+        let open = |core: &mut VoyageCore, src_port: u16, port: u16| {
+            let src = SocketAddr::from(([10, 0, 0, 1], src_port));
+            let dst = SocketAddr::from(([8, 8, 8, 8], port));
+            let packet = build_udp_packet(src, dst, b"quic").unwrap();
+            let info = core
+                .conn_manager
+                .process_packet(&ParsedPacket::parse(&packet).unwrap())
+                .unwrap();
+            core.route_flow(&info)
+        };
+        assert_eq!(open(&mut core, 40000, 443).action, RouteAction::Proxy);
+
+        core.config.block_quic = true;
+        let blocked = open(&mut core, 40001, 443);
+        assert_eq!(blocked.action, RouteAction::Reject);
+        assert_eq!(blocked.matched_rule.as_deref(), Some(proxy::BLOCK_QUIC_RULE));
+        assert_eq!(open(&mut core, 40002, 4433).action, RouteAction::Direct);
+        assert_eq!(core.get_stats().quic_blocked, 1);
+    }
+
+    #[test]
+    fn test_drain() {
+        let mut core = VoyageCore::new(ProxyConfig::default());
+        core.load_rules("FINAL, PROXY").unwrap();
+        let open = |core: &mut VoyageCore, port: u16| {
+            let packet = create_tcp_packet([10, 0, 0, 1], [8, 8, 8, 8], port, 443, true);
+            let info = core
+                .conn_manager
+                .process_packet(&ParsedPacket::parse(&packet).unwrap())
+                .unwrap();
+            core.route_flow(&info)
+        };
+        assert_eq!(open(&mut core, 40000).action, RouteAction::Proxy);
+        assert_eq!(core.drain_done(), None);
+
+        assert_eq!(core.begin_drain(Duration::from_secs(30)), 1);
+        let rejected = open(&mut core, 40001);
+        assert_eq!(rejected.action, RouteAction::Reject);
+        assert_eq!(rejected.matched_rule.as_deref(), Some(engine::DRAIN_RULE));
+        assert_eq!(core.drain_done(), Some(false));
+
+        core.config.drain_policy = DrainPolicy::Direct;
+        assert_eq!(open(&mut core, 40002).action, RouteAction::Direct);
+
+        core.end_drain();
+        assert_eq!(open(&mut core, 40003).action, RouteAction::Proxy);
+
+        core.begin_drain(Duration::ZERO);
+        assert_eq!(core.drain_done(), Some(true));
+    }
+
+    #[test]
+    fn test_network_changed() {
+        let mut core = VoyageCore::new(ProxyConfig::default());
+        let wifi = NetworkPath {
+            available: true,
+            interface: NetworkInterface::Wifi,
+            expensive: false,
+            local_networks: vec!["192.168.1.0/24".into()],
+        };
+        let cellular = NetworkPath {
+            interface: NetworkInterface::Cellular,
+            expensive: true,
+            local_networks: Vec::new(),
+            ..wifi.clone()
+        };
+        let packet = create_tcp_packet([10, 0, 0, 1], [8, 8, 8, 8], 40000, 443, true);
+        core.conn_manager
+            .process_packet(&ParsedPacket::parse(&packet).unwrap())
+            .unwrap();
+
+        assert_eq!(core.network_changed(wifi.clone()).unwrap(), Some(0));
+        assert_eq!(core.network_changed(wifi).unwrap(), None);
+        assert_eq!(core.connections(&FfiConnectionFilter::default()).len(), 1);
+
+        assert_eq!(core.network_changed(cellular).unwrap(), Some(1));
+        assert!(core.connections(&FfiConnectionFilter::default()).is_empty());
+        assert_eq!(core.recent_connections(10)[0].reason, CloseReason::Aborted);
+        assert_eq!(
+            core.network_path().unwrap().interface,
+            NetworkInterface::Cellular
+        );
+    }
+
+    #[test]
+    fn test_wake_keeps_active_flows() {
+        let mut core = VoyageCore::new(ProxyConfig::default());
+        let packet = create_tcp_packet([10, 0, 0, 1], [8, 8, 8, 8], 40000, 443, true);
+        core.conn_manager
+            .process_packet(&ParsedPacket::parse(&packet).unwrap())
+            .unwrap();
+        assert_eq!(core.wake(), 0);
+
+        // A short nap leaves flows alone
+        core.sleep();
+        assert_eq!(core.wake(), 0);
+        assert!(core.asleep_since.is_none());
+
+        // So does a long sleep for flows that kept going meanwhile
+        core.asleep_since = Instant::now().checked_sub(network::HALF_DEAD_AFTER * 2);
+        assert_eq!(core.wake(), 0);
+        assert_eq!(core.connections(&FfiConnectionFilter::default()).len(), 1);
+    }
+
+    #[test]
+    fn test_clamp_mss() {
+        let mut syn = create_tcp_packet([10, 0, 0, 1], [8, 8, 8, 8], 40000, 443, true);
+        syn[32] = 0x60; // 24-byte TCP header
+        syn.extend_from_slice(&[2, 4, 0x05, 0xB4]); // MSS 1460
+        syn[3] = syn.len() as u8;
+
+        let core = VoyageCore::new(ProxyConfig::default());
+        assert_eq!(core.clamp_mss(&mut syn.clone()), None);
+
+        let core = VoyageCore::new(ProxyConfig::default().with_mss_clamp(1500, 100));
+        assert_eq!(core.clamp_mss(&mut syn), Some(1460));
+        assert_eq!(u16::from_be_bytes([syn[42], syn[43]]), 1360);
+    }
+
+    #[test]
+    fn test_fake_ip_range_avoids_local_networks() {
+        let mut core = VoyageCore::new(ProxyConfig::default());
+        let default_range = core.fake_ip_pool.range();
+        core.fake_ip_pool.allocate("example.com");
+
+        let home: Ipv4Range = "192.168.1.0/24".parse().unwrap();
+        assert_eq!(core.set_local_networks(vec![home]), default_range);
+        assert!(core.drain_events().is_empty());
+        assert_eq!(core.fake_ip_pool.len(), 1);
+
+        // A corporate VPN that happens to use the benchmarking block
+        let vpn: Ipv4Range = "198.18.0.0/16".parse().unwrap();
+        let chosen = core.set_local_networks(vec![home, vpn]);
+        assert!(!chosen.overlaps(&vpn));
+        assert_eq!(core.fake_ip_pool.range(), chosen);
+        assert!(core.fake_ip_pool.is_empty());
+
+        let events = core.drain_events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].key, "warning.fake_ip_conflict");
+        assert!(core.drain_events().is_empty());
+    }
+
+    #[test]
+    fn test_plan_dns_follows_rules() {
+        let mut core = VoyageCore::new(ProxyConfig::default());
+        core.load_rules("DOMAIN-SUFFIX, ads.example, REJECT\nFINAL, PROXY")
+            .unwrap();
+
+        let query = |name: &str| DnsMessage {
+            id: 7,
+            flags: 0x0100,
+            questions: vec![dns::DnsQuestion {
+                name: name.into(),
+                qtype: dns::TYPE_A,
+                qclass: dns::CLASS_IN,
+            }],
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+        };
+
+        match core.plan_dns(&query("tracker.ads.example")) {
+            DnsPlan::Answer(reply) => assert_eq!(reply.rcode(), dns::RCODE_NXDOMAIN),
+            other => panic!("unexpected plan {:?}", other),
+        }
+        let fake_ip = match core.plan_dns(&query("example.org")) {
+            DnsPlan::Answer(reply) => reply.answers[0].data.clone(),
+            other => panic!("unexpected plan {:?}", other),
+        };
+        assert_eq!(core.fake_ip_pool.len(), 1);
+        // Local answers feed the domain map too
+        let dns::RecordData::A(fake_ip) = fake_ip else {
+            panic!("expected an A record");
+        };
+        assert_eq!(
+            core.proxy_manager().domain_for_ip(fake_ip.into()),
+            Some("example.org")
+        );
+        // DNS lookups are not counted as routed connections
+        assert_eq!(core.proxy_manager().get_stats().proxied_connections, 0);
     }
 
     #[test]
@@ -181,9 +2593,10 @@ FINAL, DIRECT
             server_port: 1080,
             username: None,
             password: None,
+            ..Default::default()
         };
 
-        let mut core = VoyageCore::new(config);
+        let core = VoyageCore::new(config);
 
         assert!(core.is_enabled());
 
@@ -194,43 +2607,3 @@ FINAL, DIRECT
         assert!(core.is_enabled());
     }
 }
-
-/// Helper function to create a TCP packet for testing
-pub fn create_tcp_packet(
-    src_ip: [u8; 4],
-    dst_ip: [u8; 4],
-    src_port: u16,
-    dst_port: u16,
-    syn: bool,
-) -> Vec<u8> {
-    let mut packet = vec![0u8; 40];
-    
-    // IPv4 header
-    packet[0] = 0x45; // Version 4, IHL 5
-    packet[1] = 0x00; // DSCP/ECN
-    packet[2] = 0x00; // Total length (high)
-    packet[3] = 0x28; // Total length (low) = 40
-    packet[4..6].copy_from_slice(&[0x00, 0x00]); // ID
-    packet[6..8].copy_from_slice(&[0x40, 0x00]); // Flags + Fragment
-    packet[8] = 64; // TTL
-    packet[9] = 6; // Protocol: TCP
-    packet[10..12].copy_from_slice(&[0x00, 0x00]); // Checksum (placeholder)
-    packet[12..16].copy_from_slice(&src_ip);
-    packet[16..20].copy_from_slice(&dst_ip);
-    
-    // TCP header
-    packet[20] = (src_port >> 8) as u8;
-    packet[21] = src_port as u8;
-    packet[22] = (dst_port >> 8) as u8;
-    packet[23] = dst_port as u8;
-    packet[24..28].copy_from_slice(&[0x00, 0x00, 0x00, 0x01]); // Seq
-    packet[28..32].copy_from_slice(&[0x00, 0x00, 0x00, 0x00]); // Ack
-    packet[32] = 0x50; // Data offset (5 words)
-    packet[33] = if syn { 0x02 } else { 0x10 }; // Flags: SYN or ACK
-    packet[34..36].copy_from_slice(&[0xFF, 0xFF]); // Window
-    packet[36..38].copy_from_slice(&[0x00, 0x00]); // Checksum
-    packet[38..40].copy_from_slice(&[0x00, 0x00]); // Urgent ptr
-    
-    packet
-}
-
@@ -0,0 +1,262 @@
+//! Configuration Linting
+//!
+//! This module checks a configuration file without loading it, so the app
+//! can show problems before activating a profile. Errors are what
+//! `VoyageConfig::parse` would refuse, all of them rather than the first;
+//! warnings point at settings that load but cannot do what they say, such
+//! as rules that can never match because an earlier rule catches their
+//! traffic.
+
+use std::collections::HashMap;
+
+use crate::config::FakeIpConfig;
+use crate::fakeip::Ipv4Range;
+use crate::message::LocalizedMessage;
+use crate::profile::{self, ConfigFormat, SyntaxError, VoyageConfig};
+use crate::rule::{RuleEngine, RuleType};
+
+/// How serious a diagnostic is
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// The configuration does not load
+    Error,
+    /// The configuration loads, but part of it has no effect
+    Warning,
+}
+
+/// A problem found in a configuration file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigDiagnostic {
+    pub severity: Severity,
+    /// 1-based line, if the problem could be located
+    pub line: Option<u32>,
+    /// 1-based column within `line`
+    pub column: Option<u32>,
+    pub message: LocalizedMessage,
+}
+
+/// Check `text` in the format `ConfigFormat::detect` guesses, after
+/// substituting `${NAME}` placeholders from `variables`
+pub fn validate_config(text: &str, variables: &HashMap<String, String>) -> Vec<ConfigDiagnostic> {
+    match profile::substitute(text, variables) {
+        Ok(text) => lint(&text, ConfigFormat::detect(&text)),
+        Err(e) => vec![syntax_diagnostic(e)],
+    }
+}
+
+/// Check `text` written in `format`, returning diagnostics ordered by line.
+/// An empty list means the file loads and every setting takes effect.
+pub fn lint(text: &str, format: ConfigFormat) -> Vec<ConfigDiagnostic> {
+    let config = match VoyageConfig::deserialize(text, format) {
+        Ok(config) => config,
+        Err(e) => return vec![syntax_diagnostic(e)],
+    };
+
+    let mut diagnostics: Vec<ConfigDiagnostic> = config
+        .problems()
+        .into_iter()
+        .map(|problem| {
+            let (line, column) = locate(text, &problem.needle, 0);
+            ConfigDiagnostic {
+                severity: Severity::Error,
+                line,
+                column,
+                message: LocalizedMessage::new("error.config", vec![problem.detail]),
+            }
+        })
+        .collect();
+    lint_rules(text, &config, &mut diagnostics);
+    lint_networks(text, &config, &mut diagnostics);
+
+    // Diagnostics without a line go last
+    diagnostics.sort_by_key(|diagnostic| (diagnostic.line.unwrap_or(u32::MAX), diagnostic.column));
+    diagnostics
+}
+
+/// Warn about rules an earlier rule always matches first
+fn lint_rules(text: &str, config: &VoyageConfig, diagnostics: &mut Vec<ConfigDiagnostic>) {
+    let mut earlier: Vec<(&str, RuleType)> = Vec::new();
+    let mut from_line = 0;
+    for rule in &config.rules {
+        // Rules are listed in order, so each is searched below the last
+        let (line, column) = locate(text, rule, from_line);
+        if let Some(line) = line {
+            from_line = line as usize;
+        }
+        // Rules that do not parse were reported as errors
+        let Ok(parsed) = RuleEngine::parse_config(&config.translate_rule(rule)) else {
+            continue;
+        };
+        let Some(rule_type) = parsed.into_iter().next().map(|parsed| parsed.rule_type) else {
+            continue;
+        };
+
+        if let Some((cover, cover_type)) = earlier
+            .iter()
+            .find(|(_, earlier)| earlier.covers(&rule_type))
+        {
+            let key = if *cover_type == RuleType::Final {
+                "lint.unreachable_after_final"
+            } else {
+                "lint.shadowed_rule"
+            };
+            diagnostics.push(ConfigDiagnostic {
+                severity: Severity::Warning,
+                line,
+                column,
+                message: LocalizedMessage::new(key, vec![rule.clone(), cover.to_string()]),
+            });
+        }
+        earlier.push((rule, rule_type));
+    }
+}
+
+/// Warn about overlapping skip-proxy networks and a fake-IP range that
+/// collides with them
+fn lint_networks(text: &str, config: &VoyageConfig, diagnostics: &mut Vec<ConfigDiagnostic>) {
+    let networks: Vec<(&str, Ipv4Range)> = config
+        .general
+        .skip_proxy
+        .iter()
+        .filter_map(|network| Some((network.as_str(), network.parse().ok()?)))
+        .collect();
+
+    for (index, (network, range)) in networks.iter().enumerate() {
+        if let Some((other, _)) = networks[..index]
+            .iter()
+            .find(|(_, other)| other.overlaps(range))
+        {
+            let (line, column) = locate(text, network, 0);
+            diagnostics.push(ConfigDiagnostic {
+                severity: Severity::Warning,
+                line,
+                column,
+                message: LocalizedMessage::new(
+                    "lint.overlapping_networks",
+                    vec![network.to_string(), other.to_string()],
+                ),
+            });
+        }
+    }
+
+    if !config.dns.fake_ip {
+        return;
+    }
+    let fake_ip = match &config.dns.fake_ip_range {
+        Some(range) => match range.parse::<Ipv4Range>() {
+            Ok(parsed) => parsed,
+            Err(_) => return,
+        },
+        None => FakeIpConfig::default().range,
+    };
+    if let Some((network, _)) = networks.iter().find(|(_, range)| range.overlaps(&fake_ip)) {
+        let (line, column) = match &config.dns.fake_ip_range {
+            Some(range) => locate(text, range, 0),
+            None => locate(text, network, 0),
+        };
+        diagnostics.push(ConfigDiagnostic {
+            severity: Severity::Warning,
+            line,
+            column,
+            message: LocalizedMessage::new(
+                "lint.fake_ip_overlap",
+                vec![fake_ip.to_string(), network.to_string()],
+            ),
+        });
+    }
+}
+
+fn syntax_diagnostic(e: SyntaxError) -> ConfigDiagnostic {
+    ConfigDiagnostic {
+        severity: Severity::Error,
+        line: e.line.map(|line| line as u32),
+        column: e.column.map(|column| column as u32),
+        message: LocalizedMessage::new("error.config", vec![e.detail]),
+    }
+}
+
+/// 1-based line and column of the first mention of `needle` at or below
+/// 0-based line `from_line`
+fn locate(text: &str, needle: &str, from_line: usize) -> (Option<u32>, Option<u32>) {
+    if needle.is_empty() {
+        return (None, None);
+    }
+    text.lines()
+        .enumerate()
+        .skip(from_line)
+        .find_map(|(index, line)| {
+            line.find(needle)
+                .map(|offset| (Some(index as u32 + 1), Some(offset as u32 + 1)))
+        })
+        .unwrap_or((None, None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(diagnostics: &[ConfigDiagnostic]) -> Vec<(Severity, Option<u32>, &str)> {
+        diagnostics
+            .iter()
+            .map(|d| (d.severity, d.line, d.message.key.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn test_lint_rules() {
+        let text = "\
+proxies:
+  - {name: Home, type: socks5, server: 10.0.0.2, port: 1080}
+rules:
+  - DOMAIN-SUFFIX, google.com, Home
+  - DOMAIN, www.google.com, DIRECT
+  - IP-CIDR, 10.0.0.0/8, DIRECT
+  - IP-CIDR, 10.1.0.0/16, Home
+  - DOMAIN, example.com, Nowhere
+  - FINAL, DIRECT
+  - DOMAIN, late.example.com, Home
+";
+        let diagnostics = validate_config(text, &HashMap::new());
+        assert_eq!(
+            keys(&diagnostics),
+            vec![
+                (Severity::Warning, Some(5), "lint.shadowed_rule"),
+                (Severity::Warning, Some(7), "lint.shadowed_rule"),
+                (Severity::Error, Some(8), "error.config"),
+                (Severity::Warning, Some(10), "lint.unreachable_after_final"),
+            ]
+        );
+        assert_eq!(diagnostics[0].column, Some(5));
+        assert_eq!(
+            diagnostics[3].message.render(),
+            "Rule DOMAIN, late.example.com, Home is never reached: it comes after FINAL, DIRECT"
+        );
+        assert!(VoyageConfig::parse(text, ConfigFormat::Yaml).is_err());
+    }
+
+    #[test]
+    fn test_lint_networks_and_syntax() {
+        let text = "\
+[general]
+skip-proxy = [\"192.168.0.0/16\", \"192.168.1.0/24\", \"198.18.0.0/24\"]
+";
+        let diagnostics = validate_config(text, &HashMap::new());
+        assert_eq!(
+            keys(&diagnostics),
+            vec![
+                (Severity::Warning, Some(2), "lint.overlapping_networks"),
+                (Severity::Warning, Some(2), "lint.fake_ip_overlap"),
+            ]
+        );
+        assert!(diagnostics[0].column < diagnostics[1].column);
+
+        let diagnostics = validate_config("{\n  \"rules\": [\n    1\n  ]\n}", &HashMap::new());
+        assert_eq!(
+            keys(&diagnostics),
+            vec![(Severity::Error, Some(3), "error.config")]
+        );
+        assert_eq!(diagnostics[0].column, Some(5));
+
+        assert!(validate_config("rules:\n  - FINAL, DIRECT\n", &HashMap::new()).is_empty());
+    }
+}
@@ -0,0 +1,187 @@
+//! Log Delivery
+//!
+//! This module installs a `log` backend that hands records to a host
+//! callback, since stderr goes nowhere inside a Network Extension. Records
+//! are queued on a bounded channel and delivered from a dedicated thread,
+//! so a slow console never blocks the datapath; when the queue is full new
+//! records are dropped and counted.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::error::VoyageError;
+
+/// Records queued for delivery before new ones are dropped
+pub const LOG_QUEUE_SIZE: usize = 1024;
+
+/// Severity of a log record
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<log::Level> for LogLevel {
+    fn from(level: log::Level) -> Self {
+        match level {
+            log::Level::Error => LogLevel::Error,
+            log::Level::Warn => LogLevel::Warn,
+            log::Level::Info => LogLevel::Info,
+            log::Level::Debug => LogLevel::Debug,
+            log::Level::Trace => LogLevel::Trace,
+        }
+    }
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => LevelFilter::Error,
+            LogLevel::Warn => LevelFilter::Warn,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Debug => LevelFilter::Debug,
+            LogLevel::Trace => LevelFilter::Trace,
+        }
+    }
+}
+
+/// A log record handed to the host
+#[derive(Debug, Clone)]
+pub struct LogRecord {
+    /// Severity
+    pub level: LogLevel,
+    /// Module that logged the record
+    pub target: String,
+    /// Formatted message
+    pub message: String,
+    /// When the record was logged, in milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+}
+
+/// `log` backend feeding the delivery queue
+struct QueueLogger {
+    queue: Mutex<Option<SyncSender<LogRecord>>>,
+    dropped: AtomicU64,
+}
+
+static LOGGER: QueueLogger = QueueLogger {
+    queue: Mutex::new(None),
+    dropped: AtomicU64::new(0),
+};
+
+/// Whether `LOGGER` became the global logger
+static INSTALLED: OnceLock<bool> = OnceLock::new();
+
+impl Log for QueueLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let Ok(queue) = self.queue.lock() else {
+            return;
+        };
+        let Some(sender) = queue.as_ref() else {
+            return;
+        };
+
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0);
+        let record = LogRecord {
+            level: record.level().into(),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+            timestamp_ms,
+        };
+        if let Err(TrySendError::Full(_)) = sender.try_send(record) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Deliver records at `level` and above to `deliver`, replacing any
+/// previous sink.
+///
+/// Fails if another `log` backend was installed first.
+pub fn set_sink<F>(level: LogLevel, mut deliver: F) -> Result<(), VoyageError>
+where
+    F: FnMut(LogRecord) + Send + 'static,
+{
+    if !*INSTALLED.get_or_init(|| log::set_logger(&LOGGER).is_ok()) {
+        return Err(VoyageError::config(
+            "Another logger is already installed".into(),
+        ));
+    }
+
+    let (sender, records) = mpsc::sync_channel(LOG_QUEUE_SIZE);
+    std::thread::Builder::new()
+        .name("voyage-log".into())
+        .spawn(move || {
+            // Ends once the sender is replaced or cleared
+            for record in records {
+                deliver(record);
+            }
+        })
+        .map_err(|e| VoyageError::io(e.to_string()))?;
+
+    *LOGGER.queue.lock().map_err(|_| VoyageError::LockError)? = Some(sender);
+    log::set_max_level(level.into());
+    Ok(())
+}
+
+/// Stop delivering records; records already queued are still delivered
+pub fn clear_sink() {
+    if *INSTALLED.get().unwrap_or(&false) {
+        log::set_max_level(LevelFilter::Off);
+    }
+    if let Ok(mut queue) = LOGGER.queue.lock() {
+        queue.take();
+    }
+}
+
+/// Records dropped because the queue was full
+pub fn dropped_records() -> u64 {
+    LOGGER.dropped.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_sink_receives_records() {
+        let (tx, rx) = mpsc::channel();
+        set_sink(LogLevel::Info, move |record| {
+            if record.target == "voyage_log_test" {
+                let _ = tx.send(record);
+            }
+        })
+        .unwrap();
+
+        log::debug!(target: "voyage_log_test", "filtered out");
+        log::warn!(target: "voyage_log_test", "proxy {} unreachable", "a");
+        let record = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(record.level, LogLevel::Warn);
+        assert_eq!(record.message, "proxy a unreachable");
+        assert!(record.timestamp_ms > 0);
+
+        clear_sink();
+        log::error!(target: "voyage_log_test", "after clear");
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+}
@@ -0,0 +1,209 @@
+//! Background Maintenance
+//!
+//! This module runs the periodic housekeeping the engine needs: expiring
+//! NAT entries, reaping flows whose relay died and closing their smoltcp
+//! sockets. It runs as a task on the core's runtime, started and stopped
+//! together with the engine.
+
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use smoltcp::iface::SocketHandle;
+use tokio::runtime::Handle;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+use crate::connection::ConnectionManager;
+use crate::iface::InterfaceManager;
+use crate::VoyageCore;
+
+/// Cumulative maintenance metrics
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MaintenanceStats {
+    /// Maintenance passes run
+    pub runs: u64,
+    /// Flows removed because they closed or idled out
+    pub expired_flows: u64,
+    /// Orphaned smoltcp sockets handed out for closing
+    pub orphaned_sockets: u64,
+    /// Duration of the last pass in microseconds
+    pub last_run_us: u64,
+}
+
+/// Result of a single maintenance pass
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MaintenanceReport {
+    /// Flows removed because they closed or idled out
+    pub expired_flows: usize,
+    /// Sockets of removed or reaped flows
+    pub orphaned_sockets: Vec<SocketHandle>,
+}
+
+/// Run one maintenance pass over the connection table.
+///
+/// Orphaned sockets are closed when the interface is given; otherwise they
+/// are returned for the owner of the socket set to close.
+pub fn run_once(
+    conn_manager: &mut ConnectionManager,
+    iface: Option<&mut InterfaceManager>,
+) -> MaintenanceReport {
+    let expired_flows = conn_manager.cleanup();
+    let mut orphaned_sockets = conn_manager.reap_orphaned();
+
+    if let Some(iface) = iface {
+        iface.close_orphaned(&orphaned_sockets);
+        orphaned_sockets.clear();
+    }
+
+    MaintenanceReport {
+        expired_flows,
+        orphaned_sockets,
+    }
+}
+
+impl MaintenanceStats {
+    /// Fold a pass into the totals
+    pub fn record(&mut self, report: &MaintenanceReport, took: Duration) {
+        self.runs += 1;
+        self.expired_flows += report.expired_flows as u64;
+        self.orphaned_sockets += report.orphaned_sockets.len() as u64;
+        self.last_run_us = took.as_micros() as u64;
+    }
+}
+
+/// Handle to the background maintenance task; stopping (or dropping) it
+/// ends the loop
+pub struct MaintenanceTask {
+    stop: Arc<Notify>,
+    task: JoinHandle<()>,
+}
+
+impl MaintenanceTask {
+    /// Start running `VoyageCore::run_maintenance` every `interval` on
+    /// `runtime`
+    pub fn start(core: Arc<RwLock<VoyageCore>>, runtime: &Handle, interval: Duration) -> Self {
+        let stop = Arc::new(Notify::new());
+        let stopped = Arc::clone(&stop);
+
+        let task = runtime.spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+            // The first tick completes immediately
+            ticker.tick().await;
+            loop {
+                if tokio::time::timeout(interval, stopped.notified()).await.is_ok() {
+                    break;
+                }
+                ticker.tick().await;
+                // Wait for the core lock off the runtime's workers
+                let core = Arc::clone(&core);
+                let pass = tokio::task::spawn_blocking(move || match core.write() {
+                    Ok(mut core) => {
+                        core.run_maintenance();
+                        true
+                    }
+                    Err(_) => false,
+                });
+                if !matches!(pass.await, Ok(true)) {
+                    log::error!("Core lock poisoned, stopping maintenance");
+                    break;
+                }
+            }
+        });
+
+        log::debug!("Maintenance task started ({:?} interval)", interval);
+        Self { stop, task }
+    }
+
+    /// Stop the task; a pass already running completes
+    pub fn stop(self) {
+        drop(self);
+    }
+}
+
+impl Drop for MaintenanceTask {
+    fn drop(&mut self) {
+        self.stop.notify_one();
+        self.task.abort();
+        log::debug!("Maintenance task stopped");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    use crate::config::ProxyConfig;
+    use crate::nat::NatKey;
+
+    fn closed_flow(manager: &mut ConnectionManager, src_port: u16) -> NatKey {
+        let key = NatKey::tcp(
+            format!("10.0.0.1:{}", src_port).parse().unwrap(),
+            "8.8.8.8:443".parse().unwrap(),
+        );
+        let packet = crate::create_tcp_packet([10, 0, 0, 1], [8, 8, 8, 8], src_port, 443, true);
+        manager
+            .process_packet(&crate::packet::ParsedPacket::parse(&packet).unwrap())
+            .unwrap();
+        manager.close_connection(&key);
+        key
+    }
+
+    #[test]
+    fn test_run_once_closes_sockets() {
+        let mut iface = InterfaceManager::new();
+        let mut manager = ConnectionManager::new();
+        let key = closed_flow(&mut manager, 10001);
+        manager.register_socket(key, iface.create_tcp_socket());
+
+        let report = run_once(&mut manager, Some(&mut iface));
+        assert_eq!(report.expired_flows, 1);
+        assert!(report.orphaned_sockets.is_empty());
+        assert_eq!(iface.socket_count(), 0);
+        assert_eq!(manager.active_connections(), 0);
+    }
+
+    #[test]
+    fn test_run_once_without_iface_returns_sockets() {
+        let mut iface = InterfaceManager::new();
+        let mut manager = ConnectionManager::new();
+        let key = closed_flow(&mut manager, 10001);
+        let handle = iface.create_tcp_socket();
+        manager.register_socket(key, handle);
+
+        let report = run_once(&mut manager, None);
+        assert_eq!(report.orphaned_sockets, vec![handle]);
+
+        let mut stats = MaintenanceStats::default();
+        stats.record(&report, Duration::from_micros(15));
+        assert_eq!(stats.runs, 1);
+        assert_eq!(stats.expired_flows, 1);
+        assert_eq!(stats.orphaned_sockets, 1);
+        assert_eq!(stats.last_run_us, 15);
+    }
+
+    #[test]
+    fn test_background_task_runs_and_stops() {
+        let core = Arc::new(RwLock::new(VoyageCore::new(ProxyConfig::default())));
+        {
+            let core = core.read().unwrap();
+            let packet = crate::create_tcp_packet([10, 0, 0, 1], [8, 8, 8, 8], 10001, 443, true);
+            let parsed = crate::packet::ParsedPacket::parse(&packet).unwrap();
+            let info = core.conn_manager.process_packet(&parsed).unwrap();
+            core.conn_manager.close_connection(&info.key);
+        }
+
+        let runtime = core.write().unwrap().runtime().unwrap();
+        let task = MaintenanceTask::start(Arc::clone(&core), &runtime, Duration::from_millis(5));
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while core.read().unwrap().maintenance_stats().runs == 0 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        task.stop();
+
+        let core = core.read().unwrap();
+        assert!(core.maintenance_stats().runs >= 1);
+        assert_eq!(core.maintenance_stats().expired_flows, 1);
+        assert_eq!(core.conn_manager.active_connections(), 0);
+    }
+}
@@ -0,0 +1,170 @@
+//! Memory Accounting
+//!
+//! This module estimates what the core keeps on the heap, broken down by
+//! owner, so the host can shed load before the system terminates the
+//! extension. Figures are derived from table sizes and the buffers the
+//! interfaces allocated rather than measured by the allocator, so they are
+//! a lower bound that tracks the real footprint closely enough to react to
+//! trends.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::device::{PacketQueue, WeakPacketQueue};
+
+/// Estimated heap usage per owner, in bytes
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// Receive and send buffers of the TCP sockets of interfaces created
+    /// by the core
+    pub socket_buffers: u64,
+    /// NAT table and its port index
+    pub nat_entries: u64,
+    /// Packets waiting in the queues of interfaces created by the core
+    pub queued_packets: u64,
+    /// Cached DNS answers
+    pub dns_cache: u64,
+    /// Idle buffers kept for reuse by the packet buffer pool
+    pub buffer_pool: u64,
+    /// Sum of the above
+    pub total: u64,
+    /// Memory budget set by the host (0 when unlimited)
+    pub budget: u64,
+}
+
+impl MemoryStats {
+    /// Fill in `total` from the individual owners
+    pub fn with_total(mut self) -> Self {
+        self.total = self.socket_buffers
+            + self.nat_entries
+            + self.queued_packets
+            + self.dns_cache
+            + self.buffer_pool;
+        self
+    }
+}
+
+/// Bytes of socket buffers an interface has allocated
+#[derive(Debug, Clone, Default)]
+pub struct SocketBufferGauge(Arc<AtomicUsize>);
+
+impl SocketBufferGauge {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Account for a socket with `bytes` of buffers
+    pub fn add(&self, bytes: usize) {
+        self.0.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Stop accounting for a socket with `bytes` of buffers
+    pub fn remove(&self, bytes: usize) {
+        self.0.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    /// Buffer bytes of the live sockets
+    pub fn bytes(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Socket buffer gauges of the interfaces the core created, held weakly
+/// like their packet queues
+#[derive(Debug, Default)]
+pub struct GaugeRegistry {
+    gauges: Mutex<Vec<Weak<AtomicUsize>>>,
+}
+
+impl GaugeRegistry {
+    /// Create an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start accounting for `gauge`, forgetting gauges that were dropped
+    pub fn register(&self, gauge: &SocketBufferGauge) {
+        if let Ok(mut gauges) = self.gauges.lock() {
+            gauges.retain(|gauge| gauge.strong_count() > 0);
+            gauges.push(Arc::downgrade(&gauge.0));
+        }
+    }
+
+    /// Socket buffer bytes of all live interfaces
+    pub fn total_bytes(&self) -> usize {
+        let Ok(gauges) = self.gauges.lock() else {
+            return 0;
+        };
+        gauges
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|bytes| bytes.load(Ordering::Relaxed))
+            .sum()
+    }
+}
+
+/// Packet queues of the interfaces the core created, held weakly so
+/// dropping an interface also drops its entry
+#[derive(Debug, Default)]
+pub struct QueueRegistry {
+    queues: Mutex<Vec<WeakPacketQueue>>,
+}
+
+impl QueueRegistry {
+    /// Create an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start accounting for `queue`, forgetting queues that were dropped
+    pub fn register(&self, queue: &PacketQueue) {
+        if let Ok(mut queues) = self.queues.lock() {
+            queues.retain(|queue| !queue.is_dropped());
+            queues.push(queue.downgrade());
+        }
+    }
+
+    /// Bytes of all packets currently queued
+    pub fn queued_bytes(&self) -> usize {
+        let Ok(queues) = self.queues.lock() else {
+            return 0;
+        };
+        queues
+            .iter()
+            .filter_map(WeakPacketQueue::upgrade)
+            .map(|queue| queue.bytes())
+            .sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_queue_registry() {
+        let registry = QueueRegistry::new();
+        let queue = PacketQueue::new();
+        registry.register(&queue);
+        queue.push_back(vec![0; 100]);
+        queue.push_back(vec![0; 20]);
+        assert_eq!(registry.queued_bytes(), 120);
+
+        drop(queue);
+        assert_eq!(registry.queued_bytes(), 0);
+    }
+
+    #[test]
+    fn test_total() {
+        let stats = MemoryStats {
+            socket_buffers: 1,
+            nat_entries: 2,
+            queued_packets: 3,
+            dns_cache: 4,
+            buffer_pool: 5,
+            ..Default::default()
+        }
+        .with_total();
+        assert_eq!(stats.total, 15);
+    }
+}
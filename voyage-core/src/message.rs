@@ -0,0 +1,153 @@
+//! Message Catalog
+//!
+//! This module defines stable keys for user-facing errors and status
+//! messages, along with their English defaults. Hosts localize a message
+//! by key and substitute `{0}`, `{1}`, ... with its parameters.
+
+/// A user-facing message as a stable key plus positional parameters
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalizedMessage {
+    /// Stable catalog key, e.g. `error.invalid_packet`
+    pub key: String,
+    /// Positional parameters substituted into the template
+    pub args: Vec<String>,
+}
+
+/// A catalog entry with its English default text
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageTemplate {
+    /// Stable catalog key
+    pub key: String,
+    /// English template with `{0}`-style placeholders
+    pub default_text: String,
+}
+
+/// All message keys with their English templates
+pub const CATALOG: &[(&str, &str)] = &[
+    // Core errors
+    ("error.not_initialized", "Core not initialized"),
+    ("error.already_initialized", "Core already initialized"),
+    ("error.lock", "Lock error"),
+    ("error.invalid_packet", "Invalid packet: {0}"),
+    ("error.socket", "Socket error: {0}"),
+    ("error.nat_table_full", "NAT table full"),
+    ("error.connection", "Connection error: {0}"),
+    ("error.nat", "NAT error: {0}"),
+    ("error.rule", "Rule error: {0}"),
+    ("error.rule_syntax", "Rule error on line {0}: {1}"),
+    ("error.socks5", "SOCKS5 error: {0}"),
+    ("error.io", "IO error: {0}"),
+    ("error.config", "Configuration error: {0}"),
+    ("error.config_syntax", "Configuration error on line {0}: {1}"),
+    // Configuration import
+    ("import.invalid_line", "Unrecognized line skipped: {0}"),
+    ("import.unsupported_section", "Section [{0}] is not supported and was skipped"),
+    ("import.unsupported_setting", "Setting {0} is not supported and was skipped"),
+    ("import.invalid_value", "Value {0} of {1} is not supported and was skipped"),
+    ("import.unsupported_proxy", "Proxy {0} of type {1} is not supported and was skipped"),
+    ("import.invalid_proxy", "Proxy {0} was skipped: {1}"),
+    ("import.duplicate_name", "Name {0} is already taken; the entry was skipped"),
+    ("import.renamed", "{0} is a built-in policy name; imported as {1}"),
+    ("import.unsupported_option", "Option {0} of {1} is not supported and was ignored"),
+    ("import.unsupported_group", "Group {0} of type {1} was imported as a select group"),
+    ("import.dropped_member", "Member {0} of group {1} was not imported and was removed"),
+    ("import.empty_group", "Group {0} has no members left and was skipped"),
+    ("import.unsupported_rule", "Rule {0} of type {1} is not supported and was skipped"),
+    ("import.invalid_rule", "Rule {0} was skipped: {1}"),
+    ("import.unknown_policy", "Rule {0} names policy {1}, which was not imported; the rule was skipped"),
+    ("import.rejected_as_reject", "Policy {0} was imported as REJECT"),
+    (
+        "warning.fake_ip_conflict",
+        "Fake-IP range {0} overlaps local network {1}; using {2} instead",
+    ),
+    (
+        "warning.fake_ip_no_free_range",
+        "Fake-IP range {0} overlaps local network {1} and no alternate range is free",
+    ),
+    ("warning.health_check_failed", "Health check {0} failed: {1}"),
+    // Configuration linting
+    ("lint.unreachable_after_final", "Rule {0} is never reached: it comes after {1}"),
+    ("lint.shadowed_rule", "Rule {0} is never reached: {1} matches everything it does"),
+    ("lint.overlapping_networks", "Skip-proxy network {0} overlaps {1}"),
+    (
+        "lint.fake_ip_overlap",
+        "Fake-IP range {0} overlaps skip-proxy network {1}; an alternate range will be used",
+    ),
+    // Status
+    ("status.config_reloaded", "Configuration reloaded; changed: {0}"),
+    // SOCKS5 server replies
+    ("socks5.reply.succeeded", "Succeeded"),
+    ("socks5.reply.general_failure", "General SOCKS server failure"),
+    ("socks5.reply.connection_not_allowed", "Connection not allowed by ruleset"),
+    ("socks5.reply.network_unreachable", "Network unreachable"),
+    ("socks5.reply.host_unreachable", "Host unreachable"),
+    ("socks5.reply.connection_refused", "Connection refused"),
+    ("socks5.reply.ttl_expired", "TTL expired"),
+    ("socks5.reply.command_not_supported", "Command not supported"),
+    ("socks5.reply.address_type_not_supported", "Address type not supported"),
+];
+
+/// Look up the English template for a key
+pub fn default_text(key: &str) -> Option<&'static str> {
+    CATALOG
+        .iter()
+        .find(|(k, _)| *k == key)
+        .map(|(_, text)| *text)
+}
+
+/// Get the full catalog, e.g. to seed the host's string tables
+pub fn catalog() -> Vec<MessageTemplate> {
+    CATALOG
+        .iter()
+        .map(|(key, text)| MessageTemplate {
+            key: key.to_string(),
+            default_text: text.to_string(),
+        })
+        .collect()
+}
+
+impl LocalizedMessage {
+    /// Create a message with parameters
+    pub fn new(key: &str, args: Vec<String>) -> Self {
+        Self {
+            key: key.to_string(),
+            args,
+        }
+    }
+
+    /// Create a message without parameters
+    pub fn plain(key: &str) -> Self {
+        Self::new(key, Vec::new())
+    }
+
+    /// Render the message with its English template (falls back to the key)
+    pub fn render(&self) -> String {
+        let mut text = default_text(&self.key).unwrap_or(&self.key).to_string();
+        for (i, arg) in self.args.iter().enumerate() {
+            text = text.replace(&format!("{{{}}}", i), arg);
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let msg = LocalizedMessage::new("error.invalid_packet", vec!["Empty packet".into()]);
+        assert_eq!(msg.render(), "Invalid packet: Empty packet");
+        assert_eq!(LocalizedMessage::plain("error.lock").render(), "Lock error");
+        assert_eq!(LocalizedMessage::plain("no.such.key").render(), "no.such.key");
+    }
+
+    #[test]
+    fn test_catalog_keys_unique() {
+        let mut keys: Vec<&str> = CATALOG.iter().map(|(k, _)| *k).collect();
+        keys.sort();
+        keys.dedup();
+        assert_eq!(keys.len(), CATALOG.len());
+        assert_eq!(catalog().len(), CATALOG.len());
+    }
+}
@@ -0,0 +1,494 @@
+//! Metrics Exposition
+//!
+//! This module renders the engine's counters as OpenMetrics text so
+//! advanced users can scrape them with Prometheus: core stats, proxy
+//! stats, NAT table size, DNS cache hit rate and per-policy usage. The
+//! text is available over FFI and, on macOS, from an optional HTTP
+//! listener bound to localhost.
+
+use std::fmt::{Display, Write as _};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener as StdTcpListener};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Notify;
+
+use crate::rule::RouteAction;
+use crate::VoyageCore;
+
+/// Content type of the exposition
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Prefix of every metric name
+const PREFIX: &str = "voyage_";
+
+/// Longest request head the listener reads
+const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+/// How long the listener waits for a request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Every policy, in exposition order
+const POLICIES: [RouteAction; 3] = [RouteAction::Direct, RouteAction::Proxy, RouteAction::Reject];
+
+/// OpenMetrics text being built
+#[derive(Debug, Default)]
+struct Exposition {
+    out: String,
+}
+
+impl Exposition {
+    fn family(&mut self, name: &str, kind: &str, help: &str) {
+        let _ = writeln!(self.out, "# TYPE {PREFIX}{name} {kind}");
+        let _ = writeln!(self.out, "# HELP {PREFIX}{name} {help}");
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: impl Display) {
+        let _ = write!(self.out, "{PREFIX}{name}");
+        if !labels.is_empty() {
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|(label, value)| format!("{label}=\"{}\"", escape(value)))
+                .collect();
+            let _ = write!(self.out, "{{{}}}", labels.join(","));
+        }
+        let _ = writeln!(self.out, " {value}");
+    }
+
+    fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.family(name, "counter", help);
+        self.sample(&format!("{name}_total"), &[], value);
+    }
+
+    fn gauge(&mut self, name: &str, help: &str, value: impl Display) {
+        self.family(name, "gauge", help);
+        self.sample(name, &[], value);
+    }
+
+    /// A counter with one sample per policy
+    fn policy_counter(&mut self, name: &str, help: &str, value: impl Fn(RouteAction) -> u64) {
+        self.family(name, "counter", help);
+        for policy in POLICIES {
+            let total = format!("{name}_total");
+            self.sample(&total, &[("policy", policy.name())], value(policy));
+        }
+    }
+
+    fn finish(mut self) -> String {
+        self.out.push_str("# EOF\n");
+        self.out
+    }
+}
+
+/// Escape a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Render the engine's counters as OpenMetrics text
+pub fn render(core: &VoyageCore) -> String {
+    let stats = core.get_stats();
+    let proxy = core.proxy_manager().get_stats().clone();
+    let dns = core.dns.stats();
+    let usage = core.conn_manager.usage_by_policy();
+    let mut text = Exposition::default();
+
+    text.counter(
+        "bytes_sent",
+        "Bytes sent by relayed flows.",
+        stats.bytes_sent,
+    );
+    text.counter(
+        "bytes_received",
+        "Bytes received by relayed flows.",
+        stats.bytes_received,
+    );
+    text.gauge(
+        "upload_rate_bytes",
+        "Current upload speed in bytes per second.",
+        stats.upload_rate,
+    );
+    text.gauge(
+        "download_rate_bytes",
+        "Current download speed in bytes per second.",
+        stats.download_rate,
+    );
+    text.gauge(
+        "active_connections",
+        "Connections currently tracked.",
+        stats.active_connections,
+    );
+    text.counter(
+        "connections",
+        "Connections since start.",
+        stats.total_connections,
+    );
+    text.counter(
+        "reaped_flows",
+        "Flows reaped because their relay died.",
+        stats.reaped_flows,
+    );
+    text.counter(
+        "expired_flows",
+        "Flows removed by background cleanup.",
+        stats.expired_flows,
+    );
+    text.counter(
+        "maintenance_runs",
+        "Background cleanup passes run.",
+        stats.maintenance_runs,
+    );
+    text.gauge(
+        "memory_usage_bytes",
+        "Estimated memory held by flows.",
+        stats.memory_usage,
+    );
+    text.gauge(
+        "memory_budget_bytes",
+        "Memory budget, 0 when unlimited.",
+        stats.memory_budget,
+    );
+    text.gauge(
+        "socket_buffer_bytes",
+        "Buffer memory of the TCP sockets terminating flows.",
+        stats.socket_buffer_bytes,
+    );
+    text.counter(
+        "connection_limit_hits",
+        "New flows refused by the connection or memory cap.",
+        stats.connection_limit_hits,
+    );
+    text.gauge(
+        "waiting_connections",
+        "Flows waiting for a concurrency slot.",
+        stats.waiting_connections,
+    );
+
+    text.gauge(
+        "nat_entries",
+        "Entries in the NAT table.",
+        stats.active_connections,
+    );
+    text.gauge(
+        "nat_capacity",
+        "Maximum entries in the NAT table.",
+        core.config.nat.max_entries,
+    );
+    text.counter(
+        "nat_evictions",
+        "Flows evicted from a full NAT table.",
+        stats.nat_evictions,
+    );
+    text.counter(
+        "nat_source_limit_hits",
+        "Flows refused by the per-source cap.",
+        stats.nat_source_limit_hits,
+    );
+    text.counter(
+        "rate_limited_connections",
+        "New connections dropped by the connection rate limits.",
+        stats.rate_limited_connections,
+    );
+    text.counter(
+        "rate_limit_blocks",
+        "Sources blocked for going over their connection rate.",
+        stats.rate_limit_blocks,
+    );
+    text.counter(
+        "malformed_packets",
+        "Inbound packets the parser rejected.",
+        stats.malformed_packets,
+    );
+    text.counter(
+        "nat_port_collisions",
+        "Local ports found taken while allocating NAT mappings.",
+        stats.nat_port_collisions,
+    );
+    text.counter(
+        "nat_invalid_transitions",
+        "TCP segments whose flags did not fit their flow's state.",
+        stats.nat_invalid_transitions,
+    );
+
+    text.policy_counter(
+        "routed_connections",
+        "Connections routed by policy.",
+        |policy| match policy {
+            RouteAction::Direct => proxy.direct_connections,
+            RouteAction::Proxy => proxy.proxied_connections,
+            RouteAction::Reject => proxy.rejected_connections,
+        },
+    );
+    text.counter(
+        "proxy_bytes_sent",
+        "Bytes sent through the proxy.",
+        proxy.proxy_bytes_sent,
+    );
+    text.counter(
+        "proxy_bytes_received",
+        "Bytes received through the proxy.",
+        proxy.proxy_bytes_received,
+    );
+    text.counter(
+        "queued_connections",
+        "Connections that waited for a concurrency slot.",
+        proxy.queued_connections,
+    );
+    text.counter(
+        "limit_rejected_connections",
+        "Connections rejected by a concurrency cap.",
+        proxy.limit_rejected_connections,
+    );
+    text.counter(
+        "route_cache_hits",
+        "Routes answered from the rule decision cache.",
+        proxy.route_cache_hits,
+    );
+    text.counter(
+        "route_cache_misses",
+        "Routes that had to run through the rules.",
+        proxy.route_cache_misses,
+    );
+    text.counter(
+        "quic_blocked",
+        "Proxied QUIC flows rejected to force a TCP fallback.",
+        proxy.quic_blocked,
+    );
+
+    text.policy_counter("policy_bytes_sent", "Bytes sent by policy.", |policy| {
+        usage.get(&policy).map_or(0, |usage| usage.bytes_sent)
+    });
+    text.policy_counter(
+        "policy_bytes_received",
+        "Bytes received by policy.",
+        |policy| usage.get(&policy).map_or(0, |usage| usage.bytes_received),
+    );
+    text.policy_counter(
+        "policy_connections",
+        "Connections opened by policy.",
+        |policy| usage.get(&policy).map_or(0, |usage| usage.connections),
+    );
+
+    text.counter("dns_queries", "DNS queries seen.", dns.queries);
+    text.counter(
+        "dns_failures",
+        "Forwarded DNS queries that failed.",
+        dns.failures,
+    );
+    text.counter("dns_blocked", "DNS queries blocked by a rule.", dns.blocked);
+    text.counter(
+        "dns_cache_hits",
+        "DNS queries answered from the cache.",
+        dns.cache_hits,
+    );
+    text.counter(
+        "dns_cache_misses",
+        "Cacheable DNS queries sent upstream.",
+        dns.cache_misses,
+    );
+    let lookups = dns.cache_hits + dns.cache_misses;
+    let hit_ratio = if lookups == 0 {
+        0.0
+    } else {
+        dns.cache_hits as f64 / lookups as f64
+    };
+    text.gauge(
+        "dns_cache_hit_ratio",
+        "Share of cacheable DNS queries hit.",
+        hit_ratio,
+    );
+    text.gauge(
+        "dns_cache_entries",
+        "Entries in the DNS cache.",
+        dns.cache_entries,
+    );
+
+    text.finish()
+}
+
+/// Handle to the localhost metrics listener; stopping (or dropping) it
+/// closes the socket
+pub struct MetricsServer {
+    port: u16,
+    stop: Arc<Notify>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    /// Serve `GET /metrics` on 127.0.0.1:`port` (0 picks a free port),
+    /// answering with the text `render` returns
+    pub fn start<F>(port: u16, render: F) -> std::io::Result<Self>
+    where
+        F: Fn() -> String + Send + Sync + 'static,
+    {
+        let listener = StdTcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, port)))?;
+        listener.set_nonblocking(true)?;
+        let port = listener.local_addr()?.port();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .enable_time()
+            .build()?;
+        let stop = Arc::new(Notify::new());
+        let stopped = Arc::clone(&stop);
+        let render = Arc::new(render);
+
+        let thread = std::thread::Builder::new()
+            .name("voyage-metrics".into())
+            .spawn(move || {
+                runtime.block_on(async move {
+                    let listener = match TcpListener::from_std(listener) {
+                        Ok(listener) => listener,
+                        Err(e) => {
+                            log::error!("Metrics listener failed: {}", e);
+                            return;
+                        }
+                    };
+                    let accept = tokio::spawn(async move {
+                        loop {
+                            match listener.accept().await {
+                                Ok((stream, _)) => {
+                                    tokio::spawn(serve(stream, Arc::clone(&render)));
+                                }
+                                Err(e) => log::warn!("Metrics accept failed: {}", e),
+                            }
+                        }
+                    });
+                    stopped.notified().await;
+                    accept.abort();
+                });
+            })?;
+
+        log::info!("Metrics listener on 127.0.0.1:{}", port);
+        Ok(Self {
+            port,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Port the listener is bound to
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Close the listener
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            self.stop.notify_one();
+            let _ = thread.join();
+            log::debug!("Metrics listener stopped");
+        }
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// Answer one request and close the connection
+async fn serve<F>(mut stream: TcpStream, render: Arc<F>)
+where
+    F: Fn() -> String + Send + Sync + 'static,
+{
+    let head = match tokio::time::timeout(REQUEST_TIMEOUT, read_head(&mut stream)).await {
+        Ok(Some(head)) => head,
+        _ => return,
+    };
+    let request_line = head.lines().next().unwrap_or_default();
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => response("200 OK", CONTENT_TYPE, &render()),
+        (Some("GET"), _) => response("404 Not Found", "text/plain", "Not Found\n"),
+        _ => response(
+            "405 Method Not Allowed",
+            "text/plain",
+            "Method Not Allowed\n",
+        ),
+    };
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+/// Read up to the end of the request head
+async fn read_head(stream: &mut TcpStream) -> Option<String> {
+    let mut head = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await.ok()?;
+        if n == 0 || head.len() + n > MAX_REQUEST_SIZE {
+            return None;
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    String::from_utf8(head).ok()
+}
+
+fn response(status: &str, content_type: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream as StdTcpStream;
+
+    use crate::config::ProxyConfig;
+
+    #[test]
+    fn test_render() {
+        let core = VoyageCore::new(ProxyConfig::default());
+        let text = render(&core);
+
+        assert!(text.ends_with("# EOF\n"));
+        assert!(text.contains("# TYPE voyage_bytes_sent counter\n"));
+        assert!(text.contains("voyage_bytes_sent_total 0\n"));
+        assert!(text.contains("voyage_active_connections 0\n"));
+        assert!(text.contains("voyage_routed_connections_total{policy=\"PROXY\"} 0\n"));
+        assert!(text.contains("voyage_dns_cache_hit_ratio 0\n"));
+        assert!(text.contains(&format!(
+            "voyage_nat_capacity {}\n",
+            ProxyConfig::default().nat.max_entries
+        )));
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+
+    #[test]
+    fn test_server() {
+        let server = MetricsServer::start(0, || "voyage_up 1\n# EOF\n".to_string()).unwrap();
+        let get = |path: &str| {
+            let mut stream = StdTcpStream::connect(("127.0.0.1", server.port())).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains(CONTENT_TYPE));
+        assert!(response.ends_with("\r\n\r\nvoyage_up 1\n# EOF\n"));
+        assert!(get("/").starts_with("HTTP/1.1 404"));
+        server.stop();
+    }
+}
@@ -0,0 +1,612 @@
+//! TLS Interception
+//!
+//! This module terminates TLS for user-whitelisted hosts with certificates
+//! minted on the fly by a local CA, opens a verified TLS connection to the
+//! real server, and lets rewrite hooks see and edit the HTTP/1.1 request
+//! and response heads in between. It is compiled only with the `mitm`
+//! feature and used only for hosts on the interceptor's list; the CA
+//! certificate has to be installed and trusted on the device first.
+//!
+//! Only HTTP/1.1 is offered to the app, and each intercepted connection
+//! carries a single exchange (`Connection: close`), so every request
+//! passes through the core's rewrite rules and the hooks.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use rcgen::{
+    BasicConstraints, CertificateParams, DistinguishedName, DnType, ExtendedKeyUsagePurpose,
+    IsCa, Issuer, KeyPair, KeyUsagePurpose, PublicKeyData, SerialNumber, PKCS_ECDSA_P256_SHA256,
+};
+use ring::rand::{SecureRandom, SystemRandom};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::{CertifiedKey, SigningKey};
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use time::{Duration as TimeDuration, OffsetDateTime};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use x509_parser::parse_x509_certificate;
+
+use crate::error::VoyageError;
+use crate::rewrite::{self, HttpHead, RewriteEngine, UrlMode};
+use crate::tap::{host_matches, TapSession, TapStream};
+
+/// Validity of a generated CA certificate
+const CA_VALIDITY_DAYS: i64 = 3650;
+
+/// Validity of a minted host certificate; Apple platforms refuse server
+/// certificates valid for more than 398 days
+const LEAF_VALIDITY_DAYS: i64 = 365;
+
+/// Host certificates kept for reuse
+const CERT_CACHE_SIZE: usize = 256;
+
+/// Longest HTTP head accepted from either side
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+
+/// The only application protocol offered to both sides
+const ALPN_HTTP1: &[u8] = b"http/1.1";
+
+/// A local certificate authority that signs certificates for intercepted
+/// hosts
+pub struct CertificateAuthority {
+    /// Subject, key identifier and key of the CA, as minted certificates
+    /// reference them
+    issuer: Issuer<'static, KeyPair>,
+    key_der: Vec<u8>,
+    cert_der: Vec<u8>,
+}
+
+impl CertificateAuthority {
+    /// Generate a new CA with a fresh P-256 key
+    pub fn generate(common_name: &str) -> Result<Self, VoyageError> {
+        let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256)
+            .map_err(|_| VoyageError::config("Cannot generate a CA key".into()))?;
+        let mut params = certificate_params(common_name, CA_VALIDITY_DAYS)?;
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![
+            KeyUsagePurpose::DigitalSignature,
+            KeyUsagePurpose::KeyCertSign,
+            KeyUsagePurpose::CrlSign,
+        ];
+        let cert = params.self_signed(&key).map_err(signing_failed)?;
+        Ok(Self {
+            key_der: key.serialize_der(),
+            cert_der: cert.der().to_vec(),
+            issuer: Issuer::new(params, key),
+        })
+    }
+
+    /// Load a CA saved from `certificate_der` and `private_key_der`
+    pub fn load(cert_der: &[u8], key_der: &[u8]) -> Result<Self, VoyageError> {
+        let key = load_key(key_der)?;
+        let malformed = || VoyageError::config("Malformed CA certificate".into());
+        let (_, cert) = parse_x509_certificate(cert_der).map_err(|_| malformed())?;
+        if cert.public_key().raw != key.subject_public_key_info().as_slice() {
+            return Err(VoyageError::config(
+                "CA key does not match its certificate".into(),
+            ));
+        }
+        let issuer = Issuer::from_ca_cert_der(&CertificateDer::from(cert_der), key)
+            .map_err(|_| malformed())?;
+        Ok(Self {
+            issuer,
+            key_der: key_der.to_vec(),
+            cert_der: cert_der.to_vec(),
+        })
+    }
+
+    /// The CA certificate, for the user to install and trust
+    pub fn certificate_der(&self) -> &[u8] {
+        &self.cert_der
+    }
+
+    /// The CA key in PKCS#8, for saving alongside the certificate
+    pub fn private_key_der(&self) -> &[u8] {
+        &self.key_der
+    }
+
+    /// Sign a certificate for `host` carrying the public key of `key`
+    fn mint(&self, host: &str, key: &KeyPair) -> Result<Vec<u8>, VoyageError> {
+        let mut params = certificate_params(host, LEAF_VALIDITY_DAYS)?;
+        params.subject_alt_names = CertificateParams::new(vec![host.to_string()])
+            .map_err(signing_failed)?
+            .subject_alt_names;
+        params.is_ca = IsCa::ExplicitNoCa;
+        params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        params.use_authority_key_identifier_extension = true;
+        let cert = params.signed_by(key, &self.issuer).map_err(signing_failed)?;
+        Ok(cert.der().to_vec())
+    }
+}
+
+impl fmt::Debug for CertificateAuthority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CertificateAuthority")
+            .field("issuer", &self.issuer)
+            .finish_non_exhaustive()
+    }
+}
+
+fn load_key(pkcs8: &[u8]) -> Result<KeyPair, VoyageError> {
+    let der = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(pkcs8));
+    KeyPair::from_der_and_sign_algo(&der, &PKCS_ECDSA_P256_SHA256)
+        .map_err(|e| VoyageError::config(format!("Invalid P-256 key: {}", e)))
+}
+
+/// Parameters of a certificate for `common_name` with a random serial,
+/// valid from a day ago (to absorb clock skew) for `days`
+fn certificate_params(common_name: &str, days: i64) -> Result<CertificateParams, VoyageError> {
+    // Serials derived from the key would repeat across hosts, which share
+    // one key
+    let mut serial = [0u8; 16];
+    SystemRandom::new()
+        .fill(&mut serial)
+        .map_err(|_| VoyageError::config("Cannot sign a certificate".into()))?;
+    serial[0] &= 0x7f;
+
+    let mut params = CertificateParams::default();
+    params.distinguished_name = DistinguishedName::new();
+    params.distinguished_name.push(DnType::CommonName, common_name);
+    params.serial_number = Some(SerialNumber::from_slice(&serial));
+    let now = OffsetDateTime::now_utc();
+    params.not_before = now - TimeDuration::days(1);
+    params.not_after = now + TimeDuration::days(days);
+    Ok(params)
+}
+
+fn signing_failed(e: rcgen::Error) -> VoyageError {
+    VoyageError::config(format!("Cannot sign a certificate: {}", e))
+}
+
+/// Host certificates minted by the CA, all sharing one key
+struct CertStore {
+    ca: CertificateAuthority,
+    key: KeyPair,
+    signing_key: Arc<dyn SigningKey>,
+    cache: Mutex<CertCache>,
+}
+
+/// Minted certificates by host
+#[derive(Default)]
+struct CertCache {
+    /// Certificate and LRU clock value of its last use
+    entries: HashMap<String, (Arc<CertifiedKey>, u64)>,
+    clock: u64,
+}
+
+impl CertStore {
+    fn new(ca: CertificateAuthority) -> Result<Self, VoyageError> {
+        let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256)
+            .map_err(|_| VoyageError::config("Cannot generate a host key".into()))?;
+        let der = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der()));
+        let signing_key = rustls::crypto::ring::sign::any_ecdsa_type(&der)
+            .map_err(|e| VoyageError::config(e.to_string()))?;
+        Ok(Self {
+            ca,
+            key,
+            signing_key,
+            cache: Mutex::new(CertCache::default()),
+        })
+    }
+
+    /// Certificate for `host`, minted on first use
+    fn certified_key(&self, host: &str) -> Option<Arc<CertifiedKey>> {
+        let host = host.to_ascii_lowercase();
+        let mut cache = self.cache.lock().ok()?;
+        let CertCache { entries, clock } = &mut *cache;
+        *clock += 1;
+        if let Some((key, last_used)) = entries.get_mut(&host) {
+            *last_used = *clock;
+            return Some(Arc::clone(key));
+        }
+
+        let cert = match self.ca.mint(&host, &self.key) {
+            Ok(cert) => cert,
+            Err(e) => {
+                log::warn!("Cannot mint a certificate for {}: {}", host, e);
+                return None;
+            }
+        };
+        let key = Arc::new(CertifiedKey::new(
+            vec![CertificateDer::from(cert)],
+            Arc::clone(&self.signing_key),
+        ));
+        if entries.len() >= CERT_CACHE_SIZE {
+            let lru = entries
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(host, _)| host.clone());
+            if let Some(host) = lru {
+                entries.remove(&host);
+            }
+        }
+        entries.insert(host, (Arc::clone(&key), *clock));
+        Some(key)
+    }
+}
+
+impl fmt::Debug for CertStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CertStore")
+            .field("ca", &self.ca)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ResolvesServerCert for CertStore {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.certified_key(client_hello.server_name()?)
+    }
+}
+
+/// Sees and edits intercepted HTTP exchanges
+pub trait RewriteHook: Send + Sync {
+    /// Called with each request head before it goes upstream
+    fn on_request(&self, _host: &str, _request: &mut HttpHead) {}
+
+    /// Called with each response head before it goes to the app
+    fn on_response(&self, _host: &str, _request: &HttpHead, _response: &mut HttpHead) {}
+}
+
+/// Terminates TLS for whitelisted hosts and relays their HTTP exchanges
+/// through the rewrite hooks
+pub struct Interceptor {
+    /// Lowercased host names, or `*.suffix` for any subdomain
+    hosts: Vec<String>,
+    certs: Arc<CertStore>,
+    acceptor: TlsAcceptor,
+    connector: TlsConnector,
+    hooks: Vec<Arc<dyn RewriteHook>>,
+}
+
+impl Interceptor {
+    /// Create an interceptor for `hosts` signing with `ca`. Upstream
+    /// servers are verified against the bundled web PKI roots.
+    pub fn new(ca: CertificateAuthority, hosts: Vec<String>) -> Result<Self, VoyageError> {
+        let certs = Arc::new(CertStore::new(ca)?);
+        let mut server =
+            ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .map_err(|e| VoyageError::config(e.to_string()))?
+                .with_no_client_auth()
+                .with_cert_resolver(certs.clone());
+        server.alpn_protocols = vec![ALPN_HTTP1.to_vec()];
+
+        let roots = RootCertStore {
+            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+        };
+        Ok(Self {
+            hosts: hosts
+                .iter()
+                .map(|host| host.trim_end_matches('.').to_ascii_lowercase())
+                .collect(),
+            certs,
+            acceptor: TlsAcceptor::from(Arc::new(server)),
+            connector: connector(roots)?,
+            hooks: Vec::new(),
+        })
+    }
+
+    /// Verify upstream servers against `roots` instead of the web PKI
+    pub fn with_upstream_roots(mut self, roots: RootCertStore) -> Result<Self, VoyageError> {
+        self.connector = connector(roots)?;
+        Ok(self)
+    }
+
+    /// Add a hook called for every intercepted exchange
+    pub fn with_hook(mut self, hook: Arc<dyn RewriteHook>) -> Self {
+        self.hooks.push(hook);
+        self
+    }
+
+    /// The CA signing the host certificates
+    pub fn certificate_authority(&self) -> &CertificateAuthority {
+        &self.certs.ca
+    }
+
+    /// Whether connections to `host` are intercepted
+    pub fn should_intercept(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        self.hosts.iter().any(|pattern| host_matches(pattern, &host))
+    }
+
+    /// Relay an intercepted connection to `host` over the already opened
+    /// `upstream`, applying `rules` to the request before the hooks see
+    /// it, and return the plaintext bytes sent and received. The plaintext
+    /// exchanged with `upstream` is mirrored to `tap`, if given.
+    pub async fn intercept(
+        &self,
+        client: &mut TcpStream,
+        upstream: &mut TcpStream,
+        host: &str,
+        rules: &RewriteEngine,
+        tap: Option<TapSession>,
+    ) -> io::Result<(u64, u64)> {
+        let server_name = ServerName::try_from(host.to_string())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let mut client = self.acceptor.accept(client).await?;
+        let upstream = self.connector.connect(server_name, upstream).await?;
+        let mut upstream = TapStream::new(upstream, tap);
+
+        let (mut request, body) = read_head(&mut client).await?;
+        let url = format!("https://{}{}", host, request.path());
+        match rules.apply(&url, &mut request) {
+            Some((UrlMode::Redirect, location)) => {
+                let response = rewrite::redirect_response(&location);
+                client.write_all(&response).await?;
+                client.shutdown().await?;
+                return Ok((0, response.len() as u64));
+            }
+            Some((UrlMode::Transparent, to)) => match rewrite::split_url(&to) {
+                // The connection is already bound to `host`
+                Some((scheme, authority, path))
+                    if scheme.eq_ignore_ascii_case("https") && authority == host =>
+                {
+                    request.set_path(path)
+                }
+                _ => log::warn!("Cannot send an intercepted request for {} to {}", host, to),
+            },
+            None => {}
+        }
+        for hook in &self.hooks {
+            hook.on_request(host, &mut request);
+        }
+        request.set_header("Connection", "close");
+        let head = request.to_bytes();
+        upstream.write_all(&head).await?;
+        upstream.write_all(&body).await?;
+        let sent = (head.len() + body.len()) as u64;
+
+        let (mut response, body) = read_head(&mut upstream).await?;
+        for hook in &self.hooks {
+            hook.on_response(host, &request, &mut response);
+        }
+        let head = response.to_bytes();
+        client.write_all(&head).await?;
+        client.write_all(&body).await?;
+        let received = (head.len() + body.len()) as u64;
+        log::debug!(
+            "Intercepted {} {}{} -> {}",
+            request.method(),
+            host,
+            request.path(),
+            response.start_line
+        );
+
+        let (up, down) = match tokio::io::copy_bidirectional(&mut client, &mut upstream).await {
+            Ok(copied) => copied,
+            // Apps often hang up without a TLS close_notify once the
+            // response is in
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => (0, 0),
+            Err(e) => return Err(e),
+        };
+        Ok((sent + up, received + down))
+    }
+}
+
+impl fmt::Debug for Interceptor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Interceptor")
+            .field("hosts", &self.hosts)
+            .field("hooks", &self.hooks.len())
+            .finish_non_exhaustive()
+    }
+}
+
+fn connector(roots: RootCertStore) -> Result<TlsConnector, VoyageError> {
+    let mut client =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|e| VoyageError::config(e.to_string()))?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+    client.alpn_protocols = vec![ALPN_HTTP1.to_vec()];
+    Ok(TlsConnector::from(Arc::new(client)))
+}
+
+/// Read an HTTP head, returning it with any bytes read past it
+async fn read_head<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<(HttpHead, Vec<u8>)> {
+    let malformed = || io::Error::new(io::ErrorKind::InvalidData, "Malformed HTTP head");
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        if let Some(end) = buf.windows(4).position(|window| window == b"\r\n\r\n") {
+            let rest = buf.split_off(end + 4);
+            let head = std::str::from_utf8(&buf).map_err(|_| malformed())?;
+            return Ok((HttpHead::parse(head).ok_or_else(malformed)?, rest));
+        }
+        if buf.len() > MAX_HEAD_SIZE {
+            return Err(malformed());
+        }
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use x509_parser::extensions::{GeneralName, ParsedExtension};
+
+    struct TagHook;
+
+    impl RewriteHook for TagHook {
+        fn on_request(&self, _host: &str, request: &mut HttpHead) {
+            request.set_header("X-Intercepted", "1");
+        }
+
+        fn on_response(&self, host: &str, request: &HttpHead, response: &mut HttpHead) {
+            response.set_header(
+                "X-Seen",
+                &format!("{} {}{}", request.method(), host, request.path()),
+            );
+        }
+    }
+
+    #[test]
+    fn test_minted_certificate() {
+        let ca = CertificateAuthority::generate("Test CA").unwrap();
+        let key = KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap();
+        let first = ca.mint("www.test", &key).unwrap();
+        let second = ca.mint("10.0.0.1", &key).unwrap();
+        let (_, ca_cert) = parse_x509_certificate(ca.certificate_der()).unwrap();
+        let (_, first) = parse_x509_certificate(&first).unwrap();
+        let (_, second) = parse_x509_certificate(&second).unwrap();
+
+        assert_eq!(first.issuer(), ca_cert.subject());
+        assert!(!first.is_ca());
+        assert_ne!(first.serial, second.serial);
+        let validity = first.validity().time_to_expiration().unwrap();
+        assert!(validity.whole_days() < 398);
+
+        let san = first.subject_alternative_name().unwrap().unwrap();
+        assert_eq!(san.value.general_names, [GeneralName::DNSName("www.test")]);
+        let san = second.subject_alternative_name().unwrap().unwrap();
+        assert_eq!(san.value.general_names, [GeneralName::IPAddress(&[10, 0, 0, 1])]);
+    }
+
+    #[test]
+    fn test_should_intercept() {
+        let ca = CertificateAuthority::generate("Test CA").unwrap();
+        let interceptor =
+            Interceptor::new(ca, vec!["api.example.com".into(), "*.test".into()]).unwrap();
+        assert!(interceptor.should_intercept("API.example.com."));
+        assert!(interceptor.should_intercept("a.b.test"));
+        assert!(!interceptor.should_intercept("test"));
+        assert!(!interceptor.should_intercept("example.com"));
+    }
+
+    #[test]
+    fn test_ca_round_trip() {
+        let ca = CertificateAuthority::generate("Test CA").unwrap();
+        let loaded =
+            CertificateAuthority::load(ca.certificate_der(), ca.private_key_der()).unwrap();
+        let leaf = loaded
+            .mint("www.test", &KeyPair::generate_for(&PKCS_ECDSA_P256_SHA256).unwrap())
+            .unwrap();
+        let (_, ca_cert) = parse_x509_certificate(ca.certificate_der()).unwrap();
+        let (_, leaf) = parse_x509_certificate(&leaf).unwrap();
+        assert_eq!(leaf.issuer(), ca_cert.subject());
+        let ca_key_id = ca_cert
+            .extensions()
+            .iter()
+            .find_map(|ext| match ext.parsed_extension() {
+                ParsedExtension::SubjectKeyIdentifier(id) => Some(id.0),
+                _ => None,
+            });
+        let leaf_authority = leaf
+            .extensions()
+            .iter()
+            .find_map(|ext| match ext.parsed_extension() {
+                ParsedExtension::AuthorityKeyIdentifier(id) => {
+                    id.key_identifier.as_ref().map(|id| id.0)
+                }
+                _ => None,
+            });
+        assert!(ca_key_id.is_some());
+        assert_eq!(leaf_authority, ca_key_id);
+
+        let other = CertificateAuthority::generate("Other").unwrap();
+        assert!(CertificateAuthority::load(ca.certificate_der(), other.private_key_der()).is_err());
+        assert!(CertificateAuthority::load(b"junk", ca.private_key_der()).is_err());
+    }
+
+    #[test]
+    fn test_intercept_rewrites_exchange() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let ca = CertificateAuthority::generate("Test CA").unwrap();
+            let mut roots = RootCertStore::empty();
+            roots
+                .add(CertificateDer::from(ca.certificate_der().to_vec()))
+                .unwrap();
+
+            // The real server, with a certificate from the same CA
+            let origin_certs = Arc::new(
+                CertStore::new(
+                    CertificateAuthority::load(ca.certificate_der(), ca.private_key_der()).unwrap(),
+                )
+                .unwrap(),
+            );
+            let origin_config = ServerConfig::builder_with_provider(Arc::new(
+                rustls::crypto::ring::default_provider(),
+            ))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_cert_resolver(origin_certs);
+            let origin = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let origin_addr = origin.local_addr().unwrap();
+            let origin_task = tokio::spawn(async move {
+                let (stream, _) = origin.accept().await.unwrap();
+                let mut tls = TlsAcceptor::from(Arc::new(origin_config))
+                    .accept(stream)
+                    .await
+                    .unwrap();
+                let (request, _) = read_head(&mut tls).await.unwrap();
+                tls.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                    .await
+                    .unwrap();
+                tls.shutdown().await.unwrap();
+                request
+            });
+
+            let interceptor = Interceptor::new(ca, vec!["*.test".into()])
+                .unwrap()
+                .with_upstream_roots(roots.clone())
+                .unwrap()
+                .with_hook(Arc::new(TagHook));
+            let rules = RewriteEngine::from_config(
+                "URL-REWRITE, https://www.test/page, https://www.test/other, TRANSPARENT\n\
+                 HEADER-REWRITE, https://*.test/*, DEL, Cookie",
+            )
+            .unwrap();
+            let inbound = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let inbound_addr = inbound.local_addr().unwrap();
+            let relay_task = tokio::spawn(async move {
+                let (mut client, _) = inbound.accept().await.unwrap();
+                let mut upstream = TcpStream::connect(origin_addr).await.unwrap();
+                interceptor
+                    .intercept(&mut client, &mut upstream, "www.test", &rules, None)
+                    .await
+                    .unwrap()
+            });
+
+            // The app trusts the installed CA
+            let app = connector(roots).unwrap();
+            let stream = TcpStream::connect(inbound_addr).await.unwrap();
+            let name = ServerName::try_from("www.test").unwrap();
+            let mut tls = app.connect(name, stream).await.unwrap();
+            tls.write_all(b"GET /page HTTP/1.1\r\nHost: www.test\r\nCookie: id=1\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = String::new();
+            tls.read_to_string(&mut response).await.unwrap();
+            tls.shutdown().await.unwrap();
+
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert!(response.contains("X-Seen: GET www.test/other\r\n"));
+            assert!(response.ends_with("\r\n\r\nok"));
+            let request = origin_task.await.unwrap();
+            assert_eq!(request.path(), "/other");
+            assert_eq!(request.header("Cookie"), None);
+            assert_eq!(request.header("X-Intercepted"), Some("1"));
+            assert_eq!(request.header("Connection"), Some("close"));
+            let (sent, received) = relay_task.await.unwrap();
+            assert!(sent > 0 && received > 0);
+        });
+    }
+}
@@ -3,11 +3,27 @@
 //! This module provides NAT functionality to track connections between
 //! the virtual TUN device and real network sockets.
 
-use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::BuildHasher;
 use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
 use std::time::{Duration, Instant};
 
+use crate::config::DEFAULT_NAT_MAX_ENTRIES;
 use crate::error::VoyageError;
+use crate::history::CloseReason;
+use crate::packet::{TcpFlags, PROTO_ICMP, PROTO_ICMPV6};
+use crate::proxy::RoutingDecision;
+use crate::rule::RouteAction;
+use crate::rate::RateMeter;
+
+/// Lowest local port handed out to flows by default
+pub const DEFAULT_MIN_PORT: u16 = 10000;
+
+/// Highest local port handed out to flows by default
+pub const DEFAULT_MAX_PORT: u16 = 60000;
 
 /// NAT table entry state
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -24,9 +40,96 @@ pub enum NatState {
     Closed,
 }
 
+/// Which way a TCP segment crossed the tunnel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowDirection {
+    /// From the app towards the network
+    Outbound,
+    /// From the local stack back to the app
+    Inbound,
+}
+
+/// How UDP flows are mapped to local ports
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NatMode {
+    /// A new local port for every 5-tuple
+    #[default]
+    Symmetric,
+    /// One local port per source socket, shared by every destination
+    /// (endpoint-independent mapping, as STUN/WebRTC/games expect)
+    FullCone,
+}
+
+/// How local ports are picked for new mappings
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PortStrategy {
+    /// The next free port after the last one handed out
+    #[default]
+    Sequential,
+    /// A free port picked at random, so ports are not predictable
+    Random,
+    /// A port derived from a keyed hash of the source socket, so an app
+    /// socket keeps its port across re-created mappings
+    SourceHash,
+    /// The app's own source port when it is free and in range
+    PreservePort,
+}
+
+/// Idle timeouts per flow state, in seconds (modelled on conntrack)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NatTimeouts {
+    /// TCP handshake not yet completed
+    pub tcp_syn_sent_secs: u64,
+    /// Established TCP connection
+    pub tcp_established_secs: u64,
+    /// TCP connection shutting down (FIN seen)
+    pub tcp_closing_secs: u64,
+    /// UDP flow with traffic in one direction only
+    pub udp_unreplied_secs: u64,
+    /// UDP flow that has seen replies
+    pub udp_stream_secs: u64,
+    /// ICMP echo session
+    pub icmp_secs: u64,
+}
+
+impl NatTimeouts {
+    /// Idle timeout for an entry in its current state
+    pub fn for_entry(&self, key: &NatKey, entry: &NatEntry) -> Duration {
+        let secs = if key.is_tcp() {
+            match entry.state {
+                NatState::SynSent => self.tcp_syn_sent_secs,
+                NatState::Established => self.tcp_established_secs,
+                NatState::FinWait | NatState::Closing | NatState::Closed => self.tcp_closing_secs,
+            }
+        } else if key.is_icmp() {
+            self.icmp_secs
+        } else if entry.bytes_received > 0 || entry.state == NatState::Established {
+            self.udp_stream_secs
+        } else {
+            self.udp_unreplied_secs
+        };
+        Duration::from_secs(secs)
+    }
+}
+
+impl Default for NatTimeouts {
+    fn default() -> Self {
+        Self {
+            tcp_syn_sent_secs: 30,
+            tcp_established_secs: 7200,
+            tcp_closing_secs: 30,
+            udp_unreplied_secs: 30,
+            udp_stream_secs: 180,
+            icmp_secs: 30,
+        }
+    }
+}
+
 /// A NAT table entry tracking a single connection
 #[derive(Debug, Clone)]
 pub struct NatEntry {
+    /// Identifier unique for the manager's lifetime (0 until inserted)
+    pub id: u64,
     /// Original source address (from the app)
     pub src_addr: SocketAddr,
     /// Original destination address
@@ -35,25 +138,54 @@ pub struct NatEntry {
     pub local_port: u16,
     /// Connection state
     pub state: NatState,
+    /// Creation timestamp
+    pub created_at: Instant,
     /// Last activity timestamp
     pub last_seen: Instant,
     /// Bytes sent through this connection
     pub bytes_sent: u64,
     /// Bytes received through this connection
     pub bytes_received: u64,
+    /// Upload speed
+    pub upload: RateMeter,
+    /// Download speed
+    pub download: RateMeter,
+    /// Routing decision, set when the flow is first classified
+    pub route: Option<RoutingDecision>,
+    /// Why the flow is ending, once known
+    pub close_reason: Option<CloseReason>,
+    /// Key/value metadata attached by the host app
+    pub annotations: BTreeMap<String, String>,
+    /// TCP segments whose flags did not fit the flow's state
+    pub invalid_transitions: u32,
+    /// Whether the app's SYN has been answered with a SYN-ACK
+    syn_acked: bool,
+    /// Side that sent the first FIN, once the flow started closing
+    first_fin: Option<FlowDirection>,
 }
 
 impl NatEntry {
     /// Create a new NAT entry
     pub fn new(src_addr: SocketAddr, dst_addr: SocketAddr, local_port: u16) -> Self {
+        let now = Instant::now();
         Self {
+            id: 0,
             src_addr,
             dst_addr,
             local_port,
             state: NatState::SynSent,
-            last_seen: Instant::now(),
+            created_at: now,
+            last_seen: now,
             bytes_sent: 0,
             bytes_received: 0,
+            upload: RateMeter::new(now),
+            download: RateMeter::new(now),
+            route: None,
+            close_reason: None,
+            annotations: BTreeMap::new(),
+            invalid_transitions: 0,
+            syn_acked: false,
+            first_fin: None,
         }
     }
 
@@ -84,6 +216,94 @@ impl NatEntry {
         self.state = NatState::Closed;
         self.touch();
     }
+
+    /// Advance the TCP state machine with the flags of a segment.
+    ///
+    /// Covers the handshake, resets from either side and the FIN exchange,
+    /// including simultaneous close. Returns `false` for flags that do not
+    /// fit the current state (a SYN on an established flow, a FIN after
+    /// close, ...); the state is left alone and the segment is counted in
+    /// `invalid_transitions`.
+    pub fn on_flags(&mut self, flags: TcpFlags, direction: FlowDirection) -> bool {
+        let valid = self.transition(flags, direction);
+        if valid {
+            self.touch();
+        } else {
+            self.invalid_transitions += 1;
+        }
+        valid
+    }
+
+    fn transition(&mut self, flags: TcpFlags, direction: FlowDirection) -> bool {
+        // A reset ends the flow whichever side sends it
+        if flags.rst {
+            self.state = NatState::Closed;
+            return true;
+        }
+
+        match self.state {
+            NatState::SynSent => match (flags.syn, flags.ack, direction) {
+                // Retransmitted SYN, or a simultaneous open
+                (true, false, _) => true,
+                (true, true, FlowDirection::Inbound) => {
+                    self.syn_acked = true;
+                    true
+                }
+                (true, true, FlowDirection::Outbound) => false,
+                // The app gave up before the handshake completed
+                (false, _, _) if flags.fin => {
+                    self.state = NatState::FinWait;
+                    self.first_fin = Some(direction);
+                    true
+                }
+                (false, true, FlowDirection::Outbound) if self.syn_acked => {
+                    self.state = NatState::Established;
+                    true
+                }
+                _ => false,
+            },
+            NatState::Established => {
+                if flags.syn {
+                    // Only a SYN-ACK retransmitted before our ACK arrived
+                    return flags.ack && direction == FlowDirection::Inbound;
+                }
+                if flags.fin {
+                    self.state = NatState::FinWait;
+                    self.first_fin = Some(direction);
+                }
+                true
+            }
+            NatState::FinWait => {
+                if flags.syn {
+                    return false;
+                }
+                // The other side closing too, possibly simultaneously
+                if flags.fin && self.first_fin != Some(direction) {
+                    self.state = NatState::Closing;
+                }
+                true
+            }
+            NatState::Closing => {
+                if flags.syn {
+                    return false;
+                }
+                // The side that closed first acknowledges the last FIN
+                if flags.ack && self.first_fin == Some(direction) {
+                    self.state = NatState::Closed;
+                }
+                true
+            }
+            // Late ACKs of the final FIN are expected, nothing else is
+            NatState::Closed => flags.ack && !flags.syn && !flags.fin,
+        }
+    }
+
+    /// Record why the flow is ending; the first reason recorded wins
+    pub fn set_close_reason(&mut self, reason: CloseReason) {
+        if self.close_reason.is_none() {
+            self.close_reason = Some(reason);
+        }
+    }
 }
 
 /// Key for looking up NAT entries
@@ -97,7 +317,7 @@ pub struct NatKey {
     pub dst_ip: IpAddr,
     /// Destination port
     pub dst_port: u16,
-    /// Protocol (6 = TCP, 17 = UDP)
+    /// Protocol (6 = TCP, 17 = UDP, 1/58 = ICMP/ICMPv6)
     pub protocol: u8,
 }
 
@@ -124,6 +344,20 @@ impl NatKey {
         }
     }
 
+    /// Create a new NAT key for an ICMP echo session.
+    ///
+    /// Echo messages have no ports, so the echo identifier stands in for
+    /// both; the protocol follows the address family.
+    pub fn icmp(src_ip: IpAddr, dst_ip: IpAddr, identifier: u16) -> Self {
+        Self {
+            src_ip,
+            src_port: identifier,
+            dst_ip,
+            dst_port: identifier,
+            protocol: if src_ip.is_ipv4() { PROTO_ICMP } else { PROTO_ICMPV6 },
+        }
+    }
+
     /// Get source as SocketAddr
     pub fn src_addr(&self) -> SocketAddr {
         SocketAddr::new(self.src_ip, self.src_port)
@@ -134,6 +368,17 @@ impl NatKey {
         SocketAddr::new(self.dst_ip, self.dst_port)
     }
 
+    /// Protocol name for flow logs ("tcp", "udp" or "icmp")
+    pub fn protocol_name(&self) -> &'static str {
+        if self.is_tcp() {
+            "tcp"
+        } else if self.is_icmp() {
+            "icmp"
+        } else {
+            "udp"
+        }
+    }
+
     /// Check if this is a TCP connection
     pub fn is_tcp(&self) -> bool {
         self.protocol == 6
@@ -143,6 +388,97 @@ impl NatKey {
     pub fn is_udp(&self) -> bool {
         self.protocol == 17
     }
+
+    /// Check if this is an ICMP echo session
+    pub fn is_icmp(&self) -> bool {
+        self.protocol == PROTO_ICMP || self.protocol == PROTO_ICMPV6
+    }
+}
+
+/// State the NAT tables of a sharded connection table share, so entry
+/// identifiers and local ports stay unique and the per-source cap holds
+/// across tables
+#[derive(Debug)]
+pub struct NatShared {
+    /// Identifier of the next entry
+    next_id: AtomicU64,
+    /// Where sequential allocation resumes, so ports follow creation
+    /// order across tables
+    next_port: AtomicU16,
+    /// Bitmap of the local ports held by entries
+    ports: Box<[AtomicU64]>,
+    /// Live entries per source IP
+    source_counts: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl NatShared {
+    /// Take the next entry identifier
+    fn take_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Identifier the next entry will get
+    fn peek_id(&self) -> u64 {
+        self.next_id.load(Ordering::Relaxed)
+    }
+
+    /// Where sequential allocation resumes within `min..=max`
+    fn next_port(&self, min: u16, max: u16) -> u16 {
+        self.next_port.load(Ordering::Relaxed).clamp(min, max)
+    }
+
+    /// Resume sequential allocation at `port`
+    fn set_next_port(&self, port: u16) {
+        self.next_port.store(port, Ordering::Relaxed);
+    }
+
+    /// Take `port`, unless an entry already holds it
+    fn claim_port(&self, port: u16) -> bool {
+        let bit = 1 << (port % 64);
+        self.ports[usize::from(port / 64)].fetch_or(bit, Ordering::Relaxed) & bit == 0
+    }
+
+    /// Give `port` back
+    fn release_port(&self, port: u16) {
+        let bit = 1 << (port % 64);
+        self.ports[usize::from(port / 64)].fetch_and(!bit, Ordering::Relaxed);
+    }
+
+    /// Count a new entry of `src`, unless it already holds `limit`
+    fn claim_source(&self, src: IpAddr, limit: Option<usize>) -> bool {
+        let mut counts = self.source_counts.lock().unwrap_or_else(PoisonError::into_inner);
+        let count = counts.entry(src).or_insert(0);
+        if limit.is_some_and(|limit| *count >= limit) {
+            if *count == 0 {
+                counts.remove(&src);
+            }
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    /// Stop counting an entry of `src`
+    fn release_source(&self, src: IpAddr) {
+        let mut counts = self.source_counts.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(count) = counts.get_mut(&src) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&src);
+            }
+        }
+    }
+}
+
+impl Default for NatShared {
+    fn default() -> Self {
+        Self {
+            next_id: AtomicU64::new(1),
+            next_port: AtomicU16::new(0),
+            ports: (0..1024).map(|_| AtomicU64::new(0)).collect(),
+            source_counts: Mutex::new(HashMap::new()),
+        }
+    }
 }
 
 /// NAT Manager for tracking connections
@@ -151,24 +487,47 @@ pub struct NatManager {
     entries: HashMap<NatKey, NatEntry>,
     /// Reverse lookup: local port -> NAT key
     port_to_key: HashMap<u16, NatKey>,
-    /// Next available local port
-    next_port: u16,
+    /// Entry identifiers and per-source counts, possibly shared with
+    /// other tables
+    shared: Arc<NatShared>,
     /// Minimum local port
     min_port: u16,
     /// Maximum local port
     max_port: u16,
     /// Maximum number of entries
     max_entries: usize,
-    /// TCP timeout duration
-    tcp_timeout: Duration,
-    /// UDP timeout duration
-    udp_timeout: Duration,
+    /// Idle timeouts per flow state
+    timeouts: NatTimeouts,
+    /// UDP port mapping behaviour
+    udp_mode: NatMode,
+    /// How local ports are picked
+    port_strategy: PortStrategy,
+    /// Keys for the random and source-hash strategies
+    port_hasher: RandomState,
+    /// Total ports found taken while allocating
+    port_collisions: u64,
+    /// Full-cone mappings: source socket -> (local port, entries using it)
+    cone_ports: HashMap<SocketAddr, (u16, usize)>,
+    /// Maximum entries per source IP (unlimited when `None`)
+    max_per_source: Option<usize>,
+    /// Entries evicted to make room, not yet collected by the owner
+    evicted: Vec<(NatKey, NatEntry)>,
+    /// Total LRU evictions
+    evictions: u64,
+    /// Total entries refused because their source hit its cap
+    source_limit_hits: u64,
+    /// Total TCP segments whose flags did not fit their flow's state
+    invalid_transitions: u64,
+    /// Live entries routed through the proxy
+    proxied_count: usize,
+    /// Live routed entries per destination host (rejected ones excluded)
+    destination_counts: HashMap<String, usize>,
 }
 
 impl NatManager {
     /// Create a new NAT manager with default settings
     pub fn new() -> Self {
-        Self::with_config(10000, 60000, 65535)
+        Self::with_config(DEFAULT_MIN_PORT, DEFAULT_MAX_PORT, DEFAULT_NAT_MAX_ENTRIES)
     }
 
     /// Create a NAT manager with custom port range
@@ -176,34 +535,142 @@ impl NatManager {
         Self {
             entries: HashMap::new(),
             port_to_key: HashMap::new(),
-            next_port: min_port,
+            shared: Arc::default(),
             min_port,
             max_port,
             max_entries,
-            tcp_timeout: Duration::from_secs(300), // 5 minutes
-            udp_timeout: Duration::from_secs(60),  // 1 minute
+            timeouts: NatTimeouts::default(),
+            udp_mode: NatMode::default(),
+            port_strategy: PortStrategy::default(),
+            port_hasher: RandomState::new(),
+            port_collisions: 0,
+            cone_ports: HashMap::new(),
+            max_per_source: None,
+            evicted: Vec::new(),
+            evictions: 0,
+            source_limit_hits: 0,
+            invalid_transitions: 0,
+            proxied_count: 0,
+            destination_counts: HashMap::new(),
         }
     }
 
-    /// Allocate a new local port
-    fn allocate_port(&mut self) -> Result<u16, VoyageError> {
-        let start_port = self.next_port;
-        loop {
-            let port = self.next_port;
-            self.next_port = if self.next_port >= self.max_port {
-                self.min_port
-            } else {
-                self.next_port + 1
-            };
+    /// Share entry identifiers, local ports and per-source counts with
+    /// other tables
+    pub fn with_shared(mut self, shared: Arc<NatShared>) -> Self {
+        self.shared = shared;
+        self
+    }
 
-            if !self.port_to_key.contains_key(&port) {
-                return Ok(port);
-            }
+    /// Cap the number of entries a single source IP may hold
+    pub fn with_max_per_source(mut self, limit: Option<usize>) -> Self {
+        self.max_per_source = limit;
+        self
+    }
+
+    /// Set the idle timeouts
+    pub fn with_timeouts(mut self, timeouts: NatTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Change the idle timeouts (applies to existing entries too)
+    pub fn set_timeouts(&mut self, timeouts: NatTimeouts) {
+        self.timeouts = timeouts;
+    }
+
+    /// Get the idle timeouts
+    pub fn timeouts(&self) -> NatTimeouts {
+        self.timeouts
+    }
+
+    /// Set the UDP mapping mode
+    pub fn with_udp_mode(mut self, mode: NatMode) -> Self {
+        self.udp_mode = mode;
+        self
+    }
 
-            if self.next_port == start_port {
+    /// Change the UDP mapping mode (applies to new mappings)
+    pub fn set_udp_mode(&mut self, mode: NatMode) {
+        self.udp_mode = mode;
+    }
+
+    /// Get the UDP mapping mode
+    pub fn udp_mode(&self) -> NatMode {
+        self.udp_mode
+    }
+
+    /// Set how local ports are picked
+    pub fn with_port_strategy(mut self, strategy: PortStrategy) -> Self {
+        self.port_strategy = strategy;
+        self
+    }
+
+    /// Change how local ports are picked (applies to new mappings)
+    pub fn set_port_strategy(&mut self, strategy: PortStrategy) {
+        self.port_strategy = strategy;
+    }
+
+    /// Get the port allocation strategy
+    pub fn port_strategy(&self) -> PortStrategy {
+        self.port_strategy
+    }
+
+    /// Change the table capacity, evicting the least recently active
+    /// entries if the table holds more than `max_entries`
+    pub fn set_max_entries(&mut self, max_entries: usize) {
+        self.max_entries = max_entries;
+        while self.entries.len() > max_entries && self.evict_lru().is_ok() {}
+    }
+
+    /// Get the table capacity
+    pub fn max_entries(&self) -> usize {
+        self.max_entries
+    }
+
+    /// Allocate a new local port for `key`, starting from the strategy's
+    /// candidate and probing upwards while ports are taken
+    fn allocate_port(&mut self, key: &NatKey) -> Result<u16, VoyageError> {
+        let candidate = match self.port_strategy {
+            PortStrategy::Sequential => None,
+            PortStrategy::Random => {
+                Some(self.port_in_range(self.port_hasher.hash_one(self.shared.peek_id())))
+            }
+            PortStrategy::SourceHash => {
+                Some(self.port_in_range(self.port_hasher.hash_one(key.src_addr())))
+            }
+            PortStrategy::PreservePort => {
+                Some(key.src_port).filter(|port| (self.min_port..=self.max_port).contains(port))
+            }
+        };
+        let start_port =
+            candidate.unwrap_or_else(|| self.shared.next_port(self.min_port, self.max_port));
+
+        let mut port = start_port;
+        while !self.shared.claim_port(port) {
+            self.port_collisions += 1;
+            port = self.port_after(port);
+            if port == start_port {
                 return Err(VoyageError::NatTableFull);
             }
         }
+        if candidate.is_none() {
+            self.shared.set_next_port(self.port_after(port));
+        }
+        Ok(port)
+    }
+
+    fn port_after(&self, port: u16) -> u16 {
+        if port >= self.max_port {
+            self.min_port
+        } else {
+            port + 1
+        }
+    }
+
+    fn port_in_range(&self, hash: u64) -> u16 {
+        let range = u64::from(self.max_port - self.min_port) + 1;
+        self.min_port + (hash % range) as u16
     }
 
     /// Create or get a NAT entry for a connection
@@ -212,21 +679,53 @@ impl NatManager {
             return Ok(self.entries.get(&key).unwrap());
         }
 
+        if !self.shared.claim_source(key.src_ip, self.max_per_source) {
+            self.source_limit_hits += 1;
+            return Err(VoyageError::nat(format!(
+                "Source {} reached its limit of {} flows",
+                key.src_ip,
+                self.max_per_source.unwrap_or_default()
+            )));
+        }
+        if let Err(e) = self.create(key) {
+            self.shared.release_source(key.src_ip);
+            return Err(e);
+        }
+
+        Ok(self.entries.get(&key).unwrap())
+    }
+
+    /// Insert a new entry for `key`, whose source was already counted
+    fn create(&mut self, key: NatKey) -> Result<(), VoyageError> {
         if self.entries.len() >= self.max_entries {
-            // Try to clean up expired entries first
+            // Try to clean up expired entries first, then make room
             self.cleanup_expired();
             if self.entries.len() >= self.max_entries {
-                return Err(VoyageError::NatTableFull);
+                self.evict_lru()?;
             }
         }
 
-        let local_port = self.allocate_port()?;
-        let entry = NatEntry::new(key.src_addr(), key.dst_addr(), local_port);
+        let local_port = if key.is_udp() && self.udp_mode == NatMode::FullCone {
+            match self.cone_ports.get_mut(&key.src_addr()) {
+                Some((port, refs)) => {
+                    *refs += 1;
+                    *port
+                }
+                None => {
+                    let port = self.allocate_port(&key)?;
+                    self.cone_ports.insert(key.src_addr(), (port, 1));
+                    port
+                }
+            }
+        } else {
+            self.allocate_port(&key)?
+        };
+        let mut entry = NatEntry::new(key.src_addr(), key.dst_addr(), local_port);
+        entry.id = self.shared.take_id();
 
-        self.port_to_key.insert(local_port, key);
+        self.port_to_key.entry(local_port).or_insert(key);
         self.entries.insert(key, entry);
-
-        Ok(self.entries.get(&key).unwrap())
+        Ok(())
     }
 
     /// Get a NAT entry by key
@@ -244,11 +743,87 @@ impl NatManager {
         self.port_to_key.get(&port).and_then(|key| self.entries.get(key))
     }
 
+    /// Get NAT key by entry identifier
+    pub fn get_key_by_id(&self, id: u64) -> Option<NatKey> {
+        self.entries
+            .iter()
+            .find(|(_, entry)| entry.id == id)
+            .map(|(key, _)| *key)
+    }
+
     /// Get NAT key by local port
     pub fn get_key_by_port(&self, port: u16) -> Option<&NatKey> {
         self.port_to_key.get(&port)
     }
 
+    /// Local port a UDP reply from `remote` to the app's `src` arrives on:
+    /// the port of the flow to `remote`, or else the source's full-cone port
+    pub fn reply_port(&self, src: SocketAddr, remote: SocketAddr) -> Option<u16> {
+        self.entries
+            .get(&NatKey::udp(src, remote))
+            .map(|entry| entry.local_port)
+            .or_else(|| self.cone_ports.get(&src).map(|(port, _)| *port))
+    }
+
+    /// Key for a packet arriving on `port` from `remote`.
+    ///
+    /// Full-cone ports accept any remote, so the key is built from the mapped
+    /// source; other ports only match the destination they were opened for.
+    pub fn inbound_key(&self, port: u16, remote: SocketAddr) -> Option<NatKey> {
+        let key = self.port_to_key.get(&port)?;
+        match self.cone_ports.get(&key.src_addr()) {
+            Some((cone_port, _)) if *cone_port == port => Some(NatKey::udp(key.src_addr(), remote)),
+            _ => (key.dst_addr() == remote).then_some(*key),
+        }
+    }
+
+    /// Record the routing decision for an entry
+    pub fn set_route(&mut self, key: &NatKey, decision: RoutingDecision) -> bool {
+        let Some(entry) = self.entries.get_mut(key) else {
+            return false;
+        };
+        let previous = entry.route.replace(decision.clone());
+        if let Some(previous) = previous {
+            self.count_route(&previous, false);
+        }
+        self.count_route(&decision, true);
+        true
+    }
+
+    /// Live entries routed through the proxy
+    pub fn proxied_count(&self) -> usize {
+        self.proxied_count
+    }
+
+    /// Live entries routed to `host` and not rejected
+    pub fn destination_count(&self, host: &str) -> usize {
+        self.destination_counts.get(host).copied().unwrap_or(0)
+    }
+
+    /// Add or remove a routed entry from the concurrency counts
+    fn count_route(&mut self, route: &RoutingDecision, add: bool) {
+        if route.action == RouteAction::Reject {
+            return;
+        }
+        let proxied = (route.action == RouteAction::Proxy) as usize;
+        if add {
+            self.proxied_count += proxied;
+        } else {
+            self.proxied_count -= proxied;
+        }
+        let Some(host) = route.destination_host() else {
+            return;
+        };
+        if add {
+            *self.destination_counts.entry(host).or_insert(0) += 1;
+        } else if let Some(count) = self.destination_counts.get_mut(&host) {
+            *count -= 1;
+            if *count == 0 {
+                self.destination_counts.remove(&host);
+            }
+        }
+    }
+
     /// Update entry state to established
     pub fn establish(&mut self, key: &NatKey) -> bool {
         if let Some(entry) = self.entries.get_mut(key) {
@@ -259,11 +834,35 @@ impl NatManager {
         }
     }
 
+    /// Feed the flags of a TCP segment to an entry's state machine,
+    /// returning the resulting state (`None` if the entry does not exist)
+    pub fn on_flags(
+        &mut self,
+        key: &NatKey,
+        flags: TcpFlags,
+        direction: FlowDirection,
+    ) -> Option<NatState> {
+        let entry = self.entries.get_mut(key)?;
+        if !entry.on_flags(flags, direction) {
+            log::debug!(
+                "Invalid TCP transition {} -> {}: {:?} {:?} in {:?}",
+                key.src_addr(),
+                key.dst_addr(),
+                direction,
+                flags,
+                entry.state
+            );
+            self.invalid_transitions += 1;
+        }
+        Some(entry.state)
+    }
+
     /// Update bytes sent for an entry
     pub fn add_bytes_sent(&mut self, key: &NatKey, bytes: u64) {
         if let Some(entry) = self.entries.get_mut(key) {
             entry.bytes_sent += bytes;
             entry.touch();
+            entry.upload.record(bytes, entry.last_seen);
         }
     }
 
@@ -272,35 +871,107 @@ impl NatManager {
         if let Some(entry) = self.entries.get_mut(key) {
             entry.bytes_received += bytes;
             entry.touch();
+            entry.download.record(bytes, entry.last_seen);
         }
     }
 
     /// Remove a NAT entry
     pub fn remove(&mut self, key: &NatKey) -> Option<NatEntry> {
-        if let Some(entry) = self.entries.remove(key) {
-            self.port_to_key.remove(&entry.local_port);
-            Some(entry)
-        } else {
-            None
+        let entry = self.entries.remove(key)?;
+        self.release_port(key, entry.local_port);
+        if let Some(route) = &entry.route {
+            self.count_route(route, false);
         }
+        self.shared.release_source(key.src_ip);
+        Some(entry)
     }
 
-    /// Clean up expired entries
-    pub fn cleanup_expired(&mut self) {
-        let tcp_timeout = self.tcp_timeout;
-        let udp_timeout = self.udp_timeout;
-
-        let expired_keys: Vec<NatKey> = self
+    /// Evict the least recently active entry; the owner collects it with
+    /// `take_evicted`
+    pub fn evict_lru(&mut self) -> Result<(), VoyageError> {
+        let key = self
             .entries
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_seen)
+            .map(|(key, _)| *key)
+            .ok_or(VoyageError::NatTableFull)?;
+
+        log::debug!("NAT table full, evicting {} -> {}", key.src_addr(), key.dst_addr());
+        if let Some(entry) = self.remove(&key) {
+            self.evicted.push((key, entry));
+        }
+        self.evictions += 1;
+        Ok(())
+    }
+
+    /// Take the entries evicted since the last call, so the owner can drop
+    /// any state it keeps for them
+    pub fn take_evicted(&mut self) -> Vec<(NatKey, NatEntry)> {
+        std::mem::take(&mut self.evicted)
+    }
+
+    /// Total entries evicted to make room
+    pub fn evictions(&self) -> u64 {
+        self.evictions
+    }
+
+    /// Total entries refused by the per-source cap
+    pub fn source_limit_hits(&self) -> u64 {
+        self.source_limit_hits
+    }
+
+    /// Total ports found taken while allocating
+    pub fn port_collisions(&self) -> u64 {
+        self.port_collisions
+    }
+
+    /// Total TCP segments whose flags did not fit their flow's state
+    pub fn invalid_transitions(&self) -> u64 {
+        self.invalid_transitions
+    }
+
+    /// Free a local port, unless other full-cone entries still share it
+    fn release_port(&mut self, key: &NatKey, port: u16) {
+        let src = key.src_addr();
+        if let Some((cone_port, refs)) = self.cone_ports.get_mut(&src) {
+            if *cone_port == port {
+                *refs -= 1;
+                if *refs > 0 {
+                    // Keep the reverse lookup pointing at a live entry
+                    if self.port_to_key.get(&port) == Some(key) {
+                        if let Some(other) = self
+                            .entries
+                            .iter()
+                            .find(|(k, e)| e.local_port == port && k.src_addr() == src)
+                            .map(|(k, _)| *k)
+                        {
+                            self.port_to_key.insert(port, other);
+                        }
+                    }
+                    return;
+                }
+                self.cone_ports.remove(&src);
+            }
+        }
+        self.port_to_key.remove(&port);
+        self.shared.release_port(port);
+    }
+
+    /// Keys of entries that are closed or idle past their timeout
+    pub fn expired_keys(&self) -> Vec<NatKey> {
+        let timeouts = self.timeouts;
+        self.entries
             .iter()
             .filter(|(key, entry)| {
-                let timeout = if key.is_tcp() { tcp_timeout } else { udp_timeout };
-                entry.is_expired(timeout) || entry.state == NatState::Closed
+                entry.is_expired(timeouts.for_entry(key, entry)) || entry.state == NatState::Closed
             })
             .map(|(key, _)| *key)
-            .collect();
+            .collect()
+    }
 
-        for key in expired_keys {
+    /// Clean up expired entries
+    pub fn cleanup_expired(&mut self) {
+        for key in self.expired_keys() {
             self.remove(&key);
         }
     }
@@ -310,6 +981,12 @@ impl NatManager {
         self.entries.len()
     }
 
+    /// Estimated heap bytes held by the table and its port index
+    pub fn estimated_bytes(&self) -> usize {
+        self.entries.capacity() * std::mem::size_of::<(NatKey, NatEntry)>()
+            + self.port_to_key.capacity() * std::mem::size_of::<(u16, NatKey)>()
+    }
+
     /// Check if the NAT table is empty
     pub fn is_empty(&self) -> bool {
         self.entries.is_empty()
@@ -325,6 +1002,21 @@ impl NatManager {
         self.entries.values().map(|e| e.bytes_received).sum()
     }
 
+    /// Iterate entries matching every given filter: IP protocol number,
+    /// state, and activity at or after `since`
+    pub fn iter_filtered(
+        &self,
+        protocol: Option<u8>,
+        state: Option<NatState>,
+        since: Option<Instant>,
+    ) -> impl Iterator<Item = (&NatKey, &NatEntry)> {
+        self.entries.iter().filter(move |(key, entry)| {
+            protocol.is_none_or(|p| key.protocol == p)
+                && state.is_none_or(|s| entry.state == s)
+                && since.is_none_or(|t| entry.last_seen >= t)
+        })
+    }
+
     /// Get all active connections info
     pub fn get_all_connections(&self) -> Vec<(NatKey, NatEntry)> {
         self.entries
@@ -383,6 +1075,57 @@ mod tests {
         assert_eq!(entry.state, NatState::Closed);
     }
 
+    #[test]
+    fn test_tcp_state_machine() {
+        use FlowDirection::{Inbound, Outbound};
+        let src = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 12345));
+        let dst = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(8, 8, 8, 8), 443));
+        let (syn, syn_ack, ack, fin, rst) = (0x02, 0x12, 0x10, 0x11, 0x04);
+        let feed = |entry: &mut NatEntry, flags, direction| {
+            entry.on_flags(TcpFlags::from_byte(flags), direction)
+        };
+
+        // Handshake: an ACK before the SYN-ACK does not establish the flow
+        let mut entry = NatEntry::new(src, dst, 50000);
+        assert!(feed(&mut entry, syn, Outbound));
+        assert!(!feed(&mut entry, ack, Outbound));
+        assert!(feed(&mut entry, syn_ack, Inbound));
+        assert!(feed(&mut entry, ack, Outbound));
+        assert_eq!(entry.state, NatState::Established);
+
+        // A new SYN on an established flow is invalid and changes nothing
+        assert!(!feed(&mut entry, syn, Outbound));
+        assert_eq!(entry.state, NatState::Established);
+        assert_eq!(entry.invalid_transitions, 2);
+
+        // Simultaneous close: both FINs, then the final ACK
+        assert!(feed(&mut entry, fin, Outbound));
+        assert_eq!(entry.state, NatState::FinWait);
+        assert!(feed(&mut entry, fin, Inbound));
+        assert_eq!(entry.state, NatState::Closing);
+        assert!(feed(&mut entry, ack, Outbound));
+        assert_eq!(entry.state, NatState::Closed);
+        assert!(feed(&mut entry, ack, Inbound));
+        assert!(!feed(&mut entry, fin, Inbound));
+
+        // A reset from the stack closes the flow in any state
+        let mut entry = NatEntry::new(src, dst, 50001);
+        feed(&mut entry, syn, Outbound);
+        assert!(feed(&mut entry, rst, Inbound));
+        assert_eq!(entry.state, NatState::Closed);
+
+        // The manager counts invalid transitions across flows
+        let mut manager = NatManager::new();
+        let key = make_tcp_key(12345, 443);
+        manager.get_or_create(key).unwrap();
+        let state = manager.on_flags(&key, TcpFlags::from_byte(syn_ack), Outbound);
+        assert_eq!(state, Some(NatState::SynSent));
+        assert_eq!(manager.invalid_transitions(), 1);
+        let missing = make_tcp_key(1, 1);
+        let state = manager.on_flags(&missing, TcpFlags::from_byte(syn), Outbound);
+        assert!(state.is_none());
+    }
+
     #[test]
     fn test_nat_key_creation() {
         let src = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 12345));
@@ -399,6 +1142,27 @@ mod tests {
         assert_eq!(udp_key.protocol, 17);
     }
 
+    #[test]
+    fn test_nat_key_icmp() {
+        let v4 = NatKey::icmp("10.0.0.1".parse().unwrap(), "8.8.8.8".parse().unwrap(), 0x1234);
+        assert!(v4.is_icmp());
+        assert!(!v4.is_tcp() && !v4.is_udp());
+        assert_eq!(v4.protocol, PROTO_ICMP);
+        assert_eq!(v4.src_port, 0x1234);
+        assert_eq!(v4.dst_port, 0x1234);
+
+        let v6 = NatKey::icmp("fd00::1".parse().unwrap(), "2001:db8::1".parse().unwrap(), 7);
+        assert!(v6.is_icmp());
+        assert_eq!(v6.protocol, PROTO_ICMPV6);
+
+        // Each echo identifier is its own session
+        let mut manager = NatManager::new();
+        let other = NatKey::icmp(v4.src_ip, v4.dst_ip, 0x1235);
+        let port = manager.get_or_create(v4).unwrap().local_port;
+        assert_ne!(manager.get_or_create(other).unwrap().local_port, port);
+        assert_eq!(manager.get_key_by_port(port), Some(&v4));
+    }
+
     #[test]
     fn test_nat_manager_create_entry() {
         let mut manager = NatManager::new();
@@ -478,6 +1242,231 @@ mod tests {
         assert_eq!(entry.unwrap().src_addr.port(), 12345);
     }
 
+    #[test]
+    fn test_port_strategies() {
+        let port_of =
+            |manager: &mut NatManager, key| manager.get_or_create(key).unwrap().local_port;
+
+        // Preserved ports fall back to the next free port on collision
+        let mut manager = NatManager::new().with_port_strategy(PortStrategy::PreservePort);
+        assert_eq!(port_of(&mut manager, make_tcp_key(12345, 443)), 12345);
+        assert_eq!(port_of(&mut manager, make_tcp_key(12345, 80)), 12346);
+        assert_eq!(port_of(&mut manager, make_tcp_key(80, 443)), 10000);
+        assert_eq!(manager.port_collisions(), 1);
+
+        // A source socket hashes to the same port once its mapping is gone
+        let mut manager = NatManager::new().with_port_strategy(PortStrategy::SourceHash);
+        let key = make_tcp_key(40000, 443);
+        let first = port_of(&mut manager, key);
+        manager.remove(&key);
+        assert_eq!(port_of(&mut manager, make_tcp_key(40000, 80)), first);
+
+        // Random ports stay in range and never collide with live mappings
+        let mut manager =
+            NatManager::with_config(20000, 20009, 100).with_port_strategy(PortStrategy::Random);
+        let mut ports: Vec<u16> = (0..10)
+            .map(|i| port_of(&mut manager, make_tcp_key(30000 + i, 443)))
+            .collect();
+        ports.sort();
+        assert_eq!(ports, (20000..=20009).collect::<Vec<_>>());
+        assert!(matches!(
+            manager.get_or_create(make_tcp_key(30010, 443)),
+            Err(VoyageError::NatTableFull)
+        ));
+    }
+
+    #[test]
+    fn test_nat_dual_stack() {
+        let mut manager = NatManager::new();
+        let v4 = make_tcp_key(12345, 443);
+        let v6 = NatKey::tcp(
+            "[fd00::2]:12345".parse().unwrap(),
+            "[2001:db8::1]:443".parse().unwrap(),
+        );
+
+        // Both families draw from one port pool
+        let v4_port = manager.get_or_create(v4).unwrap().local_port;
+        let v6_port = manager.get_or_create(v6).unwrap().local_port;
+        assert_ne!(v4_port, v6_port);
+        assert_eq!(manager.get_by_port(v6_port).unwrap().dst_addr, v6.dst_addr());
+
+        manager.remove(&v6);
+        assert!(manager.get_by_port(v6_port).is_none());
+        assert_eq!(manager.len(), 1);
+    }
+
+    fn make_udp_key(src_port: u16, dst: [u8; 4], dst_port: u16) -> NatKey {
+        let src = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), src_port));
+        let dst = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::from(dst), dst_port));
+        NatKey::udp(src, dst)
+    }
+
+    #[test]
+    fn test_udp_symmetric_mapping() {
+        let mut manager = NatManager::new();
+        let a = make_udp_key(5000, [1, 1, 1, 1], 3478);
+        let b = make_udp_key(5000, [2, 2, 2, 2], 3478);
+
+        let port_a = manager.get_or_create(a).unwrap().local_port;
+        let port_b = manager.get_or_create(b).unwrap().local_port;
+        assert_ne!(port_a, port_b);
+
+        // Only the original destination may answer
+        assert_eq!(manager.inbound_key(port_a, a.dst_addr()), Some(a));
+        assert_eq!(manager.inbound_key(port_a, b.dst_addr()), None);
+    }
+
+    #[test]
+    fn test_udp_full_cone_mapping() {
+        let mut manager = NatManager::new().with_udp_mode(NatMode::FullCone);
+        let a = make_udp_key(5000, [1, 1, 1, 1], 3478);
+        let b = make_udp_key(5000, [2, 2, 2, 2], 3478);
+        let other_src = make_udp_key(5001, [1, 1, 1, 1], 3478);
+
+        let port_a = manager.get_or_create(a).unwrap().local_port;
+        let port_b = manager.get_or_create(b).unwrap().local_port;
+        let port_c = manager.get_or_create(other_src).unwrap().local_port;
+        assert_eq!(port_a, port_b);
+        assert_ne!(port_a, port_c);
+
+        // Any remote may reach the mapped source
+        let peer: SocketAddr = "3.3.3.3:9000".parse().unwrap();
+        assert_eq!(manager.inbound_key(port_a, peer), Some(NatKey::udp(a.src_addr(), peer)));
+
+        // The port stays mapped until its last user is gone
+        manager.remove(&a);
+        assert_eq!(manager.get_key_by_port(port_a), Some(&b));
+        manager.remove(&b);
+        assert!(manager.get_key_by_port(port_a).is_none());
+        assert_eq!(manager.get_or_create(a).unwrap().local_port, port_a + 2);
+    }
+
+    #[test]
+    fn test_full_cone_leaves_tcp_symmetric() {
+        let mut manager = NatManager::new().with_udp_mode(NatMode::FullCone);
+        let port1 = manager.get_or_create(make_tcp_key(5000, 443)).unwrap().local_port;
+        let port2 = manager.get_or_create(make_tcp_key(5000, 80)).unwrap().local_port;
+        assert_ne!(port1, port2);
+    }
+
+    #[test]
+    fn test_nat_timeouts_by_state() {
+        let timeouts = NatTimeouts::default();
+        let tcp = make_tcp_key(1000, 443);
+        let mut entry = NatEntry::new(tcp.src_addr(), tcp.dst_addr(), 10000);
+
+        assert_eq!(timeouts.for_entry(&tcp, &entry), Duration::from_secs(30));
+        entry.establish();
+        assert_eq!(timeouts.for_entry(&tcp, &entry), Duration::from_secs(7200));
+        entry.start_close();
+        assert_eq!(timeouts.for_entry(&tcp, &entry), Duration::from_secs(30));
+
+        let udp = make_udp_key(5000, [1, 1, 1, 1], 53);
+        let mut entry = NatEntry::new(udp.src_addr(), udp.dst_addr(), 10001);
+        assert_eq!(timeouts.for_entry(&udp, &entry), Duration::from_secs(30));
+        entry.bytes_received = 100;
+        assert_eq!(timeouts.for_entry(&udp, &entry), Duration::from_secs(180));
+
+        let icmp = NatKey::icmp(udp.src_ip, udp.dst_ip, 1);
+        let mut entry = NatEntry::new(icmp.src_addr(), icmp.dst_addr(), 10002);
+        assert_eq!(timeouts.for_entry(&icmp, &entry), Duration::from_secs(30));
+        entry.bytes_received = 64;
+        assert_eq!(timeouts.for_entry(&icmp, &entry), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn test_cleanup_uses_state_timeouts() {
+        let timeouts = NatTimeouts {
+            tcp_syn_sent_secs: 0,
+            ..Default::default()
+        };
+        let mut manager = NatManager::new().with_timeouts(timeouts);
+        let half_open = make_tcp_key(1000, 443);
+        let established = make_tcp_key(1001, 443);
+        manager.get_or_create(half_open).unwrap();
+        manager.get_or_create(established).unwrap();
+        manager.establish(&established);
+
+        std::thread::sleep(Duration::from_millis(5));
+        manager.cleanup_expired();
+        assert!(manager.get(&half_open).is_none());
+        assert!(manager.get(&established).is_some());
+    }
+
+    #[test]
+    fn test_nat_lru_eviction() {
+        let mut manager = NatManager::with_config(10000, 60000, 2);
+        let a = make_tcp_key(1000, 443);
+        let b = make_tcp_key(1001, 443);
+        let c = make_tcp_key(1002, 443);
+
+        manager.get_or_create(a).unwrap();
+        std::thread::sleep(Duration::from_millis(2));
+        manager.get_or_create(b).unwrap();
+        std::thread::sleep(Duration::from_millis(2));
+        // Activity on `a` makes `b` the oldest
+        manager.add_bytes_sent(&a, 10);
+
+        manager.get_or_create(c).unwrap();
+        assert_eq!(manager.len(), 2);
+        assert!(manager.get(&b).is_none());
+        assert_eq!(manager.evictions(), 1);
+        let evicted: Vec<NatKey> = manager.take_evicted().into_iter().map(|(key, _)| key).collect();
+        assert_eq!(evicted, vec![b]);
+        assert!(manager.take_evicted().is_empty());
+
+        // Shrinking the table evicts the oldest remaining entry
+        manager.set_max_entries(1);
+        assert!(manager.get(&a).is_none());
+        assert_eq!((manager.len(), manager.evictions()), (1, 2));
+    }
+
+    #[test]
+    fn test_nat_per_source_limit() {
+        let mut manager = NatManager::new().with_max_per_source(Some(2));
+        manager.get_or_create(make_tcp_key(1000, 443)).unwrap();
+        manager.get_or_create(make_tcp_key(1001, 443)).unwrap();
+
+        let result = manager.get_or_create(make_tcp_key(1002, 443));
+        assert!(matches!(result, Err(VoyageError::Nat { .. })));
+        assert_eq!(manager.source_limit_hits(), 1);
+
+        // Another source is unaffected
+        let other = NatKey::tcp(
+            SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 1000)),
+            SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(8, 8, 8, 8), 443)),
+        );
+        assert!(manager.get_or_create(other).is_ok());
+
+        // Freeing a slot lets the source open a new flow
+        manager.remove(&make_tcp_key(1000, 443));
+        assert!(manager.get_or_create(make_tcp_key(1002, 443)).is_ok());
+    }
+
+    #[test]
+    fn test_nat_iter_filtered() {
+        let mut manager = NatManager::new();
+        let tcp = make_tcp_key(1000, 443);
+        let udp = NatKey::udp(tcp.src_addr(), "8.8.8.8:53".parse().unwrap());
+        manager.get_or_create(tcp).unwrap();
+        manager.get_or_create(udp).unwrap();
+        manager.establish(&tcp);
+
+        let keys = |protocol, state, since| -> Vec<NatKey> {
+            manager
+                .iter_filtered(protocol, state, since)
+                .map(|(key, _)| *key)
+                .collect()
+        };
+        assert_eq!(keys(None, None, None).len(), 2);
+        assert_eq!(keys(Some(17), None, None), vec![udp]);
+        assert_eq!(keys(None, Some(NatState::Established), None), vec![tcp]);
+        assert!(keys(Some(17), Some(NatState::Established), None).is_empty());
+
+        let later = Instant::now() + Duration::from_secs(1);
+        assert!(keys(None, None, Some(later)).is_empty());
+    }
+
     #[test]
     fn test_nat_manager_establish() {
         let mut manager = NatManager::new();
@@ -489,4 +1478,197 @@ mod tests {
         manager.establish(&key);
         assert_eq!(manager.get(&key).unwrap().state, NatState::Established);
     }
+
+    mod prop {
+        use super::*;
+        use proptest::prelude::*;
+        use std::collections::HashSet;
+
+        /// Narrow ranges so ports run out, wrap and collide
+        const MIN_PORT: u16 = 20000;
+        const MAX_PORT: u16 = 20015;
+        const MAX_ENTRIES: usize = 12;
+        const TIMEOUT_SECS: u64 = 60;
+
+        #[derive(Debug, Clone)]
+        enum Op {
+            /// A flow arrives from one of a few app sockets
+            Open { udp: bool, src_port: u16, dst_port: u16 },
+            /// The owner removes a flow, by position among live flows
+            Remove(usize),
+            /// A flow sees both FINs or a reset
+            Close(usize),
+            /// A flow goes idle past its timeout
+            Idle(usize),
+            /// A flow sees traffic
+            Touch(usize),
+            Cleanup,
+        }
+
+        fn op() -> impl Strategy<Value = Op> {
+            prop_oneof![
+                4 => (any::<bool>(), 1u16..6, 1u16..4).prop_map(|(udp, src_port, dst_port)| {
+                    Op::Open { udp, src_port, dst_port }
+                }),
+                2 => any::<usize>().prop_map(Op::Remove),
+                1 => any::<usize>().prop_map(Op::Close),
+                1 => any::<usize>().prop_map(Op::Idle),
+                1 => any::<usize>().prop_map(Op::Touch),
+                1 => Just(Op::Cleanup),
+            ]
+        }
+
+        fn strategy() -> impl Strategy<Value = PortStrategy> {
+            prop_oneof![
+                Just(PortStrategy::Sequential),
+                Just(PortStrategy::Random),
+                Just(PortStrategy::SourceHash),
+                Just(PortStrategy::PreservePort),
+            ]
+        }
+
+        fn manager(udp_mode: NatMode, port_strategy: PortStrategy) -> NatManager {
+            let secs = TIMEOUT_SECS;
+            let timeouts = NatTimeouts {
+                tcp_syn_sent_secs: secs,
+                tcp_established_secs: secs,
+                tcp_closing_secs: secs,
+                udp_unreplied_secs: secs,
+                udp_stream_secs: secs,
+                icmp_secs: secs,
+            };
+            NatManager::with_config(MIN_PORT, MAX_PORT, MAX_ENTRIES)
+                .with_timeouts(timeouts)
+                .with_udp_mode(udp_mode)
+                .with_port_strategy(port_strategy)
+        }
+
+        /// Live keys in creation order, so indices are reproducible
+        fn live(nat: &NatManager) -> Vec<NatKey> {
+            let mut entries: Vec<_> = nat.entries.iter().collect();
+            entries.sort_by_key(|(_, entry)| entry.id);
+            entries.into_iter().map(|(key, _)| *key).collect()
+        }
+
+        fn pick(nat: &NatManager, index: usize) -> Option<NatKey> {
+            let keys = live(nat);
+            (!keys.is_empty()).then(|| keys[index % keys.len()])
+        }
+
+        fn check_invariants(nat: &NatManager) -> Result<(), TestCaseError> {
+            // The reverse index points only at live entries holding the port
+            for (port, key) in &nat.port_to_key {
+                let entry = nat.entries.get(key);
+                prop_assert!(entry.is_some(), "port {} maps to a removed flow", port);
+                prop_assert_eq!(entry.unwrap().local_port, *port);
+            }
+
+            let mut by_port: HashMap<u16, Vec<&NatKey>> = HashMap::new();
+            for (key, entry) in &nat.entries {
+                prop_assert!((MIN_PORT..=MAX_PORT).contains(&entry.local_port));
+                prop_assert!(nat.port_to_key.contains_key(&entry.local_port));
+                by_port.entry(entry.local_port).or_default().push(key);
+            }
+
+            // Only full-cone UDP flows of one app socket share a port
+            for (port, keys) in &by_port {
+                if keys.len() > 1 {
+                    let src = keys[0].src_addr();
+                    let shared = nat.udp_mode == NatMode::FullCone
+                        && keys.iter().all(|key| key.is_udp() && key.src_addr() == src);
+                    prop_assert!(shared, "port {} shared by {:?}", port, keys);
+                    prop_assert_eq!(nat.cone_ports.get(&src), Some(&(*port, keys.len())));
+                }
+            }
+
+            let sources: usize = nat.shared.source_counts.lock().unwrap().values().sum();
+            prop_assert_eq!(sources, nat.entries.len());
+            prop_assert!(nat.entries.len() <= MAX_ENTRIES);
+            Ok(())
+        }
+
+        fn run(
+            udp_mode: NatMode,
+            port_strategy: PortStrategy,
+            ops: Vec<Op>,
+        ) -> Result<(), TestCaseError> {
+            let mut nat = manager(udp_mode, port_strategy);
+            let idle_since = Instant::now().checked_sub(Duration::from_secs(TIMEOUT_SECS * 2));
+
+            for op in ops {
+                match op {
+                    Op::Open { udp, src_port, dst_port } => {
+                        let src = SocketAddr::from(([10, 0, 0, 1], src_port));
+                        let dst = SocketAddr::from(([8, 8, 8, 8], dst_port));
+                        let key = if udp { NatKey::udp(src, dst) } else { NatKey::tcp(src, dst) };
+                        let existing = nat.get(&key).map(|entry| entry.id);
+                        if let Ok(entry) = nat.get_or_create(key) {
+                            // An existing flow keeps its entry
+                            if let Some(id) = existing {
+                                prop_assert_eq!(entry.id, id);
+                            }
+                        }
+                        for (key, _) in nat.take_evicted() {
+                            prop_assert!(nat.get(&key).is_none());
+                        }
+                    }
+                    Op::Remove(index) => {
+                        if let Some(key) = pick(&nat, index) {
+                            prop_assert!(nat.remove(&key).is_some());
+                            prop_assert!(nat.get(&key).is_none());
+                        }
+                    }
+                    Op::Close(index) => {
+                        if let Some(key) = pick(&nat, index) {
+                            nat.get_mut(&key).unwrap().close();
+                        }
+                    }
+                    Op::Idle(index) => {
+                        if let (Some(key), Some(past)) = (pick(&nat, index), idle_since) {
+                            nat.get_mut(&key).unwrap().last_seen = past;
+                        }
+                    }
+                    Op::Touch(index) => {
+                        if let Some(key) = pick(&nat, index) {
+                            nat.add_bytes_sent(&key, 100);
+                        }
+                    }
+                    Op::Cleanup => {
+                        let expired: HashSet<NatKey> = nat.expired_keys().into_iter().collect();
+                        let active: Vec<NatKey> = live(&nat)
+                            .into_iter()
+                            .filter(|key| !expired.contains(key))
+                            .collect();
+                        nat.cleanup_expired();
+                        for key in &active {
+                            prop_assert!(nat.get(key).is_some(), "cleanup removed {:?}", key);
+                        }
+                        for key in &expired {
+                            prop_assert!(nat.get(key).is_none(), "cleanup kept {:?}", key);
+                        }
+                    }
+                }
+                check_invariants(&nat)?;
+            }
+            Ok(())
+        }
+
+        proptest! {
+            #[test]
+            fn test_symmetric_nat_invariants(
+                port_strategy in strategy(),
+                ops in proptest::collection::vec(op(), 1..200),
+            ) {
+                run(NatMode::Symmetric, port_strategy, ops)?;
+            }
+
+            #[test]
+            fn test_full_cone_nat_invariants(
+                port_strategy in strategy(),
+                ops in proptest::collection::vec(op(), 1..200),
+            ) {
+                run(NatMode::FullCone, port_strategy, ops)?;
+            }
+        }
+    }
 }
@@ -0,0 +1,129 @@
+//! Network Changes
+//!
+//! iOS devices roam between Wi-Fi and cellular and go to sleep with the
+//! tunnel up. This module describes the network path the host reports and
+//! the background refresh the core runs after a change or a wake: resolving
+//! the proxy server again and re-running the health checks.
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::error::VoyageError;
+use crate::fakeip::Ipv4Range;
+use crate::message::LocalizedMessage;
+use crate::selftest;
+use crate::VoyageCore;
+
+/// Minimum sleep after which flows idle since the device slept are reset
+pub const HALF_DEAD_AFTER: Duration = Duration::from_secs(30);
+
+/// Timeout for resolving the proxy server hostname
+pub const RESOLVE_TIMEOUT_MS: u64 = 3000;
+
+/// Kind of interface the default route goes through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetworkInterface {
+    Wifi,
+    Cellular,
+    Wired,
+    Other,
+}
+
+/// The network path as reported by the host, e.g. from `NWPathMonitor`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NetworkPath {
+    /// Whether the device has a usable route
+    pub available: bool,
+    /// Interface of the default route
+    pub interface: NetworkInterface,
+    /// Whether the path is metered (cellular or a personal hotspot)
+    pub expensive: bool,
+    /// IPv4 networks (CIDRs) of the device on this path
+    pub local_networks: Vec<String>,
+}
+
+impl NetworkPath {
+    /// Parse the local networks of the path
+    pub fn networks(&self) -> Result<Vec<Ipv4Range>, VoyageError> {
+        self.local_networks
+            .iter()
+            .map(|c| c.parse::<Ipv4Range>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(VoyageError::config)
+    }
+}
+
+/// Resolve the proxy server and re-run the health checks.
+///
+/// Runs in the background after the network changed or the device woke up;
+/// failed checks are queued as warnings for the host.
+pub async fn refresh(core: Arc<RwLock<VoyageCore>>) {
+    let (host, port, upstreams) = match core.read() {
+        Ok(core) => (
+            core.config.server_host.clone(),
+            core.config.server_port,
+            core.dns.config().upstreams.clone(),
+        ),
+        Err(_) => return,
+    };
+
+    if host.parse::<IpAddr>().is_err() {
+        match resolve(&host, port).await {
+            Ok(addr) => {
+                log::info!("Proxy server {} resolved to {}", host, addr);
+                if let Ok(mut core) = core.write() {
+                    core.set_proxy_address(&host, addr);
+                }
+            }
+            Err(e) => log::warn!("Failed to resolve proxy server {}: {}", host, e),
+        }
+    }
+
+    let results = match tokio::task::spawn_blocking(move || selftest::run_all(&upstreams)).await {
+        Ok(results) => results,
+        Err(_) => return,
+    };
+    let Ok(mut core) = core.write() else {
+        return;
+    };
+    for failed in results.into_iter().filter(|r| !r.passed) {
+        core.push_event(LocalizedMessage::new(
+            "warning.health_check_failed",
+            vec![failed.name, failed.detail],
+        ));
+    }
+}
+
+async fn resolve(host: &str, port: u16) -> Result<SocketAddr, VoyageError> {
+    let timeout = Duration::from_millis(RESOLVE_TIMEOUT_MS);
+    let mut addrs = tokio::time::timeout(timeout, tokio::net::lookup_host((host, port)))
+        .await
+        .map_err(|_| VoyageError::io(format!("No answer within {} ms", RESOLVE_TIMEOUT_MS)))?
+        .map_err(|e| VoyageError::io(e.to_string()))?;
+    addrs
+        .next()
+        .ok_or_else(|| VoyageError::io("No addresses found".into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_networks() {
+        let mut path = NetworkPath {
+            available: true,
+            interface: NetworkInterface::Wifi,
+            expensive: false,
+            local_networks: vec!["192.168.1.0/24".into()],
+        };
+        assert_eq!(
+            path.networks().unwrap(),
+            vec!["192.168.1.0/24".parse().unwrap()]
+        );
+
+        path.local_networks.push("not a network".into());
+        assert!(path.networks().is_err());
+    }
+}
@@ -3,6 +3,7 @@
 //! This module provides IP packet parsing functionality for both IPv4 and IPv6,
 //! as well as TCP and UDP header parsing.
 
+use std::fmt;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 use crate::error::VoyageError;
@@ -16,6 +17,10 @@ pub const IPV6_HEADER_LEN: usize = 40;
 pub const TCP_MIN_HEADER_LEN: usize = 20;
 /// UDP header length
 pub const UDP_HEADER_LEN: usize = 8;
+/// ICMP/ICMPv6 header length
+pub const ICMP_HEADER_LEN: usize = 8;
+/// TCP option kind for Maximum Segment Size
+pub const TCP_OPT_MSS: u8 = 2;
 
 /// Protocol numbers
 pub const PROTO_TCP: u8 = 6;
@@ -23,6 +28,62 @@ pub const PROTO_UDP: u8 = 17;
 pub const PROTO_ICMP: u8 = 1;
 pub const PROTO_ICMPV6: u8 = 58;
 
+/// ICMP message types
+pub const ICMP_ECHO_REPLY: u8 = 0;
+pub const ICMP_ECHO_REQUEST: u8 = 8;
+pub const ICMPV6_ECHO_REQUEST: u8 = 128;
+pub const ICMPV6_ECHO_REPLY: u8 = 129;
+
+/// Why a packet or message failed to parse
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// No data at all
+    Empty,
+    /// IP version other than 4 or 6
+    UnknownIpVersion { version: u8 },
+    /// Shorter than an IPv4 header
+    Ipv4TooShort,
+    /// IPv4 header length field out of range
+    InvalidIpv4HeaderLength,
+    /// Shorter than an IPv6 header
+    Ipv6TooShort,
+    /// Shorter than a TCP header
+    TcpTooShort,
+    /// TCP data offset out of range
+    InvalidTcpDataOffset,
+    /// Shorter than a UDP header
+    UdpTooShort,
+    /// Shorter than an ICMP header
+    IcmpTooShort,
+    /// Neither TCP, UDP nor an ICMP echo, so no flow can track it
+    NoFlow,
+    /// Malformed DNS message
+    MalformedDns,
+    /// DNS message too long to frame over TCP
+    DnsTooLong,
+}
+
+impl fmt::Display for ParseErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseErrorKind::Empty => f.write_str("Empty packet"),
+            ParseErrorKind::UnknownIpVersion { version } => {
+                write!(f, "Unknown IP version: {}", version)
+            }
+            ParseErrorKind::Ipv4TooShort => f.write_str("IPv4 packet too short"),
+            ParseErrorKind::InvalidIpv4HeaderLength => f.write_str("Invalid IPv4 IHL"),
+            ParseErrorKind::Ipv6TooShort => f.write_str("IPv6 packet too short"),
+            ParseErrorKind::TcpTooShort => f.write_str("TCP header too short"),
+            ParseErrorKind::InvalidTcpDataOffset => f.write_str("Invalid TCP data offset"),
+            ParseErrorKind::UdpTooShort => f.write_str("UDP header too short"),
+            ParseErrorKind::IcmpTooShort => f.write_str("ICMP header too short"),
+            ParseErrorKind::NoFlow => f.write_str("Cannot create NAT key"),
+            ParseErrorKind::MalformedDns => f.write_str("Malformed DNS message"),
+            ParseErrorKind::DnsTooLong => f.write_str("DNS message too long"),
+        }
+    }
+}
+
 /// IP version
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum IpVersion {
@@ -84,32 +145,33 @@ impl IpPacketInfo {
     /// Parse an IP packet header
     pub fn parse(data: &[u8]) -> Result<Self, VoyageError> {
         if data.is_empty() {
-            return Err(VoyageError::InvalidPacket("Empty packet".into()));
+            return Err(VoyageError::packet(ParseErrorKind::Empty, 0));
         }
 
         let version = data[0] >> 4;
         match version {
             4 => Self::parse_ipv4(data),
             6 => Self::parse_ipv6(data),
-            _ => Err(VoyageError::InvalidPacket(format!(
-                "Unknown IP version: {}",
-                version
-            ))),
+            _ => Err(VoyageError::packet(ParseErrorKind::UnknownIpVersion { version }, 0)),
         }
     }
 
     /// Parse IPv4 header
     fn parse_ipv4(data: &[u8]) -> Result<Self, VoyageError> {
         if data.len() < IPV4_MIN_HEADER_LEN {
-            return Err(VoyageError::InvalidPacket("IPv4 packet too short".into()));
+            return Err(VoyageError::packet(ParseErrorKind::Ipv4TooShort, 0));
         }
 
         let ihl = (data[0] & 0x0F) as usize * 4;
         if ihl < IPV4_MIN_HEADER_LEN || data.len() < ihl {
-            return Err(VoyageError::InvalidPacket("Invalid IPv4 IHL".into()));
+            return Err(VoyageError::packet(ParseErrorKind::InvalidIpv4HeaderLength, 0));
         }
 
         let total_len = u16::from_be_bytes([data[2], data[3]]) as usize;
+        // The total length covers the header; a shorter one is corrupt
+        if total_len < ihl {
+            return Err(VoyageError::packet(ParseErrorKind::InvalidIpv4HeaderLength, 2));
+        }
         let protocol = data[9];
 
         let src_ip = IpAddr::V4(Ipv4Addr::new(data[12], data[13], data[14], data[15]));
@@ -129,7 +191,7 @@ impl IpPacketInfo {
     /// Parse IPv6 header
     fn parse_ipv6(data: &[u8]) -> Result<Self, VoyageError> {
         if data.len() < IPV6_HEADER_LEN {
-            return Err(VoyageError::InvalidPacket("IPv6 packet too short".into()));
+            return Err(VoyageError::packet(ParseErrorKind::Ipv6TooShort, 0));
         }
 
         let payload_len = u16::from_be_bytes([data[4], data[5]]) as usize;
@@ -270,7 +332,7 @@ impl TcpPacketInfo {
     /// Parse TCP header from transport layer data
     pub fn parse(data: &[u8]) -> Result<Self, VoyageError> {
         if data.len() < TCP_MIN_HEADER_LEN {
-            return Err(VoyageError::InvalidPacket("TCP header too short".into()));
+            return Err(VoyageError::packet(ParseErrorKind::TcpTooShort, 0));
         }
 
         let src_port = u16::from_be_bytes([data[0], data[1]]);
@@ -284,7 +346,7 @@ impl TcpPacketInfo {
         let urgent_ptr = u16::from_be_bytes([data[18], data[19]]);
 
         if data_offset < TCP_MIN_HEADER_LEN || data.len() < data_offset {
-            return Err(VoyageError::InvalidPacket("Invalid TCP data offset".into()));
+            return Err(VoyageError::packet(ParseErrorKind::InvalidTcpDataOffset, 12));
         }
 
         Ok(Self {
@@ -311,11 +373,7 @@ impl TcpPacketInfo {
 
     /// Get payload length
     pub fn payload_len(&self, transport_data_len: usize) -> usize {
-        if transport_data_len > self.data_offset {
-            transport_data_len - self.data_offset
-        } else {
-            0
-        }
+        transport_data_len.saturating_sub(self.data_offset)
     }
 }
 
@@ -336,7 +394,7 @@ impl UdpPacketInfo {
     /// Parse UDP header from transport layer data
     pub fn parse(data: &[u8]) -> Result<Self, VoyageError> {
         if data.len() < UDP_HEADER_LEN {
-            return Err(VoyageError::InvalidPacket("UDP header too short".into()));
+            return Err(VoyageError::packet(ParseErrorKind::UdpTooShort, 0));
         }
 
         let src_port = u16::from_be_bytes([data[0], data[1]]);
@@ -371,6 +429,46 @@ impl UdpPacketInfo {
     }
 }
 
+/// Parsed ICMP/ICMPv6 header information
+#[derive(Debug, Clone)]
+pub struct IcmpPacketInfo {
+    /// Message type
+    pub icmp_type: u8,
+    /// Message code
+    pub code: u8,
+    /// Checksum
+    pub checksum: u16,
+    /// Echo identifier (meaningful for echo messages only)
+    pub identifier: u16,
+    /// Echo sequence number (meaningful for echo messages only)
+    pub sequence: u16,
+}
+
+impl IcmpPacketInfo {
+    /// Parse an ICMP or ICMPv6 header from transport layer data
+    pub fn parse(data: &[u8]) -> Result<Self, VoyageError> {
+        if data.len() < ICMP_HEADER_LEN {
+            return Err(VoyageError::packet(ParseErrorKind::IcmpTooShort, 0));
+        }
+
+        Ok(Self {
+            icmp_type: data[0],
+            code: data[1],
+            checksum: u16::from_be_bytes([data[2], data[3]]),
+            identifier: u16::from_be_bytes([data[4], data[5]]),
+            sequence: u16::from_be_bytes([data[6], data[7]]),
+        })
+    }
+
+    /// Check if this is an echo request or reply (ICMP or ICMPv6)
+    pub fn is_echo(&self) -> bool {
+        matches!(
+            self.icmp_type,
+            ICMP_ECHO_REQUEST | ICMP_ECHO_REPLY | ICMPV6_ECHO_REQUEST | ICMPV6_ECHO_REPLY
+        )
+    }
+}
+
 /// Complete parsed packet info
 #[derive(Debug, Clone)]
 pub struct ParsedPacket {
@@ -380,6 +478,8 @@ pub struct ParsedPacket {
     pub tcp: Option<TcpPacketInfo>,
     /// UDP info (if UDP packet)
     pub udp: Option<UdpPacketInfo>,
+    /// ICMP info (if ICMP/ICMPv6 packet)
+    pub icmp: Option<IcmpPacketInfo>,
 }
 
 impl ParsedPacket {
@@ -388,24 +488,34 @@ impl ParsedPacket {
         let ip = IpPacketInfo::parse(data)?;
 
         let transport_data = ip.get_payload(data);
+        // Transport offsets are relative to the transport header
+        let in_packet = |e: VoyageError| e.shifted(ip.payload_offset);
+
+        let (mut tcp, mut udp, mut icmp) = (None, None, None);
+        match ip.protocol {
+            TransportProtocol::Tcp => {
+                tcp = Some(TcpPacketInfo::parse(transport_data).map_err(in_packet)?)
+            }
+            TransportProtocol::Udp => {
+                udp = Some(UdpPacketInfo::parse(transport_data).map_err(in_packet)?)
+            }
+            TransportProtocol::Icmp => {
+                icmp = Some(IcmpPacketInfo::parse(transport_data).map_err(in_packet)?)
+            }
+            _ => {}
+        }
 
-        let (tcp, udp) = match ip.protocol {
-            TransportProtocol::Tcp => (Some(TcpPacketInfo::parse(transport_data)?), None),
-            TransportProtocol::Udp => (None, Some(UdpPacketInfo::parse(transport_data)?)),
-            _ => (None, None),
-        };
-
-        Ok(Self { ip, tcp, udp })
+        Ok(Self { ip, tcp, udp, icmp })
     }
 
     /// Get source socket address (for TCP/UDP)
     pub fn src_addr(&self) -> Option<SocketAddr> {
         if let Some(ref tcp) = self.tcp {
             Some(SocketAddr::new(self.ip.src_ip, tcp.src_port))
-        } else if let Some(ref udp) = self.udp {
-            Some(SocketAddr::new(self.ip.src_ip, udp.src_port))
         } else {
-            None
+            self.udp
+                .as_ref()
+                .map(|udp| SocketAddr::new(self.ip.src_ip, udp.src_port))
         }
     }
 
@@ -413,15 +523,22 @@ impl ParsedPacket {
     pub fn dst_addr(&self) -> Option<SocketAddr> {
         if let Some(ref tcp) = self.tcp {
             Some(SocketAddr::new(self.ip.dst_ip, tcp.dst_port))
-        } else if let Some(ref udp) = self.udp {
-            Some(SocketAddr::new(self.ip.dst_ip, udp.dst_port))
         } else {
-            None
+            self.udp
+                .as_ref()
+                .map(|udp| SocketAddr::new(self.ip.dst_ip, udp.dst_port))
         }
     }
 
-    /// Create a NAT key for this packet
+    /// Create a NAT key for this packet.
+    ///
+    /// ICMP echo messages are keyed by their identifier; other ICMP
+    /// messages have no flow and return `None`.
     pub fn to_nat_key(&self) -> Option<NatKey> {
+        if let Some(icmp) = self.icmp.as_ref().filter(|icmp| icmp.is_echo()) {
+            return Some(NatKey::icmp(self.ip.src_ip, self.ip.dst_ip, icmp.identifier));
+        }
+
         let src = self.src_addr()?;
         let dst = self.dst_addr()?;
 
@@ -460,6 +577,283 @@ impl ParsedPacket {
     }
 }
 
+/// Incrementally update a ones-complement checksum after a 16-bit word
+/// changed from `old` to `new` (RFC 1624)
+fn update_checksum(checksum: u16, old: u16, new: u16) -> u16 {
+    let mut sum = (!checksum as u32) + (!old as u32) + (new as u32);
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Lower the MSS option of a TCP SYN or SYN-ACK to at most `max_mss`.
+///
+/// The packet is rewritten in place and the TCP checksum is patched.
+/// Returns the original MSS if it was clamped.
+pub fn clamp_tcp_mss(packet: &mut [u8], max_mss: u16) -> Option<u16> {
+    let ip = IpPacketInfo::parse(packet).ok()?;
+    if ip.protocol != TransportProtocol::Tcp {
+        return None;
+    }
+
+    let tcp_start = ip.payload_offset;
+    let tcp = TcpPacketInfo::parse(packet.get(tcp_start..)?).ok()?;
+    if !tcp.flags.syn {
+        return None;
+    }
+
+    let options_end = tcp_start + tcp.data_offset;
+    let mut i = tcp_start + TCP_MIN_HEADER_LEN;
+    while i < options_end {
+        match packet[i] {
+            0 => break,
+            1 => i += 1,
+            kind => {
+                let len = *packet.get(i + 1)? as usize;
+                if len < 2 || i + len > options_end {
+                    return None;
+                }
+                if kind == TCP_OPT_MSS && len == 4 {
+                    let mss = u16::from_be_bytes([packet[i + 2], packet[i + 3]]);
+                    if mss <= max_mss {
+                        return None;
+                    }
+                    packet[i + 2..i + 4].copy_from_slice(&max_mss.to_be_bytes());
+
+                    let checksum_at = tcp_start + 16;
+                    let checksum = u16::from_be_bytes([packet[checksum_at], packet[checksum_at + 1]]);
+                    // A value at an odd offset straddles two checksum words and
+                    // contributes to the sum byte-swapped.
+                    let (old, new) = if (i + 2 - tcp_start).is_multiple_of(2) {
+                        (mss, max_mss)
+                    } else {
+                        (mss.swap_bytes(), max_mss.swap_bytes())
+                    };
+                    let checksum = update_checksum(checksum, old, new);
+                    packet[checksum_at..checksum_at + 2].copy_from_slice(&checksum.to_be_bytes());
+                    return Some(mss);
+                }
+                i += len;
+            }
+        }
+    }
+
+    None
+}
+
+/// Split a packet larger than `mtu` into packets that fit.
+///
+/// TCP payloads are cut into segments of the MSS the MTU leaves room for,
+/// with FIN and PSH kept on the last one. IPv4 UDP is fragmented unless the
+/// sender set Don't Fragment. Returns `None` for packets that cannot be
+/// split: UDP with DF or over IPv6, SYNs carrying data, other protocols, or
+/// headers that leave no room for payload. Checksums of the pieces are
+/// recomputed, so verify the original first.
+pub fn split_oversized(packet: &[u8], mtu: usize) -> Option<Vec<Vec<u8>>> {
+    let ip = IpPacketInfo::parse(packet).ok()?;
+    let end = ip.total_len.min(packet.len());
+    match ip.protocol {
+        TransportProtocol::Tcp => segment_tcp(packet.get(..end)?, &ip, mtu),
+        TransportProtocol::Udp if ip.version == IpVersion::V4 => {
+            fragment_ipv4(packet.get(..end)?, ip.header_len, mtu)
+        }
+        _ => None,
+    }
+}
+
+fn segment_tcp(packet: &[u8], ip: &IpPacketInfo, mtu: usize) -> Option<Vec<Vec<u8>>> {
+    use smoltcp::wire::TcpPacket;
+
+    let tcp_start = ip.payload_offset;
+    let tcp = TcpPacketInfo::parse(packet.get(tcp_start..)?).ok()?;
+    if tcp.flags.syn {
+        return None;
+    }
+    let headers = tcp_start + tcp.data_offset;
+    let mss = mtu.checked_sub(headers).filter(|&mss| mss > 0)?;
+    let payload = packet.get(headers..)?;
+    let (src, dst) = (smoltcp_addr(ip.src_ip), smoltcp_addr(ip.dst_ip));
+
+    let count = payload.len().div_ceil(mss);
+    let segments = payload
+        .chunks(mss)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let mut segment = Vec::with_capacity(headers + chunk.len());
+            segment.extend_from_slice(&packet[..headers]);
+            segment.extend_from_slice(chunk);
+
+            let seq = tcp.seq_num.wrapping_add((i * mss) as u32);
+            segment[tcp_start + 4..tcp_start + 8].copy_from_slice(&seq.to_be_bytes());
+            if i + 1 < count {
+                // FIN and PSH belong to the end of the data
+                segment[tcp_start + 13] &= !0x09;
+            }
+            set_ip_length(&mut segment, ip.version, ip.header_len);
+            TcpPacket::new_unchecked(&mut segment[tcp_start..]).fill_checksum(&src, &dst);
+            segment
+        })
+        .collect();
+    Some(segments)
+}
+
+fn fragment_ipv4(packet: &[u8], header_len: usize, mtu: usize) -> Option<Vec<Vec<u8>>> {
+    let flags = u16::from_be_bytes([packet[6], packet[7]]);
+    if flags & 0x4000 != 0 {
+        return None;
+    }
+    let more_fragments = flags & 0x2000 != 0;
+    let base_offset = (flags & 0x1FFF) as usize * 8;
+    // Fragment payloads other than the last must be multiples of 8 bytes
+    let chunk_len = mtu.checked_sub(header_len)? / 8 * 8;
+    if chunk_len == 0 {
+        return None;
+    }
+
+    let payload = &packet[header_len..];
+    let count = payload.len().div_ceil(chunk_len);
+    let fragments = payload
+        .chunks(chunk_len)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let mut fragment = Vec::with_capacity(header_len + chunk.len());
+            fragment.extend_from_slice(&packet[..header_len]);
+            fragment.extend_from_slice(chunk);
+
+            let offset = (base_offset + i * chunk_len) / 8;
+            let more = i + 1 < count || more_fragments;
+            let flags = offset as u16 | if more { 0x2000 } else { 0 };
+            fragment[6..8].copy_from_slice(&flags.to_be_bytes());
+            set_ip_length(&mut fragment, IpVersion::V4, header_len);
+            fragment
+        })
+        .collect();
+    Some(fragments)
+}
+
+/// Rewrite the length field of an IP header to the packet's size, and the
+/// header checksum for IPv4
+fn set_ip_length(packet: &mut [u8], version: IpVersion, header_len: usize) {
+    match version {
+        IpVersion::V4 => {
+            let total_len = packet.len() as u16;
+            packet[2..4].copy_from_slice(&total_len.to_be_bytes());
+            smoltcp::wire::Ipv4Packet::new_unchecked(&mut packet[..header_len]).fill_checksum();
+        }
+        IpVersion::V6 => {
+            let payload_len = (packet.len() - IPV6_HEADER_LEN) as u16;
+            packet[4..6].copy_from_slice(&payload_len.to_be_bytes());
+        }
+    }
+}
+
+fn smoltcp_addr(addr: IpAddr) -> smoltcp::wire::IpAddress {
+    use smoltcp::wire::{IpAddress, Ipv4Address, Ipv6Address};
+
+    match addr {
+        IpAddr::V4(v4) => IpAddress::Ipv4(Ipv4Address::from_bytes(&v4.octets())),
+        IpAddr::V6(v6) => IpAddress::Ipv6(Ipv6Address::from_bytes(&v6.octets())),
+    }
+}
+
+/// Check the IPv4 header checksum and the TCP or UDP checksum of a packet.
+///
+/// Packets of other protocols, or behind IPv6 extension headers, only have
+/// their IP header checked. Truncated packets fail.
+pub fn verify_checksums(packet: &[u8]) -> bool {
+    use smoltcp::wire::{IpAddress, IpProtocol, Ipv4Packet, Ipv6Packet, TcpPacket, UdpPacket};
+
+    let (src, dst, protocol, payload) = match packet.first().map(|b| b >> 4) {
+        Some(4) => {
+            let Ok(ip) = Ipv4Packet::new_checked(packet) else {
+                return false;
+            };
+            if !ip.verify_checksum() {
+                return false;
+            }
+            let src = IpAddress::Ipv4(ip.src_addr());
+            let dst = IpAddress::Ipv4(ip.dst_addr());
+            (src, dst, ip.next_header(), ip.payload())
+        }
+        Some(6) => {
+            let Ok(ip) = Ipv6Packet::new_checked(packet) else {
+                return false;
+            };
+            let src = IpAddress::Ipv6(ip.src_addr());
+            let dst = IpAddress::Ipv6(ip.dst_addr());
+            (src, dst, ip.next_header(), ip.payload())
+        }
+        _ => return false,
+    };
+
+    match protocol {
+        IpProtocol::Tcp => TcpPacket::new_checked(payload)
+            .map(|tcp| tcp.verify_checksum(&src, &dst))
+            .unwrap_or(false),
+        IpProtocol::Udp => UdpPacket::new_checked(payload)
+            .map(|udp| udp.verify_checksum(&src, &dst))
+            .unwrap_or(false),
+        _ => true,
+    }
+}
+
+/// Build an IPv4/IPv6 UDP packet with valid checksums.
+///
+/// Returns `None` if the source and destination address families differ.
+pub fn build_udp_packet(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> Option<Vec<u8>> {
+    use smoltcp::phy::ChecksumCapabilities;
+    use smoltcp::wire::{
+        IpAddress, IpProtocol, Ipv4Address, Ipv4Packet, Ipv4Repr, Ipv6Address, Ipv6Packet,
+        Ipv6Repr, UdpPacket, UdpRepr,
+    };
+
+    let udp = UdpRepr {
+        src_port: src.port(),
+        dst_port: dst.port(),
+    };
+    let udp_len = udp.header_len() + payload.len();
+    let caps = ChecksumCapabilities::default();
+
+    let (mut buffer, header_len, src_ip, dst_ip) = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            let repr = Ipv4Repr {
+                src_addr: Ipv4Address::from_bytes(&s.octets()),
+                dst_addr: Ipv4Address::from_bytes(&d.octets()),
+                next_header: IpProtocol::Udp,
+                payload_len: udp_len,
+                hop_limit: 64,
+            };
+            let mut buffer = vec![0u8; repr.buffer_len() + udp_len];
+            repr.emit(&mut Ipv4Packet::new_unchecked(&mut buffer), &caps);
+            (buffer, repr.buffer_len(), IpAddress::Ipv4(repr.src_addr), IpAddress::Ipv4(repr.dst_addr))
+        }
+        (IpAddr::V6(s), IpAddr::V6(d)) => {
+            let repr = Ipv6Repr {
+                src_addr: Ipv6Address::from_bytes(&s.octets()),
+                dst_addr: Ipv6Address::from_bytes(&d.octets()),
+                next_header: IpProtocol::Udp,
+                payload_len: udp_len,
+                hop_limit: 64,
+            };
+            let mut buffer = vec![0u8; repr.buffer_len() + udp_len];
+            repr.emit(&mut Ipv6Packet::new_unchecked(&mut buffer));
+            (buffer, repr.buffer_len(), IpAddress::Ipv6(repr.src_addr), IpAddress::Ipv6(repr.dst_addr))
+        }
+        _ => return None,
+    };
+
+    udp.emit(
+        &mut UdpPacket::new_unchecked(&mut buffer[header_len..]),
+        &src_ip,
+        &dst_ip,
+        payload.len(),
+        |buf| buf.copy_from_slice(payload),
+        &caps,
+    );
+    Some(buffer)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -613,6 +1007,38 @@ mod tests {
         assert_eq!(key.dst_port, 443);
     }
 
+    #[test]
+    fn test_icmp_echo_nat_key() {
+        let mut packet = vec![0u8; 28]; // 20 byte IP + 8 byte ICMP
+        packet[0] = 0x45;
+        packet[3] = 0x1C;
+        packet[9] = PROTO_ICMP;
+        packet[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        packet[16..20].copy_from_slice(&[1, 1, 1, 1]);
+        packet[20] = ICMP_ECHO_REQUEST;
+        packet[24] = 0xBE; // Identifier 0xBEEF
+        packet[25] = 0xEF;
+        packet[27] = 3; // Sequence 3
+
+        let parsed = ParsedPacket::parse(&packet).unwrap();
+        let icmp = parsed.icmp.as_ref().unwrap();
+        assert!(icmp.is_echo());
+        assert_eq!(icmp.sequence, 3);
+
+        let key = parsed.to_nat_key().unwrap();
+        assert!(key.is_icmp());
+        assert_eq!(key.src_port, 0xBEEF);
+        assert_eq!(key.dst_ip, IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1)));
+
+        // Destination unreachable carries no session
+        packet[20] = 3;
+        let parsed = ParsedPacket::parse(&packet).unwrap();
+        assert!(parsed.to_nat_key().is_none());
+
+        // Truncated ICMP header
+        assert!(ParsedPacket::parse(&packet[..26]).is_err());
+    }
+
     #[test]
     fn test_src_dst_addr() {
         let packet = make_ipv4_tcp_syn();
@@ -634,7 +1060,211 @@ mod tests {
     #[test]
     fn test_too_short_packet() {
         let result = ParsedPacket::parse(&[0x45, 0x00]);
-        assert!(result.is_err());
+        assert!(matches!(
+            result,
+            Err(VoyageError::Packet {
+                kind: ParseErrorKind::Ipv4TooShort,
+                offset: 0
+            })
+        ));
+
+        // Offsets of transport errors count from the start of the packet
+        let packet = make_ipv4_tcp_syn();
+        let result = ParsedPacket::parse(&packet[..30]);
+        assert!(matches!(
+            result,
+            Err(VoyageError::Packet {
+                kind: ParseErrorKind::TcpTooShort,
+                offset: 20
+            })
+        ));
+    }
+
+    #[test]
+    fn test_segment_tcp() {
+        let mut packet = crate::create_tcp_packet([10, 0, 0, 2], [10, 0, 0, 1], 40000, 80, false);
+        packet[33] |= 0x09; // FIN, PSH
+        packet.extend((0..250).map(|i| i as u8));
+        let total_len = packet.len() as u16;
+        packet[2..4].copy_from_slice(&total_len.to_be_bytes());
+
+        let segments = split_oversized(&packet, 140).unwrap();
+        assert_eq!(segments.len(), 3);
+        let mut payload = Vec::new();
+        for (i, segment) in segments.iter().enumerate() {
+            assert!(segment.len() <= 140);
+            assert!(verify_checksums(segment));
+            let parsed = ParsedPacket::parse(segment).unwrap();
+            let tcp = parsed.tcp.as_ref().unwrap();
+            assert_eq!(tcp.seq_num, 1 + payload.len() as u32);
+            assert_eq!(tcp.flags.fin, i == 2);
+            payload.extend_from_slice(parsed.tcp_payload(segment).unwrap());
+        }
+        assert_eq!(payload, packet[40..]);
+
+        // A SYN with data is left alone
+        packet[33] = 0x02;
+        assert!(split_oversized(&packet, 140).is_none());
+    }
+
+    #[test]
+    fn test_fragment_udp() {
+        let src: SocketAddr = "10.0.0.2:5353".parse().unwrap();
+        let dst: SocketAddr = "10.0.0.1:53".parse().unwrap();
+        let mut packet = build_udp_packet(src, dst, &[1; 300]).unwrap();
+        // Clear the Don't Fragment flag smoltcp sets
+        packet[6] &= !0x40;
+
+        let fragments = split_oversized(&packet, 128).unwrap();
+        let fields: Vec<_> = fragments
+            .iter()
+            .map(|f| (f.len(), u16::from_be_bytes([f[6], f[7]])))
+            .collect();
+        // 104 payload bytes each, offsets in 8-byte units, MF on all but the last
+        assert_eq!(fields, [(124, 0x2000), (124, 0x200D), (120, 0x001A)]);
+        assert!(fragments.iter().all(|f| {
+            smoltcp::wire::Ipv4Packet::new_checked(&f[..]).unwrap().verify_checksum()
+        }));
+
+        // Don't Fragment, or IPv6
+        packet[6] |= 0x40;
+        assert!(split_oversized(&packet, 128).is_none());
+        let v6 = build_udp_packet("[fd00::2]:1".parse().unwrap(), "[fd00::1]:2".parse().unwrap(), &[1; 300]);
+        assert!(split_oversized(&v6.unwrap(), 128).is_none());
+    }
+
+    #[test]
+    fn test_total_length_shorter_than_header() {
+        let src: SocketAddr = "10.0.0.2:5353".parse().unwrap();
+        let dst: SocketAddr = "10.0.0.1:53".parse().unwrap();
+        let mut packet = build_udp_packet(src, dst, &[1; 300]).unwrap();
+        packet[6] &= !0x40;
+        // Total length 8 with a 20-byte header used to panic when fragmenting
+        packet[2..4].copy_from_slice(&8u16.to_be_bytes());
+
+        assert!(matches!(
+            IpPacketInfo::parse(&packet),
+            Err(VoyageError::Packet { kind: ParseErrorKind::InvalidIpv4HeaderLength, offset: 2 })
+        ));
+        assert!(ParsedPacket::parse(&packet).is_err());
+        assert!(split_oversized(&packet, 128).is_none());
+        let tcp = crate::create_tcp_packet([10, 0, 0, 1], [10, 0, 0, 2], 1, 2, false);
+        let mut tcp = [tcp, vec![0; 300]].concat();
+        tcp[2..4].copy_from_slice(&19u16.to_be_bytes());
+        assert!(split_oversized(&tcp, 128).is_none());
+    }
+
+    #[test]
+    fn test_verify_checksums() {
+        let src: SocketAddr = "10.0.0.2:5353".parse().unwrap();
+        let dst: SocketAddr = "10.0.0.1:53".parse().unwrap();
+        let mut packet = build_udp_packet(src, dst, b"query").unwrap();
+        assert!(verify_checksums(&packet));
+
+        let src6: SocketAddr = "[fd00::2]:5353".parse().unwrap();
+        let dst6: SocketAddr = "[fd00::1]:53".parse().unwrap();
+        assert!(verify_checksums(&build_udp_packet(src6, dst6, b"query").unwrap()));
+
+        // Corrupt the payload, then the IP header
+        packet[30] ^= 0xFF;
+        assert!(!verify_checksums(&packet));
+        packet[30] ^= 0xFF;
+        packet[8] -= 1;
+        assert!(!verify_checksums(&packet));
+
+        assert!(!verify_checksums(&packet[..10]));
+        assert!(!verify_checksums(&[]));
+    }
+
+    /// Full TCP checksum over an IPv4 packet, for verifying in-place rewrites
+    fn ipv4_tcp_checksum(packet: &[u8]) -> u16 {
+        let tcp = &packet[20..];
+        let mut data = Vec::new();
+        data.extend_from_slice(&packet[12..20]);
+        data.extend_from_slice(&[0, PROTO_TCP]);
+        data.extend_from_slice(&(tcp.len() as u16).to_be_bytes());
+        data.extend_from_slice(&tcp[..16]);
+        data.extend_from_slice(&tcp[18..]);
+        if !data.len().is_multiple_of(2) {
+            data.push(0);
+        }
+        let mut sum: u32 = data
+            .chunks(2)
+            .map(|c| u16::from_be_bytes([c[0], c[1]]) as u32)
+            .sum();
+        while sum > 0xFFFF {
+            sum = (sum & 0xFFFF) + (sum >> 16);
+        }
+        !(sum as u16)
+    }
+
+    /// IPv4 TCP SYN carrying `options` and a valid checksum
+    fn make_syn_with_options(options: &[u8]) -> Vec<u8> {
+        let mut packet = make_ipv4_tcp_syn();
+        packet.extend_from_slice(options);
+        let total = packet.len() as u16;
+        packet[2..4].copy_from_slice(&total.to_be_bytes());
+        packet[32] = (((20 + options.len()) / 4) as u8) << 4;
+        let checksum = ipv4_tcp_checksum(&packet);
+        packet[36..38].copy_from_slice(&checksum.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn test_clamp_tcp_mss() {
+        // NOP, NOP, MSS 1460, then padding to keep 32-bit alignment
+        let mut packet = make_syn_with_options(&[1, 1, TCP_OPT_MSS, 4, 0x05, 0xB4, 0, 0]);
+
+        assert_eq!(clamp_tcp_mss(&mut packet, 1360), Some(1460));
+        assert_eq!(u16::from_be_bytes([packet[44], packet[45]]), 1360);
+        assert_eq!(
+            u16::from_be_bytes([packet[36], packet[37]]),
+            ipv4_tcp_checksum(&packet)
+        );
+
+        // Already small enough: untouched
+        assert_eq!(clamp_tcp_mss(&mut packet, 1400), None);
+        assert_eq!(u16::from_be_bytes([packet[44], packet[45]]), 1360);
+    }
+
+    #[test]
+    fn test_clamp_tcp_mss_odd_offset() {
+        let mut packet = make_syn_with_options(&[1, TCP_OPT_MSS, 4, 0x05, 0xB4, 0, 0, 0]);
+
+        assert_eq!(clamp_tcp_mss(&mut packet, 1200), Some(1460));
+        assert_eq!(u16::from_be_bytes([packet[43], packet[44]]), 1200);
+        assert_eq!(
+            u16::from_be_bytes([packet[36], packet[37]]),
+            ipv4_tcp_checksum(&packet)
+        );
+    }
+
+    #[test]
+    fn test_clamp_tcp_mss_ignores_non_syn() {
+        let mut packet = make_syn_with_options(&[TCP_OPT_MSS, 4, 0x05, 0xB4]);
+        packet[33] = 0x10; // ACK only
+        assert_eq!(clamp_tcp_mss(&mut packet, 1000), None);
+
+        let mut udp = make_ipv4_udp();
+        assert_eq!(clamp_tcp_mss(&mut udp, 1000), None);
+    }
+
+    #[test]
+    fn test_build_udp_packet() {
+        let src: SocketAddr = "198.18.0.2:53".parse().unwrap();
+        let dst: SocketAddr = "10.0.0.1:5353".parse().unwrap();
+        let packet = build_udp_packet(src, dst, b"hello").unwrap();
+
+        assert!(smoltcp::wire::Ipv4Packet::new_checked(&packet[..]).unwrap().verify_checksum());
+        let parsed = ParsedPacket::parse(&packet).unwrap();
+        assert_eq!(parsed.src_addr(), Some(src));
+        assert_eq!(parsed.dst_addr(), Some(dst));
+        assert_eq!(parsed.udp_payload(&packet), Some(&b"hello"[..]));
+
+        let v6: SocketAddr = "[fd00::1]:53".parse().unwrap();
+        assert!(build_udp_packet(v6, dst, b"x").is_none());
+        let packet = build_udp_packet(v6, "[fd00::2]:5353".parse().unwrap(), b"x").unwrap();
+        assert_eq!(packet.len(), 40 + 8 + 1);
     }
 
     #[test]
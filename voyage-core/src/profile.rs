@@ -0,0 +1,994 @@
+//! Configuration Files
+//!
+//! This module loads a complete Voyage configuration (general settings, DNS,
+//! proxies, proxy groups, rules, rewrite rules, outbound socket options and
+//! logging) from YAML, TOML or JSON. serde does the parsing; a validation
+//! pass then checks rules and the references between sections, reporting the
+//! line of the offending entry.
+//!
+//! The core still talks to a single upstream, so rules naming a proxy or a
+//! group are loaded as PROXY rules, and `general.proxy` (or the first proxy)
+//! picks the server. Groups lead to the member selected at runtime, the
+//! first one until the host selects another.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::{FakeIpConfig, OutboundConfig, ProxyConfig};
+use crate::error::VoyageError;
+use crate::fakeip::Ipv4Range;
+use crate::hosts::HostEntry;
+use crate::logging::LogLevel;
+use crate::rewrite::{RewriteEngine, RewriteRule};
+use crate::rule::RuleEngine;
+use crate::secret::SecretString;
+
+/// Port assumed for DNS servers given without one
+const DNS_PORT: u16 = 53;
+
+/// Policies rules can name besides proxies and groups
+const BUILTIN_POLICIES: [&str; 3] = ["DIRECT", "PROXY", "REJECT"];
+
+/// Syntax of a configuration file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// Format of a file with extension `ext`
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        match ext.to_ascii_lowercase().as_str() {
+            "yaml" | "yml" => Some(Self::Yaml),
+            "toml" => Some(Self::Toml),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+
+    /// Guess the format of `text`: JSON starts with a brace, TOML with a
+    /// `[table]` header or `key = value`, anything else is YAML
+    pub fn detect(text: &str) -> Self {
+        let first = text
+            .lines()
+            .map(str::trim)
+            .find(|line| !line.is_empty() && !line.starts_with('#'));
+        match first {
+            Some(line) if line.starts_with('{') => Self::Json,
+            Some(line) if line.starts_with('[') => Self::Toml,
+            Some(line) if line.split_once('=').is_some_and(|(key, _)| !key.contains(':')) => {
+                Self::Toml
+            }
+            _ => Self::Yaml,
+        }
+    }
+}
+
+/// Settings not tied to a section
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct GeneralSettings {
+    /// Proxy or group that PROXY traffic goes through (first proxy if unset)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<String>,
+    /// IPv4 networks reached directly, kept clear of fake IPs
+    pub skip_proxy: Vec<String>,
+    /// MTU of the virtual interface
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mtu: Option<usize>,
+    /// HTML answered to rejected plain HTTP requests, with `{host}` and
+    /// `{rule}` placeholders
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reject_page: Option<String>,
+    /// Reject QUIC to proxied destinations so apps fall back to TCP
+    pub block_quic: bool,
+}
+
+/// Built-in DNS forwarder
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DnsSettings {
+    /// Upstreams for DIRECT names, as `ip` or `ip:port`
+    pub servers: Vec<String>,
+    /// Resolver queried through the proxy for PROXY names
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proxy_server: Option<String>,
+    /// Answer PROXY names with fake IPs
+    pub fake_ip: bool,
+    /// Range fake IPs are taken from
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fake_ip_range: Option<String>,
+    /// Static name to address mappings
+    pub hosts: BTreeMap<String, String>,
+    /// Domains of the local network, resolved and connected DIRECT
+    pub search_domains: Vec<String>,
+    /// Resolver for local names, as `ip` or `ip:port`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub local_server: Option<String>,
+}
+
+impl Default for DnsSettings {
+    fn default() -> Self {
+        Self {
+            servers: Vec::new(),
+            proxy_server: None,
+            fake_ip: true,
+            fake_ip_range: None,
+            hosts: BTreeMap::new(),
+            search_domains: Vec::new(),
+            local_server: None,
+        }
+    }
+}
+
+/// Protocol of a proxy server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyKind {
+    Socks5,
+}
+
+/// An upstream proxy server
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ProxyEntry {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: ProxyKind,
+    pub server: String,
+    pub port: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<SecretString>,
+}
+
+/// How a proxy group picks among its members
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum GroupKind {
+    /// The member chosen by the user, the first one by default
+    Select,
+    /// The member with the lowest latency
+    UrlTest,
+    /// The first member that is reachable
+    Fallback,
+}
+
+/// A named set of proxies rules can refer to
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ProxyGroupEntry {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub kind: GroupKind,
+    /// Proxies, groups or DIRECT
+    pub proxies: Vec<String>,
+    /// Abort the connections relayed through the previous member when the
+    /// selection changes, so apps reconnect through the new one
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub interrupt_existing_connections: bool,
+    /// Member selected at runtime; not saved
+    #[serde(skip)]
+    pub selected: Option<String>,
+}
+
+/// Socket options of direct and proxy connections
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct OutboundSettings {
+    /// Disable Nagle's algorithm
+    pub nodelay: bool,
+    /// Idle seconds before the first keep-alive probe; 0 turns keep-alive off
+    pub keepalive_idle: u64,
+    /// Seconds between unanswered keep-alive probes
+    pub keepalive_interval: u64,
+    /// Unanswered probes before the connection is dropped
+    pub keepalive_probes: u32,
+    /// TCP Fast Open, where the system supports it
+    pub fast_open: bool,
+    /// Seconds without traffic before a relayed connection is closed; 0
+    /// turns the timeout off
+    pub idle_timeout: u64,
+}
+
+impl OutboundSettings {
+    fn to_config(&self) -> OutboundConfig {
+        OutboundConfig {
+            nodelay: self.nodelay,
+            keepalive_idle_secs: self.keepalive_idle,
+            keepalive_interval_secs: self.keepalive_interval,
+            keepalive_probes: self.keepalive_probes,
+            fast_open: self.fast_open,
+            idle_timeout_secs: self.idle_timeout,
+        }
+    }
+}
+
+impl Default for OutboundSettings {
+    fn default() -> Self {
+        let config = OutboundConfig::default();
+        Self {
+            nodelay: config.nodelay,
+            keepalive_idle: config.keepalive_idle_secs,
+            keepalive_interval: config.keepalive_interval_secs,
+            keepalive_probes: config.keepalive_probes,
+            fast_open: config.fast_open,
+            idle_timeout: config.idle_timeout_secs,
+        }
+    }
+}
+
+/// Log output
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct LoggingSettings {
+    /// error, warn, info, debug or trace
+    pub level: String,
+}
+
+impl Default for LoggingSettings {
+    fn default() -> Self {
+        Self {
+            level: "info".into(),
+        }
+    }
+}
+
+/// What differs between two configurations, as found by `VoyageConfig::diff`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConfigDiff {
+    /// Routing rules, compared after mapping proxy and group names
+    pub rules: bool,
+    /// Proxies only the new configuration has
+    pub proxies_added: Vec<String>,
+    /// Proxies only the old configuration has
+    pub proxies_removed: Vec<String>,
+    /// Proxies whose server, port or credentials changed
+    pub proxies_changed: Vec<String>,
+    /// Proxy group definitions
+    pub groups: bool,
+    /// The server PROXY traffic goes through
+    pub upstream: bool,
+    /// DNS servers, hosts or fake-IP settings
+    pub dns: bool,
+    /// Networks in `general.skip-proxy`
+    pub skip_proxy: bool,
+    /// MTU of the virtual interface
+    pub mtu: bool,
+    /// Outbound socket options
+    pub outbound: bool,
+    /// Page answered to rejected HTTP requests
+    pub reject_page: bool,
+    /// QUIC blocking
+    pub block_quic: bool,
+    /// HTTP rewrite rules
+    pub rewrite: bool,
+    /// Log level
+    pub log_level: bool,
+}
+
+impl ConfigDiff {
+    /// Whether the configurations are equivalent
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Names of the settings that changed, as written in the file
+    pub fn sections(&self) -> Vec<&'static str> {
+        let proxies = !self.proxies_added.is_empty()
+            || !self.proxies_removed.is_empty()
+            || !self.proxies_changed.is_empty();
+        [
+            (self.rules, "rules"),
+            (proxies, "proxies"),
+            (self.groups, "proxy-groups"),
+            (self.upstream, "general.proxy"),
+            (self.dns, "dns"),
+            (self.skip_proxy, "general.skip-proxy"),
+            (self.mtu, "general.mtu"),
+            (self.outbound, "outbound"),
+            (self.reject_page, "general.reject-page"),
+            (self.block_quic, "general.block-quic"),
+            (self.rewrite, "rewrite"),
+            (self.log_level, "logging"),
+        ]
+        .into_iter()
+        .filter_map(|(changed, name)| changed.then_some(name))
+        .collect()
+    }
+}
+
+/// A complete configuration file
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct VoyageConfig {
+    pub general: GeneralSettings,
+    pub dns: DnsSettings,
+    pub proxies: Vec<ProxyEntry>,
+    pub proxy_groups: Vec<ProxyGroupEntry>,
+    /// Rules in the rule syntax; the policy may name a proxy or group
+    pub rules: Vec<String>,
+    /// URL-REWRITE and HEADER-REWRITE rules for HTTP requests
+    pub rewrite: Vec<String>,
+    pub outbound: OutboundSettings,
+    pub logging: LoggingSettings,
+}
+
+impl VoyageConfig {
+    /// Parse and validate `text` written in `format`
+    pub fn parse(text: &str, format: ConfigFormat) -> Result<Self, VoyageError> {
+        let config =
+            Self::deserialize(text, format).map_err(|e| syntax_error(e.line, e.detail))?;
+        config.validate(text)?;
+        Ok(config)
+    }
+
+    /// Parse `text` without validating it
+    pub(crate) fn deserialize(text: &str, format: ConfigFormat) -> Result<Self, SyntaxError> {
+        match format {
+            ConfigFormat::Yaml => serde_yaml::from_str(text).map_err(|e| SyntaxError {
+                line: e.location().map(|location| location.line()),
+                column: e.location().map(|location| location.column()),
+                detail: e.to_string(),
+            }),
+            ConfigFormat::Toml => toml::from_str(text).map_err(|e| SyntaxError {
+                line: e.span().map(|span| line_at(text, span.start)),
+                column: e.span().map(|span| column_at(text, span.start)),
+                detail: e.message().to_string(),
+            }),
+            ConfigFormat::Json => serde_json::from_str(text).map_err(|e| SyntaxError {
+                line: Some(e.line()),
+                column: Some(e.column()),
+                detail: e.to_string(),
+            }),
+        }
+    }
+
+    /// Parse `text` in the format `ConfigFormat::detect` guesses
+    pub fn parse_auto(text: &str) -> Result<Self, VoyageError> {
+        Self::parse(text, ConfigFormat::detect(text))
+    }
+
+    /// Check values and references between sections. `text` is the source
+    /// the config was parsed from, used to locate errors.
+    pub fn validate(&self, text: &str) -> Result<(), VoyageError> {
+        match self.problems().into_iter().next() {
+            Some(problem) => Err(error_at(text, &problem.needle, problem.detail)),
+            None => Ok(()),
+        }
+    }
+
+    /// Every error `validate` could report, in the order it checks them
+    pub(crate) fn problems(&self) -> Vec<Problem> {
+        let mut problems = Vec::new();
+        let mut problem = |needle: &str, detail: String| {
+            problems.push(Problem {
+                needle: needle.to_string(),
+                detail,
+            })
+        };
+
+        let mut names = HashSet::new();
+        for proxy in &self.proxies {
+            if proxy.name.is_empty() || proxy.server.is_empty() || proxy.port == 0 {
+                problem(
+                    &proxy.name,
+                    format!("Proxy {:?} needs a name, server and port", proxy.name),
+                );
+            }
+            if !names.insert(proxy.name.as_str()) || is_builtin(&proxy.name) {
+                problem(&proxy.name, duplicate_name(&proxy.name));
+            }
+        }
+        for group in &self.proxy_groups {
+            if !names.insert(group.name.as_str()) || is_builtin(&group.name) {
+                problem(&group.name, duplicate_name(&group.name));
+            }
+        }
+        for group in &self.proxy_groups {
+            if group.proxies.is_empty() {
+                problem(&group.name, format!("Proxy group {} has no members", group.name));
+            }
+            for member in &group.proxies {
+                if !names.contains(member.as_str()) && member != "DIRECT" {
+                    problem(member, format!("Unknown proxy: {}", member));
+                }
+            }
+        }
+        if let Some(proxy) = &self.general.proxy {
+            if !names.contains(proxy.as_str()) {
+                problem(proxy, format!("Unknown proxy: {}", proxy));
+            } else if self.resolve_proxy(proxy).is_none() {
+                problem(proxy, format!("Proxy group {} does not lead to a proxy", proxy));
+            }
+        }
+
+        for rule in &self.rules {
+            if let Err(e) = RuleEngine::parse_config(&self.translate_rule(rule)) {
+                let detail = match e {
+                    VoyageError::RuleSyntax { detail, .. } => detail,
+                    other => other.to_string(),
+                };
+                problem(rule, detail);
+            }
+        }
+        for rule in &self.rewrite {
+            if let Err(e) = RewriteRule::parse_line(rule) {
+                problem(rule, e);
+            }
+        }
+
+        for network in &self.general.skip_proxy {
+            if let Err(e) = network.parse::<Ipv4Range>() {
+                problem(network, e);
+            }
+        }
+        if let Some(range) = &self.dns.fake_ip_range {
+            if let Err(e) = range.parse::<Ipv4Range>() {
+                problem(range, e);
+            }
+        }
+        let servers = self.dns.servers.iter().chain(&self.dns.proxy_server);
+        for server in servers.chain(&self.dns.local_server) {
+            if let Err(e) = parse_dns_server(server) {
+                problem(server, e);
+            }
+        }
+        for (name, addr) in &self.dns.hosts {
+            if let Err(e) = addr.parse::<IpAddr>() {
+                problem(name, format!("Invalid IP for {}: {}", name, e));
+            }
+        }
+        let outbound = &self.outbound;
+        if outbound.keepalive_idle > 0 {
+            if outbound.keepalive_interval == 0 {
+                problem("keepalive-interval", "Keep-alive interval must be positive".into());
+            }
+            if outbound.keepalive_probes == 0 {
+                problem("keepalive-probes", "Keep-alive probes must be positive".into());
+            }
+        }
+        if let Err(e) = parse_log_level(&self.logging.level) {
+            problem(&self.logging.level, e);
+        }
+        problems
+    }
+
+    /// Write the configuration as YAML
+    pub fn to_yaml(&self) -> Result<String, VoyageError> {
+        serde_yaml::to_string(self).map_err(|e| VoyageError::config(e.to_string()))
+    }
+
+    /// Proxy server settings for the core
+    pub fn to_proxy_config(&self) -> Result<ProxyConfig, VoyageError> {
+        let mut config = ProxyConfig::default();
+
+        if let Some(proxy) = self.selected_proxy() {
+            config.server_host = proxy.server.clone();
+            config.server_port = proxy.port;
+            config.username = proxy.username.clone();
+            config.password = proxy.password.clone();
+        }
+
+        if !self.dns.servers.is_empty() {
+            config.dns.upstreams = self
+                .dns
+                .servers
+                .iter()
+                .map(|server| parse_dns_server(server))
+                .collect::<Result<_, _>>()
+                .map_err(VoyageError::config)?;
+        }
+        if let Some(server) = &self.dns.proxy_server {
+            config.dns.proxy_upstream = parse_dns_server(server).map_err(VoyageError::config)?;
+        }
+        config.dns.fake_ip = self.dns.fake_ip;
+        config.dns.search_domains = self.dns.search_domains.clone();
+        if let Some(server) = &self.dns.local_server {
+            config.dns.local_upstream =
+                Some(parse_dns_server(server).map_err(VoyageError::config)?);
+        }
+        for (name, addr) in &self.dns.hosts {
+            let addr = addr
+                .parse()
+                .map_err(|e| VoyageError::config(format!("Invalid IP for {}: {}", name, e)))?;
+            config.dns.hosts.push(HostEntry::new(name, addr));
+        }
+        if let Some(range) = &self.dns.fake_ip_range {
+            config.fake_ip = FakeIpConfig::new(range.parse().map_err(VoyageError::config)?);
+        }
+        if let Some(mtu) = self.general.mtu {
+            config.interface.mtu = mtu;
+        }
+        config.outbound = self.outbound.to_config();
+        if let Some(page) = &self.general.reject_page {
+            config.reject_page = page.clone();
+        }
+        config.block_quic = self.general.block_quic;
+        Ok(config)
+    }
+
+    /// Rules in the syntax `VoyageCore::load_rules` takes, with proxy and
+    /// group names replaced by PROXY
+    pub fn rules_text(&self) -> String {
+        self.rules
+            .iter()
+            .map(|rule| self.translate_rule(rule))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Rewrite rules for the inbound proxy
+    pub fn rewrite_engine(&self) -> Result<RewriteEngine, VoyageError> {
+        RewriteEngine::from_config(&self.rewrite.join("\n")).map_err(VoyageError::config)
+    }
+
+    /// Networks listed in `general.skip-proxy`
+    pub fn skip_proxy_networks(&self) -> Result<Vec<Ipv4Range>, VoyageError> {
+        self.general
+            .skip_proxy
+            .iter()
+            .map(|network| network.parse().map_err(VoyageError::config))
+            .collect()
+    }
+
+    /// Configured log level
+    pub fn log_level(&self) -> Result<LogLevel, VoyageError> {
+        parse_log_level(&self.logging.level).map_err(VoyageError::config)
+    }
+
+    /// What changed going from this configuration to `new`
+    pub fn diff(&self, new: &VoyageConfig) -> ConfigDiff {
+        let mut diff = ConfigDiff::default();
+        for proxy in &new.proxies {
+            match self.proxies.iter().find(|old| old.name == proxy.name) {
+                None => diff.proxies_added.push(proxy.name.clone()),
+                Some(old) if old != proxy => diff.proxies_changed.push(proxy.name.clone()),
+                Some(_) => {}
+            }
+        }
+        diff.proxies_removed = self
+            .proxies
+            .iter()
+            .filter(|old| !new.proxies.iter().any(|proxy| proxy.name == old.name))
+            .map(|old| old.name.clone())
+            .collect();
+
+        // Renaming the selected proxy is not a change of server
+        let endpoint = |proxy: Option<&ProxyEntry>| {
+            proxy.map(|proxy| {
+                (
+                    proxy.server.clone(),
+                    proxy.port,
+                    proxy.username.clone(),
+                    proxy.password.clone(),
+                )
+            })
+        };
+        diff.upstream = endpoint(self.selected_proxy()) != endpoint(new.selected_proxy());
+        diff.rules = self.rules_text() != new.rules_text();
+        diff.groups = self.proxy_groups != new.proxy_groups;
+        diff.dns = self.dns != new.dns;
+        diff.skip_proxy = self.general.skip_proxy != new.general.skip_proxy;
+        diff.mtu = self.general.mtu != new.general.mtu;
+        diff.reject_page = self.general.reject_page != new.general.reject_page;
+        diff.block_quic = self.general.block_quic != new.general.block_quic;
+        diff.rewrite = self.rewrite != new.rewrite;
+        diff.outbound = self.outbound != new.outbound;
+        diff.log_level = self.log_level().ok() != new.log_level().ok();
+        diff
+    }
+
+    /// Proxy PROXY traffic goes through: `general.proxy`, or the first proxy
+    fn selected_proxy(&self) -> Option<&ProxyEntry> {
+        match &self.general.proxy {
+            Some(name) => self.resolve_proxy(name),
+            None => self.proxies.first(),
+        }
+    }
+
+    /// Select `member` in the proxy group `group`, returning whether the
+    /// group interrupts existing connections on a change
+    pub fn select_proxy(&mut self, group: &str, member: &str) -> Result<bool, VoyageError> {
+        let entry = self
+            .proxy_groups
+            .iter_mut()
+            .find(|entry| entry.name == group)
+            .ok_or_else(|| VoyageError::config(format!("Unknown proxy group: {}", group)))?;
+        if !entry.proxies.iter().any(|proxy| proxy == member) {
+            return Err(VoyageError::config(format!(
+                "Proxy group {} has no member {}",
+                group, member
+            )));
+        }
+        entry.selected = Some(member.to_string());
+        Ok(entry.interrupt_existing_connections)
+    }
+
+    /// Proxy a proxy or group name leads to; groups lead to their selected
+    /// member, the first by default. `None` for DIRECT, unknown names and
+    /// cycles.
+    fn resolve_proxy(&self, name: &str) -> Option<&ProxyEntry> {
+        let mut name = name;
+        for _ in 0..=self.proxy_groups.len() {
+            if let Some(proxy) = self.proxies.iter().find(|proxy| proxy.name == name) {
+                return Some(proxy);
+            }
+            let group = self.proxy_groups.iter().find(|group| group.name == name)?;
+            name = group.selected.as_ref().or(group.proxies.first())?;
+        }
+        None
+    }
+
+    pub(crate) fn translate_rule(&self, rule: &str) -> String {
+        let mut parts: Vec<&str> = rule.split(',').map(str::trim).collect();
+        let policy_index = if parts[0].eq_ignore_ascii_case("FINAL") { 1 } else { 2 };
+        if let Some(policy) = parts.get_mut(policy_index) {
+            let named = self.proxies.iter().any(|proxy| proxy.name == *policy)
+                || self.proxy_groups.iter().any(|group| group.name == *policy);
+            if named {
+                *policy = "PROXY";
+            }
+        }
+        parts.join(", ")
+    }
+}
+
+pub(crate) fn is_builtin(name: &str) -> bool {
+    BUILTIN_POLICIES.iter().any(|builtin| builtin.eq_ignore_ascii_case(name))
+}
+
+pub(crate) fn parse_dns_server(server: &str) -> Result<SocketAddr, String> {
+    server
+        .parse()
+        .or_else(|_| server.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, DNS_PORT)))
+        .map_err(|_| format!("Invalid DNS server: {}", server))
+}
+
+pub(crate) fn parse_log_level(level: &str) -> Result<LogLevel, String> {
+    match level.to_ascii_lowercase().as_str() {
+        "error" => Ok(LogLevel::Error),
+        "warn" | "warning" => Ok(LogLevel::Warn),
+        "info" => Ok(LogLevel::Info),
+        "debug" => Ok(LogLevel::Debug),
+        "trace" => Ok(LogLevel::Trace),
+        _ => Err(format!("Unknown log level: {}", level)),
+    }
+}
+
+/// Replace `${NAME}` placeholders in configuration `text` with values from
+/// `variables`, so secrets kept elsewhere (the Keychain, say) need not be
+/// written into shared profiles. `$${` stands for a literal `${`. Lines
+/// are preserved, so errors in the result point at the source.
+pub fn substitute_variables(
+    text: &str,
+    variables: &HashMap<String, String>,
+) -> Result<String, VoyageError> {
+    substitute(text, variables).map_err(|e| syntax_error(e.line, e.detail))
+}
+
+pub(crate) fn substitute(
+    text: &str,
+    variables: &HashMap<String, String>,
+) -> Result<String, SyntaxError> {
+    let error_at_offset = |offset: usize, detail: String| SyntaxError {
+        line: Some(line_at(text, offset)),
+        column: Some(column_at(text, offset)),
+        detail,
+    };
+
+    let mut result = String::with_capacity(text.len());
+    let mut offset = 0;
+    while let Some(found) = text[offset..].find('$') {
+        let start = offset + found;
+        result.push_str(&text[offset..start]);
+        let rest = &text[start..];
+        if rest.starts_with("$${") {
+            result.push_str("${");
+            offset = start + 3;
+        } else if let Some(body) = rest.strip_prefix("${") {
+            let end = body
+                .find(['}', '\n'])
+                .filter(|&end| body[end..].starts_with('}'))
+                .ok_or_else(|| error_at_offset(start, "Unterminated variable".into()))?;
+            let name = &body[..end];
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(error_at_offset(start, format!("Invalid variable name: {:?}", name)));
+            }
+            let value = variables
+                .get(name)
+                .ok_or_else(|| error_at_offset(start, format!("Undefined variable: {}", name)))?;
+            if value.contains('\n') {
+                let detail = format!("Variable {} spans several lines", name);
+                return Err(error_at_offset(start, detail));
+            }
+            result.push_str(value);
+            offset = start + 2 + end + 1;
+        } else {
+            result.push('$');
+            offset = start + 1;
+        }
+    }
+    result.push_str(&text[offset..]);
+    Ok(result)
+}
+
+/// A deserialization error, located by 1-based line and column
+pub(crate) struct SyntaxError {
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub detail: String,
+}
+
+/// An error found by `VoyageConfig::problems`, located by the text it is about
+pub(crate) struct Problem {
+    pub needle: String,
+    pub detail: String,
+}
+
+/// 1-based line of byte `offset` in `text`
+fn line_at(text: &str, offset: usize) -> usize {
+    text[..offset.min(text.len())].matches('\n').count() + 1
+}
+
+/// 1-based column of byte `offset` in `text`
+fn column_at(text: &str, offset: usize) -> usize {
+    let before = &text[..offset.min(text.len())];
+    before.len() - before.rfind('\n').map_or(0, |newline| newline + 1) + 1
+}
+
+fn syntax_error(line: Option<usize>, detail: String) -> VoyageError {
+    match line {
+        Some(line) => VoyageError::config_syntax(line as u32, detail),
+        None => VoyageError::config(detail),
+    }
+}
+
+/// 1-based line of the first line of `text` mentioning `needle`
+pub(crate) fn line_of(text: &str, needle: &str) -> Option<usize> {
+    (!needle.is_empty())
+        .then(|| text.lines().position(|line| line.contains(needle)))
+        .flatten()
+        .map(|index| index + 1)
+}
+
+/// Error located at the first line of `text` mentioning `needle`
+fn error_at(text: &str, needle: &str, detail: String) -> VoyageError {
+    syntax_error(line_of(text, needle), detail)
+}
+
+fn duplicate_name(name: &str) -> String {
+    format!("Duplicate proxy or group name: {}", name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const YAML: &str = "\
+general:
+  proxy: Auto
+  skip-proxy: [192.168.0.0/16]
+dns:
+  servers: [1.1.1.1, '8.8.8.8:53']
+  hosts:
+    router.local: 192.168.1.1
+proxies:
+  - name: Tokyo
+    type: socks5
+    server: tokyo.example.com
+    port: 1080
+  - name: Osaka
+    type: socks5
+    server: osaka.example.com
+    port: 1081
+proxy-groups:
+  - name: Auto
+    type: url-test
+    proxies: [Osaka, Tokyo]
+rules:
+  - DOMAIN-SUFFIX, google.com, Auto
+  - DOMAIN, ads.example.com, REJECT
+  - FINAL, DIRECT
+logging:
+  level: debug
+";
+
+    #[test]
+    fn test_parse_yaml() {
+        let config = VoyageConfig::parse(YAML, ConfigFormat::Yaml).unwrap();
+        assert_eq!(config.proxies.len(), 2);
+        assert_eq!(config.proxy_groups[0].kind, GroupKind::UrlTest);
+        assert_eq!(config.log_level().unwrap(), LogLevel::Debug);
+
+        let proxy = config.to_proxy_config().unwrap();
+        assert_eq!((proxy.server_host.as_str(), proxy.server_port), ("osaka.example.com", 1081));
+        assert_eq!(proxy.dns.upstreams[0], "1.1.1.1:53".parse().unwrap());
+        assert_eq!(proxy.dns.hosts.len(), 1);
+        assert_eq!(
+            config.rules_text(),
+            "DOMAIN-SUFFIX, google.com, PROXY\nDOMAIN, ads.example.com, REJECT\nFINAL, DIRECT"
+        );
+    }
+
+    #[test]
+    fn test_parse_toml_and_json() {
+        let toml = "\
+[general]
+proxy = \"Home\"
+
+[[proxies]]
+name = \"Home\"
+type = \"socks5\"
+server = \"10.0.0.2\"
+port = 1080
+
+rules = []
+";
+        assert_eq!(ConfigFormat::detect(toml), ConfigFormat::Toml);
+        // `rules` after an array of tables belongs to the last proxy
+        let err = VoyageConfig::parse_auto(toml).unwrap_err();
+        assert!(matches!(err, VoyageError::ConfigSyntax { .. }), "{:?}", err);
+
+        let toml = toml.replace("rules = []\n", "");
+        let config = VoyageConfig::parse_auto(&toml).unwrap();
+        assert_eq!(config.to_proxy_config().unwrap().server_host, "10.0.0.2");
+
+        let json = r#"{
+  "proxies": [{"name": "A", "type": "socks5", "server": "a.example.com", "port": 1080}],
+  "rules": ["FINAL, A"]
+}"#;
+        assert_eq!(ConfigFormat::detect(json), ConfigFormat::Json);
+        let config = VoyageConfig::parse_auto(json).unwrap();
+        assert_eq!(config.rules_text(), "FINAL, PROXY");
+    }
+
+    #[test]
+    fn test_local_dns_settings() {
+        let yaml = "dns:\n  search-domains: [home.arpa]\n  local-server: 192.168.1.1\n";
+        let proxy = VoyageConfig::parse(yaml, ConfigFormat::Yaml)
+            .unwrap()
+            .to_proxy_config()
+            .unwrap();
+        assert!(proxy.dns.is_local_name("nas.home.arpa"));
+        assert_eq!(proxy.dns.local_upstream, Some("192.168.1.1:53".parse().unwrap()));
+
+        let bad = VoyageConfig::parse("dns:\n  local-server: router\n", ConfigFormat::Yaml);
+        assert!(bad.is_err());
+    }
+
+    #[test]
+    fn test_rewrite_section() {
+        let yaml = "rewrite:\n  - URL-REWRITE, http://a.test/*, https://a.test/$1, 302\n\
+                    \x20 - HEADER-REWRITE, *, DEL, Cookie\n";
+        let file = VoyageConfig::parse(yaml, ConfigFormat::Yaml).unwrap();
+        assert_eq!(file.rewrite_engine().unwrap().len(), 2);
+        let diff = VoyageConfig::default().diff(&file);
+        assert_eq!(diff.sections(), vec!["rewrite"]);
+
+        let bad = "rules: []\nrewrite:\n  - HEADER-REWRITE, *, SET, Accept\n";
+        let err = VoyageConfig::parse(bad, ConfigFormat::Yaml).unwrap_err();
+        assert!(matches!(err, VoyageError::ConfigSyntax { line: 3, .. }), "{:?}", err);
+    }
+
+    #[test]
+    fn test_errors_report_lines() {
+        let err = VoyageConfig::parse(&YAML.replace("port: 1081", "port: high"), ConfigFormat::Yaml)
+            .unwrap_err();
+        assert!(matches!(err, VoyageError::ConfigSyntax { line: 16, .. }), "{:?}", err);
+
+        let bad_rule = YAML.replace("DOMAIN, ads.example.com", "DOMAIN-ISH, ads.example.com");
+        let err = VoyageConfig::parse(&bad_rule, ConfigFormat::Yaml).unwrap_err();
+        assert!(matches!(err, VoyageError::ConfigSyntax { line: 23, .. }), "{:?}", err);
+
+        let unknown = YAML.replace("[Osaka, Tokyo]", "[Osaka, Kyoto]");
+        let err = VoyageConfig::parse(&unknown, ConfigFormat::Yaml).unwrap_err();
+        assert_eq!(err.to_string(), "Configuration error on line 20: Unknown proxy: Kyoto");
+
+        let json = "{\n  \"rules\": [\n    \"FINAL\"\n  ],\n  \"dns\": 1\n}";
+        let err = VoyageConfig::parse(json, ConfigFormat::Json).unwrap_err();
+        assert!(matches!(err, VoyageError::ConfigSyntax { line: 5, .. }), "{:?}", err);
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = VoyageConfig::parse(YAML, ConfigFormat::Yaml).unwrap();
+        assert!(old.diff(&old).is_empty());
+
+        // Reordering the group changes the server PROXY traffic uses
+        let new = YAML
+            .replace("[Osaka, Tokyo]", "[Tokyo, Osaka]")
+            .replace("port: 1081", "port: 1082")
+            .replace("level: debug", "level: info");
+        let diff = old.diff(&VoyageConfig::parse(&new, ConfigFormat::Yaml).unwrap());
+        assert!(diff.upstream && diff.groups && diff.log_level);
+        assert_eq!(diff.proxies_changed, vec!["Osaka"]);
+        assert!(!diff.rules && !diff.dns);
+        assert_eq!(
+            diff.sections(),
+            vec!["proxies", "proxy-groups", "general.proxy", "logging"]
+        );
+
+        // A group renamed in both places routes the same way
+        let new = YAML.replace("Auto", "Fast").replace("Tokyo", "Tokio");
+        let diff = old.diff(&VoyageConfig::parse(&new, ConfigFormat::Yaml).unwrap());
+        assert!(!diff.rules && !diff.upstream);
+        assert_eq!(diff.proxies_added, vec!["Tokio"]);
+        assert_eq!(diff.proxies_removed, vec!["Tokyo"]);
+    }
+
+    #[test]
+    fn test_outbound_settings() {
+        let old = VoyageConfig::parse(YAML, ConfigFormat::Yaml).unwrap();
+        assert_eq!(old.to_proxy_config().unwrap().outbound, OutboundConfig::default());
+
+        let text = format!("{}outbound:\n  keepalive-idle: 30\n  fast-open: true\n", YAML);
+        let new = VoyageConfig::parse(&text, ConfigFormat::Yaml).unwrap();
+        let outbound = new.to_proxy_config().unwrap().outbound;
+        assert_eq!(outbound.keepalive_idle_secs, 30);
+        assert!(outbound.fast_open && outbound.nodelay);
+        assert_eq!(old.diff(&new).sections(), vec!["outbound"]);
+
+        let text = format!("{}outbound:\n  keepalive-probes: 0\n", YAML);
+        let err = VoyageConfig::parse(&text, ConfigFormat::Yaml).unwrap_err();
+        assert!(matches!(err, VoyageError::ConfigSyntax { .. }), "{:?}", err);
+    }
+
+    #[test]
+    fn test_substitute_variables() {
+        let variables = HashMap::from([
+            ("PASSWORD".to_string(), "s3cr$t".to_string()),
+            ("PORT".to_string(), "1080".to_string()),
+        ]);
+        let text = "\
+proxies:
+  - {name: Home, type: socks5, server: 10.0.0.2, port: ${PORT}, username: me, password: '${PASSWORD}'}
+rules: ['DOMAIN, price$${x}.example.com, DIRECT']
+";
+        let expanded = substitute_variables(text, &variables).unwrap();
+        let config = VoyageConfig::parse_auto(&expanded).unwrap();
+        assert_eq!(config.proxies[0].port, 1080);
+        assert_eq!(config.proxies[0].password, Some("s3cr$t".into()));
+        assert_eq!(config.rules, vec!["DOMAIN, price${x}.example.com, DIRECT"]);
+
+        let err = substitute_variables("rules: []\nlogging: {level: ${LEVEL}}\n", &variables)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Configuration error on line 2: Undefined variable: LEVEL");
+        for bad in ["${}", "${A-B}", "${OPEN\n}"] {
+            assert!(substitute_variables(bad, &variables).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_group_cycles() {
+        let config = VoyageConfig {
+            proxy_groups: vec![
+                ProxyGroupEntry {
+                    name: "A".into(),
+                    kind: GroupKind::Select,
+                    proxies: vec!["B".into()],
+                    interrupt_existing_connections: false,
+                    selected: None,
+                },
+                ProxyGroupEntry {
+                    name: "B".into(),
+                    kind: GroupKind::Select,
+                    proxies: vec!["A".into()],
+                    interrupt_existing_connections: false,
+                    selected: None,
+                },
+            ],
+            general: GeneralSettings {
+                proxy: Some("A".into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(config.validate("").is_err());
+    }
+}
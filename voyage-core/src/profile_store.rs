@@ -0,0 +1,194 @@
+//! Named Profiles
+//!
+//! This module keeps several named configurations (say "Home", "Work" and
+//! "Travel") so the running core can switch between them without being
+//! re-initialized. `VoyageCore::switch_profile` applies a profile through
+//! `reload_config`, so only what differs from the active profile changes.
+//!
+//! Routing counters are kept per profile: traffic routed while a profile is
+//! active is credited to it.
+
+use std::collections::BTreeMap;
+
+use crate::error::VoyageError;
+use crate::profile::VoyageConfig;
+use crate::proxy::ProxyStats;
+
+/// A stored profile and the traffic routed while it was active
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProfileInfo {
+    pub name: String,
+    /// Whether this is the profile the core is running
+    pub active: bool,
+    /// Times the profile was switched to
+    pub activations: u64,
+    /// Connections routed directly while the profile was active
+    pub direct_connections: u64,
+    /// Connections routed through the proxy while the profile was active
+    pub proxied_connections: u64,
+    /// Connections rejected while the profile was active
+    pub rejected_connections: u64,
+}
+
+#[derive(Debug, Clone)]
+struct StoredProfile {
+    config: VoyageConfig,
+    info: ProfileInfo,
+}
+
+/// Named configurations and which one is active
+#[derive(Debug, Default)]
+pub struct ProfileManager {
+    profiles: BTreeMap<String, StoredProfile>,
+    active: Option<String>,
+    /// Routing counters when the active profile was switched to
+    baseline: ProxyStats,
+}
+
+impl ProfileManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store `config` under `name`, replacing a profile of that name but
+    /// keeping its counters. A replaced active profile takes effect on the
+    /// next switch to it.
+    pub fn insert(&mut self, name: &str, config: VoyageConfig) -> Result<(), VoyageError> {
+        if name.trim().is_empty() {
+            return Err(VoyageError::config("Profile name must not be empty".into()));
+        }
+        config.validate("")?;
+        let info = match self.profiles.remove(name) {
+            Some(existing) => existing.info,
+            None => ProfileInfo {
+                name: name.to_string(),
+                ..Default::default()
+            },
+        };
+        self.profiles.insert(name.to_string(), StoredProfile { config, info });
+        Ok(())
+    }
+
+    /// Forget a profile; the active one cannot be removed
+    pub fn remove(&mut self, name: &str) -> Result<(), VoyageError> {
+        if self.active.as_deref() == Some(name) {
+            return Err(VoyageError::config(format!(
+                "Profile {} is active and cannot be removed",
+                name
+            )));
+        }
+        self.profiles
+            .remove(name)
+            .map(|_| ())
+            .ok_or_else(|| unknown_profile(name))
+    }
+
+    /// Configuration stored under `name`
+    pub fn get(&self, name: &str) -> Result<&VoyageConfig, VoyageError> {
+        self.profiles
+            .get(name)
+            .map(|profile| &profile.config)
+            .ok_or_else(|| unknown_profile(name))
+    }
+
+    /// Name of the active profile, if the core runs one
+    pub fn active(&self) -> Option<&str> {
+        self.active.as_deref()
+    }
+
+    /// Make `name` the active profile once it has been applied. `current`
+    /// are the routing counters at the switch; what was routed since the
+    /// last switch goes to the profile being left.
+    pub fn activate(&mut self, name: &str, current: &ProxyStats) -> Result<(), VoyageError> {
+        if !self.profiles.contains_key(name) {
+            return Err(unknown_profile(name));
+        }
+        self.settle(current);
+        self.active = Some(name.to_string());
+        if let Some(profile) = self.profiles.get_mut(name) {
+            profile.info.activations += 1;
+        }
+        Ok(())
+    }
+
+    /// All profiles sorted by name, with the active one's counters
+    /// brought up to `current`
+    pub fn list(&self, current: &ProxyStats) -> Vec<ProfileInfo> {
+        self.profiles
+            .values()
+            .map(|profile| {
+                let mut info = profile.info.clone();
+                if self.active.as_deref() == Some(info.name.as_str()) {
+                    info.active = true;
+                    add_delta(&mut info, &self.baseline, current);
+                }
+                info
+            })
+            .collect()
+    }
+
+    /// Credit the traffic routed since the last switch to the active profile
+    fn settle(&mut self, current: &ProxyStats) {
+        if let Some(profile) = self
+            .active
+            .as_ref()
+            .and_then(|name| self.profiles.get_mut(name))
+        {
+            add_delta(&mut profile.info, &self.baseline, current);
+        }
+        self.baseline = current.clone();
+    }
+}
+
+fn add_delta(info: &mut ProfileInfo, baseline: &ProxyStats, current: &ProxyStats) {
+    info.direct_connections += current
+        .direct_connections
+        .saturating_sub(baseline.direct_connections);
+    info.proxied_connections += current
+        .proxied_connections
+        .saturating_sub(baseline.proxied_connections);
+    info.rejected_connections += current
+        .rejected_connections
+        .saturating_sub(baseline.rejected_connections);
+}
+
+fn unknown_profile(name: &str) -> VoyageError {
+    VoyageError::config(format!("Unknown profile: {}", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(direct: u64, proxied: u64) -> ProxyStats {
+        ProxyStats {
+            direct_connections: direct,
+            proxied_connections: proxied,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_profile_counters() {
+        let mut profiles = ProfileManager::new();
+        profiles.insert("Home", VoyageConfig::default()).unwrap();
+        profiles.insert("Work", VoyageConfig::default()).unwrap();
+        assert!(profiles.insert(" ", VoyageConfig::default()).is_err());
+        assert!(profiles.activate("Travel", &stats(0, 0)).is_err());
+
+        profiles.activate("Home", &stats(5, 5)).unwrap();
+        profiles.activate("Work", &stats(7, 6)).unwrap();
+        profiles.activate("Home", &stats(7, 10)).unwrap();
+
+        let list = profiles.list(&stats(8, 10));
+        assert_eq!(list[0].name, "Home");
+        assert!(list[0].active && !list[1].active);
+        assert_eq!(list[0].activations, 2);
+        assert_eq!((list[0].direct_connections, list[0].proxied_connections), (3, 1));
+        assert_eq!((list[1].direct_connections, list[1].proxied_connections), (0, 4));
+
+        assert!(profiles.remove("Home").is_err());
+        profiles.remove("Work").unwrap();
+        assert!(profiles.get("Work").is_err());
+    }
+}
@@ -3,14 +3,20 @@
 //! This module provides the proxy management layer that coordinates
 //! routing decisions and proxy connections.
 
+use std::collections::{HashMap, HashSet};
 use std::net::IpAddr;
 use std::sync::Arc;
+use std::time::Instant;
 
 use tokio::sync::Mutex;
 
-use crate::config::ProxyConfig;
+use crate::config::{ProxyConfig, DEFAULT_DOMAIN_MAP_SIZE};
+use crate::dns::{DnsMessage, DomainMap};
 use crate::error::VoyageError;
-use crate::rule::{FfiRouteAction, RouteAction, RuleEngine};
+use crate::geoip::GeoIpDb;
+use crate::geosite::GeoSiteDb;
+use crate::rule::{FfiRouteAction, MatchContext, RouteAction, Rule, RuleEngine, RuleType};
+use crate::secret::SecretString;
 
 /// Connection routing decision with metadata
 #[derive(Debug, Clone)]
@@ -25,6 +31,10 @@ pub struct RoutingDecision {
     pub dst_port: u16,
     /// Rule that matched (if any)
     pub matched_rule: Option<String>,
+    /// Disable Nagle and delayed ACKs on the flow's socket
+    pub nodelay: bool,
+    /// Bandwidth cap of the matched rule, in bytes per second
+    pub rate_limit: Option<u64>,
 }
 
 impl RoutingDecision {
@@ -36,6 +46,8 @@ impl RoutingDecision {
             dst_ip: None,
             dst_port,
             matched_rule: None,
+            nodelay: false,
+            rate_limit: None,
         }
     }
 
@@ -47,6 +59,8 @@ impl RoutingDecision {
             dst_ip: None,
             dst_port,
             matched_rule: None,
+            nodelay: false,
+            rate_limit: None,
         }
     }
 
@@ -58,6 +72,8 @@ impl RoutingDecision {
             dst_ip: None,
             dst_port,
             matched_rule: None,
+            nodelay: false,
+            rate_limit: None,
         }
     }
 
@@ -78,6 +94,19 @@ impl RoutingDecision {
         self.matched_rule = Some(rule.into());
         self
     }
+
+    /// Host the flow goes to: its name when known, its address otherwise
+    pub fn destination_host(&self) -> Option<String> {
+        self.domain
+            .clone()
+            .or_else(|| self.dst_ip.map(|ip| ip.to_string()))
+    }
+
+    /// Mark the flow as latency-sensitive
+    pub fn with_nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
 }
 
 /// Proxy statistics
@@ -93,6 +122,198 @@ pub struct ProxyStats {
     pub proxy_bytes_sent: u64,
     /// Total bytes received through proxy
     pub proxy_bytes_received: u64,
+    /// Connections that waited for a concurrency slot
+    pub queued_connections: u64,
+    /// Connections rejected by a concurrency cap, at once or after
+    /// waiting too long
+    pub limit_rejected_connections: u64,
+    /// Routes answered from the decision cache
+    pub route_cache_hits: u64,
+    /// Routes that had to run through the rules
+    pub route_cache_misses: u64,
+    /// Flows sent DIRECT because proxying them would loop back into the
+    /// tunnel
+    pub routing_loops: u64,
+    /// Flows the routing script sent elsewhere than the rules did
+    pub script_overrides: u64,
+    /// Proxied QUIC flows rejected so the app falls back to TCP
+    pub quic_blocked: u64,
+}
+
+/// Rule name recorded on flows sent DIRECT to break a routing loop
+pub const ROUTING_LOOP_RULE: &str = "routing loop";
+
+/// Rule name recorded on local-network names sent DIRECT in place of the
+/// FINAL rule
+pub const LOCAL_NAME_RULE: &str = "local name";
+
+/// Rule name recorded on flows whose policy the routing script picked
+pub const SCRIPT_RULE: &str = "script";
+
+/// Rule name recorded on QUIC flows rejected by `ProxyConfig::block_quic`
+pub const BLOCK_QUIC_RULE: &str = "block-quic";
+
+/// Custom routing logic run after the rules on every flow they route. It
+/// sees the rules' decision and what else is known of the flow, and returns
+/// the policy to use instead, or `None` to keep it. Called with the core
+/// locked, so it must be quick and must not call back into the core.
+pub type RouteScript =
+    Arc<dyn Fn(&RoutingDecision, &FlowFacts) -> Option<RouteAction> + Send + Sync>;
+
+/// What a routing script knows of a flow beyond the rules' decision
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FlowFacts<'a> {
+    /// App that opened the flow, if the host attributed it already
+    pub process: Option<&'a str>,
+    /// Country code of the destination address, from the GeoIP database
+    pub country: Option<&'a str>,
+}
+
+/// Destinations whose rule match is remembered
+const ROUTE_CACHE_SIZE: usize = 1024;
+
+/// What the rules picked for a destination
+#[derive(Debug, Clone)]
+struct RuleMatch {
+    action: RouteAction,
+    nodelay: bool,
+    rate_limit: Option<u64>,
+    matched_rule: Option<String>,
+}
+
+impl RuleMatch {
+    fn into_decision(
+        self,
+        domain: Option<String>,
+        dst_ip: Option<IpAddr>,
+        dst_port: u16,
+    ) -> RoutingDecision {
+        RoutingDecision {
+            action: self.action,
+            domain,
+            dst_ip,
+            dst_port,
+            matched_rule: self.matched_rule,
+            nodelay: self.nodelay,
+            rate_limit: self.rate_limit,
+        }
+    }
+}
+
+/// Cache key: domain, destination address and port, and the source
+/// address and port when SRC-IP-CIDR and SRC-PORT rules make them matter
+/// (`None` and 0 otherwise)
+type RouteKey = (Option<String>, Option<IpAddr>, u16, Option<IpAddr>, u16);
+
+/// LRU cache of rule matches for chatty apps reconnecting to the same
+/// hosts, emptied whenever the rules change
+#[derive(Debug)]
+struct RouteCache {
+    /// Match and LRU clock value of the last access
+    entries: HashMap<RouteKey, (RuleMatch, u64)>,
+    capacity: usize,
+    clock: u64,
+    /// Some rule matches on the source port
+    by_src_port: bool,
+    /// Some rule matches on the source address
+    by_src_ip: bool,
+}
+
+impl RouteCache {
+    fn new(capacity: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            capacity,
+            clock: 0,
+            by_src_port: false,
+            by_src_ip: false,
+        }
+    }
+
+    /// Forget every match made under the old `rules`
+    fn invalidate(&mut self, rules: &RuleEngine) {
+        self.entries.clear();
+        self.by_src_port = rules
+            .rules()
+            .iter()
+            .any(|rule| matches!(rule.rule_type, RuleType::SrcPort(_)));
+        self.by_src_ip = rules
+            .rules()
+            .iter()
+            .any(|rule| matches!(rule.rule_type, RuleType::SrcIpCidr(..)));
+    }
+
+    fn key(&self, domain: Option<String>, ctx: &MatchContext) -> RouteKey {
+        let src_port = if self.by_src_port { ctx.src_port } else { 0 };
+        let src_ip = ctx.src_ip.filter(|_| self.by_src_ip);
+        (domain, ctx.dst_ip, ctx.dst_port, src_ip, src_port)
+    }
+
+    fn get(&mut self, key: &RouteKey) -> Option<RuleMatch> {
+        self.clock += 1;
+        let (rule_match, last_used) = self.entries.get_mut(key)?;
+        *last_used = self.clock;
+        Some(rule_match.clone())
+    }
+
+    fn insert(&mut self, key: RouteKey, rule_match: RuleMatch) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() >= self.capacity {
+            let lru = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, last_used))| *last_used)
+                .map(|(key, _)| key.clone());
+            if let Some(key) = lru {
+                self.entries.remove(&key);
+            }
+        }
+        self.entries.insert(key, (rule_match, self.clock));
+    }
+}
+
+/// Maximum number of divergence samples kept for the comparison report
+const MAX_DIVERGENCE_SAMPLES: usize = 64;
+
+/// A flow the candidate ruleset would have routed differently
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteDivergence {
+    /// Domain name if known
+    pub domain: Option<String>,
+    /// Destination IP
+    pub dst_ip: Option<IpAddr>,
+    /// Destination port
+    pub dst_port: u16,
+    /// Action taken by the active ruleset
+    pub active: RouteAction,
+    /// Action the candidate ruleset would have taken
+    pub candidate: RouteAction,
+}
+
+/// Summary of an A/B comparison between the active and candidate rulesets
+#[derive(Debug, Clone, Default)]
+pub struct RouteComparison {
+    /// Flows evaluated against both rulesets
+    pub evaluated: u64,
+    /// Flows where the two rulesets disagreed
+    pub diverged: u64,
+    /// Most recent divergences, oldest first
+    pub samples: Vec<RouteDivergence>,
+}
+
+impl RouteComparison {
+    fn record(&mut self, divergence: Option<RouteDivergence>) {
+        self.evaluated += 1;
+        if let Some(divergence) = divergence {
+            self.diverged += 1;
+            if self.samples.len() == MAX_DIVERGENCE_SAMPLES {
+                self.samples.remove(0);
+            }
+            self.samples.push(divergence);
+        }
+    }
 }
 
 /// Manages proxy configurations and routing decisions
@@ -105,6 +326,20 @@ pub struct ProxyManager {
     stats: ProxyStats,
     /// Whether proxy is enabled
     enabled: bool,
+    /// Candidate ruleset evaluated alongside the active one (diagnostics only)
+    candidate_engine: Option<RuleEngine>,
+    /// Running A/B comparison results
+    comparison: RouteComparison,
+    /// Names that resolved to each address, for IP-only connections
+    domain_map: DomainMap,
+    /// Recent rule matches
+    route_cache: RouteCache,
+    /// Local ports of the core's own outbound sockets
+    own_ports: HashSet<u16>,
+    /// Custom logic that may override the rules
+    script: Option<RouteScript>,
+    /// Countries of destination addresses
+    geoip: Option<Arc<GeoIpDb>>,
 }
 
 impl ProxyManager {
@@ -115,22 +350,44 @@ impl ProxyManager {
             rule_engine: RuleEngine::new(),
             stats: ProxyStats::default(),
             enabled: false,
+            candidate_engine: None,
+            comparison: RouteComparison::default(),
+            domain_map: DomainMap::new(DEFAULT_DOMAIN_MAP_SIZE),
+            route_cache: RouteCache::new(ROUTE_CACHE_SIZE),
+            own_ports: HashSet::new(),
+            script: None,
+            geoip: None,
         }
     }
 
     /// Create a new proxy manager with configuration
     pub fn with_config(config: ProxyConfig) -> Self {
         Self {
+            domain_map: DomainMap::new(config.dns.domain_map_size),
             config: Some(config),
             rule_engine: RuleEngine::new(),
             stats: ProxyStats::default(),
             enabled: true,
+            candidate_engine: None,
+            comparison: RouteComparison::default(),
+            route_cache: RouteCache::new(ROUTE_CACHE_SIZE),
+            own_ports: HashSet::new(),
+            script: None,
+            geoip: None,
         }
     }
 
+    /// Run `script` on every flow routed by the rules, replacing any
+    /// previous script; `None` removes it
+    pub fn set_script(&mut self, script: Option<RouteScript>) {
+        self.script = script;
+    }
+
     /// Set the proxy configuration
     pub fn set_config(&mut self, config: ProxyConfig) {
         self.config = Some(config);
+        // The search domains may have changed
+        self.route_cache.invalidate(&self.rule_engine);
     }
 
     /// Get the proxy configuration
@@ -155,14 +412,48 @@ impl ProxyManager {
 
     /// Load rules from configuration string
     pub fn load_rules(&mut self, config: &str) -> Result<usize, VoyageError> {
-        self.rule_engine
-            .load_from_config(config)
-            .map_err(|e| VoyageError::ConfigError(e))
+        Ok(self.add_rules(RuleEngine::parse_config(config)?))
+    }
+
+    /// Add already parsed rules, returning how many were added
+    pub fn add_rules(&mut self, rules: Vec<Rule>) -> usize {
+        let count = rules.len();
+        self.rule_engine.add_rules(rules);
+        self.route_cache.invalidate(&self.rule_engine);
+        count
+    }
+
+    /// Replace the rules in one step, returning how many are active
+    pub fn replace_rules(&mut self, rules: Vec<Rule>) -> usize {
+        self.rule_engine.clear();
+        self.add_rules(rules)
+    }
+
+    /// Look destination countries up in `db`; `None` forgets them
+    pub fn set_geoip(&mut self, db: Option<Arc<GeoIpDb>>) {
+        self.geoip = db;
+    }
+
+    /// Country code of `ip`, if a GeoIP database is loaded and lists it
+    pub fn country(&self, ip: IpAddr) -> Option<&str> {
+        self.geoip.as_ref()?.country(ip)
+    }
+
+    /// Match GEOSITE rules of the active and candidate rulesets against
+    /// `db`, returning the categories it lacks
+    pub fn set_geosite(&mut self, db: Option<Arc<GeoSiteDb>>) -> Vec<String> {
+        if let Some(candidate) = self.candidate_engine.as_mut() {
+            candidate.set_geosite(db.clone());
+        }
+        let missing = self.rule_engine.set_geosite(db);
+        self.route_cache.invalidate(&self.rule_engine);
+        missing
     }
 
     /// Clear all rules
     pub fn clear_rules(&mut self) {
         self.rule_engine.clear();
+        self.route_cache.invalidate(&self.rule_engine);
     }
 
     /// Get the number of rules
@@ -170,50 +461,325 @@ impl ProxyManager {
         self.rule_engine.len()
     }
 
-    /// Evaluate routing for a connection
-    pub fn evaluate_route(
-        &mut self,
-        domain: Option<&str>,
-        dst_ip: Option<IpAddr>,
-        dst_port: u16,
-        src_port: u16,
-    ) -> RoutingDecision {
-        let action = if self.is_enabled() {
-            self.rule_engine.evaluate(domain, dst_ip, dst_port, src_port)
-        } else {
-            RouteAction::Direct
-        };
+    /// Load a candidate ruleset to compare against the active one.
+    ///
+    /// Every evaluated flow is also run through the candidate rules and
+    /// divergences are logged; routing behavior is unchanged.
+    pub fn load_candidate_rules(&mut self, config: &str) -> Result<usize, VoyageError> {
+        let mut engine = RuleEngine::new();
+        engine.set_geosite(self.rule_engine.geosite().cloned());
+        let rules = RuleEngine::parse_config(config)?;
+        let count = rules.len();
+        engine.add_rules(rules);
+        self.candidate_engine = Some(engine);
+        self.comparison = RouteComparison::default();
+        Ok(count)
+    }
+
+    /// Stop comparing against the candidate ruleset
+    pub fn clear_candidate_rules(&mut self) {
+        self.candidate_engine = None;
+    }
+
+    /// Check if a candidate ruleset is loaded
+    pub fn has_candidate_rules(&self) -> bool {
+        self.candidate_engine.is_some()
+    }
+
+    /// Get the A/B comparison summary
+    pub fn route_comparison(&self) -> &RouteComparison {
+        &self.comparison
+    }
+
+    /// Remember the addresses a DNS answer resolved to
+    pub fn record_dns_answer(&mut self, response: &DnsMessage) {
+        self.domain_map.record(response, Instant::now());
+    }
+
+    /// Name that most recently resolved to `ip`
+    pub fn domain_for_ip(&self, ip: IpAddr) -> Option<&str> {
+        self.domain_map.lookup(ip, Instant::now())
+    }
+
+    /// Evaluate routing for a connection.
+    ///
+    /// Connections that arrive without a domain are matched by the name
+    /// their destination IP was last resolved from, if any.
+    pub fn evaluate_route(&mut self, ctx: &MatchContext) -> RoutingDecision {
+        let resolved = self.resolved(ctx);
+        if let Some(reason) = self.routing_loop(&resolved) {
+            let decision = loop_decision(&resolved);
+            log::warn!(
+                "Sending {}:{} DIRECT: {}",
+                decision.destination_host().unwrap_or_default(),
+                ctx.dst_port,
+                reason
+            );
+            self.stats.routing_loops += 1;
+            self.stats.direct_connections += 1;
+            return decision;
+        }
+
+        let mut decision = self.cached_route(ctx);
+        if self.apply_script(&mut decision, ctx) {
+            self.stats.script_overrides += 1;
+        }
+        let domain = decision.domain.as_deref();
+        let action = &decision.action;
 
         // Update stats
-        match &action {
+        match action {
             RouteAction::Direct => self.stats.direct_connections += 1,
             RouteAction::Proxy => self.stats.proxied_connections += 1,
             RouteAction::Reject => self.stats.rejected_connections += 1,
         }
 
-        let decision = RoutingDecision {
+        if let (true, Some(candidate_engine)) = (self.is_enabled(), &self.candidate_engine) {
+            let candidate = candidate_engine.evaluate(&ctx.with_domain(domain));
+            let divergence = (candidate != *action).then(|| {
+                log::info!(
+                    "Route divergence for {} ({:?}:{}): active={:?} candidate={:?}",
+                    domain.unwrap_or("-"),
+                    ctx.dst_ip,
+                    ctx.dst_port,
+                    action,
+                    candidate
+                );
+                RouteDivergence {
+                    domain: domain.map(String::from),
+                    dst_ip: ctx.dst_ip,
+                    dst_port: ctx.dst_port,
+                    active: action.clone(),
+                    candidate,
+                }
+            });
+            self.comparison.record(divergence);
+        }
+
+        decision
+    }
+
+    /// Route a connection already counted by `evaluate_route` again, e.g.
+    /// once its hostname is known, leaving the stats and the A/B comparison
+    /// as they are
+    pub fn reroute(&self, ctx: &MatchContext) -> RoutingDecision {
+        let mut decision = self.peek_route(ctx);
+        if decision.matched_rule.as_deref() != Some(ROUTING_LOOP_RULE) {
+            self.apply_script(&mut decision, ctx);
+        }
+        decision
+    }
+
+    /// Let the routing script override the rules' action; true if it did
+    fn apply_script(&self, decision: &mut RoutingDecision, ctx: &MatchContext) -> bool {
+        // Flows the proxy is off for are not routed by anything
+        let Some(script) = self.script.as_ref().filter(|_| self.is_enabled()) else {
+            return false;
+        };
+        let facts = FlowFacts {
+            process: ctx.process,
+            country: decision.dst_ip.and_then(|ip| self.country(ip)),
+        };
+        let Some(action) = script(decision, &facts).filter(|action| *action != decision.action)
+        else {
+            return false;
+        };
+        log::debug!(
+            "Script routed {}:{} {:?} instead of {:?}",
+            decision.destination_host().unwrap_or_default(),
+            decision.dst_port,
             action,
-            domain: domain.map(String::from),
-            dst_ip,
-            dst_port,
-            matched_rule: None,
+            decision.action
+        );
+        decision.action = action;
+        decision.matched_rule = Some(SCRIPT_RULE.into());
+        true
+    }
+
+    /// Routing decision for a connection, without counting it in stats or
+    /// the A/B comparison
+    pub fn peek_route(&self, ctx: &MatchContext) -> RoutingDecision {
+        let ctx = self.resolved(ctx);
+        if self.routing_loop(&ctx).is_some() {
+            return loop_decision(&ctx);
+        }
+        self.match_rules(&ctx).into_decision(
+            ctx.domain.map(String::from),
+            ctx.dst_ip,
+            ctx.dst_port,
+        )
+    }
+
+    /// `ctx` with the name its destination IP was last resolved from when
+    /// it has no domain
+    fn resolved<'a>(&'a self, ctx: &MatchContext<'a>) -> MatchContext<'a> {
+        ctx.with_domain(
+            ctx.domain
+                .or_else(|| ctx.dst_ip.and_then(|ip| self.domain_for_ip(ip))),
+        )
+    }
+
+    /// `peek_route`, answered from the decision cache when the destination
+    /// was routed recently
+    fn cached_route(&mut self, ctx: &MatchContext) -> RoutingDecision {
+        if !self.is_enabled() {
+            return self.peek_route(ctx);
+        }
+        let domain = self.resolved(ctx).domain.map(String::from);
+        let key = self.route_cache.key(domain.clone(), ctx);
+        let rule_match = match self.route_cache.get(&key) {
+            Some(rule_match) => {
+                self.stats.route_cache_hits += 1;
+                rule_match
+            }
+            None => {
+                self.stats.route_cache_misses += 1;
+                let rule_match = self.match_rules(&ctx.with_domain(domain.as_deref()));
+                self.route_cache.insert(key, rule_match.clone());
+                rule_match
+            }
         };
+        rule_match.into_decision(domain, ctx.dst_ip, ctx.dst_port)
+    }
 
-        decision
+    /// Why proxying a flow would send it back into the tunnel, if it would.
+    ///
+    /// Flows to the proxy server itself, and flows from the core's own
+    /// sockets when the tunnel captures them, would otherwise be wrapped in
+    /// another proxied connection over and over.
+    fn routing_loop(&self, ctx: &MatchContext) -> Option<&'static str> {
+        if !self.is_enabled() {
+            return None;
+        }
+        if ctx.src_port != 0 && self.own_ports.contains(&ctx.src_port) {
+            return Some("it comes from one of the core's own sockets");
+        }
+        let config = self.config.as_ref()?;
+        if ctx.dst_port != config.server_port {
+            return None;
+        }
+        let to_server = match config.server_host.parse::<IpAddr>() {
+            Ok(server_ip) => ctx.dst_ip == Some(server_ip),
+            Err(_) => ctx
+                .domain
+                .is_some_and(|name| name.eq_ignore_ascii_case(&config.server_host)),
+        };
+        to_server.then_some("it goes to the proxy server")
+    }
+
+    /// Remember the local port of an outbound socket the core opened, so
+    /// its packets are never proxied if the tunnel captures them
+    pub fn register_own_socket(&mut self, port: u16) {
+        self.own_ports.insert(port);
+    }
+
+    /// Forget an outbound socket registered with `register_own_socket`
+    pub fn release_own_socket(&mut self, port: u16) {
+        self.own_ports.remove(&port);
+    }
+
+    /// Run a destination through the active rules
+    fn match_rules(&self, ctx: &MatchContext) -> RuleMatch {
+        if !self.is_enabled() {
+            return RuleMatch {
+                action: RouteAction::Direct,
+                nodelay: false,
+                rate_limit: None,
+                matched_rule: None,
+            };
+        }
+        match self.find_rule(ctx) {
+            Some(rule) => RuleMatch {
+                action: rule.action.clone(),
+                nodelay: rule.nodelay,
+                rate_limit: rule.rate_limit,
+                matched_rule: Some(rule.label()),
+            },
+            None if ctx.domain.is_some_and(|domain| self.is_local_name(domain)) => RuleMatch {
+                action: RouteAction::Direct,
+                nodelay: false,
+                rate_limit: None,
+                matched_rule: Some(LOCAL_NAME_RULE.into()),
+            },
+            None => RuleMatch {
+                action: self.rule_engine.default_action().clone(),
+                nodelay: false,
+                rate_limit: None,
+                matched_rule: None,
+            },
+        }
+    }
+
+    /// First rule matching a connection. FINAL does not count for names of
+    /// the local network, which go DIRECT instead.
+    fn find_rule(&self, ctx: &MatchContext) -> Option<&Rule> {
+        self.rule_engine
+            .find_match(ctx)
+            .filter(|rule| {
+                !matches!(rule.rule_type, RuleType::Final)
+                    || !ctx.domain.is_some_and(|domain| self.is_local_name(domain))
+            })
+    }
+
+    /// Whether `domain` belongs to the local network
+    fn is_local_name(&self, domain: &str) -> bool {
+        self.config
+            .as_ref()
+            .is_some_and(|config| config.dns.is_local_name(domain))
+    }
+
+    /// Action the active rules pick for a connection, without counting it in
+    /// stats or the A/B comparison
+    pub fn peek_action(&self, ctx: &MatchContext) -> RouteAction {
+        self.peek_route(ctx).action
+    }
+
+    /// Rule action for resolving a name (not counted in connection stats)
+    pub fn dns_action(&self, domain: &str) -> RouteAction {
+        if !self.is_enabled() {
+            return RouteAction::Direct;
+        }
+        match self.find_rule(&MatchContext::new(Some(domain), None, 0)) {
+            Some(rule) => rule.action.clone(),
+            None if self.is_local_name(domain) => RouteAction::Direct,
+            None => self.rule_engine.default_action().clone(),
+        }
     }
 
     /// Get FFI-friendly route action
-    pub fn evaluate_route_ffi(
-        &mut self,
-        domain: Option<&str>,
-        dst_ip: Option<IpAddr>,
-        dst_port: u16,
-        src_port: u16,
-    ) -> FfiRouteAction {
-        let decision = self.evaluate_route(domain, dst_ip, dst_port, src_port);
+    pub fn evaluate_route_ffi(&mut self, ctx: &MatchContext) -> FfiRouteAction {
+        let decision = self.evaluate_route(ctx);
         FfiRouteAction::from(decision.action)
     }
 
+    /// Count a connection queued by a concurrency cap
+    pub fn record_queued(&mut self) {
+        self.stats.queued_connections += 1;
+    }
+
+    /// Count a connection first routed by `action` as rejected by a
+    /// concurrency cap
+    pub fn record_limit_rejected(&mut self, action: &RouteAction) {
+        match action {
+            RouteAction::Direct => {
+                self.stats.direct_connections = self.stats.direct_connections.saturating_sub(1)
+            }
+            RouteAction::Proxy => {
+                self.stats.proxied_connections = self.stats.proxied_connections.saturating_sub(1)
+            }
+            RouteAction::Reject => return,
+        }
+        self.stats.rejected_connections += 1;
+        self.stats.limit_rejected_connections += 1;
+    }
+
+    /// Count a proxied QUIC flow as rejected to force a TCP fallback
+    pub fn record_quic_blocked(&mut self) {
+        self.stats.proxied_connections = self.stats.proxied_connections.saturating_sub(1);
+        self.stats.rejected_connections += 1;
+        self.stats.quic_blocked += 1;
+    }
+
     /// Add bytes sent through proxy
     pub fn add_proxy_bytes_sent(&mut self, bytes: u64) {
         self.stats.proxy_bytes_sent += bytes;
@@ -240,7 +806,7 @@ impl ProxyManager {
     }
 
     /// Get proxy credentials
-    pub fn get_credentials(&self) -> Option<(String, String)> {
+    pub fn get_credentials(&self) -> Option<(String, SecretString)> {
         self.config.as_ref().and_then(|c| {
             match (&c.username, &c.password) {
                 (Some(u), Some(p)) => Some((u.clone(), p.clone())),
@@ -256,6 +822,16 @@ impl Default for ProxyManager {
     }
 }
 
+/// DIRECT decision for a flow that would loop back into the tunnel
+fn loop_decision(ctx: &MatchContext) -> RoutingDecision {
+    RoutingDecision {
+        domain: ctx.domain.map(String::from),
+        dst_ip: ctx.dst_ip,
+        matched_rule: Some(ROUTING_LOOP_RULE.into()),
+        ..RoutingDecision::direct(ctx.dst_port)
+    }
+}
+
 /// Thread-safe wrapper for ProxyManager
 pub type SharedProxyManager = Arc<Mutex<ProxyManager>>;
 
@@ -288,6 +864,7 @@ mod tests {
             server_port: 1080,
             username: Some("user".into()),
             password: Some("pass".into()),
+            ..Default::default()
         };
 
         let manager = ProxyManager::with_config(config.clone());
@@ -304,6 +881,7 @@ mod tests {
             server_port: 1080,
             username: None,
             password: None,
+            ..Default::default()
         });
 
         manager.enable();
@@ -331,7 +909,9 @@ FINAL, DIRECT
         let mut manager = ProxyManager::new();
         // Manager is disabled, should return Direct
 
-        let decision = manager.evaluate_route(Some("www.google.com"), None, 443, 0);
+        let decision = manager.evaluate_route(
+            &MatchContext::new(Some("www.google.com"), None, 443),
+        );
         assert_eq!(decision.action, RouteAction::Direct);
     }
 
@@ -342,6 +922,7 @@ FINAL, DIRECT
             server_port: 1080,
             username: None,
             password: None,
+            ..Default::default()
         });
 
         manager
@@ -355,18 +936,148 @@ FINAL, DIRECT
             .unwrap();
 
         // Should match PROXY
-        let decision = manager.evaluate_route(Some("www.google.com"), None, 443, 0);
+        let decision = manager.evaluate_route(
+            &MatchContext::new(Some("www.google.com"), None, 443),
+        );
         assert_eq!(decision.action, RouteAction::Proxy);
 
         // Should match REJECT
-        let decision = manager.evaluate_route(Some("blocked.com"), None, 443, 0);
+        let decision = manager.evaluate_route(&MatchContext::new(Some("blocked.com"), None, 443));
         assert_eq!(decision.action, RouteAction::Reject);
 
         // Should match DIRECT (FINAL)
-        let decision = manager.evaluate_route(Some("example.com"), None, 443, 0);
+        let decision = manager.evaluate_route(&MatchContext::new(Some("example.com"), None, 443));
+        assert_eq!(decision.action, RouteAction::Direct);
+    }
+
+    #[test]
+    fn test_evaluate_route_by_resolved_ip() {
+        use crate::dns::{DnsQuestion, DnsRecord, CLASS_IN, RCODE_NOERROR, TYPE_A};
+
+        let mut manager = ProxyManager::with_config(ProxyConfig::default());
+        manager
+            .load_rules("DOMAIN-SUFFIX, google.com, PROXY\nFINAL, DIRECT")
+            .unwrap();
+        let ip: IpAddr = "142.250.1.1".parse().unwrap();
+
+        let decision = manager.evaluate_route(&MatchContext::new(None, Some(ip), 443));
+        assert_eq!(decision.action, RouteAction::Direct);
+
+        let query = DnsMessage {
+            id: 1,
+            flags: 0x0100,
+            questions: vec![DnsQuestion {
+                name: "www.google.com".into(),
+                qtype: TYPE_A,
+                qclass: CLASS_IN,
+            }],
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+        };
+        let response = DnsMessage::reply(&query, RCODE_NOERROR).with_answer(DnsRecord::a(
+            "www.google.com",
+            "142.250.1.1".parse().unwrap(),
+            60,
+        ));
+        manager.record_dns_answer(&response);
+
+        let decision = manager.evaluate_route(&MatchContext::new(None, Some(ip), 443));
+        assert_eq!(decision.action, RouteAction::Proxy);
+        assert_eq!(decision.domain.as_deref(), Some("www.google.com"));
+
+        // An explicit domain takes precedence over the mapping
+        let decision = manager.evaluate_route(
+            &MatchContext::new(Some("example.com"), Some(ip), 443),
+        );
         assert_eq!(decision.action, RouteAction::Direct);
     }
 
+    #[test]
+    fn test_evaluate_route_nodelay() {
+        let mut manager = ProxyManager::with_config(ProxyConfig::default());
+        manager
+            .load_rules(
+                r#"
+DOMAIN-SUFFIX, .game.com, PROXY, nodelay
+DOMAIN-SUFFIX, example.com, PROXY, rate-limit=1mbps
+FINAL, PROXY
+"#,
+            )
+            .unwrap();
+
+        let decision = manager.evaluate_route(&MatchContext::new(Some("eu.game.com"), None, 443));
+        assert_eq!(decision.action, RouteAction::Proxy);
+        assert!(decision.nodelay);
+
+        let decision = manager.evaluate_route(&MatchContext::new(Some("example.com"), None, 443));
+        assert!(!decision.nodelay);
+        assert_eq!(decision.rate_limit, Some(125_000));
+    }
+
+    #[test]
+    fn test_route_comparison() {
+        let mut manager = ProxyManager::with_config(ProxyConfig::default());
+        manager
+            .load_rules(
+                r#"
+DOMAIN-SUFFIX, .google.com, PROXY
+FINAL, DIRECT
+"#,
+            )
+            .unwrap();
+        manager
+            .load_candidate_rules(
+                r#"
+DOMAIN-SUFFIX, .google.com, PROXY
+DOMAIN-KEYWORD, ads, REJECT
+FINAL, PROXY
+"#,
+            )
+            .unwrap();
+        assert!(manager.has_candidate_rules());
+
+        // Behavior follows the active ruleset only
+        let decision = manager.evaluate_route(
+            &MatchContext::new(Some("www.google.com"), None, 443),
+        );
+        assert_eq!(decision.action, RouteAction::Proxy);
+        let decision = manager.evaluate_route(
+            &MatchContext::new(Some("ads.example.com"), None, 443),
+        );
+        assert_eq!(decision.action, RouteAction::Direct);
+        manager.evaluate_route(&MatchContext::new(Some("example.com"), None, 80));
+
+        let comparison = manager.route_comparison();
+        assert_eq!(comparison.evaluated, 3);
+        assert_eq!(comparison.diverged, 2);
+        assert_eq!(comparison.samples[0].domain.as_deref(), Some("ads.example.com"));
+        assert_eq!(comparison.samples[0].active, RouteAction::Direct);
+        assert_eq!(comparison.samples[0].candidate, RouteAction::Reject);
+        assert_eq!(comparison.samples[1].candidate, RouteAction::Proxy);
+        assert_eq!(manager.get_stats().direct_connections, 2);
+
+        manager.clear_candidate_rules();
+        manager.evaluate_route(&MatchContext::new(Some("example.com"), None, 80));
+        assert_eq!(manager.route_comparison().evaluated, 3);
+    }
+
+    #[test]
+    fn test_route_comparison_sample_limit() {
+        let mut manager = ProxyManager::with_config(ProxyConfig::default());
+        manager.load_rules("FINAL, DIRECT").unwrap();
+        manager.load_candidate_rules("FINAL, PROXY").unwrap();
+
+        for port in 0..(MAX_DIVERGENCE_SAMPLES as u16 + 10) {
+            manager.evaluate_route(&MatchContext::new(None, None, port));
+        }
+
+        let comparison = manager.route_comparison();
+        assert_eq!(comparison.diverged, MAX_DIVERGENCE_SAMPLES as u64 + 10);
+        assert_eq!(comparison.samples.len(), MAX_DIVERGENCE_SAMPLES);
+        assert_eq!(comparison.samples[0].dst_port, 10);
+    }
+
     #[test]
     fn test_stats_tracking() {
         let mut manager = ProxyManager::with_config(ProxyConfig {
@@ -374,6 +1085,7 @@ FINAL, DIRECT
             server_port: 1080,
             username: None,
             password: None,
+            ..Default::default()
         });
 
         manager
@@ -386,15 +1098,22 @@ FINAL, DIRECT
             )
             .unwrap();
 
-        manager.evaluate_route(Some("proxy.com"), None, 443, 0);
-        manager.evaluate_route(Some("reject.com"), None, 443, 0);
-        manager.evaluate_route(Some("other.com"), None, 443, 0);
-        manager.evaluate_route(Some("another.com"), None, 443, 0);
+        manager.evaluate_route(&MatchContext::new(Some("proxy.com"), None, 443));
+        manager.evaluate_route(&MatchContext::new(Some("reject.com"), None, 443));
+        manager.evaluate_route(&MatchContext::new(Some("other.com"), None, 443));
+        manager.evaluate_route(&MatchContext::new(Some("another.com"), None, 443));
 
         let stats = manager.get_stats();
         assert_eq!(stats.proxied_connections, 1);
         assert_eq!(stats.rejected_connections, 1);
         assert_eq!(stats.direct_connections, 2);
+
+        // Peeking does not count
+        assert_eq!(
+            manager.peek_action(&MatchContext::new(Some("proxy.com"), None, 443)),
+            RouteAction::Proxy
+        );
+        assert_eq!(manager.get_stats().proxied_connections, 1);
     }
 
     #[test]
@@ -427,6 +1146,7 @@ FINAL, DIRECT
             server_port: 1080,
             username: None,
             password: None,
+            ..Default::default()
         });
 
         let addr = manager.get_proxy_addr().unwrap();
@@ -440,10 +1160,11 @@ FINAL, DIRECT
             server_port: 1080,
             username: Some("user".into()),
             password: Some("pass".into()),
+            ..Default::default()
         });
 
         let creds = manager.get_credentials().unwrap();
-        assert_eq!(creds, ("user".to_string(), "pass".to_string()));
+        assert_eq!(creds, ("user".to_string(), "pass".into()));
     }
 
     #[test]
@@ -453,6 +1174,7 @@ FINAL, DIRECT
             server_port: 1080,
             username: None,
             password: None,
+            ..Default::default()
         });
 
         assert!(manager.get_credentials().is_none());
@@ -481,6 +1203,190 @@ FINAL, DIRECT
         assert_eq!(manager.rule_count(), 0);
     }
 
+    #[test]
+    fn test_route_cache() {
+        let mut manager = ProxyManager::with_config(ProxyConfig::default());
+        manager
+            .load_rules("DOMAIN-SUFFIX,example.com,PROXY\nFINAL,DIRECT")
+            .unwrap();
+
+        for _ in 0..3 {
+            let decision = manager.evaluate_route(
+                &MatchContext::new(Some("www.example.com"), None, 443).with_source(None, 50000),
+            );
+            assert_eq!(decision.action, RouteAction::Proxy);
+            assert!(decision.matched_rule.is_some());
+        }
+        let stats = manager.get_stats();
+        assert_eq!((stats.route_cache_hits, stats.route_cache_misses), (2, 1));
+        assert_eq!(stats.proxied_connections, 3);
+
+        // Reloading rules drops cached matches
+        manager
+            .replace_rules(RuleEngine::parse_config("DOMAIN-SUFFIX,example.com,REJECT").unwrap());
+        let decision = manager.evaluate_route(
+            &MatchContext::new(Some("www.example.com"), None, 443).with_source(None, 50001),
+        );
+        assert_eq!(decision.action, RouteAction::Reject);
+        assert_eq!(manager.get_stats().route_cache_misses, 2);
+
+        // SRC-PORT rules make the source port part of the key
+        manager.replace_rules(RuleEngine::parse_config("SRC-PORT,5353,PROXY").unwrap());
+        let first = manager.evaluate_route(
+            &MatchContext::new(Some("a.test"), None, 443).with_source(None, 5353),
+        );
+        let second = manager.evaluate_route(
+            &MatchContext::new(Some("a.test"), None, 443).with_source(None, 5354),
+        );
+        assert_eq!(first.action, RouteAction::Proxy);
+        assert_eq!(second.action, RouteAction::Direct);
+        assert_eq!(manager.get_stats().route_cache_hits, 2);
+
+        // As do SRC-IP-CIDR rules for the source address
+        manager.replace_rules(RuleEngine::parse_config("SRC-IP-CIDR,10.8.0.0/24,PROXY").unwrap());
+        let inside = Some("10.8.0.2".parse().unwrap());
+        let outside = Some("10.0.0.2".parse().unwrap());
+        let first = manager.evaluate_route(
+            &MatchContext::new(Some("a.test"), None, 443).with_source(inside, 5353),
+        );
+        let second = manager.evaluate_route(
+            &MatchContext::new(Some("a.test"), None, 443).with_source(outside, 5353),
+        );
+        assert_eq!(first.action, RouteAction::Proxy);
+        assert_eq!(second.action, RouteAction::Direct);
+        assert_eq!(manager.get_stats().route_cache_hits, 2);
+    }
+
+    #[test]
+    fn test_routing_loop_goes_direct() {
+        let mut manager = ProxyManager::with_config(ProxyConfig::new("203.0.113.7", 1080));
+        manager.load_rules("FINAL,PROXY").unwrap();
+        let server: IpAddr = "203.0.113.7".parse().unwrap();
+
+        let decision = manager.evaluate_route(
+            &MatchContext::new(None, Some(server), 1080).with_source(None, 50000),
+        );
+        assert_eq!(decision.action, RouteAction::Direct);
+        assert_eq!(decision.matched_rule.as_deref(), Some(ROUTING_LOOP_RULE));
+        // Other ports on the same host are ordinary traffic
+        let decision = manager.evaluate_route(
+            &MatchContext::new(None, Some(server), 443).with_source(None, 50000),
+        );
+        assert_eq!(decision.action, RouteAction::Proxy);
+
+        manager.register_own_socket(40000);
+        let dst: IpAddr = "198.51.100.1".parse().unwrap();
+        let decision = manager.peek_route(
+            &MatchContext::new(None, Some(dst), 443).with_source(None, 40000),
+        );
+        assert_eq!(decision.matched_rule.as_deref(), Some(ROUTING_LOOP_RULE));
+        manager.release_own_socket(40000);
+        assert_eq!(manager.peek_action(
+            &MatchContext::new(None, Some(dst), 443).with_source(None, 40000),
+        ), RouteAction::Proxy);
+
+        let stats = manager.get_stats();
+        assert_eq!((stats.routing_loops, stats.direct_connections), (1, 1));
+    }
+
+    #[test]
+    fn test_script_overrides_rules() {
+        let mut manager = ProxyManager::with_config(ProxyConfig::default());
+        manager.load_rules("FINAL,PROXY").unwrap();
+        manager.set_script(Some(Arc::new(|decision: &RoutingDecision, _: &FlowFacts| {
+            match (decision.domain.as_deref(), decision.dst_port) {
+                (_, 25) => Some(RouteAction::Reject),
+                (Some("intranet.test"), _) => Some(RouteAction::Direct),
+                _ => Some(decision.action.clone()),
+            }
+        })));
+
+        let decision = manager.evaluate_route(
+            &MatchContext::new(Some("mail.test"), None, 25).with_source(None, 50000),
+        );
+        assert_eq!(decision.action, RouteAction::Reject);
+        assert_eq!(decision.matched_rule.as_deref(), Some(SCRIPT_RULE));
+        let decision = manager.evaluate_route(
+            &MatchContext::new(Some("intranet.test"), None, 443).with_source(None, 50000),
+        );
+        assert_eq!(decision.action, RouteAction::Direct);
+        let decision = manager.evaluate_route(
+            &MatchContext::new(Some("example.com"), None, 443).with_source(None, 50000),
+        );
+        assert_eq!(decision.action, RouteAction::Proxy);
+        assert_ne!(decision.matched_rule.as_deref(), Some(SCRIPT_RULE));
+
+        let stats = manager.get_stats();
+        assert_eq!(stats.script_overrides, 2);
+        assert_eq!(stats.rejected_connections, 1);
+        assert_eq!(stats.direct_connections, 1);
+        manager.set_script(None);
+        let decision = manager.evaluate_route(
+            &MatchContext::new(Some("mail.test"), None, 25).with_source(None, 50000),
+        );
+        assert_eq!(decision.action, RouteAction::Proxy);
+    }
+
+    #[test]
+    fn test_script_sees_process_and_country() {
+        let mut manager = ProxyManager::with_config(ProxyConfig::default());
+        manager.load_rules("FINAL,PROXY").unwrap();
+        // geoip.dat listing 3.0.0.0/8 as US
+        let geoip = [
+            0x0a, 0x0e, 0x0a, 0x02, b'u', b's', 0x12, 0x08, 0x0a, 0x04, 3, 0, 0, 0, 0x10, 0x08,
+        ];
+        manager.set_geoip(Some(Arc::new(GeoIpDb::parse(&geoip).unwrap())));
+        manager.set_script(Some(Arc::new(|_: &RoutingDecision, facts: &FlowFacts| {
+            match (facts.process, facts.country) {
+                (Some("com.example.mail"), Some("US")) => Some(RouteAction::Direct),
+                _ => None,
+            }
+        })));
+
+        let to = |ip: &str| MatchContext::new(None, Some(ip.parse().unwrap()), 443);
+        let mail = |ip| to(ip).with_process(Some("com.example.mail"));
+        assert_eq!(manager.evaluate_route(&mail("3.1.2.3")).action, RouteAction::Direct);
+        assert_eq!(manager.evaluate_route(&mail("4.1.2.3")).action, RouteAction::Proxy);
+        assert_eq!(manager.evaluate_route(&to("3.1.2.3")).action, RouteAction::Proxy);
+        assert_eq!(manager.reroute(&mail("3.1.2.3")).action, RouteAction::Direct);
+
+        manager.set_geoip(None);
+        assert_eq!(manager.evaluate_route(&mail("3.1.2.3")).action, RouteAction::Proxy);
+    }
+
+    #[test]
+    fn test_routing_loop_by_server_name() {
+        let mut manager = ProxyManager::with_config(ProxyConfig::new("proxy.example.com", 8388));
+        manager.load_rules("FINAL,PROXY").unwrap();
+        let decision = manager.evaluate_route(
+            &MatchContext::new(Some("PROXY.example.com"), None, 8388).with_source(None, 50000),
+        );
+        assert_eq!(decision.action, RouteAction::Direct);
+    }
+
+    #[test]
+    fn test_local_names_skip_final() {
+        let mut config = ProxyConfig::default();
+        config.dns.search_domains = vec!["corp.example".into()];
+        let mut manager = ProxyManager::with_config(config);
+        manager
+            .load_rules("DOMAIN,vpn.corp.example,PROXY\nFINAL,PROXY")
+            .unwrap();
+
+        for name in ["printer", "nas.local", "wiki.corp.example"] {
+            let decision = manager.evaluate_route(
+                &MatchContext::new(Some(name), None, 443).with_source(None, 50000),
+            );
+            assert_eq!(decision.action, RouteAction::Direct, "{}", name);
+            assert_eq!(decision.matched_rule.as_deref(), Some(LOCAL_NAME_RULE));
+            assert_eq!(manager.dns_action(name), RouteAction::Direct);
+        }
+        // Explicit rules still apply
+        let action = manager.peek_action(&MatchContext::new(Some("vpn.corp.example"), None, 443));
+        assert_eq!(action, RouteAction::Proxy);
+        assert_eq!(manager.dns_action("example.com"), RouteAction::Proxy);
+    }
+
     #[test]
     fn test_shared_proxy_manager() {
         let shared = new_shared_proxy_manager();
@@ -491,6 +1397,7 @@ FINAL, DIRECT
             server_port: 1080,
             username: None,
             password: None,
+            ..Default::default()
         };
         let shared_with_config = new_shared_proxy_manager_with_config(config);
         assert!(Arc::strong_count(&shared_with_config) == 1);
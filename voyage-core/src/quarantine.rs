@@ -0,0 +1,150 @@
+//! Malformed Packet Quarantine
+//!
+//! This module keeps a small ring buffer of the most recent packets the
+//! parser rejected, with the parse error, so a user reporting that some
+//! app breaks can hand over the exact frames the core choked on. Only the
+//! start of each packet is kept, enough for the headers.
+
+use std::collections::VecDeque;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::VoyageError;
+
+/// Packets kept by default
+pub const DEFAULT_QUARANTINE_SIZE: usize = 32;
+
+/// Bytes kept of each packet
+pub const QUARANTINE_SNAPLEN: usize = 128;
+
+/// A packet the parser rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MalformedPacket {
+    /// When the packet arrived, in milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+    /// What the parser objected to
+    pub error: String,
+    /// Byte offset of the problem in the packet
+    pub offset: u64,
+    /// Length of the whole packet
+    pub length: u64,
+    /// The first `QUARANTINE_SNAPLEN` bytes of the packet
+    pub data: Vec<u8>,
+}
+
+impl MalformedPacket {
+    /// The kept bytes as lowercase hex
+    pub fn hex(&self) -> String {
+        self.data.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+/// Most recently rejected packets, oldest dropped first
+#[derive(Debug, Clone)]
+pub struct PacketQuarantine {
+    packets: VecDeque<MalformedPacket>,
+    capacity: usize,
+    /// Packets rejected since creation, including ones no longer kept
+    total: u64,
+}
+
+impl PacketQuarantine {
+    /// Create a quarantine keeping at most `capacity` packets
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            packets: VecDeque::with_capacity(capacity.min(1024)),
+            capacity,
+            total: 0,
+        }
+    }
+
+    /// Keep `packet` if `error` is a parse error, dropping the oldest
+    /// packet if full. Returns whether it was kept.
+    pub fn record(&mut self, packet: &[u8], error: &VoyageError) -> bool {
+        let VoyageError::Packet { kind, offset } = error else {
+            return false;
+        };
+        self.total += 1;
+        if self.capacity == 0 {
+            return false;
+        }
+        if self.packets.len() >= self.capacity {
+            self.packets.pop_front();
+        }
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        self.packets.push_back(MalformedPacket {
+            timestamp_ms,
+            error: kind.to_string(),
+            offset: *offset,
+            length: packet.len() as u64,
+            data: packet[..packet.len().min(QUARANTINE_SNAPLEN)].to_vec(),
+        });
+        true
+    }
+
+    /// Up to `limit` packets, newest first
+    pub fn recent(&self, limit: usize) -> impl Iterator<Item = &MalformedPacket> {
+        self.packets.iter().rev().take(limit)
+    }
+
+    /// Number of packets kept
+    pub fn len(&self) -> usize {
+        self.packets.len()
+    }
+
+    /// Check if no packet is kept
+    pub fn is_empty(&self) -> bool {
+        self.packets.is_empty()
+    }
+
+    /// Packets rejected since creation
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Forget every kept packet
+    pub fn clear(&mut self) {
+        self.packets.clear();
+    }
+}
+
+impl Default for PacketQuarantine {
+    fn default() -> Self {
+        Self::new(DEFAULT_QUARANTINE_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packet::{ParseErrorKind, ParsedPacket};
+
+    #[test]
+    fn test_quarantine() {
+        let mut quarantine = PacketQuarantine::new(2);
+        let packet = vec![0x45; 300];
+        let error = ParsedPacket::parse(&packet[..10]).unwrap_err();
+        assert!(quarantine.record(&packet[..10], &error));
+        assert!(!quarantine.record(&packet, &VoyageError::NatTableFull));
+
+        let truncated = VoyageError::packet(ParseErrorKind::TcpTooShort, 20);
+        assert!(quarantine.record(&packet, &truncated));
+        assert!(quarantine.record(
+            &[0x70],
+            &VoyageError::packet(ParseErrorKind::UnknownIpVersion { version: 7 }, 0)
+        ));
+
+        // The oldest packet made room, and long packets are cut short
+        assert_eq!((quarantine.len(), quarantine.total()), (2, 3));
+        let kept: Vec<_> = quarantine.recent(10).collect();
+        assert_eq!(kept[0].hex(), "70");
+        assert_eq!((kept[1].offset, kept[1].length), (20, 300));
+        assert_eq!(kept[1].data.len(), QUARANTINE_SNAPLEN);
+        assert_eq!(kept[1].error, ParseErrorKind::TcpTooShort.to_string());
+
+        quarantine.clear();
+        assert!(quarantine.is_empty());
+    }
+}
@@ -0,0 +1,222 @@
+//! DNS Query Log
+//!
+//! This module keeps a bounded ring buffer of recently answered DNS
+//! queries: the name and type asked, which upstream answered, the
+//! addresses returned, the response code, how long it took and whether a
+//! fake IP was handed out, so the app can show a request log.
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::dns::{
+    DnsMessage, RecordData, RCODE_FORMERR, RCODE_NOERROR, RCODE_NXDOMAIN, RCODE_SERVFAIL, TYPE_A,
+    TYPE_AAAA, TYPE_CNAME, TYPE_SOA,
+};
+
+/// An answered query
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DnsQueryRecord {
+    /// When the query arrived, in milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+    pub name: String,
+    /// Record type, e.g. "A" or "AAAA"
+    pub qtype: String,
+    /// Upstream that answered; `None` when answered locally (hosts, DNS
+    /// rules, cache or fake IP) or when every upstream failed
+    pub upstream: Option<String>,
+    /// The upstream was reached through the proxy
+    pub via_proxy: bool,
+    /// Addresses in the answer
+    pub answers: Vec<String>,
+    /// Response code, e.g. "NOERROR" or "NXDOMAIN"
+    pub rcode: String,
+    pub latency_ms: u64,
+    /// The answer was a fake IP
+    pub fake_ip: bool,
+}
+
+impl DnsQueryRecord {
+    /// Record `query` answered with `response` by `upstream` after
+    /// `latency`
+    pub fn new(
+        query: &DnsMessage,
+        response: &DnsMessage,
+        upstream: Option<SocketAddr>,
+        via_proxy: bool,
+        latency: Duration,
+    ) -> Self {
+        let (name, qtype) = query
+            .question()
+            .map(|q| (q.name.clone(), type_name(q.qtype)))
+            .unwrap_or_default();
+        let answers = response
+            .answers
+            .iter()
+            .filter_map(|record| match &record.data {
+                RecordData::A(addr) => Some(addr.to_string()),
+                RecordData::Aaaa(addr) => Some(addr.to_string()),
+                _ => None,
+            })
+            .collect();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        Self {
+            timestamp_ms: now.saturating_sub(latency).as_millis() as u64,
+            name,
+            qtype,
+            upstream: upstream.map(|addr| addr.to_string()),
+            via_proxy: upstream.is_some() && via_proxy,
+            answers,
+            rcode: rcode_name(response.rcode()),
+            latency_ms: latency.as_millis() as u64,
+            fake_ip: false,
+        }
+    }
+}
+
+/// Mnemonic of a record type, or "TYPE<n>" for unnamed types
+fn type_name(qtype: u16) -> String {
+    match qtype {
+        TYPE_A => "A".into(),
+        TYPE_CNAME => "CNAME".into(),
+        TYPE_SOA => "SOA".into(),
+        TYPE_AAAA => "AAAA".into(),
+        12 => "PTR".into(),
+        15 => "MX".into(),
+        16 => "TXT".into(),
+        33 => "SRV".into(),
+        64 => "SVCB".into(),
+        65 => "HTTPS".into(),
+        other => format!("TYPE{}", other),
+    }
+}
+
+/// Mnemonic of a response code, or "RCODE<n>" for unnamed codes
+fn rcode_name(rcode: u8) -> String {
+    match rcode {
+        RCODE_NOERROR => "NOERROR".into(),
+        RCODE_FORMERR => "FORMERR".into(),
+        RCODE_SERVFAIL => "SERVFAIL".into(),
+        RCODE_NXDOMAIN => "NXDOMAIN".into(),
+        4 => "NOTIMP".into(),
+        5 => "REFUSED".into(),
+        other => format!("RCODE{}", other),
+    }
+}
+
+/// Most recently answered queries, oldest dropped first
+#[derive(Debug, Clone)]
+pub struct DnsQueryLog {
+    records: VecDeque<DnsQueryRecord>,
+    capacity: usize,
+}
+
+impl DnsQueryLog {
+    /// Create a log keeping at most `capacity` records
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            records: VecDeque::with_capacity(capacity.min(1024)),
+            capacity,
+        }
+    }
+
+    /// Number of records kept
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    /// Check if no query has been recorded
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Forget every record
+    pub fn clear(&mut self) {
+        self.records.clear();
+    }
+
+    /// Record a query, dropping the oldest record if full
+    pub fn push(&mut self, record: DnsQueryRecord) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.records.len() >= self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    /// Up to `limit` records, newest first
+    pub fn recent(&self, limit: usize) -> impl Iterator<Item = &DnsQueryRecord> {
+        self.records.iter().rev().take(limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{DnsQuestion, DnsRecord, CLASS_IN};
+
+    fn query(name: &str, qtype: u16) -> DnsMessage {
+        DnsMessage {
+            id: 0x1234,
+            flags: 0x0100,
+            questions: vec![DnsQuestion {
+                name: name.into(),
+                qtype,
+                qclass: CLASS_IN,
+            }],
+            answers: Vec::new(),
+            authorities: Vec::new(),
+            additionals: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_record_from_response() {
+        let query = query("example.com", TYPE_A);
+        let response = DnsMessage::reply(&query, RCODE_NOERROR).with_answer(DnsRecord::a(
+            "example.com",
+            "93.184.216.34".parse().unwrap(),
+            300,
+        ));
+        let upstream = "1.1.1.1:53".parse().unwrap();
+        let record = DnsQueryRecord::new(
+            &query,
+            &response,
+            Some(upstream),
+            false,
+            Duration::from_millis(42),
+        );
+
+        assert_eq!(record.name, "example.com");
+        assert_eq!(record.qtype, "A");
+        assert_eq!(record.upstream.as_deref(), Some("1.1.1.1:53"));
+        assert_eq!(record.answers, vec!["93.184.216.34".to_string()]);
+        assert_eq!(record.rcode, "NOERROR");
+        assert_eq!(record.latency_ms, 42);
+
+        let failed = DnsMessage::reply(&query, RCODE_SERVFAIL);
+        let record = DnsQueryRecord::new(&query, &failed, None, true, Duration::ZERO);
+        assert_eq!(record.rcode, "SERVFAIL");
+        assert!(!record.via_proxy);
+        assert!(record.answers.is_empty());
+        assert_eq!(type_name(65), "HTTPS");
+    }
+
+    #[test]
+    fn test_log_keeps_newest() {
+        let query = query("example.com", TYPE_AAAA);
+        let response = DnsMessage::reply(&query, RCODE_NXDOMAIN);
+        let mut log = DnsQueryLog::new(2);
+        for ms in 1..=3 {
+            let latency = Duration::from_millis(ms);
+            log.push(DnsQueryRecord::new(&query, &response, None, false, latency));
+        }
+        assert_eq!(log.len(), 2);
+        let latencies: Vec<u64> = log.recent(10).map(|r| r.latency_ms).collect();
+        assert_eq!(latencies, [3, 2]);
+    }
+}
@@ -0,0 +1,115 @@
+//! Transfer Rates
+//!
+//! This module provides the meter behind per-flow and aggregate speeds.
+//! Bytes are counted in one-second windows and folded into an exponentially
+//! weighted moving average as each window closes, so the reported rate
+//! follows bursts quickly and decays to zero once a flow goes quiet.
+
+use std::time::{Duration, Instant};
+
+/// Length of a counting window
+pub const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Weight of the newest window in the average
+const RATE_ALPHA: f64 = 0.5;
+
+/// EWMA of bytes per second
+#[derive(Debug, Clone, Copy)]
+pub struct RateMeter {
+    /// Smoothed rate in bytes per second
+    average: f64,
+    /// Bytes counted in the current window
+    window_bytes: u64,
+    /// Start of the current window
+    window_start: Instant,
+}
+
+impl RateMeter {
+    /// Create an idle meter whose first window starts at `now`
+    pub fn new(now: Instant) -> Self {
+        Self {
+            average: 0.0,
+            window_bytes: 0,
+            window_start: now,
+        }
+    }
+
+    /// Count `bytes` transferred at `now`
+    pub fn record(&mut self, bytes: u64, now: Instant) {
+        self.roll(now);
+        self.window_bytes += bytes;
+    }
+
+    /// Smoothed rate in bytes per second as of `now`
+    pub fn rate(&self, now: Instant) -> u64 {
+        let mut meter = *self;
+        meter.roll(now);
+        meter.average.round() as u64
+    }
+
+    /// Add the traffic of another meter, e.g. for the same group counted
+    /// elsewhere. Its open window is folded into ours as of `now`.
+    pub fn merge(&mut self, other: &RateMeter, now: Instant) {
+        let mut other = *other;
+        self.roll(now);
+        other.roll(now);
+        self.average += other.average;
+        self.window_bytes += other.window_bytes;
+    }
+
+    /// Fold every window that closed before `now` into the average
+    fn roll(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        let windows = (elapsed.as_millis() / RATE_WINDOW.as_millis()) as u32;
+        if windows == 0 {
+            return;
+        }
+
+        let window_rate = self.window_bytes as f64 / RATE_WINDOW.as_secs_f64();
+        self.average = RATE_ALPHA * window_rate + (1.0 - RATE_ALPHA) * self.average;
+        // Windows without traffic pull the average towards zero
+        self.average *= (1.0 - RATE_ALPHA).powi(windows as i32 - 1);
+        self.window_bytes = 0;
+        self.window_start += RATE_WINDOW * windows;
+    }
+}
+
+impl Default for RateMeter {
+    fn default() -> Self {
+        Self::new(Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_follows_traffic() {
+        let start = Instant::now();
+        let mut meter = RateMeter::new(start);
+        meter.record(1000, start);
+        // Nothing is reported until the window closes
+        assert_eq!(meter.rate(start + Duration::from_millis(500)), 0);
+        assert_eq!(meter.rate(start + RATE_WINDOW), 500);
+
+        for second in 1..10 {
+            meter.record(1000, start + RATE_WINDOW * second);
+        }
+        let steady = meter.rate(start + RATE_WINDOW * 10);
+        assert!((990..=1000).contains(&steady), "{}", steady);
+    }
+
+    #[test]
+    fn test_rate_decays_when_idle() {
+        let start = Instant::now();
+        let mut meter = RateMeter::new(start);
+        for second in 0..10 {
+            meter.record(4000, start + RATE_WINDOW * second);
+        }
+        let busy = meter.rate(start + RATE_WINDOW * 10);
+        let quieter = meter.rate(start + RATE_WINDOW * 12);
+        assert!(quieter < busy / 2);
+        assert_eq!(meter.rate(start + RATE_WINDOW * 60), 0);
+    }
+}
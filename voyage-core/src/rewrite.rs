@@ -0,0 +1,418 @@
+//! HTTP Rewriting
+//!
+//! This module edits plain HTTP requests passing through the inbound proxy
+//! (and intercepted HTTPS requests with the `mitm` feature) before they go
+//! upstream. `URL-REWRITE` rules send a URL elsewhere, either by answering
+//! the app with a 302 or by quietly fetching the new URL instead;
+//! `HEADER-REWRITE` rules add, replace or drop request headers. Patterns
+//! are whole URLs in which `*` matches any run of characters, and `$1`,
+//! `$2`... in a replacement stand for what each `*` matched:
+//!
+//! ```text
+//! URL-REWRITE, http://old.example.com/*, https://new.example.com/$1, 302
+//! URL-REWRITE, http://api.example.com/v1/*, http://api.example.com/v2/$1, TRANSPARENT
+//! HEADER-REWRITE, http://*.example.com/*, SET, User-Agent, Voyage
+//! HEADER-REWRITE, *, DEL, X-Tracking-Id
+//! ```
+//!
+//! Host names in the URLs matched are lowercase, and a port is only
+//! present when it is not the scheme's default.
+
+/// Start line and headers of an HTTP/1.1 request or response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpHead {
+    /// `GET /path HTTP/1.1` or `HTTP/1.1 200 OK`
+    pub start_line: String,
+    /// Headers in the order they were sent
+    pub headers: Vec<(String, String)>,
+}
+
+impl HttpHead {
+    /// Parse a head without its terminating blank line
+    pub fn parse(head: &str) -> Option<Self> {
+        let mut lines = head.split("\r\n").filter(|line| !line.is_empty());
+        let start_line = lines.next()?.to_string();
+        let headers = lines
+            .map(|line| {
+                let (name, value) = line.split_once(':')?;
+                Some((name.trim().to_string(), value.trim().to_string()))
+            })
+            .collect::<Option<_>>()?;
+        Some(Self {
+            start_line,
+            headers,
+        })
+    }
+
+    /// Method of a request
+    pub fn method(&self) -> &str {
+        self.start_line.split(' ').next().unwrap_or_default()
+    }
+
+    /// Target of a request
+    pub fn path(&self) -> &str {
+        self.start_line.split(' ').nth(1).unwrap_or_default()
+    }
+
+    /// Replace the target of a request
+    pub fn set_path(&mut self, path: &str) {
+        let mut parts = self.start_line.splitn(3, ' ');
+        let method = parts.next().unwrap_or_default();
+        let version = parts.nth(1).unwrap_or("HTTP/1.1");
+        self.start_line = format!("{} {} {}", method, path, version);
+    }
+
+    /// Status code of a response
+    pub fn status(&self) -> Option<u16> {
+        self.start_line.split(' ').nth(1)?.parse().ok()
+    }
+
+    /// First value of a header (case-insensitive)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Replace every value of a header with `value`
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.remove_header(name);
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+
+    /// Drop every value of a header
+    pub fn remove_header(&mut self, name: &str) {
+        self.headers
+            .retain(|(header, _)| !header.eq_ignore_ascii_case(name));
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut out = format!("{}\r\n", self.start_line);
+        for (name, value) in &self.headers {
+            out.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        out.push_str("\r\n");
+        out.into_bytes()
+    }
+}
+
+/// How a URL-REWRITE rule sends the request to its new URL
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UrlMode {
+    /// Answer `302 Found` so the app fetches the new URL itself
+    Redirect,
+    /// Fetch the new URL in place of the old one without telling the app
+    Transparent,
+}
+
+/// What a HEADER-REWRITE rule does to a request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeaderAction {
+    /// Append a header, keeping existing values
+    Add(String, String),
+    /// Replace every value of a header
+    Set(String, String),
+    /// Drop every value of a header
+    Del(String),
+}
+
+/// What a rewrite rule does when its pattern matches
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RewriteAction {
+    /// Send the request to the replacement URL
+    Url { replacement: String, mode: UrlMode },
+    /// Edit a request header
+    Header(HeaderAction),
+}
+
+/// A single rewrite rule
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RewriteRule {
+    /// URL pattern, `*` matching any run of characters
+    pub pattern: String,
+    /// Action to take when matched
+    pub action: RewriteAction,
+}
+
+impl RewriteRule {
+    /// Parse a `URL-REWRITE, pattern, replacement, 302|TRANSPARENT` or
+    /// `HEADER-REWRITE, pattern, ADD|SET|DEL, name[, value]` line
+    pub fn parse_line(line: &str) -> Result<Self, String> {
+        let parts: Vec<&str> = line.split(',').map(|s| s.trim()).collect();
+        if parts.len() < 4 || parts[1].is_empty() {
+            return Err(format!("Invalid rewrite rule format: {}", line));
+        }
+
+        let pattern = parts[1].to_string();
+        let action = match parts[0].to_uppercase().as_str() {
+            "URL-REWRITE" => {
+                let mode = match &parts[3..] {
+                    [mode] if *mode == "302" => UrlMode::Redirect,
+                    [mode] if mode.eq_ignore_ascii_case("TRANSPARENT") => UrlMode::Transparent,
+                    _ => {
+                        return Err(format!(
+                            "Invalid URL-REWRITE mode: {}",
+                            parts[3..].join(", ")
+                        ))
+                    }
+                };
+                if split_url(parts[2]).is_none() {
+                    return Err(format!("Invalid replacement URL: {}", parts[2]));
+                }
+                RewriteAction::Url {
+                    replacement: parts[2].to_string(),
+                    mode,
+                }
+            }
+            "HEADER-REWRITE" => {
+                let name = parts[3].to_string();
+                if name.is_empty() || name.contains(|c: char| c == ':' || c.is_whitespace()) {
+                    return Err(format!("Invalid header name: {}", parts[3]));
+                }
+                // Header values may themselves contain commas
+                let value = parts[4..].join(", ");
+                let action = match (parts[2].to_uppercase().as_str(), parts.len()) {
+                    ("ADD", 5..) => HeaderAction::Add(name, value),
+                    ("SET", 5..) => HeaderAction::Set(name, value),
+                    ("DEL", 4) => HeaderAction::Del(name),
+                    ("ADD" | "SET", _) => {
+                        return Err(format!("{} rewrite requires a header value", parts[2]))
+                    }
+                    ("DEL", _) => return Err("DEL rewrite takes only a header name".into()),
+                    (other, _) => return Err(format!("Unknown header action: {}", other)),
+                };
+                RewriteAction::Header(action)
+            }
+            other => return Err(format!("Unsupported rewrite rule type: {}", other)),
+        };
+
+        Ok(Self { pattern, action })
+    }
+
+    /// What each `*` of the pattern matched, when `url` matches
+    pub fn captures<'a>(&self, url: &'a str) -> Option<Vec<&'a str>> {
+        let mut pieces = self.pattern.split('*');
+        let mut rest = url.strip_prefix(pieces.next().unwrap_or_default())?;
+        let mut pieces: Vec<&str> = pieces.collect();
+        let Some(last) = pieces.pop() else {
+            return rest.is_empty().then(Vec::new);
+        };
+        let mut captures = Vec::with_capacity(pieces.len() + 1);
+        for piece in pieces {
+            let at = rest.find(piece)?;
+            captures.push(&rest[..at]);
+            rest = &rest[at + piece.len()..];
+        }
+        captures.push(rest.strip_suffix(last)?);
+        Some(captures)
+    }
+}
+
+/// Ordered rewrite rules
+#[derive(Debug, Clone, Default)]
+pub struct RewriteEngine {
+    rules: Vec<RewriteRule>,
+}
+
+impl RewriteEngine {
+    /// Create an engine without rules
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Parse rules from configuration, skipping blank lines and comments
+    pub fn from_config(config: &str) -> Result<Self, String> {
+        let rules = config
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#') && !line.starts_with("//"))
+            .map(RewriteRule::parse_line)
+            .collect::<Result<_, _>>()?;
+        Ok(Self { rules })
+    }
+
+    /// Number of rules
+    pub fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// Check if there are no rules
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Rewrite `request`, made for `url`. Every header rule matching the
+    /// URL edits the request, in order; the first URL rule matching it
+    /// decides where the request goes, returned with the new URL.
+    pub fn apply(&self, url: &str, request: &mut HttpHead) -> Option<(UrlMode, String)> {
+        let mut destination = None;
+        for rule in &self.rules {
+            let Some(captures) = rule.captures(url) else {
+                continue;
+            };
+            match &rule.action {
+                RewriteAction::Url { replacement, mode } if destination.is_none() => {
+                    destination = Some((*mode, expand(replacement, &captures)));
+                }
+                RewriteAction::Url { .. } => {}
+                RewriteAction::Header(HeaderAction::Add(name, value)) => {
+                    request.headers.push((name.clone(), value.clone()));
+                }
+                RewriteAction::Header(HeaderAction::Set(name, value)) => {
+                    request.set_header(name, value)
+                }
+                RewriteAction::Header(HeaderAction::Del(name)) => request.remove_header(name),
+            }
+        }
+        if let Some((mode, to)) = &destination {
+            log::debug!("Rewrote {} to {} ({:?})", url, to, mode);
+        }
+        destination
+    }
+}
+
+/// `replacement` with `$1`..`$9` replaced by the matching capture
+fn expand(replacement: &str, captures: &[&str]) -> String {
+    let mut out = String::with_capacity(replacement.len());
+    let mut chars = replacement.chars().peekable();
+    while let Some(c) = chars.next() {
+        let index = match chars.peek().and_then(|next| next.to_digit(10)) {
+            Some(digit) if c == '$' && digit > 0 => digit as usize - 1,
+            _ => {
+                out.push(c);
+                continue;
+            }
+        };
+        chars.next();
+        out.push_str(captures.get(index).copied().unwrap_or_default());
+    }
+    out
+}
+
+/// Split an absolute `http` or `https` URL into scheme, authority and
+/// origin-form path
+pub(crate) fn split_url(url: &str) -> Option<(&str, &str, &str)> {
+    let (scheme, rest) = url.split_once("://")?;
+    if !scheme.eq_ignore_ascii_case("http") && !scheme.eq_ignore_ascii_case("https") {
+        return None;
+    }
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+    (!authority.is_empty()).then_some((scheme, authority, path))
+}
+
+/// A `302 Found` sending the app to `location`
+pub(crate) fn redirect_response(location: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 302 Found\r\nLocation: {}\r\nContent-Length: 0\r\n\
+         Cache-Control: no-store\r\nConnection: close\r\n\r\n",
+        location
+    )
+    .into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_http_head() {
+        let mut head = HttpHead::parse("GET /a HTTP/1.1\r\nHost: x\r\nAccept: */*").unwrap();
+        assert_eq!((head.method(), head.path()), ("GET", "/a"));
+        assert_eq!(head.header("host"), Some("x"));
+        head.set_header("accept", "text/html");
+        head.remove_header("Host");
+        head.set_path("/b?c");
+        assert_eq!(
+            head.to_bytes(),
+            b"GET /b?c HTTP/1.1\r\naccept: text/html\r\n\r\n"
+        );
+        assert_eq!(
+            HttpHead::parse("HTTP/1.1 204 No Content").unwrap().status(),
+            Some(204)
+        );
+        assert!(HttpHead::parse("GET / HTTP/1.1\r\nbroken").is_none());
+    }
+
+    #[test]
+    fn test_parse_rules() {
+        let rules = RewriteEngine::from_config(
+            "# redirects\n\
+             URL-REWRITE, http://old.test/*, https://new.test/$1, 302\n\
+             url-rewrite, http://api.test/v1/*, http://api.test/v2/$1, transparent\n\
+             HEADER-REWRITE, *, SET, Accept, text/html, */*\n\
+             HEADER-REWRITE, http://*.test/*, DEL, Cookie",
+        )
+        .unwrap();
+        assert_eq!(rules.len(), 4);
+        assert_eq!(
+            rules.rules[2].action,
+            RewriteAction::Header(HeaderAction::Set("Accept".into(), "text/html, */*".into()))
+        );
+
+        assert!(RewriteRule::parse_line("URL-REWRITE, *, http://a.test/").is_err());
+        assert!(RewriteRule::parse_line("URL-REWRITE, *, http://a.test/, 301").is_err());
+        assert!(RewriteRule::parse_line("URL-REWRITE, *, ftp://a.test/, 302").is_err());
+        assert!(RewriteRule::parse_line("HEADER-REWRITE, *, SET, Accept").is_err());
+        assert!(RewriteRule::parse_line("HEADER-REWRITE, *, DEL, Cookie, x").is_err());
+        assert!(RewriteRule::parse_line("HEADER-REWRITE, *, DEL, Bad Name").is_err());
+        assert!(RewriteRule::parse_line("HEADER-REWRITE, *, MOVE, Cookie").is_err());
+        assert!(RewriteRule::parse_line("BODY-REWRITE, *, SET, a, b").is_err());
+    }
+
+    #[test]
+    fn test_captures() {
+        let rule = RewriteRule::parse_line("HEADER-REWRITE, http://*.test/*, DEL, A").unwrap();
+        assert_eq!(
+            rule.captures("http://a.b.test/x/y"),
+            Some(vec!["a.b", "x/y"])
+        );
+        assert_eq!(rule.captures("http://a.test/"), Some(vec!["a", ""]));
+        assert_eq!(rule.captures("https://a.test/"), None);
+
+        let exact = RewriteRule::parse_line("HEADER-REWRITE, http://a.test/, DEL, A").unwrap();
+        assert_eq!(exact.captures("http://a.test/"), Some(vec![]));
+        assert_eq!(exact.captures("http://a.test/x"), None);
+        assert_eq!(expand("/$2/$1/$3$", &["a", "b"]), "/b/a/$");
+    }
+
+    #[test]
+    fn test_apply() {
+        let rules = RewriteEngine::from_config(
+            "HEADER-REWRITE, http://*.test/*, ADD, X-Via, voyage\n\
+             URL-REWRITE, http://old.test/*, https://new.test/$1, 302\n\
+             URL-REWRITE, http://*.test/*, http://other.test/$2, TRANSPARENT\n\
+             HEADER-REWRITE, *, DEL, Cookie",
+        )
+        .unwrap();
+
+        let mut head = HttpHead::parse("GET /a?b HTTP/1.1\r\nCookie: id=1").unwrap();
+        assert_eq!(
+            rules.apply("http://old.test/a?b", &mut head),
+            Some((UrlMode::Redirect, "https://new.test/a?b".into()))
+        );
+        assert_eq!(head.headers, vec![("X-Via".into(), "voyage".into())]);
+
+        let mut head = HttpHead::parse("GET / HTTP/1.1").unwrap();
+        assert_eq!(
+            rules.apply("http://www.test/", &mut head),
+            Some((UrlMode::Transparent, "http://other.test/".into()))
+        );
+        let mut head = HttpHead::parse("GET / HTTP/1.1").unwrap();
+        assert_eq!(rules.apply("http://example.com/", &mut head), None);
+    }
+
+    #[test]
+    fn test_split_url() {
+        assert_eq!(
+            split_url("http://a.test:8080/x?y"),
+            Some(("http", "a.test:8080", "/x?y"))
+        );
+        assert_eq!(split_url("https://a.test"), Some(("https", "a.test", "/")));
+        assert_eq!(split_url("ftp://a.test/"), None);
+        assert_eq!(split_url("http:///x"), None);
+    }
+}
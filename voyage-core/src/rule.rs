@@ -3,11 +3,17 @@
 //! This module provides a Surge-style rule engine for routing decisions.
 //! Rules are evaluated in order, and the first matching rule determines the action.
 
-use std::net::{IpAddr, Ipv4Addr};
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::str::FromStr;
+use std::sync::Arc;
+
+use crate::error::VoyageError;
+use crate::geosite::{GeoSite, GeoSiteDb};
+use crate::shaping;
 
 /// Routing action for a matched rule
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum RouteAction {
     /// Direct connection without proxy
     Direct,
@@ -17,6 +23,17 @@ pub enum RouteAction {
     Reject,
 }
 
+impl RouteAction {
+    /// Policy name as written in rules
+    pub fn name(&self) -> &'static str {
+        match self {
+            RouteAction::Direct => "DIRECT",
+            RouteAction::Proxy => "PROXY",
+            RouteAction::Reject => "REJECT",
+        }
+    }
+}
+
 /// Rule type for matching connections
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum RuleType {
@@ -28,14 +45,66 @@ pub enum RuleType {
     DomainKeyword(String),
     /// Match IP CIDR range
     IpCidr(Ipv4Addr, u8),
+    /// Match IPv6 CIDR range
+    IpCidr6(Ipv6Addr, u8),
     /// Match destination port
     DstPort(u16),
     /// Match source port
     SrcPort(u16),
+    /// Match source IPv4/IPv6 CIDR range (e.g. a secondary TUN subnet)
+    SrcIpCidr(IpAddr, u8),
+    /// Match domains of a geosite category (e.g. "category-ads-all")
+    GeoSite(GeoSite),
     /// Match any connection (final rule)
     Final,
 }
 
+/// A connection as the rules see it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MatchContext<'a> {
+    /// Destination hostname, if known
+    pub domain: Option<&'a str>,
+    /// Destination address, if known
+    pub dst_ip: Option<IpAddr>,
+    /// Destination port
+    pub dst_port: u16,
+    /// Address the flow comes from, if known
+    pub src_ip: Option<IpAddr>,
+    /// Port the flow comes from, 0 if unknown
+    pub src_port: u16,
+    /// App that opened the flow, if the host attributed it
+    pub process: Option<&'a str>,
+}
+
+impl<'a> MatchContext<'a> {
+    /// Connection to `dst_port` on `domain` and/or `dst_ip` from an unknown source
+    pub fn new(domain: Option<&'a str>, dst_ip: Option<IpAddr>, dst_port: u16) -> Self {
+        Self {
+            domain,
+            dst_ip,
+            dst_port,
+            ..Self::default()
+        }
+    }
+
+    /// Set where the connection comes from
+    pub fn with_source(mut self, src_ip: Option<IpAddr>, src_port: u16) -> Self {
+        self.src_ip = src_ip;
+        self.src_port = src_port;
+        self
+    }
+
+    /// Set the app that opened the connection
+    pub fn with_process(self, process: Option<&'a str>) -> Self {
+        Self { process, ..self }
+    }
+
+    /// The same connection under another hostname
+    pub fn with_domain(self, domain: Option<&'a str>) -> Self {
+        Self { domain, ..self }
+    }
+}
+
 /// A single routing rule
 #[derive(Debug, Clone)]
 pub struct Rule {
@@ -45,6 +114,10 @@ pub struct Rule {
     pub action: RouteAction,
     /// Optional rule name/comment
     pub name: Option<String>,
+    /// Disable Nagle and delayed ACKs for matched TCP flows
+    pub nodelay: bool,
+    /// Bandwidth cap in bytes per second, shared by all matched flows
+    pub rate_limit: Option<u64>,
 }
 
 impl Rule {
@@ -54,6 +127,8 @@ impl Rule {
             rule_type,
             action,
             name: None,
+            nodelay: false,
+            rate_limit: None,
         }
     }
 
@@ -63,12 +138,48 @@ impl Rule {
             rule_type,
             action,
             name: Some(name.into()),
+            nodelay: false,
+            rate_limit: None,
         }
     }
 
+    /// Name reported for flows this rule matched: its own name, or else
+    /// the rule as written, e.g. `DOMAIN-SUFFIX,google.com`
+    pub fn label(&self) -> String {
+        self.name.clone().unwrap_or_else(|| self.rule_type.to_string())
+    }
+
     /// Check if this rule matches the given connection
-    pub fn matches(&self, domain: Option<&str>, ip: Option<IpAddr>, dst_port: u16, src_port: u16) -> bool {
-        match &self.rule_type {
+    pub fn matches(&self, ctx: &MatchContext) -> bool {
+        self.rule_type.matches(ctx)
+    }
+}
+
+/// The rule type and value as written in rules, e.g. `IP-CIDR,10.0.0.0/8`
+impl fmt::Display for RuleType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuleType::Domain(domain) => write!(f, "DOMAIN,{}", domain),
+            RuleType::DomainSuffix(suffix) => write!(f, "DOMAIN-SUFFIX,{}", suffix),
+            RuleType::DomainKeyword(keyword) => write!(f, "DOMAIN-KEYWORD,{}", keyword),
+            RuleType::IpCidr(network, prefix) => write!(f, "IP-CIDR,{}/{}", network, prefix),
+            RuleType::IpCidr6(network, prefix) => write!(f, "IP-CIDR6,{}/{}", network, prefix),
+            RuleType::DstPort(port) => write!(f, "DST-PORT,{}", port),
+            RuleType::SrcPort(port) => write!(f, "SRC-PORT,{}", port),
+            RuleType::SrcIpCidr(network, prefix) => {
+                write!(f, "SRC-IP-CIDR,{}/{}", network, prefix)
+            }
+            RuleType::GeoSite(site) => write!(f, "GEOSITE,{}", site.spec),
+            RuleType::Final => f.write_str("FINAL"),
+        }
+    }
+}
+
+impl RuleType {
+    /// Check if this rule type matches the given connection
+    pub fn matches(&self, ctx: &MatchContext) -> bool {
+        let MatchContext { domain, dst_ip: ip, dst_port, src_ip, src_port, .. } = *ctx;
+        match self {
             RuleType::Domain(d) => domain.map(|h| h.eq_ignore_ascii_case(d)).unwrap_or(false),
             
             RuleType::DomainSuffix(suffix) => {
@@ -90,17 +201,84 @@ impl Rule {
                     false
                 }
             }
+
+            RuleType::IpCidr6(network, prefix_len) => {
+                if let Some(IpAddr::V6(addr)) = ip {
+                    ipv6_in_cidr(addr, *network, *prefix_len)
+                } else {
+                    false
+                }
+            }
             
             RuleType::DstPort(port) => dst_port == *port,
             
             RuleType::SrcPort(port) => src_port == *port,
+
+            RuleType::SrcIpCidr(network, prefix_len) => {
+                src_ip.is_some_and(|addr| ip_in_network(addr, *network, *prefix_len))
+            }
+
+            RuleType::GeoSite(site) => domain.is_some_and(|h| site.matches(h)),
             
             RuleType::Final => true,
         }
     }
+
+    /// Whether every connection `other` matches is also matched by this
+    /// rule type, so a rule of type `other` placed after it never fires
+    pub fn covers(&self, other: &RuleType) -> bool {
+        let lower = |s: &str| s.to_ascii_lowercase();
+        match (self, other) {
+            (RuleType::Final, _) => true,
+            (RuleType::Domain(a), RuleType::Domain(b)) => a.eq_ignore_ascii_case(b),
+            (RuleType::DomainSuffix(_) | RuleType::DomainKeyword(_), RuleType::Domain(d)) => {
+                self.matches(&MatchContext::new(Some(d), None, 0))
+            }
+            (RuleType::DomainSuffix(a), RuleType::DomainSuffix(b)) => {
+                lower(b).ends_with(&lower(a))
+                    && self.matches(&MatchContext::new(Some(b.trim_start_matches('.')), None, 0))
+            }
+            (
+                RuleType::DomainKeyword(keyword),
+                RuleType::DomainSuffix(b) | RuleType::DomainKeyword(b),
+            ) => lower(b.trim_start_matches('.')).contains(&lower(keyword)),
+            (RuleType::IpCidr(a, p), RuleType::IpCidr(b, q)) => p <= q && ip_in_cidr(*b, *a, *p),
+            (RuleType::IpCidr6(a, p), RuleType::IpCidr6(b, q)) => {
+                p <= q && ipv6_in_cidr(*b, *a, *p)
+            }
+            (RuleType::DstPort(a), RuleType::DstPort(b))
+            | (RuleType::SrcPort(a), RuleType::SrcPort(b)) => a == b,
+            (RuleType::SrcIpCidr(a, p), RuleType::SrcIpCidr(b, q)) => {
+                p <= q && ip_in_network(*b, *a, *p)
+            }
+            (RuleType::GeoSite(a), RuleType::GeoSite(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+/// Check if an address is within a CIDR range of either family
+fn ip_in_network(addr: IpAddr, network: IpAddr, prefix_len: u8) -> bool {
+    match (addr, network) {
+        (IpAddr::V4(addr), IpAddr::V4(network)) => ip_in_cidr(addr, network, prefix_len),
+        (IpAddr::V6(addr), IpAddr::V6(network)) => ipv6_in_cidr(addr, network, prefix_len),
+        _ => false,
+    }
 }
 
 /// Check if an IP address is within a CIDR range
+fn ipv6_in_cidr(addr: Ipv6Addr, network: Ipv6Addr, prefix_len: u8) -> bool {
+    if prefix_len == 0 {
+        return true;
+    }
+    if prefix_len > 128 {
+        return false;
+    }
+
+    let mask = !0u128 << (128 - prefix_len);
+    (u128::from(addr) & mask) == (u128::from(network) & mask)
+}
+
 fn ip_in_cidr(addr: Ipv4Addr, network: Ipv4Addr, prefix_len: u8) -> bool {
     if prefix_len == 0 {
         return true;
@@ -122,6 +300,8 @@ pub struct RuleEngine {
     rules: Vec<Rule>,
     /// Default action when no rule matches
     default_action: RouteAction,
+    /// Categories of GEOSITE rules
+    geosite: Option<Arc<GeoSiteDb>>,
 }
 
 impl RuleEngine {
@@ -130,6 +310,7 @@ impl RuleEngine {
         Self {
             rules: Vec::new(),
             default_action: RouteAction::Direct,
+            geosite: None,
         }
     }
 
@@ -138,17 +319,54 @@ impl RuleEngine {
         Self {
             rules: Vec::new(),
             default_action,
+            geosite: None,
         }
     }
 
     /// Add a rule to the engine
-    pub fn add_rule(&mut self, rule: Rule) {
+    pub fn add_rule(&mut self, mut rule: Rule) {
+        self.resolve(&mut rule);
         self.rules.push(rule);
     }
 
     /// Add multiple rules
     pub fn add_rules(&mut self, rules: impl IntoIterator<Item = Rule>) {
-        self.rules.extend(rules);
+        for rule in rules {
+            self.add_rule(rule);
+        }
+    }
+
+    /// Match GEOSITE rules, present and future, against `db`; without a
+    /// database they match nothing. Returns the categories `db` lacks.
+    pub fn set_geosite(&mut self, db: Option<Arc<GeoSiteDb>>) -> Vec<String> {
+        self.geosite = db;
+        let mut missing = Vec::new();
+        let mut rules = std::mem::take(&mut self.rules);
+        for rule in &mut rules {
+            if let Some(spec) = self.resolve(rule) {
+                missing.push(spec);
+            }
+        }
+        self.rules = rules;
+        missing
+    }
+
+    /// Database GEOSITE rules are matched against
+    pub fn geosite(&self) -> Option<&Arc<GeoSiteDb>> {
+        self.geosite.as_ref()
+    }
+
+    /// Compile `rule`'s category if it is a GEOSITE rule, returning the
+    /// category if the database lacks it
+    fn resolve(&self, rule: &mut Rule) -> Option<String> {
+        let RuleType::GeoSite(site) = &mut rule.rule_type else {
+            return None;
+        };
+        if site.resolve(self.geosite.as_deref()) {
+            return None;
+        }
+        log::warn!("GEOSITE category {} is not in the database", site.spec);
+        Some(site.spec.clone())
     }
 
     /// Clear all rules
@@ -167,20 +385,42 @@ impl RuleEngine {
     }
 
     /// Evaluate rules for a connection and return the action
-    pub fn evaluate(&self, domain: Option<&str>, ip: Option<IpAddr>, dst_port: u16, src_port: u16) -> RouteAction {
-        for rule in &self.rules {
-            if rule.matches(domain, ip, dst_port, src_port) {
-                return rule.action.clone();
-            }
-        }
-        self.default_action.clone()
+    pub fn evaluate(&self, ctx: &MatchContext) -> RouteAction {
+        self.find_match(ctx)
+            .map(|rule| rule.action.clone())
+            .unwrap_or_else(|| self.default_action.clone())
+    }
+
+    /// Find the first rule matching a connection
+    pub fn find_match(&self, ctx: &MatchContext) -> Option<&Rule> {
+        self.rules.iter().find(|rule| rule.matches(ctx))
+    }
+
+    /// Get the action used when no rule matches
+    pub fn default_action(&self) -> &RouteAction {
+        &self.default_action
     }
 
     /// Load rules from a Surge-style configuration string
     pub fn load_from_config(&mut self, config: &str) -> Result<usize, String> {
-        let mut count = 0;
+        let rules = Self::parse_lines(config).map_err(|(_, detail)| detail)?;
+        let count = rules.len();
+        self.add_rules(rules);
+        Ok(count)
+    }
+
+    /// Parse rules from a configuration string without loading them, so
+    /// callers can parse before taking a lock.
+    ///
+    /// Errors carry the 1-based number of the offending line.
+    pub fn parse_config(config: &str) -> Result<Vec<Rule>, VoyageError> {
+        Self::parse_lines(config).map_err(|(line, detail)| VoyageError::rule_syntax(line, detail))
+    }
+
+    fn parse_lines(config: &str) -> Result<Vec<Rule>, (u32, String)> {
+        let mut rules = Vec::new();
 
-        for line in config.lines() {
+        for (index, line) in config.lines().enumerate() {
             let line = line.trim();
 
             // Skip empty lines and comments
@@ -188,13 +428,14 @@ impl RuleEngine {
                 continue;
             }
 
-            if let Some(rule) = Self::parse_rule_line(line)? {
-                self.add_rule(rule);
-                count += 1;
+            match Self::parse_rule_line(line) {
+                Ok(Some(rule)) => rules.push(rule),
+                Ok(None) => {}
+                Err(detail) => return Err((index as u32 + 1, detail)),
             }
         }
 
-        Ok(count)
+        Ok(rules)
     }
 
     /// Parse a single rule line
@@ -206,7 +447,12 @@ impl RuleEngine {
         }
 
         let rule_type_str = parts[0].to_uppercase();
-        let action = Self::parse_action(parts.last().unwrap())?;
+
+        // FINAL has no value field; every other rule is TYPE, VALUE, ACTION.
+        // Anything after the action is a rule option.
+        let action_index = if rule_type_str == "FINAL" { 1 } else { 2 };
+        let action = Self::parse_action(parts.get(action_index).unwrap_or(parts.last().unwrap()))?;
+        let options = parts.get(action_index + 1..).unwrap_or(&[]);
 
         let rule_type = match rule_type_str.as_str() {
             "DOMAIN" => {
@@ -231,16 +477,17 @@ impl RuleEngine {
                 if parts.len() < 3 {
                     return Err("IP-CIDR rule requires a CIDR".into());
                 }
-                let cidr_parts: Vec<&str> = parts[1].split('/').collect();
-                if cidr_parts.len() != 2 {
-                    return Err(format!("Invalid CIDR format: {}", parts[1]));
+                match Self::parse_cidr(parts[1])? {
+                    (IpAddr::V4(ip), prefix) => RuleType::IpCidr(ip, prefix),
+                    (IpAddr::V6(ip), prefix) => RuleType::IpCidr6(ip, prefix),
                 }
-                let ip = Ipv4Addr::from_str(cidr_parts[0])
-                    .map_err(|e| format!("Invalid IP: {}", e))?;
-                let prefix: u8 = cidr_parts[1]
-                    .parse()
-                    .map_err(|e| format!("Invalid prefix length: {}", e))?;
-                RuleType::IpCidr(ip, prefix)
+            }
+            "SRC-IP-CIDR" => {
+                if parts.len() < 3 {
+                    return Err("SRC-IP-CIDR rule requires a CIDR".into());
+                }
+                let (ip, prefix) = Self::parse_cidr(parts[1])?;
+                RuleType::SrcIpCidr(ip, prefix)
             }
             "DST-PORT" => {
                 if parts.len() < 3 {
@@ -260,11 +507,48 @@ impl RuleEngine {
                     .map_err(|e| format!("Invalid port: {}", e))?;
                 RuleType::SrcPort(port)
             }
+            "GEOSITE" => {
+                if parts.len() < 3 {
+                    return Err("GEOSITE rule requires a category".into());
+                }
+                RuleType::GeoSite(GeoSite::new(parts[1]))
+            }
             "FINAL" => RuleType::Final,
             _ => return Err(format!("Unknown rule type: {}", rule_type_str)),
         };
 
-        Ok(Some(Rule::new(rule_type, action)))
+        let mut rule = Rule::new(rule_type, action);
+        for option in options {
+            let (name, value) = match option.split_once('=') {
+                Some((name, value)) => (name.trim(), Some(value.trim())),
+                None => (*option, None),
+            };
+            match (name.to_ascii_lowercase().as_str(), value) {
+                ("nodelay", None) => rule.nodelay = true,
+                ("rate-limit", Some(rate)) => rule.rate_limit = Some(shaping::parse_rate(rate)?),
+                ("", None) => {}
+                _ => return Err(format!("Unknown rule option: {}", option)),
+            }
+        }
+
+        Ok(Some(rule))
+    }
+
+    /// Parse an `address/prefix` range of either family
+    fn parse_cidr(cidr: &str) -> Result<(IpAddr, u8), String> {
+        let cidr_parts: Vec<&str> = cidr.split('/').collect();
+        if cidr_parts.len() != 2 {
+            return Err(format!("Invalid CIDR format: {}", cidr));
+        }
+        let ip = IpAddr::from_str(cidr_parts[0]).map_err(|e| format!("Invalid IP: {}", e))?;
+        let prefix: u8 = cidr_parts[1]
+            .parse()
+            .map_err(|e| format!("Invalid prefix length: {}", e))?;
+        let max_prefix = if ip.is_ipv4() { 32 } else { 128 };
+        if prefix > max_prefix {
+            return Err(format!("Prefix length out of range: {}", cidr));
+        }
+        Ok((ip, prefix))
     }
 
     /// Parse action string
@@ -326,32 +610,32 @@ mod tests {
     fn test_domain_match() {
         let rule = Rule::new(RuleType::Domain("example.com".into()), RouteAction::Proxy);
 
-        assert!(rule.matches(Some("example.com"), None, 443, 0));
-        assert!(rule.matches(Some("EXAMPLE.COM"), None, 443, 0));
-        assert!(!rule.matches(Some("www.example.com"), None, 443, 0));
-        assert!(!rule.matches(Some("example.org"), None, 443, 0));
-        assert!(!rule.matches(None, None, 443, 0));
+        assert!(rule.matches(&MatchContext::new(Some("example.com"), None, 443)));
+        assert!(rule.matches(&MatchContext::new(Some("EXAMPLE.COM"), None, 443)));
+        assert!(!rule.matches(&MatchContext::new(Some("www.example.com"), None, 443)));
+        assert!(!rule.matches(&MatchContext::new(Some("example.org"), None, 443)));
+        assert!(!rule.matches(&MatchContext::new(None, None, 443)));
     }
 
     #[test]
     fn test_domain_suffix_match() {
         let rule = Rule::new(RuleType::DomainSuffix(".google.com".into()), RouteAction::Proxy);
 
-        assert!(rule.matches(Some("www.google.com"), None, 443, 0));
-        assert!(rule.matches(Some("mail.google.com"), None, 443, 0));
-        assert!(rule.matches(Some("google.com"), None, 443, 0));
-        assert!(!rule.matches(Some("google.org"), None, 443, 0));
-        assert!(!rule.matches(Some("notgoogle.com"), None, 443, 0));
+        assert!(rule.matches(&MatchContext::new(Some("www.google.com"), None, 443)));
+        assert!(rule.matches(&MatchContext::new(Some("mail.google.com"), None, 443)));
+        assert!(rule.matches(&MatchContext::new(Some("google.com"), None, 443)));
+        assert!(!rule.matches(&MatchContext::new(Some("google.org"), None, 443)));
+        assert!(!rule.matches(&MatchContext::new(Some("notgoogle.com"), None, 443)));
     }
 
     #[test]
     fn test_domain_keyword_match() {
         let rule = Rule::new(RuleType::DomainKeyword("google".into()), RouteAction::Proxy);
 
-        assert!(rule.matches(Some("www.google.com"), None, 443, 0));
-        assert!(rule.matches(Some("google.co.jp"), None, 443, 0));
-        assert!(rule.matches(Some("googleapis.com"), None, 443, 0));
-        assert!(!rule.matches(Some("example.com"), None, 443, 0));
+        assert!(rule.matches(&MatchContext::new(Some("www.google.com"), None, 443)));
+        assert!(rule.matches(&MatchContext::new(Some("google.co.jp"), None, 443)));
+        assert!(rule.matches(&MatchContext::new(Some("googleapis.com"), None, 443)));
+        assert!(!rule.matches(&MatchContext::new(Some("example.com"), None, 443)));
     }
 
     #[test]
@@ -362,49 +646,84 @@ mod tests {
         );
 
         assert!(rule.matches(
-            None,
-            Some(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1))),
-            443,
-            0
+            &MatchContext::new(None, Some(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1))), 443),
         ));
         assert!(rule.matches(
-            None,
-            Some(IpAddr::V4(Ipv4Addr::new(192, 168, 255, 255))),
-            443,
-            0
+            &MatchContext::new(None, Some(IpAddr::V4(Ipv4Addr::new(192, 168, 255, 255))), 443),
         ));
         assert!(!rule.matches(
-            None,
-            Some(IpAddr::V4(Ipv4Addr::new(192, 169, 0, 1))),
-            443,
-            0
+            &MatchContext::new(None, Some(IpAddr::V4(Ipv4Addr::new(192, 169, 0, 1))), 443),
         ));
         assert!(!rule.matches(
-            None,
-            Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))),
-            443,
-            0
+            &MatchContext::new(None, Some(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))), 443),
         ));
     }
 
+    #[test]
+    fn test_ip_cidr6_match() {
+        let rule = RuleEngine::parse_rule_line("IP-CIDR6, 2001:db8::/32, PROXY")
+            .unwrap()
+            .unwrap();
+        assert_eq!(rule.rule_type, RuleType::IpCidr6("2001:db8::".parse().unwrap(), 32));
+
+        let to = |ip: &str| MatchContext::new(None, Some(ip.parse().unwrap()), 443);
+        assert!(rule.matches(&to("2001:db8:1::1")));
+        assert!(!rule.matches(&to("2001:db9::1")));
+        // Mapped IPv4 addresses are not covered by IPv6 ranges
+        assert!(!rule.matches(&to("32.1.13.184")));
+    }
+
     #[test]
     fn test_port_match() {
         let dst_rule = Rule::new(RuleType::DstPort(443), RouteAction::Direct);
         let src_rule = Rule::new(RuleType::SrcPort(8080), RouteAction::Proxy);
 
-        assert!(dst_rule.matches(None, None, 443, 0));
-        assert!(!dst_rule.matches(None, None, 80, 0));
+        assert!(dst_rule.matches(&MatchContext::new(None, None, 443)));
+        assert!(!dst_rule.matches(&MatchContext::new(None, None, 80)));
+
+        assert!(src_rule.matches(&MatchContext::new(None, None, 443).with_source(None, 8080)));
+        assert!(!src_rule.matches(&MatchContext::new(None, None, 443).with_source(None, 9000)));
+    }
+
+    #[test]
+    fn test_src_ip_cidr_match() {
+        let rule = RuleEngine::parse_rule_line("SRC-IP-CIDR, 10.8.0.0/24, PROXY")
+            .unwrap()
+            .unwrap();
+        assert_eq!(rule.rule_type, RuleType::SrcIpCidr("10.8.0.0".parse().unwrap(), 24));
 
-        assert!(src_rule.matches(None, None, 443, 8080));
-        assert!(!src_rule.matches(None, None, 443, 9000));
+        let dst = Some("1.1.1.1".parse().unwrap());
+        assert!(rule.matches(
+            &MatchContext::new(None, dst, 443).with_source(Some("10.8.0.5".parse().unwrap()), 0),
+        ));
+        assert!(!rule.matches(
+            &MatchContext::new(None, dst, 443).with_source(Some("10.9.0.5".parse().unwrap()), 0),
+        ));
+        // The destination address is not considered
+        assert!(!rule.matches(&MatchContext::new(None, Some("10.8.0.5".parse().unwrap()), 443)));
+        assert!(!rule.matches(
+            &MatchContext::new(None, dst, 443).with_source(Some("fd00::5".parse().unwrap()), 0),
+        ));
+
+        let rule = RuleEngine::parse_rule_line("SRC-IP-CIDR, fd00:1::/64, DIRECT")
+            .unwrap()
+            .unwrap();
+        assert!(rule.matches(
+            &MatchContext::new(None, dst, 443).with_source(Some("fd00:1::5".parse().unwrap()), 0),
+        ));
+        assert!(!rule.matches(
+            &MatchContext::new(None, dst, 443).with_source(Some("fd00:2::5".parse().unwrap()), 0),
+        ));
     }
 
     #[test]
     fn test_final_match() {
         let rule = Rule::new(RuleType::Final, RouteAction::Proxy);
 
-        assert!(rule.matches(None, None, 0, 0));
-        assert!(rule.matches(Some("anything"), Some(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4))), 443, 8080));
+        assert!(rule.matches(&MatchContext::new(None, None, 0)));
+        let ip = Some(IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4)));
+        let flow = MatchContext::new(Some("anything"), ip, 443).with_source(None, 8080);
+        assert!(rule.matches(&flow));
     }
 
     #[test]
@@ -422,15 +741,21 @@ mod tests {
         engine.add_rule(Rule::new(RuleType::Final, RouteAction::Proxy));
 
         assert_eq!(
-            engine.evaluate(Some("www.google.com"), None, 443, 0),
+            engine.evaluate(&MatchContext::new(Some("www.google.com"), None, 443)),
             RouteAction::Proxy
         );
         assert_eq!(
-            engine.evaluate(None, Some(IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3))), 443, 0),
+            engine.evaluate(
+                &MatchContext::new(None, Some(IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3))), 443),
+            ),
             RouteAction::Direct
         );
         assert_eq!(
-            engine.evaluate(Some("example.com"), Some(IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8))), 443, 0),
+            engine.evaluate(&MatchContext::new(
+                Some("example.com"),
+                Some(IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8))),
+                443
+            )),
             RouteAction::Proxy
         );
     }
@@ -533,6 +858,104 @@ FINAL, DIRECT
         // Missing action
         let result = engine.load_from_config("DOMAIN");
         assert!(result.is_err());
+
+        // Prefix longer than the address
+        assert!(engine.load_from_config("IP-CIDR, 10.0.0.0/33, DIRECT").is_err());
+        assert!(engine.load_from_config("IP-CIDR6, ::/129, DIRECT").is_err());
+        assert!(engine.load_from_config("SRC-IP-CIDR, 10.8.0.0/33, DIRECT").is_err());
+        assert!(engine.load_from_config("SRC-IP-CIDR, 10.8.0.0, DIRECT").is_err());
+
+        let err = RuleEngine::parse_config("# rules\nDOMAIN, a.com, DIRECT\n\nDOMAIN").unwrap_err();
+        assert!(matches!(err, VoyageError::RuleSyntax { line: 4, .. }));
+    }
+
+    #[test]
+    fn test_rule_label() {
+        let lines = [
+            "DOMAIN,a.com",
+            "DOMAIN-SUFFIX,google.com",
+            "DOMAIN-KEYWORD,ads",
+            "IP-CIDR,10.0.0.0/8",
+            "IP-CIDR6,fd00::/8",
+            "DST-PORT,443",
+            "SRC-PORT,5000",
+            "SRC-IP-CIDR,10.8.0.0/16",
+            "GEOSITE,category-ads-all",
+            "FINAL",
+        ];
+        let config: Vec<String> = lines.iter().map(|line| format!("{},DIRECT", line)).collect();
+        let rules = RuleEngine::parse_config(&config.join("\n")).unwrap();
+        let labels: Vec<String> = rules.iter().map(Rule::label).collect();
+        assert_eq!(labels, lines);
+
+        let named = Rule::with_name(RuleType::Final, RouteAction::Direct, "catch-all");
+        assert_eq!(named.label(), "catch-all");
+    }
+
+    #[test]
+    fn test_rule_covers() {
+        let suffix = RuleType::DomainSuffix("google.com".into());
+        assert!(suffix.covers(&RuleType::Domain("www.google.com".into())));
+        assert!(suffix.covers(&RuleType::DomainSuffix("mail.google.com".into())));
+        assert!(!suffix.covers(&RuleType::DomainSuffix("com".into())));
+        assert!(RuleType::DomainKeyword("goog".into()).covers(&suffix));
+
+        let wide = RuleType::IpCidr(Ipv4Addr::new(10, 0, 0, 0), 8);
+        let narrow = RuleType::IpCidr(Ipv4Addr::new(10, 1, 0, 0), 16);
+        assert!(wide.covers(&narrow));
+        assert!(!narrow.covers(&wide));
+        let wide = RuleType::SrcIpCidr("10.8.0.0".parse().unwrap(), 16);
+        let narrow = RuleType::SrcIpCidr("10.8.1.0".parse().unwrap(), 24);
+        assert!(wide.covers(&narrow));
+        assert!(!narrow.covers(&wide));
+        assert!(!wide.covers(&RuleType::SrcIpCidr("fd00::".parse().unwrap(), 16)));
+        assert!(RuleType::Final.covers(&RuleType::DstPort(443)));
+        assert!(!RuleType::DstPort(443).covers(&RuleType::DstPort(80)));
+        assert!(!RuleType::DstPort(443).covers(&RuleType::Final));
+    }
+
+    #[test]
+    fn test_parse_nodelay_option() {
+        let mut engine = RuleEngine::new();
+        engine
+            .load_from_config(
+                r#"
+DOMAIN-SUFFIX, game.example.com, PROXY, nodelay
+DOMAIN, example.com, DIRECT
+FINAL, DIRECT, nodelay
+"#,
+            )
+            .unwrap();
+
+        assert!(engine.rules()[0].nodelay);
+        assert_eq!(engine.rules()[0].action, RouteAction::Proxy);
+        assert!(!engine.rules()[1].nodelay);
+        assert!(engine.rules()[2].nodelay);
+
+        let matched = engine
+            .find_match(&MatchContext::new(Some("eu.game.example.com"), None, 443))
+            .unwrap();
+        assert!(matched.nodelay);
+
+        assert!(engine
+            .load_from_config("DOMAIN, example.com, DIRECT, bogus")
+            .is_err());
+    }
+
+    #[test]
+    fn test_parse_rate_limit_option() {
+        let rules = RuleEngine::parse_config(
+            "DOMAIN-SUFFIX, example.com, PROXY, nodelay, rate-limit = 2MB/s\nFINAL, DIRECT",
+        )
+        .unwrap();
+        assert_eq!(rules[0].rate_limit, Some(2_000_000));
+        assert!(rules[0].nodelay);
+        assert_eq!(rules[1].rate_limit, None);
+
+        for bad in ["rate-limit", "rate-limit=fast", "nodelay=1"] {
+            let line = format!("DOMAIN, example.com, DIRECT, {}", bad);
+            assert!(RuleEngine::parse_config(&line).is_err(), "{}", line);
+        }
     }
 
     #[test]
@@ -0,0 +1,89 @@
+//! Secrets
+//!
+//! This module provides `SecretString`, the type proxy passwords are kept
+//! in. Its `Debug` and `Display` output is masked, so a credential cannot
+//! reach the extension's logs through a `{:?}` of the structure holding
+//! it; code that needs the value asks for it with `expose`.
+
+use std::fmt;
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Text shown in place of a secret
+pub const REDACTED: &str = "***";
+
+/// A credential that never shows up in formatted output
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct SecretString(String);
+
+impl SecretString {
+    pub fn new(secret: impl Into<String>) -> Self {
+        Self(secret.into())
+    }
+
+    /// The secret itself, for sending it to the server it is meant for
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(secret: String) -> Self {
+        Self(secret)
+    }
+}
+
+impl From<&str> for SecretString {
+    fn from(secret: &str) -> Self {
+        Self(secret.to_string())
+    }
+}
+
+impl fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SecretString({})", REDACTED)
+    }
+}
+
+impl fmt::Display for SecretString {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(REDACTED)
+    }
+}
+
+/// Configuration files hold the real value, so serialization exposes it
+impl Serialize for SecretString {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretString {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret_is_masked() {
+        let secret = SecretString::from("hunter2");
+        assert_eq!(secret.expose(), "hunter2");
+        assert_eq!(format!("{}", secret), "***");
+        assert_eq!(format!("{:?}", Some(&secret)), "Some(SecretString(***))");
+
+        let yaml = serde_yaml::to_string(&secret).unwrap();
+        assert_eq!(serde_yaml::from_str::<SecretString>(&yaml).unwrap(), secret);
+    }
+}
@@ -0,0 +1,203 @@
+//! Startup Self-Test
+//!
+//! This module runs fast internal checks the app can trigger after an
+//! update to catch a broken build before the tunnel is brought up.
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use smoltcp::phy::{Device, RxToken, TxToken};
+
+use crate::device::VirtualTunDevice;
+use crate::dns::{self, DnsMessage, DnsQuestion, CLASS_IN, TYPE_A};
+use crate::nat::{NatKey, NatManager};
+use crate::packet::{build_udp_packet, ParsedPacket};
+use crate::rule::{MatchContext, RouteAction, RuleEngine};
+
+/// Timeout for the resolver reachability probe
+pub const RESOLVER_PROBE_TIMEOUT_MS: u64 = 1500;
+
+/// Outcome of a single check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SelfTestResult {
+    /// Check name, e.g. `packet_parse`
+    pub name: String,
+    /// Whether the check passed
+    pub passed: bool,
+    /// Failure reason (empty when passed)
+    pub detail: String,
+    /// Time the check took
+    pub duration_ms: u64,
+}
+
+fn run_check(name: &str, check: impl FnOnce() -> Result<(), String>) -> SelfTestResult {
+    let started = Instant::now();
+    let outcome = check();
+    let duration_ms = started.elapsed().as_millis() as u64;
+    if let Err(reason) = &outcome {
+        log::warn!("Self-test {} failed: {}", name, reason);
+    }
+    SelfTestResult {
+        name: name.to_string(),
+        passed: outcome.is_ok(),
+        detail: outcome.err().unwrap_or_default(),
+        duration_ms,
+    }
+}
+
+fn ensure(condition: bool, reason: &str) -> Result<(), String> {
+    if condition {
+        Ok(())
+    } else {
+        Err(reason.to_string())
+    }
+}
+
+/// Build a packet, parse it back and compare the addresses and payload
+fn check_packet_parse() -> Result<(), String> {
+    let tcp = crate::create_tcp_packet([10, 0, 0, 1], [93, 184, 216, 34], 40000, 443, true);
+    let parsed = ParsedPacket::parse(&tcp).map_err(|e| e.to_string())?;
+    ensure(parsed.is_tcp_syn(), "TCP SYN flag lost")?;
+    ensure(
+        parsed.dst_addr() == Some("93.184.216.34:443".parse().unwrap()),
+        "TCP destination mismatch",
+    )?;
+
+    let src: SocketAddr = "10.0.0.1:5353".parse().unwrap();
+    let dst: SocketAddr = "198.18.0.2:53".parse().unwrap();
+    let udp = build_udp_packet(src, dst, b"voyage").ok_or("UDP build failed")?;
+    let parsed = ParsedPacket::parse(&udp).map_err(|e| e.to_string())?;
+    ensure(parsed.src_addr() == Some(src), "UDP source mismatch")?;
+    ensure(
+        parsed.udp_payload(&udp) == Some(&b"voyage"[..]),
+        "UDP payload mismatch",
+    )
+}
+
+/// Load a small ruleset and verify first-match semantics
+fn check_rule_engine() -> Result<(), String> {
+    let mut engine = RuleEngine::new();
+    engine.load_from_config(
+        "DOMAIN-SUFFIX, example.com, PROXY\n\
+         DOMAIN-KEYWORD, ads, REJECT\n\
+         IP-CIDR, 192.168.0.0/16, DIRECT\n\
+         FINAL, PROXY",
+    )?;
+    ensure(engine.len() == 4, "Unexpected rule count")?;
+
+    let cases = [
+        (Some("www.example.com"), None, RouteAction::Proxy),
+        (Some("ads.tracker.net"), None, RouteAction::Reject),
+        (None, Some("192.168.1.1".parse().unwrap()), RouteAction::Direct),
+        (Some("other.org"), None, RouteAction::Proxy),
+    ];
+    for (domain, ip, expected) in cases {
+        let action = engine.evaluate(&MatchContext::new(domain, ip, 443));
+        ensure(
+            action == expected,
+            &format!("{:?}/{:?} routed to {:?}", domain, ip, action),
+        )?;
+    }
+    Ok(())
+}
+
+/// Allocate NAT entries and check ports are distinct and reversible
+fn check_nat_allocation() -> Result<(), String> {
+    let mut nat = NatManager::new();
+    let src: SocketAddr = "10.0.0.1:40000".parse().unwrap();
+    let a = NatKey::tcp(src, "1.1.1.1:443".parse().unwrap());
+    let b = NatKey::tcp(src, "8.8.8.8:443".parse().unwrap());
+
+    let port_a = nat.get_or_create(a).map_err(|e| e.to_string())?.local_port;
+    let port_b = nat.get_or_create(b).map_err(|e| e.to_string())?.local_port;
+    ensure(port_a != port_b, "NAT reused a local port")?;
+    ensure(nat.get_key_by_port(port_a) == Some(&a), "NAT reverse lookup failed")?;
+    ensure(nat.remove(&a).is_some() && nat.len() == 1, "NAT removal failed")
+}
+
+/// Push a packet through the virtual device in both directions
+fn check_device_queue() -> Result<(), String> {
+    let mut device = VirtualTunDevice::new();
+    let packet = crate::create_tcp_packet([10, 0, 0, 1], [1, 1, 1, 1], 40000, 80, true);
+    device.inject_packet(packet.clone());
+
+    let now = smoltcp::time::Instant::from_millis(0);
+    let (rx, _) = device.receive(now).ok_or("No packet received")?;
+    let received = rx.consume(|buf| buf.to_vec());
+    ensure(received == packet, "Received packet was modified")?;
+
+    let tx = device.transmit(now).ok_or("No transmit token")?;
+    tx.consume(packet.len(), |buf| buf.copy_from_slice(&packet));
+    let sent = device.take_packets();
+    ensure(sent.len() == 1 && sent[0] == packet, "Transmitted packet lost")
+}
+
+/// Send a real query to the first upstream that answers
+fn check_resolver(upstreams: &[SocketAddr], timeout: Duration) -> Result<(), String> {
+    let query = DnsMessage {
+        id: 0x5E1F,
+        flags: 0x0100,
+        questions: vec![DnsQuestion {
+            name: "example.com".into(),
+            qtype: TYPE_A,
+            qclass: CLASS_IN,
+        }],
+        answers: Vec::new(),
+        authorities: Vec::new(),
+        additionals: Vec::new(),
+    };
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|e| e.to_string())?;
+    let (_, response) = rt
+        .block_on(dns::forward(upstreams, None, &query.encode(), timeout))
+        .map_err(|e| e.to_string())?;
+    let response = DnsMessage::parse(&response)?;
+    ensure(response.is_response(), "Upstream sent a non-response")
+}
+
+/// Run every check; the resolver probe uses `upstreams`
+pub fn run_all(upstreams: &[SocketAddr]) -> Vec<SelfTestResult> {
+    let timeout = Duration::from_millis(RESOLVER_PROBE_TIMEOUT_MS);
+    vec![
+        run_check("packet_parse", check_packet_parse),
+        run_check("rule_engine", check_rule_engine),
+        run_check("nat_allocation", check_nat_allocation),
+        run_check("device_queue", check_device_queue),
+        run_check("resolver_reachability", || check_resolver(upstreams, timeout)),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_offline_checks_pass() {
+        assert_eq!(check_packet_parse(), Ok(()));
+        assert_eq!(check_rule_engine(), Ok(()));
+        assert_eq!(check_nat_allocation(), Ok(()));
+        assert_eq!(check_device_queue(), Ok(()));
+    }
+
+    #[test]
+    fn test_run_check_reports_failure() {
+        let result = run_check("broken", || Err("boom".into()));
+        assert!(!result.passed);
+        assert_eq!(result.detail, "boom");
+        assert!(run_check("ok", || Ok(())).detail.is_empty());
+    }
+
+    #[test]
+    fn test_resolver_unreachable_fails() {
+        // Nothing configured to answer
+        let results = run_all(&[]);
+        assert_eq!(results.len(), 5);
+        let resolver = results.last().unwrap();
+        assert_eq!(resolver.name, "resolver_reachability");
+        assert!(!resolver.passed);
+        assert!(results[..4].iter().all(|r| r.passed));
+    }
+}
@@ -0,0 +1,405 @@
+//! Traffic Shaping
+//!
+//! This module caps bandwidth with token buckets. A limit can apply to all
+//! traffic, to every flow routed by a policy (DIRECT or PROXY), or to the
+//! flows a rule matched (`DOMAIN-SUFFIX, example.com, PROXY, rate-limit=1mbps`).
+//! Flows matched by the same rule share its bucket, so the limit caps the
+//! rule as a whole rather than each connection.
+//!
+//! The relay copy loops enforce the limits: before forwarding a chunk they
+//! ask the flow's `FlowLimiter` how long to wait. Buckets allow a burst of
+//! one second's worth of traffic, and a chunk larger than what is left is
+//! let through at once and paid back by later chunks.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::proxy::RoutingDecision;
+use crate::rate::RateMeter;
+use crate::rule::RouteAction;
+
+/// Parse a rate such as `512kbps`, `1mbps` or `2.5MB/s` into bytes per
+/// second. Lower-case `bps` units count bits, `B/s` units count bytes.
+pub fn parse_rate(text: &str) -> Result<u64, String> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("Invalid rate: {}", text))?;
+
+    let bytes_per_unit = match unit.trim() {
+        "bps" => 1.0 / 8.0,
+        "kbps" => 1e3 / 8.0,
+        "mbps" => 1e6 / 8.0,
+        "gbps" => 1e9 / 8.0,
+        "B/s" => 1.0,
+        "KB/s" => 1e3,
+        "MB/s" => 1e6,
+        "GB/s" => 1e9,
+        _ => return Err(format!("Invalid rate unit: {}", text)),
+    };
+    let rate = (number * bytes_per_unit).round() as u64;
+    if rate == 0 {
+        return Err(format!("Rate must be at least one byte per second: {}", text));
+    }
+    Ok(rate)
+}
+
+/// Bytes allowed at `rate` per second, with a burst of one second
+#[derive(Debug, Clone, Copy)]
+pub struct TokenBucket {
+    /// Bytes per second
+    rate: u64,
+    /// Bytes that may pass without waiting; negative while paying back a
+    /// chunk that exceeded them
+    tokens: f64,
+    /// When `tokens` was last brought up to date
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Create a full bucket
+    pub fn new(rate: u64, now: Instant) -> Self {
+        Self {
+            rate,
+            tokens: rate as f64,
+            updated: now,
+        }
+    }
+
+    /// Bytes per second
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    /// Change the rate, keeping what the bucket holds up to the new burst
+    pub fn set_rate(&mut self, rate: u64, now: Instant) {
+        self.refill(now);
+        self.rate = rate;
+        self.tokens = self.tokens.min(rate as f64);
+    }
+
+    /// Take `bytes` from the bucket, returning how long to wait before
+    /// sending them so the rate is kept
+    pub fn reserve(&mut self, bytes: u64, now: Instant) -> Duration {
+        self.refill(now);
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        }
+    }
+
+    /// Take `amount` from the bucket if it holds that much, without going
+    /// into debt otherwise
+    pub fn try_take(&mut self, amount: u64, now: Instant) -> bool {
+        self.refill(now);
+        if self.tokens < amount as f64 {
+            return false;
+        }
+        self.tokens -= amount as f64;
+        true
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.updated = self.updated.max(now);
+    }
+}
+
+/// What a limit applies to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShapingScope {
+    /// All relayed traffic
+    Global,
+    /// Flows routed directly
+    Direct,
+    /// Flows routed through the proxy
+    Proxy,
+    /// Flows matched by one rule
+    Rule,
+}
+
+/// Current state of one limit
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShapingStats {
+    pub scope: ShapingScope,
+    /// The rule for `ShapingScope::Rule`, otherwise the scope's name
+    pub name: String,
+    /// Configured limit in bytes per second
+    pub rate_limit: u64,
+    /// Bytes that passed through the limit
+    pub bytes: u64,
+    /// Transfers that had to wait
+    pub throttled_transfers: u64,
+    /// Total wait imposed, in milliseconds
+    pub delayed_ms: u64,
+    /// Current throughput in bytes per second
+    pub current_rate: u64,
+}
+
+#[derive(Debug)]
+struct LimiterState {
+    bucket: TokenBucket,
+    meter: RateMeter,
+    bytes: u64,
+    throttled_transfers: u64,
+    delayed: Duration,
+}
+
+/// A bucket shared by every flow the limit applies to
+#[derive(Debug)]
+struct Limiter {
+    scope: ShapingScope,
+    name: String,
+    state: Mutex<LimiterState>,
+}
+
+impl Limiter {
+    fn new(scope: ShapingScope, name: String, rate: u64, now: Instant) -> Arc<Self> {
+        Arc::new(Self {
+            scope,
+            name,
+            state: Mutex::new(LimiterState {
+                bucket: TokenBucket::new(rate, now),
+                meter: RateMeter::new(now),
+                bytes: 0,
+                throttled_transfers: 0,
+                delayed: Duration::ZERO,
+            }),
+        })
+    }
+
+    fn set_rate(&self, rate: u64, now: Instant) {
+        if let Ok(mut state) = self.state.lock() {
+            state.bucket.set_rate(rate, now);
+        }
+    }
+
+    fn reserve(&self, bytes: u64, now: Instant) -> Duration {
+        let Ok(mut state) = self.state.lock() else {
+            return Duration::ZERO;
+        };
+        let delay = state.bucket.reserve(bytes, now);
+        state.meter.record(bytes, now);
+        state.bytes += bytes;
+        if !delay.is_zero() {
+            state.throttled_transfers += 1;
+            state.delayed += delay;
+        }
+        delay
+    }
+
+    fn stats(&self, now: Instant) -> Option<ShapingStats> {
+        let state = self.state.lock().ok()?;
+        Some(ShapingStats {
+            scope: self.scope,
+            name: self.name.clone(),
+            rate_limit: state.bucket.rate(),
+            bytes: state.bytes,
+            throttled_transfers: state.throttled_transfers,
+            delayed_ms: state.delayed.as_millis() as u64,
+            current_rate: state.meter.rate(now),
+        })
+    }
+}
+
+/// The limits one flow is subject to, for its relay to consult
+#[derive(Debug, Clone, Default)]
+pub struct FlowLimiter {
+    limiters: Vec<Arc<Limiter>>,
+}
+
+impl FlowLimiter {
+    /// Whether no limit applies to the flow
+    pub fn is_unlimited(&self) -> bool {
+        self.limiters.is_empty()
+    }
+
+    /// Account for `bytes` about to be forwarded, returning how long to
+    /// wait first; the slowest applicable limit decides
+    pub fn delay(&self, bytes: u64, now: Instant) -> Duration {
+        self.limiters
+            .iter()
+            .map(|limiter| limiter.reserve(bytes, now))
+            .max()
+            .unwrap_or(Duration::ZERO)
+    }
+
+    /// Wait until `bytes` may be forwarded; called by relay copy loops
+    /// after each read
+    pub async fn throttle(&self, bytes: u64) {
+        let delay = self.delay(bytes, Instant::now());
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+/// Global, per-policy and per-rule limits
+#[derive(Debug, Default)]
+pub struct TrafficShaper {
+    global: Option<Arc<Limiter>>,
+    policies: HashMap<RouteAction, Arc<Limiter>>,
+    /// Keyed by the rule's name as it appears in routing decisions
+    rules: HashMap<String, Arc<Limiter>>,
+}
+
+impl TrafficShaper {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limit all traffic to `rate` bytes per second, or lift the limit
+    pub fn set_global_limit(&mut self, rate: Option<u64>) {
+        set_limit(&mut self.global, ShapingScope::Global, "global", rate);
+    }
+
+    /// Limit flows routed by `policy` to `rate` bytes per second, or lift
+    /// the limit. Rejected flows carry no traffic and cannot be limited.
+    pub fn set_policy_limit(&mut self, policy: RouteAction, rate: Option<u64>) {
+        let scope = match policy {
+            RouteAction::Direct => ShapingScope::Direct,
+            RouteAction::Proxy => ShapingScope::Proxy,
+            RouteAction::Reject => return,
+        };
+        let mut slot = self.policies.remove(&policy);
+        set_limit(&mut slot, scope, policy.name(), rate);
+        if let Some(limiter) = slot {
+            self.policies.insert(policy, limiter);
+        }
+    }
+
+    /// Forget per-rule limits, for when the rules are replaced. Flows
+    /// already running keep the limiter they were given.
+    pub fn clear_rule_limits(&mut self) {
+        self.rules.clear();
+    }
+
+    /// Limits for a flow routed by `decision`
+    pub fn flow_limiter(&mut self, decision: &RoutingDecision) -> FlowLimiter {
+        let now = Instant::now();
+        let mut limiters: Vec<Arc<Limiter>> = self.global.iter().cloned().collect();
+        limiters.extend(self.policies.get(&decision.action).cloned());
+
+        if let (Some(rate), Some(rule)) = (decision.rate_limit, &decision.matched_rule) {
+            let limiter = self
+                .rules
+                .entry(rule.clone())
+                .or_insert_with(|| Limiter::new(ShapingScope::Rule, rule.clone(), rate, now));
+            limiter.set_rate(rate, now);
+            limiters.push(limiter.clone());
+        }
+        FlowLimiter { limiters }
+    }
+
+    /// State of every limit: global first, then policies, then rules by name
+    pub fn stats(&self) -> Vec<ShapingStats> {
+        let now = Instant::now();
+        let mut policies: Vec<&Arc<Limiter>> = self.policies.values().collect();
+        policies.sort_by_key(|limiter| limiter.name.clone());
+        let mut rules: Vec<&Arc<Limiter>> = self.rules.values().collect();
+        rules.sort_by_key(|limiter| limiter.name.clone());
+
+        self.global
+            .iter()
+            .chain(policies)
+            .chain(rules)
+            .filter_map(|limiter| limiter.stats(now))
+            .collect()
+    }
+}
+
+fn set_limit(slot: &mut Option<Arc<Limiter>>, scope: ShapingScope, name: &str, rate: Option<u64>) {
+    let now = Instant::now();
+    match (rate.filter(|&rate| rate > 0), slot.as_ref()) {
+        (Some(rate), Some(limiter)) => limiter.set_rate(rate, now),
+        (Some(rate), None) => *slot = Some(Limiter::new(scope, name.to_string(), rate, now)),
+        (None, _) => *slot = None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("1mbps"), Ok(125_000));
+        assert_eq!(parse_rate("512kbps"), Ok(64_000));
+        assert_eq!(parse_rate("2.5MB/s"), Ok(2_500_000));
+        assert_eq!(parse_rate(" 100 B/s "), Ok(100));
+        assert!(parse_rate("fast").is_err());
+        assert!(parse_rate("10 furlongs").is_err());
+        assert!(parse_rate("0mbps").is_err());
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000, start);
+
+        // A second's worth passes at once, the rest waits for refills
+        assert_eq!(bucket.reserve(1000, start), Duration::ZERO);
+        assert_eq!(bucket.reserve(500, start), Duration::from_millis(500));
+        assert_eq!(
+            bucket.reserve(500, start + Duration::from_millis(500)),
+            Duration::from_millis(500)
+        );
+
+        // Idle time refills no more than one second's burst
+        let later = start + Duration::from_secs(10);
+        assert_eq!(bucket.reserve(1000, later), Duration::ZERO);
+        assert!(!bucket.reserve(1, later).is_zero());
+
+        // A new rate applies to refills, not to what the bucket holds
+        let later = later + Duration::from_secs(5);
+        bucket.set_rate(2000, later);
+        assert_eq!(bucket.reserve(1000, later), Duration::ZERO);
+        assert_eq!(bucket.reserve(1000, later), Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_shaper_limits() {
+        let mut shaper = TrafficShaper::new();
+        let direct = RoutingDecision::direct(443);
+        assert!(shaper.flow_limiter(&direct).is_unlimited());
+
+        shaper.set_global_limit(Some(10_000));
+        shaper.set_policy_limit(RouteAction::Proxy, Some(1000));
+        shaper.set_policy_limit(RouteAction::Reject, Some(1000));
+        let mut proxied = RoutingDecision::proxy(443).with_rule("video");
+        proxied.rate_limit = Some(100);
+
+        let now = Instant::now();
+        let first = shaper.flow_limiter(&proxied);
+        let second = shaper.flow_limiter(&proxied);
+        assert_eq!(first.delay(100, now), Duration::ZERO);
+        // Flows of one rule share its bucket
+        assert_eq!(second.delay(50, now), Duration::from_millis(500));
+        assert_eq!(shaper.flow_limiter(&direct).delay(5000, now), Duration::ZERO);
+
+        let stats = shaper.stats();
+        let scopes: Vec<_> = stats.iter().map(|s| (s.scope, s.name.as_str())).collect();
+        assert_eq!(
+            scopes,
+            [
+                (ShapingScope::Global, "global"),
+                (ShapingScope::Proxy, "PROXY"),
+                (ShapingScope::Rule, "video"),
+            ]
+        );
+        assert_eq!(stats[0].bytes, 5150);
+        assert_eq!((stats[2].throttled_transfers, stats[2].delayed_ms), (1, 500));
+
+        shaper.set_global_limit(None);
+        shaper.clear_rule_limits();
+        assert_eq!(shaper.stats().len(), 1);
+    }
+}
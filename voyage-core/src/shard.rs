@@ -0,0 +1,651 @@
+//! Sharded Connection Table
+//!
+//! This module splits the core's flows over several `ConnectionManager`s,
+//! each behind its own lock, so packets of different flows are processed in
+//! parallel and stats polling only briefly holds one shard at a time. A flow
+//! lives in the shard picked by a hash of its app-side socket, which keeps
+//! every flow of a full-cone UDP mapping, and the replies to it, in one
+//! shard. The shards share entry identifiers, local ports, per-source
+//! counts and the event bus; the connection limit and the NAT table size
+//! are enforced across all of them here.
+
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+use smoltcp::iface::{SocketHandle, SocketSet};
+use tokio::sync::broadcast;
+
+use crate::config::NatConfig;
+use crate::connection::{ConnectionInfo, ConnectionManager};
+use crate::error::VoyageError;
+use crate::event::{ConnectionEvent, EventBus};
+use crate::flowlog::FlowLogger;
+use crate::history::{CloseReason, ClosedConnection};
+use crate::iface::InterfaceManager;
+use crate::nat::{
+    NatEntry, NatKey, NatManager, NatMode, NatShared, NatState, NatTimeouts, PortStrategy,
+    DEFAULT_MAX_PORT, DEFAULT_MIN_PORT,
+};
+use crate::packet::{ParseErrorKind, ParsedPacket};
+use crate::proxy::RoutingDecision;
+use crate::rule::RouteAction;
+use crate::traffic::TrafficRecorder;
+use crate::usage::UsageTable;
+
+/// Number of independently locked shards
+pub const CONNECTION_SHARDS: usize = 8;
+
+/// Flows of the core, split over independently locked shards
+pub struct ShardedConnections {
+    shards: Vec<Mutex<ConnectionManager>>,
+    /// Picks the shard of an app socket
+    hasher: RandomState,
+    /// Live flows across all shards
+    live: AtomicUsize,
+    /// Maximum flows tracked at once
+    connection_limit: usize,
+    /// New flows refused because `connection_limit` was reached
+    connection_limit_hits: AtomicU64,
+    /// NAT table capacity across all shards
+    max_entries: usize,
+    /// Lifecycle events of every shard
+    events: EventBus,
+}
+
+impl ShardedConnections {
+    /// Create a table with default NAT settings
+    pub fn new() -> Self {
+        Self::with_nat_config(&NatConfig::default())
+    }
+
+    /// Create a table with NAT settings from the config
+    pub fn with_nat_config(config: &NatConfig) -> Self {
+        let shared = Arc::new(NatShared::default());
+        let events = EventBus::default();
+        let traffic = TrafficRecorder::default();
+        let shards = (0..CONNECTION_SHARDS)
+            .map(|_| {
+                // Each shard's NAT table is unbounded; the capacity holds
+                // across shards
+                let nat = NatManager::with_config(DEFAULT_MIN_PORT, DEFAULT_MAX_PORT, usize::MAX)
+                    .with_shared(Arc::clone(&shared))
+                    .with_udp_mode(config.udp_mode)
+                    .with_port_strategy(config.port_strategy)
+                    .with_max_per_source(config.max_per_source)
+                    .with_timeouts(config.timeouts);
+                Mutex::new(ConnectionManager::with_parts(
+                    nat,
+                    config.history_size,
+                    events.clone(),
+                    traffic.clone(),
+                ))
+            })
+            .collect();
+        Self {
+            shards,
+            hasher: RandomState::new(),
+            live: AtomicUsize::new(0),
+            connection_limit: usize::MAX,
+            connection_limit_hits: AtomicU64::new(0),
+            max_entries: config.max_entries,
+            events,
+        }
+    }
+
+    /// Process an incoming packet and get or create a connection
+    pub fn process_packet(&self, packet: &ParsedPacket) -> Result<ConnectionInfo, VoyageError> {
+        let key = packet
+            .to_nat_key()
+            .ok_or_else(|| VoyageError::packet(ParseErrorKind::NoFlow, 0))?;
+        let index = self.index(key.src_addr());
+        if self.lock(index).contains(&key) {
+            return self.with_shard(index, |shard| shard.process_packet(packet));
+        }
+
+        self.reserve()?;
+        let result = self.with_shard(index, |shard| shard.process_packet(packet));
+        self.live.fetch_sub(1, Ordering::Relaxed);
+        result
+    }
+
+    /// Account a UDP datagram from a remote on its way to the app (see
+    /// `ConnectionManager::process_reply`)
+    pub fn process_reply(&self, packet: &ParsedPacket) -> Result<ConnectionInfo, VoyageError> {
+        // Replies are addressed to the app socket the flow was hashed by
+        let index = packet
+            .to_nat_key()
+            .map_or(0, |reply| self.index(reply.dst_addr()));
+        let result = self.with_shard(index, |shard| shard.process_reply(packet));
+        while self.live.load(Ordering::Relaxed) > self.max_entries && self.evict_lru() {}
+        result
+    }
+
+    /// Make room for a new flow: refuse it at the connection limit and
+    /// evict the least recently active flows while the NAT table is full.
+    /// The reservation counts as a live flow until the caller drops it.
+    fn reserve(&self) -> Result<(), VoyageError> {
+        if self.live.fetch_add(1, Ordering::Relaxed) >= self.connection_limit {
+            self.live.fetch_sub(1, Ordering::Relaxed);
+            self.connection_limit_hits.fetch_add(1, Ordering::Relaxed);
+            return Err(VoyageError::connection(format!(
+                "Connection limit of {} reached",
+                self.connection_limit
+            )));
+        }
+        while self.live.load(Ordering::Relaxed) > self.max_entries && self.evict_lru() {}
+        Ok(())
+    }
+
+    /// Evict the least recently active flow of all shards
+    fn evict_lru(&self) -> bool {
+        let oldest = (0..self.shards.len())
+            .filter_map(|index| Some((self.lock(index).least_recent_activity()?, index)))
+            .min();
+        match oldest {
+            Some((_, index)) => self.with_shard(index, ConnectionManager::evict_lru),
+            None => false,
+        }
+    }
+
+    /// Shard holding the flows of the app socket `app`
+    fn index(&self, app: SocketAddr) -> usize {
+        (self.hasher.hash_one(app) % self.shards.len() as u64) as usize
+    }
+
+    fn lock(&self, index: usize) -> MutexGuard<'_, ConnectionManager> {
+        // A shard is consistent between calls, so a panic elsewhere does
+        // not leave it broken
+        self.shards[index].lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Run `f` on a shard, keeping the live flow count in step with it
+    fn with_shard<R>(&self, index: usize, f: impl FnOnce(&mut ConnectionManager) -> R) -> R {
+        let mut shard = self.lock(index);
+        let before = shard.active_connections();
+        let result = f(&mut shard);
+        let after = shard.active_connections();
+        if after > before {
+            self.live.fetch_add(after - before, Ordering::Relaxed);
+        } else {
+            self.live.fetch_sub(before - after, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Run `f` on the shard of `key`
+    fn with_flow<R>(&self, key: &NatKey, f: impl FnOnce(&mut ConnectionManager) -> R) -> R {
+        self.with_shard(self.index(key.src_addr()), f)
+    }
+
+    /// Read the shard of `key`
+    fn read<R>(&self, key: &NatKey, f: impl FnOnce(&ConnectionManager) -> R) -> R {
+        f(&self.lock(self.index(key.src_addr())))
+    }
+
+    /// Run `f` on every shard in turn, e.g. for a maintenance pass
+    pub fn each_shard<R>(&self, mut f: impl FnMut(&mut ConnectionManager) -> R) -> Vec<R> {
+        (0..self.shards.len())
+            .map(|index| self.with_shard(index, &mut f))
+            .collect()
+    }
+
+    /// Sum `per_flow` over the tracked flows
+    pub fn sum_flows(&self, per_flow: impl Fn(&NatKey) -> usize) -> usize {
+        self.sum(|shard| {
+            shard
+                .iter_filtered(None, None, None)
+                .map(|(key, _)| per_flow(key))
+                .sum()
+        })
+    }
+
+    /// Sum `f` over all shards
+    fn sum<T: std::iter::Sum<T>>(&self, f: impl Fn(&ConnectionManager) -> T) -> T {
+        (0..self.shards.len()).map(|index| f(&self.lock(index))).sum()
+    }
+
+    /// Change the NAT idle timeouts
+    pub fn set_nat_timeouts(&self, timeouts: NatTimeouts) {
+        self.each_shard(|shard| shard.set_nat_timeouts(timeouts));
+    }
+
+    /// Get the NAT idle timeouts
+    pub fn nat_timeouts(&self) -> NatTimeouts {
+        self.lock(0).nat_timeouts()
+    }
+
+    /// Close relayed flows after `timeout` without traffic (`None` disables)
+    pub fn set_relay_idle_timeout(&self, timeout: Option<Duration>) {
+        self.each_shard(|shard| shard.set_relay_idle_timeout(timeout));
+    }
+
+    /// Change the UDP mapping mode for new flows
+    pub fn set_udp_nat_mode(&self, mode: NatMode) {
+        self.each_shard(|shard| shard.set_udp_nat_mode(mode));
+    }
+
+    /// Change how local ports are picked for new flows
+    pub fn set_nat_port_strategy(&self, strategy: PortStrategy) {
+        self.each_shard(|shard| shard.set_nat_port_strategy(strategy));
+    }
+
+    /// Change the NAT table capacity; flows evicted to fit are closed
+    pub fn set_nat_max_entries(&mut self, max_entries: usize) {
+        self.max_entries = max_entries;
+        while self.live.load(Ordering::Relaxed) > max_entries && self.evict_lru() {}
+    }
+
+    /// Cap the number of tracked flows; new flows beyond it are refused
+    /// while existing ones keep running
+    pub fn set_connection_limit(&mut self, limit: usize) {
+        self.connection_limit = limit;
+    }
+
+    /// Maximum flows tracked at once
+    pub fn connection_limit(&self) -> usize {
+        self.connection_limit
+    }
+
+    /// New flows refused because the connection limit was reached
+    pub fn connection_limit_hits(&self) -> u64 {
+        self.connection_limit_hits.load(Ordering::Relaxed)
+    }
+
+    /// Register a socket handle for a connection
+    pub fn register_socket(&self, key: NatKey, handle: SocketHandle) {
+        self.with_flow(&key, |shard| shard.register_socket(key, handle));
+    }
+
+    /// Get the socket handle for a connection
+    pub fn get_socket_handle(&self, key: &NatKey) -> Option<SocketHandle> {
+        self.read(key, |shard| shard.get_socket_handle(key))
+    }
+
+    /// Open listeners for new flows, poll `iface` and fold its socket state
+    /// changes into the shards (see `ConnectionManager::poll_interface`)
+    pub fn poll_interface(&self, iface: &mut InterfaceManager) -> bool {
+        self.each_shard(|shard| shard.open_listeners(iface));
+        let changed = iface.poll();
+        let changes = iface.take_state_changes();
+        if !changes.is_empty() {
+            // Each shard skips the flows it does not hold
+            self.each_shard(|shard| shard.apply_socket_changes(&changes));
+        }
+        changed
+    }
+
+    /// Keep a new TCP flow from getting a listening socket
+    pub fn hold_listener(&self, key: &NatKey) {
+        self.with_flow(key, |shard| shard.hold_listener(key));
+    }
+
+    /// Give a held flow its listening socket on the next poll
+    pub fn release_listener(&self, key: NatKey) {
+        self.with_flow(&key, |shard| shard.release_listener(key));
+    }
+
+    /// Check whether a flow is still tracked
+    pub fn contains(&self, key: &NatKey) -> bool {
+        self.read(key, |shard| shard.contains(key))
+    }
+
+    /// Live flows routed through the proxy
+    pub fn proxied_count(&self) -> usize {
+        self.sum(ConnectionManager::proxied_count)
+    }
+
+    /// Live flows routed to `host` and not rejected
+    pub fn destination_count(&self, host: &str) -> usize {
+        self.sum(|shard| shard.destination_count(host))
+    }
+
+    /// Check whether a flow's first data segment still needs sniffing
+    pub fn needs_sniff(&self, key: &NatKey) -> bool {
+        self.read(key, |shard| shard.needs_sniff(key))
+    }
+
+    /// Record the result of sniffing a flow
+    pub fn set_sniffed_domain(&self, key: NatKey, domain: Option<String>) {
+        self.with_flow(&key, |shard| shard.set_sniffed_domain(key, domain));
+    }
+
+    /// Store the routing decision for a connection
+    pub fn set_route(&self, key: &NatKey, decision: RoutingDecision) -> bool {
+        self.with_flow(key, |shard| shard.set_route(key, decision))
+    }
+
+    /// Subscribe to connection lifecycle events of every shard
+    pub fn subscribe_events(&self) -> broadcast::Receiver<ConnectionEvent> {
+        self.events.subscribe()
+    }
+
+    /// Get the stored routing decision for a connection
+    pub fn route(&self, key: &NatKey) -> Option<RoutingDecision> {
+        self.read(key, |shard| shard.route(key).cloned())
+    }
+
+    /// Get the hostname sniffed for a connection
+    pub fn domain(&self, key: &NatKey) -> Option<String> {
+        self.read(key, |shard| shard.domain(key).map(String::from))
+    }
+
+    /// Get the app a flow was attributed to
+    pub fn app_id(&self, key: &NatKey) -> Option<String> {
+        self.read(key, |shard| shard.app_id(key).map(String::from))
+    }
+
+    /// Record why a flow is ending; the first reason recorded wins
+    pub fn set_close_reason(&self, key: &NatKey, reason: CloseReason) -> bool {
+        self.with_flow(key, |shard| shard.set_close_reason(key, reason))
+    }
+
+    /// Log every flow that completes from now on to `flow_log`, or stop
+    /// logging with `None`
+    pub fn set_flow_logger(&self, flow_log: Option<FlowLogger>) {
+        let flow_log = flow_log.map(Arc::new);
+        self.each_shard(|shard| shard.set_flow_logger(flow_log.clone()));
+    }
+
+    /// Up to `limit` recently closed flows of all shards, newest first
+    pub fn recent_connections(&self, limit: usize) -> Vec<ClosedConnection> {
+        let mut recent: Vec<ClosedConnection> = (0..self.shards.len())
+            .flat_map(|index| {
+                let shard = self.lock(index);
+                shard.recent_connections(limit).cloned().collect::<Vec<_>>()
+            })
+            .collect();
+        recent.sort_by_key(|closed| std::cmp::Reverse(closed.closed_at));
+        recent.truncate(limit);
+        recent
+    }
+
+    /// Remove flows whose relay task died and flows evicted from the NAT
+    /// table, returning their socket handles
+    pub fn reap_orphaned(&self) -> Vec<SocketHandle> {
+        self.each_shard(ConnectionManager::reap_orphaned)
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    /// Number of flows reaped because their relay task died
+    pub fn reaped_flows(&self) -> u64 {
+        self.sum(ConnectionManager::reaped_flows)
+    }
+
+    /// Number of flows evicted from the full NAT table
+    pub fn nat_evictions(&self) -> u64 {
+        self.sum(ConnectionManager::nat_evictions)
+    }
+
+    /// Number of flows refused by the per-source NAT cap
+    pub fn nat_source_limit_hits(&self) -> u64 {
+        self.sum(ConnectionManager::nat_source_limit_hits)
+    }
+
+    /// Number of local ports found taken while allocating
+    pub fn nat_port_collisions(&self) -> u64 {
+        self.sum(ConnectionManager::nat_port_collisions)
+    }
+
+    /// Number of TCP segments whose flags did not fit their flow's state
+    pub fn nat_invalid_transitions(&self) -> u64 {
+        self.sum(ConnectionManager::nat_invalid_transitions)
+    }
+
+    /// Mark a connection as established
+    pub fn establish(&self, key: &NatKey) {
+        self.with_flow(key, |shard| shard.establish(key));
+    }
+
+    /// Add bytes sent to a connection
+    pub fn add_bytes_sent(&self, key: &NatKey, bytes: u64) {
+        self.with_flow(key, |shard| shard.add_bytes_sent(key, bytes));
+    }
+
+    /// Add bytes received to a connection
+    pub fn add_bytes_received(&self, key: &NatKey, bytes: u64) {
+        self.with_flow(key, |shard| shard.add_bytes_received(key, bytes));
+    }
+
+    /// Close a connection
+    pub fn close_connection(&self, key: &NatKey) {
+        self.with_flow(key, |shard| shard.close_connection(key));
+    }
+
+    /// Remove a connection completely
+    pub fn remove_connection(&self, key: &NatKey) -> Option<ConnectionInfo> {
+        self.with_flow(key, |shard| shard.remove_connection(key))
+    }
+
+    /// Kill a connection on demand (see `ConnectionManager::abort`)
+    pub fn abort(&self, key: &NatKey) -> Option<ConnectionInfo> {
+        self.with_flow(key, |shard| shard.abort(key))
+    }
+
+    /// Abort every flow, returning the socket handles of all of them
+    pub fn abort_all(&self) -> Vec<SocketHandle> {
+        self.each_shard(ConnectionManager::abort_all)
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    /// Get the NAT key of the connection with this identifier
+    pub fn key_by_id(&self, id: u64) -> Option<NatKey> {
+        (0..self.shards.len()).find_map(|index| self.lock(index).key_by_id(id))
+    }
+
+    /// Get the number of active connections
+    pub fn active_connections(&self) -> usize {
+        self.live.load(Ordering::Relaxed)
+    }
+
+    /// Estimated heap bytes held by the NAT tables
+    pub fn nat_bytes(&self) -> usize {
+        self.sum(ConnectionManager::nat_bytes)
+    }
+
+    /// Get total bytes sent
+    pub fn total_bytes_sent(&self) -> u64 {
+        self.sum(ConnectionManager::total_bytes_sent)
+    }
+
+    /// Get total bytes received
+    pub fn total_bytes_received(&self) -> u64 {
+        self.sum(ConnectionManager::total_bytes_received)
+    }
+
+    /// Get total connections created
+    pub fn total_connections(&self) -> u64 {
+        self.sum(ConnectionManager::total_connections)
+    }
+
+    /// Current upload speed across all flows, in bytes per second
+    pub fn upload_rate(&self) -> u64 {
+        self.sum(ConnectionManager::upload_rate)
+    }
+
+    /// Current download speed across all flows, in bytes per second
+    pub fn download_rate(&self) -> u64 {
+        self.sum(ConnectionManager::download_rate)
+    }
+
+    /// Attribute a flow to an app
+    pub fn set_app_id(&self, key: &NatKey, app_id: String) -> bool {
+        self.with_flow(key, |shard| shard.set_app_id(key, app_id))
+    }
+
+    /// Attach `value` under `name` to a flow, or remove `name` when `value`
+    /// is `None`
+    pub fn annotate(
+        &self,
+        key: &NatKey,
+        name: &str,
+        value: Option<String>,
+    ) -> Result<(), VoyageError> {
+        self.with_flow(key, |shard| shard.annotate(key, name, value))
+    }
+
+    /// Merge a usage table of every shard
+    fn usage<K: std::hash::Hash + Eq + Clone>(
+        &self,
+        table: impl Fn(&ConnectionManager) -> &UsageTable<K>,
+    ) -> UsageTable<K> {
+        let now = Instant::now();
+        let mut merged = table(&self.lock(0)).clone();
+        for index in 1..self.shards.len() {
+            merged.merge(table(&self.lock(index)), now);
+        }
+        merged
+    }
+
+    /// Traffic totals per source IP
+    pub fn usage_by_source(&self) -> UsageTable<IpAddr> {
+        self.usage(ConnectionManager::usage_by_source)
+    }
+
+    /// Traffic totals per app identifier
+    pub fn usage_by_app(&self) -> UsageTable<String> {
+        self.usage(ConnectionManager::usage_by_app)
+    }
+
+    /// Traffic totals per sniffed or resolved domain
+    pub fn usage_by_domain(&self) -> UsageTable<String> {
+        self.usage(ConnectionManager::usage_by_domain)
+    }
+
+    /// Traffic totals per routing policy
+    pub fn usage_by_policy(&self) -> UsageTable<RouteAction> {
+        self.usage(ConnectionManager::usage_by_policy)
+    }
+
+    /// Bucketed traffic totals, overall and per policy
+    pub fn traffic(&self) -> TrafficRecorder {
+        let mut merged = self.lock(0).traffic().clone();
+        for index in 1..self.shards.len() {
+            merged.merge(self.lock(index).traffic());
+        }
+        merged
+    }
+
+    /// NAT entries matching every given filter (see
+    /// `NatManager::iter_filtered`), copied out of their shards
+    pub fn iter_filtered(
+        &self,
+        protocol: Option<u8>,
+        state: Option<NatState>,
+        since: Option<Instant>,
+    ) -> impl Iterator<Item = (NatKey, NatEntry)> {
+        let entries: Vec<(NatKey, NatEntry)> = (0..self.shards.len())
+            .flat_map(|index| {
+                self.lock(index)
+                    .iter_filtered(protocol, state, since)
+                    .map(|(key, entry)| (*key, entry.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        entries.into_iter()
+    }
+
+    /// Dump the full flow table as pretty-printed JSON, ordered by local
+    /// port
+    pub fn dump_flows_json(&self, sockets: Option<&SocketSet<'_>>) -> String {
+        let mut flows: Vec<_> = (0..self.shards.len())
+            .flat_map(|index| self.lock(index).dump_flows(sockets))
+            .collect();
+        flows.sort_by_key(|flow| flow.local_port);
+        serde_json::to_string_pretty(&flows).unwrap_or_else(|_| "[]".into())
+    }
+}
+
+impl Default for ShardedConnections {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::create_tcp_packet;
+
+    fn open(table: &ShardedConnections, src_port: u16) -> Result<ConnectionInfo, VoyageError> {
+        let packet = create_tcp_packet([10, 0, 0, 1], [8, 8, 8, 8], src_port, 443, true);
+        table.process_packet(&ParsedPacket::parse(&packet).unwrap())
+    }
+
+    #[test]
+    fn test_flows_spread_over_shards_with_unique_ids_and_ports() {
+        let table = ShardedConnections::new();
+        let flows: Vec<ConnectionInfo> =
+            (40000..40064).map(|port| open(&table, port).unwrap()).collect();
+        assert_eq!(table.active_connections(), 64);
+
+        let used = table.each_shard(|shard| shard.active_connections());
+        assert!(used.iter().filter(|&&count| count > 0).count() > 1, "{:?}", used);
+
+        let mut ports: Vec<u16> = flows.iter().map(|flow| flow.local_port).collect();
+        ports.sort_unstable();
+        ports.dedup();
+        assert_eq!(ports.len(), 64);
+
+        let entries: Vec<(NatKey, NatEntry)> = table.iter_filtered(None, None, None).collect();
+        let mut ids: Vec<u64> = entries.iter().map(|(_, entry)| entry.id).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), 64);
+        for (key, entry) in &entries {
+            assert_eq!(table.key_by_id(entry.id), Some(*key));
+        }
+    }
+
+    #[test]
+    fn test_limits_hold_across_shards() {
+        let mut table = ShardedConnections::new();
+        table.set_connection_limit(3);
+        for port in 40000..40003 {
+            open(&table, port).unwrap();
+        }
+        assert!(open(&table, 40003).is_err());
+        assert_eq!(table.connection_limit_hits(), 1);
+        // Known flows still pass
+        open(&table, 40000).unwrap();
+
+        // Shrinking the table evicts the least recently active flows
+        table.set_nat_max_entries(1);
+        assert_eq!(table.active_connections(), 1);
+        assert_eq!(table.nat_evictions(), 2);
+        assert!(table.iter_filtered(None, None, None).all(|(key, _)| key.src_port == 40000));
+    }
+
+    #[test]
+    fn test_parallel_flows() {
+        let table = Arc::new(ShardedConnections::new());
+        let workers: Vec<_> = (0..4u16)
+            .map(|worker| {
+                let table = Arc::clone(&table);
+                std::thread::spawn(move || {
+                    for port in 0..100 {
+                        let info = open(&table, 40000 + worker * 100 + port).unwrap();
+                        table.add_bytes_sent(&info.key, 10);
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+
+        assert_eq!(table.active_connections(), 400);
+        assert_eq!(table.total_connections(), 400);
+        assert_eq!(table.total_bytes_sent(), 4000);
+        let source = table.usage_by_source();
+        let usage = source.get(&IpAddr::from([10, 0, 0, 1])).unwrap();
+        assert_eq!((usage.connections, usage.bytes_sent), (400, 4000));
+    }
+}
@@ -0,0 +1,439 @@
+//! Deterministic Simulation
+//!
+//! This module drives an `InterfaceManager` without real sockets or real
+//! time, so the TCP termination logic can be tested end to end. A
+//! `SimPeer` plays the app: it scripts segments (handshake, data, FIN,
+//! RST) with consistent sequence numbers and learns the interface's from
+//! what it sends back. Time only moves when the test advances the
+//! `SimClock`, so retransmission and timeouts are reproducible.
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use smoltcp::iface::SocketHandle;
+use smoltcp::phy::ChecksumCapabilities;
+use smoltcp::socket::tcp::State as TcpState;
+use smoltcp::wire::{
+    IpAddress, IpProtocol, Ipv4Address, Ipv4Packet, Ipv4Repr, Ipv6Address, Ipv6Packet, Ipv6Repr,
+    TcpControl, TcpPacket, TcpRepr, TcpSeqNumber,
+};
+
+use crate::config::ProxyConfig;
+use crate::iface::InterfaceManager;
+use crate::packet::{ParsedPacket, TcpFlags};
+
+/// MSS the simulated app advertises in its SYN
+pub const SIM_PEER_MSS: u16 = 1460;
+
+/// Virtual time in milliseconds, shared by its clones
+#[derive(Debug, Clone, Default)]
+pub struct SimClock(Arc<AtomicU64>);
+
+impl SimClock {
+    /// A clock starting at zero
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Milliseconds since the clock started
+    pub fn now_ms(&self) -> u64 {
+        self.0.load(Ordering::Acquire)
+    }
+
+    /// Move the clock forward by `by`
+    pub fn advance(&self, by: Duration) {
+        self.0.fetch_add(by.as_millis() as u64, Ordering::AcqRel);
+    }
+}
+
+/// A TCP segment the interface sent towards the app
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    pub seq: u32,
+    /// Acknowledgment number, when the ACK flag is set
+    pub ack: Option<u32>,
+    pub flags: TcpFlags,
+    pub window: u16,
+    pub payload: Vec<u8>,
+}
+
+impl Segment {
+    /// Decode an emitted packet; `None` unless it is TCP
+    pub fn parse(packet: &[u8]) -> Option<Self> {
+        let parsed = ParsedPacket::parse(packet).ok()?;
+        let tcp = parsed.tcp.as_ref()?;
+        let end = parsed.ip.total_len.min(packet.len());
+        let payload = packet
+            .get(parsed.ip.payload_offset + tcp.data_offset..end)
+            .unwrap_or_default();
+        Some(Self {
+            src: parsed.src_addr()?,
+            dst: parsed.dst_addr()?,
+            seq: tcp.seq_num,
+            ack: tcp.flags.ack.then_some(tcp.ack_num),
+            flags: tcp.flags,
+            window: tcp.window,
+            payload: payload.to_vec(),
+        })
+    }
+
+    /// Sequence space the segment takes: its payload plus SYN and FIN
+    pub fn seq_len(&self) -> u32 {
+        self.payload.len() as u32 + self.flags.syn as u32 + self.flags.fin as u32
+    }
+}
+
+/// App side of one connection through the interface
+#[derive(Debug, Clone)]
+pub struct SimPeer {
+    /// The app's address
+    pub local: SocketAddr,
+    /// The destination the app connects to
+    pub remote: SocketAddr,
+    /// Next sequence number the app sends
+    seq: u32,
+    /// Next sequence number expected from the interface, once synced
+    ack: Option<u32>,
+}
+
+impl SimPeer {
+    /// A peer connecting from `local` to `remote` with initial sequence
+    /// number `isn`. Both addresses must be of the same family.
+    pub fn new(local: SocketAddr, remote: SocketAddr, isn: u32) -> Self {
+        Self {
+            local,
+            remote,
+            seq: isn,
+            ack: None,
+        }
+    }
+
+    /// Next sequence number the app sends
+    pub fn seq(&self) -> u32 {
+        self.seq
+    }
+
+    /// Next sequence number the app expects, once the handshake is seen
+    pub fn ack(&self) -> Option<u32> {
+        self.ack
+    }
+
+    /// Open the connection
+    pub fn syn(&mut self) -> Vec<u8> {
+        let packet = self.build(TcpControl::Syn, self.seq, &[]);
+        self.seq = self.seq.wrapping_add(1);
+        packet
+    }
+
+    /// Acknowledge everything received so far
+    pub fn ack_packet(&self) -> Vec<u8> {
+        self.build(TcpControl::None, self.seq, &[])
+    }
+
+    /// Send `payload` and advance the sequence number past it
+    pub fn data(&mut self, payload: &[u8]) -> Vec<u8> {
+        let packet = self.build(TcpControl::Psh, self.seq, payload);
+        self.seq = self.seq.wrapping_add(payload.len() as u32);
+        packet
+    }
+
+    /// Send `payload` again at `seq` without advancing, as a retransmission
+    pub fn retransmit(&self, seq: u32, payload: &[u8]) -> Vec<u8> {
+        self.build(TcpControl::Psh, seq, payload)
+    }
+
+    /// Close the app's side of the connection
+    pub fn fin(&mut self) -> Vec<u8> {
+        let packet = self.build(TcpControl::Fin, self.seq, &[]);
+        self.seq = self.seq.wrapping_add(1);
+        packet
+    }
+
+    /// Reset the connection
+    pub fn rst(&self) -> Vec<u8> {
+        self.build(TcpControl::Rst, self.seq, &[])
+    }
+
+    /// Take in a segment from the interface, advancing what the app
+    /// acknowledges when it arrives in order. Segments of other flows are
+    /// ignored; returns whether the segment was in order.
+    pub fn receive(&mut self, segment: &Segment) -> bool {
+        if segment.src != self.remote || segment.dst != self.local {
+            return false;
+        }
+        let expected = match self.ack {
+            Some(ack) => ack,
+            None if segment.flags.syn => segment.seq,
+            None => return false,
+        };
+        if segment.seq != expected {
+            return false;
+        }
+        self.ack = Some(expected.wrapping_add(segment.seq_len()));
+        true
+    }
+
+    fn build(&self, control: TcpControl, seq: u32, payload: &[u8]) -> Vec<u8> {
+        let tcp = TcpRepr {
+            src_port: self.local.port(),
+            dst_port: self.remote.port(),
+            control,
+            seq_number: TcpSeqNumber(seq as i32),
+            ack_number: self.ack.map(|ack| TcpSeqNumber(ack as i32)),
+            window_len: u16::MAX,
+            window_scale: None,
+            max_seg_size: (control == TcpControl::Syn).then_some(SIM_PEER_MSS),
+            sack_permitted: false,
+            sack_ranges: [None; 3],
+            payload,
+        };
+        build_tcp_packet(self.local.ip(), self.remote.ip(), &tcp)
+    }
+}
+
+/// Build an IPv4/IPv6 TCP packet with valid checksums
+fn build_tcp_packet(src: IpAddr, dst: IpAddr, tcp: &TcpRepr) -> Vec<u8> {
+    let caps = ChecksumCapabilities::default();
+    let tcp_len = tcp.buffer_len();
+    let (mut buffer, header_len, src_ip, dst_ip) = match (src, dst) {
+        (IpAddr::V4(s), IpAddr::V4(d)) => {
+            let repr = Ipv4Repr {
+                src_addr: Ipv4Address::from_bytes(&s.octets()),
+                dst_addr: Ipv4Address::from_bytes(&d.octets()),
+                next_header: IpProtocol::Tcp,
+                payload_len: tcp_len,
+                hop_limit: 64,
+            };
+            let mut buffer = vec![0u8; repr.buffer_len() + tcp_len];
+            repr.emit(&mut Ipv4Packet::new_unchecked(&mut buffer), &caps);
+            let (src, dst) = (
+                IpAddress::Ipv4(repr.src_addr),
+                IpAddress::Ipv4(repr.dst_addr),
+            );
+            (buffer, repr.buffer_len(), src, dst)
+        }
+        (IpAddr::V6(s), IpAddr::V6(d)) => {
+            let repr = Ipv6Repr {
+                src_addr: Ipv6Address::from_bytes(&s.octets()),
+                dst_addr: Ipv6Address::from_bytes(&d.octets()),
+                next_header: IpProtocol::Tcp,
+                payload_len: tcp_len,
+                hop_limit: 64,
+            };
+            let mut buffer = vec![0u8; repr.buffer_len() + tcp_len];
+            repr.emit(&mut Ipv6Packet::new_unchecked(&mut buffer));
+            let (src, dst) = (
+                IpAddress::Ipv6(repr.src_addr),
+                IpAddress::Ipv6(repr.dst_addr),
+            );
+            (buffer, repr.buffer_len(), src, dst)
+        }
+        _ => panic!("Simulated peer mixes address families: {} -> {}", src, dst),
+    };
+    tcp.emit(
+        &mut TcpPacket::new_unchecked(&mut buffer[header_len..]),
+        &src_ip,
+        &dst_ip,
+        &caps,
+    );
+    buffer
+}
+
+/// An interface driven by a virtual clock, fed and drained by the test
+pub struct Simulation {
+    clock: SimClock,
+    iface: InterfaceManager,
+}
+
+impl Simulation {
+    /// An interface set up from `config` on a clock starting at zero
+    pub fn new(config: &ProxyConfig) -> Self {
+        let clock = SimClock::new();
+        let mut iface = InterfaceManager::from_proxy_config(config);
+        iface.set_clock(Some(clock.clone()));
+        Self { clock, iface }
+    }
+
+    pub fn clock(&self) -> &SimClock {
+        &self.clock
+    }
+
+    pub fn iface(&mut self) -> &mut InterfaceManager {
+        &mut self.iface
+    }
+
+    /// Deliver `packet` from the app, poll, and return the TCP segments
+    /// the interface sent in response
+    pub fn send(&mut self, packet: Vec<u8>) -> Vec<Segment> {
+        self.iface.inject_packet(packet);
+        self.poll()
+    }
+
+    /// Move the clock forward by `by`, poll, and return the segments sent
+    /// by the timers that fired
+    pub fn advance(&mut self, by: Duration) -> Vec<Segment> {
+        self.clock.advance(by);
+        self.poll()
+    }
+
+    /// Poll without new input and return the segments sent, e.g. after a
+    /// socket was written to
+    pub fn poll(&mut self) -> Vec<Segment> {
+        self.iface.poll();
+        self.iface
+            .take_packets()
+            .iter()
+            .filter_map(|packet| Segment::parse(packet))
+            .collect()
+    }
+
+    /// State of the socket `handle`
+    pub fn state(&mut self, handle: SocketHandle) -> TcpState {
+        self.iface.get_tcp_socket(handle).state()
+    }
+
+    /// Complete `peer`'s handshake with the socket listening on its
+    /// destination port, returning the SYN-ACK
+    pub fn handshake(&mut self, peer: &mut SimPeer) -> Option<Segment> {
+        let syn_ack = self
+            .send(peer.syn())
+            .into_iter()
+            .find(|segment| segment.flags.is_syn_ack())?;
+        peer.receive(&syn_ack);
+        self.send(peer.ack_packet());
+        Some(syn_ack)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const APP: &str = "10.0.0.2:40000";
+    const REMOTE: &str = "93.184.216.34:80";
+
+    fn setup() -> (Simulation, SimPeer, SocketHandle) {
+        let mut sim = Simulation::new(&ProxyConfig::default());
+        let handle = sim.iface().listen_tcp(80).unwrap();
+        let peer = SimPeer::new(APP.parse().unwrap(), REMOTE.parse().unwrap(), 1000);
+        (sim, peer, handle)
+    }
+
+    #[test]
+    fn test_handshake() {
+        let (mut sim, mut peer, handle) = setup();
+        let syn_ack = sim.handshake(&mut peer).unwrap();
+        assert_eq!(syn_ack.src, peer.remote);
+        assert_eq!(syn_ack.ack, Some(1001));
+        assert_eq!(sim.state(handle), TcpState::Established);
+        assert_eq!(
+            sim.iface()
+                .get_tcp_socket(handle)
+                .remote_endpoint()
+                .unwrap()
+                .port,
+            40000
+        );
+    }
+
+    #[test]
+    fn test_data_both_ways() {
+        let (mut sim, mut peer, handle) = setup();
+        sim.handshake(&mut peer).unwrap();
+
+        // App to socket: acknowledged once the delayed ACK fires
+        sim.send(peer.data(b"GET / HTTP/1.1\r\n\r\n"));
+        let mut buf = [0u8; 64];
+        let n = sim
+            .iface()
+            .get_tcp_socket(handle)
+            .recv_slice(&mut buf)
+            .unwrap();
+        assert_eq!(&buf[..n], b"GET / HTTP/1.1\r\n\r\n");
+        let acks = sim.advance(Duration::from_millis(50));
+        assert!(acks.iter().any(|segment| segment.ack == Some(peer.seq())));
+
+        // Socket to app
+        let socket = sim.iface().get_tcp_socket(handle);
+        socket
+            .send_slice(b"HTTP/1.1 204 No Content\r\n\r\n")
+            .unwrap();
+        let sent = sim.poll();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].payload, b"HTTP/1.1 204 No Content\r\n\r\n");
+        assert!(peer.receive(&sent[0]));
+    }
+
+    #[test]
+    fn test_retransmission() {
+        let (mut sim, mut peer, handle) = setup();
+        sim.handshake(&mut peer).unwrap();
+
+        sim.iface()
+            .get_tcp_socket(handle)
+            .send_slice(b"hello")
+            .unwrap();
+        let first = sim.poll();
+        assert_eq!(first.len(), 1);
+
+        // The app never acknowledges: nothing is resent before the RTO,
+        // then the same bytes go out at the same sequence number
+        assert!(sim.advance(Duration::from_millis(100)).is_empty());
+        let resent = sim.advance(Duration::from_secs(3));
+        assert_eq!(resent.len(), 1);
+        assert_eq!(resent[0].seq, first[0].seq);
+        assert_eq!(resent[0].payload, b"hello");
+
+        // A duplicate from the app is acknowledged but delivered only once
+        let start = peer.seq();
+        sim.send(peer.data(b"ping"));
+        let dup_ack = sim.send(peer.retransmit(start, b"ping"));
+        assert!(dup_ack
+            .iter()
+            .any(|segment| segment.ack == Some(peer.seq())));
+        let mut buf = [0u8; 16];
+        let n = sim
+            .iface()
+            .get_tcp_socket(handle)
+            .recv_slice(&mut buf)
+            .unwrap();
+        assert_eq!(&buf[..n], b"ping");
+        assert!(!sim.iface().get_tcp_socket(handle).can_recv());
+    }
+
+    #[test]
+    fn test_fin_from_app() {
+        let (mut sim, mut peer, handle) = setup();
+        sim.handshake(&mut peer).unwrap();
+
+        let reply = sim.send(peer.fin());
+        assert_eq!(sim.state(handle), TcpState::CloseWait);
+        assert!(reply.iter().any(|segment| segment.ack == Some(peer.seq())));
+
+        // The relay closes its side: FIN to the app, which acknowledges it
+        sim.iface().get_tcp_socket(handle).close();
+        let fin = sim.poll();
+        assert!(fin[0].flags.is_fin());
+        assert_eq!(sim.state(handle), TcpState::LastAck);
+        assert!(peer.receive(&fin[0]));
+        sim.send(peer.ack_packet());
+        assert_eq!(sim.state(handle), TcpState::Closed);
+    }
+
+    #[test]
+    fn test_rst_reports_state_change() {
+        let (mut sim, mut peer, handle) = setup();
+        sim.handshake(&mut peer).unwrap();
+        sim.iface().take_state_changes();
+
+        sim.send(peer.rst());
+        let changes = sim.iface().take_state_changes();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].handle, handle);
+        assert_eq!(changes[0].state, TcpState::Closed);
+    }
+}
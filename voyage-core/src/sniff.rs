@@ -0,0 +1,291 @@
+//! Protocol Sniffing
+//!
+//! This module inspects the first data segment of a flow to recover the
+//! hostname the app is talking to, so DOMAIN rules can match flows that
+//! arrive as bare IP addresses.
+
+/// TLS record type for handshake messages
+const TLS_CONTENT_HANDSHAKE: u8 = 0x16;
+/// TLS handshake type for ClientHello
+const TLS_HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+/// TLS extension carrying the server name
+const TLS_EXT_SERVER_NAME: u16 = 0x0000;
+/// Server name type for DNS hostnames
+const TLS_SNI_HOST_NAME: u8 = 0x00;
+
+/// Well-known port for TLS traffic
+pub const TLS_PORT: u16 = 443;
+/// Well-known port for plaintext HTTP traffic
+pub const HTTP_PORT: u16 = 80;
+
+/// Request methods recognised at the start of an HTTP/1.x request
+const HTTP_METHODS: &[&str] = &[
+    "GET", "POST", "HEAD", "PUT", "DELETE", "OPTIONS", "PATCH", "CONNECT", "TRACE",
+];
+
+/// Simple forward-only reader over a byte slice
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        let b = *self.data.get(self.pos)?;
+        self.pos += 1;
+        Some(b)
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.bytes(2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        let bytes = self.bytes(3)?;
+        Some(((bytes[0] as usize) << 16) | ((bytes[1] as usize) << 8) | bytes[2] as usize)
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let slice = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(slice)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.bytes(len).map(|_| ())
+    }
+
+    /// Read a vector prefixed with a one-byte length
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()? as usize;
+        self.bytes(len)
+    }
+
+    /// Read a vector prefixed with a two-byte length
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.bytes(len)
+    }
+}
+
+/// Extract the SNI hostname from a TLS ClientHello.
+///
+/// Only the first record is inspected; a ClientHello split across
+/// several TCP segments or records is not reassembled.
+pub fn extract_sni(data: &[u8]) -> Option<String> {
+    let mut record = Reader::new(data);
+    if record.u8()? != TLS_CONTENT_HANDSHAKE {
+        return None;
+    }
+    record.skip(2)?; // legacy record version
+    let record_len = record.u16()? as usize;
+    // Tolerate a truncated record: the extensions we need are usually early
+    let fragment = &data[record.pos..data.len().min(record.pos + record_len)];
+
+    let mut hs = Reader::new(fragment);
+    if hs.u8()? != TLS_HANDSHAKE_CLIENT_HELLO {
+        return None;
+    }
+    hs.u24()?;
+    hs.skip(2)?; // client version
+    hs.skip(32)?; // random
+    hs.vec8()?; // session id
+    hs.vec16()?; // cipher suites
+    hs.vec8()?; // compression methods
+
+    let mut extensions = Reader::new(hs.vec16()?);
+    while let (Some(ext_type), Some(ext_data)) = (extensions.u16(), extensions.vec16()) {
+        if ext_type != TLS_EXT_SERVER_NAME {
+            continue;
+        }
+
+        let mut list = Reader::new(Reader::new(ext_data).vec16()?);
+        while let Some(name_type) = list.u8() {
+            let name = list.vec16()?;
+            if name_type == TLS_SNI_HOST_NAME {
+                return parse_hostname(name);
+            }
+        }
+        return None;
+    }
+
+    None
+}
+
+/// Extract the Host header from the start of a plaintext HTTP/1.x request.
+///
+/// Only the headers present in this segment are inspected.
+pub fn extract_http_host(data: &[u8]) -> Option<String> {
+    // Headers are ASCII; stop at the first non-UTF-8 byte (e.g. a body)
+    let text = match std::str::from_utf8(data) {
+        Ok(text) => text,
+        Err(e) => std::str::from_utf8(&data[..e.valid_up_to()]).ok()?,
+    };
+
+    let mut lines = text.split("\r\n");
+    let request_line = lines.next()?;
+    let mut parts = request_line.split(' ');
+    let method = parts.next()?;
+    let _target = parts.next()?;
+    let version = parts.next()?;
+    if !HTTP_METHODS.contains(&method) || !version.starts_with("HTTP/1.") {
+        return None;
+    }
+
+    for line in lines {
+        if line.is_empty() {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        if name.trim().eq_ignore_ascii_case("host") {
+            return parse_hostname(strip_port(value.trim()).as_bytes());
+        }
+    }
+
+    None
+}
+
+/// Drop an optional `:port` suffix from a Host header value
+fn strip_port(host: &str) -> &str {
+    if let Some(rest) = host.strip_prefix('[') {
+        // IPv6 literal, e.g. "[::1]:8080"
+        return rest.split(']').next().unwrap_or(rest);
+    }
+    match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => name,
+        _ => host,
+    }
+}
+
+/// Validate and normalize a hostname taken off the wire
+fn parse_hostname(name: &[u8]) -> Option<String> {
+    let host = std::str::from_utf8(name).ok()?.trim_end_matches('.');
+    let valid = !host.is_empty()
+        && host.len() <= 253
+        && host
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.' || b == b'_');
+    valid.then(|| host.to_ascii_lowercase())
+}
+
+/// Try to recover the hostname from the first payload of a TCP flow
+pub fn sniff_domain(dst_port: u16, payload: &[u8]) -> Option<String> {
+    if payload.is_empty() {
+        return None;
+    }
+
+    match dst_port {
+        TLS_PORT => extract_sni(payload),
+        HTTP_PORT => extract_http_host(payload),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a minimal TLS 1.2 ClientHello carrying `extensions`
+    fn client_hello(extensions: &[u8]) -> Vec<u8> {
+        let mut body = Vec::new();
+        body.extend_from_slice(&[0x03, 0x03]); // client version
+        body.extend_from_slice(&[0u8; 32]); // random
+        body.push(0); // session id
+        body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]); // one cipher suite
+        body.extend_from_slice(&[0x01, 0x00]); // null compression
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(extensions);
+
+        let mut handshake = vec![TLS_HANDSHAKE_CLIENT_HELLO];
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&body);
+
+        let mut record = vec![TLS_CONTENT_HANDSHAKE, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    fn sni_extension(host: &str) -> Vec<u8> {
+        let mut list = vec![TLS_SNI_HOST_NAME];
+        list.extend_from_slice(&(host.len() as u16).to_be_bytes());
+        list.extend_from_slice(host.as_bytes());
+
+        let mut ext_data = (list.len() as u16).to_be_bytes().to_vec();
+        ext_data.extend_from_slice(&list);
+
+        let mut ext = TLS_EXT_SERVER_NAME.to_be_bytes().to_vec();
+        ext.extend_from_slice(&(ext_data.len() as u16).to_be_bytes());
+        ext.extend_from_slice(&ext_data);
+        ext
+    }
+
+    #[test]
+    fn test_extract_sni() {
+        // A supported_groups extension precedes SNI
+        let mut extensions = vec![0x00, 0x0A, 0x00, 0x04, 0x00, 0x02, 0x00, 0x1D];
+        extensions.extend_from_slice(&sni_extension("WWW.Example.com"));
+
+        let hello = client_hello(&extensions);
+        assert_eq!(extract_sni(&hello), Some("www.example.com".to_string()));
+    }
+
+    #[test]
+    fn test_extract_sni_missing() {
+        let hello = client_hello(&[0x00, 0x0A, 0x00, 0x04, 0x00, 0x02, 0x00, 0x1D]);
+        assert_eq!(extract_sni(&hello), None);
+    }
+
+    #[test]
+    fn test_extract_sni_rejects_garbage() {
+        assert_eq!(extract_sni(&[]), None);
+        assert_eq!(extract_sni(b"GET / HTTP/1.1\r\n"), None);
+
+        let hello = client_hello(&sni_extension("example.com"));
+        for len in 0..hello.len() - 1 {
+            // Truncated input never panics; it only finds the name once complete
+            let _ = extract_sni(&hello[..len]);
+        }
+        assert_eq!(extract_sni(&client_hello(&sni_extension("bad host"))), None);
+    }
+
+    #[test]
+    fn test_extract_http_host() {
+        let request = b"GET /index.html HTTP/1.1\r\nUser-Agent: test\r\nHost: Example.com:8080\r\n\r\n";
+        assert_eq!(extract_http_host(request), Some("example.com".to_string()));
+
+        let request = b"POST /api HTTP/1.0\r\nhost:api.example.com\r\n\r\n{\"a\":1}";
+        assert_eq!(extract_http_host(request), Some("api.example.com".to_string()));
+    }
+
+    #[test]
+    fn test_extract_http_host_rejects() {
+        // Not a request
+        assert_eq!(extract_http_host(b"HTTP/1.1 200 OK\r\nHost: a.com\r\n\r\n"), None);
+        // Host only in the body
+        assert_eq!(extract_http_host(b"GET / HTTP/1.1\r\n\r\nHost: a.com\r\n"), None);
+        // HTTP/2 preface
+        assert_eq!(extract_http_host(b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n"), None);
+        assert_eq!(extract_http_host(b"GET / HTTP/1.1\r\nHost: [::1]:80\r\n\r\n"), None);
+    }
+
+    #[test]
+    fn test_sniff_domain_by_port() {
+        let hello = client_hello(&sni_extension("example.com"));
+        assert_eq!(sniff_domain(443, &hello), Some("example.com".to_string()));
+        assert_eq!(
+            sniff_domain(80, b"GET / HTTP/1.1\r\nHost: example.org\r\n\r\n"),
+            Some("example.org".to_string())
+        );
+        assert_eq!(sniff_domain(80, &hello), None);
+        assert_eq!(sniff_domain(8443, &hello), None);
+        assert_eq!(sniff_domain(443, &[]), None);
+    }
+}
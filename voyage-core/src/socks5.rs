@@ -3,13 +3,18 @@
 //! This module provides a SOCKS5 client for proxying TCP connections
 //! through a SOCKS5 proxy server.
 
+use std::fmt;
 use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
 
 use bytes::{BufMut, BytesMut};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 
+use crate::config::OutboundConfig;
 use crate::error::VoyageError;
+use crate::message;
+use crate::outbound;
+use crate::secret::SecretString;
 
 /// SOCKS5 version
 const SOCKS5_VERSION: u8 = 0x05;
@@ -102,18 +107,73 @@ impl From<u8> for ReplyCode {
 }
 
 impl ReplyCode {
-    /// Convert to error message
+    /// Stable message catalog key for this reply
+    pub fn message_key(&self) -> &'static str {
+        match self {
+            ReplyCode::Succeeded => "socks5.reply.succeeded",
+            ReplyCode::GeneralFailure => "socks5.reply.general_failure",
+            ReplyCode::ConnectionNotAllowed => "socks5.reply.connection_not_allowed",
+            ReplyCode::NetworkUnreachable => "socks5.reply.network_unreachable",
+            ReplyCode::HostUnreachable => "socks5.reply.host_unreachable",
+            ReplyCode::ConnectionRefused => "socks5.reply.connection_refused",
+            ReplyCode::TtlExpired => "socks5.reply.ttl_expired",
+            ReplyCode::CommandNotSupported => "socks5.reply.command_not_supported",
+            ReplyCode::AddressTypeNotSupported => "socks5.reply.address_type_not_supported",
+        }
+    }
+
+    /// Convert to error message (English default from the message catalog)
     pub fn to_error_message(&self) -> &'static str {
+        message::default_text(self.message_key()).unwrap_or("Unknown SOCKS reply")
+    }
+}
+
+/// Step of a SOCKS5 exchange
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeStage {
+    /// Version and authentication method negotiation
+    Greeting,
+    /// Username/password sub-negotiation
+    Authentication,
+    /// CONNECT request and its reply
+    Connect,
+}
+
+/// What went wrong in a SOCKS5 exchange
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Socks5ErrorKind {
+    /// The peer speaks another SOCKS version
+    BadVersion,
+    /// No authentication method both sides support
+    NoAcceptableMethod,
+    /// The server asks for credentials but none are configured
+    MissingCredentials,
+    /// The server refused the credentials
+    AuthFailed,
+    /// A command other than CONNECT
+    UnsupportedCommand { command: u8 },
+    /// An unknown address type
+    UnsupportedAddress { atyp: u8 },
+    /// The server rejected the request with this reply
+    Reply { code: ReplyCode },
+}
+
+impl fmt::Display for Socks5ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            ReplyCode::Succeeded => "Succeeded",
-            ReplyCode::GeneralFailure => "General SOCKS server failure",
-            ReplyCode::ConnectionNotAllowed => "Connection not allowed by ruleset",
-            ReplyCode::NetworkUnreachable => "Network unreachable",
-            ReplyCode::HostUnreachable => "Host unreachable",
-            ReplyCode::ConnectionRefused => "Connection refused",
-            ReplyCode::TtlExpired => "TTL expired",
-            ReplyCode::CommandNotSupported => "Command not supported",
-            ReplyCode::AddressTypeNotSupported => "Address type not supported",
+            Socks5ErrorKind::BadVersion => f.write_str("Invalid SOCKS version"),
+            Socks5ErrorKind::NoAcceptableMethod => f.write_str("No acceptable auth method"),
+            Socks5ErrorKind::MissingCredentials => {
+                f.write_str("Authentication required but no credentials")
+            }
+            Socks5ErrorKind::AuthFailed => f.write_str("Authentication failed"),
+            Socks5ErrorKind::UnsupportedCommand { command } => {
+                write!(f, "Unsupported SOCKS command {}", command)
+            }
+            Socks5ErrorKind::UnsupportedAddress { atyp } => {
+                write!(f, "Unknown address type {}", atyp)
+            }
+            Socks5ErrorKind::Reply { code } => f.write_str(code.to_error_message()),
         }
     }
 }
@@ -181,7 +241,9 @@ pub struct Socks5Client {
     /// Username for authentication
     username: Option<String>,
     /// Password for authentication
-    password: Option<String>,
+    password: Option<SecretString>,
+    /// Socket options of the connection to the proxy
+    options: OutboundConfig,
 }
 
 impl Socks5Client {
@@ -191,6 +253,7 @@ impl Socks5Client {
             proxy_addr,
             username: None,
             password: None,
+            options: OutboundConfig::default(),
         }
     }
 
@@ -203,16 +266,23 @@ impl Socks5Client {
         Self {
             proxy_addr,
             username: Some(username.into()),
-            password: Some(password.into()),
+            password: Some(SecretString::new(password)),
+            options: OutboundConfig::default(),
         }
     }
 
+    /// Use `options` for the connection to the proxy
+    pub fn with_options(mut self, options: OutboundConfig) -> Self {
+        self.options = options;
+        self
+    }
+
     /// Connect to the target through the SOCKS5 proxy
     pub async fn connect(&self, target: TargetAddr) -> Result<TcpStream, VoyageError> {
         // Connect to the proxy server
-        let mut stream = TcpStream::connect(self.proxy_addr)
+        let mut stream = outbound::connect(self.proxy_addr, &self.options)
             .await
-            .map_err(|e| VoyageError::IoError(e.to_string()))?;
+            .map_err(|e| VoyageError::io(e.to_string()))?;
 
         // Perform handshake
         self.handshake(&mut stream).await?;
@@ -241,17 +311,17 @@ impl Socks5Client {
         stream
             .write_all(&greeting)
             .await
-            .map_err(|e| VoyageError::IoError(e.to_string()))?;
+            .map_err(|e| VoyageError::io(e.to_string()))?;
 
         // Read server response
         let mut response = [0u8; 2];
         stream
             .read_exact(&mut response)
             .await
-            .map_err(|e| VoyageError::IoError(e.to_string()))?;
+            .map_err(|e| VoyageError::io(e.to_string()))?;
 
         if response[0] != SOCKS5_VERSION {
-            return Err(VoyageError::Socks5Error("Invalid SOCKS version".into()));
+            return Err(VoyageError::socks5(HandshakeStage::Greeting, Socks5ErrorKind::BadVersion));
         }
 
         let method = AuthMethod::from(response[1]);
@@ -259,41 +329,43 @@ impl Socks5Client {
         match method {
             AuthMethod::NoAuth => Ok(()),
             AuthMethod::UsernamePassword => self.authenticate(stream).await,
-            AuthMethod::NoAcceptable => {
-                Err(VoyageError::Socks5Error("No acceptable auth method".into()))
-            }
+            AuthMethod::NoAcceptable => Err(VoyageError::socks5(
+                HandshakeStage::Greeting,
+                Socks5ErrorKind::NoAcceptableMethod,
+            )),
         }
     }
 
     /// Perform username/password authentication
     async fn authenticate(&self, stream: &mut TcpStream) -> Result<(), VoyageError> {
-        let username = self.username.as_ref().ok_or_else(|| {
-            VoyageError::Socks5Error("Authentication required but no username".into())
-        })?;
-        let password = self.password.as_ref().ok_or_else(|| {
-            VoyageError::Socks5Error("Authentication required but no password".into())
-        })?;
+        let missing =
+            || VoyageError::socks5(HandshakeStage::Authentication, Socks5ErrorKind::MissingCredentials);
+        let username = self.username.as_ref().ok_or_else(missing)?;
+        let password = self.password.as_ref().ok_or_else(missing)?;
 
         let mut auth_request = BytesMut::new();
         auth_request.put_u8(0x01); // Auth version
         auth_request.put_u8(username.len() as u8);
         auth_request.put_slice(username.as_bytes());
         auth_request.put_u8(password.len() as u8);
-        auth_request.put_slice(password.as_bytes());
+        auth_request.put_slice(password.expose().as_bytes());
 
         stream
             .write_all(&auth_request)
             .await
-            .map_err(|e| VoyageError::IoError(e.to_string()))?;
+            .map_err(|e| VoyageError::io(e.to_string()))?;
 
         let mut response = [0u8; 2];
         stream
             .read_exact(&mut response)
             .await
-            .map_err(|e| VoyageError::IoError(e.to_string()))?;
+            .map_err(|e| VoyageError::io(e.to_string()))?;
 
         if response[1] != 0x00 {
-            return Err(VoyageError::Socks5Error("Authentication failed".into()));
+            return Err(VoyageError::socks5(
+                HandshakeStage::Authentication,
+                Socks5ErrorKind::AuthFailed,
+            ));
         }
 
         Ok(())
@@ -314,23 +386,24 @@ impl Socks5Client {
         stream
             .write_all(&request)
             .await
-            .map_err(|e| VoyageError::IoError(e.to_string()))?;
+            .map_err(|e| VoyageError::io(e.to_string()))?;
 
         // Read response header
         let mut header = [0u8; 4];
         stream
             .read_exact(&mut header)
             .await
-            .map_err(|e| VoyageError::IoError(e.to_string()))?;
+            .map_err(|e| VoyageError::io(e.to_string()))?;
 
         if header[0] != SOCKS5_VERSION {
-            return Err(VoyageError::Socks5Error("Invalid SOCKS version in reply".into()));
+            return Err(VoyageError::socks5(HandshakeStage::Connect, Socks5ErrorKind::BadVersion));
         }
 
         let reply_code = ReplyCode::from(header[1]);
         if reply_code != ReplyCode::Succeeded {
-            return Err(VoyageError::Socks5Error(
-                reply_code.to_error_message().into(),
+            return Err(VoyageError::socks5(
+                HandshakeStage::Connect,
+                Socks5ErrorKind::Reply { code: reply_code },
             ));
         }
 
@@ -343,7 +416,7 @@ impl Socks5Client {
                 stream
                     .read_exact(&mut addr)
                     .await
-                    .map_err(|e| VoyageError::IoError(e.to_string()))?;
+                    .map_err(|e| VoyageError::io(e.to_string()))?;
             }
             0x03 => {
                 // Domain: 1 byte len + domain + 2 port
@@ -351,12 +424,12 @@ impl Socks5Client {
                 stream
                     .read_exact(&mut len)
                     .await
-                    .map_err(|e| VoyageError::IoError(e.to_string()))?;
+                    .map_err(|e| VoyageError::io(e.to_string()))?;
                 let mut domain = vec![0u8; len[0] as usize + 2];
                 stream
                     .read_exact(&mut domain)
                     .await
-                    .map_err(|e| VoyageError::IoError(e.to_string()))?;
+                    .map_err(|e| VoyageError::io(e.to_string()))?;
             }
             0x04 => {
                 // IPv6: 16 bytes + 2 port
@@ -364,11 +437,12 @@ impl Socks5Client {
                 stream
                     .read_exact(&mut addr)
                     .await
-                    .map_err(|e| VoyageError::IoError(e.to_string()))?;
+                    .map_err(|e| VoyageError::io(e.to_string()))?;
             }
-            _ => {
-                return Err(VoyageError::Socks5Error(
-                    "Unknown address type in reply".into(),
+            other => {
+                return Err(VoyageError::socks5(
+                    HandshakeStage::Connect,
+                    Socks5ErrorKind::UnsupportedAddress { atyp: other },
                 ));
             }
         }
@@ -391,7 +465,7 @@ pub fn create_socks5_client(
         SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0))
     } else {
         // For hostnames, we need to resolve - this is a simplified version
-        return Err(VoyageError::ConfigError(
+        return Err(VoyageError::config(
             "Hostname resolution not supported in sync context".into(),
         ));
     };
@@ -482,7 +556,7 @@ mod tests {
 
         assert_eq!(client.proxy_addr, addr);
         assert_eq!(client.username, Some("user".to_string()));
-        assert_eq!(client.password, Some("pass".to_string()));
+        assert_eq!(client.password, Some("pass".into()));
     }
 
     #[test]
@@ -498,6 +572,14 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_reply_code_message_keys_in_catalog() {
+        for code in 0u8..=8 {
+            let reply = ReplyCode::from(code);
+            assert!(message::default_text(reply.message_key()).is_some());
+        }
+    }
+
     #[test]
     fn test_create_socks5_client_ipv4() {
         let client = create_socks5_client("127.0.0.1", 1080, None, None).unwrap();
@@ -512,7 +594,7 @@ mod tests {
         let client =
             create_socks5_client("127.0.0.1", 1080, Some("user"), Some("pass")).unwrap();
         assert_eq!(client.username, Some("user".to_string()));
-        assert_eq!(client.password, Some("pass".to_string()));
+        assert_eq!(client.password, Some("pass".into()));
     }
 
     #[test]
@@ -0,0 +1,154 @@
+//! Shared Statistics
+//!
+//! This module holds the counters behind `get_stats` in atomics, so the
+//! host can poll them without taking the core lock. The core publishes a
+//! fresh snapshot after every maintenance pass and explicit change, and
+//! from packet processing at most every `PUBLISH_INTERVAL`, as collecting
+//! one visits every connection shard; fields are stored one by one, so a
+//! reader may see a mix of two consecutive snapshots.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::ffi::CoreStats;
+
+/// Minimum time between snapshots published from packet processing
+pub const PUBLISH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// `published_ms` before the first `publish_due`
+const NEVER_PUBLISHED: u64 = u64::MAX;
+
+/// Time of the last snapshot published from packet processing
+#[derive(Debug)]
+struct PublishClock {
+    /// Reference point of `published_ms`
+    origin: Instant,
+    /// Milliseconds after `origin` of the last `publish_due` that said yes
+    published_ms: AtomicU64,
+}
+
+impl Default for PublishClock {
+    fn default() -> Self {
+        Self {
+            origin: Instant::now(),
+            published_ms: AtomicU64::new(NEVER_PUBLISHED),
+        }
+    }
+}
+
+/// Lock-free copy of the latest `CoreStats`
+#[derive(Debug, Default)]
+pub struct SharedStats {
+    /// When packet processing last published
+    clock: PublishClock,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    active_connections: AtomicU64,
+    total_connections: AtomicU64,
+    reaped_flows: AtomicU64,
+    nat_evictions: AtomicU64,
+    nat_source_limit_hits: AtomicU64,
+    nat_port_collisions: AtomicU64,
+    nat_invalid_transitions: AtomicU64,
+    expired_flows: AtomicU64,
+    maintenance_runs: AtomicU64,
+    upload_rate: AtomicU64,
+    download_rate: AtomicU64,
+    memory_usage: AtomicU64,
+    memory_budget: AtomicU64,
+    socket_buffer_bytes: AtomicU64,
+    connection_limit_hits: AtomicU64,
+    waiting_connections: AtomicU64,
+    limit_rejected_connections: AtomicU64,
+    route_cache_hits: AtomicU64,
+    route_cache_misses: AtomicU64,
+    quic_blocked: AtomicU64,
+    rate_limited_connections: AtomicU64,
+    rate_limit_blocks: AtomicU64,
+    malformed_packets: AtomicU64,
+}
+
+impl SharedStats {
+    /// Create zeroed counters
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether `PUBLISH_INTERVAL` passed since the last time this said
+    /// yes. Of callers racing each other, only one is told to publish.
+    pub fn publish_due(&self) -> bool {
+        let clock = &self.clock;
+        let now = clock.origin.elapsed().as_millis() as u64;
+        let last = clock.published_ms.load(Ordering::Relaxed);
+        let interval = PUBLISH_INTERVAL.as_millis() as u64;
+        if last != NEVER_PUBLISHED && now < last.saturating_add(interval) {
+            return false;
+        }
+        clock
+            .published_ms
+            .compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    /// Replace the counters with `stats`
+    pub fn publish(&self, stats: &CoreStats) {
+        self.bytes_sent.store(stats.bytes_sent, Ordering::Relaxed);
+        self.bytes_received.store(stats.bytes_received, Ordering::Relaxed);
+        self.active_connections.store(stats.active_connections, Ordering::Relaxed);
+        self.total_connections.store(stats.total_connections, Ordering::Relaxed);
+        self.reaped_flows.store(stats.reaped_flows, Ordering::Relaxed);
+        self.nat_evictions.store(stats.nat_evictions, Ordering::Relaxed);
+        self.nat_source_limit_hits.store(stats.nat_source_limit_hits, Ordering::Relaxed);
+        self.nat_port_collisions.store(stats.nat_port_collisions, Ordering::Relaxed);
+        self.nat_invalid_transitions.store(stats.nat_invalid_transitions, Ordering::Relaxed);
+        self.expired_flows.store(stats.expired_flows, Ordering::Relaxed);
+        self.maintenance_runs.store(stats.maintenance_runs, Ordering::Relaxed);
+        self.upload_rate.store(stats.upload_rate, Ordering::Relaxed);
+        self.download_rate.store(stats.download_rate, Ordering::Relaxed);
+        self.memory_usage.store(stats.memory_usage, Ordering::Relaxed);
+        self.memory_budget.store(stats.memory_budget, Ordering::Relaxed);
+        self.socket_buffer_bytes.store(stats.socket_buffer_bytes, Ordering::Relaxed);
+        self.connection_limit_hits.store(stats.connection_limit_hits, Ordering::Relaxed);
+        self.waiting_connections.store(stats.waiting_connections, Ordering::Relaxed);
+        self.limit_rejected_connections
+            .store(stats.limit_rejected_connections, Ordering::Relaxed);
+        self.route_cache_hits.store(stats.route_cache_hits, Ordering::Relaxed);
+        self.route_cache_misses.store(stats.route_cache_misses, Ordering::Relaxed);
+        self.quic_blocked.store(stats.quic_blocked, Ordering::Relaxed);
+        self.rate_limited_connections
+            .store(stats.rate_limited_connections, Ordering::Relaxed);
+        self.rate_limit_blocks.store(stats.rate_limit_blocks, Ordering::Relaxed);
+        self.malformed_packets.store(stats.malformed_packets, Ordering::Relaxed);
+    }
+
+    /// Latest published counters
+    pub fn snapshot(&self) -> CoreStats {
+        CoreStats {
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            active_connections: self.active_connections.load(Ordering::Relaxed),
+            total_connections: self.total_connections.load(Ordering::Relaxed),
+            reaped_flows: self.reaped_flows.load(Ordering::Relaxed),
+            nat_evictions: self.nat_evictions.load(Ordering::Relaxed),
+            nat_source_limit_hits: self.nat_source_limit_hits.load(Ordering::Relaxed),
+            nat_port_collisions: self.nat_port_collisions.load(Ordering::Relaxed),
+            nat_invalid_transitions: self.nat_invalid_transitions.load(Ordering::Relaxed),
+            expired_flows: self.expired_flows.load(Ordering::Relaxed),
+            maintenance_runs: self.maintenance_runs.load(Ordering::Relaxed),
+            upload_rate: self.upload_rate.load(Ordering::Relaxed),
+            download_rate: self.download_rate.load(Ordering::Relaxed),
+            memory_usage: self.memory_usage.load(Ordering::Relaxed),
+            memory_budget: self.memory_budget.load(Ordering::Relaxed),
+            socket_buffer_bytes: self.socket_buffer_bytes.load(Ordering::Relaxed),
+            connection_limit_hits: self.connection_limit_hits.load(Ordering::Relaxed),
+            waiting_connections: self.waiting_connections.load(Ordering::Relaxed),
+            limit_rejected_connections: self.limit_rejected_connections.load(Ordering::Relaxed),
+            route_cache_hits: self.route_cache_hits.load(Ordering::Relaxed),
+            route_cache_misses: self.route_cache_misses.load(Ordering::Relaxed),
+            quic_blocked: self.quic_blocked.load(Ordering::Relaxed),
+            rate_limited_connections: self.rate_limited_connections.load(Ordering::Relaxed),
+            rate_limit_blocks: self.rate_limit_blocks.load(Ordering::Relaxed),
+            malformed_packets: self.malformed_packets.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,325 @@
+//! Traffic Tap
+//!
+//! Debugging tools and content-inspection plugins sometimes need the bytes
+//! a connection carries, not just its metadata. A tap mirrors copies of the
+//! streams the inbound proxy relays for allow-listed hosts to a consumer: a
+//! host callback or a file. Intercepted TLS connections are mirrored as
+//! the decrypted plaintext.
+//!
+//! As with the flow log, chunks are queued on a bounded channel and handed
+//! over from a dedicated thread, so a slow consumer never stalls a relay;
+//! when the queue is full new chunks are dropped and counted. Without a
+//! tap, or for hosts off the allow-list, relays copy as before.
+
+use std::io;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::error::VoyageError;
+use crate::flowlog::RotatingFile;
+
+/// Chunks queued for the consumer before new ones are dropped
+pub const TAP_QUEUE_SIZE: usize = 1024;
+
+/// Which way a mirrored chunk went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TapDirection {
+    /// From the app to the destination
+    Sent,
+    /// From the destination to the app
+    Received,
+}
+
+impl TapDirection {
+    fn name(self) -> &'static str {
+        match self {
+            TapDirection::Sent => "sent",
+            TapDirection::Received => "received",
+        }
+    }
+}
+
+/// A copy of bytes relayed on a tapped connection
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TapChunk {
+    /// Identifies the connection among those seen by this tap
+    pub connection_id: u64,
+    /// Domain of the connection, or its destination IP
+    pub host: String,
+    pub port: u16,
+    pub direction: TapDirection,
+    /// When the bytes were relayed, in milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+    pub data: Vec<u8>,
+}
+
+impl TapChunk {
+    /// The chunk as a single JSON line with the data in hex, without the
+    /// newline
+    pub fn to_json(&self) -> String {
+        let data: String = self.data.iter().map(|b| format!("{:02x}", b)).collect();
+        serde_json::json!({
+            "connection_id": self.connection_id,
+            "host": self.host,
+            "port": self.port,
+            "direction": self.direction.name(),
+            "timestamp_ms": self.timestamp_ms,
+            "data": data,
+        })
+        .to_string()
+    }
+}
+
+/// Whether `host` matches `pattern`: an exact name or address, or
+/// `*.suffix` for any subdomain of `suffix`. Both are expected lowercase
+/// without a trailing dot.
+pub(crate) fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => {
+            host.len() > suffix.len()
+                && host.ends_with(suffix)
+                && host.as_bytes()[host.len() - suffix.len() - 1] == b'.'
+        }
+        None => pattern == host,
+    }
+}
+
+/// Allow-list of hosts and the queue feeding the consumer thread; dropping
+/// it ends the thread once the queued chunks are delivered
+#[derive(Debug)]
+pub struct TrafficTap {
+    hosts: Vec<String>,
+    sender: SyncSender<TapChunk>,
+    next_id: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl TrafficTap {
+    /// Mirror connections to `hosts` as JSON lines in a rotating file
+    pub fn to_file(
+        path: impl AsRef<Path>,
+        max_bytes: u64,
+        max_files: u32,
+        hosts: Vec<String>,
+    ) -> Result<Self, VoyageError> {
+        let mut file = RotatingFile::open(path, max_bytes, max_files)?;
+        Self::spawn(hosts, move |chunk| {
+            if let Err(e) = file.write_line(&chunk.to_json()) {
+                log::warn!("Traffic tap write failed: {}", e);
+            }
+        })
+    }
+
+    /// Hand chunks of connections to `hosts` to `deliver`
+    pub fn to_callback<F>(hosts: Vec<String>, deliver: F) -> Result<Self, VoyageError>
+    where
+        F: FnMut(TapChunk) + Send + 'static,
+    {
+        Self::spawn(hosts, deliver)
+    }
+
+    fn spawn<F>(hosts: Vec<String>, mut deliver: F) -> Result<Self, VoyageError>
+    where
+        F: FnMut(TapChunk) + Send + 'static,
+    {
+        let (sender, chunks) = mpsc::sync_channel(TAP_QUEUE_SIZE);
+        std::thread::Builder::new()
+            .name("voyage-tap".into())
+            .spawn(move || {
+                for chunk in chunks {
+                    deliver(chunk);
+                }
+            })
+            .map_err(|e| VoyageError::io(e.to_string()))?;
+        Ok(Self {
+            hosts: hosts
+                .iter()
+                .map(|host| host.trim_end_matches('.').to_ascii_lowercase())
+                .collect(),
+            sender,
+            next_id: AtomicU64::new(1),
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Whether connections to `host` are mirrored
+    pub fn should_tap(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        self.hosts
+            .iter()
+            .any(|pattern| host_matches(pattern, &host))
+    }
+
+    /// Start mirroring a connection to `host:port`
+    pub fn session(self: &Arc<Self>, host: &str, port: u16) -> TapSession {
+        TapSession {
+            tap: self.clone(),
+            connection_id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            host: host.to_string(),
+            port,
+        }
+    }
+
+    /// Chunks dropped because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn send(&self, chunk: TapChunk) {
+        if let Err(TrySendError::Full(_)) = self.sender.try_send(chunk) {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// One tapped connection
+#[derive(Debug, Clone)]
+pub struct TapSession {
+    tap: Arc<TrafficTap>,
+    connection_id: u64,
+    host: String,
+    port: u16,
+}
+
+impl TapSession {
+    /// Queue a copy of `data`
+    pub fn mirror(&self, direction: TapDirection, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        self.tap.send(TapChunk {
+            connection_id: self.connection_id,
+            host: self.host.clone(),
+            port: self.port,
+            direction,
+            timestamp_ms,
+            data: data.to_vec(),
+        });
+    }
+}
+
+/// Wraps the upstream side of a relay, mirroring what is written to it as
+/// sent and what is read from it as received. Without a session it only
+/// passes bytes through.
+#[derive(Debug)]
+pub struct TapStream<S> {
+    inner: S,
+    session: Option<TapSession>,
+}
+
+impl<S> TapStream<S> {
+    pub fn new(inner: S, session: Option<TapSession>) -> Self {
+        Self { inner, session }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for TapStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let start = buf.filled().len();
+        let this = &mut *self;
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(())), Some(session)) = (&result, &this.session) {
+            session.mirror(TapDirection::Received, &buf.filled()[start..]);
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for TapStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let (Poll::Ready(Ok(written)), Some(session)) = (&result, &this.session) {
+            session.mirror(TapDirection::Sent, &buf[..*written]);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    fn test_should_tap() {
+        let (tx, _rx) = mpsc::channel();
+        let hosts = vec!["*.example.com".into(), "1.1.1.1".into()];
+        let tap = TrafficTap::to_callback(hosts, move |chunk| {
+            let _ = tx.send(chunk);
+        })
+        .unwrap();
+        assert!(tap.should_tap("api.example.com."));
+        assert!(tap.should_tap("1.1.1.1"));
+        assert!(!tap.should_tap("example.com"));
+        assert!(!tap.should_tap("notexample.com"));
+    }
+
+    #[test]
+    fn test_tap_stream_mirrors_both_ways() {
+        let (tx, rx) = mpsc::channel();
+        let hosts = vec!["example.com".into()];
+        let tap = Arc::new(
+            TrafficTap::to_callback(hosts, move |chunk| {
+                let _ = tx.send(chunk);
+            })
+            .unwrap(),
+        );
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let (upstream, mut server) = tokio::io::duplex(64);
+            let session = tap.session("example.com", 80);
+            let mut stream = TapStream::new(upstream, Some(session));
+            stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+            server
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = [0u8; 27];
+            stream.read_exact(&mut response).await.unwrap();
+        });
+
+        let timeout = Duration::from_secs(5);
+        let sent = rx.recv_timeout(timeout).unwrap();
+        assert_eq!(sent.direction, TapDirection::Sent);
+        assert_eq!(sent.data, b"GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(sent.connection_id, 1);
+        let received = rx.recv_timeout(timeout).unwrap();
+        assert_eq!(received.direction, TapDirection::Received);
+        assert_eq!(received.data, b"HTTP/1.1 204 No Content\r\n\r\n");
+        assert!(received.to_json().contains("\"direction\":\"received\""));
+        assert_eq!(tap.dropped(), 0);
+    }
+}
@@ -0,0 +1,332 @@
+//! Traffic History
+//!
+//! This module keeps upload and download totals in fixed-size rings of
+//! per-second and per-minute buckets, overall and per routing policy, so
+//! the app can draw speed graphs from `get_traffic_history` instead of
+//! polling stats on a timer. Buckets with no traffic read as zero.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::rule::RouteAction;
+
+/// Per-second buckets kept (five minutes)
+pub const SECOND_BUCKETS: usize = 300;
+
+/// Per-minute buckets kept (one day)
+pub const MINUTE_BUCKETS: usize = 1440;
+
+/// Bucket size of a history
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TrafficResolution {
+    Second,
+    Minute,
+}
+
+impl TrafficResolution {
+    /// Time covered by one bucket
+    pub fn bucket(&self) -> Duration {
+        match self {
+            TrafficResolution::Second => Duration::from_secs(1),
+            TrafficResolution::Minute => Duration::from_secs(60),
+        }
+    }
+
+    /// Buckets kept at this resolution
+    pub fn capacity(&self) -> usize {
+        match self {
+            TrafficResolution::Second => SECOND_BUCKETS,
+            TrafficResolution::Minute => MINUTE_BUCKETS,
+        }
+    }
+}
+
+/// Bytes moved during one bucket
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TrafficSample {
+    /// Start of the bucket, in milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+/// Charts for one resolution, oldest bucket first
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrafficHistory {
+    /// Length of each bucket in milliseconds
+    pub bucket_ms: u64,
+    /// All traffic
+    pub total: Vec<TrafficSample>,
+    /// Traffic of flows routed DIRECT
+    pub direct: Vec<TrafficSample>,
+    /// Traffic of flows routed through the proxy
+    pub proxy: Vec<TrafficSample>,
+    /// Traffic of rejected flows
+    pub reject: Vec<TrafficSample>,
+}
+
+/// Ring of bucket totals, indexed by bucket number since the recorder's
+/// origin
+#[derive(Debug, Clone)]
+struct TrafficRing {
+    /// (sent, received) per bucket; the back is bucket `last`
+    buckets: VecDeque<(u64, u64)>,
+    last: u64,
+    capacity: usize,
+}
+
+impl TrafficRing {
+    fn new(capacity: usize) -> Self {
+        Self {
+            buckets: VecDeque::new(),
+            last: 0,
+            capacity,
+        }
+    }
+
+    fn record(&mut self, index: u64, sent: u64, received: u64) {
+        if self.buckets.is_empty() {
+            self.last = index;
+            self.buckets.push_back((0, 0));
+        } else if index > self.last {
+            let gap = (index - self.last).min(self.capacity as u64);
+            for _ in 0..gap {
+                self.buckets.push_back((0, 0));
+            }
+            while self.buckets.len() > self.capacity {
+                self.buckets.pop_front();
+            }
+            self.last = index;
+        }
+        if let Some(bucket) = self.buckets.back_mut() {
+            bucket.0 += sent;
+            bucket.1 += received;
+        }
+    }
+
+    /// Add the buckets of a ring counted from the same origin
+    fn merge(&mut self, other: &TrafficRing) {
+        if other.buckets.is_empty() {
+            return;
+        }
+        let last = self.last.max(other.last);
+        let first = (last + 1).saturating_sub(self.capacity as u64);
+        let buckets = (first..=last)
+            .map(|index| {
+                let (mine, theirs) = (self.get(index), other.get(index));
+                (mine.0 + theirs.0, mine.1 + theirs.1)
+            })
+            .collect();
+        self.buckets = buckets;
+        self.last = last;
+    }
+
+    /// Totals of bucket `index`, zero when not kept
+    fn get(&self, index: u64) -> (u64, u64) {
+        if index > self.last {
+            return (0, 0);
+        }
+        let age = (self.last - index) as usize;
+        if age >= self.buckets.len() {
+            return (0, 0);
+        }
+        self.buckets[self.buckets.len() - 1 - age]
+    }
+}
+
+/// Per-second and per-minute rings for one traffic group
+#[derive(Debug, Clone)]
+struct TrafficRings {
+    seconds: TrafficRing,
+    minutes: TrafficRing,
+}
+
+impl TrafficRings {
+    fn new() -> Self {
+        Self {
+            seconds: TrafficRing::new(SECOND_BUCKETS),
+            minutes: TrafficRing::new(MINUTE_BUCKETS),
+        }
+    }
+
+    fn merge(&mut self, other: &TrafficRings) {
+        self.seconds.merge(&other.seconds);
+        self.minutes.merge(&other.minutes);
+    }
+
+    fn ring(&self, resolution: TrafficResolution) -> &TrafficRing {
+        match resolution {
+            TrafficResolution::Second => &self.seconds,
+            TrafficResolution::Minute => &self.minutes,
+        }
+    }
+}
+
+/// Bucketed traffic totals, overall and per policy
+#[derive(Debug, Clone)]
+pub struct TrafficRecorder {
+    origin: Instant,
+    /// `origin` in milliseconds since the Unix epoch
+    origin_ms: u64,
+    total: TrafficRings,
+    direct: TrafficRings,
+    proxy: TrafficRings,
+    reject: TrafficRings,
+}
+
+impl TrafficRecorder {
+    /// Create a recorder whose first buckets start at `now`
+    pub fn new(now: Instant) -> Self {
+        let origin_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        Self {
+            origin: now,
+            origin_ms,
+            total: TrafficRings::new(),
+            direct: TrafficRings::new(),
+            proxy: TrafficRings::new(),
+            reject: TrafficRings::new(),
+        }
+    }
+
+    /// Count bytes moved at `now` by a flow routed by `policy`, if routed
+    pub fn record(&mut self, policy: Option<RouteAction>, sent: u64, received: u64, now: Instant) {
+        let seconds = self.index(TrafficResolution::Second, now);
+        let minutes = self.index(TrafficResolution::Minute, now);
+        let add = |rings: &mut TrafficRings| {
+            rings.seconds.record(seconds, sent, received);
+            rings.minutes.record(minutes, sent, received);
+        };
+        add(&mut self.total);
+        match policy {
+            Some(RouteAction::Direct) => add(&mut self.direct),
+            Some(RouteAction::Proxy) => add(&mut self.proxy),
+            Some(RouteAction::Reject) => add(&mut self.reject),
+            None => {}
+        }
+    }
+
+    /// Add the buckets of a recorder with the same origin, i.e. a clone of
+    /// the same recorder that counted other flows
+    pub fn merge(&mut self, other: &TrafficRecorder) {
+        self.total.merge(&other.total);
+        self.direct.merge(&other.direct);
+        self.proxy.merge(&other.proxy);
+        self.reject.merge(&other.reject);
+    }
+
+    /// The last `window` buckets up to and including the one holding
+    /// `now`, capped at the resolution's capacity
+    pub fn history(
+        &self,
+        resolution: TrafficResolution,
+        window: usize,
+        now: Instant,
+    ) -> TrafficHistory {
+        let bucket_ms = resolution.bucket().as_millis() as u64;
+        let current = self.index(resolution, now);
+        let count = window.min(resolution.capacity()) as u64;
+        let first = (current + 1).saturating_sub(count);
+        let samples = |rings: &TrafficRings| {
+            let ring = rings.ring(resolution);
+            (first..=current)
+                .take(count as usize)
+                .map(|index| {
+                    let (bytes_sent, bytes_received) = ring.get(index);
+                    TrafficSample {
+                        timestamp_ms: self.origin_ms + index * bucket_ms,
+                        bytes_sent,
+                        bytes_received,
+                    }
+                })
+                .collect()
+        };
+        TrafficHistory {
+            bucket_ms,
+            total: samples(&self.total),
+            direct: samples(&self.direct),
+            proxy: samples(&self.proxy),
+            reject: samples(&self.reject),
+        }
+    }
+
+    fn index(&self, resolution: TrafficResolution, now: Instant) -> u64 {
+        let elapsed = now.saturating_duration_since(self.origin);
+        (elapsed.as_millis() / resolution.bucket().as_millis()) as u64
+    }
+}
+
+impl Default for TrafficRecorder {
+    fn default() -> Self {
+        Self::new(Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sent(samples: &[TrafficSample]) -> Vec<u64> {
+        samples.iter().map(|sample| sample.bytes_sent).collect()
+    }
+
+    #[test]
+    fn test_buckets_per_second_and_minute() {
+        let start = Instant::now();
+        let mut recorder = TrafficRecorder::new(start);
+        recorder.record(Some(RouteAction::Proxy), 100, 10, start);
+        recorder.record(
+            Some(RouteAction::Direct),
+            50,
+            0,
+            start + Duration::from_millis(500),
+        );
+        recorder.record(None, 7, 0, start + Duration::from_secs(2));
+
+        let now = start + Duration::from_secs(3);
+        let history = recorder.history(TrafficResolution::Second, 4, now);
+        assert_eq!(history.bucket_ms, 1000);
+        assert_eq!(sent(&history.total), vec![150, 0, 7, 0]);
+        assert_eq!(sent(&history.proxy), vec![100, 0, 0, 0]);
+        assert_eq!(sent(&history.direct), vec![50, 0, 0, 0]);
+        assert_eq!(history.total[0].bytes_received, 10);
+        assert_eq!(
+            history.total[1].timestamp_ms - history.total[0].timestamp_ms,
+            1000
+        );
+
+        let minutes = recorder.history(TrafficResolution::Minute, 2, now);
+        assert_eq!(sent(&minutes.total), vec![157]);
+    }
+
+    #[test]
+    fn test_old_buckets_fall_off() {
+        let start = Instant::now();
+        let mut recorder = TrafficRecorder::new(start);
+        recorder.record(None, 1, 0, start);
+        let later = start + Duration::from_secs(SECOND_BUCKETS as u64 + 10);
+        recorder.record(None, 2, 0, later);
+
+        let history = recorder.history(TrafficResolution::Second, usize::MAX, later);
+        assert_eq!(history.total.len(), SECOND_BUCKETS);
+        assert_eq!(history.total.iter().map(|s| s.bytes_sent).sum::<u64>(), 2);
+        assert_eq!(history.total.last().unwrap().bytes_sent, 2);
+    }
+
+    #[test]
+    fn test_merge_adds_buckets_of_a_clone() {
+        let start = Instant::now();
+        let mut a = TrafficRecorder::new(start);
+        let mut b = a.clone();
+        a.record(Some(RouteAction::Proxy), 100, 0, start);
+        b.record(Some(RouteAction::Proxy), 10, 0, start);
+        b.record(None, 5, 0, start + Duration::from_secs(2));
+
+        a.merge(&b);
+        let history = a.history(TrafficResolution::Second, 3, start + Duration::from_secs(2));
+        assert_eq!(sent(&history.total), vec![110, 0, 5]);
+        assert_eq!(sent(&history.proxy), vec![110, 0, 0]);
+    }
+}
@@ -0,0 +1,214 @@
+//! Traffic Usage
+//!
+//! This module aggregates flow byte counts into per-group totals (by source
+//! IP, app, domain, ...) that outlive the flows themselves, so the app can
+//! show data usage and top talkers. Each table is capped; when full, the
+//! group that has been quiet the longest is dropped.
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::Instant;
+
+use crate::rate::RateMeter;
+
+/// Default number of groups kept per table
+pub const DEFAULT_USAGE_ENTRIES: usize = 1024;
+
+/// Traffic totals of one group
+#[derive(Debug, Clone, Copy)]
+pub struct Usage {
+    /// Bytes sent
+    pub bytes_sent: u64,
+    /// Bytes received
+    pub bytes_received: u64,
+    /// Connections opened
+    pub connections: u64,
+    /// Upload speed
+    pub upload: RateMeter,
+    /// Download speed
+    pub download: RateMeter,
+    /// Last time the group saw traffic or a new connection
+    pub last_active: Instant,
+}
+
+impl Usage {
+    fn new(now: Instant) -> Self {
+        Self {
+            bytes_sent: 0,
+            bytes_received: 0,
+            connections: 0,
+            upload: RateMeter::new(now),
+            download: RateMeter::new(now),
+            last_active: now,
+        }
+    }
+
+    /// Bytes transferred in both directions
+    pub fn total_bytes(&self) -> u64 {
+        self.bytes_sent + self.bytes_received
+    }
+
+    /// Add the totals of the same group counted elsewhere
+    pub fn merge(&mut self, other: &Usage, now: Instant) {
+        self.bytes_sent += other.bytes_sent;
+        self.bytes_received += other.bytes_received;
+        self.connections += other.connections;
+        self.upload.merge(&other.upload, now);
+        self.download.merge(&other.download, now);
+        self.last_active = self.last_active.max(other.last_active);
+    }
+}
+
+/// Capped usage totals keyed by group
+#[derive(Debug, Clone)]
+pub struct UsageTable<K> {
+    groups: HashMap<K, Usage>,
+    capacity: usize,
+}
+
+impl<K: Hash + Eq + Clone> UsageTable<K> {
+    /// Create a table keeping at most `capacity` groups
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            groups: HashMap::new(),
+            capacity,
+        }
+    }
+
+    /// Number of groups
+    pub fn len(&self) -> usize {
+        self.groups.len()
+    }
+
+    /// Check if no traffic has been recorded
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    /// Forget every group
+    pub fn clear(&mut self) {
+        self.groups.clear();
+    }
+
+    /// Totals of one group
+    pub fn get(&self, key: &K) -> Option<&Usage> {
+        self.groups.get(key)
+    }
+
+    /// Count a new connection for `key`
+    pub fn add_connection(&mut self, key: &K, now: Instant) {
+        if let Some(usage) = self.entry(key, now) {
+            usage.connections += 1;
+            usage.last_active = now;
+        }
+    }
+
+    /// Count bytes transferred by `key`
+    pub fn add_bytes(&mut self, key: &K, sent: u64, received: u64, now: Instant) {
+        if let Some(usage) = self.entry(key, now) {
+            usage.bytes_sent += sent;
+            usage.bytes_received += received;
+            usage.upload.record(sent, now);
+            usage.download.record(received, now);
+            usage.last_active = now;
+        }
+    }
+
+    /// Up to `limit` groups with the most bytes transferred, largest first
+    pub fn top(&self, limit: usize) -> Vec<(&K, &Usage)> {
+        let mut groups: Vec<(&K, &Usage)> = self.groups.iter().collect();
+        groups.sort_by_key(|(_, usage)| Reverse(usage.total_bytes()));
+        groups.truncate(limit);
+        groups
+    }
+
+    /// Add the groups of a table kept elsewhere, e.g. by another shard.
+    /// The result may hold more groups than its capacity.
+    pub fn merge(&mut self, other: &UsageTable<K>, now: Instant) {
+        for (key, usage) in &other.groups {
+            match self.groups.get_mut(key) {
+                Some(mine) => mine.merge(usage, now),
+                None => {
+                    self.groups.insert(key.clone(), *usage);
+                }
+            }
+        }
+    }
+
+    fn entry(&mut self, key: &K, now: Instant) -> Option<&mut Usage> {
+        if self.capacity == 0 {
+            return None;
+        }
+        if !self.groups.contains_key(key) && self.groups.len() >= self.capacity {
+            let quietest = self
+                .groups
+                .iter()
+                .min_by_key(|(_, usage)| usage.last_active)
+                .map(|(key, _)| key.clone());
+            if let Some(quietest) = quietest {
+                self.groups.remove(&quietest);
+            }
+        }
+        Some(
+            self.groups
+                .entry(key.clone())
+                .or_insert_with(|| Usage::new(now)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_top_talkers() {
+        let now = Instant::now();
+        let mut table = UsageTable::new(8);
+        table.add_connection(&"a", now);
+        table.add_bytes(&"a", 100, 50, now);
+        table.add_bytes(&"b", 10, 1000, now);
+        table.add_bytes(&"a", 5, 0, now);
+
+        let top = table.top(1);
+        assert_eq!(top.len(), 1);
+        assert_eq!(*top[0].0, "b");
+
+        let a = table.get(&"a").unwrap();
+        assert_eq!((a.bytes_sent, a.bytes_received, a.connections), (105, 50, 1));
+        assert_eq!(table.top(10).len(), 2);
+    }
+
+    #[test]
+    fn test_capacity_drops_quietest_group() {
+        let now = Instant::now();
+        let mut table = UsageTable::new(2);
+        table.add_bytes(&1, 1, 0, now);
+        table.add_bytes(&2, 1, 0, now + Duration::from_secs(1));
+        table.add_bytes(&1, 1, 0, now + Duration::from_secs(2));
+        table.add_bytes(&3, 1, 0, now + Duration::from_secs(3));
+
+        assert_eq!(table.len(), 2);
+        assert!(table.get(&2).is_none());
+        assert_eq!(table.get(&1).unwrap().bytes_sent, 2);
+    }
+
+    #[test]
+    fn test_merge_adds_shared_groups() {
+        let now = Instant::now();
+        let mut a = UsageTable::new(8);
+        a.add_connection(&"x", now);
+        a.add_bytes(&"x", 100, 10, now);
+        let mut b = UsageTable::new(8);
+        b.add_bytes(&"x", 1, 2, now + Duration::from_secs(1));
+        b.add_bytes(&"y", 5, 0, now);
+
+        a.merge(&b, now + Duration::from_secs(1));
+        let x = a.get(&"x").unwrap();
+        assert_eq!((x.bytes_sent, x.bytes_received, x.connections), (101, 12, 1));
+        assert_eq!(x.last_active, now + Duration::from_secs(1));
+        assert_eq!(a.get(&"y").unwrap().bytes_sent, 5);
+    }
+}
@@ -4,6 +4,8 @@
 //! the complete packet processing pipeline.
 
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{mpsc, Mutex};
+use std::time::Duration;
 
 use serial_test::serial;
 
@@ -14,7 +16,8 @@ use voyage_core::device::VirtualTunDevice;
 use voyage_core::nat::{NatKey, NatManager};
 use voyage_core::packet::{ParsedPacket, TransportProtocol};
 use voyage_core::proxy::ProxyManager;
-use voyage_core::rule::{RouteAction, Rule, RuleEngine, RuleType};
+use voyage_core::rule::{MatchContext, RouteAction, Rule, RuleEngine, RuleType};
+use voyage_core::PacketWriter;
 
 /// Create a minimal IPv4 TCP SYN packet for testing
 fn make_tcp_syn_packet(src_port: u16, dst_port: u16) -> Vec<u8> {
@@ -55,6 +58,30 @@ fn make_tcp_syn_packet(src_port: u16, dst_port: u16) -> Vec<u8> {
     packet
 }
 
+/// Create a minimal IPv6 TCP SYN packet from fd00::2 to 2001:4860:4860::8888
+fn make_tcp6_syn_packet(src_port: u16, dst_port: u16) -> Vec<u8> {
+    let mut packet = vec![0u8; 60]; // 40 byte IPv6 + 20 byte TCP
+
+    // IPv6 header
+    packet[0] = 0x60; // Version 6
+    packet[5] = 20; // Payload length: 20 bytes
+    packet[6] = 0x06; // Next header: TCP
+    packet[7] = 64; // Hop limit
+
+    let src: std::net::Ipv6Addr = "fd00::2".parse().unwrap();
+    let dst: std::net::Ipv6Addr = "2001:4860:4860::8888".parse().unwrap();
+    packet[8..24].copy_from_slice(&src.octets());
+    packet[24..40].copy_from_slice(&dst.octets());
+
+    // TCP header
+    packet[40..42].copy_from_slice(&src_port.to_be_bytes());
+    packet[42..44].copy_from_slice(&dst_port.to_be_bytes());
+    packet[52] = 0x50; // Data offset (5 words)
+    packet[53] = 0x02; // Flags: SYN
+
+    packet
+}
+
 /// Create a minimal IPv4 UDP packet for testing
 fn make_udp_packet(src_port: u16, dst_port: u16) -> Vec<u8> {
     let mut packet = vec![0u8; 28]; // 20 byte IP + 8 byte UDP
@@ -98,6 +125,7 @@ fn test_full_packet_processing_pipeline() {
         server_port: 1080,
         username: None,
         password: None,
+        ..Default::default()
     });
 
     // Load rules
@@ -128,14 +156,65 @@ FINAL, DIRECT
 
     // Evaluate routing (should match IP-CIDR rule for 8.8.8.8)
     let decision = proxy_manager.evaluate_route(
-        None,
-        parsed.dst_addr().map(|a| a.ip()),
-        443,
-        12345,
+        &MatchContext::new(None, parsed.dst_addr().map(|a| a.ip()), 443).with_source(None, 12345),
     );
     assert_eq!(decision.action, RouteAction::Proxy);
 }
 
+#[test]
+#[serial]
+fn test_ipv6_packet_processing_pipeline() {
+    let mut conn_manager = ConnectionManager::new();
+    let mut proxy_manager = ProxyManager::with_config(ProxyConfig::default());
+    proxy_manager
+        .load_rules(
+            r#"
+IP-CIDR, 8.8.8.0/24, DIRECT
+IP-CIDR6, 2001:4860::/32, PROXY
+FINAL, DIRECT
+"#,
+        )
+        .unwrap();
+
+    let packet = make_tcp6_syn_packet(12345, 443);
+    let parsed = ParsedPacket::parse(&packet).unwrap();
+    assert!(parsed.is_tcp_syn());
+    assert_eq!(parsed.ip.protocol, TransportProtocol::Tcp);
+
+    // v4 and v6 flows share the NAT port pool without clashing
+    let v6 = conn_manager.process_packet(&parsed).unwrap();
+    let v4 = conn_manager
+        .process_packet(&ParsedPacket::parse(&make_tcp_syn_packet(12345, 443)).unwrap())
+        .unwrap();
+    assert_ne!(v6.local_port, v4.local_port);
+    assert_eq!(v6.key.dst_addr().to_string(), "[2001:4860:4860::8888]:443");
+    assert_eq!(conn_manager.get_by_port(v6.local_port).unwrap().key, v6.key);
+
+    let decision = proxy_manager.evaluate_route(
+        &MatchContext::new(None, parsed.dst_addr().map(|a| a.ip()), 443).with_source(None, 12345),
+    );
+    assert_eq!(decision.action, RouteAction::Proxy);
+}
+
+#[test]
+#[serial]
+fn test_ipv6_flow_through_core() {
+    let mut core = voyage_core::VoyageCore::new(ProxyConfig::default());
+    core.load_rules("IP-CIDR6, 2001:4860::/32, PROXY\nFINAL, DIRECT").unwrap();
+
+    let mut packet = make_tcp6_syn_packet(40000, 443);
+    core.process_inbound(&mut packet).unwrap();
+
+    let key = ParsedPacket::parse(&packet).unwrap().to_nat_key().unwrap();
+    assert_eq!(core.conn_manager.active_connections(), 1);
+    assert_eq!(core.conn_manager.route(&key).unwrap().action, RouteAction::Proxy);
+
+    // The interface carries an IPv6 address next to the IPv4 one
+    let iface = core.new_interface();
+    let addrs: Vec<String> = iface.ip_addrs().iter().map(ToString::to_string).collect();
+    assert_eq!(addrs, ["10.0.0.1/24", "fd00::1/64"]);
+}
+
 #[test]
 #[serial]
 fn test_nat_connection_tracking() {
@@ -180,19 +259,19 @@ fn test_rule_engine_evaluation_order() {
 
     // Specific domain should be rejected
     assert_eq!(
-        engine.evaluate(Some("specific.google.com"), None, 443, 0),
+        engine.evaluate(&MatchContext::new(Some("specific.google.com"), None, 443)),
         RouteAction::Reject
     );
 
     // Other google.com domains should be proxied
     assert_eq!(
-        engine.evaluate(Some("www.google.com"), None, 443, 0),
+        engine.evaluate(&MatchContext::new(Some("www.google.com"), None, 443)),
         RouteAction::Proxy
     );
 
     // Other domains should be direct
     assert_eq!(
-        engine.evaluate(Some("example.com"), None, 443, 0),
+        engine.evaluate(&MatchContext::new(Some("example.com"), None, 443)),
         RouteAction::Direct
     );
 }
@@ -230,12 +309,10 @@ fn test_packet_queue_concurrent_access() {
     if let Ok(device) = device.lock() {
         while device.has_rx_packets() {
             // Get packets through the device's rx_queue
-            if let Ok(mut queue) = device.rx_queue().lock() {
-                if queue.pop_front().is_some() {
-                    count += 1;
-                } else {
-                    break;
-                }
+            if device.rx_queue().pop_front().is_some() {
+                count += 1;
+            } else {
+                break;
             }
         }
     }
@@ -313,6 +390,7 @@ fn test_proxy_stats_tracking() {
         server_port: 1080,
         username: None,
         password: None,
+        ..Default::default()
     });
 
     manager
@@ -327,13 +405,13 @@ FINAL, DIRECT
 
     // Generate some traffic
     for _ in 0..10 {
-        manager.evaluate_route(Some("proxy.com"), None, 443, 0);
+        manager.evaluate_route(&MatchContext::new(Some("proxy.com"), None, 443));
     }
     for _ in 0..5 {
-        manager.evaluate_route(Some("reject.com"), None, 443, 0);
+        manager.evaluate_route(&MatchContext::new(Some("reject.com"), None, 443));
     }
     for _ in 0..20 {
-        manager.evaluate_route(Some("other.com"), None, 443, 0);
+        manager.evaluate_route(&MatchContext::new(Some("other.com"), None, 443));
     }
 
     let stats = manager.get_stats();
@@ -385,20 +463,16 @@ fn test_cidr_matching() {
 
     // Private IPs should be direct
     assert_eq!(
-        engine.evaluate(
+        engine.evaluate(&MatchContext::new(
             None,
             Some(std::net::IpAddr::V4(Ipv4Addr::new(192, 168, 1, 100))),
-            443,
-            0
-        ),
+            443
+        )),
         RouteAction::Direct
     );
     assert_eq!(
         engine.evaluate(
-            None,
-            Some(std::net::IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3))),
-            443,
-            0
+            &MatchContext::new(None, Some(std::net::IpAddr::V4(Ipv4Addr::new(10, 1, 2, 3))), 443),
         ),
         RouteAction::Direct
     );
@@ -406,10 +480,7 @@ fn test_cidr_matching() {
     // Public IPs should be proxied
     assert_eq!(
         engine.evaluate(
-            None,
-            Some(std::net::IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8))),
-            443,
-            0
+            &MatchContext::new(None, Some(std::net::IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8))), 443),
         ),
         RouteAction::Proxy
     );
@@ -423,6 +494,7 @@ fn test_config_loading() {
         server_port: 1080,
         username: Some("user".into()),
         password: Some("password".into()),
+        ..Default::default()
     };
 
     let manager = ProxyManager::with_config(config.clone());
@@ -433,7 +505,7 @@ fn test_config_loading() {
 
     let (user, pass) = manager.get_credentials().unwrap();
     assert_eq!(user, "user");
-    assert_eq!(pass, "password");
+    assert_eq!(pass.expose(), "password");
 }
 
 #[test]
@@ -492,24 +564,71 @@ fn test_enable_disable_proxy() {
         server_port: 1080,
         username: None,
         password: None,
+        ..Default::default()
     });
 
     manager.load_rules("FINAL, PROXY").unwrap();
 
     // Enabled: should return PROXY
     assert!(manager.is_enabled());
-    let decision = manager.evaluate_route(Some("example.com"), None, 443, 0);
+    let decision = manager.evaluate_route(&MatchContext::new(Some("example.com"), None, 443));
     assert_eq!(decision.action, RouteAction::Proxy);
 
     // Disabled: should return DIRECT
     manager.disable();
     assert!(!manager.is_enabled());
-    let decision = manager.evaluate_route(Some("example.com"), None, 443, 0);
+    let decision = manager.evaluate_route(&MatchContext::new(Some("example.com"), None, 443));
     assert_eq!(decision.action, RouteAction::Direct);
 
     // Re-enabled
     manager.enable();
     assert!(manager.is_enabled());
-    let decision = manager.evaluate_route(Some("example.com"), None, 443, 0);
+    let decision = manager.evaluate_route(&MatchContext::new(Some("example.com"), None, 443));
     assert_eq!(decision.action, RouteAction::Proxy);
 }
+
+struct ChannelWriter(Mutex<mpsc::Sender<Vec<u8>>>);
+
+impl PacketWriter for ChannelWriter {
+    fn write_packets(&self, packets: Vec<Vec<u8>>) {
+        let sender = self.0.lock().unwrap();
+        for packet in packets {
+            let _ = sender.send(packet);
+        }
+    }
+}
+
+fn inject(packet: &[u8]) -> i32 {
+    // SAFETY: the slice is valid for the duration of the call
+    unsafe { voyage_core::voyage_inject_inbound_packet(packet.as_ptr(), packet.len()) }
+}
+
+#[test]
+#[serial]
+fn test_inject_through_ffi_entry_point() {
+    voyage_core::init_core("127.0.0.1".into(), 1080, None, None).unwrap();
+    let (sender, written) = mpsc::channel();
+    voyage_core::set_packet_writer(Box::new(ChannelWriter(Mutex::new(sender)))).unwrap();
+
+    // The packet reaches the engine's interface, which answers the app
+    let syn = voyage_core::create_tcp_packet([10, 0, 0, 1], [8, 8, 8, 8], 40000, 443, true);
+    assert_eq!(inject(&syn), voyage_core::INJECT_QUEUED);
+    let reply = written.recv_timeout(Duration::from_secs(2)).unwrap();
+    let reply = ParsedPacket::parse(&reply).unwrap();
+    assert_eq!(reply.dst_addr(), Some("10.0.0.1:40000".parse().unwrap()));
+    assert_eq!(voyage_core::get_device_stats().unwrap().rx_packets, 1);
+    assert_eq!(voyage_core::get_active_connections().unwrap().len(), 1);
+
+    assert_eq!(inject(&syn[..10]), voyage_core::INJECT_REJECTED);
+    let reason = voyage_core::last_error_message().unwrap();
+    assert_eq!(reason.key, "error.invalid_packet");
+    // The reason is kept per thread, so other callers cannot overwrite it
+    let elsewhere = std::thread::spawn(voyage_core::last_error_message).join().unwrap();
+    assert!(elsewhere.is_none());
+
+    // A stopped engine takes no packets
+    voyage_core::stop_engine().unwrap();
+    assert_eq!(inject(&syn), voyage_core::INJECT_UNAVAILABLE);
+    voyage_core::shutdown_core();
+    assert_eq!(inject(&syn), voyage_core::INJECT_UNAVAILABLE);
+}